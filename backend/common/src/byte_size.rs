@@ -75,6 +75,7 @@ mod test {
     use crate::byte_size::ByteSize;
 
     #[test]
+    #[allow(clippy::identity_op)]
     fn can_parse_valid_strings() {
         let cases = vec![
             ("100", 100),
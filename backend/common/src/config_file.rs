@@ -0,0 +1,329 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Load command line options from a TOML config file as well as the command line.
+//!
+//! The keys in the file are the long names of the command line options (for example
+//! `max-feeds = 100` or `allow-chain = ["..."]`; underscores may be used in place of
+//! hyphens), and each one is turned into the arguments it stands for, so the options
+//! themselves remain the single source of truth for what can be configured. Options given
+//! on the command line override those in the file.
+
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use structopt::clap::{AppSettings, ErrorKind};
+use structopt::StructOpt;
+use toml_edit::{Document, Item, Value};
+
+/// The command line option that names a config file to load.
+pub const CONFIG_OPTION: &str = "--config";
+
+/// Something went wrong loading options from a config file.
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigFileError {
+    #[error("Cannot read config file '{0}': {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Cannot parse config file '{0}': {1}")]
+    Parse(PathBuf, toml_edit::TomlError),
+    #[error("Unknown option '{1}' in config file '{0}'")]
+    UnknownKey(PathBuf, String),
+    #[error("Bad value for '{1}' in config file '{0}': {2}")]
+    BadValue(PathBuf, String, String),
+}
+
+/// Parse options from the command line, along with any config file given via `--config`.
+/// Like [`StructOpt::from_args`], this prints a message and exits if anything is wrong.
+pub fn from_args<T: StructOpt>() -> T {
+    match from_iter(std::env::args_os()) {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Parse options from the arguments given (the first of which is the binary name), along
+/// with any config file that they name via `--config`. Problems with the config file are
+/// returned, and problems with the arguments themselves are reported by `clap`, which exits.
+pub fn from_iter<T, I>(args: I) -> Result<T, ConfigFileError>
+where
+    T: StructOpt,
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+    let path = match config_file_path(&args) {
+        Some(path) => path,
+        None => return Ok(T::from_iter(args)),
+    };
+    let contents =
+        std::fs::read_to_string(&path).map_err(|e| ConfigFileError::Read(path.clone(), e))?;
+    let file_args = args_from_toml(&path, &contents)?;
+
+    let (bin, cli_args) = args.split_first().expect("the binary name is always given");
+    let mut all_args = vec![bin.clone()];
+    for (key, key_args) in file_args {
+        // Check each key on its own, so that we can say which one is wrong. A flag that's
+        // turned off has no arguments, but should still be one of ours:
+        let check = match key_args.is_empty() {
+            true => vec![OsString::from(format!("--{}", key))],
+            false => key_args.clone(),
+        };
+        let check = std::iter::once(bin.clone()).chain(check);
+        if let Err(e) = T::clap()
+            .setting(AppSettings::ColorNever)
+            .get_matches_from_safe(check)
+        {
+            return Err(match e.kind {
+                ErrorKind::UnknownArgument => ConfigFileError::UnknownKey(path, key),
+                _ => {
+                    // Just the error itself, without any usage that follows it:
+                    let reason = e.message.lines().next().unwrap_or_default();
+                    let reason = reason.trim_start_matches("error: ").to_owned();
+                    ConfigFileError::BadValue(path, key, reason)
+                }
+            });
+        }
+        // Lists aren't overridden by the `AllArgsOverrideSelf` setting below; they'd be
+        // added to instead, so leave them out here if they're on the command line.
+        if !is_given(&key, cli_args) {
+            all_args.extend(key_args);
+        }
+    }
+    all_args.extend(cli_args.iter().cloned());
+
+    // Later occurrences of an option override earlier ones, so the command line wins:
+    let matches = T::clap()
+        .setting(AppSettings::AllArgsOverrideSelf)
+        .get_matches_from(all_args);
+    Ok(T::from_clap(&matches))
+}
+
+/// Find the config file named by `--config <path>` or `--config=<path>`, if any.
+fn config_file_path(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--" {
+            return None;
+        }
+        if arg == CONFIG_OPTION {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg
+            .strip_prefix(CONFIG_OPTION)
+            .and_then(|a| a.strip_prefix('='))
+        {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+/// Is the option with the long name given present in the arguments?
+fn is_given(long_name: &str, args: &[OsString]) -> bool {
+    let option = format!("--{}", long_name);
+    args.iter()
+        .map(|arg| arg.to_string_lossy())
+        .take_while(|arg| arg != "--")
+        .any(|arg| {
+            arg == option
+                || arg
+                    .strip_prefix(&option)
+                    .is_some_and(|a| a.starts_with('='))
+        })
+}
+
+/// Turn each key in the TOML given into the command line arguments that it stands for,
+/// returning the (hyphenated) key alongside them.
+fn args_from_toml(
+    path: &Path,
+    contents: &str,
+) -> Result<Vec<(String, Vec<OsString>)>, ConfigFileError> {
+    let doc: Document = contents
+        .parse()
+        .map_err(|e| ConfigFileError::Parse(path.to_owned(), e))?;
+    let bad_value = |key: &str, reason: &str| {
+        ConfigFileError::BadValue(path.to_owned(), key.to_owned(), reason.to_owned())
+    };
+
+    let mut all_args = Vec::new();
+    for (key, item) in doc.iter() {
+        let key = key.replace('_', "-");
+        let option = OsString::from(format!("--{}", key));
+        if option == OsStr::new(CONFIG_OPTION) {
+            return Err(bad_value(
+                &key,
+                "config files cannot load other config files",
+            ));
+        }
+        let values = match item {
+            Item::Value(Value::Array(array)) => array.iter().collect(),
+            Item::Value(value) => vec![value],
+            _ => return Err(bad_value(&key, "expected a value rather than a table")),
+        };
+
+        let mut args = Vec::new();
+        for value in values {
+            let value = match value {
+                Value::String(s) => s.value().clone(),
+                Value::Integer(n) => n.value().to_string(),
+                Value::Float(n) => n.value().to_string(),
+                Value::Datetime(d) => d.value().to_string(),
+                // A flag is either given or not:
+                Value::Boolean(b) => {
+                    if *b.value() {
+                        args.push(option.clone());
+                    }
+                    continue;
+                }
+                Value::Array(_) | Value::InlineTable(_) => {
+                    return Err(bad_value(&key, "expected a string, number or boolean"))
+                }
+            };
+            args.push(option.clone());
+            args.push(value.into());
+        }
+        all_args.push((key, args));
+    }
+    Ok(all_args)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(StructOpt, Debug, PartialEq)]
+    struct Opts {
+        #[structopt(short = "l", long = "listen", default_value = "127.0.0.1:8000")]
+        socket: String,
+        #[structopt(long, default_value = "10")]
+        max_things: usize,
+        #[structopt(long = "allow-chain", number_of_values = 1)]
+        allow_chain: Vec<String>,
+        #[structopt(long)]
+        be_quiet: bool,
+        #[structopt(long)]
+        config: Option<PathBuf>,
+    }
+
+    /// Write a config file with the contents given, and parse options from it and the
+    /// command line arguments given.
+    fn parse(name: &str, contents: &str, args: &[&str]) -> Result<Opts, ConfigFileError> {
+        let path = std::env::temp_dir().join(format!(
+            "telemetry-config-{}-{}.toml",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, contents).unwrap();
+        let config = format!("--config={}", path.display());
+        let args = std::iter::once("bin")
+            .chain(std::iter::once(config.as_str()))
+            .chain(args.iter().copied());
+        let opts = from_iter(args);
+        std::fs::remove_file(&path).unwrap();
+        opts.map(|opts| Opts {
+            config: None,
+            ..opts
+        })
+    }
+
+    #[test]
+    fn options_are_loaded_from_the_config_file() {
+        let opts = parse(
+            "loaded",
+            r#"
+                listen = "0.0.0.0:8000"
+                max_things = 20
+                allow-chain = ["a", "b"]
+                be-quiet = true
+            "#,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            opts,
+            Opts {
+                socket: "0.0.0.0:8000".into(),
+                max_things: 20,
+                allow_chain: vec!["a".into(), "b".into()],
+                be_quiet: true,
+                config: None,
+            }
+        );
+    }
+
+    #[test]
+    fn command_line_options_override_the_config_file() {
+        let opts = parse(
+            "overrides",
+            r#"
+                listen = "0.0.0.0:8000"
+                max-things = 20
+                allow-chain = ["a", "b"]
+                be-quiet = false
+            "#,
+            &[
+                "-l",
+                "1.2.3.4:5",
+                "--max-things=30",
+                "--allow-chain",
+                "c",
+                "--be-quiet",
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            opts,
+            Opts {
+                socket: "1.2.3.4:5".into(),
+                max_things: 30,
+                allow_chain: vec!["c".into()],
+                be_quiet: true,
+                config: None,
+            }
+        );
+    }
+
+    #[test]
+    fn mistakes_in_the_config_file_are_errors() {
+        let err = parse("unknown", "max-thingz = 20", &[]).unwrap_err();
+        assert!(matches!(err, ConfigFileError::UnknownKey(_, k) if k == "max-thingz"));
+
+        let err = parse("unknown-flag", "be-loud = false", &[]).unwrap_err();
+        assert!(matches!(err, ConfigFileError::UnknownKey(_, k) if k == "be-loud"));
+
+        let err = parse("bad-value", "max-things = \"lots\"", &[]).unwrap_err();
+        assert!(matches!(err, ConfigFileError::BadValue(_, k, _) if k == "max-things"));
+
+        let err = parse("table", "[max-things]\nfoo = 1", &[]).unwrap_err();
+        assert!(matches!(err, ConfigFileError::BadValue(_, k, _) if k == "max-things"));
+
+        let err = parse("nested", "config = \"other.toml\"", &[]).unwrap_err();
+        assert!(matches!(err, ConfigFileError::BadValue(_, k, _) if k == "config"));
+
+        let err = parse("syntax", "max-things = ", &[]).unwrap_err();
+        assert!(matches!(err, ConfigFileError::Parse(..)));
+    }
+
+    #[test]
+    fn no_config_file_means_just_the_command_line() {
+        let opts: Opts = from_iter(["bin", "--max-things", "5"]).unwrap();
+        assert_eq!(opts.max_things, 5);
+        assert_eq!(opts.config, None);
+    }
+}
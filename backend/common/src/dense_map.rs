@@ -32,6 +32,16 @@ pub struct DenseMap<Id, T> {
     _id_type: std::marker::PhantomData<Id>,
 }
 
+impl<Id, T> Default for DenseMap<Id, T>
+where
+    Id: From<usize> + Copy,
+    usize: From<Id>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<Id, T> DenseMap<Id, T>
 where
     Id: From<usize> + Copy,
@@ -49,6 +59,23 @@ where
         self.add_with(|_| item)
     }
 
+    /// Add an item with the given Id, if that Id isn't in use. If it is,
+    /// the item is handed back.
+    pub fn add_at(&mut self, id: Id, item: T) -> Result<(), T> {
+        let id: usize = id.into();
+        if id >= self.items.len() {
+            // Any slots we skip over are free to be used later:
+            self.retired.extend(self.items.len()..id);
+            self.items.resize_with(id + 1, || None);
+        } else if self.items[id].is_some() {
+            return Err(item);
+        } else {
+            self.retired.retain(|&retired_id| retired_id != id);
+        }
+        self.items[id] = Some(item);
+        Ok(())
+    }
+
     pub fn as_slice(&self) -> &[Option<T>] {
         &self.items
     }
@@ -108,6 +135,7 @@ where
             .filter_map(|(id, item)| Some((id.into(), item.as_mut()?)))
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn into_iter(self) -> impl Iterator<Item = (Id, T)> {
         self.items
             .into_iter()
@@ -162,4 +190,26 @@ mod test {
 
         assert_eq!(map.len(), 0);
     }
+
+    #[test]
+    fn items_can_be_added_at_free_ids() {
+        let mut map = DenseMap::<usize, usize>::new();
+
+        let id1 = map.add(1);
+        let id2 = map.add(2);
+        map.remove(id1);
+
+        // IDs in use can't be added at, but free ones can:
+        assert_eq!(map.add_at(id2, 3), Err(3));
+        assert_eq!(map.add_at(id1, 3), Ok(()));
+        assert_eq!(map.get(id1), Some(&3));
+
+        // Adding past the end leaves free IDs behind to be used later:
+        assert_eq!(map.add_at(4, 4), Ok(()));
+        assert_eq!(map.len(), 3);
+        let mut new_ids = vec![map.add(5), map.add(6)];
+        new_ids.sort();
+        assert_eq!(new_ids, vec![2, 3]);
+        assert_eq!(map.add(7), 5);
+    }
 }
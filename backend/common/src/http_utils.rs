@@ -15,10 +15,18 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use futures::io::{BufReader, BufWriter};
-use hyper::server::conn::AddrStream;
+use futures::{future, ready};
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
 use hyper::{Body, Request, Response, Server};
 use std::future::Future;
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::time::{Instant, Sleep};
 use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
 
 /// A convenience function to start up a Hyper server and handle requests.
@@ -27,19 +35,275 @@ where
     H: Clone + Send + Sync + 'static + FnMut(SocketAddr, Request<Body>) -> F,
     F: Send + 'static + Future<Output = Result<Response<Body>, anyhow::Error>>,
 {
+    let (_, server) = bind_server(addr, handler, futures::future::pending())?;
+    server.await
+}
+
+/// Bind a Hyper server to the address given, returning the address that it's actually listening
+/// on (useful if port 0 was asked for) and a future which handles requests until `shutdown` resolves.
+pub fn bind_server<H, F, S>(
+    addr: SocketAddr,
+    handler: H,
+    shutdown: S,
+) -> Result<(SocketAddr, impl Future<Output = Result<(), anyhow::Error>>), anyhow::Error>
+where
+    H: Clone + Send + Sync + 'static + FnMut(SocketAddr, Request<Body>) -> F,
+    F: Send + 'static + Future<Output = Result<Response<Body>, anyhow::Error>>,
+    S: Future<Output = ()>,
+{
+    bind_server_with_opts(addr, ServerOpts::default(), handler, shutdown)
+}
+
+/// Options to configure how a server accepts connections.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ServerOpts {
+    /// Accept at most this many new connections per second. Connections beyond this rate are
+    /// left waiting to be accepted (rather than being refused), so that a flood of them is
+    /// spread out over time. If not given, connections are accepted as fast as they arrive.
+    pub max_accepts_per_sec: Option<u32>,
+    /// Enable TCP keepalive on accepted connections, probing them after they've been idle for
+    /// this long. `TCP_NODELAY` is always set on them, so that small messages aren't delayed.
+    pub tcp_keepalive: Option<Duration>,
+    /// How many connections the OS can queue up for us to accept. If not given, the
+    /// standard library's default is used.
+    pub listen_backlog: Option<u32>,
+    /// How many accepted connections can be in the middle of being handled at once. A
+    /// connection is being handled until it's closed or upgraded to a websocket. Connections
+    /// accepted beyond this are answered straight away with a "503 Service Unavailable"
+    /// telling them to try again later, and closed. If not given, there's no limit.
+    pub max_pending_connections: Option<usize>,
+}
+
+/// Like [`bind_server`], but configured with the options given.
+pub fn bind_server_with_opts<H, F, S>(
+    addr: SocketAddr,
+    opts: ServerOpts,
+    handler: H,
+    shutdown: S,
+) -> Result<(SocketAddr, impl Future<Output = Result<(), anyhow::Error>>), anyhow::Error>
+where
+    H: Clone + Send + Sync + 'static + FnMut(SocketAddr, Request<Body>) -> F,
+    F: Send + 'static + Future<Output = Result<Response<Body>, anyhow::Error>>,
+    S: Future<Output = ()>,
+{
+    let pending = opts
+        .max_pending_connections
+        .map(|n| Arc::new(Semaphore::new(n.min(Semaphore::MAX_PERMITS))));
     let service = hyper::service::make_service_fn(move |addr: &AddrStream| {
         let mut handler = handler.clone();
         let addr = addr.remote_addr();
-        async move { Ok::<_, hyper::Error>(hyper::service::service_fn(move |r| handler(addr, r))) }
+        // The permit is held by the service, which lives until the connection is closed or
+        // upgraded. If there are none left, we're too busy to handle the connection:
+        let permit = pending
+            .as_ref()
+            .map(|pending| Arc::clone(pending).try_acquire_owned().ok());
+        let overloaded = matches!(permit, Some(None));
+        if overloaded {
+            log::debug!("Too many pending connections; asking {addr} to try again later");
+        }
+        async move {
+            Ok::<_, hyper::Error>(hyper::service::service_fn(move |r| {
+                let _permit = &permit;
+                if overloaded {
+                    future::Either::Left(future::ok(try_later_response()))
+                } else {
+                    future::Either::Right(handler(addr, r))
+                }
+            }))
+        }
+    });
+    let incoming = bind_incoming(&addr, &opts)?;
+    let local_addr = incoming.local_addr();
+    let incoming = PacedIncoming::new(incoming, opts.max_accepts_per_sec);
+    let server = Server::builder(incoming).serve(service);
+
+    log::info!("listening on http://{}", local_addr);
+    let server = server.with_graceful_shutdown(shutdown);
+
+    Ok((local_addr, async move {
+        server.await?;
+        Ok(())
+    }))
+}
+
+/// Like [`bind_server`], but listening on a Unix domain socket at the path given rather than
+/// on a TCP address, and so without a remote address to hand to the handler.
+///
+/// A stale socket left at the path by a previous run (one that nothing is listening on) is
+/// replaced, but we refuse to start if there's anything else there. The socket is removed
+/// again once the returned future completes or is dropped.
+#[cfg(unix)]
+pub fn bind_unix_server<H, F, S>(
+    path: &std::path::Path,
+    handler: H,
+    shutdown: S,
+) -> Result<impl Future<Output = Result<(), anyhow::Error>>, anyhow::Error>
+where
+    H: Clone + Send + Sync + 'static + FnMut(Request<Body>) -> F,
+    F: Send + 'static + Future<Output = Result<Response<Body>, anyhow::Error>>,
+    S: Future<Output = ()>,
+{
+    remove_stale_socket(path)?;
+    let listener = tokio::net::UnixListener::bind(path)
+        .map_err(|e| anyhow::anyhow!("Cannot listen on {}: {e}", path.display()))?;
+    let socket_file = RemoveOnDrop(path.to_owned());
+
+    let service = hyper::service::make_service_fn(move |_: &tokio::net::UnixStream| {
+        let handler = handler.clone();
+        async move { Ok::<_, hyper::Error>(hyper::service::service_fn(handler)) }
+    });
+    let incoming = hyper::server::accept::poll_fn(move |cx| {
+        listener
+            .poll_accept(cx)
+            .map(|accepted| Some(accepted.map(|(stream, _)| stream)))
     });
-    let server = Server::bind(&addr).serve(service);
+    let server = Server::builder(incoming).serve(service);
+
+    log::info!("listening on unix:{}", path.display());
+    let server = server.with_graceful_shutdown(shutdown);
+
+    Ok(async move {
+        let _socket_file = socket_file;
+        server.await?;
+        Ok(())
+    })
+}
+
+/// Remove the socket at the path given if nothing is listening on it any more. It's an error
+/// for there to be anything else at the path.
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> Result<(), anyhow::Error> {
+    use std::io::ErrorKind;
+    use std::os::unix::fs::FileTypeExt;
+
+    let file_type = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata.file_type(),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => anyhow::bail!("Cannot listen on {}: {e}", path.display()),
+    };
+    if !file_type.is_socket() {
+        anyhow::bail!(
+            "Cannot listen on {}: It already exists, and isn't a socket",
+            path.display()
+        );
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => anyhow::bail!(
+            "Cannot listen on {}: Something is already listening on it",
+            path.display()
+        ),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            log::info!("Removing stale socket {}", path.display());
+            std::fs::remove_file(path)?;
+            Ok(())
+        }
+        Err(e) => anyhow::bail!("Cannot listen on {}: {e}", path.display()),
+    }
+}
+
+/// Removes the file at this path when dropped.
+#[cfg(unix)]
+struct RemoveOnDrop(std::path::PathBuf);
+
+#[cfg(unix)]
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            log::warn!("Cannot remove {}: {e}", self.0.display());
+        }
+    }
+}
+
+/// The response given to connections that we're too busy to handle. The connection is
+/// closed once it's been sent.
+fn try_later_response() -> Response<Body> {
+    Response::builder()
+        .status(503)
+        .header("Retry-After", "1")
+        .header("Connection", "close")
+        .body("Too many pending connections; try again later".into())
+        .expect("response is valid")
+}
+
+/// Listen on the address given, configuring the connections that are accepted as asked.
+fn bind_incoming(addr: &SocketAddr, opts: &ServerOpts) -> Result<AddrIncoming, anyhow::Error> {
+    let mut incoming = match opts.listen_backlog {
+        Some(backlog) => AddrIncoming::from_listener(bind_listener(addr, backlog)?)?,
+        None => AddrIncoming::bind(addr)?,
+    };
+    incoming.set_nodelay(true);
+    incoming.set_keepalive(opts.tcp_keepalive);
+    Ok(incoming)
+}
 
-    log::info!("listening on http://{}", server.local_addr());
-    server.await?;
+/// Listen on the address given, with room for the OS to queue up this many connections for
+/// us to accept.
+fn bind_listener(addr: &SocketAddr, backlog: u32) -> std::io::Result<tokio::net::TcpListener> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(*addr),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    // As the standard library does, allow quick restarts on the same address:
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&(*addr).into())?;
+    socket.listen(backlog.min(i32::MAX as u32) as i32)?;
+    socket.set_nonblocking(true)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
 
+/// Set `TCP_NODELAY` on the socket, so that small messages aren't delayed, and enable TCP
+/// keepalive on it if asked to.
+pub fn set_tcp_opts(
+    socket: &tokio::net::TcpStream,
+    tcp_keepalive: Option<Duration>,
+) -> std::io::Result<()> {
+    socket.set_nodelay(true)?;
+    if let Some(time) = tcp_keepalive {
+        let keepalive = socket2::TcpKeepalive::new().with_time(time);
+        socket2::SockRef::from(socket).set_tcp_keepalive(&keepalive)?;
+    }
     Ok(())
 }
 
+/// Accepts connections from the wrapped listener no faster than the rate given, by waiting
+/// a fixed interval after each accepted connection before accepting the next.
+struct PacedIncoming {
+    incoming: AddrIncoming,
+    pacing: Option<(Duration, Pin<Box<Sleep>>)>,
+}
+
+impl PacedIncoming {
+    fn new(incoming: AddrIncoming, max_accepts_per_sec: Option<u32>) -> Self {
+        let pacing = max_accepts_per_sec.filter(|&n| n > 0).map(|n| {
+            let interval = Duration::from_secs(1) / n;
+            (interval, Box::pin(tokio::time::sleep(Duration::ZERO)))
+        });
+        PacedIncoming { incoming, pacing }
+    }
+}
+
+impl Accept for PacedIncoming {
+    type Conn = AddrStream;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+        if let Some((_, delay)) = &mut this.pacing {
+            ready!(delay.as_mut().poll(cx));
+        }
+        let conn = ready!(Pin::new(&mut this.incoming).poll_accept(cx));
+        if let Some((interval, delay)) = &mut this.pacing {
+            delay.as_mut().reset(Instant::now() + *interval);
+        }
+        Poll::Ready(conn)
+    }
+}
+
 type WsStream = BufReader<BufWriter<Compat<hyper::upgrade::Upgraded>>>;
 pub type WsSender = soketto::connection::Sender<WsStream>;
 pub type WsReceiver = soketto::connection::Receiver<WsStream>;
@@ -49,6 +313,34 @@ pub fn upgrade_to_websocket<H, F>(req: Request<Body>, on_upgrade: H) -> hyper::R
 where
     H: 'static + Send + FnOnce(WsSender, WsReceiver) -> F,
     F: Send + Future<Output = ()>,
+{
+    upgrade_to_websocket_with_opts(req, WsUpgradeOpts::default(), move |sender, receiver, _| {
+        on_upgrade(sender, receiver)
+    })
+}
+
+/// Options to configure the websocket connection that a request is upgraded into.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WsUpgradeOpts {
+    /// The subprotocols that we support, in order of preference. The first of these that
+    /// the client asked for is selected.
+    pub protocols: &'static [&'static str],
+    /// The maximum size of a message (and of any single frame of it) that we'll receive.
+    /// Receiving a larger message fails without it being buffered. If not given, Soketto's
+    /// default limit is used.
+    pub max_message_size: Option<usize>,
+}
+
+/// Upgrade a Hyper request into a Soketto Websocket configured with the options given. The
+/// subprotocol agreed on with the client, if any, is handed to `on_upgrade`.
+pub fn upgrade_to_websocket_with_opts<H, F>(
+    req: Request<Body>,
+    opts: WsUpgradeOpts,
+    on_upgrade: H,
+) -> hyper::Response<Body>
+where
+    H: 'static + Send + FnOnce(WsSender, WsReceiver, Option<&'static str>) -> F,
+    F: Send + Future<Output = ()>,
 {
     if !is_upgrade_request(&req) {
         return basic_response(400, "Expecting WebSocket upgrade headers");
@@ -80,12 +372,31 @@ where
     let mut accept_key_buf = [0; 32];
     let accept_key = generate_websocket_accept_key(key.as_bytes(), &mut accept_key_buf);
 
+    // Find the first of our subprotocols that the client asked for, if any:
+    let requested_protocols: Vec<&str> = req
+        .headers()
+        .get_all("Sec-WebSocket-Protocol")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|p| p.trim())
+        .collect();
+    let protocol = opts
+        .protocols
+        .iter()
+        .copied()
+        .find(|p| requested_protocols.contains(p));
+
     // Tell the client that we accept the upgrade-to-WS request:
-    let response = Response::builder()
+    let mut response = Response::builder()
         .status(hyper::StatusCode::SWITCHING_PROTOCOLS)
         .header(hyper::header::CONNECTION, "upgrade")
         .header(hyper::header::UPGRADE, "websocket")
-        .header("Sec-WebSocket-Accept", accept_key)
+        .header("Sec-WebSocket-Accept", accept_key);
+    if let Some(protocol) = protocol {
+        response = response.header("Sec-WebSocket-Protocol", protocol);
+    }
+    let response = response
         .body(Body::empty())
         .expect("bug: failed to build response");
 
@@ -105,10 +416,15 @@ where
             soketto::handshake::Server::new(BufReader::new(BufWriter::new(stream.compat())));
 
         // Get hold of a way to send and receive messages:
-        let (sender, receiver) = server.into_builder().finish();
+        let mut builder = server.into_builder();
+        if let Some(max_message_size) = opts.max_message_size {
+            builder.set_max_message_size(max_message_size);
+            builder.set_max_frame_size(max_message_size);
+        }
+        let (sender, receiver) = builder.finish();
 
         // Pass these to our when-upgraded handler:
-        on_upgrade(sender, receiver).await;
+        on_upgrade(sender, receiver, protocol).await;
     });
 
     response
@@ -136,7 +452,7 @@ fn generate_websocket_accept_key<'a>(key: &[u8], buf: &'a mut [u8; 32]) -> &'a [
 
     use base64::{engine::general_purpose, Engine as _};
     let n = general_purpose::STANDARD
-        .encode_slice(&d, buf)
+        .encode_slice(d, buf)
         .expect("Sha1 must fit into [u8; 32]");
     &buf[..n]
 }
@@ -173,3 +489,159 @@ fn header_contains_value(
     }
     false
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn accepts_are_paced() {
+        let accepted_at = Arc::new(Mutex::new(Vec::new()));
+        let handler_accepted_at = Arc::clone(&accepted_at);
+        let (addr, server) = bind_server_with_opts(
+            "127.0.0.1:0".parse().unwrap(),
+            ServerOpts {
+                max_accepts_per_sec: Some(20),
+                ..Default::default()
+            },
+            move |_addr, _req| {
+                handler_accepted_at.lock().unwrap().push(Instant::now());
+                async { Ok(Response::new(Body::empty())) }
+            },
+            futures::future::pending(),
+        )
+        .unwrap();
+        tokio::spawn(server);
+
+        // Connect a bunch of clients at once; none are refused, but they are
+        // accepted (and so their requests are handled) one every 50ms:
+        let start = Instant::now();
+        let clients = (0..6).map(|_| async move {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut res = Vec::new();
+            stream.read_to_end(&mut res).await.unwrap();
+            assert!(res.starts_with(b"HTTP/1.1 200"));
+        });
+        futures::future::join_all(clients).await;
+
+        let mut accepted_at = accepted_at.lock().unwrap().clone();
+        accepted_at.sort();
+        assert_eq!(accepted_at.len(), 6);
+        assert!(accepted_at[0] - start < Duration::from_millis(50));
+        for pair in accepted_at.windows(2) {
+            assert!(pair[1] - pair[0] >= Duration::from_millis(45));
+        }
+    }
+
+    #[tokio::test]
+    async fn accepted_connections_have_tcp_options_set() {
+        let opts = ServerOpts {
+            tcp_keepalive: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        let mut incoming = bind_incoming(&"127.0.0.1:0".parse().unwrap(), &opts).unwrap();
+        let _client = tokio::net::TcpStream::connect(incoming.local_addr())
+            .await
+            .unwrap();
+        let conn = futures::future::poll_fn(|cx| Pin::new(&mut incoming).poll_accept(cx))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let socket = socket2::SockRef::from(&conn);
+        assert!(socket.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn connections_beyond_the_pending_limit_are_told_to_try_later() {
+        let (addr, server) = bind_server_with_opts(
+            "127.0.0.1:0".parse().unwrap(),
+            ServerOpts {
+                listen_backlog: Some(16),
+                max_pending_connections: Some(1),
+                ..Default::default()
+            },
+            |_addr, req: Request<Body>| async move {
+                if req.uri().path() == "/slow" {
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                }
+                Ok(Response::new(Body::empty()))
+            },
+            futures::future::pending(),
+        )
+        .unwrap();
+        tokio::spawn(server);
+
+        let request = |path: &'static str| async move {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let req =
+                format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+            stream.write_all(req.as_bytes()).await.unwrap();
+            let mut res = Vec::new();
+            stream.read_to_end(&mut res).await.unwrap();
+            res
+        };
+
+        // While one connection is being handled, another is turned away straight away:
+        let slow = tokio::spawn(request("/slow"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let start = Instant::now();
+        let res = request("/").await;
+        assert!(res.starts_with(b"HTTP/1.1 503"));
+        assert!(String::from_utf8_lossy(&res).contains("retry-after: 1"));
+        assert!(start.elapsed() < Duration::from_millis(300));
+
+        // Once it's done, there's room again:
+        assert!(slow.await.unwrap().starts_with(b"HTTP/1.1 200"));
+        assert!(request("/").await.starts_with(b"HTTP/1.1 200"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_servers_replace_stale_sockets_and_clean_up() {
+        let dir = std::env::temp_dir().join(format!("http-utils-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("admin.sock");
+        let serve = || {
+            bind_unix_server(
+                &path,
+                |_req| async { Ok(Response::new(Body::from("hello"))) },
+                futures::future::pending(),
+            )
+        };
+
+        // Anything but a stale socket at the path is refused:
+        std::fs::write(&path, b"not a socket").unwrap();
+        assert!(serve().is_err());
+        std::fs::remove_file(&path).unwrap();
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        assert!(serve().is_err());
+        drop(listener);
+
+        // ..but a stale socket is replaced, and we can talk HTTP over the new one:
+        let server = tokio::spawn(serve().unwrap());
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut res = Vec::new();
+        stream.read_to_end(&mut res).await.unwrap();
+        assert!(res.starts_with(b"HTTP/1.1 200"));
+        assert!(res.ends_with(b"hello"));
+
+        // The socket is removed when the server stops:
+        server.abort();
+        let _ = server.await;
+        assert!(!path.exists());
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -20,7 +20,7 @@ use std::net::IpAddr;
 
 use crate::id_type;
 use crate::node_message::Payload;
-use crate::node_types::{BlockHash, NodeDetails};
+use crate::node_types::{BlockHash, NodeDetails, Timestamp};
 use serde::{Deserialize, Serialize};
 
 id_type! {
@@ -40,14 +40,33 @@ pub enum FromShardAggregator {
         node: NodeDetails,
         local_id: ShardNodeId,
         genesis_hash: BlockHash,
+        /// An ID identifying the shard that the node is connected to, if one was given.
+        shard_id: Option<Box<str>>,
+        /// The token that the node connected to the shard with, if it gave one.
+        token: Option<Box<str>>,
     },
     /// A message payload with updated details for a node
     UpdateNode {
         local_id: ShardNodeId,
         payload: Payload,
+        /// When the node says that it sent the message, by its own clock, if it said.
+        sent_at: Option<Timestamp>,
     },
     /// Inform the telemetry core that a node has been removed
-    RemoveNode { local_id: ShardNodeId },
+    RemoveNode {
+        local_id: ShardNodeId,
+        reason: RemovalReason,
+    },
+    /// Inform the telemetry core that a node's clock has started or stopped disagreeing with
+    /// the shard's by more than the shard allows. `skew_ms` is how far ahead (or, if negative,
+    /// behind) the node's clock was when it started to disagree, or `None` once it agrees again.
+    ClockSkew {
+        local_id: ShardNodeId,
+        skew_ms: Option<i64>,
+    },
+    /// Inform the telemetry core that the shard has started or stopped draining;
+    /// a draining shard refuses new node connections ahead of being taken down.
+    Draining { draining: bool },
 }
 
 /// Message sent form the telemetry core to a telemetry shard
@@ -64,4 +83,201 @@ pub enum FromTelemetryCore {
 pub enum MuteReason {
     Overquota,
     ChainNotAllowed,
+    /// The node is on a chain we don't know about, and we're already
+    /// tracking as many chains as we're allowed to.
+    TooManyChains,
+    /// The node has connected again, and the new connection replaces this one.
+    Superseded,
+}
+
+/// Why has a node been removed by a shard?
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalReason {
+    /// The node's connection was closed, or lost.
+    Disconnected,
+    /// The node stopped sending messages.
+    TimedOut,
+    /// The shard closed the node's connection because it sent too much data.
+    RateLimited,
+    /// The shard closed the node's connection while draining, so that it reconnects elsewhere.
+    Draining,
+}
+
+/// The version of the internal protocol spoken between shards and the telemetry core.
+/// Bump this whenever the messages above change in a way that isn't backward compatible.
+pub const PROTOCOL_VERSION: u32 = 18;
+
+/// The first message that a shard sends to the telemetry core after connecting, so that
+/// the two can agree on which version of the internal protocol they are speaking. This must
+/// not change shape between protocol versions.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    pub protocol_version: u32,
+}
+
+/// The telemetry core's response to a [`Handshake`]. Like [`Handshake`], this must not
+/// change shape between protocol versions.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeResponse {
+    /// The core speaks the same protocol version as the shard.
+    Accepted,
+    /// The core speaks a different protocol version, and will close the connection.
+    Rejected { core_protocol_version: u32 },
+}
+
+impl HandshakeResponse {
+    /// Respond to a handshake from a shard, given the protocol version that the core speaks.
+    pub fn respond_to(handshake: Handshake, core_protocol_version: u32) -> HandshakeResponse {
+        if handshake.protocol_version == core_protocol_version {
+            HandshakeResponse::Accepted
+        } else {
+            HandshakeResponse::Rejected {
+                core_protocol_version,
+            }
+        }
+    }
+
+    /// Turn this response into an error if the core rejected the given shard protocol version.
+    pub fn into_result(self, shard_protocol_version: u32) -> Result<(), ProtocolVersionMismatch> {
+        match self {
+            HandshakeResponse::Accepted => Ok(()),
+            HandshakeResponse::Rejected {
+                core_protocol_version,
+            } => Err(ProtocolVersionMismatch {
+                shard_protocol_version,
+                core_protocol_version,
+            }),
+        }
+    }
+}
+
+/// The shard and telemetry core could not agree on an internal protocol version.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("Incompatible internal protocol versions: shard speaks version {shard_protocol_version} but core speaks version {core_protocol_version}")]
+pub struct ProtocolVersionMismatch {
+    pub shard_protocol_version: u32,
+    pub core_protocol_version: u32,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bincode::Options;
+
+    #[test]
+    fn matching_versions_are_accepted() {
+        let handshake = Handshake {
+            protocol_version: PROTOCOL_VERSION,
+        };
+        let res = HandshakeResponse::respond_to(handshake, PROTOCOL_VERSION);
+        assert_eq!(res, HandshakeResponse::Accepted);
+        assert_eq!(res.into_result(PROTOCOL_VERSION), Ok(()));
+    }
+
+    #[test]
+    fn mismatched_versions_produce_error() {
+        let handshake = Handshake {
+            protocol_version: PROTOCOL_VERSION + 1,
+        };
+        let res = HandshakeResponse::respond_to(handshake, PROTOCOL_VERSION);
+        assert_eq!(
+            res.into_result(PROTOCOL_VERSION + 1),
+            Err(ProtocolVersionMismatch {
+                shard_protocol_version: PROTOCOL_VERSION + 1,
+                core_protocol_version: PROTOCOL_VERSION,
+            })
+        );
+    }
+
+    /// These types don't implement `PartialEq`, so compare their debug output instead.
+    fn bincode_roundtrips<T: Serialize + serde::de::DeserializeOwned + std::fmt::Debug>(msg: T) {
+        let bytes = bincode::options().serialize(&msg).unwrap();
+        let decoded: T = bincode::options().deserialize(&bytes).unwrap();
+        assert_eq!(format!("{decoded:?}"), format!("{msg:?}"));
+    }
+
+    #[test]
+    fn from_shard_aggregator_roundtrips_through_bincode() {
+        bincode_roundtrips(FromShardAggregator::AddNode {
+            ip: "127.0.0.1".parse().unwrap(),
+            node: NodeDetails {
+                chain: "Polkadot".into(),
+                name: "Alice".into(),
+                implementation: "Parity Polkadot".into(),
+                version: "0.9.43-ba42b9ce51d".into(),
+                validator: None,
+                authority: true,
+                role: crate::node_types::NodeRole::Authority,
+                network_id: Default::default(),
+                startup_time: Some("1625565542717".into()),
+                target_os: Some("linux".into()),
+                target_arch: Some("x86_64".into()),
+                target_env: Some("gnu".into()),
+                commit: Some("ba42b9ce51d".into()),
+                sysinfo: None,
+                ip: None,
+            },
+            local_id: ShardNodeId(1),
+            genesis_hash: BlockHash::from_low_u64_be(2),
+            shard_id: Some("shard-1".into()),
+            token: None,
+        });
+        bincode_roundtrips(FromShardAggregator::UpdateNode {
+            local_id: ShardNodeId(1),
+            payload: Payload::BlockImport(crate::node_types::Block {
+                hash: BlockHash::from_low_u64_be(3),
+                height: 3,
+            }),
+            sent_at: Some(1625565542717),
+        });
+        for reason in [
+            RemovalReason::Disconnected,
+            RemovalReason::TimedOut,
+            RemovalReason::RateLimited,
+            RemovalReason::Draining,
+        ] {
+            bincode_roundtrips(FromShardAggregator::RemoveNode {
+                local_id: ShardNodeId(1),
+                reason,
+            });
+        }
+        bincode_roundtrips(FromShardAggregator::ClockSkew {
+            local_id: ShardNodeId(1),
+            skew_ms: Some(-90_000),
+        });
+        bincode_roundtrips(FromShardAggregator::Draining { draining: true });
+    }
+
+    #[test]
+    fn from_telemetry_core_roundtrips_through_bincode() {
+        for reason in [
+            MuteReason::Overquota,
+            MuteReason::ChainNotAllowed,
+            MuteReason::TooManyChains,
+            MuteReason::Superseded,
+        ] {
+            bincode_roundtrips(FromTelemetryCore::Mute {
+                local_id: ShardNodeId(4),
+                reason,
+            });
+        }
+    }
+
+    #[test]
+    fn handshake_response_roundtrips_through_bincode() {
+        bincode_roundtrips(HandshakeResponse::Accepted);
+        bincode_roundtrips(HandshakeResponse::Rejected {
+            core_protocol_version: PROTOCOL_VERSION,
+        });
+    }
+
+    #[test]
+    fn handshake_roundtrips_through_bincode() {
+        let handshake = Handshake {
+            protocol_version: 7,
+        };
+        let bytes = bincode::options().serialize(&handshake).unwrap();
+        let decoded: Handshake = bincode::options().deserialize(&bytes).unwrap();
+        assert_eq!(decoded, handshake);
+    }
 }
@@ -0,0 +1,186 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Measuring how long node messages take to make their way through the shard and the core.
+//! Only a sample of messages are timed, so that this costs next to nothing at high volume.
+//! A sampled message is given the time at which it was received, and once it has been
+//! handled, the time that has passed since is recorded in a [`LatencyHistogram`].
+
+use hdrhistogram::Histogram;
+use std::time::{Duration, Instant};
+
+/// Decides which messages to time, by picking every Nth one.
+#[derive(Clone, Debug)]
+pub struct LatencySampler {
+    every: u64,
+    seen: u64,
+}
+
+impl LatencySampler {
+    /// Sample every `every`th message. If this is 0, no messages are sampled.
+    pub fn new(every: u64) -> Self {
+        LatencySampler { every, seen: 0 }
+    }
+
+    /// If the next message should be timed, return the time at which it was received.
+    pub fn sample(&mut self) -> Option<Instant> {
+        if self.every == 0 {
+            return None;
+        }
+        self.seen += 1;
+        if self.seen < self.every {
+            return None;
+        }
+        self.seen = 0;
+        Some(Instant::now())
+    }
+}
+
+/// Percentiles of the latencies recorded in a window, in microseconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LatencyPercentiles {
+    /// How many latencies were recorded.
+    pub samples: u64,
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+/// Records latencies in windows of a fixed length. Percentiles are reported for the last
+/// complete window, so that they reflect how things are now rather than how they've been
+/// since we started, and don't jump around as a window fills up.
+#[derive(Debug)]
+pub struct LatencyHistogram {
+    window: Duration,
+    window_started: Instant,
+    current: Histogram<u64>,
+    last: Histogram<u64>,
+}
+
+impl LatencyHistogram {
+    /// Latencies longer than this (an hour) are recorded as this.
+    const MAX_US: u64 = 3_600_000_000;
+
+    pub fn new(window: Duration) -> Self {
+        let histogram =
+            || Histogram::new_with_bounds(1, Self::MAX_US, 3).expect("histogram bounds are valid");
+        LatencyHistogram {
+            window,
+            window_started: Instant::now(),
+            current: histogram(),
+            last: histogram(),
+        }
+    }
+
+    /// Record the time that has passed since a sampled message was received.
+    pub fn record_since(&mut self, received_at: Instant) {
+        let now = Instant::now();
+        self.record_at(now.saturating_duration_since(received_at), now);
+    }
+
+    fn record_at(&mut self, latency: Duration, now: Instant) {
+        self.roll_window(now);
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        self.current.saturating_record(micros.max(1));
+    }
+
+    /// Percentiles of the latencies recorded in the last complete window.
+    pub fn percentiles(&mut self) -> LatencyPercentiles {
+        self.percentiles_at(Instant::now())
+    }
+
+    fn percentiles_at(&mut self, now: Instant) -> LatencyPercentiles {
+        self.roll_window(now);
+        if self.last.is_empty() {
+            return LatencyPercentiles::default();
+        }
+        LatencyPercentiles {
+            samples: self.last.len(),
+            p50_us: self.last.value_at_quantile(0.5),
+            p90_us: self.last.value_at_quantile(0.9),
+            p99_us: self.last.value_at_quantile(0.99),
+            max_us: self.last.max(),
+        }
+    }
+
+    /// Start a new window if the current one has come to an end.
+    fn roll_window(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.window_started);
+        if elapsed < self.window {
+            return;
+        }
+        std::mem::swap(&mut self.current, &mut self.last);
+        self.current.reset();
+        // If a whole window has passed since the current one ended, nothing was recorded in it:
+        if elapsed >= self.window * 2 {
+            self.last.reset();
+        }
+        self.window_started = now;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sampler_picks_every_nth_message() {
+        let mut sampler = LatencySampler::new(3);
+        let sampled: Vec<bool> = (0..7).map(|_| sampler.sample().is_some()).collect();
+        assert_eq!(sampled, vec![false, false, true, false, false, true, false]);
+
+        let mut sampler = LatencySampler::new(0);
+        assert!((0..10).all(|_| sampler.sample().is_none()));
+    }
+
+    #[test]
+    fn percentiles_are_of_the_last_complete_window() {
+        let start = Instant::now();
+        let window = Duration::from_secs(10);
+        let mut histogram = LatencyHistogram::new(window);
+        histogram.window_started = start;
+
+        for ms in 1..=100 {
+            histogram.record_at(Duration::from_millis(ms), start);
+        }
+        // Nothing is reported until the window is complete:
+        assert_eq!(
+            histogram.percentiles_at(start),
+            LatencyPercentiles::default()
+        );
+
+        let next_window = start + window;
+        histogram.record_at(Duration::from_secs(5), next_window);
+        let percentiles = histogram.percentiles_at(next_window);
+        assert_eq!(percentiles.samples, 100);
+        // Values are accurate to 3 significant figures:
+        assert!((49_900..=50_100).contains(&percentiles.p50_us));
+        assert!((98_900..=99_100).contains(&percentiles.p99_us));
+        assert!((99_900..=100_100).contains(&percentiles.max_us));
+
+        // Once that window is over, the one after is reported:
+        let percentiles = histogram.percentiles_at(next_window + window);
+        assert_eq!(percentiles.samples, 1);
+        assert!((4_990_000..=5_010_000).contains(&percentiles.max_us));
+
+        // If nothing is recorded for a while, there's nothing to report:
+        assert_eq!(
+            histogram.percentiles_at(next_window + window * 3),
+            LatencyPercentiles::default()
+        );
+    }
+}
@@ -15,13 +15,17 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 pub mod byte_size;
+pub mod config_file;
 pub mod http_utils;
 pub mod id_type;
 pub mod internal_messages;
+pub mod latency;
+pub mod logging;
 pub mod node_message;
 pub mod node_types;
 pub mod ready_chunks_all;
 pub mod rolling_total;
+pub mod runtime;
 pub mod time;
 pub mod ws_client;
 
@@ -0,0 +1,251 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::sync::RwLock;
+
+use log::{LevelFilter, Log, Metadata, Record};
+use simple_logger::SimpleLogger;
+
+/// The environment variable that per-module log levels are read from.
+pub const LOG_FILTER_ENV_VAR: &str = "RUST_LOG";
+
+/// Log levels parsed from a comma separated list of directives like
+/// `telemetry_core::aggregator=debug,info`, in the style of `env_logger`. A directive
+/// without a module name sets the default level, and the last directive for any given
+/// module wins.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LogFilter {
+    pub default_level: Option<LevelFilter>,
+    pub module_levels: Vec<(String, LevelFilter)>,
+}
+
+/// A directive in a log filter that we couldn't make sense of.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("Invalid log directive '{0}'; expected '<level>' or '<module>=<level>'")]
+pub struct InvalidLogDirective(pub String);
+
+impl LogFilter {
+    /// Parse a filter, returning the directives that we couldn't understand alongside it
+    /// so that they can be reported once logging has started.
+    pub fn parse(s: &str) -> (LogFilter, Vec<InvalidLogDirective>) {
+        let mut filter = LogFilter::default();
+        let mut invalid = Vec::new();
+
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => match level.trim().parse() {
+                    Ok(level) if !module.trim().is_empty() => {
+                        let module = module.trim();
+                        filter.module_levels.retain(|(m, _)| m != module);
+                        filter.module_levels.push((module.to_owned(), level));
+                    }
+                    _ => invalid.push(InvalidLogDirective(directive.to_owned())),
+                },
+                None => match directive.parse() {
+                    Ok(level) => filter.default_level = Some(level),
+                    // A bare module name enables all logging for it, as with `env_logger`:
+                    Err(_) if is_module_path(directive) => {
+                        filter.module_levels.retain(|(m, _)| m != directive);
+                        filter
+                            .module_levels
+                            .push((directive.to_owned(), LevelFilter::Trace));
+                    }
+                    Err(_) => invalid.push(InvalidLogDirective(directive.to_owned())),
+                },
+            }
+        }
+
+        (filter, invalid)
+    }
+
+    /// The level that logs from the module (or log target) given are filtered at. The
+    /// longest module that the target falls within wins, falling back to the default level.
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.module_levels
+            .iter()
+            .filter(|(module, _)| is_within_module(target, module))
+            .max_by_key(|(module, _)| module.len())
+            .map(|(_, level)| *level)
+            .or(self.default_level)
+            .unwrap_or(LevelFilter::Off)
+    }
+
+    /// The most verbose level that anything is logged at.
+    fn max_level(&self) -> LevelFilter {
+        self.module_levels
+            .iter()
+            .map(|(_, level)| *level)
+            .chain(self.default_level)
+            .max()
+            .unwrap_or(LevelFilter::Off)
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let directives = self
+            .module_levels
+            .iter()
+            .map(|(module, level)| format!("{module}={}", level.as_str().to_lowercase()))
+            .chain(self.default_level.map(|l| l.as_str().to_lowercase()));
+        f.write_str(&directives.collect::<Vec<_>>().join(","))
+    }
+}
+
+fn is_within_module(target: &str, module: &str) -> bool {
+    match target.strip_prefix(module) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+fn is_module_path(s: &str) -> bool {
+    s.split("::")
+        .all(|part| !part.is_empty() && part.chars().all(|c| c.is_alphanumeric() || c == '_'))
+}
+
+/// The filter that logs are currently subject to. This can be changed at runtime.
+static FILTER: RwLock<LogFilter> = RwLock::new(LogFilter {
+    default_level: None,
+    module_levels: Vec::new(),
+});
+
+/// Hands logs that pass the current [`FILTER`] to a logger which doesn't filter them.
+struct FilteredLogger(SimpleLogger);
+
+impl Log for FilteredLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= FILTER.read().unwrap().level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.0.log(record)
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+/// Start logging to stdout. Everything is logged at `default_level` unless the
+/// `RUST_LOG` environment variable says otherwise, either by giving a different default
+/// level or by setting the level for specific modules (for example
+/// `RUST_LOG=telemetry_core::aggregator=debug,info`).
+pub fn init(default_level: LevelFilter) {
+    let (mut filter, invalid) = std::env::var(LOG_FILTER_ENV_VAR)
+        .map(|s| LogFilter::parse(&s))
+        .unwrap_or_default();
+    filter.default_level = filter.default_level.or(Some(default_level));
+    set_filter(filter);
+
+    let logger = SimpleLogger::new().with_level(LevelFilter::Trace);
+    log::set_boxed_logger(Box::new(FilteredLogger(logger)))
+        .expect("Must be able to start a logger");
+
+    for e in invalid {
+        log::warn!("Ignoring {LOG_FILTER_ENV_VAR}: {e}");
+    }
+}
+
+/// The filter that logs are currently subject to.
+pub fn filter() -> LogFilter {
+    FILTER.read().unwrap().clone()
+}
+
+/// Change the levels that things are logged at. The default level is left alone if the
+/// new filter doesn't give one.
+pub fn set_filter(mut filter: LogFilter) {
+    let mut current = FILTER.write().unwrap();
+    filter.default_level = filter.default_level.or(current.default_level);
+    log::set_max_level(filter.max_level());
+    *current = filter;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_module_and_default_levels() {
+        let (filter, invalid) = LogFilter::parse("telemetry_core::aggregator=debug,info");
+        assert!(invalid.is_empty());
+        assert_eq!(
+            filter,
+            LogFilter {
+                default_level: Some(LevelFilter::Info),
+                module_levels: vec![("telemetry_core::aggregator".to_owned(), LevelFilter::Debug)],
+            }
+        );
+    }
+
+    #[test]
+    fn later_directives_win() {
+        let (filter, _) = LogFilter::parse("warn, foo=debug, foo=error, bar, info");
+        assert_eq!(filter.default_level, Some(LevelFilter::Info));
+        assert_eq!(
+            filter.module_levels,
+            vec![
+                ("foo".to_owned(), LevelFilter::Error),
+                ("bar".to_owned(), LevelFilter::Trace)
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_directives_are_returned() {
+        let (filter, invalid) = LogFilter::parse("foo=loud,=debug,a b,,debug");
+        assert_eq!(filter.default_level, Some(LevelFilter::Debug));
+        assert!(filter.module_levels.is_empty());
+        assert_eq!(
+            invalid,
+            vec![
+                InvalidLogDirective("foo=loud".to_owned()),
+                InvalidLogDirective("=debug".to_owned()),
+                InvalidLogDirective("a b".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn most_specific_module_level_wins() {
+        let (filter, _) = LogFilter::parse("warn,telemetry_core=info,telemetry_core::state=trace");
+        assert_eq!(
+            filter.level_for("telemetry_core::state::chain"),
+            LevelFilter::Trace
+        );
+        assert_eq!(
+            filter.level_for("telemetry_core::state"),
+            LevelFilter::Trace
+        );
+        assert_eq!(
+            filter.level_for("telemetry_core::aggregator"),
+            LevelFilter::Info
+        );
+        assert_eq!(filter.level_for("telemetry_core_extra"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("hyper"), LevelFilter::Warn);
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn filters_display_as_directives() {
+        let (filter, _) = LogFilter::parse("foo::bar=debug,warn");
+        assert_eq!(filter.to_string(), "foo::bar=debug,warn");
+        assert_eq!(LogFilter::parse(&filter.to_string()).0, filter);
+    }
+}
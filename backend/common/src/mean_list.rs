@@ -17,14 +17,24 @@
 use num_traits::{Float, Zero};
 use std::ops::AddAssign;
 
+/// How many means a list holds unless it's told otherwise.
+const DEFAULT_LEN: usize = 20;
+/// Means stop being combined once each one covers this many values. After that, the oldest
+/// mean is dropped to make room for each new one.
+const MAX_TICKS_PER_MEAN: u8 = 32;
+
+/// A series of the means of the values pushed into it, holding at most a fixed number of them.
+/// Each mean starts out covering a single value. Whenever the list fills up, neighbouring means
+/// are averaged together so that each covers twice as many values as before, and so the list
+/// covers twice the history in the same space.
 pub struct MeanList<T>
 where
     T: Float + AddAssign + Zero + From<u8>,
 {
     period_sum: T,
     period_count: u8,
-    mean_index: u8,
-    means: [T; 20],
+    mean_index: usize,
+    means: Box<[T]>,
     ticks_per_mean: u8,
 }
 
@@ -33,26 +43,31 @@ where
     T: Float + AddAssign + Zero + From<u8>,
 {
     fn default() -> MeanList<T> {
+        MeanList::new(DEFAULT_LEN)
+    }
+}
+
+impl<T> MeanList<T>
+where
+    T: Float + AddAssign + Zero + From<u8>,
+{
+    /// Create a list holding at most `len` means (and at least one).
+    pub fn new(len: usize) -> MeanList<T> {
         MeanList {
             period_sum: T::zero(),
             period_count: 0,
             mean_index: 0,
-            means: [T::zero(); 20],
+            means: vec![T::zero(); len.max(1)].into_boxed_slice(),
             ticks_per_mean: 1,
         }
     }
-}
 
-impl<T> MeanList<T>
-where
-    T: Float + AddAssign + Zero + From<u8>,
-{
     pub fn slice(&self) -> &[T] {
-        &self.means[..usize::from(self.mean_index)]
+        &self.means[..self.mean_index]
     }
 
     pub fn push(&mut self, val: T) -> bool {
-        if self.mean_index == 20 && self.ticks_per_mean < 32 {
+        if self.mean_index == self.means.len() && self.ticks_per_mean < MAX_TICKS_PER_MEAN {
             self.squash_means();
         }
 
@@ -70,11 +85,11 @@ where
     fn push_mean(&mut self) {
         let mean = self.period_sum / std::convert::From::from(self.period_count);
 
-        if self.mean_index == 20 && self.ticks_per_mean == 32 {
+        if self.mean_index == self.means.len() {
             self.means.rotate_left(1);
-            self.means[19] = mean;
+            self.means[self.mean_index - 1] = mean;
         } else {
-            self.means[usize::from(self.mean_index)] = mean;
+            self.means[self.mean_index] = mean;
             self.mean_index += 1;
         }
 
@@ -83,13 +98,90 @@ where
     }
 
     fn squash_means(&mut self) {
+        let len = self.means.len();
+        let prev_ticks_per_mean = self.ticks_per_mean;
         self.ticks_per_mean *= 2;
-        self.mean_index = 10;
+        self.mean_index = len / 2;
 
-        for i in 0..10 {
+        for i in 0..len / 2 {
             let i2 = i * 2;
 
             self.means[i] = (self.means[i2] + self.means[i2 + 1]) / std::convert::From::from(2)
         }
+
+        // With an odd number of means, the newest has nothing to be combined with. Rather than
+        // losing it, it becomes the first half of the next mean. This only happens when a mean
+        // has just been pushed, so there's nothing else in the current period.
+        if len % 2 == 1 {
+            self.period_sum = self.means[len - 1] * std::convert::From::from(prev_ticks_per_mean);
+            self.period_count = prev_ticks_per_mean;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn push_all(list: &mut MeanList<f64>, vals: impl IntoIterator<Item = u8>) {
+        for val in vals {
+            list.push(f64::from(val));
+        }
+    }
+
+    #[test]
+    fn means_are_combined_when_full() {
+        let mut list = MeanList::new(4);
+        push_all(&mut list, 1..=4);
+        assert_eq!(list.slice(), &[1.0, 2.0, 3.0, 4.0]);
+
+        // The next value halves the list, and takes two values to make a mean from now on:
+        assert!(!list.push(5.0));
+        assert_eq!(list.slice(), &[1.5, 3.5]);
+        assert!(list.push(6.0));
+        assert_eq!(list.slice(), &[1.5, 3.5, 5.5]);
+        push_all(&mut list, 7..=8);
+        assert_eq!(list.slice(), &[1.5, 3.5, 5.5, 7.5]);
+    }
+
+    #[test]
+    fn odd_lengths_carry_the_newest_mean_over() {
+        let mut list = MeanList::new(3);
+        push_all(&mut list, 1..=3);
+        assert_eq!(list.slice(), &[1.0, 2.0, 3.0]);
+
+        // 3 has nothing to pair with, so it's combined with the next value instead:
+        assert!(list.push(4.0));
+        assert_eq!(list.slice(), &[1.5, 3.5]);
+
+        // A list of one mean keeps folding new values into it:
+        let mut list = MeanList::new(1);
+        push_all(&mut list, [1, 3]);
+        assert_eq!(list.slice(), &[2.0]);
+        push_all(&mut list, [5, 7]);
+        assert_eq!(list.slice(), &[4.0]);
+    }
+
+    #[test]
+    fn oldest_means_are_dropped_once_means_are_wide_enough() {
+        let mut list = MeanList::new(2);
+        // Enough values for each mean to cover 32 of them:
+        push_all(&mut list, (0..64).map(|_| 1));
+        assert_eq!(list.slice(), &[1.0, 1.0]);
+
+        // From here, each new mean pushes the oldest one out:
+        push_all(&mut list, (0..32).map(|_| 3));
+        assert_eq!(list.slice(), &[1.0, 3.0]);
+        push_all(&mut list, (0..32).map(|_| 5));
+        assert_eq!(list.slice(), &[3.0, 5.0]);
+    }
+
+    #[test]
+    fn default_length_is_unchanged() {
+        let mut list = MeanList::<f64>::default();
+        push_all(&mut list, 1..=20);
+        assert_eq!(list.slice().len(), 20);
+        list.push(21.0);
+        assert_eq!(list.slice().len(), 10);
     }
 }
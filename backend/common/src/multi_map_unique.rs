@@ -24,6 +24,12 @@ pub struct MultiMapUnique<K, V> {
     key_to_values: HashMap<K, HashSet<V>>,
 }
 
+impl<K, V> Default for MultiMapUnique<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<K, V> MultiMapUnique<K, V> {
     /// Construct a new MultiMap
     pub fn new() -> Self {
@@ -41,6 +47,25 @@ impl<K, V> MultiMapUnique<K, V> {
         self.key_to_values.get(key)
     }
 
+    /// Return the key that a value is associated with, if any.
+    ///
+    /// ```
+    /// let mut m = common::MultiMapUnique::new();
+    ///
+    /// m.insert("a", 1);
+    /// m.insert("b", 2);
+    ///
+    /// assert_eq!(m.get_key(&1), Some(&"a"));
+    /// assert_eq!(m.get_key(&2), Some(&"b"));
+    /// assert_eq!(m.get_key(&3), None);
+    /// ```
+    pub fn get_key(&self, value: &V) -> Option<&K>
+    where
+        V: Eq + Hash,
+    {
+        self.value_to_key.get(value)
+    }
+
     /// Remove a value from the MultiMap, returning the key it was found
     /// under, if it was found at all.
     ///
@@ -18,12 +18,23 @@
 //! There is a separate JSON representation of these types, because internally we want to be
 //! able to serialize these messages to bincode, and various serde attributes aren't compatible
 //! with this, hence this separate internal representation.
+//!
+//! Nodes also send a `ts` field with each message, according to their own clocks. Shards
+//! compare it with their own clocks, and tell the core about nodes whose clocks are too far out.
 
-use crate::node_types::{Block, BlockHash, BlockNumber, NodeDetails};
+use crate::node_types::{Block, BlockHash, BlockNumber, NodeDetails, Timestamp};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 pub type NodeMessageId = u64;
 
+/// The most custom metrics that we'll keep track of for any one node. Any more
+/// than this are ignored.
+pub const MAX_CUSTOM_METRICS: usize = 32;
+
+/// How many bytes of a malformed message do we hold on to, to help work out what was wrong with it?
+const MALFORMED_SNIPPET_BYTES: usize = 256;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum NodeMessage {
     V1 { payload: Payload },
@@ -47,6 +58,27 @@ impl NodeMessage {
     }
 }
 
+/// A message from a node that could not be parsed.
+#[derive(thiserror::Error, Debug)]
+#[error("Malformed node message ({snippet}): {source}")]
+pub struct MalformedNodeMessage {
+    /// The start of the message, to give some idea of what was sent.
+    pub snippet: String,
+    /// Why the message could not be parsed.
+    pub source: serde_json::Error,
+}
+
+impl MalformedNodeMessage {
+    /// Describe a message that failed to parse, given its bytes and the parse error.
+    pub fn new(bytes: &[u8], source: serde_json::Error) -> Self {
+        let bytes = bytes.get(..MALFORMED_SNIPPET_BYTES).unwrap_or(bytes);
+        MalformedNodeMessage {
+            snippet: String::from_utf8_lossy(bytes).into_owned(),
+            source,
+        }
+    }
+}
+
 impl From<NodeMessage> for Payload {
     fn from(msg: NodeMessage) -> Payload {
         msg.into_payload()
@@ -61,6 +93,9 @@ pub enum Payload {
     NotifyFinalized(Finalized),
     AfgAuthoritySet(AfgAuthoritySet),
     HwBench(NodeHwBench),
+    AfgFinalized(AfgFinalized),
+    AfgReceivedPrevote(AfgReceived),
+    AfgReceivedPrecommit(AfgReceived),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -71,7 +106,9 @@ pub struct SystemConnected {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SystemInterval {
-    pub peers: Option<u64>,
+    /// This is passed on as reported, and so may be negative if a node
+    /// is misbehaving; it's up to the telemetry core to decide what to do.
+    pub peers: Option<i64>,
     pub txcount: Option<u64>,
     pub bandwidth_upload: Option<f64>,
     pub bandwidth_download: Option<f64>,
@@ -79,6 +116,21 @@ pub struct SystemInterval {
     pub finalized_hash: Option<BlockHash>,
     pub block: Option<Block>,
     pub used_state_cache_size: Option<f32>,
+    /// CPU usage of the node process, as a percentage.
+    pub cpu: Option<f32>,
+    /// Memory used by the node process, in KiB.
+    pub memory: Option<f32>,
+    /// Disk space used by the node, in bytes.
+    pub disk_usage: Option<f64>,
+    /// How quickly the node is reading from disk, in bytes per second.
+    pub disk_read_per_sec: Option<f64>,
+    /// How quickly the node is writing to disk, in bytes per second.
+    pub disk_write_per_sec: Option<f64>,
+    /// Whether the node says it's in the middle of a major sync.
+    pub is_major_syncing: Option<bool>,
+    /// Any other numeric values that the node reported, which we pass on to feeds
+    /// without otherwise understanding them.
+    pub custom_metrics: HashMap<String, f64>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -90,6 +142,23 @@ pub struct Finalized {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AfgAuthoritySet {
     pub authority_id: Box<str>,
+    /// The members of the authority set, as the node reports them.
+    pub authorities: Box<str>,
+    pub authority_set_id: Box<str>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AfgFinalized {
+    pub finalized_hash: BlockHash,
+    pub finalized_number: Box<str>,
+}
+
+/// A GRANDPA prevote or precommit that a node has received.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AfgReceived {
+    pub target_hash: BlockHash,
+    pub target_number: Box<str>,
+    pub voter: Option<Box<str>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -124,6 +193,89 @@ impl Payload {
     }
 }
 
+/// Parse the startup time that a node reports in `system.connected` into a unix timestamp
+/// in ms. Nodes report this as a string of milliseconds since the epoch, but RFC 3339
+/// (ISO-8601) timestamps like `2021-07-06T09:59:02.717Z` are understood too. Anything
+/// else gives `None`.
+pub fn parse_startup_time(startup_time: &str) -> Option<Timestamp> {
+    let startup_time = startup_time.trim();
+    match startup_time.parse() {
+        Ok(ms) => Some(ms),
+        Err(_) => parse_rfc3339(startup_time),
+    }
+}
+
+fn parse_rfc3339(s: &str) -> Option<Timestamp> {
+    let num = |s: &str| -> Option<i64> {
+        if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        s.parse().ok()
+    };
+
+    let (date, time) = s.split_once(['T', 't', ' '])?;
+    let mut date_parts = date.split('-');
+    let (year, month, day) = (
+        num(date_parts.next()?)?,
+        num(date_parts.next()?)?,
+        num(date_parts.next()?)?,
+    );
+
+    // The time must be followed by 'Z' or an offset from UTC like "+01:00":
+    let (time, offset_secs) = match time.strip_suffix(['Z', 'z']) {
+        Some(time) => (time, 0),
+        None => {
+            let (time, offset) = time.split_at(time.rfind(['+', '-'])?);
+            let (hours, minutes) = offset[1..].split_once(':')?;
+            let offset_secs = num(hours)? * 3600 + num(minutes)? * 60;
+            match offset.starts_with('-') {
+                true => (time, -offset_secs),
+                false => (time, offset_secs),
+            }
+        }
+    };
+    let mut time_parts = time.split(':');
+    let (hours, minutes, seconds) = (
+        num(time_parts.next()?)?,
+        num(time_parts.next()?)?,
+        time_parts.next()?,
+    );
+    // Seconds may have a fractional part, of which we keep the milliseconds:
+    let (seconds, millis) = match seconds.split_once('.') {
+        Some((seconds, fraction)) => {
+            let fraction = fraction.get(..3).unwrap_or(fraction);
+            (num(seconds)?, num(&format!("{fraction:0<3}"))?)
+        }
+        None => (num(seconds)?, 0),
+    };
+
+    if date_parts.next().is_some()
+        || time_parts.next().is_some()
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hours > 23
+        || minutes > 59
+        || seconds > 60
+    {
+        return None;
+    }
+
+    let secs = days_from_civil(year, month, day) * 86400 + hours * 3600 + minutes * 60 + seconds
+        - offset_secs;
+    (secs * 1000 + millis).try_into().ok()
+}
+
+/// The number of days since the unix epoch of a date in the (proleptic) Gregorian calendar.
+/// See <http://howardhinnant.github.io/date_algorithms.html#days_from_civil>.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -134,7 +286,7 @@ mod tests {
     // know whether things can (de)serialize to bincode or not at runtime without failing unless
     // we test the different types we want to (de)serialize ourselves. We just need to test each
     // type, not each variant.
-    fn bincode_can_serialize_and_deserialize<'de, T>(item: T)
+    fn bincode_can_serialize_and_deserialize<T>(item: T)
     where
         T: Serialize + serde::de::DeserializeOwned,
     {
@@ -142,6 +294,22 @@ mod tests {
         let _: T = bincode::deserialize(&bytes).expect("Deserialization should work");
     }
 
+    #[test]
+    fn malformed_message_snippets_are_truncated() {
+        let bytes = "é".repeat(MALFORMED_SNIPPET_BYTES);
+        let err = serde_json::from_slice::<serde_json::Value>(bytes.as_bytes()).unwrap_err();
+        let malformed = MalformedNodeMessage::new(bytes.as_bytes(), err);
+        // Each 'é' is two bytes, so we keep half of them:
+        assert_eq!(malformed.snippet, "é".repeat(MALFORMED_SNIPPET_BYTES / 2));
+
+        let err = serde_json::from_slice::<serde_json::Value>(b"{oops").unwrap_err();
+        let malformed = MalformedNodeMessage::new(b"{oops", err);
+        assert_eq!(malformed.snippet, "{oops");
+        assert!(malformed
+            .to_string()
+            .starts_with("Malformed node message ({oops): "));
+    }
+
     #[test]
     fn bincode_can_serialize_and_deserialize_node_message_system_connected() {
         bincode_can_serialize_and_deserialize(NodeMessage::V1 {
@@ -155,7 +323,10 @@ mod tests {
                     target_arch: Some("x86_64".into()),
                     target_os: Some("linux".into()),
                     target_env: Some("env".into()),
+                    commit: None,
                     validator: None,
+                    authority: false,
+                    role: Default::default(),
                     network_id: ArrayString::new(),
                     startup_time: None,
                     sysinfo: None,
@@ -177,6 +348,13 @@ mod tests {
                 finalized_hash: None,
                 block: None,
                 used_state_cache_size: None,
+                cpu: None,
+                memory: None,
+                disk_usage: None,
+                disk_read_per_sec: None,
+                disk_write_per_sec: None,
+                is_major_syncing: None,
+                custom_metrics: HashMap::from([("foo".to_owned(), 1.5)]),
             }),
         });
     }
@@ -206,6 +384,36 @@ mod tests {
         bincode_can_serialize_and_deserialize(NodeMessage::V1 {
             payload: Payload::AfgAuthoritySet(AfgAuthoritySet {
                 authority_id: "foo".into(),
+                authorities: "[\"foo\"]".into(),
+                authority_set_id: "1".into(),
+            }),
+        });
+    }
+
+    #[test]
+    fn bincode_can_serialize_and_deserialize_node_message_afg_finalized() {
+        bincode_can_serialize_and_deserialize(NodeMessage::V1 {
+            payload: Payload::AfgFinalized(AfgFinalized {
+                finalized_hash: BlockHash::zero(),
+                finalized_number: "1".into(),
+            }),
+        });
+    }
+
+    #[test]
+    fn bincode_can_serialize_and_deserialize_node_message_afg_received() {
+        bincode_can_serialize_and_deserialize(NodeMessage::V1 {
+            payload: Payload::AfgReceivedPrevote(AfgReceived {
+                target_hash: BlockHash::zero(),
+                target_number: "1".into(),
+                voter: Some("foo".into()),
+            }),
+        });
+        bincode_can_serialize_and_deserialize(NodeMessage::V1 {
+            payload: Payload::AfgReceivedPrecommit(AfgReceived {
+                target_hash: BlockHash::zero(),
+                target_number: "1".into(),
+                voter: None,
             }),
         });
     }
@@ -221,4 +429,36 @@ mod tests {
         assert_eq!(raw.hash, deserialized.hash);
         assert_eq!(raw.height, deserialized.height);
     }
+
+    #[test]
+    fn startup_time_parsed_from_millis_or_rfc3339() {
+        let expected = Some(1625565542717);
+        assert_eq!(parse_startup_time("1625565542717"), expected);
+        assert_eq!(parse_startup_time("2021-07-06T09:59:02.717Z"), expected);
+        assert_eq!(
+            parse_startup_time("2021-07-06T10:59:02.717+01:00"),
+            expected
+        );
+        assert_eq!(
+            parse_startup_time("2021-07-06T04:29:02.717123-05:30"),
+            expected
+        );
+        assert_eq!(
+            parse_startup_time("2021-07-06T09:59:02Z"),
+            Some(1625565542000)
+        );
+        assert_eq!(parse_startup_time("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(
+            parse_startup_time("2000-02-29T00:00:00Z"),
+            Some(951782400000)
+        );
+
+        // Anything we can't make sense of is ignored:
+        assert_eq!(parse_startup_time(""), None);
+        assert_eq!(parse_startup_time("yesterday"), None);
+        assert_eq!(parse_startup_time("2021-07-06"), None);
+        assert_eq!(parse_startup_time("2021-07-06T09:59:02"), None);
+        assert_eq!(parse_startup_time("2021-13-06T09:59:02Z"), None);
+        assert_eq!(parse_startup_time("1969-12-31T23:59:59Z"), None);
+    }
 }
@@ -36,15 +36,200 @@ pub struct NodeDetails {
     pub implementation: Box<str>,
     pub version: Box<str>,
     pub validator: Option<Box<str>>,
+    /// Is the node an authority (validator) on its chain?
+    pub authority: bool,
+    /// The role that the node told us it has when it connected.
+    pub role: NodeRole,
     pub network_id: NetworkId,
     pub startup_time: Option<Box<str>>,
     pub target_os: Option<Box<str>>,
     pub target_arch: Option<Box<str>>,
     pub target_env: Option<Box<str>>,
+    /// The commit that the node was built from, if it can be parsed from the version.
+    pub commit: Option<Box<str>>,
     pub sysinfo: Option<NodeSysInfo>,
     pub ip: Option<Box<str>>,
 }
 
+impl NodeDetails {
+    /// Fill in the details that can be parsed from the version string. Older nodes send their
+    /// target as part of the version rather than separately, in which case it's split off from
+    /// the version. Versions that don't follow the usual convention are left as they are.
+    pub fn parse_version(&mut self) {
+        let parts = VersionParts::parse(&self.version);
+        self.commit = parts.commit.map(Into::into);
+
+        let has_target =
+            self.target_os.is_some() || self.target_arch.is_some() || self.target_env.is_some();
+        if let (false, Some(target_arch), Some(target_os)) =
+            (has_target, parts.target_arch, parts.target_os)
+        {
+            self.target_arch = Some(target_arch.into());
+            self.target_os = Some(target_os.into());
+            self.target_env = Some(parts.target_env.unwrap_or("").into());
+            self.version = parts.version.into();
+        }
+    }
+}
+
+/// The role that a node plays on its chain.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeRole {
+    /// Full nodes, and anything else that we don't recognise.
+    #[default]
+    Full,
+    Light,
+    Authority,
+    Collator,
+}
+
+impl NodeRole {
+    /// Work out a node's role from the `authority` flag that it connects with and the role
+    /// hint that some nodes also send. Collators are often authorities too, and so a hint
+    /// that the node is a collator wins over the flag; otherwise the flag wins over the hint.
+    /// Hints we don't recognise are ignored.
+    pub fn from_connected(authority: bool, hint: Option<&str>) -> NodeRole {
+        let hint = hint.map(|hint| hint.trim().to_ascii_lowercase());
+        match hint.as_deref() {
+            Some("collator") => NodeRole::Collator,
+            _ if authority => NodeRole::Authority,
+            Some("authority" | "validator") => NodeRole::Authority,
+            Some("light") => NodeRole::Light,
+            _ => NodeRole::Full,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NodeRole::Full => "full",
+            NodeRole::Light => "light",
+            NodeRole::Authority => "authority",
+            NodeRole::Collator => "collator",
+        }
+    }
+}
+
+/// Whether a node has caught up with the rest of its chain.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeSyncState {
+    Syncing,
+    Synced,
+}
+
+impl NodeSyncState {
+    /// Work out whether a node is syncing from the major sync flag that it reports, if
+    /// any, or else from how far its best block is behind the chain's best block.
+    pub fn from_reported(
+        is_major_syncing: Option<bool>,
+        blocks_behind: BlockNumber,
+        max_blocks_behind: BlockNumber,
+    ) -> NodeSyncState {
+        match is_major_syncing {
+            Some(true) => NodeSyncState::Syncing,
+            Some(false) => NodeSyncState::Synced,
+            None if blocks_behind > max_blocks_behind => NodeSyncState::Syncing,
+            None => NodeSyncState::Synced,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NodeSyncState::Syncing => "syncing",
+            NodeSyncState::Synced => "synced",
+        }
+    }
+}
+
+/// The parts that a node version string like `2.0.0-07a1af348-aarch64-macos` is made up of.
+/// Versions are composed of the following parts:
+///
+/// ```text
+/// $version-$commit_hash-$arch-$os-$env
+/// ```
+///
+/// Where `$commit_hash` and `$env` are optional, and newer nodes send the target
+/// (`$arch-$os-$env`) separately rather than as part of the version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionParts<'a> {
+    /// The version without the target, but including the commit hash if there is one.
+    pub version: &'a str,
+    pub commit: Option<&'a str>,
+    pub target_arch: Option<&'a str>,
+    pub target_os: Option<&'a str>,
+    pub target_env: Option<&'a str>,
+}
+
+impl<'a> VersionParts<'a> {
+    /// Parse a version string. This never fails; if parts of the version can't be found, they
+    /// are left as `None` (and for versions that don't follow the convention at all, the
+    /// version is just the whole string).
+    pub fn parse(version_and_target: &'a str) -> Self {
+        let (version, target) = match split_target(version_and_target) {
+            Some((version, target)) => (version, Some(target)),
+            None => (version_and_target, None),
+        };
+
+        let commit = version
+            .rsplit_once('-')
+            .map(|(_, commit)| commit)
+            .filter(|commit| commit.len() >= 7 && commit.bytes().all(|b| b.is_ascii_hexdigit()));
+
+        let mut target = target.map(|t| t.split('-'));
+        VersionParts {
+            version,
+            commit,
+            target_arch: target.as_mut().and_then(|t| t.next()),
+            target_os: target.as_mut().and_then(|t| t.next()),
+            target_env: target.as_mut().and_then(|t| t.next()),
+        }
+    }
+}
+
+fn is_version_or_hash(name: &str) -> bool {
+    name.bytes().all(|byte| {
+        byte.is_ascii_digit()
+            || byte == b'.'
+            || byte == b'a'
+            || byte == b'b'
+            || byte == b'c'
+            || byte == b'd'
+            || byte == b'e'
+            || byte == b'f'
+    })
+}
+
+/// Split an old style version string into the version and the target (`$arch-$os-$env`).
+fn split_target(version_and_target: &str) -> Option<(&str, &str)> {
+    let mut iter = version_and_target.rsplit('-').take(3).skip(2);
+
+    // This will one of these: $arch, $commit_hash, $version
+    let item = iter.next()?;
+
+    let target_offset = if is_version_or_hash(item) {
+        item.as_ptr() as usize + item.len() + 1
+    } else {
+        item.as_ptr() as usize
+    } - version_and_target.as_ptr() as usize;
+
+    let version = version_and_target.get(0..target_offset - 1)?;
+    let target = version_and_target.get(target_offset..)?;
+
+    // Make sure that what we've found looks like a target, so that a version like
+    // `2.0.0-alpha.5-da487d19d` isn't mistaken for one with an `alpha.5-da487d19d` target:
+    let mut target_parts = target.split('-');
+    let target_arch = target_parts.next()?;
+    let target_os = target_parts.next()?;
+    if is_version_or_hash(target_arch) || is_version_or_hash(target_os) {
+        return None;
+    }
+
+    Some((version, target))
+}
+
 /// Hardware and software information for the node.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct NodeSysInfo {
@@ -80,6 +265,13 @@ pub struct NodeHwBench {
 pub struct NodeStats {
     pub peers: u64,
     pub txcount: u64,
+    /// How many blocks the best block is ahead of the finalized block,
+    /// or `None` if we don't know about a finalized block yet.
+    pub finality_lag: Option<BlockNumber>,
+    /// Is the reported peer count implausible (eg negative, or zero while synced)?
+    pub implausible_peers: bool,
+    /// Whether the node is still syncing, or `None` if it hasn't told us enough to know.
+    pub sync_state: Option<NodeSyncState>,
 }
 
 // # A note about serialization/deserialization of types in this file:
@@ -96,9 +288,26 @@ impl Serialize for NodeStats {
     where
         S: Serializer,
     {
-        let mut tup = serializer.serialize_tuple(2)?;
+        // Trailing optional values are omitted until they're needed; the finality lag
+        // is null if we need to send a later value but don't know it yet.
+        let len = match (self.finality_lag, self.implausible_peers, self.sync_state) {
+            (_, _, Some(_)) => 5,
+            (_, true, None) => 4,
+            (Some(_), false, None) => 3,
+            (None, false, None) => 2,
+        };
+        let mut tup = serializer.serialize_tuple(len)?;
         tup.serialize_element(&self.peers)?;
         tup.serialize_element(&self.txcount)?;
+        if len > 2 {
+            tup.serialize_element(&self.finality_lag)?;
+        }
+        if len > 3 {
+            tup.serialize_element(&self.implausible_peers)?;
+        }
+        if len > 4 {
+            tup.serialize_element(&self.sync_state)?;
+        }
         tup.end()
     }
 }
@@ -108,8 +317,39 @@ impl<'de> Deserialize<'de> for NodeStats {
     where
         D: serde::Deserializer<'de>,
     {
-        let (peers, txcount) = <(u64, u64)>::deserialize(deserializer)?;
-        Ok(NodeStats { peers, txcount })
+        struct NodeStatsVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for NodeStatsVisitor {
+            type Value = NodeStats;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a sequence of 2 to 5 node stats")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::SeqAccess<'de>,
+            {
+                let peers = seq
+                    .next_element()?
+                    .ok_or_else(|| serde::de::Error::invalid_length(0, &self))?;
+                let txcount = seq
+                    .next_element()?
+                    .ok_or_else(|| serde::de::Error::invalid_length(1, &self))?;
+                let finality_lag = seq.next_element()?.flatten();
+                let implausible_peers = seq.next_element()?.unwrap_or(false);
+                let sync_state = seq.next_element()?.flatten();
+                Ok(NodeStats {
+                    peers,
+                    txcount,
+                    finality_lag,
+                    implausible_peers,
+                    sync_state,
+                })
+            }
+        }
+
+        deserializer.deserialize_seq(NodeStatsVisitor)
     }
 }
 
@@ -119,6 +359,15 @@ pub struct NodeIO {
     pub used_state_cache_size: MeanList<f32>,
 }
 
+impl NodeIO {
+    /// Keep at most `history_len` values of each series.
+    pub fn new(history_len: usize) -> Self {
+        NodeIO {
+            used_state_cache_size: MeanList::new(history_len),
+        }
+    }
+}
+
 impl Serialize for NodeIO {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -156,6 +405,32 @@ pub struct NodeHardware {
     pub download: MeanList<f64>,
     /// Stampchange uses means
     pub chart_stamps: MeanList<f64>,
+    /// CPU usage uses means
+    pub cpu: MeanList<f32>,
+    /// Memory usage uses means
+    pub memory: MeanList<f32>,
+    /// Disk usage uses means
+    pub disk_usage: MeanList<f64>,
+    /// Disk read rate uses means
+    pub disk_read: MeanList<f64>,
+    /// Disk write rate uses means
+    pub disk_write: MeanList<f64>,
+}
+
+impl NodeHardware {
+    /// Keep at most `history_len` values of each series.
+    pub fn new(history_len: usize) -> Self {
+        NodeHardware {
+            upload: MeanList::new(history_len),
+            download: MeanList::new(history_len),
+            chart_stamps: MeanList::new(history_len),
+            cpu: MeanList::new(history_len),
+            memory: MeanList::new(history_len),
+            disk_usage: MeanList::new(history_len),
+            disk_read: MeanList::new(history_len),
+            disk_write: MeanList::new(history_len),
+        }
+    }
 }
 
 impl Serialize for NodeHardware {
@@ -163,11 +438,17 @@ impl Serialize for NodeHardware {
     where
         S: Serializer,
     {
-        let mut tup = serializer.serialize_tuple(3)?;
+        let mut tup = serializer.serialize_tuple(8)?;
         // These are "one-way": we can't deserialize again from them to MeanLists:
         tup.serialize_element(self.upload.slice())?;
         tup.serialize_element(self.download.slice())?;
         tup.serialize_element(self.chart_stamps.slice())?;
+        // Appended so that feeds which only know about the above keep working:
+        tup.serialize_element(self.cpu.slice())?;
+        tup.serialize_element(self.memory.slice())?;
+        tup.serialize_element(self.disk_usage.slice())?;
+        tup.serialize_element(self.disk_read.slice())?;
+        tup.serialize_element(self.disk_write.slice())?;
         tup.end()
     }
 }
@@ -259,3 +540,206 @@ impl<'de> Deserialize<'de> for BlockDetails {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    type Target<'a> = (&'a str, &'a str, &'a str);
+
+    fn parts(version_and_target: &str) -> (&str, Option<&str>, Option<Target<'_>>) {
+        let parts = VersionParts::parse(version_and_target);
+        let target = parts.target_arch.map(|arch| {
+            (
+                arch,
+                parts.target_os.unwrap(),
+                parts.target_env.unwrap_or(""),
+            )
+        });
+        (parts.version, parts.commit, target)
+    }
+
+    #[test]
+    fn version_parts_are_parsed() {
+        assert_eq!(
+            parts("2.0.0-07a1af348-aarch64-macos"),
+            (
+                "2.0.0-07a1af348",
+                Some("07a1af348"),
+                Some(("aarch64", "macos", ""))
+            )
+        );
+        assert_eq!(
+            parts("0.9.17-75dd6c7d0-x86_64-linux-gnu"),
+            (
+                "0.9.17-75dd6c7d0",
+                Some("75dd6c7d0"),
+                Some(("x86_64", "linux", "gnu"))
+            )
+        );
+        assert_eq!(
+            parts("0.9.17-75dd6c7d0-x86_64-linux"),
+            (
+                "0.9.17-75dd6c7d0",
+                Some("75dd6c7d0"),
+                Some(("x86_64", "linux", ""))
+            )
+        );
+        assert_eq!(
+            parts("0.9.17-x86_64-linux-gnu"),
+            ("0.9.17", None, Some(("x86_64", "linux", "gnu")))
+        );
+        assert_eq!(
+            parts("0.9.17-x86_64-linux"),
+            ("0.9.17", None, Some(("x86_64", "linux", "")))
+        );
+        assert_eq!(
+            parts("2.0.0-alpha.5-da487d19d-x86_64-linux"),
+            (
+                "2.0.0-alpha.5-da487d19d",
+                Some("da487d19d"),
+                Some(("x86_64", "linux", ""))
+            )
+        );
+    }
+
+    #[test]
+    fn versions_without_a_target_are_parsed() {
+        assert_eq!(
+            parts("0.9.17-75dd6c7d0"),
+            ("0.9.17-75dd6c7d0", Some("75dd6c7d0"), None)
+        );
+        assert_eq!(
+            parts("2.0.0-alpha.5-da487d19d"),
+            ("2.0.0-alpha.5-da487d19d", Some("da487d19d"), None)
+        );
+        assert_eq!(parts("1.2.3"), ("1.2.3", None, None));
+    }
+
+    #[test]
+    fn unconventional_versions_are_left_alone() {
+        for version in [
+            "",
+            "a",
+            "a-b",
+            "v1.0 (custom build)",
+            "1.0.0-rc1",
+            "1.0.0-abc",
+        ] {
+            assert_eq!(parts(version), (version, None, None), "{version}");
+        }
+    }
+
+    #[test]
+    fn node_details_target_is_only_parsed_from_version_if_not_given() {
+        let mut details = NodeDetails {
+            chain: "".into(),
+            name: "".into(),
+            implementation: "".into(),
+            version: "2.0.0-07a1af348-aarch64-macos".into(),
+            validator: None,
+            authority: false,
+            role: NodeRole::Full,
+            network_id: NetworkId::new(),
+            startup_time: None,
+            target_os: None,
+            target_arch: None,
+            target_env: None,
+            commit: None,
+            sysinfo: None,
+            ip: None,
+        };
+        details.parse_version();
+        assert_eq!(&*details.version, "2.0.0-07a1af348");
+        assert_eq!(details.commit.as_deref(), Some("07a1af348"));
+        assert_eq!(details.target_arch.as_deref(), Some("aarch64"));
+        assert_eq!(details.target_os.as_deref(), Some("macos"));
+
+        // The target that we already know about isn't overwritten:
+        details.version = "3.0.0-a1b2c3d4e-x86_64-linux".into();
+        details.parse_version();
+        assert_eq!(&*details.version, "3.0.0-a1b2c3d4e-x86_64-linux");
+        assert_eq!(details.commit.as_deref(), Some("a1b2c3d4e"));
+        assert_eq!(details.target_arch.as_deref(), Some("aarch64"));
+    }
+
+    #[test]
+    fn node_role_from_connected() {
+        let cases = [
+            (false, None, NodeRole::Full),
+            (true, None, NodeRole::Authority),
+            (false, Some("full"), NodeRole::Full),
+            (false, Some("Light"), NodeRole::Light),
+            (false, Some("authority"), NodeRole::Authority),
+            (false, Some("validator"), NodeRole::Authority),
+            (false, Some("collator"), NodeRole::Collator),
+            (true, Some("collator"), NodeRole::Collator),
+            (true, Some("light"), NodeRole::Authority),
+            (true, Some("full"), NodeRole::Authority),
+            (false, Some("archive"), NodeRole::Full),
+            (false, Some(""), NodeRole::Full),
+        ];
+        for (authority, hint, role) in cases {
+            assert_eq!(
+                NodeRole::from_connected(authority, hint),
+                role,
+                "authority: {authority}, hint: {hint:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn node_sync_state_from_reported() {
+        // An explicit flag wins; otherwise nodes more than a couple of blocks behind are syncing:
+        assert_eq!(
+            NodeSyncState::from_reported(Some(true), 0, 2),
+            NodeSyncState::Syncing
+        );
+        assert_eq!(
+            NodeSyncState::from_reported(Some(false), 1000, 2),
+            NodeSyncState::Synced
+        );
+        assert_eq!(
+            NodeSyncState::from_reported(None, 3, 2),
+            NodeSyncState::Syncing
+        );
+        assert_eq!(
+            NodeSyncState::from_reported(None, 2, 2),
+            NodeSyncState::Synced
+        );
+    }
+
+    #[test]
+    fn node_stats_sync_state_serialized_last() {
+        let stats = NodeStats {
+            peers: 4,
+            txcount: 1,
+            finality_lag: None,
+            implausible_peers: false,
+            sync_state: Some(NodeSyncState::Syncing),
+        };
+        let json = serde_json::to_string(&stats).unwrap();
+        assert_eq!(json, r#"[4,1,null,false,"syncing"]"#);
+        assert_eq!(serde_json::from_str::<NodeStats>(&json).unwrap(), stats);
+
+        // Older messages without it still deserialize:
+        let stats: NodeStats = serde_json::from_str("[4,1,3]").unwrap();
+        assert_eq!(stats.finality_lag, Some(3));
+        assert_eq!(stats.sync_state, None);
+    }
+
+    #[test]
+    fn node_role_serializes_as_lowercase() {
+        for role in [
+            NodeRole::Full,
+            NodeRole::Light,
+            NodeRole::Authority,
+            NodeRole::Collator,
+        ] {
+            assert_eq!(
+                serde_json::to_string(&role).unwrap(),
+                format!("\"{}\"", role.as_str())
+            );
+        }
+    }
+}
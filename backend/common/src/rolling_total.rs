@@ -27,6 +27,12 @@ pub struct RollingTotalBuilder<Time: TimeSource = SystemTimeSource> {
     time_source: Time,
 }
 
+impl Default for RollingTotalBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RollingTotalBuilder {
     /// Build a [`RollingTotal`] struct. By default,
     /// the window_size is 10s, the granularity is 1s,
@@ -219,6 +225,7 @@ mod test {
     }
 
     #[test]
+    #[allow(clippy::into_iter_on_ref)]
     fn times_grouped_by_granularity_spacing() {
         let start_time = Instant::now();
         let granularity = Duration::from_secs(1);
@@ -0,0 +1,90 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Helpers for configuring the tokio runtimes that the core and shards run on.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Name the threads that a runtime starts `{name}-{n}` (e.g. `tel-core-worker-3`), so that
+/// they can be told apart when profiling, and if `pin_to_cpus` is true, try to pin each of
+/// the first `worker_threads` threads started (the runtime's workers) to its own CPU.
+///
+/// Pinning is best-effort; a warning is logged if it can't be done on this platform.
+pub fn configure_worker_threads<'a>(
+    builder: &'a mut tokio::runtime::Builder,
+    name: &'static str,
+    worker_threads: usize,
+    pin_to_cpus: bool,
+) -> &'a mut tokio::runtime::Builder {
+    let next_thread_id = AtomicUsize::new(0);
+    builder.thread_name_fn(move || {
+        let id = next_thread_id.fetch_add(1, Ordering::Relaxed);
+        format!("{name}-{id}")
+    });
+
+    if !pin_to_cpus {
+        return builder;
+    }
+
+    let core_ids = match core_affinity::get_core_ids() {
+        Some(core_ids) if !core_ids.is_empty() => core_ids,
+        _ => {
+            log::warn!("Cannot find the CPUs on this machine; worker threads won't be pinned");
+            return builder;
+        }
+    };
+
+    // Workers are started first, so pin threads in the order that they start, and
+    // leave any later (blocking) threads alone:
+    let core_ids = Arc::new(core_ids);
+    let next_worker_id = AtomicUsize::new(0);
+    builder.on_thread_start(move || {
+        let id = next_worker_id.fetch_add(1, Ordering::Relaxed);
+        if id >= worker_threads {
+            return;
+        }
+        let core_id = core_ids[id % core_ids.len()];
+        if !core_affinity::set_for_current(core_id) {
+            log::warn!("Cannot pin worker thread {id} to CPU {}", core_id.id);
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn worker_threads_are_named() {
+        for pin_to_cpus in [false, true] {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            let runtime = configure_worker_threads(&mut builder, "test-worker", 2, pin_to_cpus)
+                .worker_threads(2)
+                .build()
+                .unwrap();
+
+            let name = runtime
+                .block_on(
+                    runtime
+                        .spawn(async { std::thread::current().name().map(|name| name.to_owned()) }),
+                )
+                .unwrap()
+                .unwrap();
+            assert!(name.starts_with("test-worker-"), "unexpected name {name}");
+        }
+    }
+}
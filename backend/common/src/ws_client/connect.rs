@@ -14,10 +14,13 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 use super::on_close::OnClose;
+use crate::http_utils::set_tcp_opts;
 use futures::{channel, StreamExt};
+use soketto::handshake::client::Header;
 use soketto::handshake::{Client, ServerResponse};
 use std::io;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 use tokio_rustls::rustls::{OwnedTrustAnchor, ServerName};
@@ -115,7 +118,7 @@ impl Connection {
                 let msg = match message_data {
                     soketto::Data::Binary(_) => Ok(RecvMessage::Binary(data)),
                     soketto::Data::Text(_) => String::from_utf8(data)
-                        .map(|s| RecvMessage::Text(s))
+                        .map(RecvMessage::Text)
                         .map_err(|e| e.into()),
                 };
 
@@ -235,21 +238,76 @@ pub enum ConnectError {
 
 /// Establish a websocket connection that you can send and receive messages from.
 pub async fn connect(uri: &http::Uri) -> Result<Connection, ConnectError> {
+    connect_with_protocols(uri, &[]).await
+}
+
+/// Establish a websocket connection, asking the server for one of the given subprotocols.
+pub async fn connect_with_protocols(
+    uri: &http::Uri,
+    protocols: &[&str],
+) -> Result<Connection, ConnectError> {
+    connect_with_opts(uri, protocols, &[], None).await
+}
+
+/// Establish a websocket connection, sending the given (name, value) headers with the request.
+pub async fn connect_with_headers(
+    uri: &http::Uri,
+    headers: &[(&str, &str)],
+) -> Result<Connection, ConnectError> {
+    connect_with_opts(uri, &[], headers, None).await
+}
+
+/// Establish a websocket connection, enabling TCP keepalive on it so that the connection is
+/// probed after it's been idle for the time given.
+pub async fn connect_with_tcp_keepalive(
+    uri: &http::Uri,
+    tcp_keepalive: Option<Duration>,
+) -> Result<Connection, ConnectError> {
+    connect_with_opts(uri, &[], &[], tcp_keepalive).await
+}
+
+/// Establish a websocket connection, sending the given (name, value) headers with the request
+/// and enabling TCP keepalive on it as [`connect_with_tcp_keepalive`] does.
+pub async fn connect_with_headers_and_tcp_keepalive(
+    uri: &http::Uri,
+    headers: &[(&str, &str)],
+    tcp_keepalive: Option<Duration>,
+) -> Result<Connection, ConnectError> {
+    connect_with_opts(uri, &[], headers, tcp_keepalive).await
+}
+
+async fn connect_with_opts(
+    uri: &http::Uri,
+    protocols: &[&str],
+    headers: &[(&str, &str)],
+    tcp_keepalive: Option<Duration>,
+) -> Result<Connection, ConnectError> {
     let host = uri.host().unwrap_or("127.0.0.1");
     let scheme = uri.scheme_str().unwrap_or("ws");
     let mut port = 80;
     if scheme == "https" || scheme == "wss" {
         port = 443
     }
-    let path = uri.path();
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
     let port = uri.port_u16().unwrap_or(port);
     let socket = TcpStream::connect((host, port)).await?;
-    socket.set_nodelay(true).expect("socket set_nodelay failed");
+    set_tcp_opts(&socket, tcp_keepalive)?;
     // wrap TCP stream with TLS if schema is https or wss
     let socket = may_connect_tls(socket, host, scheme == "https" || scheme == "wss").await?;
 
     // Establish a WS connection:
-    let mut client = Client::new(socket.compat(), host, &path);
+    let headers: Vec<_> = headers
+        .iter()
+        .map(|&(name, value)| Header {
+            name,
+            value: value.as_bytes(),
+        })
+        .collect();
+    let mut client = Client::new(socket.compat(), host, path);
+    for protocol in protocols {
+        client.add_protocol(protocol);
+    }
+    client.set_headers(&headers);
     let (ws_to_connection, ws_from_connection) = match client.handshake().await? {
         ServerResponse::Accepted { .. } => client.into_builder().finish(),
         ServerResponse::Redirect { status_code, .. } => {
@@ -292,3 +350,22 @@ async fn may_connect_tls(
     let socket = connector.connect(domain, socket).await?;
     Ok(Box::new(socket))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn outbound_connections_have_tcp_options_set() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        set_tcp_opts(&socket, Some(Duration::from_secs(30))).unwrap();
+
+        let socket = socket2::SockRef::from(&socket);
+        assert!(socket.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+    }
+}
@@ -23,6 +23,9 @@ mod receiver;
 /// The channel based send interface
 mod sender;
 
-pub use connect::{connect, ConnectError, Connection, RawReceiver, RawSender};
+pub use connect::{
+    connect, connect_with_headers, connect_with_headers_and_tcp_keepalive, connect_with_protocols,
+    connect_with_tcp_keepalive, ConnectError, Connection, RawReceiver, RawSender,
+};
 pub use receiver::{Receiver, RecvError, RecvMessage};
 pub use sender::{SendError, Sender, SentMessage};
@@ -0,0 +1,61 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Record details about the build so that the core can report them. The commit is read
+//! from git, unless `TELEMETRY_GIT_COMMIT` is set (for instance when building somewhere
+//! without the git history, like a docker image).
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let s = String::from_utf8(output.stdout).ok()?;
+    let s = s.trim();
+    (!s.is_empty()).then(|| s.to_owned())
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=TELEMETRY_GIT_COMMIT");
+
+    let commit = std::env::var("TELEMETRY_GIT_COMMIT")
+        .ok()
+        .filter(|c| !c.is_empty())
+        .or_else(|| git(&["rev-parse", "--short", "HEAD"]));
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=TELEMETRY_GIT_COMMIT={commit}");
+    }
+
+    // Re-run this when a new commit is checked out, so that the commit we report stays current:
+    let head_files = ["HEAD".to_owned()]
+        .into_iter()
+        .chain(git(&["symbolic-ref", "-q", "HEAD"]));
+    for file in head_files {
+        if let Some(path) = git(&["rev-parse", "--git-path", &file]) {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+
+    let built_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=TELEMETRY_BUILD_TIMESTAMP={built_at}");
+}
@@ -15,13 +15,14 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use super::inner_loop;
-use crate::find_location::find_location;
-use crate::state::NodeId;
-use common::id_type;
+use crate::find_location::{find_location, LocationProvider, LookupLimits};
+use crate::state::{AllowedChain, ChainTag, NodeId, PeerCountHandling};
+use common::{id_type, node_types::BlockHash};
 use futures::{future, Sink, SinkExt};
 use std::net::IpAddr;
 use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
+use std::time::Duration;
 
 id_type! {
     /// A unique Id is assigned per websocket connection (or more accurately,
@@ -38,15 +39,75 @@ pub struct Aggregator(Arc<AggregatorInternal>);
 pub struct AggregatorOpts {
     /// Any node from these chains is muted
     pub denylist: Vec<String>,
+    /// If not empty, any node from a chain not in this list is muted.
+    pub allowlist: Vec<AllowedChain>,
+    /// Tags to attach to chains when they're created.
+    pub chain_tags: Vec<ChainTag>,
+    /// Non-authority nodes on chains with these genesis hashes are muted.
+    pub authority_only_chains: Vec<BlockHash>,
+    /// Authority nodes that connect with this token aren't subject to the allowlist.
+    pub authority_node_token: Option<Arc<str>>,
     /// If our incoming message queue exceeds this length, we start
     /// dropping non-essential messages.
     pub max_queue_len: usize,
     /// How many nodes from third party chains are allowed to connect
     /// before we prevent connections from them.
     pub max_third_party_nodes: usize,
+    /// How many chains can be tracked at once. Nodes on new chains are
+    /// muted once this many chains are known.
+    pub max_chains: usize,
+    /// How many feeds can be subscribed to each chain at once. Feeds asking to subscribe
+    /// to a chain beyond this are refused.
+    pub max_feeds_per_chain: usize,
+    /// How many different chains a feed can subscribe to until it unsubscribes. Feeds asking
+    /// to subscribe to a chain beyond this are refused.
+    pub max_subscriptions_per_feed: usize,
     /// Flag to expose the node's details (IP address, SysInfo, HwBench) of all connected
     /// nodes to the feed subscribers.
     pub expose_node_details: bool,
+    /// Go by the times that nodes say they sent their messages at, rather than the times
+    /// that we receive them at, when working out when blocks were seen.
+    pub trust_node_timestamps: bool,
+    /// What to do about nodes reporting implausible peer counts.
+    pub peer_count_handling: PeerCountHandling,
+    /// Send feeds changes to the stats and other details that a node reports
+    /// periodically no more than once per this interval. Zero disables this.
+    pub node_update_min_interval: Duration,
+    /// How long a snapshot of a chain built for a subscribing feed can be reused
+    /// for other feeds subscribing to the same chain. Zero disables this.
+    pub snapshot_cache_ttl: Duration,
+    /// How many recent events to keep for each chain, for feeds that ask for them
+    /// to be replayed when subscribing. Zero disables this.
+    pub event_history_size: usize,
+    /// Don't look up the geographical locations of nodes, or hold on to their IP addresses.
+    pub disable_geolocation: bool,
+    /// How many node locations can be looked up at once, across every aggregator.
+    pub geoip_max_concurrent: usize,
+    /// How long a location lookup can wait for its turn before it's abandoned.
+    pub geoip_max_wait: Duration,
+    /// Nodes that haven't sent a message in this long are removed. Zero disables this.
+    pub stale_node_timeout: Duration,
+    /// How often to check for nodes that haven't sent a message in a while.
+    pub stale_node_check_interval: Duration,
+    /// How often to send a heartbeat to every feed. Zero disables this.
+    pub feed_heartbeat_interval: Duration,
+    /// How often to tell feeds subscribed to the node counts of a chain how many nodes
+    /// have joined and left it. Zero disables this.
+    pub node_count_interval: Duration,
+    /// How long to keep chains around for once they have no nodes left, in case
+    /// nodes reconnect to them. Zero removes them straight away.
+    pub empty_chain_ttl: Duration,
+    /// Best blocks more than this many blocks ahead of their chain's best block are
+    /// ignored. If not set, any plausible height is accepted.
+    pub max_block_height_jump: Option<u64>,
+    /// How many values of each stat series to keep for each node. If not set, the
+    /// default number is kept.
+    pub stat_history_len: Option<usize>,
+    /// How many of the most recent block heights each chain keeps track of. If not set,
+    /// the defaults are kept.
+    pub block_window: Option<usize>,
+    /// How long each window that latencies are recorded in is.
+    pub latency_window: Duration,
 }
 
 struct AggregatorInternal {
@@ -60,20 +121,35 @@ struct AggregatorInternal {
     /// stored here so that anybody holding an `Aggregator` handle can
     /// make use of it.
     tx_to_aggregator: flume::Sender<inner_loop::ToAggregator>,
+    /// How many of our location lookups have been abandoned because they waited too long.
+    abandoned_location_lookups: Arc<AtomicU64>,
 }
 
 impl Aggregator {
-    /// Spawn a new Aggregator. This connects to the telemetry backend
-    pub async fn spawn(opts: AggregatorOpts) -> anyhow::Result<Aggregator> {
+    /// Spawn a new Aggregator, which locates nodes using the provider given, within the
+    /// limits given.
+    pub async fn spawn<L: LocationProvider>(
+        opts: AggregatorOpts,
+        location_provider: Arc<L>,
+        lookup_limits: LookupLimits,
+    ) -> anyhow::Result<Aggregator> {
         let (tx_to_aggregator, rx_from_external) = flume::unbounded();
+        let abandoned_location_lookups = Arc::new(AtomicU64::new(0));
 
-        // Kick off a locator task to locate nodes, which hands back a channel to make location requests
-        let tx_to_locator =
-            find_location(tx_to_aggregator.clone().into_sink().with(|(node_id, msg)| {
-                future::ok::<_, flume::SendError<_>>(inner_loop::ToAggregator::FromFindLocation(
-                    node_id, msg,
-                ))
-            }));
+        // Kick off a locator task to locate nodes (unless this is disabled), which hands back
+        // a channel to make location requests
+        let tx_to_locator = (!opts.disable_geolocation).then(|| {
+            find_location(
+                location_provider,
+                lookup_limits,
+                Arc::clone(&abandoned_location_lookups),
+                tx_to_aggregator.clone().into_sink().with(|(node_id, msg)| {
+                    future::ok::<_, flume::SendError<_>>(
+                        inner_loop::ToAggregator::FromFindLocation(node_id, msg),
+                    )
+                }),
+            )
+        });
 
         // Handle any incoming messages in our handler loop:
         tokio::spawn(Aggregator::handle_messages(
@@ -87,6 +163,7 @@ impl Aggregator {
             shard_conn_id: AtomicU64::new(1),
             feed_conn_id: AtomicU64::new(1),
             tx_to_aggregator,
+            abandoned_location_lookups,
         })))
     }
 
@@ -95,14 +172,19 @@ impl Aggregator {
     /// any more, this task will gracefully end.
     async fn handle_messages(
         rx_from_external: flume::Receiver<inner_loop::ToAggregator>,
-        tx_to_aggregator: flume::Sender<(NodeId, IpAddr)>,
+        tx_to_locator: Option<flume::Sender<(NodeId, IpAddr)>>,
         opts: AggregatorOpts,
     ) {
-        inner_loop::InnerLoop::new(tx_to_aggregator, opts)
+        inner_loop::InnerLoop::new(tx_to_locator, opts)
             .handle(rx_from_external)
             .await;
     }
 
+    /// Is our aggregator loop still running? It stops if it hits something unrecoverable.
+    pub fn is_running(&self) -> bool {
+        !self.0.tx_to_aggregator.is_disconnected()
+    }
+
     /// Gather metrics from our aggregator loop
     pub async fn gather_metrics(&self) -> anyhow::Result<inner_loop::Metrics> {
         let (tx, rx) = flume::unbounded();
@@ -110,10 +192,50 @@ impl Aggregator {
 
         self.0.tx_to_aggregator.send_async(msg).await?;
 
-        let metrics = rx.recv_async().await?;
+        let mut metrics = rx.recv_async().await?;
+        metrics.abandoned_location_lookups = self
+            .0
+            .abandoned_location_lookups
+            .load(std::sync::atomic::Ordering::Relaxed);
         Ok(metrics)
     }
 
+    /// Ask the aggregator for a snapshot of the chains and shards that it knows about.
+    pub async fn gather_admin_snapshot(&self) -> anyhow::Result<inner_loop::AdminSnapshot> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherAdminSnapshot(tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let snapshot = rx.recv_async().await?;
+        Ok(snapshot)
+    }
+
+    /// Ask the aggregator for a snapshot of everything that it knows about every chain and
+    /// node, which is handed back in chunks of newline delimited JSON. The receiver is
+    /// disconnected once the last chunk has been sent.
+    pub async fn gather_state_snapshot(&self) -> anyhow::Result<flume::Receiver<bytes::Bytes>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherStateSnapshot(tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+        Ok(rx)
+    }
+
+    /// Replace the chain deny and allow lists that the aggregator checks nodes against.
+    pub async fn update_chain_lists(
+        &self,
+        denylist: Vec<String>,
+        allowlist: Vec<AllowedChain>,
+    ) -> anyhow::Result<()> {
+        let msg = inner_loop::ToAggregator::UpdateChainLists {
+            denylist,
+            allowlist,
+        };
+        self.0.tx_to_aggregator.send_async(msg).await?;
+        Ok(())
+    }
+
     /// Return a sink that a shard can send messages into to be handled by the aggregator.
     pub fn subscribe_shard(
         &self,
@@ -1,8 +1,10 @@
 use super::aggregator::{Aggregator, AggregatorOpts};
 use super::inner_loop;
+use crate::find_location::{LocationProvider, LookupLimits};
+use crate::state::AllowedChain;
 use common::EitherSink;
 use futures::{Sink, SinkExt};
-use inner_loop::{FromShardWebsocket, Metrics};
+use inner_loop::{AdminSnapshot, FromShardWebsocket, Metrics};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
@@ -17,15 +19,22 @@ pub struct AggregatorSetInner {
 
 impl AggregatorSet {
     /// Spawn the number of aggregators we're asked to.
-    pub async fn spawn(
+    pub async fn spawn<L: LocationProvider>(
         num_aggregators: usize,
         opts: AggregatorOpts,
+        location_provider: Arc<L>,
     ) -> anyhow::Result<AggregatorSet> {
         assert_ne!(num_aggregators, 0, "You must have 1 or more aggregator");
 
-        let aggregators = futures::future::try_join_all(
-            (0..num_aggregators).map(|_| Aggregator::spawn(opts.clone())),
-        )
+        // Every aggregator shares the same limit on the locations being looked up at once:
+        let lookup_limits = LookupLimits::new(opts.geoip_max_concurrent, opts.geoip_max_wait);
+        let aggregators = futures::future::try_join_all((0..num_aggregators).map(|_| {
+            Aggregator::spawn(
+                opts.clone(),
+                Arc::clone(&location_provider),
+                lookup_limits.clone(),
+            )
+        }))
         .await?;
 
         let initial_metrics = (0..num_aggregators).map(|_| Metrics::default()).collect();
@@ -77,11 +86,60 @@ impl AggregatorSet {
         }
     }
 
+    /// Are the aggregators up and running? They aren't until each of them has answered
+    /// a request for metrics, and stop being so if any of their loops stop.
+    pub fn is_live(&self) -> bool {
+        self.0.aggregators.iter().all(|a| a.is_running())
+            && self
+                .0
+                .metrics
+                .lock()
+                .unwrap()
+                .iter()
+                .all(|m| m.timestamp_unix_ms != 0)
+    }
+
     /// Return the latest metrics we've gathered so far from each internal aggregator.
     pub fn latest_metrics(&self) -> Vec<Metrics> {
         self.0.metrics.lock().unwrap().clone()
     }
 
+    /// Ask each internal aggregator for its current metrics, rather than relying on the
+    /// latest metrics that were gathered periodically.
+    pub async fn gather_metrics(&self) -> anyhow::Result<Vec<Metrics>> {
+        futures::future::try_join_all(self.0.aggregators.iter().map(|a| a.gather_metrics())).await
+    }
+
+    /// Ask for a snapshot of the chains and shards that we know about. Every aggregator
+    /// is told about every node and shard, so we only need to ask one of them.
+    pub async fn gather_admin_snapshot(&self) -> anyhow::Result<AdminSnapshot> {
+        self.0.aggregators[0].gather_admin_snapshot().await
+    }
+
+    /// Ask for a snapshot of everything that we know about every chain and node, in chunks
+    /// of newline delimited JSON. As with [`AggregatorSet::gather_admin_snapshot`], we only
+    /// need to ask one aggregator.
+    pub async fn gather_state_snapshot(&self) -> anyhow::Result<flume::Receiver<bytes::Bytes>> {
+        self.0.aggregators[0].gather_state_snapshot().await
+    }
+
+    /// Replace the chain deny and allow lists. Every aggregator knows about every node,
+    /// so they are all told.
+    pub async fn update_chain_lists(
+        &self,
+        denylist: Vec<String>,
+        allowlist: Vec<AllowedChain>,
+    ) -> anyhow::Result<()> {
+        futures::future::try_join_all(
+            self.0
+                .aggregators
+                .iter()
+                .map(|a| a.update_chain_lists(denylist.clone(), allowlist.clone())),
+        )
+        .await?;
+        Ok(())
+    }
+
     /// Return a sink that a shard can send messages into to be handled by all aggregators.
     pub fn subscribe_shard(
         &self,
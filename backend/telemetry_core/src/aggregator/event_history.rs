@@ -0,0 +1,192 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::feed_message::{
+    self, FeedFormat, FeedMessageBatch, FeedMessageSerializer, FeedMessageWrite, FormattedBytes,
+    MultiFormatSerializer,
+};
+use std::collections::VecDeque;
+
+/// A bounded record of the most recent events about the nodes on a chain, which
+/// feeds can ask to have replayed to them after the snapshot of the chain.
+///
+/// The snapshot already describes the current state of every connected node, and
+/// replaying older events about them would only wind that state back, so only events
+/// about nodes that have since been removed are replayed. These are followed by a
+/// [`feed_message::RemovedNode`] for each such node, so that the feed ends up with the
+/// same nodes as the snapshot gave it.
+pub struct EventHistory {
+    capacity: usize,
+    events: VecDeque<Event>,
+}
+
+struct Event {
+    /// The ID (relative to the chain) of the node that the event is about.
+    node_id: usize,
+    /// Was the node an authority when the event happened?
+    is_authority: bool,
+    /// Has the node since been removed? Node IDs are reused, so this is noted
+    /// as soon as the node goes rather than worked out when replaying.
+    removed: bool,
+    /// The action of the event, so that it can be left out for feeds that don't want it.
+    action: u8,
+    /// The event, serialized in each of [`FeedFormat::encodings()`].
+    bytes: FormattedBytes,
+}
+
+impl EventHistory {
+    pub fn new(capacity: usize) -> Self {
+        EventHistory {
+            capacity,
+            events: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Record an event about a node, forgetting the oldest event if we're full.
+    pub fn push<Message>(&mut self, node_id: usize, is_authority: bool, msg: Message)
+    where
+        Message: FeedMessageWrite,
+    {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut serializer = MultiFormatSerializer::new(FeedFormat::encodings());
+        serializer.push(msg);
+        let bytes = serializer.into_finalized();
+        if bytes.is_empty() {
+            return;
+        }
+
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(Event {
+            node_id,
+            is_authority,
+            removed: false,
+            action: Message::ACTION,
+            bytes,
+        });
+    }
+
+    /// Note that a node has been removed, so that events about it can be replayed.
+    pub fn node_removed(&mut self, node_id: usize) {
+        for event in self.events.iter_mut().filter(|e| e.node_id == node_id) {
+            event.removed = true;
+        }
+    }
+
+    /// Serialize up to `max` of the most recent events that a feed can be sent after the
+    /// snapshot of the chain, in the format that the feed asked for. Events about removed
+    /// nodes whose IDs are now `in_use` by other nodes are left out, since they would clobber
+    /// the new nodes, as are events that the feed isn't sent.
+    pub fn replay(
+        &self,
+        max: usize,
+        authorities_only: bool,
+        format: FeedFormat,
+        in_use: impl Fn(usize) -> bool,
+    ) -> Option<bytes::Bytes> {
+        let replayable: Vec<&Event> = self
+            .events
+            .iter()
+            .filter(|e| e.removed && !in_use(e.node_id))
+            .filter(|e| !authorities_only || e.is_authority)
+            .filter(|e| format.has_action(e.action))
+            .collect();
+
+        let mut batch = FeedMessageBatch::new();
+        let mut removed_node_ids = Vec::new();
+        for event in &replayable[replayable.len().saturating_sub(max)..] {
+            if let Some(bytes) = event.bytes.get(format.encoding()) {
+                batch.push(bytes.clone());
+            }
+            if !removed_node_ids.contains(&event.node_id) {
+                removed_node_ids.push(event.node_id);
+            }
+        }
+
+        let mut removals = FeedMessageSerializer::with_format(format);
+        for node_id in removed_node_ids {
+            removals.push(feed_message::RemovedNode(node_id));
+        }
+        if let Some(bytes) = removals.into_finalized() {
+            batch.push(bytes);
+        }
+        batch.into_finalized()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::feed_message::{FeedEvents, StaleNode};
+
+    #[test]
+    fn only_events_about_removed_nodes_are_replayed() {
+        let mut history = EventHistory::new(4);
+        history.push(0, false, StaleNode(0));
+        history.push(1, true, StaleNode(1));
+        history.push(2, false, StaleNode(2));
+        history.push(1, true, StaleNode(1));
+        history.push(0, false, StaleNode(0));
+
+        // Nothing to replay while the nodes are all connected:
+        assert!(history
+            .replay(10, false, FeedFormat::FULL, |_| true)
+            .is_none());
+
+        history.node_removed(1);
+        history.node_removed(2);
+
+        // The oldest event was forgotten, and the events are followed by node removals:
+        let bytes = history
+            .replay(10, false, FeedFormat::FULL, |_| false)
+            .unwrap();
+        assert_eq!(&bytes[..], b"[20,1,20,2,20,1,4,1,4,2]");
+
+        // We can ask for fewer events, or those about authority nodes:
+        let bytes = history
+            .replay(1, false, FeedFormat::FULL, |_| false)
+            .unwrap();
+        assert_eq!(&bytes[..], b"[20,1,4,1]");
+        let bytes = history
+            .replay(10, true, FeedFormat::FULL, |_| false)
+            .unwrap();
+        assert_eq!(&bytes[..], b"[20,1,20,1,4,1]");
+
+        // Events about nodes whose IDs have been reused are left out:
+        let bytes = history
+            .replay(10, false, FeedFormat::FULL, |id| id == 1)
+            .unwrap();
+        assert_eq!(&bytes[..], b"[20,2,4,2]");
+
+        // Events the feed hasn't asked for are left out too:
+        let format = FeedFormat {
+            events: FeedEvents::BLOCK,
+            ..FeedFormat::FULL
+        };
+        assert!(history.replay(10, false, format, |_| false).is_none());
+
+        // And they're sent in the form that the feed asked for:
+        let format = FeedFormat {
+            bignums_as_strings: true,
+            ..FeedFormat::FULL
+        };
+        let bytes = history.replay(10, false, format, |_| false).unwrap();
+        assert_eq!(&bytes[..], br#"[20,"1",20,"2",20,"1",4,"1",4,"2"]"#);
+    }
+}
@@ -15,25 +15,39 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use super::aggregator::ConnId;
-use crate::feed_message::{self, FeedMessageSerializer};
-use crate::state::{self, NodeId, State};
-use crate::{find_location, AggregatorOpts};
+use super::event_history::EventHistory;
+use crate::aggregator::AggregatorOpts;
+use crate::build_info::BuildInfo;
+use crate::feed_message::{
+    self, ChainFeedSerializer, FeedEvents, FeedFormat, FeedMessageBatch, FeedMessageSerializer,
+    FeedProtocol, FormattedBytes, MultiFormatSerializer, NodeRemovalReason, SubscribeErrorCode,
+};
+use crate::find_location;
+use crate::server::constant_time_eq;
+use crate::state::{self, AllowedChain, ChainTags, NodeId, State};
 use bimap::BiMap;
 use common::{
     internal_messages::{self, MuteReason, ShardNodeId},
+    latency::{LatencyHistogram, LatencyPercentiles},
     node_message,
-    node_types::BlockHash,
+    node_types::{
+        Block, BlockDetails, BlockHash, NodeDetails, NodeHardware, NodeHwBench, NodeIO,
+        NodeLocation, NodeStats, Timestamp,
+    },
     time, MultiMapUnique,
 };
-use std::collections::HashMap;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
 use std::sync::{
-    atomic::{AtomicU64, Ordering},
+    atomic::{AtomicBool, AtomicU64, Ordering},
     Arc,
 };
+use std::time::{Duration, Instant};
 use std::{net::IpAddr, str::FromStr};
 
 /// Incoming messages come via subscriptions, and end up looking like this.
 #[derive(Clone, Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum ToAggregator {
     FromShardWebsocket(ConnId, FromShardWebsocket),
     FromFeedWebsocket(ConnId, FromFeedWebsocket),
@@ -41,6 +55,31 @@ pub enum ToAggregator {
     /// Hand back some metrics. The provided sender is expected not to block when
     /// a message is sent into it.
     GatherMetrics(flume::Sender<Metrics>),
+    /// Hand back a snapshot of the chains and shards we know about, for admin
+    /// introspection. The provided sender is expected not to block.
+    GatherAdminSnapshot(flume::Sender<AdminSnapshot>),
+    /// Hand back everything we know about every chain and node, as chunks of
+    /// newline delimited JSON. The provided sender is expected not to block, and
+    /// is dropped once the last chunk has been sent.
+    GatherStateSnapshot(flume::Sender<bytes::Bytes>),
+    /// Remove any nodes that we haven't heard from in a while. The aggregator
+    /// sends this to itself periodically.
+    PruneSilentNodes,
+    /// Send a heartbeat to every feed. The aggregator sends this to itself
+    /// periodically.
+    SendHeartbeat,
+    /// Tell feeds subscribed to the node counts of chains how many nodes have joined and
+    /// left them. The aggregator sends this to itself periodically.
+    SendNodeCounts,
+    /// Remove any chains that have had no nodes for a while. The aggregator
+    /// sends this to itself periodically.
+    RemoveEmptyChains,
+    /// Replace the chain deny and allow lists, removing any connected nodes
+    /// that are no longer allowed.
+    UpdateChainLists {
+        denylist: Vec<String>,
+        allowlist: Vec<AllowedChain>,
+    },
 }
 
 /// An incoming shard connection can send these messages to the aggregator.
@@ -57,14 +96,34 @@ pub enum FromShardWebsocket {
         ip: std::net::IpAddr,
         node: common::node_types::NodeDetails,
         genesis_hash: common::node_types::BlockHash,
+        /// The ID that the shard identifies itself with, if it gave one.
+        shard_id: Option<Box<str>>,
+        /// The token that the node connected to the shard with, if it gave one.
+        token: Option<Box<str>>,
     },
     /// Update/pass through details about a node.
     Update {
         local_id: ShardNodeId,
         payload: node_message::Payload,
+        /// When the message was received from the shard, if it's been sampled to measure
+        /// how long it takes to handle.
+        received_at: Option<Instant>,
+        /// When the node says that it sent the message, by its own clock, if it said.
+        sent_at: Option<Timestamp>,
+    },
+    /// A node's clock has started (`Some(skew_ms)`) or stopped (`None`) disagreeing with
+    /// the shard's by more than the shard allows.
+    ClockSkew {
+        local_id: ShardNodeId,
+        skew_ms: Option<i64>,
+    },
+    /// Tell the aggregator that a node has been removed, and why.
+    Remove {
+        local_id: ShardNodeId,
+        reason: NodeRemovalReason,
     },
-    /// Tell the aggregator that a node has been removed when it disconnects.
-    Remove { local_id: ShardNodeId },
+    /// The shard has started or stopped draining (refusing new nodes ahead of maintenance).
+    Draining { draining: bool },
     /// The shard is disconnected.
     Disconnected,
 }
@@ -79,6 +138,23 @@ pub enum ToShardWebsocket {
     },
 }
 
+/// How a feed names the chain that it wants to subscribe to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FeedChain {
+    /// The chain with this genesis hash.
+    GenesisHash(BlockHash),
+    /// The chain with this label (or the one with the most nodes if several share it). This
+    /// is only looked up when subscribing; from then on the feed is subscribed to the chain
+    /// with that genesis hash, and it stays subscribed if the chain's label changes.
+    Label(Box<str>),
+}
+
+impl From<BlockHash> for FeedChain {
+    fn from(genesis_hash: BlockHash) -> Self {
+        FeedChain::GenesisHash(genesis_hash)
+    }
+}
+
 /// An incoming feed connection can send these messages to the aggregator.
 #[derive(Clone, Debug)]
 pub enum FromFeedWebsocket {
@@ -88,16 +164,114 @@ pub enum FromFeedWebsocket {
     /// progress.
     Initialize {
         channel: flume::Sender<ToFeedWebsocket>,
+        /// Admin feeds are allowed to ask for details that other feeds can't.
+        admin: bool,
+        /// The version of the feed protocol that the feed speaks.
+        protocol: FeedProtocol,
     },
     /// The feed can subscribe to a chain to receive
-    /// messages relating to it.
-    Subscribe { chain: BlockHash },
+    /// messages relating to it. This replaces whatever the feed was
+    /// subscribed to before, be that another chain or every chain.
+    Subscribe {
+        chain: FeedChain,
+        ordering: FeedOrdering,
+        /// Only receive messages about the authority nodes on the chain.
+        authorities_only: bool,
+        /// Replay up to this many recent events on the chain after the snapshot of it.
+        replay: usize,
+        /// Only receive these categories of messages about the chain.
+        events: FeedEvents,
+        /// Leave out the parts of node messages that only charts need, as per
+        /// [`feed_message::FeedFormat::lean`].
+        lean: bool,
+        /// Send 64-bit integers (block heights, timestamps and so on) as strings, which JS
+        /// clients can parse without losing precision.
+        bignums_as_strings: bool,
+    },
+    /// Subscribe to every chain, including any that appear while the feed is subscribed.
+    /// Every batch of messages about a chain is preceded by [`feed_message::ForChain`], so
+    /// that the feed can tell which chain they're about. Only admin feeds can do this.
+    SubscribeToEveryChain,
+    /// Subscribe to just the node counts of a chain. Rather than anything about the nodes
+    /// themselves, the feed is periodically sent a [`feed_message::NodeCount`] saying how
+    /// many nodes have joined and left the chain.
+    SubscribeToNodeCounts { chain: BlockHash },
+    /// Unsubscribe from any chain that the feed is subscribed to, or from every chain.
+    UnsubscribeAll,
+    /// Start sending finality messages (the finalized blocks and GRANDPA messages that nodes
+    /// report) about a chain to the feed whenever it's subscribed to it. These are left out
+    /// unless asked for.
+    SendFinality { chain: BlockHash },
+    /// Stop sending finality messages about a chain to the feed.
+    NoMoreFinality { chain: BlockHash },
     /// An explicit ping message.
     Ping { value: Box<str> },
+    /// A command that couldn't be understood. The feed is told why.
+    InvalidCommand { error: Box<str> },
+    /// Ask for a list of the chains we know about, without subscribing to any.
+    ListChains,
+    /// Ask for the `n` most active chains by some metric, without subscribing to any.
+    TopChains { n: usize, by: ChainActivity },
+    /// Ask which shard a node on the subscribed chain is connected through.
+    /// Only admin feeds are answered.
+    NodeShard { node_id: usize },
+    /// Ask for everything we know about a node on the subscribed chain. The feed is told
+    /// why if we can't answer.
+    NodeDetail { node_id: usize },
     /// The feed is disconnected.
     Disconnected,
 }
 
+/// How should the snapshot of a chain that a feed is sent when it subscribes be delivered?
+/// This only affects the snapshot; updates about the chain always follow the whole snapshot,
+/// in the order that they happen in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FeedOrdering {
+    /// The nodes in the snapshot are sent in order of their IDs.
+    #[default]
+    Strict,
+    /// The nodes in the snapshot are sent as soon as they are serialized, which may mean
+    /// that they arrive out of order. This can lower the latency of large subscriptions.
+    BestEffort,
+}
+
+impl FromStr for FeedOrdering {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(FeedOrdering::Strict),
+            "best-effort" => Ok(FeedOrdering::BestEffort),
+            _ => Err(anyhow::anyhow!(
+                "Ordering {s} not recognised; expected 'strict' or 'best-effort'"
+            )),
+        }
+    }
+}
+
+/// How should the activity of chains be compared when asking for the most active of them?
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChainActivity {
+    /// Chains with more nodes are more active.
+    #[default]
+    NodeCount,
+    /// Chains that have been producing blocks more quickly are more active. Chains that
+    /// haven't produced enough blocks for us to know how quickly come last.
+    BlockRate,
+}
+
+impl FromStr for ChainActivity {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nodes" => Ok(ChainActivity::NodeCount),
+            "block-rate" => Ok(ChainActivity::BlockRate),
+            _ => Err(anyhow::anyhow!(
+                "Activity {s} not recognised; expected 'nodes' or 'block-rate'"
+            )),
+        }
+    }
+}
+
 /// A set of metrics returned when we ask for metrics
 #[derive(Clone, Debug, Default)]
 pub struct Metrics {
@@ -122,24 +296,219 @@ pub struct Metrics {
     pub connected_feeds: usize,
     /// How many shards are currently connected to this aggregator.
     pub connected_shards: usize,
+    /// How many chain snapshots have been built for subscribing feeds.
+    pub snapshots_built: u64,
+    /// How many times a cached chain snapshot has been reused for a subscribing feed.
+    pub snapshots_reused: u64,
+    /// How long sampled node updates took to handle, in the last complete window.
+    pub update_latency: LatencyPercentiles,
+    /// How many nodes have been rejected because they were on a new chain, and the
+    /// maximum number of chains were already being tracked.
+    pub nodes_rejected_for_too_many_chains: u64,
+    /// How many lookups of node locations have been abandoned because they waited too long
+    /// for their turn. This is filled in by the aggregator, which does the lookups.
+    pub abandoned_location_lookups: u64,
+    /// How many best block reorgs have been seen on each chain that has had any.
+    pub chain_reorgs: Vec<(BlockHash, u64)>,
+}
+
+/// A snapshot of the chains and shards known to an aggregator, for admin introspection.
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct AdminSnapshot {
+    /// The chains that nodes are connected to, largest first.
+    pub chains: Vec<AdminChain>,
+    /// The shards that are connected to us.
+    pub shards: Vec<AdminShard>,
+}
+
+/// Details about a single chain in an [`AdminSnapshot`].
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct AdminChain {
+    pub genesis_hash: BlockHash,
+    pub label: String,
+    pub node_count: usize,
+}
+
+/// Details about a single shard connection in an [`AdminSnapshot`].
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct AdminShard {
+    /// The ID we've assigned to the shard connection.
+    pub conn_id: u64,
+    /// The ID that the shard identifies itself with (or "conn-<conn_id>" if it didn't give
+    /// one). This is only known once the shard has told us about a node.
+    pub shard_id: Option<String>,
+    /// How many nodes are connected through this shard.
+    pub node_count: usize,
+    /// Is the connection to the shard still open? This is false while a shard
+    /// connection is closing.
+    pub connected: bool,
+    /// Is the shard draining, refusing new nodes so that it can be taken down?
+    pub draining: bool,
+    /// Unix timestamp for when we last received a message from the shard.
+    pub last_seen: Timestamp,
+}
+
+/// Once the lines of a state snapshot that haven't been sent yet add up to this many
+/// bytes, they're sent on as a chunk.
+const STATE_SNAPSHOT_CHUNK_BYTES: usize = 64 * 1024;
+
+/// A line of a state snapshot. Each chain is followed by a line for each of its nodes.
+#[derive(serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum StateSnapshotLine<'a> {
+    Chain(StateSnapshotChain<'a>),
+    Node(StateSnapshotNode<'a>),
+}
+
+/// Details about a single chain in a state snapshot.
+#[derive(serde::Serialize)]
+struct StateSnapshotChain<'a> {
+    genesis_hash: BlockHash,
+    label: &'a str,
+    tags: &'a ChainTags,
+    node_count: usize,
+    best_block: &'a Block,
+    finalized_block: &'a Block,
+    average_block_time: Option<u64>,
+}
+
+/// Everything we know about a single node in a state snapshot. The stats are serialized
+/// as they are for feeds.
+#[derive(serde::Serialize)]
+struct StateSnapshotNode<'a> {
+    genesis_hash: BlockHash,
+    /// The ID of the node on its chain, as feeds know it.
+    id: usize,
+    details: &'a NodeDetails,
+    stats: &'a NodeStats,
+    io: &'a NodeIO,
+    hardware: &'a NodeHardware,
+    best: &'a Block,
+    finalized: &'a Block,
+    block_details: &'a BlockDetails,
+    location: Option<&'a NodeLocation>,
+    hwbench: Option<&'a NodeHwBench>,
+    custom_metrics: &'a HashMap<String, f64>,
+    shard_id: Option<&'a str>,
+    clock_skew: Option<i64>,
+    stale: bool,
 }
 
 // The frontend sends text based commands; parse them into these messages:
 impl FromStr for FromFeedWebsocket {
     type Err = anyhow::Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (cmd, value) = match s.find(':') {
-            Some(idx) => (&s[..idx], &s[idx + 1..]),
-            None => return Err(anyhow::anyhow!("Expecting format `CMD:CHAIN_NAME`")),
-        };
+        // Commands that don't need an argument can be given without the colon too:
+        let (cmd, value) = s.split_once(':').unwrap_or((s, ""));
+        let needs_value = matches!(
+            cmd,
+            "node-shard"
+                | "node-detail"
+                | "subscribe"
+                | "subscribe-authorities"
+                | "subscribe-counts"
+                | "top-chains"
+                | "send-finality"
+                | "send_finality"
+                | "no-more-finality"
+                | "no_more_finality"
+        );
+        if needs_value && value.is_empty() {
+            return Err(anyhow::anyhow!("Expecting format `{cmd}:VALUE`"));
+        }
         match cmd {
             "ping" => Ok(FromFeedWebsocket::Ping {
                 value: value.into(),
             }),
-            "subscribe" => Ok(FromFeedWebsocket::Subscribe {
+            "node-shard" => Ok(FromFeedWebsocket::NodeShard {
+                node_id: value.parse()?,
+            }),
+            "node-detail" => Ok(FromFeedWebsocket::NodeDetail {
+                node_id: value.parse()?,
+            }),
+            "list-chains" => Ok(FromFeedWebsocket::ListChains),
+            "top-chains" => {
+                // Chains are compared by node count unless asked otherwise, as in
+                // `top-chains:10:block-rate`.
+                let (n, by) = match value.split_once(':') {
+                    Some((n, by)) => (n, by.parse()?),
+                    None => (value, ChainActivity::default()),
+                };
+                Ok(FromFeedWebsocket::TopChains { n: n.parse()?, by })
+            }
+            "unsubscribe-all" => Ok(FromFeedWebsocket::UnsubscribeAll),
+            "subscribe-counts" => Ok(FromFeedWebsocket::SubscribeToNodeCounts {
+                chain: value.parse()?,
+            }),
+            "send-finality" | "send_finality" => Ok(FromFeedWebsocket::SendFinality {
+                chain: value.parse()?,
+            }),
+            "no-more-finality" | "no_more_finality" => Ok(FromFeedWebsocket::NoMoreFinality {
                 chain: value.parse()?,
             }),
-            _ => return Err(anyhow::anyhow!("Command {} not recognised", cmd)),
+            "subscribe" | "subscribe-authorities" => {
+                // An ordering, a number of recent events to replay, the categories of events
+                // to receive, whether to receive lean node messages and whether to receive big
+                // integers as strings can optionally be given, as in
+                // `subscribe:CHAIN_HASH:best-effort:replay=10:events=block,finalized:lean=1:bignum=string`. Chains
+                // are identified by their genesis hash, which can be made explicit with a
+                // `genesis:` prefix, as in `subscribe:genesis:CHAIN_HASH`, or by their label
+                // with a `label:` prefix, as in `subscribe:label:Polkadot`.
+                // Admin feeds can subscribe to every chain at once with `subscribe:*`.
+                if cmd == "subscribe" && value == "*" {
+                    return Ok(FromFeedWebsocket::SubscribeToEveryChain);
+                }
+                let mut parts = value.split(':').peekable();
+                let chain = if parts.next_if_eq(&"label").is_some() {
+                    match parts.next() {
+                        Some(label) if !label.is_empty() => FeedChain::Label(label.into()),
+                        _ => anyhow::bail!("Expecting format `{cmd}:label:LABEL`"),
+                    }
+                } else {
+                    parts.next_if_eq(&"genesis");
+                    FeedChain::GenesisHash(parts.next().unwrap_or_default().parse()?)
+                };
+                let mut ordering = FeedOrdering::default();
+                let mut replay = 0;
+                let mut events = FeedEvents::ALL;
+                let mut lean = false;
+                let mut bignums_as_strings = false;
+                for part in parts {
+                    if let Some(n) = part.strip_prefix("replay=") {
+                        replay = n.parse()?;
+                    } else if let Some(categories) = part.strip_prefix("events=") {
+                        events = categories.parse()?;
+                    } else if let Some(value) = part.strip_prefix("lean=") {
+                        lean = match value {
+                            "1" | "true" => true,
+                            "0" | "false" => false,
+                            _ => {
+                                anyhow::bail!("Expecting 'lean=1' or 'lean=0', got 'lean={value}'")
+                            }
+                        };
+                    } else if let Some(value) = part.strip_prefix("bignum=") {
+                        bignums_as_strings = match value {
+                            "string" => true,
+                            "number" => false,
+                            _ => anyhow::bail!(
+                                "Expecting 'bignum=string' or 'bignum=number', got 'bignum={value}'"
+                            ),
+                        };
+                    } else {
+                        ordering = part.parse()?;
+                    }
+                }
+                Ok(FromFeedWebsocket::Subscribe {
+                    chain,
+                    ordering,
+                    authorities_only: cmd == "subscribe-authorities",
+                    replay,
+                    events,
+                    lean,
+                    bignums_as_strings,
+                })
+            }
+            _ => Err(anyhow::anyhow!("Command {} not recognised", cmd)),
         }
     }
 }
@@ -150,6 +519,80 @@ pub enum ToFeedWebsocket {
     Bytes(bytes::Bytes),
 }
 
+/// A channel to send messages out to a feed on. Feed channels may be bounded, and if a feed
+/// falls so far behind that its channel fills up, we stop sending to it and drop our end of
+/// the channel. This closes the feed connection, rather than leaving the feed with a gap in
+/// the messages it's been sent or holding up the aggregator.
+struct FeedChannel {
+    channel: Option<flume::Sender<ToFeedWebsocket>>,
+    /// What the feed asked for when it subscribed to a chain. Each subscription replaces
+    /// the last, so this applies to messages about that chain. Feeds subscribed to every
+    /// chain are sent everything in the version of the feed protocol that they speak.
+    /// Messages for the feed are serialized in this format.
+    format: FeedFormat,
+    /// The chain that the feed asked to be sent finality messages about, if any. They're
+    /// sent while the feed is subscribed to that chain on its own.
+    finality_chain: Option<BlockHash>,
+    /// The chains that the feed has subscribed to since it last unsubscribed, which count
+    /// towards the number of subscriptions that it's allowed.
+    subscribed_chains: HashSet<BlockHash>,
+}
+
+impl FeedChannel {
+    fn new(channel: flume::Sender<ToFeedWebsocket>, protocol: FeedProtocol) -> Self {
+        FeedChannel {
+            channel: Some(channel),
+            format: FeedFormat::full(protocol),
+            finality_chain: None,
+            subscribed_chains: HashSet::new(),
+        }
+    }
+
+    fn send(&mut self, message: ToFeedWebsocket) {
+        if !self.try_send(message) {
+            self.close();
+        }
+    }
+
+    /// Send a message, returning false if the channel is full, in which case the
+    /// feed should be closed with [`FeedChannel::close()`].
+    fn try_send(&self, message: ToFeedWebsocket) -> bool {
+        match &self.channel {
+            Some(channel) => {
+                !matches!(channel.try_send(message), Err(flume::TrySendError::Full(_)))
+            }
+            None => true,
+        }
+    }
+
+    /// Send the messages serialized in this feed's format, if there are any.
+    fn send_formatted(&mut self, bytes: &FormattedBytes) {
+        if let Some(bytes) = bytes.get(self.format) {
+            self.send(ToFeedWebsocket::Bytes(bytes.clone()));
+        }
+    }
+
+    /// A serializer for messages to this feed.
+    fn serializer(&self) -> FeedMessageSerializer {
+        FeedMessageSerializer::with_format(self.format)
+    }
+
+    /// Go back to sending the feed everything, as when it first connected.
+    fn reset_format(&mut self) {
+        self.format = FeedFormat::full(self.format.protocol);
+    }
+
+    fn close(&mut self) {
+        if self.channel.take().is_some() {
+            log::debug!("Closing feed whose channel is full");
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.channel.as_ref().map_or(0, |channel| channel.len())
+    }
+}
+
 /// Instances of this are responsible for handling incoming and
 /// outgoing messages in the main aggregator loop.
 pub struct InnerLoop {
@@ -158,17 +601,42 @@ pub struct InnerLoop {
     /// We maintain a mapping between NodeId and ConnId+LocalId, so that we know
     /// which messages are about which nodes.
     node_ids: BiMap<NodeId, (ConnId, ShardNodeId)>,
+    /// Connections from nodes that have been replaced by newer ones, which shards have been
+    /// told to close. Messages can still arrive from these until the shard removes them.
+    superseded_nodes: HashSet<(ConnId, ShardNodeId)>,
 
     /// Keep track of how to send messages out to feeds.
-    feed_channels: HashMap<ConnId, flume::Sender<ToFeedWebsocket>>,
+    feed_channels: HashMap<ConnId, FeedChannel>,
+    /// Which feeds are admin feeds?
+    admin_feed_conn_ids: HashSet<ConnId>,
     /// Keep track of how to send messages out to shards.
     shard_channels: HashMap<ConnId, flume::Sender<ToShardWebsocket>>,
+    /// When did we last receive a message from each shard?
+    shard_last_seen: HashMap<ConnId, Timestamp>,
+    /// Which shards have told us that they're draining.
+    draining_shards: HashSet<ConnId>,
 
     /// Which feeds are subscribed to a given chain?
     chain_to_feed_conn_ids: MultiMapUnique<BlockHash, ConnId>,
+    /// Which feeds are subscribed to only the authority nodes on a given chain?
+    chain_to_authority_feed_conn_ids: MultiMapUnique<BlockHash, ConnId>,
+    /// Which feeds are subscribed to every chain?
+    every_chain_feed_conn_ids: HashSet<ConnId>,
+    /// Which feeds are subscribed to just the node counts of a given chain?
+    chain_to_count_feed_conn_ids: MultiMapUnique<BlockHash, ConnId>,
+    /// How many nodes have joined and left each chain with feeds subscribed to its node
+    /// counts since they were last told.
+    node_count_changes: HashMap<BlockHash, (usize, usize)>,
 
-    /// Send messages here to make geographical location requests.
-    tx_to_locator: flume::Sender<(NodeId, IpAddr)>,
+    /// Send messages here to make geographical location requests. This is `None` if
+    /// geolocation is disabled, in which case we don't hold on to node IP addresses at all.
+    tx_to_locator: Option<flume::Sender<(NodeId, IpAddr)>>,
+
+    /// How many feeds can be subscribed to each chain at once?
+    max_feeds_per_chain: usize,
+
+    /// How many different chains can a feed subscribe to until it unsubscribes?
+    max_subscriptions_per_feed: usize,
 
     /// How big can the queue of messages coming in to the aggregator get before messages
     /// are prioritised and dropped to try and get back on track.
@@ -177,26 +645,131 @@ pub struct InnerLoop {
     /// Flag to expose the node's details (IP address, SysInfo, HwBench) of all connected
     /// nodes to the feed subscribers.
     expose_node_details: bool,
+
+    /// Go by the times that nodes say they sent their messages at, rather than the times
+    /// that we receive them at, when working out when blocks were seen?
+    trust_node_timestamps: bool,
+
+    /// What to do about nodes reporting implausible peer counts.
+    peer_count_handling: state::PeerCountHandling,
+
+    /// Authority nodes that connect with this token bypass the chain allow list.
+    authority_node_token: Option<Arc<str>>,
+
+    /// How often can the details that nodes report periodically be sent to feeds?
+    node_update_min_interval: Duration,
+
+    /// How long can a snapshot built for a subscribing feed be reused for?
+    snapshot_cache_ttl: Duration,
+    /// Recently built snapshots, keyed by the chain, whether they are for feeds
+    /// subscribed to only the authority nodes on it, and the format they're in.
+    snapshot_cache: HashMap<(BlockHash, bool, FeedFormat), ChainSnapshot>,
+    /// How many snapshots have we built for subscribing feeds?
+    snapshots_built: u64,
+    /// How many times have we reused a cached snapshot?
+    snapshots_reused: u64,
+    /// How long sampled node updates take from being received from a shard to the resulting
+    /// messages being queued up for feeds.
+    update_latency: LatencyHistogram,
+    /// How many nodes have we turned away because they were on a new chain
+    /// and we were already tracking as many chains as we're allowed to?
+    nodes_rejected_for_too_many_chains: u64,
+
+    /// How many recent events to keep for each chain. Zero disables this.
+    event_history_size: usize,
+    /// Recent events on each chain, which feeds can ask to have replayed when subscribing.
+    event_histories: HashMap<BlockHash, EventHistory>,
+
+    /// Nodes that we haven't received a message from in this long are removed.
+    stale_node_timeout: Duration,
+    /// How often do we check for nodes that haven't sent a message in a while?
+    stale_node_check_interval: Duration,
+    /// How often do we send a heartbeat to every feed? Zero disables this.
+    feed_heartbeat_interval: Duration,
+    /// How often do we tell feeds subscribed to node counts about nodes joining and leaving?
+    /// Zero disables this.
+    node_count_interval: Duration,
+    /// How long do chains with no nodes left stick around for? Zero removes them straight away.
+    empty_chain_ttl: Duration,
+}
+
+/// A snapshot of a chain that was sent to a subscribing feed, which can be
+/// reused for other feeds subscribing to the same chain shortly afterwards.
+struct ChainSnapshot {
+    /// When was this snapshot built?
+    built_at: Instant,
+    /// The serialized messages that make up the snapshot.
+    messages: Vec<bytes::Bytes>,
+    /// Messages sent out to feeds for the chain since the snapshot was built.
+    delta: Vec<bytes::Bytes>,
 }
 
 impl InnerLoop {
     /// Create a new inner loop handler with the various state it needs.
-    pub fn new(tx_to_locator: flume::Sender<(NodeId, IpAddr)>, opts: AggregatorOpts) -> Self {
+    pub fn new(
+        tx_to_locator: Option<flume::Sender<(NodeId, IpAddr)>>,
+        opts: AggregatorOpts,
+    ) -> Self {
+        let mut node_state = State::new(
+            opts.denylist,
+            opts.allowlist,
+            opts.max_third_party_nodes,
+            opts.max_chains,
+        );
+        node_state.set_empty_chain_ttl(opts.empty_chain_ttl);
+        node_state.set_max_block_height_jump(opts.max_block_height_jump);
+        node_state.set_stat_history_len(opts.stat_history_len);
+        node_state.set_block_window(opts.block_window);
+        node_state.set_chain_tags(opts.chain_tags);
+        node_state.set_authority_only_chains(opts.authority_only_chains);
+
         InnerLoop {
-            node_state: State::new(opts.denylist, opts.max_third_party_nodes),
+            node_state,
             node_ids: BiMap::new(),
             feed_channels: HashMap::new(),
+            admin_feed_conn_ids: HashSet::new(),
             shard_channels: HashMap::new(),
+            shard_last_seen: HashMap::new(),
+            draining_shards: HashSet::new(),
+            superseded_nodes: HashSet::new(),
             chain_to_feed_conn_ids: MultiMapUnique::new(),
+            chain_to_authority_feed_conn_ids: MultiMapUnique::new(),
+            every_chain_feed_conn_ids: HashSet::new(),
+            chain_to_count_feed_conn_ids: MultiMapUnique::new(),
+            node_count_changes: HashMap::new(),
             tx_to_locator,
+            max_feeds_per_chain: opts.max_feeds_per_chain,
+            max_subscriptions_per_feed: opts.max_subscriptions_per_feed,
             max_queue_len: opts.max_queue_len,
             expose_node_details: opts.expose_node_details,
+            trust_node_timestamps: opts.trust_node_timestamps,
+            peer_count_handling: opts.peer_count_handling,
+            authority_node_token: opts.authority_node_token,
+            node_update_min_interval: opts.node_update_min_interval,
+            snapshot_cache_ttl: opts.snapshot_cache_ttl,
+            snapshot_cache: HashMap::new(),
+            snapshots_built: 0,
+            snapshots_reused: 0,
+            update_latency: LatencyHistogram::new(opts.latency_window),
+            nodes_rejected_for_too_many_chains: 0,
+            event_history_size: opts.event_history_size,
+            event_histories: HashMap::new(),
+            stale_node_timeout: opts.stale_node_timeout,
+            stale_node_check_interval: opts.stale_node_check_interval,
+            feed_heartbeat_interval: opts.feed_heartbeat_interval,
+            node_count_interval: opts.node_count_interval,
+            empty_chain_ttl: opts.empty_chain_ttl,
         }
     }
 
     /// Start handling and responding to incoming messages.
     pub async fn handle(mut self, rx_from_external: flume::Receiver<ToAggregator>) {
         let max_queue_len = self.max_queue_len;
+        let stale_node_timeout = self.stale_node_timeout;
+        let stale_node_check_interval = self.stale_node_check_interval;
+        let feed_heartbeat_interval = self.feed_heartbeat_interval;
+        let node_count_interval = self.node_count_interval;
+        let empty_chain_ttl = self.empty_chain_ttl;
         let (metered_tx, metered_rx) = flume::unbounded();
 
         // Keep count of the number of dropped/total messages for the sake of metric reporting
@@ -215,7 +788,16 @@ impl InnerLoop {
                         self.handle_from_feed(feed_conn_id, msg)
                     }
                     ToAggregator::FromShardWebsocket(shard_conn_id, msg) => {
-                        self.handle_from_shard(shard_conn_id, msg)
+                        let received_at = match &msg {
+                            FromShardWebsocket::Update { received_at, .. } => *received_at,
+                            _ => None,
+                        };
+                        self.handle_from_shard(shard_conn_id, msg);
+                        // By now, any messages for feeds that the update led to have been
+                        // queued up to be sent to them:
+                        if let Some(received_at) = received_at {
+                            self.update_latency.record_since(received_at);
+                        }
                     }
                     ToAggregator::FromFindLocation(node_id, location) => {
                         self.handle_from_find_location(node_id, location)
@@ -226,26 +808,101 @@ impl InnerLoop {
                         dropped_messages2.load(Ordering::Relaxed),
                         total_messages2.load(Ordering::Relaxed),
                     ),
+                    ToAggregator::GatherAdminSnapshot(tx) => self.handle_gather_admin_snapshot(tx),
+                    ToAggregator::GatherStateSnapshot(tx) => self.handle_gather_state_snapshot(tx),
+                    ToAggregator::PruneSilentNodes => self.prune_silent_nodes(time::now()),
+                    ToAggregator::SendHeartbeat => self.send_heartbeat(time::now()),
+                    ToAggregator::SendNodeCounts => self.send_node_counts(),
+                    ToAggregator::RemoveEmptyChains => self.remove_empty_chains(time::now()),
+                    ToAggregator::UpdateChainLists {
+                        denylist,
+                        allowlist,
+                    } => self.handle_update_chain_lists(denylist, allowlist),
                 }
             }
         });
 
-        while let Ok(msg) = rx_from_external.recv_async().await {
+        // Periodically check for nodes that have gone silent, unless this is disabled:
+        let mut prune_interval =
+            (!stale_node_timeout.is_zero() && !stale_node_check_interval.is_zero()).then(|| {
+                let mut interval = tokio::time::interval(stale_node_check_interval);
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                interval
+            });
+
+        // Periodically let feeds know that we're still here, if asked to:
+        let mut heartbeat_interval = (!feed_heartbeat_interval.is_zero()).then(|| {
+            let mut interval = tokio::time::interval(feed_heartbeat_interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            interval
+        });
+
+        // Periodically tell feeds subscribed to node counts about nodes joining and leaving:
+        let mut node_count_interval = (!node_count_interval.is_zero()).then(|| {
+            let mut interval = tokio::time::interval(node_count_interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            interval
+        });
+
+        // Periodically remove chains that have been empty for long enough, if we keep them at all.
+        // Checking at least once a second means that they are removed close to when they expire:
+        let mut empty_chain_interval = (!empty_chain_ttl.is_zero()).then(|| {
+            let mut interval = tokio::time::interval(empty_chain_ttl.min(Duration::from_secs(1)));
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            interval
+        });
+
+        loop {
+            let msg = tokio::select! {
+                msg = rx_from_external.recv_async() => match msg {
+                    Ok(msg) => msg,
+                    Err(_) => break,
+                },
+                _ = tick(&mut prune_interval) => {
+                    if let Err(e) = metered_tx.send(ToAggregator::PruneSilentNodes) {
+                        log::error!("Cannot send message into aggregator: {e}");
+                        break;
+                    }
+                    continue;
+                }
+                _ = tick(&mut heartbeat_interval) => {
+                    if let Err(e) = metered_tx.send(ToAggregator::SendHeartbeat) {
+                        log::error!("Cannot send message into aggregator: {e}");
+                        break;
+                    }
+                    continue;
+                }
+                _ = tick(&mut node_count_interval) => {
+                    if let Err(e) = metered_tx.send(ToAggregator::SendNodeCounts) {
+                        log::error!("Cannot send message into aggregator: {e}");
+                        break;
+                    }
+                    continue;
+                }
+                _ = tick(&mut empty_chain_interval) => {
+                    if let Err(e) = metered_tx.send(ToAggregator::RemoveEmptyChains) {
+                        log::error!("Cannot send message into aggregator: {e}");
+                        break;
+                    }
+                    continue;
+                }
+            };
+
             total_messages.fetch_add(1, Ordering::Relaxed);
 
             // ignore node updates if we have too many messages to handle, in an attempt
             // to reduce the queue length back to something reasonable, lest it get out of
             // control and start consuming a load of memory.
-            if metered_tx.len() > max_queue_len {
-                if matches!(
+            if metered_tx.len() > max_queue_len
+                && matches!(
                     msg,
                     ToAggregator::FromShardWebsocket(.., FromShardWebsocket::Update { .. })
-                ) {
-                    // Note: this wraps on overflow (which is probably the best
-                    // behaviour for graphing it anyway)
-                    dropped_messages.fetch_add(1, Ordering::Relaxed);
-                    continue;
-                }
+                )
+            {
+                // Note: this wraps on overflow (which is probably the best
+                // behaviour for graphing it anyway)
+                dropped_messages.fetch_add(1, Ordering::Relaxed);
+                continue;
             }
 
             if let Err(e) = metered_tx.send(msg) {
@@ -265,11 +922,21 @@ impl InnerLoop {
     ) {
         let timestamp_unix_ms = time::now();
         let connected_nodes = self.node_ids.len();
-        let subscribed_feeds = self.chain_to_feed_conn_ids.num_values();
-        let chains_subscribed_to = self.chain_to_feed_conn_ids.num_keys();
+        let subscribed_feeds = self.chain_to_feed_conn_ids.num_values()
+            + self.chain_to_authority_feed_conn_ids.num_values()
+            + self.every_chain_feed_conn_ids.len()
+            + self.chain_to_count_feed_conn_ids.num_values();
+        let chains_subscribed_to = self.chain_to_feed_conn_ids.num_keys()
+            + self.chain_to_authority_feed_conn_ids.num_keys();
         let connected_shards = self.shard_channels.len();
         let connected_feeds = self.feed_channels.len();
         let total_messages_to_feeds: usize = self.feed_channels.values().map(|c| c.len()).sum();
+        let chain_reorgs = self
+            .node_state
+            .iter_chains()
+            .map(|chain| (chain.genesis_hash(), chain.reorg_count()))
+            .filter(|&(_, reorgs)| reorgs > 0)
+            .collect();
 
         // Ignore error sending; assume the receiver stopped caring and dropped the channel:
         let _ = rx.send(Metrics {
@@ -283,53 +950,349 @@ impl InnerLoop {
             connected_nodes,
             connected_feeds,
             connected_shards,
+            snapshots_built: self.snapshots_built,
+            snapshots_reused: self.snapshots_reused,
+            update_latency: self.update_latency.percentiles(),
+            nodes_rejected_for_too_many_chains: self.nodes_rejected_for_too_many_chains,
+            abandoned_location_lookups: 0,
+            chain_reorgs,
         });
     }
 
+    /// Gather and return a snapshot of the chains and shards we know about.
+    fn handle_gather_admin_snapshot(&mut self, tx: flume::Sender<AdminSnapshot>) {
+        let mut chains: Vec<_> = self
+            .node_state
+            .iter_chains()
+            .map(|chain| AdminChain {
+                genesis_hash: chain.genesis_hash(),
+                label: chain.label().to_owned(),
+                node_count: chain.node_count(),
+            })
+            .collect();
+        chains.sort_by(|a, b| {
+            b.node_count
+                .cmp(&a.node_count)
+                .then_with(|| a.label.cmp(&b.label))
+        });
+
+        let shards = self.shards();
+
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = tx.send(AdminSnapshot { chains, shards });
+    }
+
+    /// Serialize everything that we know about each chain and its nodes into lines of JSON,
+    /// and send them on in chunks as they're ready. The snapshot is taken all at once, so
+    /// it's consistent, but the chunks can be handed on while later ones are serialized.
+    fn handle_gather_state_snapshot(&mut self, tx: flume::Sender<bytes::Bytes>) {
+        let mut chunk = Vec::new();
+        let push_line = |chunk: &mut Vec<u8>, line: StateSnapshotLine| {
+            serde_json::to_writer(&mut *chunk, &line).expect("snapshot line should serialize");
+            chunk.push(b'\n');
+            if chunk.len() < STATE_SNAPSHOT_CHUNK_BYTES {
+                return true;
+            }
+            // Stop early if the receiver has stopped caring and dropped the channel:
+            tx.send(std::mem::take(chunk).into()).is_ok()
+        };
+
+        for chain in self.node_state.iter_chains() {
+            let genesis_hash = chain.genesis_hash();
+            let line = StateSnapshotLine::Chain(StateSnapshotChain {
+                genesis_hash,
+                label: chain.label(),
+                tags: chain.tags(),
+                node_count: chain.node_count(),
+                best_block: chain.best_block(),
+                finalized_block: chain.finalized_block(),
+                average_block_time: chain.average_block_time(),
+            });
+            if !push_line(&mut chunk, line) {
+                return;
+            }
+
+            let nodes = chain.nodes_slice().iter().enumerate();
+            for (id, node) in nodes.filter_map(|(id, n)| n.as_ref().map(|n| (id, n))) {
+                let line = StateSnapshotLine::Node(StateSnapshotNode {
+                    genesis_hash,
+                    id,
+                    details: node.details(),
+                    stats: node.stats(),
+                    io: node.io(),
+                    hardware: node.hardware(),
+                    best: node.best(),
+                    finalized: node.finalized(),
+                    block_details: node.block_details(),
+                    location: node.location(),
+                    hwbench: node.hwbench(),
+                    custom_metrics: node.custom_metrics(),
+                    shard_id: node.shard_id(),
+                    clock_skew: node.clock_skew(),
+                    stale: node.stale(),
+                });
+                if !push_line(&mut chunk, line) {
+                    return;
+                }
+            }
+        }
+
+        if !chunk.is_empty() {
+            let _ = tx.send(chunk.into());
+        }
+    }
+
+    /// Details about each of the shards connected to us, in the order they connected.
+    fn shards(&self) -> Vec<AdminShard> {
+        let mut shards: HashMap<ConnId, AdminShard> = self
+            .shard_channels
+            .iter()
+            .map(|(&conn_id, channel)| {
+                let shard = AdminShard {
+                    conn_id: conn_id.into(),
+                    shard_id: None,
+                    node_count: 0,
+                    connected: !channel.is_disconnected(),
+                    draining: self.draining_shards.contains(&conn_id),
+                    last_seen: self
+                        .shard_last_seen
+                        .get(&conn_id)
+                        .copied()
+                        .unwrap_or_default(),
+                };
+                (conn_id, shard)
+            })
+            .collect();
+        for (&node_id, &(shard_conn_id, _)) in self.node_ids.iter() {
+            let shard = match shards.get_mut(&shard_conn_id) {
+                Some(shard) => shard,
+                None => continue,
+            };
+            shard.node_count += 1;
+            if shard.shard_id.is_none() {
+                shard.shard_id = self
+                    .node_state
+                    .get_chain_by_node_id(node_id)
+                    .and_then(|chain| chain.get_node(node_id.get_chain_node_id()))
+                    .and_then(|node| node.shard_id())
+                    .map(|id| id.to_owned());
+            }
+        }
+        let mut shards: Vec<_> = shards.into_values().collect();
+        shards.sort_by_key(|shard| shard.conn_id);
+        shards
+    }
+
+    /// Push a [`feed_message::Shards`] message listing the shards connected to us.
+    fn push_shards(&self, feed_serializer: &mut MultiFormatSerializer) {
+        let shards = self.shards();
+        feed_serializer.push(feed_message::Shards(
+            shards
+                .iter()
+                .map(|s| {
+                    (
+                        s.conn_id,
+                        s.shard_id.as_deref(),
+                        s.node_count,
+                        s.last_seen,
+                        s.draining,
+                    )
+                })
+                .collect(),
+        ));
+    }
+
+    /// Tell every admin feed about the shards that are connected to us.
+    fn broadcast_shards_to_admin_feeds(&mut self) {
+        if self.admin_feed_conn_ids.is_empty() {
+            return;
+        }
+        let mut feed_serializer =
+            serializer_for_feeds(&self.feed_channels, &self.admin_feed_conn_ids);
+        self.push_shards(&mut feed_serializer);
+        let bytes = feed_serializer.into_finalized();
+        send_to_feeds(&mut self.feed_channels, &self.admin_feed_conn_ids, &bytes);
+    }
+
+    /// Remove any nodes that we haven't received a message from within the stale node timeout.
+    fn prune_silent_nodes(&mut self, now: common::node_types::Timestamp) {
+        let threshold = now.saturating_sub(self.stale_node_timeout.as_millis() as u64);
+        let node_ids = self.node_state.silent_node_ids(threshold);
+        if node_ids.is_empty() {
+            return;
+        }
+
+        log::info!(
+            "Removing {} node(s) that have not sent a message in {:?}",
+            node_ids.len(),
+            self.stale_node_timeout
+        );
+        self.remove_nodes_and_broadcast_result(node_ids, NodeRemovalReason::TimedOut);
+    }
+
+    /// Send a heartbeat, carrying the current time, to every feed.
+    fn send_heartbeat(&mut self, now: common::node_types::Timestamp) {
+        let mut feed_serializer = self.new_all_feeds_serializer();
+        feed_serializer.push(feed_message::Heartbeat(now));
+        self.finalize_and_broadcast_to_all_feeds(feed_serializer);
+    }
+
+    /// Tell feeds subscribed to the node counts of chains how many nodes have joined and left
+    /// them since they were last told, and how many nodes they have now. Chains that no nodes
+    /// have joined or left in the meantime aren't mentioned.
+    fn send_node_counts(&mut self) {
+        for (genesis_hash, (added, removed)) in std::mem::take(&mut self.node_count_changes) {
+            let feeds = match self.chain_to_count_feed_conn_ids.get_values(&genesis_hash) {
+                Some(feeds) => feeds,
+                None => continue,
+            };
+            let total = self
+                .node_state
+                .get_chain_by_genesis_hash(&genesis_hash)
+                .map_or(0, |chain| chain.node_count());
+
+            let mut feed_serializer = serializer_for_feeds(&self.feed_channels, feeds);
+            feed_serializer.push(feed_message::NodeCount(genesis_hash, added, removed, total));
+            let bytes = feed_serializer.into_finalized();
+            send_to_feeds(&mut self.feed_channels, feeds, &bytes);
+        }
+    }
+
+    /// Remove chains that have had no nodes for at least the empty chain TTL, and tell feeds.
+    fn remove_empty_chains(&mut self, now: common::node_types::Timestamp) {
+        let genesis_hashes = self.node_state.remove_empty_chains(now);
+        if genesis_hashes.is_empty() {
+            return;
+        }
+
+        log::info!(
+            "Removing {} chain(s) that have had no nodes for {:?}",
+            genesis_hashes.len(),
+            self.empty_chain_ttl
+        );
+        let mut feed_messages_for_all = self.new_all_feeds_serializer();
+        for genesis_hash in genesis_hashes {
+            self.forget_chain(genesis_hash);
+            feed_messages_for_all.push(feed_message::RemovedChain(genesis_hash));
+        }
+        self.finalize_and_broadcast_to_all_feeds(feed_messages_for_all);
+    }
+
+    /// A chain has been removed, so we no longer need any snapshots or history of it.
+    fn forget_chain(&mut self, genesis_hash: BlockHash) {
+        self.snapshot_cache
+            .retain(|(hash, _, _), _| *hash != genesis_hash);
+        self.event_histories.remove(&genesis_hash);
+    }
+
+    /// Swap in new chain deny and allow lists. Nodes already connected from chains that are
+    /// no longer allowed are muted and removed, as they would have been had they connected
+    /// after the change.
+    fn handle_update_chain_lists(&mut self, denylist: Vec<String>, allowlist: Vec<AllowedChain>) {
+        let node_ids = self.node_state.set_chain_lists(denylist, allowlist);
+        if node_ids.is_empty() {
+            return;
+        }
+
+        log::info!(
+            "Removing {} node(s) from chains that are no longer allowed",
+            node_ids.len()
+        );
+        for node_id in &node_ids {
+            let (shard_conn_id, local_id) = match self.node_ids.get_by_left(node_id) {
+                Some(&ids) => ids,
+                None => continue,
+            };
+            if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
+                let _ = shard_conn.send(ToShardWebsocket::Mute {
+                    local_id,
+                    reason: MuteReason::ChainNotAllowed,
+                });
+            }
+        }
+        self.remove_nodes_and_broadcast_result(node_ids, NodeRemovalReason::ChainNotAllowed);
+    }
+
     /// Handle messages that come from the node geographical locator.
     fn handle_from_find_location(&mut self, node_id: NodeId, location: find_location::Location) {
         self.node_state
             .update_node_location(node_id, location.clone());
 
         if let Some(loc) = location {
-            let mut feed_message_serializer = FeedMessageSerializer::new();
-            feed_message_serializer.push(feed_message::LocatedNode(
-                node_id.get_chain_node_id().into(),
-                loc.latitude,
-                loc.longitude,
-                &loc.city,
-            ));
+            let (chain_genesis_hash, is_authority) =
+                match self.node_state.get_chain_by_node_id(node_id) {
+                    Some(chain) => (
+                        chain.genesis_hash(),
+                        chain
+                            .get_node(node_id.get_chain_node_id())
+                            .is_some_and(|node| node.is_authority()),
+                    ),
+                    None => return,
+                };
 
-            let chain_genesis_hash = self
-                .node_state
-                .get_chain_by_node_id(node_id)
-                .map(|chain| chain.genesis_hash());
+            let mut feed_message_serializer = self.new_chain_feed_serializer(&chain_genesis_hash);
+            feed_message_serializer.push_for_node(
+                is_authority,
+                feed_message::LocatedNode(
+                    node_id.get_chain_node_id().into(),
+                    loc.latitude,
+                    loc.longitude,
+                    &loc.city,
+                ),
+            );
 
-            if let Some(chain_genesis_hash) = chain_genesis_hash {
-                self.finalize_and_broadcast_to_chain_feeds(
-                    &chain_genesis_hash,
-                    feed_message_serializer,
-                );
-            }
+            self.finalize_and_broadcast_to_chain_feeds(
+                &chain_genesis_hash,
+                feed_message_serializer,
+            );
         }
     }
 
     /// Handle messages coming from shards.
     fn handle_from_shard(&mut self, shard_conn_id: ConnId, msg: FromShardWebsocket) {
+        if !matches!(msg, FromShardWebsocket::Disconnected) {
+            self.shard_last_seen.insert(shard_conn_id, time::now());
+        }
+
         match msg {
             FromShardWebsocket::Initialize { channel } => {
                 self.shard_channels.insert(shard_conn_id, channel);
+                self.broadcast_shards_to_admin_feeds();
             }
             FromShardWebsocket::Add {
                 local_id,
                 ip,
                 mut node,
                 genesis_hash,
+                shard_id,
+                token,
             } => {
                 // Conditionally modify the node's details to include the IP address.
-                node.ip = self.expose_node_details.then_some(ip.to_string().into());
-                match self.node_state.add_node(genesis_hash, node) {
-                    state::AddNodeResult::ChainOnDenyList => {
+                let keep_ip = self.expose_node_details && self.tx_to_locator.is_some();
+                node.ip = keep_ip.then_some(ip.to_string().into());
+
+                // Authority nodes that give the right token don't need their chain to be allowed:
+                let bypass_allowlist = node.authority
+                    && matches!(
+                        (&self.authority_node_token, &token),
+                        (Some(expected), Some(given)) if constant_time_eq(expected.as_bytes(), given.as_bytes())
+                    );
+
+                // A node that reconnects before we've noticed that its old connection has gone
+                // would otherwise show up twice. The old node is only replaced once the new one
+                // has been let in, so that a node that's turned away can't knock out a node that
+                // claims the same identity:
+                let superseded_node_id = self.node_state.find_superseded_node(genesis_hash, &node);
+
+                let mut feed_messages_for_chain = self.new_chain_feed_serializer(&genesis_hash);
+                match self.node_state.add_node_with_allowlist_bypass(
+                    genesis_hash,
+                    node,
+                    bypass_allowlist,
+                ) {
+                    state::AddNodeResult::ChainOnDenyList
+                    | state::AddNodeResult::ChainNotOnAllowList
+                    | state::AddNodeResult::NotAnAuthority => {
                         if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
                             let _ = shard_conn.send(ToShardWebsocket::Mute {
                                 local_id,
@@ -345,6 +1308,22 @@ impl InnerLoop {
                             });
                         }
                     }
+                    state::AddNodeResult::TooManyChains => {
+                        self.nodes_rejected_for_too_many_chains += 1;
+                        // Don't flood the logs if lots of nodes are being turned away:
+                        if self.nodes_rejected_for_too_many_chains.is_power_of_two() {
+                            log::warn!(
+                                "Tracking the maximum number of chains; {} nodes on new chains have been rejected so far",
+                                self.nodes_rejected_for_too_many_chains
+                            );
+                        }
+                        if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
+                            let _ = shard_conn.send(ToShardWebsocket::Mute {
+                                local_id,
+                                reason: MuteReason::TooManyChains,
+                            });
+                        }
+                    }
                     state::AddNodeResult::NodeAddedToChain(details) => {
                         let node_id = details.id;
 
@@ -354,37 +1333,114 @@ impl InnerLoop {
                         // Don't hold onto details too long because we want &mut self later:
                         let new_chain_label = details.new_chain_label.to_owned();
                         let chain_node_count = details.chain_node_count;
+                        let chain_added = details.chain_added;
                         let has_chain_label_changed = details.has_chain_label_changed;
 
+                        if self
+                            .chain_to_count_feed_conn_ids
+                            .get_values(&genesis_hash)
+                            .is_some()
+                        {
+                            self.node_count_changes.entry(genesis_hash).or_default().0 += 1;
+                        }
+
+                        // Feeds subscribed to every chain are subscribed to new ones too:
+                        if chain_added && !self.every_chain_feed_conn_ids.is_empty() {
+                            let feeds = &self.every_chain_feed_conn_ids;
+                            let mut feed_serializer =
+                                serializer_for_feeds(&self.feed_channels, feeds);
+                            feed_serializer.push(feed_message::SubscribedTo(genesis_hash));
+                            let bytes = feed_serializer.into_finalized();
+                            send_to_feeds(&mut self.feed_channels, feeds, &bytes);
+                        }
+
+                        if self.event_history_size > 0 {
+                            self.event_histories
+                                .entry(genesis_hash)
+                                .or_insert_with(|| EventHistory::new(self.event_history_size))
+                                .push(
+                                    node_id.get_chain_node_id().into(),
+                                    details.node.is_authority(),
+                                    feed_message::AddedNode(
+                                        node_id.get_chain_node_id().into(),
+                                        details.node,
+                                        self.expose_node_details,
+                                    ),
+                                );
+                        }
+
                         // Tell chain subscribers about the node we've just added:
-                        let mut feed_messages_for_chain = FeedMessageSerializer::new();
-                        feed_messages_for_chain.push(feed_message::AddedNode(
-                            node_id.get_chain_node_id().into(),
-                            &details.node,
-                            self.expose_node_details,
-                        ));
+                        feed_messages_for_chain.push_for_node(
+                            details.node.is_authority(),
+                            feed_message::AddedNode(
+                                node_id.get_chain_node_id().into(),
+                                details.node,
+                                self.expose_node_details,
+                            ),
+                        );
+                        // Let chain subscribers know if the chain has a new label. They stay
+                        // subscribed to it, since subscriptions are by genesis hash:
+                        if has_chain_label_changed {
+                            feed_messages_for_chain.push(feed_message::ChainLabelChanged(
+                                genesis_hash,
+                                &new_chain_label,
+                            ));
+                        }
                         self.finalize_and_broadcast_to_chain_feeds(
                             &genesis_hash,
                             feed_messages_for_chain,
                         );
+
+                        // Make a note of which shard the node is connected through. If the shard
+                        // didn't give us an ID, we fall back to the ID of its connection to us.
+                        let shard_id = shard_id
+                            .unwrap_or_else(|| format!("conn-{}", u64::from(shard_conn_id)).into());
+                        self.node_state.update_node_shard_id(node_id, shard_id);
+
                         // Tell everybody about the new node count and potential rename:
-                        let mut feed_messages_for_all = FeedMessageSerializer::new();
-                        if has_chain_label_changed {
-                            feed_messages_for_all.push(feed_message::RemovedChain(genesis_hash));
-                        }
+                        let mut feed_messages_for_all = self.new_all_feeds_serializer();
                         feed_messages_for_all.push(feed_message::AddedChain(
                             &new_chain_label,
                             genesis_hash,
                             chain_node_count,
+                            self.chain_tags(&genesis_hash),
                         ));
                         self.finalize_and_broadcast_to_all_feeds(feed_messages_for_all);
 
+                        // Mute the old connection of a node that's reconnected, so that the
+                        // shard closes it, and remove the old node:
+                        if let Some(old_node_id) = superseded_node_id {
+                            if let Some((_, (old_shard_conn_id, old_local_id))) =
+                                self.node_ids.remove_by_left(&old_node_id)
+                            {
+                                self.superseded_nodes
+                                    .insert((old_shard_conn_id, old_local_id));
+                                if let Some(shard_conn) =
+                                    self.shard_channels.get_mut(&old_shard_conn_id)
+                                {
+                                    let _ = shard_conn.send(ToShardWebsocket::Mute {
+                                        local_id: old_local_id,
+                                        reason: MuteReason::Superseded,
+                                    });
+                                }
+                            }
+                            self.remove_nodes_and_broadcast_result(
+                                Some(old_node_id),
+                                NodeRemovalReason::Superseded,
+                            );
+                        }
+
                         // Ask for the geographical location of the node.
-                        let _ = self.tx_to_locator.send((node_id, ip));
+                        if let Some(tx_to_locator) = &self.tx_to_locator {
+                            let _ = tx_to_locator.send((node_id, ip));
+                        }
                     }
                 }
             }
-            FromShardWebsocket::Remove { local_id } => {
+            FromShardWebsocket::Remove { local_id, reason } => {
+                if self.superseded_nodes.remove(&(shard_conn_id, local_id)) {
+                    return;
+                }
                 let node_id = match self.node_ids.remove_by_right(&(shard_conn_id, local_id)) {
                     Some((node_id, _)) => node_id,
                     None => {
@@ -397,11 +1453,33 @@ impl InnerLoop {
                         return;
                     }
                 };
-                self.remove_nodes_and_broadcast_result(Some(node_id));
+                self.remove_nodes_and_broadcast_result(Some(node_id), reason);
+            }
+            FromShardWebsocket::Draining { draining } => {
+                let changed = if draining {
+                    self.draining_shards.insert(shard_conn_id)
+                } else {
+                    self.draining_shards.remove(&shard_conn_id)
+                };
+                if changed {
+                    log::info!(
+                        "Shard {shard_conn_id:?} has {} draining",
+                        if draining { "started" } else { "stopped" }
+                    );
+                    self.broadcast_shards_to_admin_feeds();
+                }
             }
-            FromShardWebsocket::Update { local_id, payload } => {
+            FromShardWebsocket::Update {
+                local_id,
+                payload,
+                sent_at,
+                ..
+            } => {
                 let node_id = match self.node_ids.get_by_right(&(shard_conn_id, local_id)) {
                     Some(id) => *id,
+                    // Nodes that have been superseded can send a few more messages before their
+                    // connections are closed; we don't need them:
+                    None if self.superseded_nodes.contains(&(shard_conn_id, local_id)) => return,
                     None => {
                         log::error!(
                             "Update: Cannot find ID for node with shard/connectionId of {shard_conn_id:?}/{local_id:?}"
@@ -410,24 +1488,71 @@ impl InnerLoop {
                     }
                 };
 
-                let mut feed_message_serializer = FeedMessageSerializer::new();
+                let genesis_hash = match self.node_state.get_chain_by_node_id(node_id) {
+                    Some(chain) => chain.genesis_hash(),
+                    None => {
+                        log::error!("Update: Cannot find chain for node {node_id:?}");
+                        return;
+                    }
+                };
+
+                let blocks_before = self.node_blocks(node_id);
+
+                let mut feed_message_serializer = self.new_chain_feed_serializer(&genesis_hash);
                 self.node_state.update_node(
                     node_id,
                     payload,
+                    sent_at.filter(|_| self.trust_node_timestamps),
                     &mut feed_message_serializer,
                     self.expose_node_details,
+                    self.peer_count_handling,
+                    self.node_update_min_interval,
                 );
+                self.finalize_and_broadcast_to_chain_feeds(&genesis_hash, feed_message_serializer);
 
-                if let Some(chain) = self.node_state.get_chain_by_node_id(node_id) {
-                    let genesis_hash = chain.genesis_hash();
-                    self.finalize_and_broadcast_to_chain_feeds(
-                        &genesis_hash,
-                        feed_message_serializer,
-                    );
+                if let Some(blocks_before) = blocks_before {
+                    self.record_block_events(genesis_hash, node_id, blocks_before);
+                }
+            }
+            FromShardWebsocket::ClockSkew { local_id, skew_ms } => {
+                let node_id = match self.node_ids.get_by_right(&(shard_conn_id, local_id)) {
+                    Some(id) => *id,
+                    None if self.superseded_nodes.contains(&(shard_conn_id, local_id)) => return,
+                    None => {
+                        log::error!(
+                            "ClockSkew: Cannot find ID for node with shard/connectionId of {shard_conn_id:?}/{local_id:?}"
+                        );
+                        return;
+                    }
+                };
+
+                if !self.node_state.update_node_clock_skew(node_id, skew_ms) {
+                    return;
                 }
+                let (genesis_hash, is_authority) =
+                    match self.node_state.get_chain_by_node_id(node_id) {
+                        Some(chain) => (
+                            chain.genesis_hash(),
+                            chain
+                                .get_node(node_id.get_chain_node_id())
+                                .is_some_and(|node| node.is_authority()),
+                        ),
+                        None => return,
+                    };
+
+                let mut feed_message_serializer = self.new_chain_feed_serializer(&genesis_hash);
+                feed_message_serializer.push_for_node(
+                    is_authority,
+                    feed_message::NodeClockSkew(node_id.get_chain_node_id().into(), skew_ms),
+                );
+                self.finalize_and_broadcast_to_chain_feeds(&genesis_hash, feed_message_serializer);
             }
             FromShardWebsocket::Disconnected => {
                 self.shard_channels.remove(&shard_conn_id);
+                self.shard_last_seen.remove(&shard_conn_id);
+                self.draining_shards.remove(&shard_conn_id);
+                self.superseded_nodes
+                    .retain(|&(this_shard_conn_id, _)| shard_conn_id != this_shard_conn_id);
 
                 // Find all nodes associated with this shard connection ID:
                 let node_ids_to_remove: Vec<NodeId> = self
@@ -438,7 +1563,11 @@ impl InnerLoop {
                     .collect();
 
                 // ... and remove them:
-                self.remove_nodes_and_broadcast_result(node_ids_to_remove);
+                self.remove_nodes_and_broadcast_result(
+                    node_ids_to_remove,
+                    NodeRemovalReason::ShardDisconnected,
+                );
+                self.broadcast_shards_to_admin_feeds();
             }
         }
     }
@@ -446,23 +1575,50 @@ impl InnerLoop {
     /// Handle messages coming from feeds.
     fn handle_from_feed(&mut self, feed_conn_id: ConnId, msg: FromFeedWebsocket) {
         match msg {
-            FromFeedWebsocket::Initialize { channel } => {
-                self.feed_channels.insert(feed_conn_id, channel.clone());
+            FromFeedWebsocket::Initialize {
+                channel,
+                admin,
+                protocol,
+            } => {
+                let channel = self
+                    .feed_channels
+                    .entry(feed_conn_id)
+                    .insert_entry(FeedChannel::new(channel, protocol))
+                    .into_mut();
+                if admin {
+                    self.admin_feed_conn_ids.insert(feed_conn_id);
+                }
 
                 // Tell the new feed subscription some basic things to get it going:
-                let mut feed_serializer = FeedMessageSerializer::new();
+                let mut feed_serializer = channel.serializer();
                 feed_serializer.push(feed_message::Version(32));
+                let build_info = BuildInfo::get();
+                feed_serializer.push(feed_message::BuildInfo(
+                    build_info.version,
+                    build_info.commit,
+                    build_info.built_at,
+                ));
                 for chain in self.node_state.iter_chains() {
                     feed_serializer.push(feed_message::AddedChain(
                         chain.label(),
                         chain.genesis_hash(),
                         chain.node_count(),
+                        chain.tags(),
                     ));
                 }
 
                 // Send this to the channel that subscribed:
                 if let Some(bytes) = feed_serializer.into_finalized() {
-                    let _ = channel.send(ToFeedWebsocket::Bytes(bytes));
+                    channel.send(ToFeedWebsocket::Bytes(bytes));
+                }
+
+                // Admin feeds are also told about the shards connected to us:
+                if admin {
+                    let feeds = [feed_conn_id];
+                    let mut feed_serializer = serializer_for_feeds(&self.feed_channels, &feeds);
+                    self.push_shards(&mut feed_serializer);
+                    let bytes = feed_serializer.into_finalized();
+                    send_to_feeds(&mut self.feed_channels, &feeds, &bytes);
                 }
             }
             FromFeedWebsocket::Ping { value } => {
@@ -472,107 +1628,569 @@ impl InnerLoop {
                 };
 
                 // Pong!
-                let mut feed_serializer = FeedMessageSerializer::new();
+                let mut feed_serializer = feed_channel.serializer();
                 feed_serializer.push(feed_message::Pong(&value));
                 if let Some(bytes) = feed_serializer.into_finalized() {
-                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                    feed_channel.send(ToFeedWebsocket::Bytes(bytes));
                 }
             }
-            FromFeedWebsocket::Subscribe { chain } => {
+            FromFeedWebsocket::InvalidCommand { error } => {
                 let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
                     Some(chan) => chan,
                     None => return,
                 };
 
-                // Unsubscribe from previous chain if subscribed to one:
-                let old_genesis_hash = self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
-
-                // Get old chain if there was one:
-                let node_state = &self.node_state;
-                let old_chain =
+                let mut feed_serializer = feed_channel.serializer();
+                feed_serializer.push(feed_message::CommandError(&error));
+                if let Some(bytes) = feed_serializer.into_finalized() {
+                    feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                }
+            }
+            FromFeedWebsocket::ListChains => {
+                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
+                    Some(chan) => chan,
+                    None => return,
+                };
+
+                let chains = self
+                    .node_state
+                    .iter_chains()
+                    .map(|chain| {
+                        (
+                            chain.label(),
+                            chain.genesis_hash(),
+                            chain.node_count(),
+                            chain.tags(),
+                        )
+                    })
+                    .collect();
+                let mut feed_serializer = feed_channel.serializer();
+                feed_serializer.push(feed_message::ChainsList(chains));
+                if let Some(bytes) = feed_serializer.into_finalized() {
+                    feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                }
+            }
+            FromFeedWebsocket::TopChains { n, by } => {
+                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
+                    Some(chan) => chan,
+                    None => return,
+                };
+
+                let chains = top_chains(&self.node_state, n, by);
+                let mut feed_serializer = feed_channel.serializer();
+                feed_serializer.push(feed_message::TopChains(chains));
+                if let Some(bytes) = feed_serializer.into_finalized() {
+                    feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                }
+            }
+            FromFeedWebsocket::Subscribe {
+                chain,
+                ordering,
+                authorities_only,
+                replay,
+                events,
+                lean,
+                bignums_as_strings,
+            } => {
+                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
+                    Some(chan) => chan,
+                    None => return,
+                };
+
+                // A chain asked for by label is subscribed to by its genesis hash, so that
+                // the subscription follows the chain if its label changes:
+                let chain = match chain {
+                    FeedChain::GenesisHash(genesis_hash) => genesis_hash,
+                    FeedChain::Label(label) => match self.node_state.get_chain_by_label(&label) {
+                        Some(chain) => chain.genesis_hash(),
+                        None => {
+                            let mut feed_serializer = feed_channel.serializer();
+                            feed_serializer.push(feed_message::SubscribeError(
+                                SubscribeErrorCode::UnknownLabel,
+                                None,
+                            ));
+                            if let Some(bytes) = feed_serializer.into_finalized() {
+                                feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                            }
+                            return;
+                        }
+                    },
+                };
+
+                // Check that the feed can subscribe to the new chain before touching any
+                // subscription it already has, telling it why not if it can't:
+                let error = match self.node_state.get_chain_by_genesis_hash(&chain) {
+                    None => Some(SubscribeErrorCode::UnknownChain),
+                    Some(_)
+                        if !feed_channel.subscribed_chains.contains(&chain)
+                            && feed_channel.subscribed_chains.len()
+                                >= self.max_subscriptions_per_feed =>
+                    {
+                        Some(SubscribeErrorCode::TooManySubscriptions)
+                    }
+                    Some(_) => {
+                        let subscribed = [
+                            &self.chain_to_feed_conn_ids,
+                            &self.chain_to_authority_feed_conn_ids,
+                        ];
+                        let is_resubscribing = subscribed
+                            .iter()
+                            .any(|feeds| feeds.get_key(&feed_conn_id) == Some(&chain));
+                        let num_feeds: usize = subscribed
+                            .iter()
+                            .map(|feeds| feeds.get_values(&chain).map_or(0, |f| f.len()))
+                            .sum();
+                        (!is_resubscribing && num_feeds >= self.max_feeds_per_chain)
+                            .then_some(SubscribeErrorCode::TooManyFeeds)
+                    }
+                };
+                if let Some(code) = error {
+                    let mut feed_serializer = feed_channel.serializer();
+                    feed_serializer.push(feed_message::SubscribeError(code, Some(chain)));
+                    if let Some(bytes) = feed_serializer.into_finalized() {
+                        feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                    }
+                    return;
+                }
+
+                // A feed subscribed to every chain is only subscribed to this one now:
+                if self.every_chain_feed_conn_ids.remove(&feed_conn_id) {
+                    let mut feed_serializer = feed_channel.serializer();
+                    for old_chain in self.node_state.iter_chains() {
+                        feed_serializer
+                            .push(feed_message::UnsubscribedFrom(old_chain.genesis_hash()));
+                    }
+                    if let Some(bytes) = feed_serializer.into_finalized() {
+                        feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                    }
+                }
+
+                // Unsubscribe from previous chain if subscribed to one:
+                let old_genesis_hash = self
+                    .chain_to_feed_conn_ids
+                    .remove_value(&feed_conn_id)
+                    .or_else(|| {
+                        self.chain_to_authority_feed_conn_ids
+                            .remove_value(&feed_conn_id)
+                    })
+                    .or_else(|| {
+                        self.chain_to_count_feed_conn_ids
+                            .remove_value(&feed_conn_id)
+                    });
+
+                // Get old chain if there was one:
+                let node_state = &self.node_state;
+                let old_chain =
                     old_genesis_hash.and_then(|hash| node_state.get_chain_by_genesis_hash(&hash));
 
-                // Get new chain, ignoring the rest if it doesn't exist.
+                // Get new chain (which we know exists by now):
                 let new_chain = match self.node_state.get_chain_by_genesis_hash(&chain) {
                     Some(chain) => chain,
                     None => return,
                 };
 
-                // Send messages to the feed about this subscription:
-                let mut feed_serializer = FeedMessageSerializer::new();
+                // Let the feed know that it's no longer subscribed to the old chain:
                 if let Some(old_chain) = old_chain {
+                    let mut feed_serializer = feed_channel.serializer();
                     feed_serializer.push(feed_message::UnsubscribedFrom(old_chain.genesis_hash()));
+                    if let Some(bytes) = feed_serializer.into_finalized() {
+                        feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                    }
                 }
-                feed_serializer.push(feed_message::SubscribedTo(new_chain.genesis_hash()));
-                feed_serializer.push(feed_message::TimeSync(time::now()));
-                feed_serializer.push(feed_message::BestBlock(
-                    new_chain.best_block().height,
-                    new_chain.timestamp(),
-                    new_chain.average_block_time(),
-                ));
-                feed_serializer.push(feed_message::BestFinalized(
-                    new_chain.finalized_block().height,
-                    new_chain.finalized_block().hash,
-                ));
-                feed_serializer.push(feed_message::ChainStatsUpdate(new_chain.stats()));
-                if let Some(bytes) = feed_serializer.into_finalized() {
-                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
-                }
-
-                // If many (eg 10k) nodes are connected, serializing all of their info takes time.
-                // So, parallelise this with Rayon, but we still send out messages for each node in order
-                // (which is helpful for the UI as it tries to maintain a sorted list of nodes). The chunk
-                // size is the max number of node info we fit into 1 message; smaller messages allow the UI
-                // to react a little faster and not have to wait for a larger update to come in. A chunk size
-                // of 64 means each message is ~32k.
-                use rayon::prelude::*;
-                let all_feed_messages: Vec<_> = new_chain
-                    .nodes_slice()
-                    .par_iter()
-                    .enumerate()
-                    .chunks(64)
-                    .filter_map(|nodes| {
-                        let mut feed_serializer = FeedMessageSerializer::new();
-                        for (node_id, node) in nodes
-                            .iter()
-                            .filter_map(|&(idx, n)| n.as_ref().map(|n| (idx, n)))
-                        {
-                            feed_serializer.push(feed_message::AddedNode(
-                                node_id,
-                                node,
-                                self.expose_node_details,
-                            ));
-                            feed_serializer.push(feed_message::FinalizedBlock(
-                                node_id,
-                                node.finalized().height,
-                                node.finalized().hash,
-                            ));
-                            if node.stale() {
-                                feed_serializer.push(feed_message::StaleNode(node_id));
+
+                // Everything from here on is about the new chain, and so only the events
+                // that the feed asked for are sent, in the form that it asked for them:
+                feed_channel.format = FeedFormat {
+                    protocol: feed_channel.format.protocol,
+                    events,
+                    finality: feed_channel.finality_chain == Some(chain),
+                    lean,
+                    bignums_as_strings,
+                };
+                feed_channel.subscribed_chains.insert(chain);
+
+                // If another feed subscribed to this chain very recently, we can hand back the same
+                // snapshot of the chain that we sent to it, followed by any messages that have been
+                // sent to feeds for the chain since, rather than serializing everything again.
+                let new_genesis_hash = new_chain.genesis_hash();
+                let format = feed_channel.format;
+                let cache_key = (new_genesis_hash, authorities_only, format);
+                let snapshot_cache_ttl = self.snapshot_cache_ttl;
+                let cached_snapshot = self
+                    .snapshot_cache
+                    .get(&cache_key)
+                    .filter(|snapshot| snapshot.built_at.elapsed() < snapshot_cache_ttl);
+                if let Some(snapshot) = cached_snapshot {
+                    for bytes in snapshot.messages.iter().chain(&snapshot.delta) {
+                        feed_channel.send(ToFeedWebsocket::Bytes(bytes.clone()));
+                    }
+                    self.snapshots_reused += 1;
+                } else {
+                    let header = serialize_chain_snapshot_header(&new_chain, format);
+                    let node_feed_messages = serialize_chain_snapshot_nodes(
+                        new_chain.nodes_slice(),
+                        authorities_only,
+                        self.expose_node_details,
+                        format,
+                    );
+                    if snapshot_cache_ttl.is_zero() {
+                        if let Some(bytes) = header {
+                            feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                        }
+                        match ordering {
+                            FeedOrdering::Strict => {
+                                let all_feed_messages: Vec<_> = node_feed_messages.collect();
+                                for bytes in all_feed_messages {
+                                    feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                                }
                             }
+                            FeedOrdering::BestEffort => {
+                                let is_full = AtomicBool::new(false);
+                                let channel = &*feed_channel;
+                                node_feed_messages.for_each(|bytes| {
+                                    if !channel.try_send(ToFeedWebsocket::Bytes(bytes)) {
+                                        is_full.store(true, Ordering::Relaxed);
+                                    }
+                                });
+                                if is_full.into_inner() {
+                                    feed_channel.close();
+                                }
+                            }
+                        }
+                    } else {
+                        // Snapshots that may be reused are always collected in order, so that
+                        // they are suitable for any subscription.
+                        let mut messages: Vec<_> = header.into_iter().collect();
+                        messages.par_extend(node_feed_messages);
+                        for bytes in &messages {
+                            feed_channel.send(ToFeedWebsocket::Bytes(bytes.clone()));
                         }
-                        feed_serializer.into_finalized()
+                        self.snapshot_cache.insert(
+                            cache_key,
+                            ChainSnapshot {
+                                built_at: Instant::now(),
+                                messages,
+                                delta: Vec::new(),
+                            },
+                        );
+                    }
+                    self.snapshots_built += 1;
+                }
+
+                // Follow the snapshot with any recent events that the feed asked for:
+                let replayed = self
+                    .event_histories
+                    .get(&new_genesis_hash)
+                    .filter(|_| replay > 0)
+                    .and_then(|history| {
+                        history.replay(replay, authorities_only, format, |node_id| {
+                            new_chain.get_node(node_id.into()).is_some()
+                        })
+                    });
+                if let Some(bytes) = replayed {
+                    feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                }
+
+                // Actually make a note of the new chain subscription:
+                if authorities_only {
+                    self.chain_to_authority_feed_conn_ids
+                        .insert(new_genesis_hash, feed_conn_id);
+                } else {
+                    self.chain_to_feed_conn_ids
+                        .insert(new_genesis_hash, feed_conn_id);
+                }
+            }
+            FromFeedWebsocket::SubscribeToEveryChain => {
+                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
+                    Some(chan) => chan,
+                    None => return,
+                };
+
+                // Every chain adds up to a lot of messages, so only admin feeds can ask:
+                if !self.admin_feed_conn_ids.contains(&feed_conn_id) {
+                    let mut feed_serializer = feed_channel.serializer();
+                    feed_serializer.push(feed_message::SubscribeError(
+                        SubscribeErrorCode::NotAdmin,
+                        None,
+                    ));
+                    if let Some(bytes) = feed_serializer.into_finalized() {
+                        feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                    }
+                    return;
+                }
+
+                // Unsubscribe from the chain that the feed was subscribed to, if any:
+                let old_genesis_hash = self
+                    .chain_to_feed_conn_ids
+                    .remove_value(&feed_conn_id)
+                    .or_else(|| {
+                        self.chain_to_authority_feed_conn_ids
+                            .remove_value(&feed_conn_id)
                     })
+                    .or_else(|| {
+                        self.chain_to_count_feed_conn_ids
+                            .remove_value(&feed_conn_id)
+                    });
+                if let Some(old_genesis_hash) = old_genesis_hash {
+                    let mut feed_serializer = feed_channel.serializer();
+                    feed_serializer.push(feed_message::UnsubscribedFrom(old_genesis_hash));
+                    if let Some(bytes) = feed_serializer.into_finalized() {
+                        feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                    }
+                }
+                if !self.every_chain_feed_conn_ids.insert(feed_conn_id) {
+                    return;
+                }
+
+                // Everything about every chain is sent, starting with a snapshot of each of
+                // them. Each snapshot starts with `SubscribedTo`, which says which chain it's for:
+                feed_channel.reset_format();
+                for chain in self.node_state.iter_chains() {
+                    if let Some(bytes) =
+                        serialize_chain_snapshot_header(&chain, feed_channel.format)
+                    {
+                        feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                    }
+                    let node_feed_messages: Vec<_> = serialize_chain_snapshot_nodes(
+                        chain.nodes_slice(),
+                        false,
+                        self.expose_node_details,
+                        feed_channel.format,
+                    )
                     .collect();
-                for bytes in all_feed_messages {
-                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                    for bytes in node_feed_messages {
+                        feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                    }
                 }
+            }
+            FromFeedWebsocket::SubscribeToNodeCounts { chain } => {
+                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
+                    Some(chan) => chan,
+                    None => return,
+                };
 
-                // Actually make a note of the new chain subscription:
-                let new_genesis_hash = new_chain.genesis_hash();
-                self.chain_to_feed_conn_ids
-                    .insert(new_genesis_hash, feed_conn_id);
+                let mut feed_serializer = feed_channel.serializer();
+                let total = match self.node_state.get_chain_by_genesis_hash(&chain) {
+                    Some(chain) => chain.node_count(),
+                    None => {
+                        feed_serializer.push(feed_message::SubscribeError(
+                            SubscribeErrorCode::UnknownChain,
+                            Some(chain),
+                        ));
+                        if let Some(bytes) = feed_serializer.into_finalized() {
+                            feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                        }
+                        return;
+                    }
+                };
+
+                // Unsubscribe from whatever the feed was subscribed to before, so that it
+                // hears nothing more about any nodes:
+                let old_genesis_hash = self
+                    .chain_to_feed_conn_ids
+                    .remove_value(&feed_conn_id)
+                    .or_else(|| {
+                        self.chain_to_authority_feed_conn_ids
+                            .remove_value(&feed_conn_id)
+                    })
+                    .or_else(|| {
+                        self.chain_to_count_feed_conn_ids
+                            .remove_value(&feed_conn_id)
+                    });
+                if let Some(old_genesis_hash) = old_genesis_hash {
+                    feed_serializer.push(feed_message::UnsubscribedFrom(old_genesis_hash));
+                }
+                if self.every_chain_feed_conn_ids.remove(&feed_conn_id) {
+                    for old_chain in self.node_state.iter_chains() {
+                        feed_serializer
+                            .push(feed_message::UnsubscribedFrom(old_chain.genesis_hash()));
+                    }
+                }
+
+                // The feed starts off knowing how many nodes there are, and hears about
+                // them coming and going from then on:
+                feed_channel.reset_format();
+                feed_serializer.push(feed_message::SubscribedTo(chain));
+                feed_serializer.push(feed_message::NodeCount(chain, 0, 0, total));
+                if let Some(bytes) = feed_serializer.into_finalized() {
+                    feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                }
+                self.chain_to_count_feed_conn_ids
+                    .insert(chain, feed_conn_id);
+            }
+            FromFeedWebsocket::UnsubscribeAll => {
+                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
+                    Some(chan) => chan,
+                    None => return,
+                };
+
+                // Remove the feed from every chain it's subscribed to in one go, so that
+                // no more messages about any of them are sent to it after this:
+                let old_genesis_hashes = [
+                    self.chain_to_feed_conn_ids.remove_value(&feed_conn_id),
+                    self.chain_to_authority_feed_conn_ids
+                        .remove_value(&feed_conn_id),
+                    self.chain_to_count_feed_conn_ids
+                        .remove_value(&feed_conn_id),
+                ];
+                let was_subscribed_to_every_chain =
+                    self.every_chain_feed_conn_ids.remove(&feed_conn_id);
+                feed_channel.reset_format();
+                feed_channel.subscribed_chains.clear();
+
+                let mut feed_serializer = feed_channel.serializer();
+                for genesis_hash in old_genesis_hashes.into_iter().flatten() {
+                    feed_serializer.push(feed_message::UnsubscribedFrom(genesis_hash));
+                }
+                if was_subscribed_to_every_chain {
+                    for chain in self.node_state.iter_chains() {
+                        feed_serializer.push(feed_message::UnsubscribedFrom(chain.genesis_hash()));
+                    }
+                }
+                if let Some(bytes) = feed_serializer.into_finalized() {
+                    feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                }
+            }
+            FromFeedWebsocket::SendFinality { chain } => {
+                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
+                    Some(chan) => chan,
+                    None => return,
+                };
+                feed_channel.finality_chain = Some(chain);
+
+                // If the feed is already subscribed to the chain, start sending them right away:
+                let subscribed = self
+                    .chain_to_feed_conn_ids
+                    .get_key(&feed_conn_id)
+                    .or_else(|| self.chain_to_authority_feed_conn_ids.get_key(&feed_conn_id));
+                if subscribed == Some(&chain) {
+                    feed_channel.format.finality = true;
+                }
+            }
+            FromFeedWebsocket::NoMoreFinality { chain } => {
+                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
+                    Some(chan) => chan,
+                    None => return,
+                };
+                if feed_channel.finality_chain != Some(chain) {
+                    return;
+                }
+                feed_channel.finality_chain = None;
+
+                let subscribed = self
+                    .chain_to_feed_conn_ids
+                    .get_key(&feed_conn_id)
+                    .or_else(|| self.chain_to_authority_feed_conn_ids.get_key(&feed_conn_id));
+                if subscribed == Some(&chain) {
+                    feed_channel.format.finality = false;
+                }
+            }
+            FromFeedWebsocket::NodeShard { node_id } => {
+                // Only admin feeds get to know which shard a node is connected through:
+                if !self.admin_feed_conn_ids.contains(&feed_conn_id) {
+                    return;
+                }
+                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
+                    Some(chan) => chan,
+                    None => return,
+                };
+
+                // Node IDs are relative to the chain that the feed is subscribed to:
+                let genesis_hash = match self
+                    .chain_to_feed_conn_ids
+                    .get_key(&feed_conn_id)
+                    .or_else(|| self.chain_to_authority_feed_conn_ids.get_key(&feed_conn_id))
+                {
+                    Some(genesis_hash) => genesis_hash,
+                    None => return,
+                };
+                let chain = match self.node_state.get_chain_by_genesis_hash(genesis_hash) {
+                    Some(chain) => chain,
+                    None => return,
+                };
+                let shard_id = match chain.get_node(node_id.into()).and_then(|n| n.shard_id()) {
+                    Some(shard_id) => shard_id,
+                    None => return,
+                };
+
+                let mut feed_serializer = feed_channel.serializer();
+                feed_serializer.push(feed_message::NodeShard(node_id, shard_id));
+                if let Some(bytes) = feed_serializer.into_finalized() {
+                    feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                }
+            }
+            FromFeedWebsocket::NodeDetail { node_id } => {
+                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
+                    Some(chan) => chan,
+                    None => return,
+                };
+
+                // Node IDs are relative to the chain that the feed is subscribed to, and feeds
+                // subscribed to a chain's authorities only get to know about those:
+                let (genesis_hash, authorities_only) =
+                    match self.chain_to_feed_conn_ids.get_key(&feed_conn_id) {
+                        Some(genesis_hash) => (Some(genesis_hash), false),
+                        None => (
+                            self.chain_to_authority_feed_conn_ids.get_key(&feed_conn_id),
+                            true,
+                        ),
+                    };
+                let chain = genesis_hash.and_then(|genesis_hash| {
+                    self.node_state.get_chain_by_genesis_hash(genesis_hash)
+                });
+                let chain = match chain {
+                    Some(chain) => chain,
+                    None => {
+                        let error =
+                            "Subscribe to a chain before asking for the details of its nodes";
+                        let mut feed_serializer = feed_channel.serializer();
+                        feed_serializer.push(feed_message::CommandError(error));
+                        if let Some(bytes) = feed_serializer.into_finalized() {
+                            feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                        }
+                        return;
+                    }
+                };
+
+                let mut feed_serializer = feed_channel.serializer();
+                match chain
+                    .get_node(node_id.into())
+                    .filter(|node| !authorities_only || node.is_authority())
+                {
+                    Some(node) => feed_serializer.push(feed_message::NodeDetail(
+                        node_id,
+                        node,
+                        self.expose_node_details,
+                    )),
+                    None => feed_serializer.push(feed_message::CommandError(&format!(
+                        "Node {node_id} not found on the subscribed chain"
+                    ))),
+                }
+                if let Some(bytes) = feed_serializer.into_finalized() {
+                    feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                }
             }
             FromFeedWebsocket::Disconnected => {
                 // The feed has disconnected; clean up references to it:
                 self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
+                self.chain_to_authority_feed_conn_ids
+                    .remove_value(&feed_conn_id);
+                self.every_chain_feed_conn_ids.remove(&feed_conn_id);
+                self.chain_to_count_feed_conn_ids
+                    .remove_value(&feed_conn_id);
                 self.feed_channels.remove(&feed_conn_id);
+                self.admin_feed_conn_ids.remove(&feed_conn_id);
             }
         }
     }
 
-    /// Remove all of the node IDs provided and broadcast messages to feeds as needed.
-    fn remove_nodes_and_broadcast_result(&mut self, node_ids: impl IntoIterator<Item = NodeId>) {
+    /// Remove all of the node IDs provided and broadcast messages to feeds as needed, telling
+    /// them why the nodes were removed.
+    fn remove_nodes_and_broadcast_result(
+        &mut self,
+        node_ids: impl IntoIterator<Item = NodeId>,
+        reason: NodeRemovalReason,
+    ) {
         // Group by chain to simplify the handling of feed messages:
         let mut node_ids_per_chain: HashMap<BlockHash, Vec<NodeId>> = HashMap::new();
         for node_id in node_ids.into_iter() {
@@ -585,12 +2203,13 @@ impl InnerLoop {
         }
 
         // Remove the nodes for each chain
-        let mut feed_messages_for_all = FeedMessageSerializer::new();
+        let mut feed_messages_for_all = self.new_all_feeds_serializer();
         for (chain_label, node_ids) in node_ids_per_chain {
-            let mut feed_messages_for_chain = FeedMessageSerializer::new();
+            let mut feed_messages_for_chain = self.new_chain_feed_serializer(&chain_label);
             for node_id in node_ids {
                 self.remove_node(
                     node_id,
+                    reason,
                     &mut feed_messages_for_chain,
                     &mut feed_messages_for_all,
                 );
@@ -606,8 +2225,9 @@ impl InnerLoop {
     fn remove_node(
         &mut self,
         node_id: NodeId,
-        feed_for_chain: &mut FeedMessageSerializer,
-        feed_for_all: &mut FeedMessageSerializer,
+        reason: NodeRemovalReason,
+        feed_for_chain: &mut ChainFeedSerializer,
+        feed_for_all: &mut MultiFormatSerializer,
     ) {
         // Remove our top level association (this may already have been done).
         self.node_ids.remove_by_left(&node_id);
@@ -620,63 +2240,1980 @@ impl InnerLoop {
             }
         };
 
-        // The chain has been removed (no nodes left in it, or it was renamed):
-        if removed_details.chain_node_count == 0 || removed_details.has_chain_label_changed {
+        if self
+            .chain_to_count_feed_conn_ids
+            .get_values(&removed_details.chain_genesis_hash)
+            .is_some()
+        {
+            self.node_count_changes
+                .entry(removed_details.chain_genesis_hash)
+                .or_default()
+                .1 += 1;
+        }
+
+        // The chain has gone, so we no longer need any snapshots or history of it:
+        if removed_details.chain_removed {
+            self.forget_chain(removed_details.chain_genesis_hash);
+        } else if let Some(history) = self
+            .event_histories
+            .get_mut(&removed_details.chain_genesis_hash)
+        {
+            history.node_removed(node_id.get_chain_node_id().into());
+        }
+
+        // The chain has been removed (no nodes left in it):
+        if removed_details.chain_removed {
             feed_for_all.push(feed_message::RemovedChain(
                 removed_details.chain_genesis_hash,
             ));
         }
 
         // If the chain still exists, tell everybody about the new label or updated node count:
-        if removed_details.chain_node_count != 0 {
+        if !removed_details.chain_removed {
             feed_for_all.push(feed_message::AddedChain(
                 &removed_details.new_chain_label,
                 removed_details.chain_genesis_hash,
                 removed_details.chain_node_count,
+                self.chain_tags(&removed_details.chain_genesis_hash),
             ));
         }
 
-        // Assuming the chain hasn't gone away, tell chain subscribers about the node removal
-        if removed_details.chain_node_count != 0 {
-            feed_for_chain.push(feed_message::RemovedNode(
-                node_id.get_chain_node_id().into(),
-            ));
+        // Assuming the chain hasn't gone away, tell chain subscribers about the node removal,
+        // about the node that's now furthest ahead if it was the one that was removed, and
+        // about any new label that the chain has as a result:
+        if !removed_details.chain_removed {
+            let feed_node_id = node_id.get_chain_node_id().into();
+            feed_for_chain.push_for_node(
+                removed_details.was_authority,
+                feed_message::RemovedNodeReason(feed_node_id, reason),
+            );
+            feed_for_chain.push_for_node(
+                removed_details.was_authority,
+                feed_message::RemovedNode(feed_node_id),
+            );
+            if let Some((leader_id, height)) = removed_details.new_chain_leader {
+                feed_for_chain.push(feed_message::ChainLeader(leader_id.into(), height));
+            }
+            if removed_details.has_chain_label_changed {
+                feed_for_chain.push(feed_message::ChainLabelChanged(
+                    removed_details.chain_genesis_hash,
+                    &removed_details.new_chain_label,
+                ));
+            }
+        }
+    }
+
+    /// The best and finalized blocks of a node, if we're keeping a history of events.
+    fn node_blocks(&self, node_id: NodeId) -> Option<(Block, Block)> {
+        if self.event_history_size == 0 {
+            return None;
+        }
+        let chain = self.node_state.get_chain_by_node_id(node_id)?;
+        let node = chain.get_node(node_id.get_chain_node_id())?;
+        Some((*node.best(), *node.finalized()))
+    }
+
+    /// Record any change to the best and finalized blocks of a node in the event
+    /// history of its chain.
+    fn record_block_events(
+        &mut self,
+        genesis_hash: BlockHash,
+        node_id: NodeId,
+        (best_before, finalized_before): (Block, Block),
+    ) {
+        let node = match self
+            .node_state
+            .get_chain_by_node_id(node_id)
+            .and_then(|chain| chain.get_node(node_id.get_chain_node_id()))
+        {
+            Some(node) => node,
+            None => return,
+        };
+        let history = self
+            .event_histories
+            .entry(genesis_hash)
+            .or_insert_with(|| EventHistory::new(self.event_history_size));
+
+        let feed_node_id = node_id.get_chain_node_id().into();
+        if *node.best() != best_before {
+            history.push(
+                feed_node_id,
+                node.is_authority(),
+                feed_message::ImportedBlock(feed_node_id, node.block_details()),
+            );
+        }
+        if *node.finalized() != finalized_before {
+            history.push(
+                feed_node_id,
+                node.is_authority(),
+                feed_message::FinalizedBlock(
+                    feed_node_id,
+                    node.finalized().height,
+                    node.finalized().hash,
+                ),
+            );
         }
     }
 
-    /// Finalize a [`FeedMessageSerializer`] and broadcast the result to feeds for the chain.
+    /// Create a [`ChainFeedSerializer`] for a chain, which serializes messages in each of the
+    /// formats that the feeds (and recent snapshots) for the chain are in, and only bothers
+    /// serializing messages for feeds subscribed to authority nodes if there are any.
+    fn new_chain_feed_serializer(&self, genesis_hash: &BlockHash) -> ChainFeedSerializer {
+        let chain_feeds = self.chain_to_feed_conn_ids.get_values(genesis_hash);
+        let authority_feeds = self
+            .chain_to_authority_feed_conn_ids
+            .get_values(genesis_hash);
+        let cached_formats = |authorities_only: bool| {
+            self.snapshot_cache
+                .keys()
+                .filter(move |&&(hash, authorities, _)| {
+                    hash == *genesis_hash && authorities == authorities_only
+                })
+                .map(|&(_, _, format)| format)
+        };
+        let feeds = chain_feeds
+            .into_iter()
+            .flatten()
+            .chain(&self.every_chain_feed_conn_ids);
+        let formats = feed_formats(&self.feed_channels, feeds).chain(cached_formats(false));
+        let authority_formats =
+            feed_formats(&self.feed_channels, authority_feeds.into_iter().flatten())
+                .chain(cached_formats(true));
+        ChainFeedSerializer::new(formats, authority_formats)
+    }
+
+    /// A serializer for messages to every feed, in each of the formats that they asked for.
+    fn new_all_feeds_serializer(&self) -> MultiFormatSerializer {
+        MultiFormatSerializer::new(self.feed_channels.values().map(|chan| chan.format))
+    }
+
+    /// Finalize a [`ChainFeedSerializer`] and broadcast the results to feeds for the chain.
     fn finalize_and_broadcast_to_chain_feeds(
         &mut self,
         genesis_hash: &BlockHash,
-        serializer: FeedMessageSerializer,
+        serializer: ChainFeedSerializer,
     ) {
-        if let Some(bytes) = serializer.into_finalized() {
-            self.broadcast_to_chain_feeds(genesis_hash, ToFeedWebsocket::Bytes(bytes));
+        let (bytes, authority_bytes) = serializer.into_finalized();
+        if !bytes.is_empty() {
+            self.broadcast_to_chain_feeds(genesis_hash, false, &bytes);
+        }
+        if !authority_bytes.is_empty() {
+            self.broadcast_to_chain_feeds(genesis_hash, true, &authority_bytes);
         }
     }
 
-    /// Send a message to all chain feeds.
-    fn broadcast_to_chain_feeds(&mut self, genesis_hash: &BlockHash, message: ToFeedWebsocket) {
-        if let Some(feeds) = self.chain_to_feed_conn_ids.get_values(genesis_hash) {
-            for &feed_id in feeds {
-                if let Some(chan) = self.feed_channels.get_mut(&feed_id) {
-                    let _ = chan.send(message.clone());
+    /// Send messages to all chain feeds, or just those subscribed to authority nodes, in
+    /// whichever format each feed asked for.
+    fn broadcast_to_chain_feeds(
+        &mut self,
+        genesis_hash: &BlockHash,
+        authorities_only: bool,
+        bytes: &FormattedBytes,
+    ) {
+        // Any cached snapshots of the chain for these feeds need to know about the messages
+        // too, so that feeds reusing them are brought up to date. Expired snapshots are dropped.
+        let snapshot_cache_ttl = self.snapshot_cache_ttl;
+        self.snapshot_cache
+            .retain(|&(hash, authorities, format), snapshot| {
+                if hash != *genesis_hash || authorities != authorities_only {
+                    return true;
+                }
+                if snapshot.built_at.elapsed() >= snapshot_cache_ttl {
+                    return false;
+                }
+                snapshot.delta.extend(bytes.get(format).cloned());
+                true
+            });
+
+        // Feeds subscribed to every chain are told which chain the messages are about first:
+        if !authorities_only && !self.every_chain_feed_conn_ids.is_empty() {
+            let feeds = &self.every_chain_feed_conn_ids;
+            let mut feed_serializer = serializer_for_feeds(&self.feed_channels, feeds);
+            feed_serializer.push(feed_message::ForChain(*genesis_hash));
+            let for_chain = feed_serializer.into_finalized();
+            for feed_id in feeds {
+                if let Some(chan) = self.feed_channels.get_mut(feed_id) {
+                    let mut batch = FeedMessageBatch::new();
+                    batch.push(for_chain.get(chan.format).cloned().unwrap_or_default());
+                    batch.push(bytes.get(chan.format).cloned().unwrap_or_default());
+                    if let Some(batch) = batch.into_finalized() {
+                        chan.send(ToFeedWebsocket::Bytes(batch));
+                    }
                 }
             }
         }
-    }
 
-    /// Finalize a [`FeedMessageSerializer`] and broadcast the result to all feeds
-    fn finalize_and_broadcast_to_all_feeds(&mut self, serializer: FeedMessageSerializer) {
-        if let Some(bytes) = serializer.into_finalized() {
-            self.broadcast_to_all_feeds(ToFeedWebsocket::Bytes(bytes));
+        let chain_to_feed_conn_ids = match authorities_only {
+            true => &self.chain_to_authority_feed_conn_ids,
+            false => &self.chain_to_feed_conn_ids,
+        };
+        if let Some(feeds) = chain_to_feed_conn_ids.get_values(genesis_hash) {
+            send_to_feeds(&mut self.feed_channels, feeds, bytes);
         }
     }
 
-    /// Send a message to everybody.
-    fn broadcast_to_all_feeds(&mut self, message: ToFeedWebsocket) {
+    /// The tags of the chain with the genesis hash given (none if there's no such chain).
+    fn chain_tags(&self, genesis_hash: &BlockHash) -> &ChainTags {
+        static NO_TAGS: ChainTags = ChainTags::new();
+        self.node_state
+            .get_chain_by_genesis_hash(genesis_hash)
+            .map_or(&NO_TAGS, |chain| chain.tags())
+    }
+
+    /// Finalize a [`MultiFormatSerializer`] and broadcast the results to all feeds.
+    fn finalize_and_broadcast_to_all_feeds(&mut self, serializer: MultiFormatSerializer) {
+        let bytes = serializer.into_finalized();
         for chan in self.feed_channels.values_mut() {
-            let _ = chan.send(message.clone());
+            chan.send_formatted(&bytes);
+        }
+    }
+}
+
+/// The formats that the feeds given asked for.
+fn feed_formats<'a>(
+    feed_channels: &'a HashMap<ConnId, FeedChannel>,
+    feed_ids: impl IntoIterator<Item = &'a ConnId> + 'a,
+) -> impl Iterator<Item = FeedFormat> + 'a {
+    feed_ids
+        .into_iter()
+        .filter_map(|feed_id| feed_channels.get(feed_id))
+        .map(|chan| chan.format)
+}
+
+/// A serializer for messages to the feeds given, in each of the formats that they asked for.
+fn serializer_for_feeds<'a>(
+    feed_channels: &'a HashMap<ConnId, FeedChannel>,
+    feed_ids: impl IntoIterator<Item = &'a ConnId> + 'a,
+) -> MultiFormatSerializer {
+    MultiFormatSerializer::new(feed_formats(feed_channels, feed_ids))
+}
+
+/// Send each of the feeds given the messages serialized in its format by a serializer from
+/// [`serializer_for_feeds()`].
+fn send_to_feeds<'a>(
+    feed_channels: &mut HashMap<ConnId, FeedChannel>,
+    feed_ids: impl IntoIterator<Item = &'a ConnId>,
+    bytes: &FormattedBytes,
+) {
+    for feed_id in feed_ids {
+        if let Some(chan) = feed_channels.get_mut(feed_id) {
+            chan.send_formatted(bytes);
+        }
+    }
+}
+
+/// Wait for the next tick of the interval given, or forever if there is no interval.
+async fn tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// The label, genesis hash, node count and average block time of the `n` most active chains,
+/// most active first. Ties are broken by the other measure of activity, and then by genesis
+/// hash. We may know about thousands of chains, so only the `n` chains that we hand back are
+/// sorted; the rest are just partitioned away from them.
+fn top_chains(
+    node_state: &State,
+    n: usize,
+    by: ChainActivity,
+) -> Vec<(&str, BlockHash, usize, Option<u64>)> {
+    let mut chains: Vec<_> = node_state
+        .iter_chains()
+        .map(|chain| {
+            (
+                chain.label(),
+                chain.genesis_hash(),
+                chain.node_count(),
+                chain.average_block_time(),
+            )
+        })
+        .collect();
+
+    let most_active_first =
+        |a: &(&str, BlockHash, usize, Option<u64>), b: &(&str, BlockHash, usize, Option<u64>)| {
+            // A shorter average block time is a higher block rate:
+            let by_nodes = b.2.cmp(&a.2);
+            let by_block_rate = a.3.unwrap_or(u64::MAX).cmp(&b.3.unwrap_or(u64::MAX));
+            match by {
+                ChainActivity::NodeCount => by_nodes.then(by_block_rate),
+                ChainActivity::BlockRate => by_block_rate.then(by_nodes),
+            }
+            .then(a.1.cmp(&b.1))
+        };
+    if n < chains.len() {
+        chains.select_nth_unstable_by(n, most_active_first);
+        chains.truncate(n);
+    }
+    chains.sort_unstable_by(most_active_first);
+    chains
+}
+
+/// Serialize the messages that a feed subscribing to a chain is sent before the
+/// details of the nodes on it, in the format that the feed asked for.
+fn serialize_chain_snapshot_header(
+    chain: &state::StateChain,
+    format: FeedFormat,
+) -> Option<bytes::Bytes> {
+    let mut feed_serializer = FeedMessageSerializer::with_format(format);
+    feed_serializer.push(feed_message::SubscribedTo(chain.genesis_hash()));
+    feed_serializer.push(feed_message::TimeSync(chain.clock_now()));
+    feed_serializer.push(feed_message::BestBlock(
+        chain.best_block().height,
+        chain.timestamp(),
+        chain.average_block_time(),
+    ));
+    feed_serializer.push(feed_message::BestFinalized(
+        chain.finalized_block().height,
+        chain.finalized_block().hash,
+    ));
+    feed_serializer.push(feed_message::ChainStatsUpdate(chain.stats()));
+    if let Some(stats) = chain.block_propagation_stats() {
+        feed_serializer.push(feed_message::BlockPropagation(stats));
+    }
+    if let Some(consensus) = chain.finalized_consensus() {
+        feed_serializer.push(feed_message::ChainFinalized(consensus));
+    }
+    if let Some(set) = chain.authority_set() {
+        feed_serializer.push(feed_message::AuthoritySetChanged(set));
+    }
+    if let Some((leader_id, height)) = chain.leader() {
+        feed_serializer.push(feed_message::ChainLeader(leader_id.into(), height));
+    }
+    feed_serializer.into_finalized()
+}
+
+/// Serialize the details of the nodes on a chain for a subscribing feed, in the format that
+/// the feed asked for.
+///
+/// If many (eg 10k) nodes are connected, serializing all of their info takes time.
+/// So, parallelise this with Rayon. Collecting the result preserves the order of nodes
+/// (which is helpful for the UI as it tries to maintain a sorted list of nodes), whereas
+/// consuming it with `for_each` hands back each message as soon as it's ready. The chunk
+/// size is the max number of node info we fit into 1 message; smaller messages allow the
+/// UI to react a little faster and not have to wait for a larger update to come in. A
+/// chunk size of 64 means each message is ~32k.
+fn serialize_chain_snapshot_nodes(
+    nodes: &[Option<state::Node>],
+    authorities_only: bool,
+    expose_node_details: bool,
+    format: FeedFormat,
+) -> impl ParallelIterator<Item = bytes::Bytes> + '_ {
+    nodes
+        .par_iter()
+        .enumerate()
+        .chunks(64)
+        .filter_map(move |nodes| {
+            let mut feed_serializer = FeedMessageSerializer::with_format(format);
+            for (node_id, node) in nodes
+                .iter()
+                .filter_map(|&(idx, n)| n.as_ref().map(|n| (idx, n)))
+                .filter(|(_, n)| !authorities_only || n.is_authority())
+            {
+                feed_serializer.push(feed_message::AddedNode(node_id, node, expose_node_details));
+                feed_serializer.push(feed_message::FinalizedBlock(
+                    node_id,
+                    node.finalized().height,
+                    node.finalized().hash,
+                ));
+                if node.stale() {
+                    feed_serializer.push(feed_message::StaleNode(node_id));
+                }
+                if let Some(skew_ms) = node.clock_skew() {
+                    feed_serializer.push(feed_message::NodeClockSkew(node_id, Some(skew_ms)));
+                }
+                if !node.custom_metrics().is_empty() {
+                    feed_serializer.push(feed_message::NodeCustomMetrics(
+                        node_id,
+                        node.custom_metrics(),
+                    ));
+                }
+                if !node.peer_counts().is_empty() {
+                    feed_serializer.push(feed_message::NodePeerCount(node_id, node.peer_counts()));
+                }
+            }
+            feed_serializer.into_finalized()
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::node_types::{NetworkId, NodeDetails};
+    use test_utils::feed_message_de::FeedMessage;
+
+    fn node(name: &str) -> NodeDetails {
+        NodeDetails {
+            chain: "Chain".into(),
+            name: name.into(),
+            implementation: "Bar".into(),
+            target_arch: Some("x86_64".into()),
+            target_os: Some("linux".into()),
+            target_env: Some("env".into()),
+            commit: None,
+            version: "0.1".into(),
+            validator: None,
+            authority: false,
+            role: Default::default(),
+            network_id: NetworkId::new(),
+            startup_time: None,
+            sysinfo: None,
+            ip: None,
+        }
+    }
+
+    fn inner_loop(snapshot_cache_ttl: Duration) -> InnerLoop {
+        let (tx_to_locator, _) = flume::unbounded();
+        InnerLoop::new(Some(tx_to_locator), aggregator_opts(snapshot_cache_ttl))
+    }
+
+    fn aggregator_opts(snapshot_cache_ttl: Duration) -> AggregatorOpts {
+        AggregatorOpts {
+            denylist: Vec::new(),
+            allowlist: Vec::new(),
+            chain_tags: Vec::new(),
+            authority_only_chains: Vec::new(),
+            authority_node_token: None,
+            max_queue_len: 1000,
+            max_third_party_nodes: 1000,
+            max_chains: usize::MAX,
+            max_feeds_per_chain: usize::MAX,
+            max_subscriptions_per_feed: usize::MAX,
+            expose_node_details: false,
+            trust_node_timestamps: true,
+            peer_count_handling: state::PeerCountHandling::Flag,
+            node_update_min_interval: Duration::ZERO,
+            snapshot_cache_ttl,
+            event_history_size: 0,
+            disable_geolocation: false,
+            geoip_max_concurrent: 32,
+            geoip_max_wait: Duration::from_secs(10),
+            stale_node_timeout: Duration::from_secs(60),
+            stale_node_check_interval: Duration::from_secs(10),
+            feed_heartbeat_interval: Duration::ZERO,
+            node_count_interval: Duration::ZERO,
+            empty_chain_ttl: Duration::ZERO,
+            max_block_height_jump: None,
+            stat_history_len: None,
+            block_window: None,
+            latency_window: Duration::from_secs(60),
+        }
+    }
+
+    fn add_node(inner: &mut InnerLoop, local_id: usize, genesis_hash: BlockHash) {
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Add {
+                local_id: ShardNodeId::new(local_id),
+                ip: "127.0.0.1".parse().unwrap(),
+                node: node(&format!("Node {local_id}")),
+                genesis_hash,
+                shard_id: None,
+                token: None,
+            },
+        );
+    }
+
+    fn connect_feed(
+        inner: &mut InnerLoop,
+        feed_conn_id: u64,
+        admin: bool,
+    ) -> flume::Receiver<ToFeedWebsocket> {
+        let (tx, rx) = flume::unbounded();
+        inner.handle_from_feed(
+            ConnId::new(feed_conn_id),
+            FromFeedWebsocket::Initialize {
+                channel: tx,
+                admin,
+                protocol: FeedProtocol::V2,
+            },
+        );
+        rx
+    }
+
+    fn subscribe_feed(
+        inner: &mut InnerLoop,
+        feed_conn_id: u64,
+        genesis_hash: BlockHash,
+    ) -> flume::Receiver<ToFeedWebsocket> {
+        let rx = connect_feed(inner, feed_conn_id, false);
+        subscribe(inner, feed_conn_id, genesis_hash);
+        rx
+    }
+
+    /// Subscribe a feed that's already connected to a chain, asking for every event.
+    fn subscribe(inner: &mut InnerLoop, feed_conn_id: u64, genesis_hash: BlockHash) {
+        inner.handle_from_feed(
+            ConnId::new(feed_conn_id),
+            FromFeedWebsocket::Subscribe {
+                chain: genesis_hash.into(),
+                ordering: FeedOrdering::Strict,
+                authorities_only: false,
+                replay: 0,
+                events: FeedEvents::ALL,
+                lean: false,
+                bignums_as_strings: false,
+            },
+        );
+    }
+
+    fn received_messages(rx: &flume::Receiver<ToFeedWebsocket>) -> Vec<FeedMessage> {
+        rx.try_iter()
+            .flat_map(|ToFeedWebsocket::Bytes(bytes)| FeedMessage::from_bytes(&bytes).unwrap())
+            .collect()
+    }
+
+    fn added_node_ids(messages: &[FeedMessage]) -> Vec<usize> {
+        messages
+            .iter()
+            .filter_map(|m| match m {
+                FeedMessage::AddedNode { node_id, .. } => Some(*node_id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn empty_chains_are_removed_once_ttl_passes() {
+        let ttl = Duration::from_secs(30);
+        let mut inner = inner_loop(Duration::ZERO);
+        inner.empty_chain_ttl = ttl;
+        inner.node_state.set_empty_chain_ttl(ttl);
+        let (shard_tx, _shard_rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Initialize { channel: shard_tx },
+        );
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        add_node(&mut inner, 0, genesis_hash);
+        let feed = connect_feed(&mut inner, 1, false);
+        received_messages(&feed);
+
+        // The chain is still listed, with no nodes, once its last node goes:
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Remove {
+                local_id: ShardNodeId::new(0),
+                reason: NodeRemovalReason::Disconnected,
+            },
+        );
+        let now = time::now();
+        let messages = received_messages(&feed);
+        assert!(messages.iter().any(|m| matches!(
+            m,
+            FeedMessage::AddedChain { genesis_hash: h, node_count: 0, .. } if *h == genesis_hash
+        )));
+        assert!(!messages
+            .iter()
+            .any(|m| matches!(m, FeedMessage::RemovedChain { .. })));
+
+        // It's removed once the TTL has passed, and feeds are told exactly once:
+        inner.remove_empty_chains(now + 1000);
+        assert!(received_messages(&feed).is_empty());
+        inner.remove_empty_chains(now + 30_000);
+        inner.remove_empty_chains(now + 40_000);
+        let messages = received_messages(&feed);
+        assert!(matches!(
+            &messages[..],
+            [FeedMessage::RemovedChain { genesis_hash: h }] if *h == genesis_hash
+        ));
+    }
+
+    #[test]
+    fn unsubscribe_all_stops_chain_messages() {
+        let mut inner = inner_loop(Duration::ZERO);
+        let (shard_tx, _shard_rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Initialize { channel: shard_tx },
+        );
+        let chains: Vec<_> = (1..=3).map(BlockHash::from_low_u64_be).collect();
+        for (local_id, &genesis_hash) in chains.iter().enumerate() {
+            add_node(&mut inner, local_id, genesis_hash);
+        }
+
+        // Move the feed through each of the chains, ending up subscribed to the last:
+        let feed = subscribe_feed(&mut inner, 1, chains[0]);
+        for &genesis_hash in &chains[1..] {
+            inner.handle_from_feed(
+                ConnId::new(1),
+                FromFeedWebsocket::Subscribe {
+                    chain: genesis_hash.into(),
+                    ordering: FeedOrdering::Strict,
+                    authorities_only: false,
+                    replay: 0,
+                    events: FeedEvents::ALL,
+                    lean: false,
+                    bignums_as_strings: false,
+                },
+            );
+        }
+        received_messages(&feed);
+
+        inner.handle_from_feed(ConnId::new(1), "unsubscribe-all:".parse().unwrap());
+        let messages = received_messages(&feed);
+        assert!(matches!(
+            &messages[..],
+            [FeedMessage::UnsubscribedFrom { genesis_hash }] if *genesis_hash == chains[2]
+        ));
+
+        // Nothing more is heard about nodes on any of the chains:
+        for (n, &genesis_hash) in chains.iter().enumerate() {
+            add_node(&mut inner, 10 + n, genesis_hash);
+        }
+        assert!(added_node_ids(&received_messages(&feed)).is_empty());
+
+        // Asking again when not subscribed to anything does nothing:
+        inner.handle_from_feed(ConnId::new(1), FromFeedWebsocket::UnsubscribeAll);
+        assert!(received_messages(&feed).is_empty());
+    }
+
+    #[test]
+    fn reorgs_are_sent_to_feeds_and_counted() {
+        let mut inner = inner_loop(Duration::ZERO);
+        let (shard_tx, _shard_rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Initialize { channel: shard_tx },
+        );
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        for local_id in 0..3 {
+            add_node(&mut inner, local_id, genesis_hash);
+        }
+        let feed = subscribe_feed(&mut inner, 1, genesis_hash);
+        let import = |inner: &mut InnerLoop, local_id, height, hash| {
+            inner.handle_from_shard(
+                ConnId::new(1),
+                FromShardWebsocket::Update {
+                    local_id: ShardNodeId::new(local_id),
+                    payload: node_message::Payload::BlockImport(common::node_types::Block {
+                        hash: BlockHash::from_low_u64_be(hash),
+                        height,
+                    }),
+                    received_at: None,
+                    sent_at: None,
+                },
+            );
+        };
+        let reorgs = |inner: &mut InnerLoop| {
+            let (tx, rx) = flume::unbounded();
+            inner.handle_gather_metrics(tx, 0, 0, 0);
+            rx.recv().unwrap().chain_reorgs
+        };
+
+        // Every node agrees on blocks 1 and 2:
+        for local_id in 0..3 {
+            import(&mut inner, local_id, 1, 10);
+            import(&mut inner, local_id, 2, 20);
+        }
+        // ..until two of them move over to a different block 2:
+        import(&mut inner, 0, 2, 21);
+        import(&mut inner, 1, 2, 21);
+
+        let messages = received_messages(&feed);
+        let reorgs_sent: Vec<_> = messages
+            .iter()
+            .filter(|m| matches!(m, FeedMessage::Reorg { .. }))
+            .collect();
+        assert_eq!(
+            reorgs_sent,
+            [&FeedMessage::Reorg {
+                block_number: 2,
+                block_hash: BlockHash::from_low_u64_be(21),
+                depth: 1,
+            }]
+        );
+        assert_eq!(reorgs(&mut inner), vec![(genesis_hash, 1)]);
+
+        // The last node catching up, or reporting blocks out of order, isn't another reorg:
+        import(&mut inner, 2, 1, 10);
+        import(&mut inner, 2, 2, 21);
+        assert!(!received_messages(&feed)
+            .iter()
+            .any(|m| matches!(m, FeedMessage::Reorg { .. })));
+        assert_eq!(reorgs(&mut inner), vec![(genesis_hash, 1)]);
+    }
+
+    #[test]
+    fn authority_set_changes_are_sent_to_feeds_once() {
+        let mut inner = inner_loop(Duration::ZERO);
+        let (shard_tx, _shard_rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Initialize { channel: shard_tx },
+        );
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        for local_id in 0..3 {
+            add_node(&mut inner, local_id, genesis_hash);
+        }
+        let report = |inner: &mut InnerLoop, local_id, set_id: &str, authorities: &str| {
+            inner.handle_from_shard(
+                ConnId::new(1),
+                FromShardWebsocket::Update {
+                    local_id: ShardNodeId::new(local_id),
+                    payload: node_message::Payload::AfgAuthoritySet(
+                        node_message::AfgAuthoritySet {
+                            authority_id: format!("Node {local_id}").into(),
+                            authorities: authorities.into(),
+                            authority_set_id: set_id.into(),
+                        },
+                    ),
+                    received_at: None,
+                    sent_at: None,
+                },
+            );
+        };
+        let changes = |messages: Vec<FeedMessage>| -> Vec<_> {
+            messages
+                .into_iter()
+                .filter_map(|m| match m {
+                    FeedMessage::AuthoritySetChanged {
+                        authority_set_id,
+                        authorities,
+                    } => Some((authority_set_id, authorities)),
+                    _ => None,
+                })
+                .collect()
+        };
+        for local_id in 0..3 {
+            report(&mut inner, local_id, "1", "[A]");
+        }
+        let feed = subscribe_feed(&mut inner, 1, genesis_hash);
+
+        // Feeds subscribing are told about the current set:
+        assert_eq!(changes(received_messages(&feed)), [(1, "[A]".to_owned())]);
+
+        // Once most nodes report a new set, feeds are told about it, just the once:
+        for local_id in 0..3 {
+            report(&mut inner, local_id, "2", "[A, B]");
+        }
+        assert_eq!(
+            changes(received_messages(&feed)),
+            [(2, "[A, B]".to_owned())]
+        );
+    }
+
+    #[test]
+    fn feeds_can_subscribe_to_chains_by_label() {
+        let mut inner = inner_loop(Duration::ZERO);
+        let (shard_tx, _shard_rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Initialize { channel: shard_tx },
+        );
+
+        // Two chains share a label; the one with more nodes is subscribed to:
+        let chains: Vec<_> = (1..=2).map(BlockHash::from_low_u64_be).collect();
+        add_node(&mut inner, 0, chains[0]);
+        add_node(&mut inner, 1, chains[1]);
+        add_node(&mut inner, 2, chains[1]);
+        let feed = connect_feed(&mut inner, 1, false);
+        received_messages(&feed);
+
+        inner.handle_from_feed(ConnId::new(1), "subscribe:label:Chain".parse().unwrap());
+        let messages = received_messages(&feed);
+        assert!(matches!(
+            messages.first(),
+            Some(FeedMessage::SubscribedTo { genesis_hash }) if *genesis_hash == chains[1]
+        ));
+        assert_eq!(added_node_ids(&messages), vec![0, 1]);
+
+        // Feeds are told if no chain has the label, and stay subscribed to their chain:
+        inner.handle_from_feed(ConnId::new(1), "subscribe:label:Nope".parse().unwrap());
+        assert!(matches!(
+            &received_messages(&feed)[..],
+            [FeedMessage::SubscribeError { code, genesis_hash: None }] if code == "unknown-label"
+        ));
+        assert_eq!(
+            inner.chain_to_feed_conn_ids.get_key(&ConnId::new(1)),
+            Some(&chains[1])
+        );
+    }
+
+    #[test]
+    fn admin_feeds_can_subscribe_to_every_chain() {
+        let mut inner = inner_loop(Duration::ZERO);
+        let (shard_tx, _shard_rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Initialize { channel: shard_tx },
+        );
+        let chains: Vec<_> = (1..=2).map(BlockHash::from_low_u64_be).collect();
+        add_node(&mut inner, 0, chains[0]);
+
+        // Other feeds aren't allowed to:
+        let feed = connect_feed(&mut inner, 1, false);
+        received_messages(&feed);
+        inner.handle_from_feed(ConnId::new(1), "subscribe:*".parse().unwrap());
+        assert!(matches!(
+            &received_messages(&feed)[..],
+            [FeedMessage::SubscribeError { code, genesis_hash: None }] if code == "not-admin"
+        ));
+
+        // Admin feeds are sent a snapshot of every chain that exists..
+        let admin_feed = connect_feed(&mut inner, 2, true);
+        received_messages(&admin_feed);
+        inner.handle_from_feed(ConnId::new(2), "subscribe:*".parse().unwrap());
+        let messages = received_messages(&admin_feed);
+        assert!(matches!(
+            messages.first(),
+            Some(FeedMessage::SubscribedTo { genesis_hash }) if *genesis_hash == chains[0]
+        ));
+        assert_eq!(added_node_ids(&messages), vec![0]);
+
+        // ..and are subscribed to chains that appear afterwards, being told which chain
+        // each batch of messages is about:
+        add_node(&mut inner, 1, chains[1]);
+        add_node(&mut inner, 2, chains[0]);
+        let messages = received_messages(&admin_feed);
+        let for_chains: Vec<_> = messages
+            .iter()
+            .filter_map(|m| match m {
+                FeedMessage::SubscribedTo { genesis_hash } => Some(("subscribed", *genesis_hash)),
+                FeedMessage::ForChain { genesis_hash } => Some(("for", *genesis_hash)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            for_chains,
+            vec![
+                ("subscribed", chains[1]),
+                ("for", chains[1]),
+                ("for", chains[0])
+            ]
+        );
+        assert_eq!(added_node_ids(&messages), vec![0, 1]);
+        assert!(received_messages(&feed)
+            .iter()
+            .all(|m| !matches!(m, FeedMessage::AddedNode { .. })));
+
+        // Unsubscribing unsubscribes the feed from all of them:
+        inner.handle_from_feed(ConnId::new(2), FromFeedWebsocket::UnsubscribeAll);
+        let mut unsubscribed: Vec<_> = received_messages(&admin_feed)
+            .into_iter()
+            .filter_map(|m| match m {
+                FeedMessage::UnsubscribedFrom { genesis_hash } => Some(genesis_hash),
+                _ => None,
+            })
+            .collect();
+        unsubscribed.sort();
+        assert_eq!(unsubscribed, chains);
+        add_node(&mut inner, 3, chains[1]);
+        assert!(received_messages(&admin_feed)
+            .iter()
+            .all(|m| !matches!(m, FeedMessage::AddedNode { .. })));
+    }
+
+    #[test]
+    fn feeds_subscribed_to_every_chain_are_not_resubscribed_to_empty_chains() {
+        let ttl = Duration::from_secs(30);
+        let mut inner = inner_loop(Duration::ZERO);
+        inner.empty_chain_ttl = ttl;
+        inner.node_state.set_empty_chain_ttl(ttl);
+        let (shard_tx, _shard_rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Initialize { channel: shard_tx },
+        );
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        add_node(&mut inner, 0, genesis_hash);
+        let admin_feed = connect_feed(&mut inner, 1, true);
+        inner.handle_from_feed(ConnId::new(1), "subscribe:*".parse().unwrap());
+        received_messages(&admin_feed);
+
+        // The chain is kept around once its last node goes, and so the feed is still
+        // subscribed to it when a node joins it again:
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Remove {
+                local_id: ShardNodeId::new(0),
+                reason: NodeRemovalReason::Disconnected,
+            },
+        );
+        add_node(&mut inner, 1, genesis_hash);
+        let messages = received_messages(&admin_feed);
+        assert!(!messages
+            .iter()
+            .any(|m| matches!(m, FeedMessage::SubscribedTo { .. })));
+        assert_eq!(added_node_ids(&messages), vec![0]);
+    }
+
+    #[test]
+    fn count_feeds_only_receive_node_counts() {
+        let mut inner = inner_loop(Duration::ZERO);
+        let (shard_tx, _shard_rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Initialize { channel: shard_tx },
+        );
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        add_node(&mut inner, 0, genesis_hash);
+        add_node(&mut inner, 1, genesis_hash);
+
+        // The feed is told how many nodes there are to begin with:
+        let feed = connect_feed(&mut inner, 1, false);
+        received_messages(&feed);
+        inner.handle_from_feed(
+            ConnId::new(1),
+            FromFeedWebsocket::SubscribeToNodeCounts {
+                chain: genesis_hash,
+            },
+        );
+        assert!(matches!(
+            &received_messages(&feed)[..],
+            [
+                FeedMessage::SubscribedTo { .. },
+                FeedMessage::NodeCount {
+                    added: 0,
+                    removed: 0,
+                    total: 2,
+                    ..
+                }
+            ]
+        ));
+
+        // Nodes coming and going, and what they get up to, aren't sent to it..
+        add_node(&mut inner, 2, genesis_hash);
+        add_node(&mut inner, 3, genesis_hash);
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Update {
+                local_id: ShardNodeId::new(2),
+                payload: node_message::Payload::BlockImport(common::node_types::Block {
+                    hash: BlockHash::from_low_u64_be(2),
+                    height: 1,
+                }),
+                received_at: None,
+                sent_at: None,
+            },
+        );
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Remove {
+                local_id: ShardNodeId::new(0),
+                reason: NodeRemovalReason::Disconnected,
+            },
+        );
+        assert!(received_messages(&feed).iter().all(|m| matches!(
+            m,
+            FeedMessage::AddedChain { .. } | FeedMessage::RemovedChain { .. }
+        )));
+
+        // ..only how many there were, every so often:
+        inner.send_node_counts();
+        let counts: Vec<_> = received_messages(&feed)
+            .into_iter()
+            .map(|m| match m {
+                FeedMessage::NodeCount {
+                    added,
+                    removed,
+                    total,
+                    ..
+                } => (added, removed, total),
+                m => panic!("Expected only node counts, got {m:?}"),
+            })
+            .collect();
+        assert_eq!(counts, vec![(2, 1, 3)]);
+
+        // Nothing is sent if no nodes have come or gone since:
+        inner.send_node_counts();
+        assert!(received_messages(&feed).is_empty());
+    }
+
+    #[test]
+    fn feeds_only_receive_the_events_they_subscribe_to() {
+        let mut inner = inner_loop(Duration::ZERO);
+        let (shard_tx, _shard_rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Initialize { channel: shard_tx },
+        );
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        add_node(&mut inner, 0, genesis_hash);
+
+        let all_feed = subscribe_feed(&mut inner, 1, genesis_hash);
+        let block_feed = connect_feed(&mut inner, 2, false);
+        inner.handle_from_feed(
+            ConnId::new(2),
+            "subscribe:0x0000000000000000000000000000000000000000000000000000000000000001:events=block,finalized"
+                .parse()
+                .unwrap(),
+        );
+
+        // Things happen on the chain:
+        add_node(&mut inner, 1, genesis_hash);
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Update {
+                local_id: ShardNodeId::new(0),
+                payload: node_message::Payload::BlockImport(common::node_types::Block {
+                    hash: BlockHash::from_low_u64_be(2),
+                    height: 2,
+                }),
+                received_at: None,
+                sent_at: None,
+            },
+        );
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Remove {
+                local_id: ShardNodeId::new(1),
+                reason: NodeRemovalReason::Disconnected,
+            },
+        );
+
+        let is_node_event = |m: &FeedMessage| {
+            matches!(
+                m,
+                FeedMessage::AddedNode { .. }
+                    | FeedMessage::RemovedNode { .. }
+                    | FeedMessage::LocatedNode { .. }
+                    | FeedMessage::StaleNode { .. }
+            )
+        };
+        let is_block_event = |m: &FeedMessage| matches!(m, FeedMessage::ImportedBlock { .. });
+
+        // Feeds without a mask see everything:
+        let messages = received_messages(&all_feed);
+        assert!(messages.iter().any(is_node_event));
+        assert!(messages.iter().any(is_block_event));
+
+        // Feeds with one are told that they've subscribed, but see nothing about nodes
+        // coming and going, in the snapshot of the chain or afterwards:
+        let messages = received_messages(&block_feed);
+        assert!(messages
+            .iter()
+            .any(|m| matches!(m, FeedMessage::SubscribedTo { .. })));
+        assert!(!messages.iter().any(is_node_event));
+        assert!(messages.iter().any(is_block_event));
+
+        // Unsubscribing forgets the mask:
+        inner.handle_from_feed(ConnId::new(2), FromFeedWebsocket::UnsubscribeAll);
+        subscribe(&mut inner, 2, genesis_hash);
+        assert!(received_messages(&block_feed).iter().any(is_node_event));
+    }
+
+    #[test]
+    fn finality_messages_are_only_sent_to_feeds_that_ask_for_them() {
+        let mut inner = inner_loop(Duration::ZERO);
+        let (shard_tx, _shard_rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Initialize { channel: shard_tx },
+        );
+        let chains: Vec<_> = (1..=2).map(BlockHash::from_low_u64_be).collect();
+        add_node(&mut inner, 0, chains[0]);
+        add_node(&mut inner, 1, chains[1]);
+        let feed = subscribe_feed(&mut inner, 1, chains[0]);
+
+        let mut height = 0;
+        let mut finalize = |inner: &mut InnerLoop, local_id: usize| {
+            height += 1;
+            inner.handle_from_shard(
+                ConnId::new(1),
+                FromShardWebsocket::Update {
+                    local_id: ShardNodeId::new(local_id),
+                    payload: node_message::Payload::NotifyFinalized(node_message::Finalized {
+                        hash: BlockHash::from_low_u64_be(height),
+                        height: height.to_string().into(),
+                    }),
+                    received_at: None,
+                    sent_at: None,
+                },
+            );
+        };
+        let finalized_blocks = |feed: &flume::Receiver<ToFeedWebsocket>| {
+            received_messages(feed)
+                .into_iter()
+                .filter(|m| matches!(m, FeedMessage::FinalizedBlock { .. }))
+                .count()
+        };
+
+        // Finalized blocks aren't sent by default, in the snapshot or afterwards:
+        assert_eq!(finalized_blocks(&feed), 0);
+        finalize(&mut inner, 0);
+        assert_eq!(finalized_blocks(&feed), 0);
+
+        // They are once the feed asks for them:
+        let chain = format!("{:#x}", chains[0]);
+        inner.handle_from_feed(
+            ConnId::new(1),
+            format!("send-finality:{chain}").parse().unwrap(),
+        );
+        finalize(&mut inner, 0);
+        assert_eq!(finalized_blocks(&feed), 1);
+
+        // Only for the chain they were asked for, though that's remembered if the feed
+        // comes back to it:
+        subscribe(&mut inner, 1, chains[1]);
+        finalize(&mut inner, 1);
+        assert_eq!(finalized_blocks(&feed), 0);
+        subscribe(&mut inner, 1, chains[0]);
+        assert_eq!(finalized_blocks(&feed), 1);
+
+        // And not once the feed asks for them to stop:
+        inner.handle_from_feed(
+            ConnId::new(1),
+            format!("no_more_finality:{chain}").parse().unwrap(),
+        );
+        finalize(&mut inner, 0);
+        assert_eq!(finalized_blocks(&feed), 0);
+    }
+
+    #[test]
+    fn feeds_in_the_same_format_share_broadcast_bytes() {
+        let mut inner = inner_loop(Duration::ZERO);
+        let (shard_tx, _shard_rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Initialize { channel: shard_tx },
+        );
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        add_node(&mut inner, 0, genesis_hash);
+
+        let feeds: Vec<_> = [false, true, false, true]
+            .into_iter()
+            .enumerate()
+            .map(|(idx, lean)| {
+                let feed_conn_id = idx as u64 + 1;
+                let rx = connect_feed(&mut inner, feed_conn_id, false);
+                inner.handle_from_feed(
+                    ConnId::new(feed_conn_id),
+                    FromFeedWebsocket::Subscribe {
+                        chain: genesis_hash.into(),
+                        ordering: FeedOrdering::Strict,
+                        authorities_only: false,
+                        replay: 0,
+                        events: FeedEvents::ALL,
+                        lean,
+                        bignums_as_strings: false,
+                    },
+                );
+                rx.drain().for_each(drop);
+                rx
+            })
+            .collect();
+
+        // Each feed is sent the new node, and feeds in the same format get the very same bytes:
+        add_node(&mut inner, 1, genesis_hash);
+        let ptrs: Vec<_> = feeds
+            .iter()
+            .map(|rx| {
+                let ToFeedWebsocket::Bytes(bytes) = rx.try_recv().unwrap();
+                assert_eq!(
+                    added_node_ids(&FeedMessage::from_bytes(&bytes).unwrap()),
+                    [1]
+                );
+                bytes.as_ptr()
+            })
+            .collect();
+        assert_eq!(ptrs[0], ptrs[2]);
+        assert_eq!(ptrs[1], ptrs[3]);
+        assert_ne!(ptrs[0], ptrs[1]);
+    }
+
+    #[test]
+    fn subscribe_commands_can_ask_for_events() {
+        let events = |cmd: &str| match cmd.parse::<FromFeedWebsocket>() {
+            Ok(FromFeedWebsocket::Subscribe { events, .. }) => events,
+            other => panic!("expected a subscribe command, got {other:?}"),
+        };
+        let chain = "0x0000000000000000000000000000000000000000000000000000000000000001";
+
+        assert_eq!(events(&format!("subscribe:{chain}")), FeedEvents::ALL);
+        assert_eq!(
+            events(&format!(
+                "subscribe:{chain}:best-effort:events=stats:replay=5"
+            )),
+            FeedEvents::STATS
+        );
+        assert_eq!(
+            events(&format!(
+                "subscribe-authorities:{chain}:events=node,block,finalized,stats,afg"
+            )),
+            FeedEvents::ALL
+        );
+        assert!(format!("subscribe:{chain}:events=blocks")
+            .parse::<FromFeedWebsocket>()
+            .is_err());
+    }
+
+    #[test]
+    fn subscribe_commands_can_ask_for_lean_messages() {
+        let chain = BlockHash::from_low_u64_be(1);
+        let lean = |cmd: &str| match cmd.parse::<FromFeedWebsocket>() {
+            Ok(FromFeedWebsocket::Subscribe { lean, .. }) => lean,
+            other => panic!("expected a subscribe command, got {other:?}"),
+        };
+        assert!(!lean(&format!("subscribe:{chain:#x}")));
+        assert!(lean(&format!("subscribe:{chain:#x}:lean=1")));
+        assert!(lean(&format!("subscribe:{chain:#x}:events=node:lean=true")));
+        assert!(!lean(&format!("subscribe:{chain:#x}:lean=0")));
+        assert!(format!("subscribe:{chain:#x}:lean=yes")
+            .parse::<FromFeedWebsocket>()
+            .is_err());
+    }
+
+    #[test]
+    fn subscribe_commands_can_ask_for_big_integers_as_strings() {
+        let chain = BlockHash::from_low_u64_be(1);
+        let bignums_as_strings = |cmd: &str| match cmd.parse::<FromFeedWebsocket>() {
+            Ok(FromFeedWebsocket::Subscribe {
+                bignums_as_strings, ..
+            }) => bignums_as_strings,
+            other => panic!("expected a subscribe command, got {other:?}"),
+        };
+        assert!(!bignums_as_strings(&format!("subscribe:{chain:#x}")));
+        assert!(bignums_as_strings(&format!(
+            "subscribe:{chain:#x}:bignum=string"
+        )));
+        assert!(bignums_as_strings(&format!(
+            "subscribe:{chain:#x}:lean=1:bignum=string"
+        )));
+        assert!(!bignums_as_strings(&format!(
+            "subscribe:{chain:#x}:bignum=number"
+        )));
+        assert!(format!("subscribe:{chain:#x}:bignum=text")
+            .parse::<FromFeedWebsocket>()
+            .is_err());
+    }
+
+    #[test]
+    fn subscribe_commands_can_prefix_the_genesis_hash() {
+        let subscription = |cmd: &str| match cmd.parse::<FromFeedWebsocket>() {
+            Ok(FromFeedWebsocket::Subscribe {
+                chain,
+                replay,
+                authorities_only,
+                ..
+            }) => (chain, replay, authorities_only),
+            other => panic!("expected a subscribe command, got {other:?}"),
+        };
+        let chain = "0x0000000000000000000000000000000000000000000000000000000000000001";
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+
+        assert_eq!(
+            subscription(&format!("subscribe:genesis:{chain}")),
+            (genesis_hash.into(), 0, false)
+        );
+        assert_eq!(
+            subscription(&format!("subscribe-authorities:genesis:{chain}:replay=5")),
+            (genesis_hash.into(), 5, true)
+        );
+        assert_eq!(
+            subscription("subscribe:label:Polkadot:replay=5"),
+            (FeedChain::Label("Polkadot".into()), 5, false)
+        );
+        for cmd in [
+            "subscribe:genesis",
+            "subscribe:genesis:",
+            "subscribe:genesis:Polkadot",
+            "subscribe:label",
+            "subscribe:label:",
+            "subscribe:Polkadot",
+        ] {
+            assert!(
+                cmd.parse::<FromFeedWebsocket>().is_err(),
+                "'{cmd}' should not parse"
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_commands_are_errors() {
+        // Commands without arguments don't need a colon:
+        assert!(matches!(
+            "list-chains".parse::<FromFeedWebsocket>(),
+            Ok(FromFeedWebsocket::ListChains)
+        ));
+        assert!(matches!(
+            "unsubscribe-all:".parse::<FromFeedWebsocket>(),
+            Ok(FromFeedWebsocket::UnsubscribeAll)
+        ));
+        assert!(matches!(
+            "ping".parse::<FromFeedWebsocket>(),
+            Ok(FromFeedWebsocket::Ping { value }) if value.is_empty()
+        ));
+
+        // But commands that need an argument must be given one:
+        for cmd in [
+            "",
+            ":",
+            "subscribe",
+            "subscribe:",
+            "subscribe-authorities",
+            "node-shard",
+            "node-shard:abc",
+            "node-detail",
+            "node-detail:-1",
+            "top-chains",
+            "top-chains:many",
+            "top-chains:5:wibble",
+            "send-finality",
+            "send-finality:0xnothex",
+            "no-more-finality:",
+            "subscribe:0x01:replay=lots",
+            "wibble:1",
+        ] {
+            assert!(
+                cmd.parse::<FromFeedWebsocket>().is_err(),
+                "'{cmd}' should not parse"
+            );
+        }
+    }
+
+    #[test]
+    fn top_chains_commands_can_ask_for_an_activity() {
+        let top_chains = |cmd: &str| match cmd.parse::<FromFeedWebsocket>() {
+            Ok(FromFeedWebsocket::TopChains { n, by }) => (n, by),
+            other => panic!("expected a top-chains command, got {other:?}"),
+        };
+        assert_eq!(top_chains("top-chains:5"), (5, ChainActivity::NodeCount));
+        assert_eq!(
+            top_chains("top-chains:5:nodes"),
+            (5, ChainActivity::NodeCount)
+        );
+        assert_eq!(
+            top_chains("top-chains:10:block-rate"),
+            (10, ChainActivity::BlockRate)
+        );
+    }
+
+    #[test]
+    fn top_chains_are_ordered_by_node_count() {
+        let mut inner = inner_loop(Duration::ZERO);
+        let hashes: Vec<_> = (1..=4).map(BlockHash::from_low_u64_be).collect();
+        // Chains 1 to 4 have 1, 3, 2 and 3 nodes respectively:
+        let mut local_id = 0;
+        for (hash, node_count) in hashes.iter().zip([1, 3, 2, 3]) {
+            for _ in 0..node_count {
+                add_node(&mut inner, local_id, *hash);
+                local_id += 1;
+            }
+        }
+        let feed = connect_feed(&mut inner, 1, false);
+        received_messages(&feed);
+
+        let mut top_chains = |n: usize| {
+            inner.handle_from_feed(
+                ConnId::new(1),
+                FromFeedWebsocket::TopChains {
+                    n,
+                    by: ChainActivity::NodeCount,
+                },
+            );
+            match <[FeedMessage; 1]>::try_from(received_messages(&feed)) {
+                Ok([FeedMessage::TopChains { chains }]) => chains
+                    .into_iter()
+                    .map(|(_, hash, node_count, _)| (hash, node_count))
+                    .collect::<Vec<_>>(),
+                msgs => panic!("expected the top chains, got {msgs:?}"),
+            }
+        };
+
+        // Chains with the same number of nodes are ordered by genesis hash:
+        assert_eq!(
+            top_chains(3),
+            [(hashes[1], 3), (hashes[3], 3), (hashes[2], 2)]
+        );
+        assert_eq!(top_chains(1), [(hashes[1], 3)]);
+        assert_eq!(top_chains(10).len(), 4);
+        assert!(top_chains(0).is_empty());
+    }
+
+    #[test]
+    fn failed_subscriptions_are_explained() {
+        let mut inner = inner_loop(Duration::ZERO);
+        inner.max_feeds_per_chain = 2;
+        let known = BlockHash::from_low_u64_be(1);
+        let other = BlockHash::from_low_u64_be(2);
+        let unknown = BlockHash::from_low_u64_be(3);
+        add_node(&mut inner, 0, known);
+        add_node(&mut inner, 1, other);
+        let subscribe_error = |messages: &[FeedMessage]| match messages {
+            [FeedMessage::SubscribeError { code, genesis_hash }] => (code.clone(), *genesis_hash),
+            _ => panic!("expected a subscribe error, got {messages:?}"),
+        };
+        let feed = connect_feed(&mut inner, 1, false);
+        received_messages(&feed);
+
+        // Chains that we don't know about can't be subscribed to:
+        subscribe(&mut inner, 1, unknown);
+        assert_eq!(
+            subscribe_error(&received_messages(&feed)),
+            ("unknown-chain".to_owned(), Some(unknown))
+        );
+
+        // Nor can chains that already have as many feeds as we allow:
+        let full_feeds = [
+            subscribe_feed(&mut inner, 2, known),
+            subscribe_feed(&mut inner, 3, known),
+        ];
+        subscribe(&mut inner, 1, other);
+        received_messages(&feed);
+        subscribe(&mut inner, 1, known);
+        assert_eq!(
+            subscribe_error(&received_messages(&feed)),
+            ("too-many-feeds".to_owned(), Some(known))
+        );
+
+        // Feeds that fail to subscribe keep the subscription that they had:
+        assert_eq!(
+            inner.chain_to_feed_conn_ids.get_key(&ConnId::new(1)),
+            Some(&other)
+        );
+
+        // Feeds already subscribed to a chain that's full can subscribe to it again:
+        received_messages(&full_feeds[0]);
+        subscribe(&mut inner, 2, known);
+        assert!(received_messages(&full_feeds[0]).iter().any(
+            |m| matches!(m, FeedMessage::SubscribedTo { genesis_hash } if *genesis_hash == known)
+        ));
+    }
+
+    #[test]
+    fn feeds_can_only_subscribe_to_so_many_chains() {
+        let mut inner = inner_loop(Duration::ZERO);
+        inner.max_subscriptions_per_feed = 2;
+        let chains: Vec<_> = (1..=3).map(BlockHash::from_low_u64_be).collect();
+        for (n, &chain) in chains.iter().enumerate() {
+            add_node(&mut inner, n, chain);
+        }
+        let subscribed_to = |messages: &[FeedMessage], chain: BlockHash| {
+            messages.iter().any(
+                |m| matches!(m, FeedMessage::SubscribedTo { genesis_hash } if *genesis_hash == chain),
+            )
+        };
+        let feed = connect_feed(&mut inner, 1, false);
+        received_messages(&feed);
+
+        // The feed can subscribe to as many chains as we allow, one after the other:
+        subscribe(&mut inner, 1, chains[0]);
+        assert!(subscribed_to(&received_messages(&feed), chains[0]));
+        subscribe(&mut inner, 1, chains[1]);
+        assert!(subscribed_to(&received_messages(&feed), chains[1]));
+
+        // But not to any more than that:
+        subscribe(&mut inner, 1, chains[2]);
+        assert!(matches!(
+            &received_messages(&feed)[..],
+            [FeedMessage::SubscribeError { code, genesis_hash }]
+                if code == "too-many-subscriptions" && *genesis_hash == Some(chains[2])
+        ));
+        assert_eq!(
+            inner.chain_to_feed_conn_ids.get_key(&ConnId::new(1)),
+            Some(&chains[1])
+        );
+
+        // Chains that it's already subscribed to don't count again:
+        subscribe(&mut inner, 1, chains[0]);
+        assert!(subscribed_to(&received_messages(&feed), chains[0]));
+
+        // Unsubscribing frees up its subscriptions:
+        inner.handle_from_feed(ConnId::new(1), FromFeedWebsocket::UnsubscribeAll);
+        received_messages(&feed);
+        subscribe(&mut inner, 1, chains[2]);
+        assert!(subscribed_to(&received_messages(&feed), chains[2]));
+    }
+
+    #[test]
+    fn concurrent_subscribes_reuse_cached_snapshot() {
+        let mut inner = inner_loop(Duration::from_secs(60));
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+
+        let (shard_tx, _shard_rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Initialize { channel: shard_tx },
+        );
+        add_node(&mut inner, 0, genesis_hash);
+
+        // The first subscription builds the snapshot, and the next ones reuse it:
+        let feeds: Vec<_> = (1..=3)
+            .map(|id| subscribe_feed(&mut inner, id, genesis_hash))
+            .collect();
+        assert_eq!(inner.snapshots_built, 1);
+        assert_eq!(inner.snapshots_reused, 2);
+        for feed in &feeds {
+            let messages = received_messages(feed);
+            assert!(messages.iter().any(
+                |m| matches!(m, FeedMessage::SubscribedTo { genesis_hash: h } if *h == genesis_hash)
+            ));
+            assert_eq!(added_node_ids(&messages), vec![0]);
+        }
+
+        // Subscribed feeds are told about a new node, and so is a feed reusing the
+        // snapshot after this, via the changes recorded since it was built:
+        add_node(&mut inner, 1, genesis_hash);
+        for feed in &feeds {
+            assert_eq!(added_node_ids(&received_messages(feed)), vec![1]);
+        }
+        let late_feed = subscribe_feed(&mut inner, 4, genesis_hash);
+        assert_eq!(inner.snapshots_built, 1);
+        assert_eq!(inner.snapshots_reused, 3);
+        assert_eq!(added_node_ids(&received_messages(&late_feed)), vec![0, 1]);
+    }
+
+    #[test]
+    fn snapshots_are_not_reused_if_cache_disabled() {
+        let mut inner = inner_loop(Duration::ZERO);
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        add_node(&mut inner, 0, genesis_hash);
+
+        for id in 1..=3 {
+            let feed = subscribe_feed(&mut inner, id, genesis_hash);
+            assert_eq!(added_node_ids(&received_messages(&feed)), vec![0]);
+        }
+        assert_eq!(inner.snapshots_built, 3);
+        assert_eq!(inner.snapshots_reused, 0);
+        assert!(inner.snapshot_cache.is_empty());
+    }
+
+    #[test]
+    fn silent_nodes_are_pruned_after_timeout() {
+        let mut inner = inner_loop(Duration::ZERO);
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+
+        // Node 0 is last heard from before `silent_since`, and node 1 after it:
+        add_node(&mut inner, 0, genesis_hash);
+        std::thread::sleep(Duration::from_millis(2));
+        let silent_since = time::now();
+        std::thread::sleep(Duration::from_millis(2));
+        add_node(&mut inner, 1, genesis_hash);
+
+        let feed = subscribe_feed(&mut inner, 1, genesis_hash);
+        assert_eq!(added_node_ids(&received_messages(&feed)), vec![0, 1]);
+
+        // Nothing is pruned until the timeout has passed:
+        let timeout_ms = inner.stale_node_timeout.as_millis() as u64;
+        inner.prune_silent_nodes(silent_since + timeout_ms - 1000);
+        assert!(received_messages(&feed).is_empty());
+        assert_eq!(inner.node_ids.len(), 2);
+
+        // After it, only the node that's been silent since then is removed:
+        inner.prune_silent_nodes(silent_since + timeout_ms);
+        let messages = received_messages(&feed);
+        assert!(messages
+            .iter()
+            .any(|m| matches!(m, FeedMessage::RemovedNode { node_id: 0 })));
+        assert!(!messages
+            .iter()
+            .any(|m| matches!(m, FeedMessage::RemovedNode { node_id: 1 })));
+        assert_eq!(inner.node_ids.len(), 1);
+    }
+
+    #[test]
+    fn removed_nodes_come_with_a_reason() {
+        let mut inner = inner_loop(Duration::ZERO);
+        // Keep the chain around once its last node has gone, so that we hear about every node:
+        let ttl = Duration::from_secs(30);
+        inner.empty_chain_ttl = ttl;
+        inner.node_state.set_empty_chain_ttl(ttl);
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        for local_id in 0..3 {
+            add_node(&mut inner, local_id, genesis_hash);
+        }
+        let feed = subscribe_feed(&mut inner, 1, genesis_hash);
+        received_messages(&feed);
+        let removals = |messages: Vec<FeedMessage>| {
+            messages
+                .into_iter()
+                .filter_map(|m| match m {
+                    FeedMessage::RemovedNodeReason { node_id, reason } => {
+                        Some(format!("{node_id}: {reason}"))
+                    }
+                    FeedMessage::RemovedNode { node_id } => Some(format!("{node_id}: removed")),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // The reason comes just before the removal that it explains:
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Remove {
+                local_id: ShardNodeId::new(1),
+                reason: NodeRemovalReason::Disconnected,
+            },
+        );
+        assert_eq!(
+            removals(received_messages(&feed)),
+            ["1: disconnected", "1: removed"]
+        );
+
+        // Nodes that we stop hearing from time out:
+        let timeout_ms = inner.stale_node_timeout.as_millis() as u64;
+        inner.prune_silent_nodes(time::now() + timeout_ms + 1);
+        assert_eq!(
+            removals(received_messages(&feed)),
+            ["0: timed-out", "0: removed", "2: timed-out", "2: removed"]
+        );
+    }
+
+    #[test]
+    fn feeds_whose_channels_fill_up_are_closed() {
+        let mut inner = inner_loop(Duration::ZERO);
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        add_node(&mut inner, 0, genesis_hash);
+
+        // There's room for the initial messages, but not for the snapshot of the chain too:
+        let (tx, rx) = flume::bounded(1);
+        inner.handle_from_feed(
+            ConnId::new(1),
+            FromFeedWebsocket::Initialize {
+                channel: tx,
+                admin: false,
+                protocol: FeedProtocol::V2,
+            },
+        );
+        assert_eq!(rx.len(), 1);
+        inner.handle_from_feed(
+            ConnId::new(1),
+            "subscribe:0x0000000000000000000000000000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+        );
+
+        // The aggregator has given up on the feed, so once the feed has received the
+        // messages that did fit, it finds that the channel is closed:
+        assert!(rx.recv().is_ok());
+        assert!(matches!(rx.recv(), Err(flume::RecvError::Disconnected)));
+
+        // Messages to other feeds aren't held up by it:
+        let feed = subscribe_feed(&mut inner, 2, genesis_hash);
+        assert_eq!(added_node_ids(&received_messages(&feed)), vec![0]);
+    }
+
+    #[test]
+    fn only_admin_feeds_are_told_which_shard_a_node_is_on() {
+        let mut inner = inner_loop(Duration::ZERO);
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        add_node(&mut inner, 0, genesis_hash);
+        inner.handle_from_shard(
+            ConnId::new(2),
+            FromShardWebsocket::Add {
+                local_id: ShardNodeId::new(0),
+                ip: "127.0.0.1".parse().unwrap(),
+                node: node("Node on shard A"),
+                genesis_hash,
+                shard_id: Some("shard-a".into()),
+                token: None,
+            },
+        );
+
+        for (feed_conn_id, admin) in [(1, false), (2, true)] {
+            let feed = connect_feed(&mut inner, feed_conn_id, admin);
+            inner.handle_from_feed(
+                ConnId::new(feed_conn_id),
+                "subscribe:0x0000000000000000000000000000000000000000000000000000000000000001"
+                    .parse()
+                    .unwrap(),
+            );
+            received_messages(&feed);
+
+            for node_id in [0, 1] {
+                inner.handle_from_feed(
+                    ConnId::new(feed_conn_id),
+                    format!("node-shard:{node_id}").parse().unwrap(),
+                );
+            }
+            let messages = received_messages(&feed);
+            if admin {
+                // A shard without an ID is identified by its connection:
+                assert_eq!(
+                    messages,
+                    vec![
+                        FeedMessage::NodeShard {
+                            node_id: 0,
+                            shard_id: "conn-1".to_owned()
+                        },
+                        FeedMessage::NodeShard {
+                            node_id: 1,
+                            shard_id: "shard-a".to_owned()
+                        },
+                    ]
+                );
+            } else {
+                assert!(messages.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn admin_feeds_are_told_when_shards_connect_and_disconnect() {
+        let mut inner = inner_loop(Duration::ZERO);
+        let feed = connect_feed(&mut inner, 1, false);
+        let admin_feed = connect_feed(&mut inner, 2, true);
+        let shards = |messages: Vec<FeedMessage>| -> Vec<Vec<(u64, Option<String>, usize)>> {
+            messages
+                .into_iter()
+                .filter_map(|m| match m {
+                    FeedMessage::Shards { shards } => Some(
+                        shards
+                            .into_iter()
+                            .map(|(conn_id, shard_id, node_count, _, _)| {
+                                (conn_id, shard_id, node_count)
+                            })
+                            .collect(),
+                    ),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // Admin feeds are told about the shards when they connect:
+        assert_eq!(shards(received_messages(&admin_feed)), vec![vec![]]);
+
+        let before = time::now();
+        let (shard_tx, _shard_rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Initialize { channel: shard_tx },
+        );
+        let (shard_tx, _shard_rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(2),
+            FromShardWebsocket::Initialize { channel: shard_tx },
+        );
+        assert_eq!(
+            shards(received_messages(&admin_feed)),
+            vec![vec![(1, None, 0)], vec![(1, None, 0), (2, None, 0)]]
+        );
+
+        add_node(&mut inner, 0, BlockHash::from_low_u64_be(1));
+        inner.handle_from_shard(ConnId::new(2), FromShardWebsocket::Disconnected);
+        assert_eq!(
+            shards(received_messages(&admin_feed)),
+            vec![vec![(1, Some("conn-1".to_owned()), 1)]]
+        );
+
+        // A newly connected admin feed sees when each shard was last heard from:
+        let late_admin_feed = connect_feed(&mut inner, 3, true);
+        match &received_messages(&late_admin_feed)[..] {
+            [.., FeedMessage::Shards { shards }] => {
+                assert_eq!(shards.len(), 1);
+                assert!(shards[0].3 >= before);
+            }
+            messages => panic!("expected a shards message, got {messages:?}"),
+        }
+
+        // Other feeds aren't told about shards at all:
+        assert!(shards(received_messages(&feed)).is_empty());
+    }
+
+    #[test]
+    fn admin_feeds_are_told_when_shards_drain() {
+        let mut inner = inner_loop(Duration::ZERO);
+        let admin_feed = connect_feed(&mut inner, 1, true);
+        let draining = |messages: Vec<FeedMessage>| -> Vec<Vec<(u64, bool)>> {
+            messages
+                .into_iter()
+                .filter_map(|m| match m {
+                    FeedMessage::Shards { shards } => Some(
+                        shards
+                            .into_iter()
+                            .map(|(conn_id, _, _, _, draining)| (conn_id, draining))
+                            .collect(),
+                    ),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        for conn_id in [1, 2] {
+            let (shard_tx, _shard_rx) = flume::unbounded();
+            inner.handle_from_shard(
+                ConnId::new(conn_id),
+                FromShardWebsocket::Initialize { channel: shard_tx },
+            );
+        }
+        received_messages(&admin_feed);
+
+        inner.handle_from_shard(
+            ConnId::new(2),
+            FromShardWebsocket::Draining { draining: true },
+        );
+        // Repeating ourselves doesn't lead to another message:
+        inner.handle_from_shard(
+            ConnId::new(2),
+            FromShardWebsocket::Draining { draining: true },
+        );
+        assert_eq!(
+            draining(received_messages(&admin_feed)),
+            vec![vec![(1, false), (2, true)]]
+        );
+
+        let (tx, rx) = flume::unbounded();
+        inner.handle_gather_admin_snapshot(tx);
+        let snapshot = rx.try_recv().unwrap();
+        let snapshot_draining: Vec<_> = snapshot.shards.iter().map(|s| s.draining).collect();
+        assert_eq!(snapshot_draining, vec![false, true]);
+
+        inner.handle_from_shard(
+            ConnId::new(2),
+            FromShardWebsocket::Draining { draining: false },
+        );
+        assert_eq!(
+            draining(received_messages(&admin_feed)),
+            vec![vec![(1, false), (2, false)]]
+        );
+    }
+
+    #[test]
+    fn admin_snapshot_lists_chains_and_shards() {
+        let mut inner = inner_loop(Duration::ZERO);
+        let (shard_tx, _shard_rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Initialize { channel: shard_tx },
+        );
+        let (shard_tx, shard_rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(2),
+            FromShardWebsocket::Initialize { channel: shard_tx },
+        );
+
+        // Two nodes on one chain and one on another via shard 1, and one via shard 2:
+        add_node(&mut inner, 0, BlockHash::from_low_u64_be(1));
+        add_node(&mut inner, 1, BlockHash::from_low_u64_be(2));
+        add_node(&mut inner, 2, BlockHash::from_low_u64_be(2));
+        inner.handle_from_shard(
+            ConnId::new(2),
+            FromShardWebsocket::Add {
+                local_id: ShardNodeId::new(0),
+                ip: "127.0.0.1".parse().unwrap(),
+                node: node("Node on shard A"),
+                genesis_hash: BlockHash::from_low_u64_be(1),
+                shard_id: Some("shard-a".into()),
+                token: None,
+            },
+        );
+        // Shard 2's connection is closing:
+        drop(shard_rx);
+
+        let (tx, rx) = flume::unbounded();
+        inner.handle_gather_admin_snapshot(tx);
+        let snapshot = rx.try_recv().unwrap();
+
+        // Chains are largest first (ties broken by label):
+        let chains: Vec<_> = snapshot
+            .chains
+            .iter()
+            .map(|c| (c.genesis_hash, c.node_count))
+            .collect();
+        assert_eq!(
+            chains,
+            vec![
+                (BlockHash::from_low_u64_be(1), 2),
+                (BlockHash::from_low_u64_be(2), 2)
+            ]
+        );
+
+        let shards: Vec<_> = snapshot
+            .shards
+            .iter()
+            .map(|s| (s.conn_id, s.shard_id.as_deref(), s.node_count, s.connected))
+            .collect();
+        assert_eq!(
+            shards,
+            vec![(1, Some("conn-1"), 3, true), (2, Some("shard-a"), 1, false)]
+        );
+    }
+
+    #[test]
+    fn updating_chain_lists_removes_and_mutes_disallowed_nodes() {
+        let mut inner = inner_loop(Duration::ZERO);
+        let (shard_tx, shard_rx) = flume::unbounded();
+        inner.handle_from_shard(
+            ConnId::new(1),
+            FromShardWebsocket::Initialize { channel: shard_tx },
+        );
+
+        let genesis1 = BlockHash::from_low_u64_be(1);
+        let genesis2 = BlockHash::from_low_u64_be(2);
+        add_node(&mut inner, 0, genesis1);
+        add_node(&mut inner, 1, genesis2);
+        let feed = subscribe_feed(&mut inner, 1, genesis1);
+        assert_eq!(added_node_ids(&received_messages(&feed)), vec![0]);
+
+        inner.handle_update_chain_lists(
+            Vec::new(),
+            vec![AllowedChain {
+                genesis_hash: genesis2,
+                label: "Chain Two".into(),
+            }],
+        );
+
+        // The chain that's no longer allowed is removed along with its node, and the node's
+        // shard is told to mute it:
+        assert!(received_messages(&feed).iter().any(
+            |m| matches!(m, FeedMessage::RemovedChain { genesis_hash } if *genesis_hash == genesis1)
+        ));
+        assert_eq!(inner.node_ids.len(), 1);
+        let muted: Vec<_> = shard_rx.try_iter().collect();
+        assert!(matches!(
+            &*muted,
+            [ToShardWebsocket::Mute {
+                local_id,
+                reason: MuteReason::ChainNotAllowed
+            }] if *local_id == ShardNodeId::new(0)
+        ));
+    }
+
+    #[test]
+    fn node_timestamps_are_only_used_if_trusted() {
+        for trust_node_timestamps in [true, false] {
+            let mut inner = inner_loop(Duration::ZERO);
+            inner.trust_node_timestamps = trust_node_timestamps;
+            let genesis_hash = BlockHash::from_low_u64_be(1);
+            add_node(&mut inner, 0, genesis_hash);
+            let feed = subscribe_feed(&mut inner, 1, genesis_hash);
+            received_messages(&feed);
+
+            // The node's clock is a minute behind ours:
+            let now = time::now();
+            let sent_at = now - 60_000;
+            inner.handle_from_shard(
+                ConnId::new(1),
+                FromShardWebsocket::Update {
+                    local_id: ShardNodeId::new(0),
+                    payload: node_message::Payload::BlockImport(common::node_types::Block {
+                        hash: BlockHash::from_low_u64_be(2),
+                        height: 1,
+                    }),
+                    received_at: None,
+                    sent_at: Some(sent_at),
+                },
+            );
+            let best_block_at = received_messages(&feed)
+                .into_iter()
+                .find_map(|m| match m {
+                    FeedMessage::BestBlock { timestamp, .. } => Some(timestamp),
+                    _ => None,
+                })
+                .expect("a best block should be sent");
+
+            // Feeds subscribing afterwards are told the time by the same clock, so that the
+            // times that blocks arrived at can be compared to it:
+            let feed = subscribe_feed(&mut inner, 2, genesis_hash);
+            let synced_to = received_messages(&feed)
+                .into_iter()
+                .find_map(|m| match m {
+                    FeedMessage::TimeSync { time } => Some(time),
+                    _ => None,
+                })
+                .expect("the time should be sent");
+
+            if trust_node_timestamps {
+                assert_eq!(best_block_at, sent_at);
+                assert!(synced_to < now - 50_000);
+            } else {
+                assert!(best_block_at >= now);
+                assert!(synced_to >= now);
+            }
         }
     }
 }
@@ -14,12 +14,16 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+#[allow(clippy::module_inception)]
 mod aggregator;
 mod aggregator_set;
+mod event_history;
 mod inner_loop;
 
 // Expose the various message types that can be worked with externally:
 pub use aggregator::AggregatorOpts;
-pub use inner_loop::{FromFeedWebsocket, FromShardWebsocket, ToFeedWebsocket, ToShardWebsocket};
+pub use inner_loop::{
+    FeedChain, FromFeedWebsocket, FromShardWebsocket, Metrics, ToFeedWebsocket, ToShardWebsocket,
+};
 
 pub use aggregator_set::*;
@@ -0,0 +1,41 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use common::node_types::Timestamp;
+use serde::Serialize;
+
+/// Details about the build of the core that's running, as recorded by the build script.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// The crate version.
+    pub version: &'static str,
+    /// The git commit that the core was built from, if it's known.
+    pub commit: Option<&'static str>,
+    /// Unix timestamp (in milliseconds) for when the core was built.
+    pub built_at: Timestamp,
+}
+
+impl BuildInfo {
+    pub fn get() -> BuildInfo {
+        BuildInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            commit: option_env!("TELEMETRY_GIT_COMMIT"),
+            built_at: env!("TELEMETRY_BUILD_TIMESTAMP")
+                .parse()
+                .unwrap_or_default(),
+        }
+    }
+}
@@ -0,0 +1,888 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{node_types::BlockHash, runtime};
+use futures::FutureExt;
+
+use crate::aggregator::{AggregatorOpts, AggregatorSet};
+use crate::chain_lists::{ChainListReloader, ChainListSource};
+use crate::feed_limit::FeedLimit;
+use crate::find_location::{GeoIpLocationProvider, LocationProvider};
+use crate::metrics::CoreMetrics;
+use crate::ndjson_feed::{self, NdjsonFeedOpts};
+use crate::server::{self, FeedFlushOpts, ServerOpts};
+use crate::state::{AllowedChain, ChainTag, PeerCountHandling};
+
+/// Configure and start a telemetry core. The defaults here match the defaults of the
+/// `telemetry_core` binary, which is itself just a thin wrapper around this.
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// let core = telemetry_core::CoreBuilder::new()
+///     .listen("127.0.0.1:0".parse()?)
+///     .worker_threads(2)
+///     .denylist(["Some Chain"])
+///     .spawn()
+///     .await?;
+///
+/// println!("Listening on {}", core.local_addr());
+/// core.shutdown().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct CoreBuilder<L = GeoIpLocationProvider> {
+    listen: SocketAddr,
+    worker_threads: Option<usize>,
+    worker_cpu_affinity: bool,
+    num_aggregators: Option<usize>,
+    aggregator_queue_len: usize,
+    chain_lists: ChainListSource,
+    chain_tags: Vec<ChainTag>,
+    authority_only_chains: Vec<BlockHash>,
+    reload_chain_lists_on_sighup: bool,
+    max_third_party_nodes: usize,
+    max_chains: usize,
+    max_feeds_per_chain: usize,
+    max_subscriptions_per_feed: usize,
+    max_feeds: Option<usize>,
+    authority_node_token: Option<String>,
+    expose_node_details: bool,
+    trust_node_timestamps: bool,
+    peer_count_handling: PeerCountHandling,
+    node_update_min_interval: Duration,
+    snapshot_cache_ttl: Duration,
+    event_history_size: usize,
+    disable_geolocation: bool,
+    geoip_max_concurrent: usize,
+    geoip_max_wait: Duration,
+    stale_node_timeout: Duration,
+    stale_node_check_interval: Duration,
+    feed_heartbeat_interval: Duration,
+    node_count_interval: Duration,
+    empty_chain_ttl: Duration,
+    max_block_height_jump: Option<u64>,
+    stat_history_len: Option<usize>,
+    block_window: Option<usize>,
+    latency_sample_every: u64,
+    latency_window: Duration,
+    feed_timeout: Duration,
+    feed_send_timeout: Option<Duration>,
+    feed_flush_interval: Duration,
+    feed_flush_size: usize,
+    feed_channel_capacity: Option<usize>,
+    admin_feed: bool,
+    max_feed_command_bytes: usize,
+    max_accepts_per_sec: Option<u32>,
+    listen_backlog: Option<u32>,
+    max_pending_connections: Option<usize>,
+    tcp_keepalive: Option<Duration>,
+    admin_token: Option<String>,
+    shard_secret: Option<String>,
+    ndjson_feed_listen: Option<SocketAddr>,
+    prometheus_registry: Option<prometheus::Registry>,
+    location_provider: Arc<L>,
+}
+
+impl Default for CoreBuilder {
+    fn default() -> Self {
+        CoreBuilder {
+            listen: ([127, 0, 0, 1], 8000).into(),
+            worker_threads: None,
+            worker_cpu_affinity: false,
+            num_aggregators: None,
+            aggregator_queue_len: 10_000,
+            chain_lists: ChainListSource::default(),
+            chain_tags: Vec::new(),
+            authority_only_chains: Vec::new(),
+            reload_chain_lists_on_sighup: false,
+            max_third_party_nodes: 1000,
+            max_chains: usize::MAX,
+            max_feeds_per_chain: usize::MAX,
+            max_subscriptions_per_feed: usize::MAX,
+            max_feeds: None,
+            authority_node_token: None,
+            expose_node_details: false,
+            trust_node_timestamps: true,
+            peer_count_handling: PeerCountHandling::Flag,
+            node_update_min_interval: Duration::ZERO,
+            snapshot_cache_ttl: Duration::ZERO,
+            event_history_size: 0,
+            disable_geolocation: false,
+            geoip_max_concurrent: 32,
+            geoip_max_wait: Duration::from_secs(10),
+            stale_node_timeout: Duration::ZERO,
+            stale_node_check_interval: Duration::from_secs(10),
+            feed_heartbeat_interval: Duration::ZERO,
+            node_count_interval: Duration::from_secs(5),
+            empty_chain_ttl: Duration::ZERO,
+            max_block_height_jump: None,
+            stat_history_len: None,
+            block_window: None,
+            latency_sample_every: 100,
+            latency_window: Duration::from_secs(60),
+            feed_timeout: Duration::from_secs(10),
+            feed_send_timeout: None,
+            feed_flush_interval: Duration::ZERO,
+            feed_flush_size: 64 * 1024,
+            feed_channel_capacity: None,
+            admin_feed: false,
+            max_feed_command_bytes: 16 * 1000,
+            max_accepts_per_sec: None,
+            listen_backlog: None,
+            max_pending_connections: None,
+            tcp_keepalive: None,
+            admin_token: None,
+            shard_secret: None,
+            ndjson_feed_listen: None,
+            prometheus_registry: None,
+            location_provider: Arc::new(GeoIpLocationProvider::default()),
+        }
+    }
+}
+
+impl CoreBuilder {
+    /// Create a new builder with the default configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<L: LocationProvider> CoreBuilder<L> {
+    /// The socket address to listen on. Use port 0 to listen on any free port, and
+    /// [`CoreHandle::local_addr()`] to find out which one was picked.
+    pub fn listen(mut self, addr: SocketAddr) -> Self {
+        self.listen = addr;
+        self
+    }
+
+    /// Number of worker threads to spawn. If 0 is given, use the number of CPUs available.
+    /// By default, we use the number of CPUs available, up to a maximum of 8.
+    pub fn worker_threads(mut self, n: usize) -> Self {
+        self.worker_threads = Some(n);
+        self
+    }
+
+    /// Try to pin each worker thread to its own CPU. This is best-effort; a warning is logged
+    /// if it can't be done on this platform. Defaults to false.
+    pub fn worker_cpu_affinity(mut self, pin_to_cpus: bool) -> Self {
+        self.worker_cpu_affinity = pin_to_cpus;
+        self
+    }
+
+    /// Number of aggregators to spread feed subscriptions across. If 0 is given, use the
+    /// number of CPUs available. Defaults to 1.
+    pub fn num_aggregators(mut self, n: usize) -> Self {
+        self.num_aggregators = Some(n);
+        self
+    }
+
+    /// How big can the message queue for each aggregator grow before we start dropping
+    /// non-essential messages?
+    pub fn aggregator_queue_len(mut self, len: usize) -> Self {
+        self.aggregator_queue_len = len;
+        self
+    }
+
+    /// Names of chains that are not allowed to connect. Case sensitive.
+    pub fn denylist<S: Into<String>>(mut self, chains: impl IntoIterator<Item = S>) -> Self {
+        self.chain_lists.denylist = chains.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// A file containing names of chains that are not allowed to connect, one per line, in
+    /// addition to those given to [`CoreBuilder::denylist()`].
+    pub fn denylist_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.chain_lists.denylist_file = Some(path.into());
+        self
+    }
+
+    /// Only allow nodes from these chains to connect. If none are given, nodes from any
+    /// chain can connect.
+    pub fn allowlist(mut self, chains: impl IntoIterator<Item = AllowedChain>) -> Self {
+        self.chain_lists.allowlist = chains.into_iter().collect();
+        self
+    }
+
+    /// A file containing chains to allow, one `<genesis_hash>=<label>` entry per line, in
+    /// addition to those given to [`CoreBuilder::allowlist()`].
+    pub fn allowlist_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.chain_lists.allowlist_file = Some(path.into());
+        self
+    }
+
+    /// Attach these tags to chains when they're created. Feeds are told about the tags of
+    /// each chain alongside its label and node count.
+    pub fn chain_tags(mut self, tags: impl IntoIterator<Item = ChainTag>) -> Self {
+        self.chain_tags = tags.into_iter().collect();
+        self
+    }
+
+    /// Only track authority nodes on the chains with these genesis hashes. Other nodes on
+    /// them are turned away when they connect, as if their chain weren't allowed.
+    pub fn authority_only_chains(
+        mut self,
+        genesis_hashes: impl IntoIterator<Item = BlockHash>,
+    ) -> Self {
+        self.authority_only_chains = genesis_hashes.into_iter().collect();
+        self
+    }
+
+    /// Re-read the deny and allow list files when the process receives SIGHUP. This does
+    /// nothing on platforms without SIGHUP. See [`CoreHandle::reload_chain_lists()`].
+    pub fn reload_chain_lists_on_sighup(mut self, reload: bool) -> Self {
+        self.reload_chain_lists_on_sighup = reload;
+        self
+    }
+
+    /// How many nodes from third party chains are allowed to connect before we prevent
+    /// connections from them.
+    pub fn max_third_party_nodes(mut self, n: usize) -> Self {
+        self.max_third_party_nodes = n;
+        self
+    }
+
+    /// How many chains can be tracked at once. Once this many chains are known, nodes on
+    /// new chains are rejected, while existing chains continue to accept nodes. Chains are
+    /// forgotten as soon as their last node goes, making room for others. Unlimited by default.
+    pub fn max_chains(mut self, n: usize) -> Self {
+        self.max_chains = n;
+        self
+    }
+
+    /// How many feeds can be subscribed to each chain at once. Feeds asking to subscribe to
+    /// a chain beyond this are sent a `SubscribeError` instead. Feeds are spread across
+    /// aggregators, and each aggregator applies this limit to its own feeds. Unlimited by
+    /// default.
+    pub fn max_feeds_per_chain(mut self, n: usize) -> Self {
+        self.max_feeds_per_chain = n;
+        self
+    }
+
+    /// How many different chains a feed can subscribe to until it unsubscribes, counting the
+    /// chains that later subscriptions moved it off. Feeds asking to subscribe to a chain
+    /// beyond this are sent a `SubscribeError` instead. Unlimited by default.
+    pub fn max_subscriptions_per_feed(mut self, n: usize) -> Self {
+        self.max_subscriptions_per_feed = n;
+        self
+    }
+
+    /// How many feeds can be connected at once, over websockets or NDJSON. Websocket feeds
+    /// beyond this are refused with a "503 Service Unavailable" response before they're
+    /// upgraded, and NDJSON feeds are disconnected straight away. Unlimited by default.
+    pub fn max_feeds(mut self, n: usize) -> Self {
+        self.max_feeds = Some(n);
+        self
+    }
+
+    /// Let authority nodes that connect with this token (in an "Authorization: Bearer <token>"
+    /// header, or as in "/submit?token=<token>") in even if their chain isn't on the allow
+    /// list, so that our own validators are always tracked.
+    /// Other nodes on such a chain are still turned away, and the deny list still applies to
+    /// every node. Disabled by default.
+    pub fn authority_node_token(mut self, token: impl Into<String>) -> Self {
+        self.authority_node_token = Some(token.into());
+        self
+    }
+
+    /// Expose the details (IP address, SysInfo, HwBench) of connected nodes to feeds.
+    pub fn expose_node_details(mut self, expose: bool) -> Self {
+        self.expose_node_details = expose;
+        self
+    }
+
+    /// Go by the times that nodes say they sent their messages at when working out when
+    /// blocks were seen, and the time that feeds sync with, rather than the times that we
+    /// receive them at. Enabled by default.
+    pub fn trust_node_timestamps(mut self, trust: bool) -> Self {
+        self.trust_node_timestamps = trust;
+        self
+    }
+
+    /// Don't look up the geographical locations of nodes, and don't hold on to their IP
+    /// addresses (even if [`CoreBuilder::expose_node_details()`] is set).
+    pub fn disable_geolocation(mut self, disable: bool) -> Self {
+        self.disable_geolocation = disable;
+        self
+    }
+
+    /// Look up the locations of at most this many nodes at once. Lookups beyond this wait
+    /// their turn.
+    pub fn geoip_max_concurrent(mut self, max: usize) -> Self {
+        self.geoip_max_concurrent = max;
+        self
+    }
+
+    /// Give up on looking up the location of a node once it has waited this long for its
+    /// turn, leaving it without a location.
+    pub fn geoip_max_wait(mut self, max_wait: Duration) -> Self {
+        self.geoip_max_wait = max_wait;
+        self
+    }
+
+    /// What to do about nodes that report implausible peer counts.
+    pub fn peer_count_handling(mut self, handling: PeerCountHandling) -> Self {
+        self.peer_count_handling = handling;
+        self
+    }
+
+    /// Send feeds the stats and other details that nodes report periodically at most once
+    /// per this interval for each node, with the latest values. Zero disables this.
+    pub fn node_update_min_interval(mut self, interval: Duration) -> Self {
+        self.node_update_min_interval = interval;
+        self
+    }
+
+    /// Reuse the chain snapshots sent to subscribing feeds for this long. Zero disables this.
+    pub fn snapshot_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.snapshot_cache_ttl = ttl;
+        self
+    }
+
+    /// How many recent node, block import and finalization events to keep for each chain,
+    /// for feeds that ask for them to be replayed when subscribing. Zero disables this.
+    pub fn event_history_size(mut self, size: usize) -> Self {
+        self.event_history_size = size;
+        self
+    }
+
+    /// Remove nodes that haven't sent a message for this long. Zero disables this.
+    pub fn stale_node_timeout(mut self, timeout: Duration) -> Self {
+        self.stale_node_timeout = timeout;
+        self
+    }
+
+    /// How often to check for nodes that have exceeded the stale node timeout. Zero
+    /// disables this.
+    pub fn stale_node_check_interval(mut self, interval: Duration) -> Self {
+        self.stale_node_check_interval = interval;
+        self
+    }
+
+    /// Send every feed a heartbeat carrying the current time this often, so that quiet feed
+    /// connections can be told apart from stalled ones. Zero (the default) disables this.
+    pub fn feed_heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.feed_heartbeat_interval = interval;
+        self
+    }
+
+    /// Tell feeds subscribed to just the node counts of a chain how many nodes have joined
+    /// and left it this often (five seconds by default). Zero disables this.
+    pub fn node_count_interval(mut self, interval: Duration) -> Self {
+        self.node_count_interval = interval;
+        self
+    }
+
+    /// Keep chains for this long after their last node disconnects before removing them,
+    /// so that a chain whose nodes briefly drop out and reconnect isn't removed and re-added.
+    /// Zero (the default) removes chains as soon as they're empty.
+    pub fn empty_chain_ttl(mut self, ttl: Duration) -> Self {
+        self.empty_chain_ttl = ttl;
+        self
+    }
+
+    /// Ignore best blocks that nodes report which are more than this many blocks ahead of
+    /// their chain's current best block. By default, any plausible height is accepted.
+    pub fn max_block_height_jump(mut self, max_jump: u64) -> Self {
+        self.max_block_height_jump = Some(max_jump);
+        self
+    }
+
+    /// Keep at most this many values of each stat series (bandwidth, CPU use, peer counts and
+    /// so on) for each node. Once a series is full, its older values are averaged together to
+    /// make room, so a longer period is covered at a lower resolution. The default is 20.
+    pub fn stat_history_len(mut self, len: usize) -> Self {
+        self.stat_history_len = Some(len);
+        self
+    }
+
+    /// Keep track of at most this many of the most recent block heights on each chain when
+    /// working out how quickly blocks propagate and which finalized block nodes agree on, so
+    /// that memory use doesn't grow with how quickly a chain produces blocks. By default, 20
+    /// heights are kept for block propagation and 64 for finality.
+    pub fn block_window(mut self, window: usize) -> Self {
+        self.block_window = Some(window);
+        self
+    }
+
+    /// Time one in every this many node updates, from being received from a shard to the
+    /// messages for feeds that it leads to being queued up, and report percentiles of these
+    /// latencies in our metrics. "0" times nothing. The default is 100.
+    pub fn latency_sample_every(mut self, every: u64) -> Self {
+        self.latency_sample_every = every;
+        self
+    }
+
+    /// Report the latencies of node updates over windows of this length. The default is 60
+    /// seconds.
+    pub fn latency_window(mut self, window: Duration) -> Self {
+        self.latency_window = window;
+        self
+    }
+
+    /// Close feed connections that take longer than this to receive a batch of messages.
+    pub fn feed_timeout(mut self, timeout: Duration) -> Self {
+        self.feed_timeout = timeout;
+        self
+    }
+
+    /// Drop feed connections that take longer than this to finish any single write, including
+    /// closing the connection. By default, writes are only bounded by the feed timeout.
+    pub fn feed_send_timeout(mut self, timeout: Duration) -> Self {
+        self.feed_send_timeout = Some(timeout);
+        self
+    }
+
+    /// Send messages for a feed that are ready within this long of the first one together.
+    /// Zero sends messages as soon as they are ready.
+    pub fn feed_flush_interval(mut self, interval: Duration) -> Self {
+        self.feed_flush_interval = interval;
+        self
+    }
+
+    /// Send messages waiting for a feed right away once they add up to this many bytes.
+    pub fn feed_flush_size(mut self, num_bytes: usize) -> Self {
+        self.feed_flush_size = num_bytes;
+        self
+    }
+
+    /// Close feed connections that have more than this many messages waiting to be sent
+    /// to them, rather than holding on to them in memory. By default, there's no limit.
+    pub fn feed_channel_capacity(mut self, capacity: usize) -> Self {
+        self.feed_channel_capacity = Some(capacity);
+        self
+    }
+
+    /// Serve an "/admin_feed" endpoint alongside "/feed".
+    pub fn admin_feed(mut self, enabled: bool) -> Self {
+        self.admin_feed = enabled;
+        self
+    }
+
+    /// Disconnect feeds that send us a command larger than this many bytes. Feeds only
+    /// send us small commands, so this can be kept small.
+    pub fn max_feed_command_bytes(mut self, num_bytes: usize) -> Self {
+        self.max_feed_command_bytes = num_bytes;
+        self
+    }
+
+    /// Accept at most this many new connections (from feeds and shards alike) per second.
+    /// Connections beyond this rate wait to be accepted rather than being refused, which
+    /// smooths out a flood of reconnections. By default, there's no limit.
+    pub fn max_accepts_per_sec(mut self, n: u32) -> Self {
+        self.max_accepts_per_sec = Some(n);
+        self
+    }
+
+    /// How many connections the OS can queue up for us to accept. By default, the system
+    /// default is used.
+    pub fn listen_backlog(mut self, backlog: u32) -> Self {
+        self.listen_backlog = Some(backlog);
+        self
+    }
+
+    /// How many accepted connections can be waiting to be upgraded to websockets (or to have
+    /// their HTTP requests answered) at once. Connections beyond this are told to try again
+    /// later and closed, rather than being left hanging. By default, there's no limit.
+    pub fn max_pending_connections(mut self, n: usize) -> Self {
+        self.max_pending_connections = Some(n);
+        self
+    }
+
+    /// Enable TCP keepalive on connections from feeds and shards, probing them once they've
+    /// been idle for this long so that dead ones are noticed. By default, it's left off.
+    pub fn tcp_keepalive(mut self, idle: Duration) -> Self {
+        self.tcp_keepalive = Some(idle);
+        self
+    }
+
+    /// Serve "/admin/*" HTTP endpoints, which return JSON snapshots of the chains and shards
+    /// that we know about, to requests with an "Authorization: Bearer <token>" header.
+    pub fn admin_token(mut self, token: impl Into<String>) -> Self {
+        self.admin_token = Some(token.into());
+        self
+    }
+
+    /// Only accept shards that present this secret, in an "Authorization: Bearer <secret>"
+    /// header, when they connect. By default, any shard that can reach us is accepted.
+    pub fn shard_secret(mut self, secret: impl Into<String>) -> Self {
+        self.shard_secret = Some(secret.into());
+        self
+    }
+
+    /// Also serve feeds over plain TCP on this address, sending them the same messages as
+    /// newline delimited JSON. These feeds can't send commands, but can subscribe to a chain
+    /// by sending a query like "chain=<genesis_hash>" when they connect.
+    pub fn ndjson_feed_listen(mut self, addr: SocketAddr) -> Self {
+        self.ndjson_feed_listen = Some(addr);
+        self
+    }
+
+    /// Register the core's metrics with this registry, so that they're scraped along with
+    /// whatever else is in it, rather than serving them on our own "/metrics" endpoint.
+    /// The metrics are unregistered again when the core is shut down.
+    pub fn prometheus_registry(mut self, registry: prometheus::Registry) -> Self {
+        self.prometheus_registry = Some(registry);
+        self
+    }
+
+    /// Find the geographical locations of nodes using this provider, rather than the
+    /// built-in [`GeoIpLocationProvider`].
+    pub fn location_provider<P: LocationProvider>(self, provider: P) -> CoreBuilder<P> {
+        CoreBuilder {
+            listen: self.listen,
+            worker_threads: self.worker_threads,
+            worker_cpu_affinity: self.worker_cpu_affinity,
+            num_aggregators: self.num_aggregators,
+            aggregator_queue_len: self.aggregator_queue_len,
+            chain_lists: self.chain_lists,
+            chain_tags: self.chain_tags,
+            authority_only_chains: self.authority_only_chains,
+            reload_chain_lists_on_sighup: self.reload_chain_lists_on_sighup,
+            max_third_party_nodes: self.max_third_party_nodes,
+            max_chains: self.max_chains,
+            max_feeds_per_chain: self.max_feeds_per_chain,
+            max_subscriptions_per_feed: self.max_subscriptions_per_feed,
+            max_feeds: self.max_feeds,
+            authority_node_token: self.authority_node_token,
+            expose_node_details: self.expose_node_details,
+            trust_node_timestamps: self.trust_node_timestamps,
+            peer_count_handling: self.peer_count_handling,
+            node_update_min_interval: self.node_update_min_interval,
+            snapshot_cache_ttl: self.snapshot_cache_ttl,
+            event_history_size: self.event_history_size,
+            disable_geolocation: self.disable_geolocation,
+            geoip_max_concurrent: self.geoip_max_concurrent,
+            geoip_max_wait: self.geoip_max_wait,
+            stale_node_timeout: self.stale_node_timeout,
+            stale_node_check_interval: self.stale_node_check_interval,
+            feed_heartbeat_interval: self.feed_heartbeat_interval,
+            node_count_interval: self.node_count_interval,
+            empty_chain_ttl: self.empty_chain_ttl,
+            max_block_height_jump: self.max_block_height_jump,
+            stat_history_len: self.stat_history_len,
+            block_window: self.block_window,
+            latency_sample_every: self.latency_sample_every,
+            latency_window: self.latency_window,
+            feed_timeout: self.feed_timeout,
+            feed_send_timeout: self.feed_send_timeout,
+            feed_flush_interval: self.feed_flush_interval,
+            feed_flush_size: self.feed_flush_size,
+            feed_channel_capacity: self.feed_channel_capacity,
+            admin_feed: self.admin_feed,
+            max_feed_command_bytes: self.max_feed_command_bytes,
+            max_accepts_per_sec: self.max_accepts_per_sec,
+            listen_backlog: self.listen_backlog,
+            max_pending_connections: self.max_pending_connections,
+            tcp_keepalive: self.tcp_keepalive,
+            admin_token: self.admin_token,
+            shard_secret: self.shard_secret,
+            ndjson_feed_listen: self.ndjson_feed_listen,
+            prometheus_registry: self.prometheus_registry,
+            location_provider: Arc::new(provider),
+        }
+    }
+
+    /// Start the telemetry core on its own runtime, with the configured number of worker
+    /// threads. Once this resolves, the core is listening for connections.
+    pub async fn spawn(self) -> anyhow::Result<CoreHandle> {
+        let worker_threads = match self.worker_threads {
+            Some(0) => num_cpus::get(),
+            Some(n) => n,
+            // By default, use a max of 8 worker threads, as perf
+            // testing has found that to be a good sweet spot.
+            None => usize::min(num_cpus::get(), 8),
+        };
+
+        let num_aggregators = match self.num_aggregators {
+            Some(0) => num_cpus::get(),
+            Some(n) => n,
+            // For now, we just have 1 aggregator loop by default,
+            // but we may want to be smarter here eventually.
+            None => 1,
+        };
+
+        let chain_lists = self.chain_lists.load()?;
+
+        let mut runtime = tokio::runtime::Builder::new_multi_thread();
+        let runtime = runtime::configure_worker_threads(
+            &mut runtime,
+            "tel-core-worker",
+            worker_threads,
+            self.worker_cpu_affinity,
+        )
+        .enable_all()
+        .worker_threads(worker_threads)
+        .build()?;
+
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let shutdown = shutdown_rx.map(|_| ()).shared();
+        let started = runtime.spawn(async move {
+            let aggregator = AggregatorSet::spawn(
+                num_aggregators,
+                AggregatorOpts {
+                    max_queue_len: self.aggregator_queue_len,
+                    denylist: chain_lists.denylist.clone(),
+                    allowlist: chain_lists.allowlist.clone(),
+                    chain_tags: self.chain_tags,
+                    authority_only_chains: self.authority_only_chains,
+                    authority_node_token: self.authority_node_token.map(Into::into),
+                    max_third_party_nodes: self.max_third_party_nodes,
+                    max_chains: self.max_chains,
+                    max_feeds_per_chain: self.max_feeds_per_chain,
+                    max_subscriptions_per_feed: self.max_subscriptions_per_feed,
+                    expose_node_details: self.expose_node_details,
+                    trust_node_timestamps: self.trust_node_timestamps,
+                    peer_count_handling: self.peer_count_handling,
+                    node_update_min_interval: self.node_update_min_interval,
+                    snapshot_cache_ttl: self.snapshot_cache_ttl,
+                    event_history_size: self.event_history_size,
+                    disable_geolocation: self.disable_geolocation,
+                    geoip_max_concurrent: self.geoip_max_concurrent,
+                    geoip_max_wait: self.geoip_max_wait,
+                    stale_node_timeout: self.stale_node_timeout,
+                    stale_node_check_interval: self.stale_node_check_interval,
+                    feed_heartbeat_interval: self.feed_heartbeat_interval,
+                    node_count_interval: self.node_count_interval,
+                    empty_chain_ttl: self.empty_chain_ttl,
+                    max_block_height_jump: self.max_block_height_jump,
+                    stat_history_len: self.stat_history_len,
+                    block_window: self.block_window,
+                    latency_window: self.latency_window,
+                },
+                self.location_provider,
+            )
+            .await?;
+
+            let chain_lists = Arc::new(ChainListReloader::new(
+                self.chain_lists,
+                chain_lists,
+                aggregator.clone(),
+            ));
+            if self.reload_chain_lists_on_sighup {
+                reload_chain_lists_on_sighup(Arc::clone(&chain_lists))?;
+            }
+            if let Some(registry) = &self.prometheus_registry {
+                registry.register(Box::new(CoreMetrics::new(aggregator.clone())?))?;
+            }
+
+            let feed_limit = FeedLimit::new(self.max_feeds);
+            let (local_addr, server) = server::bind_server(
+                self.listen,
+                aggregator.clone(),
+                ServerOpts {
+                    feed_timeout: self.feed_timeout,
+                    feed_send_timeout: self.feed_send_timeout,
+                    feed_flush: FeedFlushOpts {
+                        interval: self.feed_flush_interval,
+                        size: self.feed_flush_size,
+                    },
+                    feed_channel_capacity: self.feed_channel_capacity,
+                    admin_feed: self.admin_feed,
+                    max_feed_command_bytes: self.max_feed_command_bytes,
+                    max_accepts_per_sec: self.max_accepts_per_sec,
+                    listen_backlog: self.listen_backlog,
+                    max_pending_connections: self.max_pending_connections,
+                    tcp_keepalive: self.tcp_keepalive,
+                    latency_sample_every: self.latency_sample_every,
+                    admin_token: self.admin_token.map(Into::into),
+                    shard_secret: self.shard_secret.map(Into::into),
+                    serve_metrics: self.prometheus_registry.is_none(),
+                    feed_limit: feed_limit.clone(),
+                },
+                shutdown.clone(),
+            )?;
+
+            let ndjson_feed_addr = match self.ndjson_feed_listen {
+                Some(addr) => {
+                    let (ndjson_feed_addr, ndjson_feed) = ndjson_feed::bind_ndjson_feed(
+                        addr,
+                        aggregator.clone(),
+                        feed_limit,
+                        NdjsonFeedOpts {
+                            feed_timeout: self.feed_timeout,
+                            feed_channel_capacity: self.feed_channel_capacity,
+                            tcp_keepalive: self.tcp_keepalive,
+                        },
+                        shutdown,
+                    )?;
+                    tokio::spawn(ndjson_feed);
+                    Some(ndjson_feed_addr)
+                }
+                None => None,
+            };
+
+            let server = tokio::spawn(server);
+            Ok::<_, anyhow::Error>((
+                local_addr,
+                ndjson_feed_addr,
+                aggregator,
+                chain_lists,
+                server,
+                self.prometheus_registry,
+            ))
+        });
+
+        // If we fail to start, the runtime must be shut down without blocking, since we
+        // may be being called from within another runtime.
+        let (local_addr, ndjson_feed_addr, aggregator, chain_lists, server, prometheus_registry) =
+            match started.await {
+                Ok(Ok(started)) => started,
+                Ok(Err(e)) => {
+                    runtime.shutdown_background();
+                    return Err(e);
+                }
+                Err(e) => {
+                    runtime.shutdown_background();
+                    return Err(e.into());
+                }
+            };
+
+        Ok(CoreHandle {
+            local_addr,
+            ndjson_feed_addr,
+            aggregator,
+            chain_lists,
+            prometheus_registry,
+            shutdown: Some(shutdown_tx),
+            server: Some(server),
+            runtime: Some(runtime),
+        })
+    }
+}
+
+/// Reload the chain lists each time the process receives SIGHUP.
+#[cfg(unix)]
+fn reload_chain_lists_on_sighup(chain_lists: Arc<ChainListReloader>) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            if let Err(e) = chain_lists.reload().await {
+                log::error!("Cannot reload chain lists (keeping the current ones): {e}");
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn reload_chain_lists_on_sighup(_chain_lists: Arc<ChainListReloader>) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// A handle to a running telemetry core. Dropping this stops the core without waiting for it.
+pub struct CoreHandle {
+    local_addr: SocketAddr,
+    ndjson_feed_addr: Option<SocketAddr>,
+    aggregator: AggregatorSet,
+    chain_lists: Arc<ChainListReloader>,
+    prometheus_registry: Option<prometheus::Registry>,
+    shutdown: Option<tokio::sync::oneshot::Sender<()>>,
+    server: Option<tokio::task::JoinHandle<anyhow::Result<()>>>,
+    runtime: Option<tokio::runtime::Runtime>,
+}
+
+impl CoreHandle {
+    /// The address that the core is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The address that the core is listening for NDJSON feeds on, if it's been asked to.
+    pub fn ndjson_feed_addr(&self) -> Option<SocketAddr> {
+        self.ndjson_feed_addr
+    }
+
+    /// How many feeds are currently connected.
+    pub async fn num_connected_feeds(&self) -> anyhow::Result<usize> {
+        // Feeds are split across aggregators:
+        let metrics = self.aggregator.gather_metrics().await?;
+        Ok(metrics.iter().map(|m| m.connected_feeds).sum())
+    }
+
+    /// How many nodes are currently connected.
+    pub async fn num_connected_nodes(&self) -> anyhow::Result<usize> {
+        // Each aggregator knows about every node:
+        let metrics = self.aggregator.gather_metrics().await?;
+        Ok(metrics.iter().map(|m| m.connected_nodes).max().unwrap_or(0))
+    }
+
+    /// Re-read the deny and allow list files, and start checking nodes against the updated
+    /// lists. Connected nodes from chains that are no longer allowed are removed. If the
+    /// files can't be read, an error is returned and the lists in use are left alone.
+    pub async fn reload_chain_lists(&self) -> anyhow::Result<()> {
+        self.chain_lists.reload().await
+    }
+
+    /// Wait for the core to stop. This only happens if the server fails.
+    pub async fn wait(mut self) -> anyhow::Result<()> {
+        self.stop().await
+    }
+
+    /// Stop accepting new connections, and then stop the core.
+    pub async fn shutdown(mut self) -> anyhow::Result<()> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        self.stop().await
+    }
+
+    /// Wait for the server to finish and then shut everything else down.
+    async fn stop(&mut self) -> anyhow::Result<()> {
+        let result = match self.server.take() {
+            Some(server) => server.await.map_err(anyhow::Error::from).and_then(|r| r),
+            None => Ok(()),
+        };
+        self.unregister_metrics();
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+        result
+    }
+
+    /// Remove our metrics from the registry that they were registered with, if any, so
+    /// that it doesn't keep on reporting them (or stop another core registering its own).
+    fn unregister_metrics(&mut self) {
+        let registry = match self.prometheus_registry.take() {
+            Some(registry) => registry,
+            None => return,
+        };
+        // Collectors are unregistered by matching their descriptions, so a new one will do:
+        let unregistered = CoreMetrics::new(self.aggregator.clone())
+            .map(|metrics| registry.unregister(Box::new(metrics)));
+        if let Err(e) | Ok(Err(e)) = unregistered {
+            log::warn!("Cannot unregister metrics: {e}");
+        }
+    }
+}
+
+impl Drop for CoreHandle {
+    fn drop(&mut self) {
+        // Dropping a runtime blocks, which isn't allowed from within another runtime, so
+        // we tell it to shut down in the background instead.
+        self.unregister_metrics();
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
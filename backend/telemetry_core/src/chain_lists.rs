@@ -0,0 +1,213 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::path::{Path, PathBuf};
+
+use crate::aggregator::AggregatorSet;
+use crate::state::AllowedChain;
+
+/// Where the chain deny and allow lists come from. Entries given directly are combined with
+/// those read from the files, which can be re-read while the core is running.
+#[derive(Clone, Debug, Default)]
+pub struct ChainListSource {
+    pub denylist: Vec<String>,
+    pub denylist_file: Option<PathBuf>,
+    pub allowlist: Vec<AllowedChain>,
+    pub allowlist_file: Option<PathBuf>,
+}
+
+impl ChainListSource {
+    /// Read any files and combine their entries with those given directly.
+    pub fn load(&self) -> anyhow::Result<ChainLists> {
+        let mut denylist = self.denylist.clone();
+        if let Some(path) = &self.denylist_file {
+            denylist.extend(read_denylist_file(path)?);
+        }
+        let mut allowlist = self.allowlist.clone();
+        if let Some(path) = &self.allowlist_file {
+            allowlist.extend(read_allowlist_file(path)?);
+        }
+        Ok(ChainLists {
+            denylist,
+            allowlist,
+        })
+    }
+}
+
+/// The chain deny and allow lists in use.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChainLists {
+    pub denylist: Vec<String>,
+    pub allowlist: Vec<AllowedChain>,
+}
+
+impl ChainLists {
+    /// Describe each way in which the lists given differ from these ones.
+    pub fn changes(&self, new: &ChainLists) -> Vec<String> {
+        let mut changes = Vec::new();
+        for chain in new.denylist.iter().filter(|c| !self.denylist.contains(c)) {
+            changes.push(format!("denied '{chain}'"));
+        }
+        for chain in self.denylist.iter().filter(|c| !new.denylist.contains(c)) {
+            changes.push(format!("no longer denied '{chain}'"));
+        }
+        for chain in new.allowlist.iter().filter(|c| !self.allowlist.contains(c)) {
+            changes.push(format!(
+                "allowed {:?} as '{}'",
+                chain.genesis_hash, chain.label
+            ));
+        }
+        for chain in self.allowlist.iter().filter(|c| !new.allowlist.contains(c)) {
+            changes.push(format!("no longer allowed {:?}", chain.genesis_hash));
+        }
+        changes
+    }
+}
+
+/// Re-reads the chain lists on demand and hands any changes to the aggregators.
+pub struct ChainListReloader {
+    source: ChainListSource,
+    current: tokio::sync::Mutex<ChainLists>,
+    aggregator: AggregatorSet,
+}
+
+impl ChainListReloader {
+    pub fn new(source: ChainListSource, current: ChainLists, aggregator: AggregatorSet) -> Self {
+        ChainListReloader {
+            source,
+            current: tokio::sync::Mutex::new(current),
+            aggregator,
+        }
+    }
+
+    /// Re-read the chain lists. If they can't be read, the lists in use are left alone.
+    pub async fn reload(&self) -> anyhow::Result<()> {
+        // Hold the lock throughout so that concurrent reloads are applied in order:
+        let mut current = self.current.lock().await;
+        let lists = self.source.load()?;
+
+        let changes = current.changes(&lists);
+        if changes.is_empty() {
+            log::info!("Reloaded chain lists; nothing has changed");
+            return Ok(());
+        }
+        log::info!("Reloaded chain lists: {}", changes.join(", "));
+
+        self.aggregator
+            .update_chain_lists(lists.denylist.clone(), lists.allowlist.clone())
+            .await?;
+        *current = lists;
+        Ok(())
+    }
+}
+
+/// Read the names of chains to deny from a file containing one name per line.
+fn read_denylist_file(path: &Path) -> anyhow::Result<Vec<String>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Cannot read denylist file {path:?}: {e}"))?;
+
+    Ok(entries(&contents).map(|line| line.to_owned()).collect())
+}
+
+/// Read the allowed chains from a file containing one `<genesis_hash>=<label>` entry per line.
+fn read_allowlist_file(path: &Path) -> anyhow::Result<Vec<AllowedChain>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Cannot read allow chain file {path:?}: {e}"))?;
+
+    entries(&contents)
+        .map(|line| {
+            line.parse()
+                .map_err(|e| anyhow::anyhow!("Invalid line '{line}' in {path:?}: {e}"))
+        })
+        .collect()
+}
+
+/// The trimmed lines of a list file, ignoring empty lines and lines starting with '#'.
+fn entries(contents: &str) -> impl Iterator<Item = &str> {
+    contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::node_types::BlockHash;
+
+    fn allowed(n: u64, label: &str) -> AllowedChain {
+        AllowedChain {
+            genesis_hash: BlockHash::from_low_u64_be(n),
+            label: label.into(),
+        }
+    }
+
+    #[test]
+    fn changes_between_lists_are_described() {
+        let old = ChainLists {
+            denylist: vec!["A".into(), "B".into()],
+            allowlist: vec![allowed(1, "One"), allowed(2, "Two")],
+        };
+        let new = ChainLists {
+            denylist: vec!["B".into(), "C".into()],
+            allowlist: vec![allowed(1, "One"), allowed(2, "Second")],
+        };
+
+        assert!(old.changes(&old).is_empty());
+        assert_eq!(
+            old.changes(&new),
+            vec![
+                "denied 'C'".to_owned(),
+                "no longer denied 'A'".to_owned(),
+                format!("allowed {:?} as 'Second'", BlockHash::from_low_u64_be(2)),
+                format!("no longer allowed {:?}", BlockHash::from_low_u64_be(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn list_files_are_combined_with_given_entries() {
+        let dir = std::env::temp_dir().join(format!("chain_lists_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let denylist_file = dir.join("denylist");
+        let allowlist_file = dir.join("allowlist");
+        std::fs::write(&denylist_file, "# Comment\nFile Chain\n\n  Other Chain  \n").unwrap();
+        std::fs::write(
+            &allowlist_file,
+            format!("{:?}=Two\n", BlockHash::from_low_u64_be(2)),
+        )
+        .unwrap();
+
+        let source = ChainListSource {
+            denylist: vec!["Given Chain".into()],
+            denylist_file: Some(denylist_file),
+            allowlist: vec![allowed(1, "One")],
+            allowlist_file: Some(allowlist_file.clone()),
+        };
+        let lists = source.load().unwrap();
+        assert_eq!(
+            lists.denylist,
+            vec!["Given Chain", "File Chain", "Other Chain"]
+        );
+        assert_eq!(lists.allowlist, vec![allowed(1, "One"), allowed(2, "Two")]);
+
+        // Invalid entries are an error rather than being skipped:
+        std::fs::write(&allowlist_file, "not a chain\n").unwrap();
+        assert!(source.load().is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
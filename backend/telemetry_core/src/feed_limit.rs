@@ -0,0 +1,83 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// A limit on how many feeds can be connected at once, however they connect to us. Cloning
+/// this gives another handle to the same limit.
+#[derive(Clone, Debug, Default)]
+pub struct FeedLimit(Option<Arc<Semaphore>>);
+
+/// A feed connection that's being counted towards a [`FeedLimit`]. It's counted until this
+/// is dropped, however the connection ends.
+#[derive(Debug)]
+pub struct FeedPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl FeedLimit {
+    /// Allow at most `max` feeds to be connected at once. There's no limit if `max` is `None`.
+    pub fn new(max: Option<usize>) -> FeedLimit {
+        FeedLimit(max.map(|max| Arc::new(Semaphore::new(max.min(Semaphore::MAX_PERMITS)))))
+    }
+
+    /// Try to count a new feed connection. If as many feeds as we allow are already
+    /// connected, this returns `None` and the connection should be refused.
+    pub fn try_acquire(&self) -> Option<FeedPermit> {
+        let permit = match &self.0 {
+            Some(semaphore) => Some(Arc::clone(semaphore).try_acquire_owned().ok()?),
+            None => None,
+        };
+        Some(FeedPermit { _permit: permit })
+    }
+
+    /// Is there room for another feed to connect right now?
+    pub fn has_room(&self) -> bool {
+        match &self.0 {
+            Some(semaphore) => semaphore.available_permits() > 0,
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn feeds_are_counted_until_their_permits_are_dropped() {
+        let limit = FeedLimit::new(Some(2));
+        let a = limit.try_acquire().unwrap();
+        let b = limit.clone().try_acquire().unwrap();
+        assert!(!limit.has_room());
+        assert!(limit.try_acquire().is_none());
+
+        drop(a);
+        let c = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+        drop((b, c));
+        assert!(limit.has_room());
+        assert!(limit.try_acquire().is_some());
+    }
+
+    #[test]
+    fn no_limit_allows_any_number_of_feeds() {
+        let limit = FeedLimit::new(None);
+        let permits: Vec<_> = (0..1000).filter_map(|_| limit.try_acquire()).collect();
+        assert_eq!(permits.len(), 1000);
+    }
+}
@@ -19,11 +19,13 @@
 
 use serde::Serialize;
 
-use crate::state::Node;
+use crate::state::{ChainTags, Node};
+use common::internal_messages::RemovalReason;
 use common::node_types::{
-    BlockDetails, BlockHash, BlockNumber, NodeHardware, NodeIO, NodeStats, Timestamp,
+    BlockDetails, BlockHash, BlockNumber, NodeHardware, NodeIO, NodeRole, NodeStats, Timestamp,
 };
 use serde_json::to_writer;
+use std::collections::HashMap;
 
 type FeedNodeId = usize;
 
@@ -47,14 +49,19 @@ where
 pub struct FeedMessageSerializer {
     /// Current buffer.
     buffer: Vec<u8>,
+    /// Which messages are serialized, and how.
+    format: FeedFormat,
 }
 
 const BUFCAP: usize = 128;
 
 impl FeedMessageSerializer {
-    pub fn new() -> Self {
+    /// Serialize messages for feeds that asked for the format given, leaving out
+    /// any messages that they aren't sent.
+    pub fn with_format(format: FeedFormat) -> Self {
         Self {
             buffer: Vec::with_capacity(BUFCAP),
+            format,
         }
     }
 
@@ -62,6 +69,17 @@ impl FeedMessageSerializer {
     where
         Message: FeedMessageWrite,
     {
+        self.push_ref(&msg)
+    }
+
+    fn push_ref<Message>(&mut self, msg: &Message)
+    where
+        Message: FeedMessageWrite,
+    {
+        if !self.format.has_action(Message::ACTION) {
+            return;
+        }
+
         let glue = match self.buffer.len() {
             0 => b'[',
             _ => b',',
@@ -77,7 +95,12 @@ impl FeedMessageSerializer {
     where
         S: Serialize,
     {
-        let _ = to_writer(&mut self.buffer, value);
+        if self.format.bignums_as_strings {
+            let mut ser = serde_json::Serializer::with_formatter(&mut self.buffer, QuotedIntegers);
+            let _ = value.serialize(&mut ser);
+        } else {
+            let _ = to_writer(&mut self.buffer, value);
+        }
     }
 
     /// Return the bytes that we've serialized so far, consuming the serializer.
@@ -91,6 +114,470 @@ impl FeedMessageSerializer {
     }
 }
 
+/// Writes JSON as [`serde_json`] usually does, except that 64-bit integers (block heights,
+/// timestamps, node IDs and the like) are written as strings, for feeds subscribed with
+/// `bignum=string`. JavaScript numbers can't hold all such integers exactly, but these
+/// feeds can parse the strings without losing precision.
+struct QuotedIntegers;
+
+impl serde_json::ser::Formatter for QuotedIntegers {
+    fn write_i64<W>(&mut self, writer: &mut W, value: i64) -> std::io::Result<()>
+    where
+        W: ?Sized + std::io::Write,
+    {
+        writer.write_all(b"\"")?;
+        serde_json::ser::CompactFormatter.write_i64(writer, value)?;
+        writer.write_all(b"\"")
+    }
+
+    fn write_u64<W>(&mut self, writer: &mut W, value: u64) -> std::io::Result<()>
+    where
+        W: ?Sized + std::io::Write,
+    {
+        writer.write_all(b"\"")?;
+        serde_json::ser::CompactFormatter.write_u64(writer, value)?;
+        writer.write_all(b"\"")
+    }
+}
+
+/// Serializes the same messages in each of several formats, so that they can be sent to
+/// feeds that asked for different formats. Each message is serialized once per format,
+/// however many feeds there are in that format.
+#[derive(Default)]
+pub struct MultiFormatSerializer {
+    serializers: Vec<FeedMessageSerializer>,
+}
+
+impl MultiFormatSerializer {
+    pub fn new(formats: impl IntoIterator<Item = FeedFormat>) -> Self {
+        let mut serializers: Vec<FeedMessageSerializer> = Vec::new();
+        for format in formats {
+            if serializers.iter().all(|s| s.format != format) {
+                serializers.push(FeedMessageSerializer::with_format(format));
+            }
+        }
+        Self { serializers }
+    }
+
+    pub fn push<Message>(&mut self, msg: Message)
+    where
+        Message: FeedMessageWrite,
+    {
+        self.push_ref(&msg)
+    }
+
+    fn push_ref<Message>(&mut self, msg: &Message)
+    where
+        Message: FeedMessageWrite,
+    {
+        for serializer in &mut self.serializers {
+            serializer.push_ref(msg);
+        }
+    }
+
+    /// Return the bytes that we've serialized so far in each format, consuming the serializer.
+    pub fn into_finalized(self) -> FormattedBytes {
+        FormattedBytes(
+            self.serializers
+                .into_iter()
+                .filter_map(|s| {
+                    let format = s.format;
+                    s.into_finalized().map(|bytes| (format, bytes))
+                })
+                .collect(),
+        )
+    }
+}
+
+/// The bytes obtained from [`MultiFormatSerializer::into_finalized()`].
+#[derive(Default)]
+pub struct FormattedBytes(Vec<(FeedFormat, bytes::Bytes)>);
+
+impl FormattedBytes {
+    /// The messages serialized in the format given, if there are any.
+    pub fn get(&self, format: FeedFormat) -> Option<&bytes::Bytes> {
+        self.0
+            .iter()
+            .find(|(f, _)| *f == format)
+            .map(|(_, bytes)| bytes)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Combines several finalized feed messages (each of which is a JSON array of actions and
+/// their payloads) into a single array, so that they can be sent to a feed in one frame.
+///
+/// The same messages are generally batched up for many feeds, so a batch holding just one
+/// message hands back the bytes it was given rather than a copy of them.
+#[derive(Default)]
+pub struct FeedMessageBatch {
+    /// The first message, until another is added to the batch.
+    first: Option<bytes::Bytes>,
+    /// Current buffer, once there is more than one message.
+    buffer: Vec<u8>,
+}
+
+impl FeedMessageBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the bytes obtained from [`FeedMessageSerializer::into_finalized()`] to the batch.
+    pub fn push(&mut self, bytes: bytes::Bytes) {
+        if array_contents(&bytes).is_none() {
+            return;
+        }
+        if self.first.is_none() && self.buffer.is_empty() {
+            self.first = Some(bytes);
+            return;
+        }
+        if let Some(first) = self.first.take() {
+            self.extend_buffer(&first);
+        }
+        self.extend_buffer(&bytes);
+    }
+
+    /// Strip the brackets from the array and glue what's inside onto our own array.
+    fn extend_buffer(&mut self, bytes: &[u8]) {
+        let contents = match array_contents(bytes) {
+            Some(contents) => contents,
+            None => return,
+        };
+        let glue = match self.buffer.len() {
+            0 => b'[',
+            _ => b',',
+        };
+
+        self.buffer.push(glue);
+        self.buffer.extend_from_slice(contents);
+    }
+
+    /// The number of bytes in the batch so far.
+    pub fn num_bytes(&self) -> usize {
+        match &self.first {
+            Some(first) => first.len(),
+            None => self.buffer.len(),
+        }
+    }
+
+    /// Return the bytes that we've batched up, consuming the batch.
+    pub fn into_finalized(mut self) -> Option<bytes::Bytes> {
+        if let Some(first) = self.first {
+            return Some(first);
+        }
+        if self.buffer.is_empty() {
+            return None;
+        }
+
+        self.buffer.push(b']');
+        Some(self.buffer.into())
+    }
+}
+
+/// What's inside a non-empty JSON array of feed messages.
+fn array_contents(bytes: &[u8]) -> Option<&[u8]> {
+    match bytes {
+        [b'[', contents @ .., b']'] if !contents.is_empty() => Some(contents),
+        _ => None,
+    }
+}
+
+/// The websocket subprotocol that a feed can ask for to receive version 1 of the feed protocol.
+pub const FEED_V1_SUBPROTOCOL: &str = "telemetry-feed-v1";
+/// The websocket subprotocol that a feed can ask for to receive version 2 of the feed protocol.
+pub const FEED_V2_SUBPROTOCOL: &str = "telemetry-feed-v2";
+
+/// The subprotocols that feeds can ask for when connecting, in order of preference.
+pub const FEED_SUBPROTOCOLS: &[&str] = &[FEED_V2_SUBPROTOCOL, FEED_V1_SUBPROTOCOL];
+
+/// Which version of the feed protocol does a feed understand? This is agreed on when the
+/// feed connects, by way of the websocket subprotocol that it asks for. Feeds that don't ask
+/// for a subprotocol are sent the latest version.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum FeedProtocol {
+    /// The original set of messages (actions 0 to [`FEED_V1_LAST_ACTION`]), along with the
+    /// errors that feeds are sent when their commands fail.
+    V1,
+    /// Adds every other message, starting with [`NodeShard`].
+    #[default]
+    V2,
+}
+
+/// The action of the last message that was a part of version 1 of the feed protocol.
+pub const FEED_V1_LAST_ACTION: u8 = ChainStatsUpdate::ACTION;
+
+impl FeedProtocol {
+    /// Which version does the subprotocol we agreed on with a feed imply?
+    pub fn from_subprotocol(subprotocol: Option<&str>) -> FeedProtocol {
+        match subprotocol {
+            Some(FEED_V1_SUBPROTOCOL) => FeedProtocol::V1,
+            _ => FeedProtocol::V2,
+        }
+    }
+
+    /// Is a message with this action a part of this version of the protocol?
+    fn has_action(&self, action: u8) -> bool {
+        match self {
+            // Feeds need to be told when their commands fail, whichever version they speak:
+            FeedProtocol::V1 => {
+                action <= FEED_V1_LAST_ACTION
+                    || action == CommandError::ACTION
+                    || action == SubscribeError::ACTION
+            }
+            FeedProtocol::V2 => true,
+        }
+    }
+}
+
+/// The categories of chain messages that a feed can ask to be sent, as in
+/// `subscribe:CHAIN_HASH:events=block,finalized`. Messages that don't fall into any of
+/// these categories (such as [`SubscribedTo`] or [`Pong`]) are always sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FeedEvents(u8);
+
+impl FeedEvents {
+    /// Nodes being added, removed, located or going stale.
+    pub const NODE: FeedEvents = FeedEvents(1);
+    /// Blocks being imported, how quickly they propagate, and which node is furthest ahead.
+    pub const BLOCK: FeedEvents = FeedEvents(1 << 1);
+    /// Blocks being finalized.
+    pub const FINALIZED: FeedEvents = FeedEvents(1 << 2);
+    /// Periodic node and chain statistics.
+    pub const STATS: FeedEvents = FeedEvents(1 << 3);
+    /// GRANDPA votes, finalized blocks and authority sets, as nodes report them.
+    pub const AFG: FeedEvents = FeedEvents(1 << 4);
+    /// Every category; this is what feeds are sent unless they ask otherwise.
+    pub const ALL: FeedEvents = FeedEvents(0b11111);
+
+    /// Which category does a message with the given action fall into, if any?
+    fn category(action: u8) -> Option<FeedEvents> {
+        let category = match action {
+            AddedNode::ACTION
+            | RemovedNode::ACTION
+            | LocatedNode::ACTION
+            | StaleNode::ACTION
+            | NodeClockSkew::ACTION
+            | RemovedNodeReason::ACTION => FeedEvents::NODE,
+            BestBlock::ACTION
+            | ImportedBlock::ACTION
+            | BlockPropagation::ACTION
+            | ChainLeader::ACTION
+            | Reorg::ACTION => FeedEvents::BLOCK,
+            BestFinalized::ACTION | FinalizedBlock::ACTION | ChainFinalized::ACTION => {
+                FeedEvents::FINALIZED
+            }
+            NodeStatsUpdate::ACTION
+            | Hardware::ACTION
+            | NodeIOUpdate::ACTION
+            | ChainStatsUpdate::ACTION
+            | NodeCustomMetrics::ACTION
+            | NodePeerCount::ACTION
+            | NodeLastSeen::ACTION => FeedEvents::STATS,
+            AfgFinalized::ACTION
+            | AfgReceivedPrevote::ACTION
+            | AfgReceivedPrecommit::ACTION
+            | AfgAuthoritySet::ACTION
+            | AuthoritySetChanged::ACTION => FeedEvents::AFG,
+            _ => return None,
+        };
+        Some(category)
+    }
+
+    fn contains(self, other: FeedEvents) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Should a message with the given action be sent to a feed that asked for these events?
+    fn has_action(self, action: u8) -> bool {
+        match FeedEvents::category(action) {
+            Some(category) => self.contains(category),
+            None => true,
+        }
+    }
+}
+
+impl Default for FeedEvents {
+    fn default() -> Self {
+        FeedEvents::ALL
+    }
+}
+
+impl std::str::FromStr for FeedEvents {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut events = FeedEvents(0);
+        for category in s.split(',') {
+            let category = match category.trim() {
+                "node" => FeedEvents::NODE,
+                "block" => FeedEvents::BLOCK,
+                "finalized" => FeedEvents::FINALIZED,
+                "stats" => FeedEvents::STATS,
+                "afg" => FeedEvents::AFG,
+                other => anyhow::bail!(
+                    "Event category {other} not recognised; expected 'node', 'block', 'finalized', 'stats' or 'afg'"
+                ),
+            };
+            events.0 |= category.0;
+        }
+        Ok(events)
+    }
+}
+
+/// Is a message with the given action a finality message? These are [`FinalizedBlock`] and the
+/// GRANDPA messages from nodes in [`FeedEvents::AFG`], which feeds are only sent if they ask for
+/// them with `send-finality:CHAIN_HASH`, since they make up a lot of the traffic on busy chains.
+/// The rare [`AuthoritySetChanged`] is about the chain rather than any one node, and so isn't one.
+fn is_finality(action: u8) -> bool {
+    action != AuthoritySetChanged::ACTION
+        && (action == FinalizedBlock::ACTION
+            || FeedEvents::category(action) == Some(FeedEvents::AFG))
+}
+
+/// What a feed asked to be sent, which determines which messages are serialized for it and
+/// how. Feeds with the same format are sent the same bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FeedFormat {
+    /// Messages that aren't a part of this version of the feed protocol are left out.
+    pub protocol: FeedProtocol,
+    /// Messages in any category other than these are left out.
+    pub events: FeedEvents,
+    /// Finality messages are sent, rather than left out as per [`is_finality`].
+    pub finality: bool,
+    /// The network IDs of nodes, and all but the latest values of their IO and hardware
+    /// histories, are left out of node messages. These are sent as `null` and as histories
+    /// of one value, so that everything else is where feeds expect to find it.
+    pub lean: bool,
+    /// 64-bit integers are sent as strings, as per [`QuotedIntegers`].
+    pub bignums_as_strings: bool,
+}
+
+impl FeedFormat {
+    /// Every message in the latest version of the feed protocol, as it was serialized.
+    pub const FULL: FeedFormat = FeedFormat {
+        protocol: FeedProtocol::V2,
+        events: FeedEvents::ALL,
+        finality: true,
+        lean: false,
+        bignums_as_strings: false,
+    };
+
+    /// Every message in the version of the feed protocol given.
+    pub fn full(protocol: FeedProtocol) -> FeedFormat {
+        FeedFormat {
+            protocol,
+            ..FeedFormat::FULL
+        }
+    }
+
+    /// Should a message with the given action be sent to feeds in this format?
+    pub fn has_action(&self, action: u8) -> bool {
+        self.protocol.has_action(action)
+            && self.events.has_action(action)
+            && (self.finality || !is_finality(action))
+    }
+
+    /// Every format that messages can be serialized in, whichever messages are sent.
+    pub fn encodings() -> impl Iterator<Item = FeedFormat> {
+        [(false, false), (false, true), (true, false), (true, true)]
+            .into_iter()
+            .map(|(lean, bignums_as_strings)| FeedFormat {
+                lean,
+                bignums_as_strings,
+                ..FeedFormat::FULL
+            })
+    }
+
+    /// The format (from [`FeedFormat::encodings()`]) that messages are serialized in for
+    /// feeds in this format.
+    pub fn encoding(&self) -> FeedFormat {
+        FeedFormat {
+            lean: self.lean,
+            bignums_as_strings: self.bignums_as_strings,
+            ..FeedFormat::FULL
+        }
+    }
+}
+
+/// Append the messages in the bytes obtained from [`FeedMessageSerializer::into_finalized()`]
+/// to `out` as newline delimited JSON, with each message on its own line as an
+/// `[action, payload]` array.
+pub fn write_ndjson(bytes: &[u8], out: &mut Vec<u8>) {
+    let values: Vec<&serde_json::value::RawValue> = match serde_json::from_slice(bytes) {
+        Ok(values) => values,
+        Err(_) => return,
+    };
+    for pair in values.chunks(2) {
+        out.push(b'[');
+        for (idx, value) in pair.iter().enumerate() {
+            if idx > 0 {
+                out.push(b',');
+            }
+            out.extend_from_slice(value.get().as_bytes());
+        }
+        out.extend_from_slice(b"]\n");
+    }
+}
+
+/// Serializes messages for the feeds subscribed to a single chain, in each of the formats
+/// that they asked for. Feeds can see every node on the chain, or only the authority nodes,
+/// and so messages about a node are only serialized for the latter if the node is an authority.
+/// Nothing is serialized for authority feeds at all unless there are formats to serialize for.
+pub struct ChainFeedSerializer {
+    all: MultiFormatSerializer,
+    authorities: MultiFormatSerializer,
+}
+
+impl ChainFeedSerializer {
+    pub fn new(
+        formats: impl IntoIterator<Item = FeedFormat>,
+        authority_formats: impl IntoIterator<Item = FeedFormat>,
+    ) -> Self {
+        Self {
+            all: MultiFormatSerializer::new(formats),
+            authorities: MultiFormatSerializer::new(authority_formats),
+        }
+    }
+
+    /// Push a message that every feed subscribed to the chain should see.
+    pub fn push<Message>(&mut self, msg: Message)
+    where
+        Message: FeedMessageWrite,
+    {
+        self.authorities.push_ref(&msg);
+        self.all.push(msg);
+    }
+
+    /// Push a message about a node, which feeds subscribed to authority nodes
+    /// will only see if `is_authority` is true.
+    pub fn push_for_node<Message>(&mut self, is_authority: bool, msg: Message)
+    where
+        Message: FeedMessageWrite,
+    {
+        if is_authority {
+            self.push(msg);
+        } else {
+            self.all.push(msg);
+        }
+    }
+
+    /// Push a message that only feeds subscribed to authority nodes should see.
+    pub fn push_for_authorities<Message>(&mut self, msg: Message)
+    where
+        Message: FeedMessageWrite,
+    {
+        self.authorities.push(msg);
+    }
+
+    /// Return the bytes serialized so far for feeds subscribed to every node, and
+    /// for feeds subscribed to authority nodes, consuming the serializer.
+    pub fn into_finalized(self) -> (FormattedBytes, FormattedBytes) {
+        (self.all.into_finalized(), self.authorities.into_finalized())
+    }
+}
+
 macro_rules! actions {
     ($($action:literal: $t:ty,)*) => {
         $(
@@ -118,11 +605,35 @@ actions! {
     13: SubscribedTo,
     14: UnsubscribedFrom,
     15: Pong<'_>,
-    // Note; some now-unused messages were removed between IDs 15 and 20.
-    // We maintain existing IDs for backward compatibility.
+    16: AfgFinalized<'_>,
+    17: AfgReceivedPrevote<'_>,
+    18: AfgReceivedPrecommit<'_>,
+    19: AfgAuthoritySet<'_>,
     20: StaleNode,
     21: NodeIOUpdate<'_>,
     22: ChainStatsUpdate<'_>,
+    23: NodeShard<'_>,
+    24: NodeCustomMetrics<'_>,
+    25: BlockPropagation<'_>,
+    26: NodePeerCount<'_>,
+    27: Heartbeat,
+    28: ChainsList<'_>,
+    29: ChainFinalized<'_>,
+    30: Shards<'_>,
+    31: BuildInfo,
+    32: CommandError<'_>,
+    33: ChainLeader,
+    34: NodeDetail<'_>,
+    35: SubscribeError,
+    36: NodeClockSkew,
+    37: TopChains<'_>,
+    38: NodeLastSeen,
+    39: RemovedNodeReason,
+    40: ChainLabelChanged<'_>,
+    41: Reorg,
+    42: ForChain,
+    43: NodeCount,
+    44: AuthoritySetChanged<'_>,
 }
 
 #[derive(Serialize)]
@@ -139,6 +650,45 @@ pub struct AddedNode<'a>(pub FeedNodeId, pub &'a Node, pub bool);
 #[derive(Serialize)]
 pub struct RemovedNode(pub FeedNodeId);
 
+/// Why a node was removed. This is sent just before the [`RemovedNode`] message about it,
+/// rather than as part of that message, so that feeds which don't know about it can ignore it.
+#[derive(Serialize)]
+pub struct RemovedNodeReason(pub FeedNodeId, pub NodeRemovalReason);
+
+/// The reasons that a node can be removed for, sent to feeds as these strings.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NodeRemovalReason {
+    /// The node's connection was closed, or lost.
+    Disconnected,
+    /// The node stopped sending messages.
+    TimedOut,
+    /// The node's connection was closed because it sent too much data.
+    RateLimited,
+    /// The node's connection was closed so that it reconnects through another shard.
+    Draining,
+    /// The shard that the node was connected through disconnected from us.
+    ShardDisconnected,
+    /// The node's chain is no longer allowed.
+    ChainNotAllowed,
+    /// The node connected again, and its new connection replaces this one.
+    Superseded,
+    /// The node is no longer an authority. Only feeds subscribed to authorities are told
+    /// that the node has been removed for this reason.
+    NoLongerAuthority,
+}
+
+impl From<RemovalReason> for NodeRemovalReason {
+    fn from(reason: RemovalReason) -> Self {
+        match reason {
+            RemovalReason::Disconnected => NodeRemovalReason::Disconnected,
+            RemovalReason::TimedOut => NodeRemovalReason::TimedOut,
+            RemovalReason::RateLimited => NodeRemovalReason::RateLimited,
+            RemovalReason::Draining => NodeRemovalReason::Draining,
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct LocatedNode<'a>(pub FeedNodeId, pub f32, pub f32, pub &'a str);
 
@@ -151,24 +701,129 @@ pub struct FinalizedBlock(pub FeedNodeId, pub BlockNumber, pub BlockHash);
 #[derive(Serialize)]
 pub struct NodeStatsUpdate<'a>(pub FeedNodeId, pub &'a NodeStats);
 
-#[derive(Serialize)]
 pub struct NodeIOUpdate<'a>(pub FeedNodeId, pub &'a NodeIO);
 
-#[derive(Serialize)]
+impl FeedMessageWrite for NodeIOUpdate<'_> {
+    fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
+        let NodeIOUpdate(nid, io) = self;
+        let lean = ser.format.lean;
+        ser.write(&(nid, MaybeLean(*io, lean)));
+    }
+}
+
 pub struct Hardware<'a>(pub FeedNodeId, pub &'a NodeHardware);
 
+impl FeedMessageWrite for Hardware<'_> {
+    fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
+        let Hardware(nid, hardware) = self;
+        let lean = ser.format.lean;
+        ser.write(&(nid, MaybeLean(*hardware, lean)));
+    }
+}
+
+/// Node IO or hardware histories, trimmed to their latest values if `lean` is true, since
+/// the rest are only needed to draw charts.
+struct MaybeLean<'a, T>(&'a T, bool);
+
+/// The latest value of a history, if there is one.
+fn latest<T>(history: &[T]) -> &[T] {
+    &history[history.len().saturating_sub(1)..]
+}
+
+impl Serialize for MaybeLean<'_, NodeIO> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let MaybeLean(io, lean) = self;
+        if !lean {
+            return io.serialize(serializer);
+        }
+        (latest(io.used_state_cache_size.slice()),).serialize(serializer)
+    }
+}
+
+impl Serialize for MaybeLean<'_, NodeHardware> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let MaybeLean(hardware, lean) = self;
+        if !lean {
+            return hardware.serialize(serializer);
+        }
+        (
+            latest(hardware.upload.slice()),
+            latest(hardware.download.slice()),
+            latest(hardware.chart_stamps.slice()),
+            latest(hardware.cpu.slice()),
+            latest(hardware.memory.slice()),
+            latest(hardware.disk_usage.slice()),
+            latest(hardware.disk_read.slice()),
+            latest(hardware.disk_write.slice()),
+        )
+            .serialize(serializer)
+    }
+}
+
 #[derive(Serialize)]
 pub struct TimeSync(pub u64);
 
+/// The label, genesis hash, node count and tags of a chain.
 #[derive(Serialize)]
-pub struct AddedChain<'a>(pub &'a str, pub BlockHash, pub usize);
+pub struct AddedChain<'a>(pub &'a str, pub BlockHash, pub usize, pub &'a ChainTags);
 
 #[derive(Serialize)]
 pub struct RemovedChain(pub BlockHash);
 
+/// The genesis hash and new label of a chain whose label has changed. Feeds subscribed to
+/// the chain stay subscribed to it under its new label.
+#[derive(Serialize)]
+pub struct ChainLabelChanged<'a>(pub BlockHash, pub &'a str);
+
+/// The label, genesis hash, node count and tags of every chain.
+#[derive(Serialize)]
+pub struct ChainsList<'a>(pub Vec<(&'a str, BlockHash, usize, &'a ChainTags)>);
+
+/// The label, genesis hash, node count and average block time (if known) of the most active
+/// chains, most active first.
+#[derive(Serialize)]
+pub struct TopChains<'a>(pub Vec<(&'a str, BlockHash, usize, Option<u64>)>);
+
+/// The connection ID, shard ID (if known), node count, last-seen time and draining
+/// status of every connected shard.
+#[derive(Serialize)]
+pub struct Shards<'a>(pub Vec<(u64, Option<&'a str>, usize, Timestamp, bool)>);
+
+/// The version, git commit (if known) and build time of the core.
+#[derive(Serialize)]
+pub struct BuildInfo(pub &'static str, pub Option<&'static str>, pub Timestamp);
+
 #[derive(Serialize)]
 pub struct SubscribedTo(pub BlockHash);
 
+/// Why a feed couldn't subscribe to what it asked for, along with the genesis hash that it
+/// asked for if it asked for a single chain. A feed that fails to subscribe stays subscribed
+/// to whatever it was subscribed to before.
+#[derive(Serialize)]
+pub struct SubscribeError(pub SubscribeErrorCode, pub Option<BlockHash>);
+
+/// The reasons that a subscription can fail for, sent to feeds as these strings.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SubscribeErrorCode {
+    /// We don't know about the chain (or we've forgotten about it).
+    UnknownChain,
+    /// No chain that we know about has the label that was asked for.
+    UnknownLabel,
+    /// As many feeds as we allow are already subscribed to the chain.
+    TooManyFeeds,
+    /// The feed has subscribed to as many chains as we allow since it last unsubscribed.
+    TooManySubscriptions,
+    /// Only admin feeds can subscribe to every chain at once.
+    NotAdmin,
+}
+
 #[derive(Serialize)]
 pub struct UnsubscribedFrom(pub BlockHash);
 
@@ -178,39 +833,132 @@ pub struct Pong<'a>(pub &'a str);
 #[derive(Serialize)]
 pub struct StaleNode(pub FeedNodeId);
 
+/// Why a command that a feed sent couldn't be understood.
+#[derive(Serialize)]
+pub struct CommandError<'a>(pub &'a str);
+
+/// A node's validator address, and the block that it saw finalized by GRANDPA.
+#[derive(Serialize)]
+pub struct AfgFinalized<'a>(pub &'a str, pub BlockNumber, pub BlockHash);
+
+/// A node's validator address, the block that a prevote it received was for, and the voter.
+#[derive(Serialize)]
+pub struct AfgReceivedPrevote<'a>(
+    pub &'a str,
+    pub BlockNumber,
+    pub BlockHash,
+    pub Option<&'a str>,
+);
+
+/// A node's validator address, the block that a precommit it received was for, and the voter.
+#[derive(Serialize)]
+pub struct AfgReceivedPrecommit<'a>(
+    pub &'a str,
+    pub BlockNumber,
+    pub BlockHash,
+    pub Option<&'a str>,
+);
+
+/// A node's authority ID, the authority set and its ID, and the node's best block at the time.
+#[derive(Serialize)]
+pub struct AfgAuthoritySet<'a>(
+    pub &'a str,
+    pub &'a str,
+    pub &'a str,
+    pub BlockNumber,
+    pub BlockHash,
+);
+
+#[derive(Serialize)]
+pub struct NodeShard<'a>(pub FeedNodeId, pub &'a str);
+
+#[derive(Serialize)]
+pub struct NodeCustomMetrics<'a>(pub FeedNodeId, pub &'a HashMap<String, f64>);
+
+#[derive(Serialize)]
+pub struct Heartbeat(pub Timestamp);
+
+#[derive(Serialize)]
+pub struct NodePeerCount<'a>(pub FeedNodeId, pub &'a [f32]);
+
+/// A node's clock is this many ms ahead of ours (or behind, if negative), as its shard last
+/// reported it; `None` once its clock agrees with ours again.
+#[derive(Serialize)]
+pub struct NodeClockSkew(pub FeedNodeId, pub Option<i64>);
+
+/// When we last received a message from a node, in ms since the unix epoch by our clock. This
+/// is sent as the node's interval messages arrive; other messages move it on too, but feeds
+/// only hear about that the next time one of these is sent.
+#[derive(Serialize)]
+pub struct NodeLastSeen(pub FeedNodeId, pub Timestamp);
+
+/// Everything we know about a node, sent in answer to a `node-detail:NODE_ID` command. This
+/// is what [`AddedNode`] sends, followed by the node's custom metrics, its recent peer counts,
+/// whether it's stale and how skewed its clock is.
+pub struct NodeDetail<'a>(pub FeedNodeId, pub &'a Node, pub bool);
+
+/// The details of a node that [`AddedNode`] and [`NodeDetail`] begin with. The ip, sysinfo
+/// and hwbench are hidden unless `expose_node_details` is true, and the network ID (a long
+/// libp2p peer ID that dashboards rarely show) if `lean` is true.
+fn node_details(node: &Node, expose_node_details: bool, lean: bool) -> impl Serialize + '_ {
+    let details = node.details();
+    let (ip, sys_info, hwbench) = if expose_node_details {
+        (&details.ip, &details.sysinfo, node.hwbench())
+    } else {
+        (&None, &None, None)
+    };
+
+    (
+        &details.name,
+        &details.implementation,
+        &details.version,
+        &details.validator,
+        (!lean).then_some(&details.network_id),
+        ip,
+        sys_info,
+        hwbench,
+        &details.role,
+    )
+}
+
 impl FeedMessageWrite for AddedNode<'_> {
     fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
         let AddedNode(nid, node, expose_node_details) = self;
+        let lean = ser.format.lean;
 
-        let details = node.details();
-        // Hide the ip, sysinfo and hwbench if the `expose_node_details` flag was not specified.
-        let node_hwbench = node.hwbench();
-        let (ip, sys_info, hwbench) = if *expose_node_details {
-            (&details.ip, &details.sysinfo, &node_hwbench)
-        } else {
-            (&None, &None, &None)
-        };
+        ser.write(&(
+            nid,
+            node_details(node, *expose_node_details, lean),
+            node.stats(),
+            MaybeLean(node.io(), lean),
+            MaybeLean(node.hardware(), lean),
+            node.block_details(),
+            &node.location(),
+            &node.startup_time(),
+            node.last_message(),
+        ));
+    }
+}
 
-        let details = (
-            &details.name,
-            &details.implementation,
-            &details.version,
-            &details.validator,
-            &details.network_id,
-            &ip,
-            &sys_info,
-            &hwbench,
-        );
+impl FeedMessageWrite for NodeDetail<'_> {
+    fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
+        let NodeDetail(nid, node, expose_node_details) = self;
+        let lean = ser.format.lean;
 
         ser.write(&(
             nid,
-            details,
+            node_details(node, *expose_node_details, lean),
             node.stats(),
-            node.io(),
-            node.hardware(),
+            MaybeLean(node.io(), lean),
+            MaybeLean(node.hardware(), lean),
             node.block_details(),
             &node.location(),
             &node.startup_time(),
+            node.last_message(),
+            node.custom_metrics(),
+            node.peer_counts(),
+            node.stale(),
+            node.clock_skew(),
         ));
     }
 }
@@ -218,6 +966,80 @@ impl FeedMessageWrite for AddedNode<'_> {
 #[derive(Serialize)]
 pub struct ChainStatsUpdate<'a>(pub &'a ChainStats);
 
+pub struct BlockPropagation<'a>(pub &'a BlockPropagationStats);
+
+impl FeedMessageWrite for BlockPropagation<'_> {
+    fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
+        let BlockPropagation(stats) = self;
+        ser.write(&(stats.min, stats.median, stats.p95, stats.max));
+    }
+}
+
+/// The node with the highest best block on the chain, and the height of that block.
+#[derive(Serialize)]
+pub struct ChainLeader(pub FeedNodeId, pub BlockNumber);
+
+/// The height and hash of a best block that most nodes have moved on to in place of the one
+/// they agreed on before, and how many blocks were replaced as a result.
+#[derive(Serialize)]
+pub struct Reorg(pub BlockNumber, pub BlockHash, pub u64);
+
+/// The genesis hash of the chain that the messages following this one are about. Only feeds
+/// subscribed to every chain are sent this, ahead of each batch of messages about a chain.
+#[derive(Serialize)]
+pub struct ForChain(pub BlockHash);
+
+/// How many nodes have joined and left a chain since the last of these, and how many nodes
+/// it has now. Feeds subscribed to just the node counts of a chain are sent this instead of
+/// anything about the nodes themselves.
+#[derive(Serialize)]
+pub struct NodeCount(pub BlockHash, pub usize, pub usize, pub usize);
+
+pub struct ChainFinalized<'a>(pub &'a FinalizedConsensus);
+
+impl FeedMessageWrite for ChainFinalized<'_> {
+    fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
+        let ChainFinalized(consensus) = self;
+        ser.write(&(consensus.height, consensus.hash, consensus.diverged));
+    }
+}
+
+/// The finalized block that a majority of the nodes on a chain agree on.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct FinalizedConsensus {
+    pub height: BlockNumber,
+    pub hash: BlockHash,
+    /// Have nodes reported different hashes as finalized at this height?
+    pub diverged: bool,
+}
+
+/// The GRANDPA authority set that a majority of the nodes on a chain have moved on to.
+pub struct AuthoritySetChanged<'a>(pub &'a AuthoritySet);
+
+impl FeedMessageWrite for AuthoritySetChanged<'_> {
+    fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
+        let AuthoritySetChanged(set) = self;
+        ser.write(&(set.id, &set.authorities));
+    }
+}
+
+/// A GRANDPA authority set: its ID, and its members as nodes report them.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AuthoritySet {
+    pub id: u64,
+    pub authorities: Box<str>,
+}
+
+/// How long, in ms, it takes for recent blocks to be reported by the nodes on a chain
+/// after the first node to report them.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct BlockPropagationStats {
+    pub min: u64,
+    pub median: u64,
+    pub p95: u64,
+    pub max: u64,
+}
+
 #[derive(Serialize, PartialEq, Eq, Default)]
 pub struct Ranking<K> {
     pub list: Vec<(K, u64)>,
@@ -227,7 +1049,10 @@ pub struct Ranking<K> {
 
 #[derive(Serialize, PartialEq, Eq, Default)]
 pub struct ChainStats {
+    pub implementation: Ranking<String>,
     pub version: Ranking<String>,
+    /// How many nodes are running each combination of implementation, version and OS.
+    pub implementation_version_os: Ranking<(String, String, String)>,
     pub target_os: Ranking<String>,
     pub target_arch: Ranking<String>,
     pub cpu: Ranking<String>,
@@ -240,4 +1065,274 @@ pub struct ChainStats {
     pub memory_memcpy_score: Ranking<(u32, Option<u32>)>,
     pub disk_sequential_write_score: Ranking<(u32, Option<u32>)>,
     pub disk_random_write_score: Ranking<(u32, Option<u32>)>,
+    /// How many nodes have each role.
+    pub role: Ranking<NodeRole>,
+    /// The average time between blocks in ms, if we've seen enough blocks to know.
+    pub average_block_time: Option<u64>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn batch_combines_messages_into_one_array() {
+        let mut batch = FeedMessageBatch::new();
+        assert_eq!(batch.num_bytes(), 0);
+
+        for n in [1, 2] {
+            let mut serializer = FeedMessageSerializer::with_format(FeedFormat::FULL);
+            serializer.push(RemovedNode(n));
+            serializer.push(StaleNode(n));
+            batch.push(serializer.into_finalized().unwrap());
+        }
+        batch.push(bytes::Bytes::from_static(b"[]"));
+
+        let bytes = batch.into_finalized().unwrap();
+        assert_eq!(&bytes[..], b"[4,1,20,1,4,2,20,2]");
+    }
+
+    #[test]
+    fn batch_of_one_message_shares_its_bytes() {
+        let mut serializer = FeedMessageSerializer::with_format(FeedFormat::FULL);
+        serializer.push(RemovedNode(1));
+        let message = serializer.into_finalized().unwrap();
+
+        let mut batch = FeedMessageBatch::new();
+        batch.push(bytes::Bytes::from_static(b"[]"));
+        batch.push(message.clone());
+        assert_eq!(batch.num_bytes(), message.len());
+
+        let bytes = batch.into_finalized().unwrap();
+        assert_eq!(bytes.as_ptr(), message.as_ptr());
+    }
+
+    #[test]
+    fn ndjson_has_one_message_per_line() {
+        let mut serializer = FeedMessageSerializer::with_format(FeedFormat::FULL);
+        serializer.push(RemovedNode(1));
+        serializer.push(NodeShard(1, "shard"));
+        let bytes = serializer.into_finalized().unwrap();
+
+        let mut out = Vec::new();
+        write_ndjson(&bytes, &mut out);
+        write_ndjson(b"[]", &mut out);
+        assert_eq!(&out[..], b"[4,1]\n[23,[1,\"shard\"]]\n");
+    }
+
+    #[test]
+    fn older_protocols_omit_newer_messages() {
+        let serialize = |protocol| {
+            let mut serializer = FeedMessageSerializer::with_format(FeedFormat::full(protocol));
+            serializer.push(RemovedNode(1));
+            serializer.push(NodeShard(1, "shard"));
+            serializer.push(StaleNode(1));
+            serializer.into_finalized().unwrap()
+        };
+
+        let latest = serialize(FeedProtocol::from_subprotocol(None));
+        assert_eq!(&latest[..], br#"[4,1,23,[1,"shard"],20,1]"#);
+
+        let v1 = FeedProtocol::from_subprotocol(Some(FEED_V1_SUBPROTOCOL));
+        assert_eq!(&serialize(v1)[..], b"[4,1,20,1]");
+
+        let mut serializer = FeedMessageSerializer::with_format(FeedFormat::full(v1));
+        serializer.push(NodeShard(1, "shard"));
+        assert!(serializer.into_finalized().is_none());
+    }
+
+    #[test]
+    fn older_protocols_are_still_sent_errors() {
+        assert_eq!(FEED_V1_LAST_ACTION, 22);
+
+        let v1 = FeedProtocol::from_subprotocol(Some(FEED_V1_SUBPROTOCOL));
+        let mut serializer = FeedMessageSerializer::with_format(FeedFormat::full(v1));
+        serializer.push(ChainStatsUpdate(&ChainStats::default()));
+        serializer.push(NodeShard(1, "shard"));
+        serializer.push(CommandError("oops"));
+        serializer.push(SubscribeError(
+            SubscribeErrorCode::UnknownChain,
+            Some(BlockHash::zero()),
+        ));
+        let bytes = serializer.into_finalized().unwrap();
+
+        let actions: Vec<u8> = serde_json::from_slice::<Vec<serde_json::Value>>(&bytes)
+            .unwrap()
+            .chunks(2)
+            .map(|pair| pair[0].as_u64().unwrap() as u8)
+            .collect();
+        assert_eq!(actions, vec![22, 32, 35]);
+    }
+
+    #[test]
+    fn feed_events_omit_other_categories() {
+        let serializer = |events| {
+            FeedMessageSerializer::with_format(FeedFormat {
+                events,
+                ..FeedFormat::FULL
+            })
+        };
+        let mut all = serializer(FeedEvents::ALL);
+        let mut blocks = serializer(FeedEvents::BLOCK);
+        for serializer in [&mut all, &mut blocks] {
+            serializer.push(RemovedNode(1));
+            serializer.push(SubscribedTo(BlockHash::zero()));
+            serializer.push(StaleNode(1));
+        }
+        assert_eq!(
+            &all.into_finalized().unwrap()[..],
+            br#"[4,1,13,"0x0000000000000000000000000000000000000000000000000000000000000000",20,1]"#
+        );
+
+        // Messages outside of any category are always kept:
+        assert_eq!(
+            &blocks.into_finalized().unwrap()[..],
+            br#"[13,"0x0000000000000000000000000000000000000000000000000000000000000000"]"#
+        );
+
+        let mut stats = serializer(FeedEvents::STATS);
+        stats.push(StaleNode(1));
+        assert!(stats.into_finalized().is_none());
+    }
+
+    #[test]
+    fn finality_messages_can_be_omitted() {
+        let format = FeedFormat {
+            finality: false,
+            ..FeedFormat::FULL
+        };
+        let mut serializer = FeedMessageSerializer::with_format(format);
+        serializer.push(FinalizedBlock(1, 10, BlockHash::zero()));
+        serializer.push(BestFinalized(10, BlockHash::zero()));
+        serializer.push(AfgFinalized("Alice", 10, BlockHash::zero()));
+        serializer.push(RemovedNode(2));
+        assert_eq!(
+            &serializer.into_finalized().unwrap()[..],
+            format!("[2,[10,\"{:#x}\"],4,2]", BlockHash::zero()).as_bytes()
+        );
+
+        let mut serializer = FeedMessageSerializer::with_format(format);
+        serializer.push(FinalizedBlock(1, 10, BlockHash::zero()));
+        assert!(serializer.into_finalized().is_none());
+    }
+
+    #[test]
+    fn messages_are_serialized_once_per_format() {
+        let blocks = FeedFormat {
+            events: FeedEvents::BLOCK,
+            ..FeedFormat::FULL
+        };
+        let mut serializer =
+            MultiFormatSerializer::new([FeedFormat::FULL, blocks, FeedFormat::FULL]);
+        serializer.push(StaleNode(1));
+        serializer.push(ChainLeader(1, 10));
+        let bytes = serializer.into_finalized();
+        assert_eq!(
+            &bytes.get(FeedFormat::FULL).unwrap()[..],
+            b"[20,1,33,[1,10]]"
+        );
+        assert_eq!(&bytes.get(blocks).unwrap()[..], b"[33,[1,10]]");
+
+        // Formats that nothing was serialized in have nothing to send:
+        let nodes = FeedFormat {
+            events: FeedEvents::NODE,
+            ..FeedFormat::FULL
+        };
+        let mut serializer = MultiFormatSerializer::new([blocks, nodes]);
+        serializer.push(StaleNode(1));
+        let bytes = serializer.into_finalized();
+        assert!(bytes.get(blocks).is_none());
+        assert!(bytes.get(nodes).is_some());
+        assert!(bytes.get(FeedFormat::FULL).is_none());
+    }
+
+    #[test]
+    fn lean_messages_leave_out_network_ids_and_histories() {
+        let lean = FeedFormat {
+            lean: true,
+            ..FeedFormat::FULL
+        };
+        let mut hardware = NodeHardware::new(20);
+        for n in 1..=10 {
+            hardware.upload.push(n as f64);
+            hardware.cpu.push(n as f32);
+        }
+        let mut serializer = FeedMessageSerializer::with_format(lean);
+        serializer.push(Hardware(1, &hardware));
+        serializer.push(RemovedNode(2));
+        let bytes = serializer.into_finalized().unwrap();
+        assert_eq!(
+            &bytes[..],
+            br#"[9,[1,[[10.0],[],[],[10.0],[],[],[],[]]],4,2]"#
+        );
+
+        let mut serializer = FeedMessageSerializer::with_format(FeedFormat::FULL);
+        serializer.push(Hardware(1, &hardware));
+        assert!(serializer.into_finalized().unwrap().len() > bytes.len());
+
+        let node = Node::new(common::node_types::NodeDetails {
+            chain: "Chain".into(),
+            name: "Alice".into(),
+            implementation: "Substrate".into(),
+            target_arch: None,
+            target_os: None,
+            target_env: None,
+            commit: None,
+            version: "1.0".into(),
+            validator: None,
+            authority: false,
+            role: Default::default(),
+            network_id: "12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp"
+                .try_into()
+                .unwrap(),
+            startup_time: None,
+            sysinfo: None,
+            ip: None,
+        });
+        let details = |format| {
+            let mut serializer = FeedMessageSerializer::with_format(format);
+            serializer.push(AddedNode(0, &node, false));
+            let bytes = serializer.into_finalized().unwrap();
+            let values: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            values[1][1].clone()
+        };
+        assert_eq!(
+            details(FeedFormat::FULL)[4],
+            "12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp"
+        );
+        assert!(details(lean)[4].is_null());
+    }
+
+    #[test]
+    fn big_integers_can_be_sent_as_strings() {
+        let height = (1 << 53) + 1;
+        let format = FeedFormat {
+            bignums_as_strings: true,
+            ..FeedFormat::FULL
+        };
+        let mut serializer = FeedMessageSerializer::with_format(format);
+        serializer.push(BestBlock(height, 1_700_000_000_000, None));
+        serializer.push(BestBlock(100, 12, Some(1500)));
+        let bytes = serializer.into_finalized().unwrap();
+        assert_eq!(
+            &bytes[..],
+            format!(r#"[1,["{height}","1700000000000",null],1,["100","12","1500"]]"#).as_bytes()
+        );
+
+        // Integers are sent as numbers as usual otherwise:
+        let mut serializer = FeedMessageSerializer::with_format(FeedFormat::FULL);
+        serializer.push(BestBlock(height, 1_700_000_000_000, None));
+        let bytes = serializer.into_finalized().unwrap();
+        assert_eq!(
+            &bytes[..],
+            format!("[1,[{height},1700000000000,null]]").as_bytes()
+        );
+    }
+
+    #[test]
+    fn empty_batch_produces_nothing() {
+        let mut batch = FeedMessageBatch::new();
+        batch.push(bytes::Bytes::from_static(b"[]"));
+        assert!(batch.into_finalized().is_none());
+    }
 }
@@ -14,55 +14,137 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use std::future::Future;
 use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::{Sink, SinkExt};
 use maxminddb::{geoip2::City, Reader as GeoIpReader};
 use parking_lot::RwLock;
 use rustc_hash::FxHashMap;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
 use common::node_types::NodeLocation;
 
 /// The returned location is optional; it may be None if not found.
 pub type Location = Option<Arc<NodeLocation>>;
 
+/// Something that can find the geographical location of an IP address. Implementations
+/// can be written as `async fn locate(&self, ip: IpAddr) -> Option<NodeLocation>`.
+pub trait LocationProvider: Send + Sync + 'static {
+    /// Find the location of the IP address given, returning `None` if it can't be found.
+    fn locate(&self, ip: IpAddr) -> impl Future<Output = Option<NodeLocation>> + Send;
+}
+
+/// The built-in [`LocationProvider`], which looks addresses up in a bundled GeoLite2
+/// city database.
+#[derive(Debug, Clone)]
+pub struct GeoIpLocationProvider {
+    locator: Locator,
+}
+
+impl Default for GeoIpLocationProvider {
+    #[allow(clippy::inconsistent_digit_grouping, clippy::excessive_precision)]
+    fn default() -> Self {
+        // cache entries
+        let mut cache: FxHashMap<IpAddr, Arc<NodeLocation>> = FxHashMap::default();
+
+        // Default entry for localhost
+        cache.insert(
+            Ipv4Addr::new(127, 0, 0, 1).into(),
+            Arc::new(NodeLocation {
+                latitude: 52.516_6667,
+                longitude: 13.4,
+                city: "Berlin".into(),
+            }),
+        );
+
+        GeoIpLocationProvider {
+            locator: Locator::new(cache),
+        }
+    }
+}
+
+impl LocationProvider for GeoIpLocationProvider {
+    async fn locate(&self, ip: IpAddr) -> Option<NodeLocation> {
+        let locator = self.locator.clone();
+        let location = tokio::task::spawn_blocking(move || locator.locate(ip))
+            .await
+            .expect("Locate never panics")?;
+        Some(NodeLocation::clone(&location))
+    }
+}
+
+/// Limits how many location lookups can be in progress at once. Lookups beyond this wait
+/// their turn, and are abandoned if they wait too long. Clones share the same limit.
+#[derive(Debug, Clone)]
+pub struct LookupLimits {
+    permits: Arc<Semaphore>,
+    max_wait: Duration,
+}
+
+impl LookupLimits {
+    /// Allow at most `max_concurrent` lookups at once, each waiting at most `max_wait`
+    /// for its turn.
+    pub fn new(max_concurrent: usize, max_wait: Duration) -> LookupLimits {
+        let max_concurrent = max_concurrent.clamp(1, Semaphore::MAX_PERMITS);
+        LookupLimits {
+            permits: Arc::new(Semaphore::new(max_concurrent)),
+            max_wait,
+        }
+    }
+
+    /// Wait for a turn to look up a location, returning `None` if we had to wait too long.
+    async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        let permit = Arc::clone(&self.permits).acquire_owned();
+        tokio::time::timeout(self.max_wait, permit).await.ok()?.ok()
+    }
+}
+
 /// This is responsible for taking an IP address and attempting
-/// to find a geographical location from this
-pub fn find_location<Id, R>(response_chan: R) -> flume::Sender<(Id, IpAddr)>
+/// to find a geographical location from this, using the provider given. Lookups are
+/// made within the limits given; any that are abandoned are counted in `abandoned`, and
+/// we don't respond to them.
+pub fn find_location<Id, R, P>(
+    provider: Arc<P>,
+    limits: LookupLimits,
+    abandoned: Arc<AtomicU64>,
+    response_chan: R,
+) -> flume::Sender<(Id, IpAddr)>
 where
     R: Sink<(Id, Option<Arc<NodeLocation>>)> + Unpin + Send + Clone + 'static,
     Id: Clone + Send + 'static,
+    P: LocationProvider,
 {
     let (tx, rx) = flume::unbounded();
 
-    // cache entries
-    let mut cache: FxHashMap<IpAddr, Arc<NodeLocation>> = FxHashMap::default();
-
-    // Default entry for localhost
-    cache.insert(
-        Ipv4Addr::new(127, 0, 0, 1).into(),
-        Arc::new(NodeLocation {
-            latitude: 52.516_6667,
-            longitude: 13.4,
-            city: "Berlin".into(),
-        }),
-    );
-
-    // Create a locator with our cache. This is used to obtain locations.
-    let locator = Locator::new(cache);
-
     // Spawn a loop to handle location requests
     tokio::spawn(async move {
         loop {
             while let Ok((id, ip_address)) = rx.recv_async().await {
                 let mut response_chan = response_chan.clone();
-                let locator = locator.clone();
+                let provider = Arc::clone(&provider);
+                let limits = limits.clone();
+                let abandoned = Arc::clone(&abandoned);
 
                 tokio::spawn(async move {
-                    let location = tokio::task::spawn_blocking(move || locator.locate(ip_address))
-                        .await
-                        .expect("Locate never panics");
+                    let _permit = match limits.acquire().await {
+                        Some(permit) => permit,
+                        None => {
+                            // The node just stays unlocated:
+                            let n = abandoned.fetch_add(1, Ordering::Relaxed) + 1;
+                            if n.is_power_of_two() {
+                                log::warn!(
+                                    "Abandoned {n} location lookup(s) that waited longer than {:?}",
+                                    limits.max_wait
+                                );
+                            }
+                            return;
+                        }
+                    };
+                    let location = provider.locate(ip_address).await.map(Arc::new);
                     let _ = response_chan.send((id, location)).await;
                 });
             }
@@ -103,7 +185,7 @@ impl Locator {
             return cached_loc;
         }
 
-        let City { city, location, .. } = self.city.lookup(ip.into()).ok()?;
+        let City { city, location, .. } = self.city.lookup(ip).ok()?;
         let city = city
             .as_ref()?
             .names
@@ -140,4 +222,110 @@ mod tests {
         let node_location = Locator::new(Default::default()).locate(ip).unwrap();
         assert_eq!(&*node_location.city, "El Paso");
     }
+
+    struct Atlantis;
+
+    impl LocationProvider for Atlantis {
+        async fn locate(&self, ip: IpAddr) -> Option<NodeLocation> {
+            ip.is_loopback().then(|| NodeLocation {
+                latitude: 0.0,
+                longitude: 0.0,
+                city: "Atlantis".into(),
+            })
+        }
+    }
+
+    fn no_limits() -> LookupLimits {
+        LookupLimits::new(usize::MAX, Duration::from_secs(60))
+    }
+
+    #[tokio::test]
+    async fn custom_providers_can_be_used() {
+        let (tx, rx) = flume::unbounded();
+        let locator = find_location(
+            Arc::new(Atlantis),
+            no_limits(),
+            Default::default(),
+            tx.into_sink(),
+        );
+
+        locator.send((1, "127.0.0.1".parse().unwrap())).unwrap();
+        let (id, location) = rx.recv_async().await.unwrap();
+        assert_eq!(id, 1);
+        assert_eq!(&*location.unwrap().city, "Atlantis");
+
+        locator.send((2, "12.5.56.25".parse().unwrap())).unwrap();
+        assert_eq!(rx.recv_async().await.unwrap(), (2, None));
+    }
+
+    /// Lookups don't finish until they're let through, so that we can control how many are
+    /// in progress.
+    struct Gated {
+        in_progress: std::sync::atomic::AtomicUsize,
+        most_in_progress: std::sync::atomic::AtomicUsize,
+        gate: Semaphore,
+    }
+
+    impl LocationProvider for Gated {
+        async fn locate(&self, _ip: IpAddr) -> Option<NodeLocation> {
+            let n = self.in_progress.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_in_progress.fetch_max(n, Ordering::SeqCst);
+            self.gate.acquire().await.unwrap().forget();
+            self.in_progress.fetch_sub(1, Ordering::SeqCst);
+            Some(NodeLocation {
+                latitude: 0.0,
+                longitude: 0.0,
+                city: "Gated".into(),
+            })
+        }
+    }
+
+    async fn responses(rx: &flume::Receiver<(u32, Location)>, n: usize) -> Vec<u32> {
+        let mut ids = Vec::new();
+        for _ in 0..n {
+            ids.push(rx.recv_async().await.unwrap().0);
+        }
+        ids.sort();
+        ids
+    }
+
+    #[tokio::test]
+    async fn excess_lookups_wait_their_turn_or_are_abandoned() {
+        let provider = Arc::new(Gated {
+            in_progress: Default::default(),
+            most_in_progress: Default::default(),
+            gate: Semaphore::new(0),
+        });
+        let abandoned = Arc::new(AtomicU64::new(0));
+        let (tx, rx) = flume::unbounded();
+        let locator = find_location(
+            Arc::clone(&provider),
+            LookupLimits::new(2, Duration::from_millis(500)),
+            Arc::clone(&abandoned),
+            tx.into_sink(),
+        );
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+
+        // Only two lookups happen at once; the third waits for a turn and then completes:
+        for id in 1..=3 {
+            locator.send((id, ip)).unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(provider.in_progress.load(Ordering::SeqCst), 2);
+        provider.gate.add_permits(3);
+        assert_eq!(responses(&rx, 3).await, [1, 2, 3]);
+        assert_eq!(provider.most_in_progress.load(Ordering::SeqCst), 2);
+        assert_eq!(abandoned.load(Ordering::SeqCst), 0);
+
+        // Lookups that wait too long for a turn are abandoned, and never answered:
+        for id in 4..=7 {
+            locator.send((id, ip)).unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        assert_eq!(abandoned.load(Ordering::SeqCst), 2);
+        provider.gate.add_permits(2);
+        assert_eq!(responses(&rx, 2).await, [4, 5]);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(rx.is_empty());
+    }
 }
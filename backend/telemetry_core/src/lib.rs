@@ -0,0 +1,42 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+/*!
+The Telemetry Backend Core, which receives telemetry messages from Substrate/Polkadot nodes
+(by way of shards) and provides the data to subscribed feeds.
+
+Use [`CoreBuilder`] to run a core in-process; the `telemetry_core` binary is a thin wrapper
+around it.
+*/
+
+mod aggregator;
+mod build_info;
+mod builder;
+mod chain_lists;
+mod feed_limit;
+mod feed_message;
+mod find_location;
+mod message_sampler;
+mod metrics;
+mod ndjson_feed;
+mod server;
+mod state;
+
+pub use build_info::BuildInfo;
+pub use builder::{CoreBuilder, CoreHandle};
+pub use common::node_types::NodeLocation;
+pub use find_location::{GeoIpLocationProvider, LocationProvider};
+pub use state::{AllowedChain, ChainTag, PeerCountHandling};
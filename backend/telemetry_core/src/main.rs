@@ -14,25 +14,12 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-mod aggregator;
-mod feed_message;
-mod find_location;
-mod state;
-use std::str::FromStr;
-use tokio::time::{Duration, Instant};
-
-use aggregator::{
-    AggregatorOpts, AggregatorSet, FromFeedWebsocket, FromShardWebsocket, ToFeedWebsocket,
-    ToShardWebsocket,
-};
-use bincode::Options;
-use common::http_utils;
-use common::internal_messages;
-use common::ready_chunks_all::ReadyChunksAll;
-use futures::{SinkExt, StreamExt};
-use hyper::{Method, Response};
-use simple_logger::SimpleLogger;
+use common::byte_size::ByteSize;
+use common::config_file;
+use common::node_types::BlockHash;
+use std::time::Duration;
 use structopt::StructOpt;
+use telemetry_core::{AllowedChain, ChainTag, CoreBuilder, PeerCountHandling};
 
 #[cfg(not(target_env = "msvc"))]
 use jemallocator::Jemalloc;
@@ -50,27 +37,94 @@ const ABOUT: &str = "This is the Telemetry Backend Core that receives telemetry
 #[derive(StructOpt, Debug)]
 #[structopt(name = NAME, version = VERSION, author = AUTHORS, about = ABOUT)]
 struct Opts {
+    /// A TOML file to load options from. Its keys are the long names of any of these options,
+    /// for example 'max-feeds = 100' or 'allow-chain = ["..."]', and options given on the
+    /// command line override those in the file.
+    #[structopt(long)]
+    config: Option<std::path::PathBuf>,
     /// This is the socket address that Telemetry is listening to. This is restricted to
     /// localhost (127.0.0.1) by default and should be fine for most use cases. If
     /// you are using Telemetry in a container, you likely want to set this to '0.0.0.0:8000'
     #[structopt(short = "l", long = "listen", default_value = "127.0.0.1:8000")]
     socket: std::net::SocketAddr,
     /// The desired log level; one of 'error', 'warn', 'info', 'debug' or 'trace', where
-    /// 'error' only logs errors and 'trace' logs everything.
+    /// 'error' only logs errors and 'trace' logs everything. Levels for individual modules
+    /// can be set via the RUST_LOG environment variable, for example
+    /// 'RUST_LOG=telemetry_core::aggregator=debug,info', which overrides this.
     #[structopt(long = "log", default_value = "info")]
     log_level: log::LevelFilter,
     /// Space delimited list of the names of chains that are not allowed to connect to
     /// telemetry. Case sensitive.
     #[structopt(long, required = false)]
     denylist: Vec<String>,
+    /// A file containing names of chains that are not allowed to connect, one per line (empty
+    /// lines and lines starting with '#' are ignored). Entries are combined with any given via
+    /// '--denylist'. This file and '--allow-chain-file' are re-read when the process receives
+    /// SIGHUP, and connected nodes from chains that are no longer allowed are removed.
+    #[structopt(long)]
+    denylist_file: Option<std::path::PathBuf>,
+    /// Only allow nodes from the chain with this genesis hash to connect, and give the chain
+    /// the label provided, regardless of what nodes report. Expects values of the form
+    /// '<genesis_hash>=<label>', and can be provided multiple times. If neither this nor
+    /// '--allow-chain-file' is provided, nodes from any chain can connect.
+    #[structopt(long = "allow-chain", number_of_values = 1)]
+    allow_chain: Vec<AllowedChain>,
+    /// A file containing chains to allow, one '<genesis_hash>=<label>' entry per line (empty lines
+    /// and lines starting with '#' are ignored). Entries are combined with any given via '--allow-chain'.
+    #[structopt(long)]
+    allow_chain_file: Option<std::path::PathBuf>,
+    /// Attach a tag (such as the network type or operator) to the chain with this genesis hash,
+    /// which feeds are told about alongside its label. Expects values of the form
+    /// '<genesis_hash>=<key>:<value>', and can be provided multiple times.
+    #[structopt(long = "chain-tag", number_of_values = 1)]
+    chain_tag: Vec<ChainTag>,
+    /// Only track authority nodes on the chain with this genesis hash; other nodes on it are
+    /// turned away when they connect, as if their chain weren't allowed. Nodes say whether
+    /// they're an authority when they connect. Can be provided multiple times.
+    #[structopt(long = "authority-only-chain", number_of_values = 1)]
+    authority_only_chain: Vec<BlockHash>,
+    /// Let authority nodes that connect with this token (in an "Authorization: Bearer <token>"
+    /// header, or as in "/submit?token=<token>") in even if their chain isn't allowed by
+    /// '--allow-chain', so that our own validators are always tracked. Tokens in the URL can end
+    /// up in proxy logs, so prefer the header where nodes can send it. Other nodes on such a
+    /// chain are still turned away. The deny list takes precedence over the allow list, which
+    /// takes precedence over this; authority nodes on denied chains are never let in. Disabled
+    /// by default.
+    #[structopt(long)]
+    authority_node_token: Option<String>,
     /// If it takes longer than this number of seconds to send the current batch of messages
     /// to a feed, the feed connection will be closed.
     #[structopt(long, default_value = "10")]
     feed_timeout: u64,
+    /// If a single write to a feed (sending a frame of messages, flushing it, or closing the
+    /// connection) takes longer than this number of seconds, the feed's connection is assumed
+    /// to be dead and is dropped. This catches connections whose writes stall, which TCP
+    /// keepalive can be slow to notice. If no value is given, writes are only bounded by
+    /// '--feed-timeout', and closing a connection isn't bounded at all.
+    #[structopt(long)]
+    feed_send_timeout: Option<u64>,
+    /// Messages for a feed that are ready within this number of milliseconds of the first
+    /// one are sent together in a single frame. "0" sends messages as soon as they are ready.
+    #[structopt(long, default_value = "0")]
+    feed_flush_interval: u64,
+    /// If the messages waiting to be sent to a feed add up to this size, they are sent right
+    /// away rather than waiting for the '--feed-flush-interval' to pass.
+    #[structopt(long, default_value = "64k")]
+    feed_flush_size: ByteSize,
+    /// How many messages can be waiting to be sent to a feed before the feed is disconnected.
+    /// The aggregator never waits for a feed to catch up, so this bounds the memory that a
+    /// slow feed can use; it should be large enough to hold a snapshot of the largest chain.
+    /// If no value is given, there is no limit.
+    #[structopt(long)]
+    feed_channel_capacity: Option<usize>,
     /// Number of worker threads to spawn. If "0" is given, use the number of CPUs available
     /// on the machine. If no value is given, use an internal default that we have deemed sane.
     #[structopt(long)]
     worker_threads: Option<usize>,
+    /// Try to pin each worker thread to its own CPU. This is best-effort; a warning is
+    /// logged if it can't be done on this platform.
+    #[structopt(long)]
+    worker_cpu_affinity: bool,
     /// Each aggregator keeps track of the entire node state. Feed subscriptions are split across
     /// aggregators.
     #[structopt(long)]
@@ -82,484 +136,288 @@ struct Opts {
     /// How many nodes from third party chains are allowed to connect before we prevent connections from them.
     #[structopt(long, default_value = "1000")]
     max_third_party_nodes: usize,
+    /// How many chains can be tracked at once. Once this many chains are known, nodes on new
+    /// chains are rejected, while existing chains continue to accept nodes. If no value is
+    /// given, there is no limit.
+    #[structopt(long)]
+    max_chains: Option<usize>,
+    /// How many feeds can be subscribed to each chain at once (on each aggregator, if there
+    /// are several). Feeds asking to subscribe to a chain beyond this are told that it has
+    /// too many feeds. If no value is given, there is no limit.
+    #[structopt(long)]
+    max_feeds_per_chain: Option<usize>,
+    /// How many different chains a feed can subscribe to until it unsubscribes. Subscribing
+    /// to a chain still moves a feed off the chain it was subscribed to before, but that
+    /// chain keeps using up one of the feed's subscriptions, since every chain that a feed
+    /// subscribes to is a snapshot that we have to send it. Feeds asking to subscribe to a
+    /// chain beyond this are told that they have too many subscriptions. If no value is
+    /// given, there is no limit.
+    #[structopt(long)]
+    max_subscriptions_per_feed: Option<usize>,
+    /// How many feeds can be connected at once, however they connect. Feed connections beyond
+    /// this are refused before any state is set up for them; websocket feeds are sent a
+    /// "503 Service Unavailable" response. If no value is given, there is no limit.
+    #[structopt(long)]
+    max_feeds: Option<usize>,
     /// Flag to expose the node's details (IP address, SysInfo, HwBench) of all connected
     /// nodes to the feed subscribers.
     #[structopt(long)]
     pub expose_node_details: bool,
+    /// Whether to go by the times that nodes say they sent their messages at, according to
+    /// their own clocks, when working out when blocks were seen (and so which node saw a
+    /// block first, how long blocks took to propagate and so on), and the time that feeds
+    /// are told to sync with. If "false", the times that we receive messages at are used
+    /// instead, so that a node with a skewed clock can't skew what feeds are shown.
+    #[structopt(long, default_value = "true", parse(try_from_str))]
+    trust_node_timestamps: bool,
+    /// Don't look up the geographical locations of nodes from their IP addresses, and don't
+    /// hold on to their IP addresses at all (even if '--expose-node-details' is given).
+    #[structopt(long)]
+    disable_geolocation: bool,
+    /// Look up the geographical locations of at most this many nodes at once. Lookups beyond
+    /// this wait their turn, so that a flood of reconnecting nodes doesn't overwhelm the
+    /// location provider.
+    #[structopt(long, default_value = "32")]
+    geoip_max_concurrent: usize,
+    /// Give up on looking up the location of a node if it has waited this many seconds for
+    /// its turn. The node is left without a location.
+    #[structopt(long, default_value = "10")]
+    geoip_max_wait: u64,
+    /// What to do about nodes that report implausible peer counts (negative counts, or no peers
+    /// while synced); one of 'clamp' (clamp negative counts to zero) or 'flag' (clamp negative
+    /// counts to zero, and flag implausible counts in the node stats sent to feeds).
+    #[structopt(long, default_value = "flag")]
+    implausible_peer_counts: PeerCountHandling,
+    /// Send feeds the stats and other details that each node reports periodically at most once
+    /// per this number of milliseconds, with intermediate updates collapsed into the latest
+    /// values (which are sent along with the node's next update). "0" sends every update as
+    /// it arrives.
+    #[structopt(long, default_value = "0")]
+    node_update_min_interval: u64,
+    /// When feeds subscribe to a chain, the snapshot of the chain's current state that's sent
+    /// to them is cached and reused for other feeds subscribing to the same chain within this
+    /// number of milliseconds. "0" disables the cache.
+    #[structopt(long, default_value = "0")]
+    snapshot_cache_ttl_ms: u64,
+    /// How many recent events (nodes being added, and blocks being imported and finalized) to
+    /// keep for each chain, so that feeds subscribing with "subscribe:<chain>:replay=N" can be
+    /// sent up to N of them after the snapshot of the chain. "0" disables this.
+    #[structopt(long, default_value = "0")]
+    event_history_size: usize,
+    /// Nodes that haven't sent any message in this number of seconds are removed, as they
+    /// may have disappeared without their connection being closed. "0" disables this.
+    #[structopt(long, default_value = "0")]
+    stale_node_timeout: u64,
+    /// How often, in seconds, to check for nodes that haven't sent a message within the
+    /// '--stale-node-timeout'. "0" disables this.
+    #[structopt(long, default_value = "10")]
+    stale_node_check_interval: u64,
+    /// How often, in seconds, to send every feed a heartbeat message carrying the current
+    /// time, so that feeds can tell a quiet connection from a stalled one (and measure clock
+    /// skew). "0" disables this.
+    #[structopt(long, default_value = "0")]
+    feed_heartbeat_interval: u64,
+    /// How often, in seconds, to tell feeds subscribed to just the node counts of a chain
+    /// (with 'subscribe-counts') how many nodes have joined and left it since last time.
+    /// Nothing is sent if no nodes have come or gone. "0" disables this.
+    #[structopt(long, default_value = "5")]
+    node_count_interval: u64,
+    /// How long, in seconds, to keep a chain listed after its last node disconnects. Feeds are
+    /// told that the chain has been removed once this passes, unless a node rejoins it first.
+    /// Empty chains count towards '--max-chains'. "0" removes chains as soon as they're empty.
+    #[structopt(long, default_value = "0")]
+    empty_chain_ttl: u64,
+    /// Ignore best blocks that nodes report which are more than this many blocks ahead of
+    /// their chain's current best block, so that one misbehaving node can't drag the best
+    /// block shown for the whole chain to a nonsensical height. Heights too large to be
+    /// displayed are always ignored. If no value is given, there is no limit.
+    #[structopt(long)]
+    max_block_height_jump: Option<u64>,
+    /// How many values of each stat series (bandwidth, CPU use, peer counts and so on) to keep
+    /// for each node. Once a series is full, its older values are averaged together to make
+    /// room for new ones. Lower values use less memory per node. If no value is given, 20 are
+    /// kept.
+    #[structopt(long)]
+    stat_history_len: Option<usize>,
+    /// How many of the most recent block heights to keep track of on each chain, when working
+    /// out how quickly blocks propagate and which finalized block nodes agree on. Older heights
+    /// are forgotten. If no value is given, 20 heights are kept for block propagation and 64
+    /// for finality.
+    #[structopt(long)]
+    block_window: Option<usize>,
+    /// Time one in every this many node updates, from the moment the update is received from a
+    /// shard until the messages for feeds that it leads to have been queued up to be sent, and
+    /// report percentiles of these latencies on "/metrics". "0" times nothing.
+    #[structopt(long, default_value = "100")]
+    latency_sample_every: u64,
+    /// The length, in seconds, of the windows that update latencies are reported over. The
+    /// percentiles reported are those of the last complete window.
+    #[structopt(long, default_value = "60")]
+    latency_window: u64,
+    /// Serve an "/admin_feed" endpoint alongside "/feed", which also accepts commands to
+    /// inspect internal details (such as which shard a node is connected through), and is sent
+    /// the list of connected shards whenever a shard connects or disconnects. Access to this
+    /// endpoint should be restricted.
+    #[structopt(long)]
+    admin_feed: bool,
+    /// The largest command that a feed can send to us. Feeds that send larger commands are
+    /// disconnected, without us buffering the command. Feeds only send us small commands.
+    #[structopt(long, alias = "max-feed-msg-bytes", default_value = "16k")]
+    max_feed_command_bytes: ByteSize,
+    /// Accept at most this many new connections (from feeds and shards alike) per second.
+    /// Connections beyond this rate are left waiting to be accepted rather than refused, so
+    /// that a flood of reconnections is spread out over time. If no value is given, there is
+    /// no limit.
+    #[structopt(long)]
+    max_accepts_per_sec: Option<u32>,
+    /// How many connections the OS can queue up for us to accept. If no value is given, the
+    /// system default is used.
+    #[structopt(long)]
+    listen_backlog: Option<u32>,
+    /// How many accepted connections can be waiting to be upgraded to websockets (or to have
+    /// their HTTP requests answered) at once. Connections beyond this are answered straight
+    /// away with a "503 Service Unavailable" asking them to try again later, rather than being
+    /// left hanging. If no value is given, there is no limit.
+    #[structopt(long)]
+    max_pending_connections: Option<usize>,
+    /// Enable TCP keepalive on connections from feeds and shards, probing them once they've
+    /// been idle for this many seconds so that dead ones are noticed.
+    #[structopt(long)]
+    tcp_keepalive: Option<u64>,
+    /// Serve "/admin/chains", "/admin/top_chains?n=N" and "/admin/shards" endpoints, which return
+    /// JSON snapshots of the core's current state, to requests with an
+    /// "Authorization: Bearer <token>" header containing this token. The "/admin/sampling",
+    /// "/admin/samples" and "/admin/log_level" endpoints, which sample node messages and
+    /// change the log level while we're running, are served to them too.
+    #[structopt(long)]
+    admin_token: Option<String>,
+    /// Only accept shards that were started with the same '--shard-secret', so that nothing
+    /// else that can reach "/shard_submit" can pass itself off as a shard. By default, any
+    /// shard is accepted.
+    #[structopt(long)]
+    shard_secret: Option<String>,
+    /// Also serve feeds over plain TCP on this port (of the address given by '--listen'),
+    /// sending them the same messages as "/feed" as newline delimited JSON, one
+    /// '[action, payload]' array per line. These feeds can't send commands, but can subscribe
+    /// to a chain by sending a single line like 'chain=<genesis_hash>&events=block' when they
+    /// connect. Disabled by default.
+    #[structopt(long)]
+    feed_ndjson_port: Option<u16>,
 }
 
 fn main() {
-    let opts = Opts::from_args();
+    let opts: Opts = config_file::from_args();
 
-    SimpleLogger::new()
-        .with_level(opts.log_level)
-        .init()
-        .expect("Must be able to start a logger");
+    common::logging::init(opts.log_level);
 
     log::info!("Starting Telemetry Core version: {}", VERSION);
-
-    let worker_threads = match opts.worker_threads {
-        Some(0) => num_cpus::get(),
-        Some(n) => n,
-        // By default, use a max of 8 worker threads, as perf
-        // testing has found that to be a good sweet spot.
-        None => usize::min(num_cpus::get(), 8),
-    };
-
-    let num_aggregators = match opts.num_aggregators {
-        Some(0) => num_cpus::get(),
-        Some(n) => n,
-        // For now, we just have 1 aggregator loop by default,
-        // but we may want to be smarter here eventually.
-        None => 1,
-    };
-
-    tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .worker_threads(worker_threads)
-        .thread_name("telemetry_core_worker")
-        .build()
-        .unwrap()
-        .block_on(async {
-            if let Err(e) = start_server(num_aggregators, opts).await {
-                log::error!("Error starting server: {}", e);
-            }
-        });
-}
-
-/// Declare our routes and start the server.
-async fn start_server(num_aggregators: usize, opts: Opts) -> anyhow::Result<()> {
-    let aggregator_queue_len = opts.aggregator_queue_len.unwrap_or(10_000);
-    let aggregator = AggregatorSet::spawn(
-        num_aggregators,
-        AggregatorOpts {
-            max_queue_len: aggregator_queue_len,
-            denylist: opts.denylist,
-            max_third_party_nodes: opts.max_third_party_nodes,
-            expose_node_details: opts.expose_node_details,
-        },
-    )
-    .await?;
-    let socket_addr = opts.socket;
-    let feed_timeout = opts.feed_timeout;
-
-    let server = http_utils::start_server(socket_addr, move |addr, req| {
-        let aggregator = aggregator.clone();
-        async move {
-            match (req.method(), req.uri().path().trim_end_matches('/')) {
-                // Check that the server is up and running:
-                (&Method::GET, "/health") => Ok(Response::new("OK".into())),
-                // Subscribe to feed messages:
-                (&Method::GET, "/feed") => {
-                    log::info!("Opening /feed connection from {:?}", addr);
-                    Ok(http_utils::upgrade_to_websocket(
-                        req,
-                        move |ws_send, ws_recv| async move {
-                            let (feed_id, tx_to_aggregator) = aggregator.subscribe_feed();
-                            let (mut tx_to_aggregator, mut ws_send) =
-                                handle_feed_websocket_connection(
-                                    ws_send,
-                                    ws_recv,
-                                    tx_to_aggregator,
-                                    feed_timeout,
-                                    feed_id,
-                                )
-                                .await;
-                            log::info!("Closing /feed connection from {:?}", addr);
-                            // Tell the aggregator that this connection has closed, so it can tidy up.
-                            let _ = tx_to_aggregator.send(FromFeedWebsocket::Disconnected).await;
-                            let _ = ws_send.close().await;
-                        },
-                    ))
-                }
-                // Subscribe to shard messages:
-                (&Method::GET, "/shard_submit") => {
-                    Ok(http_utils::upgrade_to_websocket(
-                        req,
-                        move |ws_send, ws_recv| async move {
-                            log::info!("Opening /shard_submit connection from {:?}", addr);
-                            let tx_to_aggregator = aggregator.subscribe_shard();
-                            let (mut tx_to_aggregator, mut ws_send) =
-                                handle_shard_websocket_connection(
-                                    ws_send,
-                                    ws_recv,
-                                    tx_to_aggregator,
-                                )
-                                .await;
-                            log::info!("Closing /shard_submit connection from {:?}", addr);
-                            // Tell the aggregator that this connection has closed, so it can tidy up.
-                            let _ = tx_to_aggregator
-                                .send(FromShardWebsocket::Disconnected)
-                                .await;
-                            let _ = ws_send.close().await;
-                        },
-                    ))
-                }
-                // Return metrics in a prometheus-friendly text based format:
-                (&Method::GET, "/metrics") => Ok(return_prometheus_metrics(aggregator).await),
-                // 404 for anything else:
-                _ => Ok(Response::builder()
-                    .status(404)
-                    .body("Not found".into())
-                    .unwrap()),
-            }
-        }
-    });
-
-    server.await?;
-    Ok(())
-}
-
-/// This handles messages coming to/from a shard connection
-async fn handle_shard_websocket_connection<S>(
-    mut ws_send: http_utils::WsSender,
-    mut ws_recv: http_utils::WsReceiver,
-    mut tx_to_aggregator: S,
-) -> (S, http_utils::WsSender)
-where
-    S: futures::Sink<FromShardWebsocket, Error = anyhow::Error> + Unpin + Send + 'static,
-{
-    let (tx_to_shard_conn, rx_from_aggregator) = flume::unbounded();
-
-    // Tell the aggregator about this new connection, and give it a way to send messages to us:
-    let init_msg = FromShardWebsocket::Initialize {
-        channel: tx_to_shard_conn,
-    };
-    if let Err(e) = tx_to_aggregator.send(init_msg).await {
-        log::error!("Error sending message to aggregator: {}", e);
-        return (tx_to_aggregator, ws_send);
+    if let Some(path) = &opts.config {
+        log::info!("Loaded options from {}", path.display());
     }
 
-    // Channels to notify each loop if the other closes:
-    let (recv_closer_tx, mut recv_closer_rx) = tokio::sync::oneshot::channel::<()>();
-    let (send_closer_tx, mut send_closer_rx) = tokio::sync::oneshot::channel::<()>();
-
-    // Receive messages from a shard:
-    let recv_handle = tokio::spawn(async move {
-        loop {
-            let mut bytes = Vec::new();
-
-            // Receive a message, or bail if closer called. We don't care about cancel safety;
-            // if we're halfway through receiving a message, no biggie since we're closing the
-            // connection anyway.
-            let msg_info = tokio::select! {
-                msg_info = ws_recv.receive_data(&mut bytes) => msg_info,
-                _ = &mut recv_closer_rx => break
-            };
-
-            // Handle the socket closing, or errors receiving the message.
-            if let Err(soketto::connection::Error::Closed) = msg_info {
-                break;
-            }
-            if let Err(e) = msg_info {
-                log::error!("Shutting down websocket connection: Failed to receive data: {e}");
-                break;
-            }
-
-            let msg: internal_messages::FromShardAggregator =
-                match bincode::options().deserialize(&bytes) {
-                    Ok(msg) => msg,
-                    Err(e) => {
-                        log::error!("Failed to deserialize message from shard; booting it: {e}");
-                        break;
-                    }
-                };
-
-            // Convert and send to the aggregator:
-            let aggregator_msg = match msg {
-                internal_messages::FromShardAggregator::AddNode {
-                    ip,
-                    node,
-                    local_id,
-                    genesis_hash,
-                } => FromShardWebsocket::Add {
-                    ip,
-                    node,
-                    genesis_hash,
-                    local_id,
-                },
-                internal_messages::FromShardAggregator::UpdateNode { payload, local_id } => {
-                    FromShardWebsocket::Update { local_id, payload }
-                }
-                internal_messages::FromShardAggregator::RemoveNode { local_id } => {
-                    FromShardWebsocket::Remove { local_id }
-                }
-            };
-
-            if let Err(e) = tx_to_aggregator.send(aggregator_msg).await {
-                log::error!("Failed to send message to aggregator; closing shard: {e}");
-                break;
-            }
-        }
-
-        drop(send_closer_tx); // Kill the send task if this recv task ends
-        tx_to_aggregator
-    });
-
-    // Send messages to the shard:
-    let send_handle = tokio::spawn(async move {
-        loop {
-            let msg = tokio::select! {
-                msg = rx_from_aggregator.recv_async() => msg,
-                _ = &mut send_closer_rx => { break }
-            };
-
-            let msg = match msg {
-                Ok(msg) => msg,
-                Err(flume::RecvError::Disconnected) => break,
-            };
-
-            let internal_msg = match msg {
-                ToShardWebsocket::Mute { local_id, reason } => {
-                    internal_messages::FromTelemetryCore::Mute { local_id, reason }
-                }
-            };
-
-            let bytes = bincode::options()
-                .serialize(&internal_msg)
-                .expect("message to shard should serialize");
-
-            if let Err(e) = ws_send.send_binary(bytes).await {
-                log::error!("Failed to send message to aggregator; closing shard: {e}")
-            }
-            if let Err(e) = ws_send.flush().await {
-                log::error!("Failed to flush message to aggregator; closing shard: {e}")
-            }
-        }
-
-        drop(recv_closer_tx); // Kill the recv task if this send task ends
-        ws_send
-    });
-
-    // If our send/recv tasks are stopped (if one of them dies, they both will),
-    // collect the bits we need to hand back from them:
-    let ws_send = send_handle.await.unwrap();
-    let tx_to_aggregator = recv_handle.await.unwrap();
-
-    // loop ended; give socket back to parent:
-    (tx_to_aggregator, ws_send)
-}
-
-/// This handles messages coming from a feed connection
-async fn handle_feed_websocket_connection<S>(
-    mut ws_send: http_utils::WsSender,
-    mut ws_recv: http_utils::WsReceiver,
-    mut tx_to_aggregator: S,
-    feed_timeout: u64,
-    _feed_id: u64, // <- can be useful for debugging purposes.
-) -> (S, http_utils::WsSender)
-where
-    S: futures::Sink<FromFeedWebsocket, Error = anyhow::Error> + Unpin + Send + 'static,
-{
-    // unbounded channel so that slow feeds don't block aggregator progress:
-    let (tx_to_feed_conn, rx_from_aggregator) = flume::unbounded();
-
-    // `Receiver::into_stream()` is currently problematic at the time of writing
-    // (see https://github.com/zesterer/flume/issues/88). If this stream is polled lots
-    // and isn't ready, it'll leak memory. In this case, since we only select from it or
-    // a close channel, we shouldn't poll the thing more than once before it's ready (and
-    // when it's ready, it cleans up after itself properly). So, I hope it won't leak!
-    let mut rx_from_aggregator_chunks = ReadyChunksAll::new(rx_from_aggregator.into_stream());
-
-    // Tell the aggregator about this new connection, and give it a way to send messages to us:
-    let init_msg = FromFeedWebsocket::Initialize {
-        channel: tx_to_feed_conn,
-    };
-    if let Err(e) = tx_to_aggregator.send(init_msg).await {
-        log::error!("Error sending message to aggregator: {e}");
-        return (tx_to_aggregator, ws_send);
+    if let Err(e) = futures::executor::block_on(start_server(opts)) {
+        log::error!("Error starting server: {}", e);
     }
-
-    // Channels to notify each loop if the other closes:
-    let (recv_closer_tx, mut recv_closer_rx) = tokio::sync::oneshot::channel::<()>();
-    let (send_closer_tx, mut send_closer_rx) = tokio::sync::oneshot::channel::<()>();
-
-    // Receive messages from the feed:
-    let recv_handle = tokio::spawn(async move {
-        loop {
-            let mut bytes = Vec::new();
-            // Receive a message, or bail if closer called. We don't care about cancel safety;
-            // if we're halfway through receiving a message, no biggie since we're closing the
-            // connection anyway.
-            let msg_info = tokio::select! {
-                msg_info = ws_recv.receive_data(&mut bytes) => msg_info,
-                _ = &mut recv_closer_rx => { break }
-            };
-
-            // Handle the socket closing, or errors receiving the message.
-            if let Err(soketto::connection::Error::Closed) = msg_info {
-                break;
-            }
-            if let Err(e) = msg_info {
-                log::error!("Shutting down websocket connection: Failed to receive data: {e}");
-                break;
-            }
-
-            // We ignore all but valid UTF8 text messages from the frontend:
-            let text = match String::from_utf8(bytes) {
-                Ok(s) => s,
-                Err(_) => continue,
-            };
-
-            // Parse the message into a command we understand and send it to the aggregator:
-            let cmd = match FromFeedWebsocket::from_str(&text) {
-                Ok(cmd) => cmd,
-                Err(e) => {
-                    log::warn!("Ignoring invalid command '{text}' from the frontend: {e}");
-                    continue;
-                }
-            };
-            if let Err(e) = tx_to_aggregator.send(cmd).await {
-                log::error!("Failed to send message to aggregator; closing feed: {e}");
-                break;
-            }
-        }
-
-        drop(send_closer_tx); // Kill the send task if this recv task ends
-        tx_to_aggregator
-    });
-
-    // Send messages to the feed:
-    let send_handle = tokio::spawn(async move {
-        'outer: loop {
-            let debounce = tokio::time::sleep_until(Instant::now() + Duration::from_millis(75));
-
-            let msgs = tokio::select! {
-                msgs = rx_from_aggregator_chunks.next() => msgs,
-                _ = &mut send_closer_rx => { break }
-            };
-
-            // End the loop when connection from aggregator ends:
-            let msgs = match msgs {
-                Some(msgs) => msgs,
-                None => break,
-            };
-
-            // There is only one message type at the mo; bytes to send
-            // to the websocket. collect them all up to dispatch in one shot.
-            let all_msg_bytes = msgs.into_iter().map(|msg| match msg {
-                ToFeedWebsocket::Bytes(bytes) => bytes,
-            });
-
-            // If the feed is too slow to receive the current batch of messages, we'll drop it.
-            let message_send_deadline = Instant::now() + Duration::from_secs(feed_timeout);
-
-            for bytes in all_msg_bytes {
-                match tokio::time::timeout_at(message_send_deadline, ws_send.send_binary(&bytes))
-                    .await
-                {
-                    Err(_) => {
-                        log::debug!("Closing feed websocket that was too slow to keep up (too slow to send messages)");
-                        break 'outer;
-                    }
-                    Ok(Err(soketto::connection::Error::Closed)) => {
-                        break 'outer;
-                    }
-                    Ok(Err(e)) => {
-                        log::debug!("Closing feed websocket due to error sending data: {}", e);
-                        break 'outer;
-                    }
-                    Ok(_) => {}
-                }
-            }
-
-            match tokio::time::timeout_at(message_send_deadline, ws_send.flush()).await {
-                Err(_) => {
-                    log::debug!("Closing feed websocket that was too slow to keep up (too slow to flush messages)");
-                    break;
-                }
-                Ok(Err(soketto::connection::Error::Closed)) => {
-                    break;
-                }
-                Ok(Err(e)) => {
-                    log::debug!("Closing feed websocket due to error flushing data: {}", e);
-                    break;
-                }
-                Ok(_) => {}
-            }
-
-            debounce.await;
-        }
-
-        drop(recv_closer_tx); // Kill the recv task if this send task ends
-        ws_send
-    });
-
-    // If our send/recv tasks are stopped (if one of them dies, they both will),
-    // collect the bits we need to hand back from them:
-    let ws_send = send_handle.await.unwrap();
-    let tx_to_aggregator = recv_handle.await.unwrap();
-
-    // loop ended; give socket back to parent:
-    (tx_to_aggregator, ws_send)
 }
 
-async fn return_prometheus_metrics(aggregator: AggregatorSet) -> Response<hyper::Body> {
-    let metrics = aggregator.latest_metrics();
-
-    // Instead of using the rust prometheus library (which is optimised around global variables updated across a codebase),
-    // we just split out the text format that prometheus expects ourselves, and use the latest metrics that we've
-    // captured so far from the aggregators. See:
-    //
-    // https://github.com/prometheus/docs/blob/master/content/docs/instrumenting/exposition_formats.md#text-format-details
-    //
-    // For an example and explanation of this text based format. The minimal output we produce here seems to
-    // be handled correctly when pointing a current version of prometheus at it.
-    //
-    // Note: '{{' and '}}' are just escaped versions of '{' and '}' in Rust fmt strings.
-    use std::fmt::Write;
-    let mut s = String::new();
-    for (idx, m) in metrics.iter().enumerate() {
-        let _ = write!(
-            &mut s,
-            "telemetry_core_connected_feeds{{aggregator=\"{}\"}} {} {}\n",
-            idx, m.connected_feeds, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_connected_nodes{{aggregator=\"{}\"}} {} {}\n",
-            idx, m.connected_nodes, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_connected_shards{{aggregator=\"{}\"}} {} {}\n",
-            idx, m.connected_shards, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_chains_subscribed_to{{aggregator=\"{}\"}} {} {}\n",
-            idx, m.chains_subscribed_to, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_subscribed_feeds{{aggregator=\"{}\"}} {} {}\n",
-            idx, m.subscribed_feeds, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_total_messages_to_feeds{{aggregator=\"{}\"}} {} {}\n",
-            idx, m.total_messages_to_feeds, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_current_messages_to_aggregator{{aggregator=\"{}\"}} {} {}\n\n",
-            idx, m.current_messages_to_aggregator, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_total_messages_to_aggregator{{aggregator=\"{}\"}} {} {}\n\n",
-            idx, m.total_messages_to_aggregator, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_dropped_messages_to_aggregator{{aggregator=\"{}\"}} {} {}\n\n",
-            idx, m.dropped_messages_to_aggregator, m.timestamp_unix_ms
-        );
+/// Configure and start the core, and then wait for it to stop.
+async fn start_server(opts: Opts) -> anyhow::Result<()> {
+    let mut builder = CoreBuilder::new()
+        .listen(opts.socket)
+        .denylist(opts.denylist)
+        .allowlist(opts.allow_chain)
+        .chain_tags(opts.chain_tag)
+        .authority_only_chains(opts.authority_only_chain)
+        .reload_chain_lists_on_sighup(true)
+        .max_third_party_nodes(opts.max_third_party_nodes)
+        .expose_node_details(opts.expose_node_details)
+        .trust_node_timestamps(opts.trust_node_timestamps)
+        .disable_geolocation(opts.disable_geolocation)
+        .geoip_max_concurrent(opts.geoip_max_concurrent)
+        .geoip_max_wait(Duration::from_secs(opts.geoip_max_wait))
+        .peer_count_handling(opts.implausible_peer_counts)
+        .node_update_min_interval(Duration::from_millis(opts.node_update_min_interval))
+        .snapshot_cache_ttl(Duration::from_millis(opts.snapshot_cache_ttl_ms))
+        .event_history_size(opts.event_history_size)
+        .stale_node_timeout(Duration::from_secs(opts.stale_node_timeout))
+        .stale_node_check_interval(Duration::from_secs(opts.stale_node_check_interval))
+        .latency_sample_every(opts.latency_sample_every)
+        .latency_window(Duration::from_secs(opts.latency_window))
+        .feed_heartbeat_interval(Duration::from_secs(opts.feed_heartbeat_interval))
+        .node_count_interval(Duration::from_secs(opts.node_count_interval))
+        .empty_chain_ttl(Duration::from_secs(opts.empty_chain_ttl))
+        .feed_timeout(Duration::from_secs(opts.feed_timeout))
+        .feed_flush_interval(Duration::from_millis(opts.feed_flush_interval))
+        .feed_flush_size(opts.feed_flush_size.num_bytes())
+        .admin_feed(opts.admin_feed)
+        .worker_cpu_affinity(opts.worker_cpu_affinity)
+        .max_feed_command_bytes(opts.max_feed_command_bytes.num_bytes());
+    if let Some(n) = opts.worker_threads {
+        builder = builder.worker_threads(n);
+    }
+    if let Some(n) = opts.max_chains {
+        builder = builder.max_chains(n);
+    }
+    if let Some(n) = opts.max_feeds_per_chain {
+        builder = builder.max_feeds_per_chain(n);
+    }
+    if let Some(n) = opts.max_subscriptions_per_feed {
+        builder = builder.max_subscriptions_per_feed(n);
+    }
+    if let Some(n) = opts.max_feeds {
+        builder = builder.max_feeds(n);
+    }
+    if let Some(n) = opts.num_aggregators {
+        builder = builder.num_aggregators(n);
+    }
+    if let Some(capacity) = opts.feed_channel_capacity {
+        builder = builder.feed_channel_capacity(capacity);
+    }
+    if let Some(n) = opts.max_accepts_per_sec {
+        builder = builder.max_accepts_per_sec(n);
+    }
+    if let Some(backlog) = opts.listen_backlog {
+        builder = builder.listen_backlog(backlog);
+    }
+    if let Some(n) = opts.max_pending_connections {
+        builder = builder.max_pending_connections(n);
+    }
+    if let Some(secs) = opts.feed_send_timeout {
+        builder = builder.feed_send_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = opts.tcp_keepalive {
+        builder = builder.tcp_keepalive(Duration::from_secs(secs));
+    }
+    if let Some(max_jump) = opts.max_block_height_jump {
+        builder = builder.max_block_height_jump(max_jump);
+    }
+    if let Some(len) = opts.stat_history_len {
+        builder = builder.stat_history_len(len);
+    }
+    if let Some(window) = opts.block_window {
+        builder = builder.block_window(window);
+    }
+    if let Some(len) = opts.aggregator_queue_len {
+        builder = builder.aggregator_queue_len(len);
+    }
+    if let Some(token) = opts.authority_node_token {
+        builder = builder.authority_node_token(token);
+    }
+    if let Some(path) = opts.denylist_file {
+        builder = builder.denylist_file(path);
+    }
+    if let Some(path) = opts.allow_chain_file {
+        builder = builder.allowlist_file(path);
+    }
+    if let Some(token) = opts.admin_token {
+        builder = builder.admin_token(token);
+    }
+    if let Some(secret) = opts.shard_secret {
+        builder = builder.shard_secret(secret);
+    }
+    if let Some(port) = opts.feed_ndjson_port {
+        builder = builder.ndjson_feed_listen(std::net::SocketAddr::new(opts.socket.ip(), port));
     }
 
-    Response::builder()
-        // The version number here tells prometheus which version of the text format we're using:
-        .header(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")
-        .body(s.into())
-        .unwrap()
+    builder.spawn().await?.wait().await
 }
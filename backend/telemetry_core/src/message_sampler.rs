@@ -0,0 +1,185 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use common::internal_messages::ShardNodeId;
+use common::node_message::Payload;
+use serde::Serialize;
+
+/// Keeps a copy of some of the node messages that shards send us, so that they can be looked
+/// at while debugging. Sampling is off until it's enabled, and while it's off, checking
+/// whether to sample a message is a single atomic load.
+#[derive(Debug, Default)]
+pub struct MessageSampler {
+    enabled: AtomicBool,
+    inner: Mutex<SamplerInner>,
+}
+
+#[derive(Debug, Default)]
+struct SamplerInner {
+    every: u64,
+    capacity: usize,
+    seen: u64,
+    samples: VecDeque<SampledMessage>,
+}
+
+/// A node message that was sampled.
+#[derive(Debug, Clone, Serialize)]
+pub struct SampledMessage {
+    /// When we received the message, in ms since the unix epoch.
+    pub received_at: u64,
+    /// The address of the shard that sent it.
+    pub shard: SocketAddr,
+    /// The ID of the node that it's from, local to that shard.
+    pub local_id: ShardNodeId,
+    /// The message itself.
+    pub payload: serde_json::Value,
+}
+
+/// Whether we're sampling messages, and how.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SamplerStatus {
+    pub enabled: bool,
+    pub every: u64,
+    pub capacity: usize,
+    pub captured: usize,
+}
+
+impl MessageSampler {
+    /// Start keeping one in every `every` messages, and at most the latest `capacity` of
+    /// them. Anything captured beforehand is thrown away.
+    pub fn enable(&self, every: u64, capacity: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner = SamplerInner {
+            every: every.max(1),
+            capacity,
+            seen: 0,
+            samples: VecDeque::with_capacity(capacity.min(1024)),
+        };
+        self.enabled.store(capacity > 0, Ordering::Relaxed);
+    }
+
+    /// Stop sampling messages. Those captured so far are kept until sampling is enabled again.
+    pub fn disable(&self) {
+        self.enabled.store(false, Ordering::Relaxed);
+    }
+
+    /// Are messages being sampled? Check this before calling [`MessageSampler::record`].
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Keep a copy of this message if it's one that we should sample.
+    pub fn record(&self, shard: SocketAddr, local_id: ShardNodeId, payload: &Payload) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.seen += 1;
+        if !self.is_enabled() || !(inner.seen - 1).is_multiple_of(inner.every) {
+            return;
+        }
+
+        let payload = match serde_json::to_value(payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::warn!("Cannot sample node message: {e}");
+                return;
+            }
+        };
+        if inner.samples.len() == inner.capacity {
+            inner.samples.pop_front();
+        }
+        inner.samples.push_back(SampledMessage {
+            received_at: common::time::now(),
+            shard,
+            local_id,
+            payload,
+        });
+    }
+
+    /// The messages captured so far, oldest first.
+    pub fn samples(&self) -> Vec<SampledMessage> {
+        self.inner.lock().unwrap().samples.iter().cloned().collect()
+    }
+
+    /// Whether we're sampling messages, and how many we've captured.
+    pub fn status(&self) -> SamplerStatus {
+        let inner = self.inner.lock().unwrap();
+        SamplerStatus {
+            enabled: self.is_enabled(),
+            every: inner.every,
+            capacity: inner.capacity,
+            captured: inner.samples.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::node_types::Block;
+
+    fn payload(height: u64) -> Payload {
+        Payload::BlockImport(Block {
+            hash: Default::default(),
+            height,
+        })
+    }
+
+    fn heights(sampler: &MessageSampler) -> Vec<serde_json::Value> {
+        sampler
+            .samples()
+            .into_iter()
+            .map(|s| s.payload["BlockImport"]["height"].clone())
+            .collect()
+    }
+
+    #[test]
+    fn samples_are_only_kept_while_enabled() {
+        let sampler = MessageSampler::default();
+        let shard = "127.0.0.1:1234".parse().unwrap();
+        let id = ShardNodeId::from(1);
+
+        sampler.record(shard, id, &payload(1));
+        assert!(sampler.samples().is_empty());
+
+        sampler.enable(2, 3);
+        for height in 1..=10 {
+            sampler.record(shard, id, &payload(height));
+        }
+        // One in every two messages is kept, and only the latest three of those:
+        assert_eq!(heights(&sampler), [5, 7, 9]);
+
+        sampler.disable();
+        sampler.record(shard, id, &payload(11));
+        assert_eq!(heights(&sampler), [5, 7, 9]);
+        assert_eq!(
+            sampler.status(),
+            SamplerStatus {
+                enabled: false,
+                every: 2,
+                capacity: 3,
+                captured: 3
+            }
+        );
+
+        sampler.enable(1, 10);
+        assert!(sampler.samples().is_empty());
+    }
+}
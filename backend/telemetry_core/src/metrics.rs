@@ -0,0 +1,188 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! The core's metrics, for registering with a [`prometheus::Registry`] that's shared with
+//! whatever the core is embedded in, instead of serving them on our own "/metrics" endpoint.
+
+use crate::aggregator::{AggregatorSet, Metrics};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{IntGaugeVec, Opts};
+
+/// Obtains the value of one metric from the metrics that an aggregator reports.
+type MetricValue = fn(&Metrics) -> i64;
+
+/// The name and description of each metric, and how to obtain its value. These match the
+/// metrics served on "/metrics".
+const METRICS: &[(&str, &str, MetricValue)] = &[
+    (
+        "telemetry_core_connected_feeds",
+        "How many feeds are connected to the aggregator",
+        |m| m.connected_feeds as i64,
+    ),
+    (
+        "telemetry_core_connected_nodes",
+        "How many nodes the aggregator knows about",
+        |m| m.connected_nodes as i64,
+    ),
+    (
+        "telemetry_core_connected_shards",
+        "How many shards are connected to the aggregator",
+        |m| m.connected_shards as i64,
+    ),
+    (
+        "telemetry_core_chains_subscribed_to",
+        "How many chains feeds are subscribed to",
+        |m| m.chains_subscribed_to as i64,
+    ),
+    (
+        "telemetry_core_subscribed_feeds",
+        "How many feeds are subscribed to a chain",
+        |m| m.subscribed_feeds as i64,
+    ),
+    (
+        "telemetry_core_total_messages_to_feeds",
+        "How many messages are queued up to be sent to feeds",
+        |m| m.total_messages_to_feeds as i64,
+    ),
+    (
+        "telemetry_core_current_messages_to_aggregator",
+        "How many messages are queued up to be handled by the aggregator",
+        |m| m.current_messages_to_aggregator as i64,
+    ),
+    (
+        "telemetry_core_total_messages_to_aggregator",
+        "How many messages have been sent to the aggregator",
+        |m| m.total_messages_to_aggregator as i64,
+    ),
+    (
+        "telemetry_core_dropped_messages_to_aggregator",
+        "How many messages the aggregator has dropped because it was overwhelmed",
+        |m| m.dropped_messages_to_aggregator as i64,
+    ),
+    (
+        "telemetry_core_snapshots_built",
+        "How many chain snapshots have been built for subscribing feeds",
+        |m| m.snapshots_built as i64,
+    ),
+    (
+        "telemetry_core_snapshots_reused",
+        "How many times a cached chain snapshot has been reused",
+        |m| m.snapshots_reused as i64,
+    ),
+    (
+        "telemetry_core_nodes_rejected_for_too_many_chains",
+        "How many nodes have been rejected because too many chains were being tracked",
+        |m| m.nodes_rejected_for_too_many_chains as i64,
+    ),
+    (
+        "telemetry_core_abandoned_location_lookups",
+        "How many node location lookups have been abandoned because they waited too long",
+        |m| m.abandoned_location_lookups as i64,
+    ),
+    (
+        "telemetry_core_update_latency_samples",
+        "How many node updates were timed in the last latency window",
+        |m| m.update_latency.samples as i64,
+    ),
+    (
+        "telemetry_core_update_latency_p50_microseconds",
+        "The median time taken to handle a node update in the last latency window",
+        |m| m.update_latency.p50_us as i64,
+    ),
+    (
+        "telemetry_core_update_latency_p90_microseconds",
+        "The 90th percentile time taken to handle a node update in the last latency window",
+        |m| m.update_latency.p90_us as i64,
+    ),
+    (
+        "telemetry_core_update_latency_p99_microseconds",
+        "The 99th percentile time taken to handle a node update in the last latency window",
+        |m| m.update_latency.p99_us as i64,
+    ),
+    (
+        "telemetry_core_update_latency_max_microseconds",
+        "The longest time taken to handle a node update in the last latency window",
+        |m| m.update_latency.max_us as i64,
+    ),
+];
+
+/// Collects the latest metrics gathered from each aggregator whenever the registry that
+/// it's registered with is scraped. Each metric is labelled with the aggregator it's from.
+pub struct CoreMetrics {
+    aggregator: AggregatorSet,
+    gauges: Vec<(IntGaugeVec, MetricValue)>,
+    /// Reorgs are counted per chain, so are also labelled with the chain's genesis hash.
+    chain_reorgs: IntGaugeVec,
+}
+
+impl CoreMetrics {
+    pub fn new(aggregator: AggregatorSet) -> prometheus::Result<Self> {
+        let gauges = METRICS
+            .iter()
+            .map(|&(name, help, value)| {
+                let gauge = IntGaugeVec::new(Opts::new(name, help), &["aggregator"])?;
+                Ok((gauge, value))
+            })
+            .collect::<prometheus::Result<_>>()?;
+        let chain_reorgs = IntGaugeVec::new(
+            Opts::new(
+                "telemetry_core_chain_reorgs",
+                "How many best block reorgs have been seen on the chain",
+            ),
+            &["aggregator", "chain"],
+        )?;
+        Ok(CoreMetrics {
+            aggregator,
+            gauges,
+            chain_reorgs,
+        })
+    }
+}
+
+impl Collector for CoreMetrics {
+    fn desc(&self) -> Vec<&Desc> {
+        self.gauges
+            .iter()
+            .map(|(gauge, _)| gauge)
+            .chain([&self.chain_reorgs])
+            .flat_map(|gauge| gauge.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        // Chains come and go, so start afresh rather than reporting chains that have gone:
+        self.chain_reorgs.reset();
+        for (idx, metrics) in self.aggregator.latest_metrics().iter().enumerate() {
+            let idx = idx.to_string();
+            for (gauge, value) in &self.gauges {
+                gauge.with_label_values(&[&idx]).set(value(metrics));
+            }
+            for (genesis_hash, reorgs) in &metrics.chain_reorgs {
+                let chain = format!("{genesis_hash:#x}");
+                self.chain_reorgs
+                    .with_label_values(&[&idx, &chain])
+                    .set(*reorgs as i64);
+            }
+        }
+        self.gauges
+            .iter()
+            .map(|(gauge, _)| gauge)
+            .chain([&self.chain_reorgs])
+            .flat_map(|gauge| gauge.collect())
+            .collect()
+    }
+}
@@ -0,0 +1,328 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+/*!
+A read-only feed served over plain TCP rather than a websocket, for tooling that would rather
+consume newline delimited JSON. Consumers are sent the same messages as websocket feeds, one
+`[action, payload]` array per line.
+
+Consumers can't send commands. Instead, they can send a single line when they connect, in the
+form of a query string like `chain=CHAIN_HASH&events=block,finalized`, to subscribe to a chain.
+Consumers that don't are sent what every feed is, which includes the list of chains.
+*/
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::aggregator::{AggregatorSet, FeedChain, FromFeedWebsocket, ToFeedWebsocket};
+use crate::feed_limit::FeedLimit;
+use crate::feed_message::{self, FeedProtocol};
+use common::http_utils;
+use common::ready_chunks_all::ReadyChunksAll;
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+
+/// The longest query that a consumer can send when it connects, in bytes.
+const MAX_QUERY_BYTES: u64 = 1024;
+
+/// How should the NDJSON feed handle the connections made to it?
+#[derive(Clone, Copy, Debug)]
+pub struct NdjsonFeedOpts {
+    /// How long to wait for messages to be sent to a consumer before giving up on it.
+    pub feed_timeout: Duration,
+    /// How many messages can be waiting to be sent to a consumer before it's disconnected.
+    /// `None` means that there's no limit.
+    pub feed_channel_capacity: Option<usize>,
+    /// Enable TCP keepalive on accepted connections, probing them after they've been idle
+    /// for this long. `None` leaves it off.
+    pub tcp_keepalive: Option<Duration>,
+}
+
+/// Bind to the address given. This returns the address that we're listening on, and a future
+/// which accepts connections until `shutdown` resolves. Connections beyond the `feed_limit`
+/// are closed as soon as they're accepted.
+pub fn bind_ndjson_feed(
+    socket_addr: SocketAddr,
+    aggregator: AggregatorSet,
+    feed_limit: FeedLimit,
+    opts: NdjsonFeedOpts,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<(SocketAddr, impl Future<Output = ()>)> {
+    let listener = std::net::TcpListener::bind(socket_addr)?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let local_addr = listener.local_addr()?;
+    log::info!("listening for NDJSON feeds on tcp://{local_addr}");
+
+    Ok((local_addr, async move {
+        tokio::pin!(shutdown);
+        loop {
+            let (stream, addr) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        log::warn!("Cannot accept NDJSON feed connection: {e}");
+                        continue;
+                    }
+                },
+                _ = &mut shutdown => break,
+            };
+            let permit = match feed_limit.try_acquire() {
+                Some(permit) => permit,
+                None => {
+                    log::warn!("Refusing NDJSON feed connection from {addr:?}: Too many feeds");
+                    continue;
+                }
+            };
+            if let Err(e) = http_utils::set_tcp_opts(&stream, opts.tcp_keepalive) {
+                log::warn!("Cannot configure NDJSON feed connection from {addr:?}: {e}");
+            }
+
+            let aggregator = aggregator.clone();
+            tokio::spawn(async move {
+                // The feed is counted until this task ends:
+                let _permit = permit;
+                log::info!("Opening NDJSON feed connection from {addr:?}");
+                let (_feed_id, tx_to_aggregator) = aggregator.subscribe_feed();
+                let (mut tx_to_aggregator, mut write_half) =
+                    handle_ndjson_feed_connection(stream, tx_to_aggregator, opts).await;
+                log::info!("Closing NDJSON feed connection from {addr:?}");
+                // Tell the aggregator that this connection has closed, so it can tidy up.
+                let _ = tx_to_aggregator.send(FromFeedWebsocket::Disconnected).await;
+                let _ = write_half.shutdown().await;
+            });
+        }
+    }))
+}
+
+/// This handles the messages going to an NDJSON feed connection, and the query it sends us.
+async fn handle_ndjson_feed_connection<S>(
+    stream: TcpStream,
+    mut tx_to_aggregator: S,
+    opts: NdjsonFeedOpts,
+) -> (S, OwnedWriteHalf)
+where
+    S: futures::Sink<FromFeedWebsocket, Error = anyhow::Error> + Unpin + Send + 'static,
+{
+    let (read_half, mut write_half) = stream.into_split();
+
+    // As with websocket feeds, the aggregator never waits for room in this channel:
+    let (tx_to_feed_conn, rx_from_aggregator) = match opts.feed_channel_capacity {
+        Some(capacity) => flume::bounded(capacity),
+        None => flume::unbounded(),
+    };
+    let mut rx_from_aggregator_chunks = ReadyChunksAll::new(rx_from_aggregator.into_stream());
+
+    // Tell the aggregator about this new connection, and give it a way to send messages to us:
+    let init_msg = FromFeedWebsocket::Initialize {
+        channel: tx_to_feed_conn,
+        admin: false,
+        protocol: FeedProtocol::V2,
+    };
+    if let Err(e) = tx_to_aggregator.send(init_msg).await {
+        log::error!("Error sending message to aggregator: {e}");
+        return (tx_to_aggregator, write_half);
+    }
+
+    // Channels to notify each task if the other closes:
+    let (recv_closer_tx, mut recv_closer_rx) = tokio::sync::oneshot::channel::<()>();
+    let (send_closer_tx, mut send_closer_rx) = tokio::sync::oneshot::channel::<()>();
+
+    // Receive the query from the consumer:
+    let recv_handle = tokio::spawn(async move {
+        tokio::select! {
+            _ = receive_query(read_half, &mut tx_to_aggregator) => {},
+            _ = &mut recv_closer_rx => {}
+        }
+
+        drop(send_closer_tx); // Kill the send task if this recv task ends
+        tx_to_aggregator
+    });
+
+    // Send messages to the consumer:
+    let send_handle = tokio::spawn(async move {
+        let mut buffer = Vec::new();
+        loop {
+            let msgs = tokio::select! {
+                msgs = rx_from_aggregator_chunks.next() => msgs,
+                _ = &mut send_closer_rx => { break }
+            };
+
+            // End the loop when connection from aggregator ends:
+            let msgs = match msgs {
+                Some(msgs) => msgs,
+                None => break,
+            };
+
+            buffer.clear();
+            for msg in msgs {
+                match msg {
+                    ToFeedWebsocket::Bytes(bytes) => {
+                        feed_message::write_ndjson(&bytes, &mut buffer)
+                    }
+                }
+            }
+            if buffer.is_empty() {
+                continue;
+            }
+
+            // If the consumer is too slow to receive the messages, we'll drop it.
+            match tokio::time::timeout(opts.feed_timeout, write_half.write_all(&buffer)).await {
+                Err(_) => {
+                    log::debug!("Closing NDJSON feed connection that was too slow to keep up");
+                    break;
+                }
+                Ok(Err(e)) => {
+                    log::debug!("Closing NDJSON feed connection due to error sending data: {e}");
+                    break;
+                }
+                Ok(Ok(())) => {}
+            }
+        }
+
+        drop(recv_closer_tx); // Kill the recv task if this send task ends
+        write_half
+    });
+
+    // If our send/recv tasks are stopped (if one of them dies, they both will),
+    // collect the bits we need to hand back from them:
+    let write_half = send_handle.await.unwrap();
+    let tx_to_aggregator = recv_handle.await.unwrap();
+
+    (tx_to_aggregator, write_half)
+}
+
+/// Wait for the query that a consumer can send when it connects, and subscribe it to the chain
+/// that it asks for. This only returns if the connection should be closed; consumers that stop
+/// sending to us (or don't send anything at all) are still sent messages until they go away.
+async fn receive_query<S>(read_half: OwnedReadHalf, tx_to_aggregator: &mut S)
+where
+    S: futures::Sink<FromFeedWebsocket, Error = anyhow::Error> + Unpin,
+{
+    let mut reader = BufReader::new(read_half).take(MAX_QUERY_BYTES);
+    let mut query = Vec::new();
+    if let Err(e) = reader.read_until(b'\n', &mut query).await {
+        log::debug!("Closing NDJSON feed connection: Failed to receive query: {e}");
+        return;
+    }
+    if reader.limit() == 0 && query.last() != Some(&b'\n') {
+        log::warn!("Closing NDJSON feed connection: Query too large");
+        return;
+    }
+
+    // Queries that we can't make sense of are answered with an error:
+    let cmd = std::str::from_utf8(&query)
+        .map_err(anyhow::Error::from)
+        .and_then(parse_query);
+    let cmd = match cmd {
+        Ok(cmd) => cmd,
+        Err(e) => Some(FromFeedWebsocket::InvalidCommand {
+            error: e.to_string().into(),
+        }),
+    };
+
+    // Consumers pick the events that they want up front, so finality messages aren't held
+    // back from them as they are from other feeds unless asked for:
+    let finality = match &cmd {
+        Some(FromFeedWebsocket::Subscribe {
+            chain: FeedChain::GenesisHash(chain),
+            ..
+        }) => Some(FromFeedWebsocket::SendFinality { chain: *chain }),
+        _ => None,
+    };
+    for cmd in finality.into_iter().chain(cmd) {
+        if let Err(e) = tx_to_aggregator.send(cmd).await {
+            log::error!("Failed to send message to aggregator; closing NDJSON feed: {e}");
+            return;
+        }
+    }
+
+    // Anything else that the consumer sends is ignored:
+    let mut reader = reader.into_inner();
+    if tokio::io::copy(&mut reader, &mut tokio::io::sink())
+        .await
+        .is_err()
+    {
+        return;
+    }
+    futures::future::pending().await
+}
+
+/// Work out which chain a consumer wants to subscribe to from the query it sent when it
+/// connected, as in `chain=CHAIN_HASH&events=block,finalized`. An empty query doesn't
+/// subscribe to anything.
+fn parse_query(query: &str) -> anyhow::Result<Option<FromFeedWebsocket>> {
+    let query = query.trim().trim_start_matches('?');
+    if query.is_empty() {
+        return Ok(None);
+    }
+
+    let mut chain = None;
+    let mut options = String::new();
+    for param in query.split('&') {
+        match param.split_once('=') {
+            Some(("chain", value)) => chain = Some(value),
+            Some(("events", value)) => options = format!(":events={value}"),
+            _ => anyhow::bail!(
+                "Query parameter {param} not recognised; expected 'chain=CHAIN_HASH' or 'events=EVENTS'"
+            ),
+        }
+    }
+    let chain =
+        chain.ok_or_else(|| anyhow::anyhow!("Expecting a chain, as in `chain=CHAIN_HASH`"))?;
+    let cmd = format!("subscribe:{chain}{options}").parse()?;
+    if let FromFeedWebsocket::Subscribe {
+        chain: FeedChain::Label(_),
+        ..
+    } = cmd
+    {
+        anyhow::bail!("Chains are identified by their genesis hash, as in `chain=CHAIN_HASH`");
+    }
+    Ok(Some(cmd))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::feed_message::FeedEvents;
+    use common::node_types::BlockHash;
+
+    #[test]
+    fn queries_subscribe_to_chains() {
+        let hash = BlockHash::from_low_u64_be(1);
+
+        assert!(matches!(parse_query(""), Ok(None)));
+        assert!(matches!(parse_query("\n"), Ok(None)));
+        assert!(matches!(
+            parse_query(&format!("chain={hash:#x}\n")),
+            Ok(Some(FromFeedWebsocket::Subscribe { chain, events, .. }))
+                if chain == hash.into() && events == FeedEvents::ALL
+        ));
+        assert!(matches!(
+            parse_query(&format!("?events=block,finalized&chain=genesis:{hash:#x}")),
+            Ok(Some(FromFeedWebsocket::Subscribe { chain, events, .. }))
+                if chain == hash.into() && events == "block,finalized".parse().unwrap()
+        ));
+
+        assert!(parse_query("events=block").is_err());
+        assert!(parse_query(&format!("chain={hash:#x}&wibble=1")).is_err());
+        assert!(parse_query("chain=0xnothex").is_err());
+        assert!(parse_query("chain=label:Polkadot").is_err());
+    }
+}
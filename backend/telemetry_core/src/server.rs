@@ -0,0 +1,1005 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+use crate::aggregator::{
+    AggregatorSet, FromFeedWebsocket, FromShardWebsocket, ToFeedWebsocket, ToShardWebsocket,
+};
+use crate::build_info::BuildInfo;
+use crate::feed_limit::FeedLimit;
+use crate::feed_message::{self, FeedMessageBatch, FeedProtocol};
+use crate::message_sampler::MessageSampler;
+use bincode::Options;
+use common::http_utils;
+use common::internal_messages;
+use common::latency::LatencySampler;
+use common::ready_chunks_all::ReadyChunksAll;
+use futures::{SinkExt, StreamExt};
+use hyper::{Method, Response};
+
+/// How should the server handle the connections made to it?
+#[derive(Clone, Debug)]
+pub struct ServerOpts {
+    /// How long to wait for a batch of messages to be sent to a feed before giving up on it.
+    pub feed_timeout: Duration,
+    /// How long a single write to a feed, including closing it, can take before the feed is
+    /// assumed to be dead. `None` means that only `feed_timeout` applies.
+    pub feed_send_timeout: Option<Duration>,
+    /// How to batch up messages sent to feeds.
+    pub feed_flush: FeedFlushOpts,
+    /// How many messages can be waiting to be sent to a feed before it's disconnected.
+    /// `None` means that there's no limit.
+    pub feed_channel_capacity: Option<usize>,
+    /// Serve the "/admin_feed" endpoint alongside "/feed"?
+    pub admin_feed: bool,
+    /// The largest command that a feed can send to us before it's disconnected.
+    pub max_feed_command_bytes: usize,
+    /// Accept at most this many new connections per second. `None` means that there's no limit.
+    pub max_accepts_per_sec: Option<u32>,
+    /// How many connections the OS can queue up for us to accept. `None` uses the default.
+    pub listen_backlog: Option<u32>,
+    /// How many accepted connections can be in the middle of being handled at once before
+    /// new ones are told to try later. `None` means that there's no limit.
+    pub max_pending_connections: Option<usize>,
+    /// Enable TCP keepalive on accepted connections, probing them after they've been idle
+    /// for this long. `None` leaves it off.
+    pub tcp_keepalive: Option<Duration>,
+    /// Time one in every this many node updates from shards. Zero times none of them.
+    pub latency_sample_every: u64,
+    /// Serve the "/admin/*" HTTP endpoints to requests bearing this token.
+    pub admin_token: Option<Arc<str>>,
+    /// Only accept shards that present this secret. Any shard is accepted if it's `None`.
+    pub shard_secret: Option<Arc<str>>,
+    /// Serve our metrics on "/metrics"? They're registered with a registry instead otherwise.
+    pub serve_metrics: bool,
+    /// Feeds beyond this limit are refused.
+    pub feed_limit: FeedLimit,
+}
+
+/// Bind to the address given and declare our routes. This returns the address that we're
+/// listening on, and a future which serves requests until `shutdown` resolves.
+pub fn bind_server(
+    socket_addr: SocketAddr,
+    aggregator: AggregatorSet,
+    opts: ServerOpts,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<(SocketAddr, impl Future<Output = anyhow::Result<()>>)> {
+    let ServerOpts {
+        feed_timeout,
+        feed_send_timeout,
+        feed_flush,
+        feed_channel_capacity,
+        admin_feed,
+        max_feed_command_bytes,
+        max_accepts_per_sec,
+        listen_backlog,
+        max_pending_connections,
+        tcp_keepalive,
+        latency_sample_every,
+        admin_token,
+        shard_secret,
+        serve_metrics,
+        feed_limit,
+    } = opts;
+    let message_sampler = Arc::new(MessageSampler::default());
+    let feed_ws_opts = http_utils::WsUpgradeOpts {
+        protocols: feed_message::FEED_SUBPROTOCOLS,
+        max_message_size: Some(max_feed_command_bytes),
+    };
+
+    http_utils::bind_server_with_opts(
+        socket_addr,
+        http_utils::ServerOpts {
+            max_accepts_per_sec,
+            tcp_keepalive,
+            listen_backlog,
+            max_pending_connections,
+        },
+        move |addr, req| {
+            let aggregator = aggregator.clone();
+            let admin_token = admin_token.clone();
+            let shard_secret = shard_secret.clone();
+            let message_sampler = message_sampler.clone();
+            let feed_limit = feed_limit.clone();
+            async move {
+                match (req.method(), req.uri().path().trim_end_matches('/')) {
+                    // Check that the server is up and running:
+                    (&Method::GET, "/health" | "/healthz") => Ok(Response::new("OK".into())),
+                    // Check that we're ready to take feeds and shards; we aren't until the
+                    // aggregators are live, or while we have no room for more feeds:
+                    (&Method::GET, "/readyz") => Ok(if !aggregator.is_live() {
+                        basic_response(503, "Aggregators are not running")
+                    } else if !feed_limit.has_room() {
+                        basic_response(503, "Too many feeds are connected")
+                    } else {
+                        Response::new("OK".into())
+                    }),
+                    // Report the version, commit and build time of this core:
+                    (&Method::GET, "/version") => Ok(return_build_info()),
+                    // Subscribe to feed messages. Admin feeds (if enabled), and feeds bearing
+                    // the admin token, can also ask for things that other feeds can't:
+                    (&Method::GET, path @ ("/feed" | "/admin_feed"))
+                        if path == "/feed" || admin_feed =>
+                    {
+                        let path = if path == "/admin_feed" {
+                            "/admin_feed"
+                        } else {
+                            "/feed"
+                        };
+                        let admin = path == "/admin_feed"
+                            || admin_token
+                                .as_deref()
+                                .is_some_and(|token| is_bearer_of(&req, token));
+                        let permit = match feed_limit.try_acquire() {
+                            Some(permit) => permit,
+                            None => {
+                                log::warn!(
+                                    "Refusing {path} connection from {:?}: Too many feeds",
+                                    addr
+                                );
+                                return Ok(basic_response(503, "Too many feeds are connected"));
+                            }
+                        };
+                        log::info!("Opening {path} connection from {:?}", addr);
+                        Ok(http_utils::upgrade_to_websocket_with_opts(
+                            req,
+                            feed_ws_opts,
+                            move |ws_send, ws_recv, protocol| async move {
+                                // The feed is counted until this connection is closed:
+                                let _permit = permit;
+                                let protocol = FeedProtocol::from_subprotocol(protocol);
+                                let (feed_id, tx_to_aggregator) = aggregator.subscribe_feed();
+                                let (mut tx_to_aggregator, mut ws_send) =
+                                    handle_feed_websocket_connection(
+                                        ws_send,
+                                        ws_recv,
+                                        tx_to_aggregator,
+                                        feed_timeout,
+                                        feed_send_timeout,
+                                        feed_flush,
+                                        feed_channel_capacity,
+                                        feed_id,
+                                        admin,
+                                        protocol,
+                                    )
+                                    .await;
+                                log::info!("Closing {path} connection from {:?}", addr);
+                                // Tell the aggregator that this connection has closed, so it can tidy up.
+                                let _ =
+                                    tx_to_aggregator.send(FromFeedWebsocket::Disconnected).await;
+                                // A dead connection could otherwise keep us waiting forever:
+                                match feed_send_timeout {
+                                    Some(timeout) => {
+                                        let _ =
+                                            tokio::time::timeout(timeout, ws_send.close()).await;
+                                    }
+                                    None => {
+                                        let _ = ws_send.close().await;
+                                    }
+                                }
+                            },
+                        ))
+                    }
+                    // Subscribe to shard messages:
+                    (&Method::GET, "/shard_submit") => {
+                        if let Some(secret) = shard_secret {
+                            if !is_bearer_of(&req, &secret) {
+                                log::warn!("Refusing /shard_submit connection from {:?}: Wrong or missing shard secret", addr);
+                                return Ok(basic_response(401, "Unauthorized"));
+                            }
+                        }
+                        Ok(http_utils::upgrade_to_websocket(
+                            req,
+                            move |ws_send, ws_recv| async move {
+                                log::info!("Opening /shard_submit connection from {:?}", addr);
+                                let tx_to_aggregator = aggregator.subscribe_shard();
+                                let (mut tx_to_aggregator, mut ws_send) =
+                                    handle_shard_websocket_connection(
+                                        ws_send,
+                                        ws_recv,
+                                        tx_to_aggregator,
+                                        latency_sample_every,
+                                        addr,
+                                        message_sampler,
+                                    )
+                                    .await;
+                                log::info!("Closing /shard_submit connection from {:?}", addr);
+                                // Tell the aggregator that this connection has closed, so it can tidy up.
+                                let _ = tx_to_aggregator
+                                    .send(FromShardWebsocket::Disconnected)
+                                    .await;
+                                let _ = ws_send.close().await;
+                            },
+                        ))
+                    }
+                    // Return structured details about our current state, or change how we
+                    // log and sample messages (if enabled):
+                    (_, path) if path.starts_with("/admin/") => match admin_token {
+                        Some(token) => {
+                            Ok(
+                                handle_admin_request(aggregator, &message_sampler, req, &token)
+                                    .await,
+                            )
+                        }
+                        None => Ok(basic_response(404, "Not found")),
+                    },
+                    // Return metrics in a prometheus-friendly text based format:
+                    (&Method::GET, "/metrics") if serve_metrics => {
+                        Ok(return_prometheus_metrics(aggregator).await)
+                    }
+                    // 404 for anything else:
+                    _ => Ok(basic_response(404, "Not found")),
+                }
+            }
+        },
+        shutdown,
+    )
+}
+
+/// This handles messages coming to/from a shard connection
+async fn handle_shard_websocket_connection<S>(
+    mut ws_send: http_utils::WsSender,
+    mut ws_recv: http_utils::WsReceiver,
+    mut tx_to_aggregator: S,
+    latency_sample_every: u64,
+    shard_addr: SocketAddr,
+    message_sampler: Arc<MessageSampler>,
+) -> (S, http_utils::WsSender)
+where
+    S: futures::Sink<FromShardWebsocket, Error = anyhow::Error> + Unpin + Send + 'static,
+{
+    // Make sure that we speak the same internal protocol as the shard before anything else:
+    if let Err(e) = negotiate_shard_protocol(&mut ws_send, &mut ws_recv).await {
+        log::error!("Rejecting shard connection: {e}");
+        return (tx_to_aggregator, ws_send);
+    }
+
+    let (tx_to_shard_conn, rx_from_aggregator) = flume::unbounded();
+
+    // Tell the aggregator about this new connection, and give it a way to send messages to us:
+    let init_msg = FromShardWebsocket::Initialize {
+        channel: tx_to_shard_conn,
+    };
+    if let Err(e) = tx_to_aggregator.send(init_msg).await {
+        log::error!("Error sending message to aggregator: {}", e);
+        return (tx_to_aggregator, ws_send);
+    }
+
+    // Channels to notify each loop if the other closes:
+    let (recv_closer_tx, mut recv_closer_rx) = tokio::sync::oneshot::channel::<()>();
+    let (send_closer_tx, mut send_closer_rx) = tokio::sync::oneshot::channel::<()>();
+
+    // Receive messages from a shard:
+    let recv_handle = tokio::spawn(async move {
+        let mut latency_sampler = LatencySampler::new(latency_sample_every);
+        loop {
+            let mut bytes = Vec::new();
+
+            // Receive a message, or bail if closer called. We don't care about cancel safety;
+            // if we're halfway through receiving a message, no biggie since we're closing the
+            // connection anyway.
+            let msg_info = tokio::select! {
+                msg_info = ws_recv.receive_data(&mut bytes) => msg_info,
+                _ = &mut recv_closer_rx => break
+            };
+
+            // Handle the socket closing, or errors receiving the message.
+            if let Err(soketto::connection::Error::Closed) = msg_info {
+                break;
+            }
+            if let Err(e) = msg_info {
+                log::error!("Shutting down websocket connection: Failed to receive data: {e}");
+                break;
+            }
+
+            let msg: internal_messages::FromShardAggregator =
+                match bincode::options().deserialize(&bytes) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        log::error!("Failed to deserialize message from shard; booting it: {e}");
+                        break;
+                    }
+                };
+
+            // Convert and send to the aggregator:
+            let aggregator_msg = match msg {
+                internal_messages::FromShardAggregator::AddNode {
+                    ip,
+                    node,
+                    local_id,
+                    genesis_hash,
+                    shard_id,
+                    token,
+                } => FromShardWebsocket::Add {
+                    ip,
+                    node,
+                    genesis_hash,
+                    local_id,
+                    shard_id,
+                    token,
+                },
+                internal_messages::FromShardAggregator::UpdateNode {
+                    payload,
+                    local_id,
+                    sent_at,
+                } => {
+                    if message_sampler.is_enabled() {
+                        message_sampler.record(shard_addr, local_id, &payload);
+                    }
+                    FromShardWebsocket::Update {
+                        local_id,
+                        payload,
+                        received_at: latency_sampler.sample(),
+                        sent_at,
+                    }
+                }
+                internal_messages::FromShardAggregator::ClockSkew { local_id, skew_ms } => {
+                    FromShardWebsocket::ClockSkew { local_id, skew_ms }
+                }
+                internal_messages::FromShardAggregator::RemoveNode { local_id, reason } => {
+                    FromShardWebsocket::Remove {
+                        local_id,
+                        reason: reason.into(),
+                    }
+                }
+                internal_messages::FromShardAggregator::Draining { draining } => {
+                    FromShardWebsocket::Draining { draining }
+                }
+            };
+
+            if let Err(e) = tx_to_aggregator.send(aggregator_msg).await {
+                log::error!("Failed to send message to aggregator; closing shard: {e}");
+                break;
+            }
+        }
+
+        drop(send_closer_tx); // Kill the send task if this recv task ends
+        tx_to_aggregator
+    });
+
+    // Send messages to the shard:
+    let send_handle = tokio::spawn(async move {
+        loop {
+            let msg = tokio::select! {
+                msg = rx_from_aggregator.recv_async() => msg,
+                _ = &mut send_closer_rx => { break }
+            };
+
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(flume::RecvError::Disconnected) => break,
+            };
+
+            let internal_msg = match msg {
+                ToShardWebsocket::Mute { local_id, reason } => {
+                    internal_messages::FromTelemetryCore::Mute { local_id, reason }
+                }
+            };
+
+            let bytes = bincode::options()
+                .serialize(&internal_msg)
+                .expect("message to shard should serialize");
+
+            if let Err(e) = ws_send.send_binary(bytes).await {
+                log::error!("Failed to send message to aggregator; closing shard: {e}")
+            }
+            if let Err(e) = ws_send.flush().await {
+                log::error!("Failed to flush message to aggregator; closing shard: {e}")
+            }
+        }
+
+        drop(recv_closer_tx); // Kill the recv task if this send task ends
+        ws_send
+    });
+
+    // If our send/recv tasks are stopped (if one of them dies, they both will),
+    // collect the bits we need to hand back from them:
+    let ws_send = send_handle.await.unwrap();
+    let tx_to_aggregator = recv_handle.await.unwrap();
+
+    // loop ended; give socket back to parent:
+    (tx_to_aggregator, ws_send)
+}
+
+/// Wait for a shard to tell us which version of the internal protocol it speaks, and
+/// tell it whether we speak the same version. Returns an error if we don't.
+async fn negotiate_shard_protocol(
+    ws_send: &mut http_utils::WsSender,
+    ws_recv: &mut http_utils::WsReceiver,
+) -> anyhow::Result<()> {
+    let mut bytes = Vec::new();
+    ws_recv.receive_data(&mut bytes).await?;
+
+    let handshake: internal_messages::Handshake = bincode::options()
+        .deserialize(&bytes)
+        .map_err(|e| anyhow::anyhow!("Failed to deserialize handshake from shard: {e}"))?;
+
+    let response = internal_messages::HandshakeResponse::respond_to(
+        handshake,
+        internal_messages::PROTOCOL_VERSION,
+    );
+    let response_bytes = bincode::options()
+        .serialize(&response)
+        .expect("handshake response should serialize");
+    ws_send.send_binary(response_bytes).await?;
+    ws_send.flush().await?;
+
+    response.into_result(handshake.protocol_version)?;
+    Ok(())
+}
+
+/// This handles messages coming from a feed connection
+#[allow(clippy::too_many_arguments)]
+async fn handle_feed_websocket_connection<S>(
+    mut ws_send: http_utils::WsSender,
+    mut ws_recv: http_utils::WsReceiver,
+    mut tx_to_aggregator: S,
+    feed_timeout: Duration,
+    feed_send_timeout: Option<Duration>,
+    feed_flush: FeedFlushOpts,
+    feed_channel_capacity: Option<usize>,
+    _feed_id: u64, // <- can be useful for debugging purposes.
+    admin: bool,
+    protocol: FeedProtocol,
+) -> (S, http_utils::WsSender)
+where
+    S: futures::Sink<FromFeedWebsocket, Error = anyhow::Error> + Unpin + Send + 'static,
+{
+    // The aggregator never waits for room in this channel, so that slow feeds don't block its
+    // progress. If it's bounded and fills up, the aggregator drops its end and the feed is closed.
+    let (tx_to_feed_conn, rx_from_aggregator) = match feed_channel_capacity {
+        Some(capacity) => flume::bounded(capacity),
+        None => flume::unbounded(),
+    };
+
+    // `Receiver::into_stream()` is currently problematic at the time of writing
+    // (see https://github.com/zesterer/flume/issues/88). If this stream is polled lots
+    // and isn't ready, it'll leak memory. In this case, since we only select from it or
+    // a close channel, we shouldn't poll the thing more than once before it's ready (and
+    // when it's ready, it cleans up after itself properly). So, I hope it won't leak!
+    let mut rx_from_aggregator_chunks = ReadyChunksAll::new(rx_from_aggregator.into_stream());
+
+    // Tell the aggregator about this new connection, and give it a way to send messages to us:
+    let init_msg = FromFeedWebsocket::Initialize {
+        channel: tx_to_feed_conn,
+        admin,
+        protocol,
+    };
+    if let Err(e) = tx_to_aggregator.send(init_msg).await {
+        log::error!("Error sending message to aggregator: {e}");
+        return (tx_to_aggregator, ws_send);
+    }
+
+    // Channels to notify each loop if the other closes:
+    let (recv_closer_tx, mut recv_closer_rx) = tokio::sync::oneshot::channel::<()>();
+    let (send_closer_tx, mut send_closer_rx) = tokio::sync::oneshot::channel::<()>();
+
+    // Receive messages from the feed:
+    let recv_handle = tokio::spawn(async move {
+        loop {
+            let mut bytes = Vec::new();
+            // Receive a message, or bail if closer called. We don't care about cancel safety;
+            // if we're halfway through receiving a message, no biggie since we're closing the
+            // connection anyway.
+            let msg_info = tokio::select! {
+                msg_info = ws_recv.receive_data(&mut bytes) => msg_info,
+                _ = &mut recv_closer_rx => { break }
+            };
+
+            // Handle the socket closing, or errors receiving the message.
+            if let Err(soketto::connection::Error::Closed) = msg_info {
+                break;
+            }
+            if let Err(e) = &msg_info {
+                if is_message_too_large(e) {
+                    log::warn!("Shutting down feed connection: Command too large: {e}");
+                } else {
+                    log::error!("Shutting down websocket connection: Failed to receive data: {e}");
+                }
+                break;
+            }
+
+            // We ignore all but valid UTF8 text messages from the frontend:
+            let text = match String::from_utf8(bytes) {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+
+            // Parse the message into a command we understand and send it to the aggregator:
+            // Commands that we can't make sense of are answered with an error:
+            let cmd = match FromFeedWebsocket::from_str(&text) {
+                Ok(cmd) => cmd,
+                Err(e) => {
+                    log::debug!("Invalid command '{text}' from the frontend: {e}");
+                    FromFeedWebsocket::InvalidCommand {
+                        error: e.to_string().into(),
+                    }
+                }
+            };
+            if let Err(e) = tx_to_aggregator.send(cmd).await {
+                log::error!("Failed to send message to aggregator; closing feed: {e}");
+                break;
+            }
+        }
+
+        drop(send_closer_tx); // Kill the send task if this recv task ends
+        tx_to_aggregator
+    });
+
+    // Send messages to the feed:
+    let send_handle = tokio::spawn(async move {
+        'outer: loop {
+            let msgs = tokio::select! {
+                msgs = rx_from_aggregator_chunks.next() => msgs,
+                _ = &mut send_closer_rx => { break }
+            };
+
+            // End the loop when connection from aggregator ends:
+            let msgs = match msgs {
+                Some(msgs) => msgs,
+                None => break,
+            };
+
+            // Keep gathering messages until the flush interval has passed since we received
+            // the first of them, or we've gathered enough that we should send them right away.
+            // Then, we send them all to the feed in one frame.
+            let mut batch = FeedMessageBatch::new();
+            push_to_batch(&mut batch, msgs);
+
+            let flush_deadline = tokio::time::sleep_until(Instant::now() + feed_flush.interval);
+            tokio::pin!(flush_deadline);
+            let mut aggregator_closed = false;
+            while batch.num_bytes() < feed_flush.size {
+                tokio::select! {
+                    msgs = rx_from_aggregator_chunks.next() => match msgs {
+                        Some(msgs) => push_to_batch(&mut batch, msgs),
+                        None => {
+                            aggregator_closed = true;
+                            break;
+                        }
+                    },
+                    _ = &mut flush_deadline => break,
+                    _ = &mut send_closer_rx => break 'outer,
+                }
+            }
+
+            let bytes = match batch.into_finalized() {
+                Some(bytes) => bytes,
+                None if aggregator_closed => break,
+                None => continue,
+            };
+
+            // If the feed is too slow to receive the current batch of messages, we'll drop it.
+            // Each write is given no longer than the send timeout, if there is one:
+            let message_send_deadline = Instant::now() + feed_timeout;
+            let write_deadline = || match feed_send_timeout {
+                Some(timeout) => message_send_deadline.min(Instant::now() + timeout),
+                None => message_send_deadline,
+            };
+
+            match tokio::time::timeout_at(write_deadline(), ws_send.send_binary(&bytes)).await {
+                Err(_) => {
+                    log::debug!("Closing feed websocket that was too slow to keep up (too slow to send messages)");
+                    break;
+                }
+                Ok(Err(soketto::connection::Error::Closed)) => {
+                    break;
+                }
+                Ok(Err(e)) => {
+                    log::debug!("Closing feed websocket due to error sending data: {}", e);
+                    break;
+                }
+                Ok(_) => {}
+            }
+
+            match tokio::time::timeout_at(write_deadline(), ws_send.flush()).await {
+                Err(_) => {
+                    log::debug!("Closing feed websocket that was too slow to keep up (too slow to flush messages)");
+                    break;
+                }
+                Ok(Err(soketto::connection::Error::Closed)) => {
+                    break;
+                }
+                Ok(Err(e)) => {
+                    log::debug!("Closing feed websocket due to error flushing data: {}", e);
+                    break;
+                }
+                Ok(_) => {}
+            }
+
+            if aggregator_closed {
+                break;
+            }
+        }
+
+        drop(recv_closer_tx); // Kill the recv task if this send task ends
+        ws_send
+    });
+
+    // If our send/recv tasks are stopped (if one of them dies, they both will),
+    // collect the bits we need to hand back from them:
+    let ws_send = send_handle.await.unwrap();
+    let tx_to_aggregator = recv_handle.await.unwrap();
+
+    // loop ended; give socket back to parent:
+    (tx_to_aggregator, ws_send)
+}
+
+/// How should messages be batched up before they are sent to feeds?
+#[derive(Clone, Copy, Debug)]
+pub struct FeedFlushOpts {
+    /// Send messages that are ready within this long of the first one together.
+    pub interval: Duration,
+    /// Send messages right away once they add up to this many bytes.
+    pub size: usize,
+}
+
+/// Add messages bound for a feed to a batch of messages to be sent to it.
+fn push_to_batch(batch: &mut FeedMessageBatch, msgs: Vec<ToFeedWebsocket>) {
+    for msg in msgs {
+        match msg {
+            ToFeedWebsocket::Bytes(bytes) => batch.push(bytes),
+        }
+    }
+}
+
+/// Did receiving a command fail because it was larger than we allow? Depending on whether
+/// the command was sent in one frame or several, this is spotted in different places.
+fn is_message_too_large(e: &soketto::connection::Error) -> bool {
+    matches!(
+        e,
+        soketto::connection::Error::MessageTooLarge { .. }
+            | soketto::connection::Error::Codec(soketto::base::Error::PayloadTooLarge { .. })
+    )
+}
+
+/// Does the request have an "Authorization: Bearer <token>" header with the token given?
+fn is_bearer_of(req: &hyper::Request<hyper::Body>, token: &str) -> bool {
+    let expected_auth = format!("Bearer {token}");
+    req.headers()
+        .get(http::header::AUTHORIZATION)
+        .is_some_and(|auth| constant_time_eq(auth.as_bytes(), expected_auth.as_bytes()))
+}
+
+/// Are the two byte strings the same? Unlike `==`, this doesn't stop at the first byte that
+/// differs, so how long it takes doesn't give away how much of a guessed secret was right
+/// (though it does give away whether the guess was the right length).
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a
+        .iter()
+        .zip(b)
+        .fold(0, |diff, (a, b)| std::hint::black_box(diff | (a ^ b)));
+    diff == 0
+}
+
+/// Handle a request to one of the "/admin/*" endpoints, if it's authorized with the admin
+/// token. As well as the snapshots served by [`return_admin_snapshot`], there are:
+///
+/// - `GET /admin/sampling`: whether node messages from shards are being sampled.
+/// - `POST /admin/sampling?every=N&capacity=M`: start keeping one in every N node messages
+///   (1 by default), up to the latest M of them (1000 by default).
+/// - `DELETE /admin/sampling`: stop sampling node messages.
+/// - `GET /admin/samples`: the node messages sampled so far, oldest first.
+/// - `GET /admin/snapshot`: everything we know about every chain and node, as
+///   newline delimited JSON. See [`return_state_snapshot`].
+/// - `GET /admin/log_level`: the levels that we're logging at, as in `RUST_LOG`.
+/// - `PUT /admin/log_level`: change the levels that we're logging at to those in the body,
+///   which looks like `RUST_LOG`. The default level is left alone if one isn't given.
+async fn handle_admin_request(
+    aggregator: AggregatorSet,
+    message_sampler: &MessageSampler,
+    req: hyper::Request<hyper::Body>,
+    admin_token: &str,
+) -> Response<hyper::Body> {
+    if !is_bearer_of(&req, admin_token) {
+        return basic_response(401, "Unauthorized");
+    }
+
+    match (req.method(), req.uri().path().trim_end_matches('/')) {
+        (&Method::GET, "/admin/sampling") => json_response(&message_sampler.status()),
+        (&Method::POST, "/admin/sampling") => match sampling_query(req.uri().query()) {
+            Ok((every, capacity)) => {
+                log::info!("Sampling 1 in {every} node messages, keeping the latest {capacity}");
+                message_sampler.enable(every, capacity);
+                json_response(&message_sampler.status())
+            }
+            Err(e) => basic_response(400, &e.to_string()),
+        },
+        (&Method::DELETE, "/admin/sampling") => {
+            log::info!("No longer sampling node messages");
+            message_sampler.disable();
+            json_response(&message_sampler.status())
+        }
+        (&Method::GET, "/admin/samples") => json_response(&message_sampler.samples()),
+        (&Method::GET, "/admin/snapshot") => return_state_snapshot(aggregator).await,
+        (&Method::GET, "/admin/log_level") => {
+            basic_response(200, &common::logging::filter().to_string())
+        }
+        (&Method::PUT, "/admin/log_level") => set_log_level(req).await,
+        (&Method::GET, _) => return_admin_snapshot(aggregator, &req).await,
+        _ => basic_response(404, "Not found"),
+    }
+}
+
+/// Return a JSON snapshot of some part of our current state. The snapshot is obtained from
+/// an aggregator, so it's consistent.
+///
+/// - `/admin/chains`: every chain, largest first, with its node count.
+/// - `/admin/top_chains?n=N`: the N largest chains (10 by default).
+/// - `/admin/shards`: every shard connection, with its state and node count.
+async fn return_admin_snapshot(
+    aggregator: AggregatorSet,
+    req: &hyper::Request<hyper::Body>,
+) -> Response<hyper::Body> {
+    let snapshot = match aggregator.gather_admin_snapshot().await {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            log::error!("Error obtaining admin snapshot: {e}");
+            return basic_response(500, "Internal server error");
+        }
+    };
+
+    match req.uri().path().trim_end_matches('/') {
+        "/admin/chains" => json_response(&snapshot.chains),
+        "/admin/top_chains" => {
+            let n = match top_chains_query(req.uri().query()) {
+                Ok(n) => n,
+                Err(e) => return basic_response(400, &e.to_string()),
+            };
+            json_response(&snapshot.chains[..n.min(snapshot.chains.len())])
+        }
+        "/admin/shards" => json_response(&snapshot.shards),
+        _ => basic_response(404, "Not found"),
+    }
+}
+
+/// Stream a consistent snapshot of everything we know about every chain and node, as
+/// newline delimited JSON. Each chain is sent as a `{"chain":{..}}` line, followed by a
+/// `{"node":{..}}` line for each of its nodes. The response is streamed in chunks as the
+/// aggregator serializes them, rather than being put together in full first.
+async fn return_state_snapshot(aggregator: AggregatorSet) -> Response<hyper::Body> {
+    let chunks = match aggregator.gather_state_snapshot().await {
+        Ok(chunks) => chunks,
+        Err(e) => {
+            log::error!("Error obtaining state snapshot: {e}");
+            return basic_response(500, "Internal server error");
+        }
+    };
+
+    let body = chunks.into_stream().map(Ok::<_, std::convert::Infallible>);
+    Response::builder()
+        .header(http::header::CONTENT_TYPE, "application/x-ndjson")
+        .body(hyper::Body::wrap_stream(body))
+        .unwrap()
+}
+
+fn json_response<T: serde::Serialize + ?Sized>(value: &T) -> Response<hyper::Body> {
+    let json = serde_json::to_vec(value).expect("admin response should serialize");
+    Response::builder()
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(json.into())
+        .unwrap()
+}
+
+/// Change the levels that we log at to those given in the body of the request.
+async fn set_log_level(req: hyper::Request<hyper::Body>) -> Response<hyper::Body> {
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return basic_response(400, &format!("Cannot read body: {e}")),
+    };
+    let directives = match std::str::from_utf8(&body) {
+        Ok(directives) => directives,
+        Err(e) => return basic_response(400, &format!("Invalid log level: {e}")),
+    };
+    let (filter, invalid) = common::logging::LogFilter::parse(directives);
+    if let Some(e) = invalid.first() {
+        return basic_response(400, &e.to_string());
+    }
+
+    common::logging::set_filter(filter);
+    let filter = common::logging::filter();
+    log::info!("Now logging at '{filter}'");
+    basic_response(200, &filter.to_string())
+}
+
+/// Parse how many node messages to sample and keep from a query string like
+/// `every=10&capacity=100`.
+fn sampling_query(query: Option<&str>) -> anyhow::Result<(u64, usize)> {
+    let (mut every, mut capacity) = (1, 1000);
+    for param in query.into_iter().flat_map(|q| q.split('&')) {
+        match param.split_once('=') {
+            Some(("every", n)) => {
+                every = n
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("Invalid 'every' value '{n}': {e}"))?
+            }
+            Some(("capacity", n)) => {
+                capacity = n
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("Invalid 'capacity' value '{n}': {e}"))?
+            }
+            _ => anyhow::bail!(
+                "Query parameter {param} not recognised; expected 'every=N' or 'capacity=N'"
+            ),
+        }
+    }
+    if every == 0 {
+        anyhow::bail!("Cannot sample one in every 0 messages");
+    }
+    Ok((every, capacity))
+}
+
+/// Parse the number of chains asked for in a query string like `n=5`.
+fn top_chains_query(query: Option<&str>) -> anyhow::Result<usize> {
+    let n = query
+        .into_iter()
+        .flat_map(|q| q.split('&'))
+        .find_map(|param| param.strip_prefix("n="));
+    match n {
+        Some(n) => n
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid number of chains '{n}': {e}")),
+        None => Ok(10),
+    }
+}
+
+fn return_build_info() -> Response<hyper::Body> {
+    let json = serde_json::to_vec(&BuildInfo::get()).expect("build info should serialize");
+    Response::builder()
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(json.into())
+        .unwrap()
+}
+
+fn basic_response(status: u16, msg: &str) -> Response<hyper::Body> {
+    Response::builder()
+        .status(status)
+        .body(msg.to_owned().into())
+        .unwrap()
+}
+
+async fn return_prometheus_metrics(aggregator: AggregatorSet) -> Response<hyper::Body> {
+    let metrics = aggregator.latest_metrics();
+
+    // Instead of using the rust prometheus library (which is optimised around global variables updated across a codebase),
+    // we just split out the text format that prometheus expects ourselves, and use the latest metrics that we've
+    // captured so far from the aggregators. See:
+    //
+    // https://github.com/prometheus/docs/blob/master/content/docs/instrumenting/exposition_formats.md#text-format-details
+    //
+    // For an example and explanation of this text based format. The minimal output we produce here seems to
+    // be handled correctly when pointing a current version of prometheus at it.
+    //
+    // Note: '{{' and '}}' are just escaped versions of '{' and '}' in Rust fmt strings.
+    use std::fmt::Write;
+    let mut s = String::new();
+    for (idx, m) in metrics.iter().enumerate() {
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_connected_feeds{{aggregator=\"{}\"}} {} {}",
+            idx, m.connected_feeds, m.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_connected_nodes{{aggregator=\"{}\"}} {} {}",
+            idx, m.connected_nodes, m.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_connected_shards{{aggregator=\"{}\"}} {} {}",
+            idx, m.connected_shards, m.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_chains_subscribed_to{{aggregator=\"{}\"}} {} {}",
+            idx, m.chains_subscribed_to, m.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_subscribed_feeds{{aggregator=\"{}\"}} {} {}",
+            idx, m.subscribed_feeds, m.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_total_messages_to_feeds{{aggregator=\"{}\"}} {} {}",
+            idx, m.total_messages_to_feeds, m.timestamp_unix_ms
+        );
+        let _ = write!(
+            &mut s,
+            "telemetry_core_current_messages_to_aggregator{{aggregator=\"{}\"}} {} {}\n\n",
+            idx, m.current_messages_to_aggregator, m.timestamp_unix_ms
+        );
+        let _ = write!(
+            &mut s,
+            "telemetry_core_total_messages_to_aggregator{{aggregator=\"{}\"}} {} {}\n\n",
+            idx, m.total_messages_to_aggregator, m.timestamp_unix_ms
+        );
+        let _ = write!(
+            &mut s,
+            "telemetry_core_dropped_messages_to_aggregator{{aggregator=\"{}\"}} {} {}\n\n",
+            idx, m.dropped_messages_to_aggregator, m.timestamp_unix_ms
+        );
+        let _ = write!(
+            &mut s,
+            "telemetry_core_snapshots_built{{aggregator=\"{}\"}} {} {}\n\n",
+            idx, m.snapshots_built, m.timestamp_unix_ms
+        );
+        let _ = write!(
+            &mut s,
+            "telemetry_core_snapshots_reused{{aggregator=\"{}\"}} {} {}\n\n",
+            idx, m.snapshots_reused, m.timestamp_unix_ms
+        );
+        let _ = write!(
+            &mut s,
+            "telemetry_core_nodes_rejected_for_too_many_chains{{aggregator=\"{}\"}} {} {}\n\n",
+            idx, m.nodes_rejected_for_too_many_chains, m.timestamp_unix_ms
+        );
+        let _ = write!(
+            &mut s,
+            "telemetry_core_abandoned_location_lookups{{aggregator=\"{}\"}} {} {}\n\n",
+            idx, m.abandoned_location_lookups, m.timestamp_unix_ms
+        );
+        for (genesis_hash, reorgs) in &m.chain_reorgs {
+            let _ = write!(
+                &mut s,
+                "telemetry_core_chain_reorgs{{aggregator=\"{}\",chain=\"{:#x}\"}} {} {}\n\n",
+                idx, genesis_hash, reorgs, m.timestamp_unix_ms
+            );
+        }
+        let latency = &m.update_latency;
+        for (name, value) in [
+            ("samples", latency.samples),
+            ("p50_microseconds", latency.p50_us),
+            ("p90_microseconds", latency.p90_us),
+            ("p99_microseconds", latency.p99_us),
+            ("max_microseconds", latency.max_us),
+        ] {
+            let _ = write!(
+                &mut s,
+                "telemetry_core_update_latency_{}{{aggregator=\"{}\"}} {} {}\n\n",
+                name, idx, value, m.timestamp_unix_ms
+            );
+        }
+    }
+
+    Response::builder()
+        // The version number here tells prometheus which version of the text format we're using:
+        .header(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(s.into())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn constant_time_eq_compares_every_byte() {
+        assert!(constant_time_eq(b"Bearer secret", b"Bearer secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"Bearer secret", b"Bearer secreT"));
+        assert!(!constant_time_eq(b"Bearer secret", b"xearer secret"));
+        assert!(!constant_time_eq(b"Bearer secret", b"Bearer secre"));
+    }
+}
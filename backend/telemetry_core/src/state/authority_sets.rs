@@ -0,0 +1,118 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+
+use crate::feed_message::AuthoritySet;
+
+use super::chain::ChainNodeId;
+
+/// Works out which authority set a chain is on, from the authority sets that its nodes
+/// report. Nodes can disagree, for instance while some of them are still to hear about a new
+/// set, so a set is only agreed on once a majority of the nodes that report sets report it.
+#[derive(Default)]
+pub struct AuthoritySets {
+    /// The authority set that each node last reported.
+    reports: HashMap<ChainNodeId, AuthoritySet>,
+    /// The authority set that a majority of nodes last agreed on.
+    current: Option<AuthoritySet>,
+}
+
+impl AuthoritySets {
+    /// Make a note that a node has reported an authority set. If a majority of nodes now
+    /// agree on a newer set than the current one, the chain has moved on to it, and it's
+    /// returned. Sets with IDs that aren't numbers are ignored.
+    pub fn record(
+        &mut self,
+        nid: ChainNodeId,
+        id: &str,
+        authorities: &str,
+    ) -> Option<&AuthoritySet> {
+        let id = id.parse().ok()?;
+        self.reports.insert(
+            nid,
+            AuthoritySet {
+                id,
+                authorities: authorities.into(),
+            },
+        );
+
+        // Sets only ever advance, so older ones, however popular, can't become current:
+        let current_id = self.current.as_ref().map(|set| set.id);
+        let mut votes: HashMap<&AuthoritySet, usize> = HashMap::new();
+        for set in self.reports.values() {
+            if current_id.is_none_or(|current_id| set.id > current_id) {
+                *votes.entry(set).or_default() += 1;
+            }
+        }
+        let majority = self.reports.len() / 2 + 1;
+        let agreed = votes
+            .into_iter()
+            .find(|&(_, count)| count >= majority)
+            .map(|(set, _)| set.clone())?;
+
+        self.current = Some(agreed);
+        self.current.as_ref()
+    }
+
+    /// Forget about the authority set that a node reported, once it's gone.
+    pub fn remove(&mut self, nid: ChainNodeId) {
+        self.reports.remove(&nid);
+    }
+
+    /// The authority set that a majority of nodes last agreed on, if any.
+    pub fn current(&self) -> Option<&AuthoritySet> {
+        self.current.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn node(id: usize) -> ChainNodeId {
+        ChainNodeId::new(id)
+    }
+
+    #[test]
+    fn a_set_is_agreed_once_a_majority_report_it() {
+        let mut sets = AuthoritySets::default();
+        let set = |id, authorities: &str| {
+            Some(AuthoritySet {
+                id,
+                authorities: authorities.into(),
+            })
+        };
+
+        // One node is a majority of the nodes reporting sets so far:
+        assert_eq!(sets.record(node(0), "1", "[A]").cloned(), set(1, "[A]"));
+        assert_eq!(sets.record(node(1), "1", "[A]").cloned(), None);
+        assert_eq!(sets.record(node(2), "1", "[A]").cloned(), None);
+
+        // Set 2 is only agreed on once two of the three nodes agree on its members too:
+        assert_eq!(sets.record(node(0), "2", "[A, B]").cloned(), None);
+        assert_eq!(sets.record(node(1), "2", "[A, C]").cloned(), None);
+        assert_eq!(
+            sets.record(node(2), "2", "[A, B]").cloned(),
+            set(2, "[A, B]")
+        );
+
+        // Nodes that are behind, or report nonsense, don't take the chain backwards:
+        assert_eq!(sets.record(node(0), "1", "[A]").cloned(), None);
+        assert_eq!(sets.record(node(1), "oops", "[]").cloned(), None);
+        assert_eq!(sets.current().cloned(), set(2, "[A, B]"));
+    }
+}
@@ -0,0 +1,190 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+
+use common::node_types::{BlockNumber, Timestamp};
+
+use crate::feed_message::BlockPropagationStats;
+
+/// How many of the most recent block heights we keep track of by default. Each node
+/// reports a height at most once, so this bounds memory to a number of samples per node
+/// no matter how quickly the chain produces blocks.
+pub const DEFAULT_TRACKED_HEIGHTS: usize = 20;
+
+/// Keeps track of when each recent block height was first reported by a node on a
+/// chain, and how long it took every other node to report the same height.
+pub struct BlockPropagation {
+    heights: BTreeMap<BlockNumber, Height>,
+    /// The most heights that we keep track of at once.
+    max_heights: usize,
+}
+
+impl Default for BlockPropagation {
+    fn default() -> Self {
+        BlockPropagation {
+            heights: BTreeMap::new(),
+            max_heights: DEFAULT_TRACKED_HEIGHTS,
+        }
+    }
+}
+
+struct Height {
+    /// When was this height first reported?
+    first_seen: Timestamp,
+    /// How long after that did each subsequent node report it, in ms?
+    delays: Vec<u64>,
+}
+
+impl BlockPropagation {
+    /// Keep track of at most this many of the most recent heights (and at least one),
+    /// forgetting about older ones straight away if need be.
+    pub fn set_max_heights(&mut self, max_heights: usize) {
+        self.max_heights = max_heights.max(1);
+        while self.heights.len() > self.max_heights {
+            self.heights.pop_first();
+        }
+    }
+
+    /// Make a note that a node has reported a new best block at this height.
+    pub fn record(&mut self, height: BlockNumber, now: Timestamp) {
+        if let Some(tracked) = self.heights.get_mut(&height) {
+            tracked.delays.push(now.saturating_sub(tracked.first_seen));
+            return;
+        }
+
+        // Nodes catching up report heights that we've long since stopped tracking;
+        // these aren't being seen for the first time, so ignore them:
+        let is_full = self.heights.len() >= self.max_heights;
+        if is_full && self.heights.keys().next().is_some_and(|&h| height < h) {
+            return;
+        }
+
+        self.heights.insert(
+            height,
+            Height {
+                first_seen: now,
+                delays: Vec::new(),
+            },
+        );
+        while self.heights.len() > self.max_heights {
+            self.heights.pop_first();
+        }
+    }
+
+    /// The distribution of delays across the heights we're tracking, or `None` if no
+    /// height has been reported by more than one node yet.
+    pub fn stats(&self) -> Option<BlockPropagationStats> {
+        let mut delays: Vec<u64> = self
+            .heights
+            .values()
+            .flat_map(|h| h.delays.iter().copied())
+            .collect();
+        if delays.is_empty() {
+            return None;
+        }
+        delays.sort_unstable();
+
+        // Nearest-rank percentiles:
+        let percentile = |p: usize| delays[(delays.len() * p).div_ceil(100) - 1];
+        Some(BlockPropagationStats {
+            min: delays[0],
+            median: percentile(50),
+            p95: percentile(95),
+            max: delays[delays.len() - 1],
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn staggered_reports_give_propagation_percentiles() {
+        let mut propagation = BlockPropagation::default();
+        assert_eq!(propagation.stats(), None);
+
+        // The first node to report a height gives us nothing to measure:
+        propagation.record(1, 1000);
+        assert_eq!(propagation.stats(), None);
+
+        // Ten more nodes report height 1 every 100ms after it's first seen, and
+        // ten more report height 2 every 10ms after it's first seen:
+        for n in 1..=10 {
+            propagation.record(1, 1000 + n * 100);
+        }
+        propagation.record(2, 5000);
+        for n in 1..=10 {
+            propagation.record(2, 5000 + n * 10);
+        }
+
+        assert_eq!(
+            propagation.stats(),
+            Some(BlockPropagationStats {
+                min: 10,
+                median: 100,
+                p95: 900,
+                max: 1000,
+            })
+        );
+    }
+
+    #[test]
+    fn old_heights_are_forgotten() {
+        let mut propagation = BlockPropagation::default();
+        for height in 1..=DEFAULT_TRACKED_HEIGHTS as u64 {
+            propagation.record(height, 0);
+            propagation.record(height, 100);
+        }
+        propagation.record(DEFAULT_TRACKED_HEIGHTS as u64 + 1, 0);
+        propagation.record(DEFAULT_TRACKED_HEIGHTS as u64 + 1, 50);
+        assert_eq!(propagation.heights.len(), DEFAULT_TRACKED_HEIGHTS);
+        assert_eq!(propagation.stats().unwrap().min, 50);
+
+        // A node catching up on a height we no longer track isn't counted as
+        // first seeing it:
+        propagation.record(1, 10_000);
+        assert_eq!(propagation.heights.len(), DEFAULT_TRACKED_HEIGHTS);
+        assert!(!propagation.heights.contains_key(&1));
+    }
+
+    #[test]
+    fn tracking_many_heights_stays_bounded() {
+        let mut propagation = BlockPropagation::default();
+        propagation.set_max_heights(5);
+
+        // A fast chain, with a few nodes reporting every height:
+        for height in 1..=100_000 {
+            for node in 0..3 {
+                propagation.record(height, height * 100 + node);
+            }
+            assert!(propagation.heights.len() <= 5);
+        }
+        assert_eq!(
+            propagation.heights.keys().copied().collect::<Vec<_>>(),
+            vec![99_996, 99_997, 99_998, 99_999, 100_000]
+        );
+        assert!(propagation.heights.values().all(|h| h.delays.len() == 2));
+
+        // Shrinking the window forgets about the oldest heights straight away:
+        propagation.set_max_heights(2);
+        assert_eq!(
+            propagation.heights.keys().copied().collect::<Vec<_>>(),
+            vec![99_999, 100_000]
+        );
+    }
+}
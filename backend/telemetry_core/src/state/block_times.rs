@@ -0,0 +1,127 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use common::node_types::{BlockNumber, Timestamp};
+use common::NumStats;
+
+/// How many of the most recent block intervals the average block time is taken over.
+const BLOCK_INTERVALS: usize = 50;
+
+/// Keeps a rolling average of the time between blocks on a chain, from the times at which
+/// each new best block height is first seen. Each new height updates the average in
+/// constant time.
+pub struct BlockTimes {
+    /// The highest block seen so far, and when it was seen.
+    last: Option<(BlockNumber, Timestamp)>,
+    intervals: NumStats<u64>,
+    average: Option<u64>,
+}
+
+impl Default for BlockTimes {
+    fn default() -> Self {
+        BlockTimes {
+            last: None,
+            intervals: NumStats::new(BLOCK_INTERVALS),
+            average: None,
+        }
+    }
+}
+
+impl BlockTimes {
+    /// Make a note that a new best block at this height was first seen at `now`.
+    ///
+    /// Heights that we've already seen (for instance after a reorg) don't tell us anything
+    /// new, and nor do reports from before the last one, so these are ignored. When the best
+    /// block jumps by more than one height, the time taken is shared between the blocks.
+    pub fn record(&mut self, height: BlockNumber, now: Timestamp) {
+        match self.last {
+            Some((last_height, last_seen)) if height <= last_height || now < last_seen => {
+                return;
+            }
+            Some((last_height, last_seen)) => {
+                self.intervals
+                    .push((now - last_seen) / (height - last_height));
+                self.average = Some(self.intervals.average());
+            }
+            None => {}
+        }
+        self.last = Some((height, now));
+    }
+
+    /// Forget about every block seen so far, and start again from this one (if given).
+    pub fn reset(&mut self, last: Option<(BlockNumber, Timestamp)>) {
+        self.last = last;
+        self.intervals.reset();
+        self.average = None;
+    }
+
+    /// The average time between blocks in ms, once at least two heights have been seen.
+    pub fn average(&self) -> Option<u64> {
+        self.average
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn blocks_at_regular_intervals_give_that_average() {
+        let mut block_times = BlockTimes::default();
+        block_times.record(1, 1_000);
+        assert_eq!(block_times.average(), None);
+
+        for height in 2..=20 {
+            block_times.record(height, height * 6_000);
+        }
+        // The first interval is longer than the rest, so the average is a little off:
+        let average = block_times.average().unwrap();
+        assert!((6_000..6_500).contains(&average), "{average}");
+
+        for height in 21..=100 {
+            block_times.record(height, height * 6_000);
+        }
+        assert_eq!(block_times.average(), Some(6_000));
+    }
+
+    #[test]
+    fn repeated_and_out_of_order_blocks_are_ignored() {
+        let mut block_times = BlockTimes::default();
+        for height in 1..=10 {
+            block_times.record(height, height * 2_000);
+            // A reorg to the same height, and a late report of an older height:
+            block_times.record(height, height * 2_000 + 500);
+            block_times.record(height - 1, height * 2_000 + 1_000);
+        }
+        // Reports that arrived before the last one are ignored too:
+        block_times.record(11, 19_000);
+        assert_eq!(block_times.average(), Some(2_000));
+    }
+
+    #[test]
+    fn skipped_heights_share_the_time_between_them() {
+        let mut block_times = BlockTimes::default();
+        block_times.record(1, 0);
+        block_times.record(2, 3_000);
+        block_times.record(5, 12_000);
+        assert_eq!(block_times.average(), Some(3_000));
+
+        block_times.reset(Some((5, 12_000)));
+        assert_eq!(block_times.average(), None);
+        block_times.record(6, 16_000);
+        assert_eq!(block_times.average(), Some(4_000));
+    }
+}
@@ -16,19 +16,26 @@
 
 use common::node_message::Payload;
 use common::node_types::BlockHash;
-use common::node_types::{Block, Timestamp};
-use common::{id_type, time, DenseMap, MostSeen, NumStats};
+use common::node_types::{Block, BlockNumber, NodeSyncState, Timestamp};
+use common::{id_type, time, DenseMap, MostSeen};
 use once_cell::sync::Lazy;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::str::FromStr;
 use std::time::{Duration, Instant};
 
-use crate::feed_message::{self, ChainStats, FeedMessageSerializer};
+use crate::feed_message::{
+    self, AuthoritySet, BlockPropagationStats, ChainFeedSerializer, ChainStats, FinalizedConsensus,
+};
 use crate::find_location;
 
+use super::authority_sets::AuthoritySets;
+use super::block_propagation::{self, BlockPropagation};
+use super::block_times::BlockTimes;
 use super::chain_stats::ChainStatsCollator;
 use super::counter::CounterValue;
-use super::node::Node;
+use super::finality_consensus::{self, FinalityConsensus};
+use super::node::{IntervalUpdates, Node, PeerCountHandling};
+use super::reorgs::{self, Reorgs};
 
 id_type! {
     /// A Node ID that is unique to the chain it's in.
@@ -37,13 +44,26 @@ id_type! {
 
 pub type Label = Box<str>;
 
+/// Metadata attached to a chain, by key.
+pub type ChainTags = BTreeMap<Box<str>, Box<str>>;
+
 const STALE_TIMEOUT: u64 = 2 * 60 * 1000; // 2 minutes
 const STATS_UPDATE_INTERVAL: Duration = Duration::from_secs(5);
+/// A node is considered synced if its best block is within this many blocks of the chain's best block.
+const SYNCED_BLOCK_DISTANCE: u64 = 2;
+/// Block heights above this can't be represented exactly in a JS number, and so can't be
+/// displayed by the frontend; no real chain will get anywhere near it.
+const MAX_BLOCK_HEIGHT: u64 = (1 << 53) - 1;
 
 pub struct Chain {
     /// Labels that nodes use for this chain. We keep track of
     /// the most commonly used label as nodes are added/removed.
     labels: MostSeen<Label>,
+    /// If set, this label is always used for the chain, regardless
+    /// of the labels that nodes report.
+    fixed_label: Option<Label>,
+    /// Metadata that we've been told to attach to this chain.
+    tags: ChainTags,
     /// Set of nodes that are in this chain
     nodes: DenseMap<ChainNodeId, Node>,
     /// Best block
@@ -51,11 +71,11 @@ pub struct Chain {
     /// Finalized block
     finalized: Block,
     /// Block times history, stored so we can calculate averages
-    block_times: NumStats<u64>,
-    /// Calculated average block time
-    average_block_time: Option<u64>,
+    block_times: BlockTimes,
     /// When the best block first arrived
     timestamp: Option<Timestamp>,
+    /// How far ahead of our clock the clock that block times are going by is, in ms.
+    clock_offset_ms: i64,
     /// Genesis hash of this chain
     genesis_hash: BlockHash,
     /// Maximum number of nodes allowed to connect from this chain
@@ -66,6 +86,21 @@ pub struct Chain {
     stats: ChainStats,
     /// Timestamp of when the stats were last regenerated.
     stats_last_regenerated: Instant,
+    /// When recent blocks were first seen, and how long they took to reach other nodes.
+    block_propagation: BlockPropagation,
+    /// How quickly blocks are propagating, as of when the stats were last regenerated.
+    block_propagation_stats: Option<BlockPropagationStats>,
+    /// Which finalized block a majority of nodes agree on.
+    finality_consensus: FinalityConsensus,
+    /// Which best block most nodes agree on at recent heights, and how often that's changed.
+    reorgs: Reorgs,
+    /// Which GRANDPA authority set a majority of nodes agree that the chain is on.
+    authority_sets: AuthoritySets,
+    /// If set, best blocks more than this many blocks ahead of the chain's best block are ignored.
+    max_block_height_jump: Option<u64>,
+    /// The node reporting the highest best block. If several nodes are at that height, it's
+    /// the one that got there first.
+    leader: Option<ChainNodeId>,
 }
 
 pub enum AddNodeResult {
@@ -78,6 +113,11 @@ pub enum AddNodeResult {
 
 pub struct RemoveNodeResult {
     pub chain_renamed: bool,
+    pub was_authority: bool,
+    /// The node that was removed, if it was found.
+    pub node: Option<Node>,
+    /// If the removed node was the chain leader, the node that has taken its place.
+    pub new_leader: Option<(ChainNodeId, BlockNumber)>,
 }
 
 /// Genesis hashes of chains we consider "first party". These chains allow any
@@ -102,22 +142,77 @@ pub fn is_first_party_network(genesis_hash: &BlockHash) -> bool {
     FIRST_PARTY_NETWORKS.contains(genesis_hash)
 }
 
+/// Is a block obviously nonsense, regardless of the chain it's reported for? Only the
+/// genesis block can have an empty hash.
+fn is_bogus_block(block: &Block) -> bool {
+    block.height > MAX_BLOCK_HEIGHT || (block.height > 0 && block.hash.is_zero())
+}
+
 impl Chain {
-    /// Create a new chain with an initial label.
-    pub fn new(genesis_hash: BlockHash, max_nodes: usize) -> Self {
+    /// Create a new chain, optionally with a label that will always be used for it.
+    pub fn new(genesis_hash: BlockHash, max_nodes: usize, fixed_label: Option<Label>) -> Self {
         Chain {
             labels: MostSeen::default(),
+            fixed_label,
+            tags: ChainTags::new(),
             nodes: DenseMap::new(),
             best: Block::zero(),
             finalized: Block::zero(),
-            block_times: NumStats::new(50),
-            average_block_time: None,
+            block_times: BlockTimes::default(),
             timestamp: None,
+            clock_offset_ms: 0,
             genesis_hash,
             max_nodes,
             stats_collator: Default::default(),
             stats: Default::default(),
             stats_last_regenerated: Instant::now(),
+            block_propagation: BlockPropagation::default(),
+            block_propagation_stats: None,
+            finality_consensus: FinalityConsensus::default(),
+            reorgs: Reorgs::default(),
+            authority_sets: AuthoritySets::default(),
+            max_block_height_jump: None,
+            leader: None,
+        }
+    }
+
+    /// Ignore best blocks reported by nodes that are more than this many blocks ahead of
+    /// the chain's current best block. `None` (the default) accepts any plausible height.
+    pub fn set_max_block_height_jump(&mut self, max_jump: Option<u64>) {
+        self.max_block_height_jump = max_jump;
+    }
+
+    /// Attach these tags to the chain, replacing any it had.
+    pub fn set_tags(&mut self, tags: ChainTags) {
+        self.tags = tags;
+    }
+
+    /// Keep track of at most this many of the most recent block heights when working out how
+    /// quickly blocks propagate, which finalized block nodes agree on and whether the best
+    /// block has been reorged, so that chains that produce blocks quickly don't use more
+    /// memory. `None` keeps the defaults (the last 20 heights for block propagation and
+    /// reorgs, and 64 for finality).
+    pub fn set_block_window(&mut self, window: Option<usize>) {
+        self.block_propagation
+            .set_max_heights(window.unwrap_or(block_propagation::DEFAULT_TRACKED_HEIGHTS));
+        self.finality_consensus
+            .set_max_heights(window.unwrap_or(finality_consensus::DEFAULT_TRACKED_HEIGHTS));
+        self.reorgs
+            .set_max_heights(window.unwrap_or(reorgs::DEFAULT_TRACKED_HEIGHTS));
+    }
+
+    /// Can we believe a best block that a node has reported? A single node shouldn't be
+    /// able to drag the best block of the whole chain to a nonsensical height.
+    fn is_plausible_best_block(&self, block: &Block) -> bool {
+        if is_bogus_block(block) {
+            return false;
+        }
+        match self.max_block_height_jump {
+            // Until we know the chain's height, any height could be plausible:
+            Some(max_jump) if self.best.height > 0 => {
+                block.height <= self.best.height.saturating_add(max_jump)
+            }
+            _ => true,
         }
     }
 
@@ -126,23 +221,29 @@ impl Chain {
         self.nodes.len() >= self.max_nodes
     }
 
-    /// Assign a node to this chain.
-    pub fn add_node(&mut self, node: Node) -> AddNodeResult {
+    /// Assign a node to this chain, giving it the preferred ID if that's free.
+    pub fn add_node(&mut self, node: Node, preferred_id: Option<ChainNodeId>) -> AddNodeResult {
         if self.is_overquota() {
             return AddNodeResult::Overquota;
         }
 
         let details = node.details();
         self.stats_collator
-            .add_or_remove_node(details, None, CounterValue::Increment);
+            .add_or_remove_node(details, node.hwbench(), CounterValue::Increment);
 
         let node_chain_label = &details.chain;
         let label_result = self.labels.insert(node_chain_label);
-        let node_id = self.nodes.add(node);
+        let node_id = match preferred_id {
+            Some(id) => match self.nodes.add_at(id, node) {
+                Ok(()) => id,
+                Err(node) => self.nodes.add(node),
+            },
+            None => self.nodes.add(node),
+        };
 
         AddNodeResult::Added {
             id: node_id,
-            chain_renamed: label_result.has_changed(),
+            chain_renamed: self.fixed_label.is_none() && label_result.has_changed(),
         }
     }
 
@@ -153,6 +254,9 @@ impl Chain {
             None => {
                 return RemoveNodeResult {
                     chain_renamed: false,
+                    was_authority: false,
+                    node: None,
+                    new_leader: None,
                 }
             }
         };
@@ -161,52 +265,223 @@ impl Chain {
         self.stats_collator
             .add_or_remove_node(details, node.hwbench(), CounterValue::Decrement);
 
+        self.authority_sets.remove(node_id);
+
         let node_chain_label = &node.details().chain;
         let label_result = self.labels.remove(node_chain_label);
 
+        let mut new_leader = None;
+        if self.leader == Some(node_id) {
+            self.leader = self.find_leader();
+            new_leader = self.leader();
+        }
+
         RemoveNodeResult {
-            chain_renamed: label_result.has_changed(),
+            chain_renamed: self.fixed_label.is_none() && label_result.has_changed(),
+            was_authority: node.is_authority(),
+            node: Some(node),
+            new_leader,
         }
     }
 
-    /// Attempt to update the best block seen in this chain.
+    /// Attempt to update the best block seen in this chain. If `sent_at` is given, blocks
+    /// are timed by it rather than by when we received the update.
+    #[allow(clippy::too_many_arguments)]
     pub fn update_node(
         &mut self,
         nid: ChainNodeId,
         payload: Payload,
-        feed: &mut FeedMessageSerializer,
+        sent_at: Option<Timestamp>,
+        feed: &mut ChainFeedSerializer,
         expose_node_details: bool,
+        peer_count_handling: PeerCountHandling,
+        node_update_min_interval: Duration,
     ) {
+        let now = time::now();
+        if let Some(node) = self.nodes.get_mut(nid) {
+            node.update_last_message(now);
+        }
+
         if let Some(block) = payload.best_block() {
-            self.handle_block(block, nid, feed);
+            if self.is_plausible_best_block(block) {
+                self.handle_block(block, nid, now, sent_at.unwrap_or(now), feed);
+            } else {
+                log::debug!(
+                    "[{}] ignoring implausible best block={}/{:?} from node {:?} (chain best={})",
+                    self.labels.best(),
+                    block.height,
+                    block.hash,
+                    nid,
+                    self.best.height,
+                );
+            }
         }
 
+        // A node on its own can't be expected to find peers, even once it's caught up:
+        let has_other_nodes = self.nodes.len() > 1;
+        let chain_best_height = self.best.height;
+        let mut finalized_changed = false;
+
         if let Some(node) = self.nodes.get_mut(nid) {
+            let is_authority = node.is_authority();
             match payload {
                 Payload::SystemInterval(ref interval) => {
-                    // Send a feed message if any of the relevant node details change:
-                    if node.update_hardware(interval) {
-                        feed.push(feed_message::Hardware(nid.into(), node.hardware()));
-                    }
-                    if let Some(stats) = node.update_stats(interval) {
-                        feed.push(feed_message::NodeStatsUpdate(nid.into(), stats));
-                    }
-                    if let Some(io) = node.update_io(interval) {
-                        feed.push(feed_message::NodeIOUpdate(nid.into(), io));
+                    let synced = has_other_nodes
+                        && chain_best_height > 0
+                        && node.best().height + SYNCED_BLOCK_DISTANCE >= chain_best_height;
+                    let sync_state = NodeSyncState::from_reported(
+                        interval.is_major_syncing,
+                        chain_best_height.saturating_sub(node.best().height),
+                        SYNCED_BLOCK_DISTANCE,
+                    );
+                    let changed = IntervalUpdates {
+                        hardware: node.update_hardware(interval),
+                        stats: node
+                            .update_stats(interval, synced, sync_state, peer_count_handling)
+                            .is_some(),
+                        peer_counts: node.update_peer_counts(interval).is_some(),
+                        io: node.update_io(interval).is_some(),
+                        custom_metrics: node.update_custom_metrics(interval).is_some(),
+                        last_seen: true,
+                    };
+
+                    // Send feed messages for any of the relevant node details that have
+                    // changed, but no more often than once per `node_update_min_interval`:
+                    let updates = node.coalesce_interval_updates(
+                        changed,
+                        time::now(),
+                        node_update_min_interval,
+                    );
+                    if let Some(updates) = updates {
+                        if updates.hardware {
+                            feed.push_for_node(
+                                is_authority,
+                                feed_message::Hardware(nid.into(), node.hardware()),
+                            );
+                        }
+                        if updates.stats {
+                            feed.push_for_node(
+                                is_authority,
+                                feed_message::NodeStatsUpdate(nid.into(), node.stats()),
+                            );
+                        }
+                        if updates.peer_counts {
+                            feed.push_for_node(
+                                is_authority,
+                                feed_message::NodePeerCount(nid.into(), node.peer_counts()),
+                            );
+                        }
+                        if updates.io {
+                            feed.push_for_node(
+                                is_authority,
+                                feed_message::NodeIOUpdate(nid.into(), node.io()),
+                            );
+                        }
+                        if updates.custom_metrics {
+                            feed.push_for_node(
+                                is_authority,
+                                feed_message::NodeCustomMetrics(nid.into(), node.custom_metrics()),
+                            );
+                        }
+                        if updates.last_seen {
+                            feed.push_for_node(
+                                is_authority,
+                                feed_message::NodeLastSeen(nid.into(), node.last_message()),
+                            );
+                        }
                     }
                 }
                 Payload::AfgAuthoritySet(authority) => {
+                    // Nodes report an empty authority ID if they aren't part of the current authority set.
+                    let details_changed =
+                        node.set_validator_address(authority.authority_id.clone());
+                    node.set_authority(!authority.authority_id.is_empty());
+                    feed.push_for_node(
+                        node.is_authority(),
+                        feed_message::AfgAuthoritySet(
+                            &authority.authority_id,
+                            &authority.authorities,
+                            &authority.authority_set_id,
+                            node.best().height,
+                            node.best().hash,
+                        ),
+                    );
+                    if let Some(set) = self.authority_sets.record(
+                        nid,
+                        &authority.authority_set_id,
+                        &authority.authorities,
+                    ) {
+                        feed.push(feed_message::AuthoritySetChanged(set));
+                    }
+
                     // If our node validator address (and thus details) change, send an
-                    // updated "add node" feed message:
-                    if node.set_validator_address(authority.authority_id.clone()) {
-                        feed.push(feed_message::AddedNode(
-                            nid.into(),
-                            &node,
-                            expose_node_details,
-                        ));
+                    // updated "add node" feed message. If the node has started or stopped
+                    // being an authority, feeds only interested in authorities need to be
+                    // told to add or remove it.
+                    let added_node = feed_message::AddedNode(nid.into(), node, expose_node_details);
+                    match (is_authority, node.is_authority()) {
+                        (true, false) => {
+                            feed.push_for_authorities(feed_message::RemovedNodeReason(
+                                nid.into(),
+                                feed_message::NodeRemovalReason::NoLongerAuthority,
+                            ));
+                            feed.push_for_authorities(feed_message::RemovedNode(nid.into()));
+                            if details_changed {
+                                feed.push_for_node(false, added_node);
+                            }
+                        }
+                        (false, true) if !details_changed => {
+                            feed.push_for_authorities(added_node);
+                        }
+                        (_, now_authority) if details_changed => {
+                            feed.push_for_node(now_authority, added_node);
+                        }
+                        _ => {}
                     }
                     return;
                 }
+                // GRANDPA messages are passed on as they are, but only for nodes whose validator
+                // address we know, since that's how feeds tell the voters apart:
+                Payload::AfgFinalized(ref finalized) => {
+                    let address = node.details().validator.as_deref();
+                    if let (Some(address), Ok(height)) =
+                        (address, finalized.finalized_number.parse())
+                    {
+                        feed.push_for_node(
+                            is_authority,
+                            feed_message::AfgFinalized(address, height, finalized.finalized_hash),
+                        );
+                    }
+                }
+                Payload::AfgReceivedPrevote(ref prevote) => {
+                    let address = node.details().validator.as_deref();
+                    if let (Some(address), Ok(height)) = (address, prevote.target_number.parse()) {
+                        feed.push_for_node(
+                            is_authority,
+                            feed_message::AfgReceivedPrevote(
+                                address,
+                                height,
+                                prevote.target_hash,
+                                prevote.voter.as_deref(),
+                            ),
+                        );
+                    }
+                }
+                Payload::AfgReceivedPrecommit(ref precommit) => {
+                    let address = node.details().validator.as_deref();
+                    if let (Some(address), Ok(height)) = (address, precommit.target_number.parse())
+                    {
+                        feed.push_for_node(
+                            is_authority,
+                            feed_message::AfgReceivedPrecommit(
+                                address,
+                                height,
+                                precommit.target_hash,
+                                precommit.voter.as_deref(),
+                            ),
+                        );
+                    }
+                }
                 Payload::HwBench(ref hwbench) => {
                     let new_hwbench = common::node_types::NodeHwBench {
                         cpu_hashrate_score: hwbench.cpu_hashrate_score,
@@ -219,11 +494,10 @@ impl Chain {
                     // Note: There is no need to send this message if the details
                     // will not be serialized over the wire.
                     if expose_node_details {
-                        feed.push(feed_message::AddedNode(
-                            nid.into(),
-                            &node,
-                            expose_node_details,
-                        ));
+                        feed.push_for_node(
+                            is_authority,
+                            feed_message::AddedNode(nid.into(), node, expose_node_details),
+                        );
                     }
 
                     self.stats_collator
@@ -234,13 +508,17 @@ impl Chain {
                 _ => {}
             }
 
-            if let Some(block) = payload.finalized_block() {
+            if let Some(block) = payload
+                .finalized_block()
+                .filter(|block| !is_bogus_block(block))
+            {
                 if let Some(finalized) = node.update_finalized(block) {
-                    feed.push(feed_message::FinalizedBlock(
-                        nid.into(),
-                        finalized.height,
-                        finalized.hash,
-                    ));
+                    self.finality_consensus.record(*finalized);
+                    finalized_changed = true;
+                    feed.push_for_node(
+                        is_authority,
+                        feed_message::FinalizedBlock(nid.into(), finalized.height, finalized.hash),
+                    );
 
                     if finalized.height > self.finalized.height {
                         self.finalized = *finalized;
@@ -251,12 +529,33 @@ impl Chain {
                     }
                 }
             }
+
+            // A new best or finalized block may change how far behind finalization is:
+            if let Some(stats) = node.update_finality_lag() {
+                feed.push_for_node(
+                    is_authority,
+                    feed_message::NodeStatsUpdate(nid.into(), stats),
+                );
+            }
+        }
+
+        if finalized_changed {
+            let finalized_heights = self.nodes.iter().map(|(_, node)| node.finalized().height);
+            if let Some(consensus) = self.finality_consensus.update(finalized_heights) {
+                feed.push(feed_message::ChainFinalized(consensus));
+            }
         }
     }
 
-    fn handle_block(&mut self, block: &Block, nid: ChainNodeId, feed: &mut FeedMessageSerializer) {
+    fn handle_block(
+        &mut self,
+        block: &Block,
+        nid: ChainNodeId,
+        now: Timestamp,
+        timestamp: Timestamp,
+        feed: &mut ChainFeedSerializer,
+    ) {
         let mut propagation_time = None;
-        let now = time::now();
         let nodes_len = self.nodes.len();
 
         self.update_stale_nodes(now, feed);
@@ -266,8 +565,23 @@ impl Chain {
             Some(node) => node,
             None => return,
         };
+        let is_authority = node.is_authority();
+
+        // A node moving over to a different block at the same height won't update its best
+        // block below, but is exactly what a reorg looks like, so it's noted either way:
+        if let Some(depth) = self.reorgs.record(nid, *block) {
+            log::debug!(
+                "[{}] reorg of depth {} to block={}/{:?}",
+                self.labels.best(),
+                depth,
+                block.height,
+                block.hash,
+            );
+            feed.push(feed_message::Reorg(block.height, block.hash, depth));
+        }
 
         if node.update_block(*block) {
+            self.block_propagation.record(block.height, timestamp);
             if block.height > self.best.height {
                 self.best = *block;
                 log::debug!(
@@ -277,32 +591,57 @@ impl Chain {
                     self.best.height,
                     self.best.hash,
                 );
-                if let Some(timestamp) = self.timestamp {
-                    self.block_times.push(now.saturating_sub(timestamp));
-                    self.average_block_time = Some(self.block_times.average());
-                }
-                self.timestamp = Some(now);
+                self.block_times.record(self.best.height, timestamp);
+                self.timestamp = Some(timestamp);
+                self.clock_offset_ms = timestamp as i64 - now as i64;
                 feed.push(feed_message::BestBlock(
                     self.best.height,
-                    now,
-                    self.average_block_time,
+                    timestamp,
+                    self.block_times.average(),
                 ));
                 propagation_time = Some(0);
             } else if block.height == self.best.height {
-                if let Some(timestamp) = self.timestamp {
-                    propagation_time = Some(now.saturating_sub(timestamp));
+                if let Some(best_timestamp) = self.timestamp {
+                    propagation_time = Some(timestamp.saturating_sub(best_timestamp));
                 }
             }
 
-            if let Some(details) = node.update_details(now, propagation_time) {
-                feed.push(feed_message::ImportedBlock(nid.into(), details));
+            if let Some(details) = node.update_details(timestamp, propagation_time) {
+                feed.push_for_node(
+                    is_authority,
+                    feed_message::ImportedBlock(nid.into(), details),
+                );
+            }
+
+            // Nodes only take the lead by getting strictly ahead, so that ties go to
+            // whichever node reached the height first:
+            let leader_height = self.leader().map(|(_, height)| height);
+            if self.leader != Some(nid) && Some(block.height) > leader_height {
+                self.leader = Some(nid);
+                feed.push(feed_message::ChainLeader(nid.into(), block.height));
             }
         }
     }
 
+    /// Find the node with the highest best block, ignoring stale nodes and nodes that haven't
+    /// reported a block yet. Ties go to the node that reported its block first.
+    fn find_leader(&self) -> Option<ChainNodeId> {
+        self.nodes
+            .iter()
+            .filter(|(_, node)| !node.stale() && node.best().height > 0)
+            .min_by_key(|&(nid, node)| {
+                (
+                    std::cmp::Reverse(node.best().height),
+                    node.best_timestamp(),
+                    usize::from(nid),
+                )
+            })
+            .map(|(nid, _)| nid)
+    }
+
     /// Check if the chain is stale (has not received a new best block in a while).
     /// If so, find a new best block, ignoring any stale nodes and marking them as such.
-    fn update_stale_nodes(&mut self, now: u64, feed: &mut FeedMessageSerializer) {
+    fn update_stale_nodes(&mut self, now: u64, feed: &mut ChainFeedSerializer) {
         let threshold = now - STALE_TIMEOUT;
         let timestamp = match self.timestamp {
             Some(ts) => ts,
@@ -329,14 +668,15 @@ impl Chain {
                     finalized = *node.finalized();
                 }
             } else {
-                feed.push(feed_message::StaleNode(nid.into()));
+                feed.push_for_node(node.is_authority(), feed_message::StaleNode(nid.into()));
             }
         }
 
         if self.best.height != 0 || self.finalized.height != 0 {
             self.best = best;
             self.finalized = finalized;
-            self.block_times.reset();
+            self.block_times
+                .reset(timestamp.map(|timestamp| (best.height, timestamp)));
             self.timestamp = timestamp;
 
             feed.push(feed_message::BestBlock(
@@ -349,9 +689,18 @@ impl Chain {
                 finalized.hash,
             ));
         }
+
+        // The leader may have been one of the nodes that went stale:
+        let leader = self.find_leader();
+        if leader != self.leader {
+            self.leader = leader;
+            if let Some((nid, height)) = self.leader() {
+                feed.push(feed_message::ChainLeader(nid.into(), height));
+            }
+        }
     }
 
-    fn regenerate_stats_if_necessary(&mut self, feed: &mut FeedMessageSerializer) {
+    fn regenerate_stats_if_necessary(&mut self, feed: &mut ChainFeedSerializer) {
         let now = Instant::now();
         let elapsed = now - self.stats_last_regenerated;
         if elapsed < STATS_UPDATE_INTERVAL {
@@ -359,11 +708,20 @@ impl Chain {
         }
 
         self.stats_last_regenerated = now;
-        let new_stats = self.stats_collator.generate();
+        let mut new_stats = self.stats_collator.generate();
+        new_stats.average_block_time = self.block_times.average();
         if new_stats != self.stats {
             self.stats = new_stats;
             feed.push(feed_message::ChainStatsUpdate(&self.stats));
         }
+
+        let new_block_propagation_stats = self.block_propagation.stats();
+        if new_block_propagation_stats != self.block_propagation_stats {
+            self.block_propagation_stats = new_block_propagation_stats;
+            if let Some(stats) = &self.block_propagation_stats {
+                feed.push(feed_message::BlockPropagation(stats));
+            }
+        }
     }
 
     pub fn update_node_location(
@@ -379,6 +737,35 @@ impl Chain {
         }
     }
 
+    pub fn update_node_clock_skew(&mut self, node_id: ChainNodeId, skew_ms: Option<i64>) -> bool {
+        match self.nodes.get_mut(node_id) {
+            Some(node) => node.set_clock_skew(skew_ms),
+            None => false,
+        }
+    }
+
+    pub fn update_node_shard_id(&mut self, node_id: ChainNodeId, shard_id: Box<str>) -> bool {
+        if let Some(node) = self.nodes.get_mut(node_id) {
+            node.set_shard_id(shard_id);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Iterate over the IDs of nodes that we haven't received a message from since the threshold given.
+    pub fn silent_node_ids(&self, threshold: Timestamp) -> impl Iterator<Item = ChainNodeId> + '_ {
+        self.nodes
+            .iter()
+            .filter(move |(_, node)| node.last_message() < threshold)
+            .map(|(nid, _)| nid)
+    }
+
+    /// Iterate over the IDs of every node on the chain.
+    pub fn node_ids(&self) -> impl Iterator<Item = ChainNodeId> + '_ {
+        self.nodes.iter().map(|(nid, _)| nid)
+    }
+
     pub fn get_node(&self, id: ChainNodeId) -> Option<&Node> {
         self.nodes.get(id)
     }
@@ -386,7 +773,13 @@ impl Chain {
         self.nodes.as_slice()
     }
     pub fn label(&self) -> &str {
-        &self.labels.best()
+        match &self.fixed_label {
+            Some(label) => label,
+            None => self.labels.best(),
+        }
+    }
+    pub fn tags(&self) -> &ChainTags {
+        &self.tags
     }
     pub fn node_count(&self) -> usize {
         self.nodes.len()
@@ -397,8 +790,12 @@ impl Chain {
     pub fn timestamp(&self) -> Option<Timestamp> {
         self.timestamp
     }
+    /// The time now, going by the same clock as the chain's block times are.
+    pub fn clock_now(&self) -> Timestamp {
+        time::now().saturating_add_signed(self.clock_offset_ms)
+    }
     pub fn average_block_time(&self) -> Option<u64> {
-        self.average_block_time
+        self.block_times.average()
     }
     pub fn finalized_block(&self) -> &Block {
         &self.finalized
@@ -409,4 +806,23 @@ impl Chain {
     pub fn stats(&self) -> &ChainStats {
         &self.stats
     }
+    pub fn block_propagation_stats(&self) -> Option<&BlockPropagationStats> {
+        self.block_propagation_stats.as_ref()
+    }
+    /// How many times the best block that most nodes agree on has been reorged.
+    pub fn reorg_count(&self) -> u64 {
+        self.reorgs.count()
+    }
+    pub fn finalized_consensus(&self) -> Option<&FinalizedConsensus> {
+        self.finality_consensus.consensus()
+    }
+    /// The GRANDPA authority set that a majority of nodes agree that the chain is on.
+    pub fn authority_set(&self) -> Option<&AuthoritySet> {
+        self.authority_sets.current()
+    }
+    /// The node that's furthest ahead on the chain, and the height of its best block.
+    pub fn leader(&self) -> Option<(ChainNodeId, BlockNumber)> {
+        let nid = self.leader?;
+        Some((nid, self.nodes.get(nid)?.best().height))
+    }
 }
@@ -16,6 +16,7 @@
 
 use super::counter::{Counter, CounterValue};
 use crate::feed_message::ChainStats;
+use common::node_types::NodeRole;
 
 // These are the benchmark scores generated on our reference hardware.
 const REFERENCE_CPU_SCORE: u64 = 1028;
@@ -101,7 +102,9 @@ fn bucket_memory(memory: u64) -> (u32, Option<u32>) {
 
 #[derive(Default)]
 pub struct ChainStatsCollator {
+    implementation: Counter<String>,
     version: Counter<String>,
+    implementation_version_os: Counter<(String, String, String)>,
     target_os: Counter<String>,
     target_arch: Counter<String>,
     cpu: Counter<String>,
@@ -114,6 +117,7 @@ pub struct ChainStatsCollator {
     memory_memcpy_score: Counter<(u32, Option<u32>)>,
     disk_sequential_write_score: Counter<(u32, Option<u32>)>,
     disk_random_write_score: Counter<(u32, Option<u32>)>,
+    role: Counter<NodeRole>,
 }
 
 impl ChainStatsCollator {
@@ -123,13 +127,25 @@ impl ChainStatsCollator {
         hwbench: Option<&common::node_types::NodeHwBench>,
         op: CounterValue,
     ) {
+        self.implementation
+            .modify(Some(&*details.implementation), op);
+
         self.version.modify(Some(&*details.version), op);
 
-        self.target_os
-            .modify(details.target_os.as_ref().map(|value| &**value), op);
+        // Nodes that don't tell us their OS are counted as unknown here:
+        let implementation_version_os = details.target_os.as_ref().map(|os| {
+            (
+                details.implementation.to_string(),
+                details.version.to_string(),
+                os.to_string(),
+            )
+        });
+        self.implementation_version_os
+            .modify(implementation_version_os.as_ref(), op);
+
+        self.target_os.modify(details.target_os.as_deref(), op);
 
-        self.target_arch
-            .modify(details.target_arch.as_ref().map(|value| &**value), op);
+        self.target_arch.modify(details.target_arch.as_deref(), op);
 
         let sysinfo = details.sysinfo.as_ref();
         self.cpu.modify(
@@ -164,6 +180,8 @@ impl ChainStatsCollator {
             op,
         );
 
+        self.role.modify(Some(&details.role), op);
+
         self.update_hwbench(hwbench, op);
     }
 
@@ -205,7 +223,9 @@ impl ChainStatsCollator {
 
     pub fn generate(&self) -> ChainStats {
         ChainStats {
+            implementation: self.implementation.generate_ranking_top(10),
             version: self.version.generate_ranking_top(10),
+            implementation_version_os: self.implementation_version_os.generate_ranking_top(20),
             target_os: self.target_os.generate_ranking_top(10),
             target_arch: self.target_arch.generate_ranking_top(10),
             cpu: self.cpu.generate_ranking_top(10),
@@ -220,6 +240,108 @@ impl ChainStatsCollator {
                 .disk_sequential_write_score
                 .generate_ranking_ordered(),
             disk_random_write_score: self.disk_random_write_score.generate_ranking_ordered(),
+            role: self.role.generate_ranking_ordered(),
+            average_block_time: None,
         }
     }
 }
+
+#[test]
+fn test_implementation_version_os_counts() {
+    let details =
+        |implementation: &str, version: &str, os: Option<&str>| common::node_types::NodeDetails {
+            chain: "".into(),
+            name: "".into(),
+            implementation: implementation.into(),
+            version: version.into(),
+            validator: None,
+            authority: false,
+            role: Default::default(),
+            network_id: Default::default(),
+            startup_time: None,
+            target_os: os.map(Into::into),
+            target_arch: None,
+            target_env: None,
+            commit: None,
+            sysinfo: None,
+            ip: None,
+        };
+
+    let mut collator = ChainStatsCollator::default();
+    for node in [
+        details("parity-polkadot", "1.0.0", Some("linux")),
+        details("parity-polkadot", "1.0.0", Some("linux")),
+        details("parity-polkadot", "1.0.0", Some("macos")),
+        details("other", "0.1.0", None),
+    ] {
+        collator.add_or_remove_node(&node, None, CounterValue::Increment);
+    }
+    collator.add_or_remove_node(
+        &details("parity-polkadot", "1.0.0", Some("macos")),
+        None,
+        CounterValue::Decrement,
+    );
+
+    let stats = collator.generate();
+    assert_eq!(
+        stats.implementation.list,
+        vec![("parity-polkadot".to_owned(), 2), ("other".to_owned(), 1)]
+    );
+    assert_eq!(
+        stats.implementation_version_os.list,
+        vec![(
+            (
+                "parity-polkadot".to_owned(),
+                "1.0.0".to_owned(),
+                "linux".to_owned()
+            ),
+            2
+        )]
+    );
+    assert_eq!(stats.implementation_version_os.unknown, 1);
+}
+
+#[test]
+fn test_role_counts() {
+    let details = |role: NodeRole| common::node_types::NodeDetails {
+        chain: "".into(),
+        name: "".into(),
+        implementation: "".into(),
+        version: "".into(),
+        validator: None,
+        authority: role == NodeRole::Authority,
+        role,
+        network_id: Default::default(),
+        startup_time: None,
+        target_os: None,
+        target_arch: None,
+        target_env: None,
+        commit: None,
+        sysinfo: None,
+        ip: None,
+    };
+
+    let mut collator = ChainStatsCollator::default();
+    for role in [
+        NodeRole::Collator,
+        NodeRole::Full,
+        NodeRole::Authority,
+        NodeRole::Full,
+        NodeRole::Light,
+        NodeRole::Authority,
+    ] {
+        collator.add_or_remove_node(&details(role), None, CounterValue::Increment);
+    }
+    collator.add_or_remove_node(&details(NodeRole::Light), None, CounterValue::Decrement);
+
+    let stats = collator.generate();
+    assert_eq!(
+        stats.role.list,
+        vec![
+            (NodeRole::Full, 2),
+            (NodeRole::Authority, 2),
+            (NodeRole::Collator, 1)
+        ]
+    );
+    assert_eq!(stats.role.unknown, 0);
+}
@@ -0,0 +1,220 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::{BTreeMap, HashMap};
+
+use common::node_types::{Block, BlockHash, BlockNumber};
+
+use crate::feed_message::FinalizedConsensus;
+
+/// How many of the most recent finalized heights we keep track of the reported hashes
+/// for by default. Heights older than this are assumed to be long agreed upon.
+pub const DEFAULT_TRACKED_HEIGHTS: usize = 64;
+
+/// Works out which finalized block a majority of the nodes on a chain agree on, so that
+/// there's a single finalized height for the chain rather than one per node.
+pub struct FinalityConsensus {
+    /// How many nodes have reported each hash as finalized at each recent height.
+    reports: BTreeMap<BlockNumber, HashMap<BlockHash, usize>>,
+    /// The most heights that we keep track of at once.
+    max_heights: usize,
+    /// The finalized block that a majority of nodes last agreed on.
+    consensus: Option<FinalizedConsensus>,
+}
+
+impl Default for FinalityConsensus {
+    fn default() -> Self {
+        FinalityConsensus {
+            reports: BTreeMap::new(),
+            max_heights: DEFAULT_TRACKED_HEIGHTS,
+            consensus: None,
+        }
+    }
+}
+
+impl FinalityConsensus {
+    /// Keep track of the hashes reported at most this many of the most recent heights (and
+    /// at least one), forgetting about older ones straight away if need be.
+    pub fn set_max_heights(&mut self, max_heights: usize) {
+        self.max_heights = max_heights.max(1);
+        while self.reports.len() > self.max_heights {
+            self.reports.pop_first();
+        }
+    }
+
+    /// Make a note that a node has reported a new finalized block.
+    pub fn record(&mut self, block: Block) {
+        // Nodes catching up report heights that we've long since stopped tracking:
+        let is_full = self.reports.len() >= self.max_heights;
+        if is_full
+            && self
+                .reports
+                .keys()
+                .next()
+                .is_some_and(|&h| block.height < h)
+        {
+            return;
+        }
+
+        *self
+            .reports
+            .entry(block.height)
+            .or_default()
+            .entry(block.hash)
+            .or_default() += 1;
+        while self.reports.len() > self.max_heights {
+            self.reports.pop_first();
+        }
+    }
+
+    /// Given the height that each node on the chain has finalized, work out the highest
+    /// height that a majority of the nodes reporting finalization have reached. With only
+    /// one or two such nodes, they must all have reached it. If there's a new consensus as
+    /// a result, it's returned.
+    ///
+    /// Nodes only tell us the hash of the latest block they've finalized, so the hash is
+    /// whichever has been reported most often at that height. If different hashes have been
+    /// reported, the consensus is flagged as having diverged.
+    pub fn update(
+        &mut self,
+        finalized_heights: impl Iterator<Item = BlockNumber>,
+    ) -> Option<&FinalizedConsensus> {
+        let mut heights: Vec<BlockNumber> = finalized_heights.filter(|&h| h > 0).collect();
+        if heights.is_empty() {
+            return None;
+        }
+        let majority = heights.len() / 2;
+        let (_, &mut height, _) = heights.select_nth_unstable_by(majority, |a, b| b.cmp(a));
+
+        let hashes = self.reports.get(&height)?;
+        let (&hash, _) = hashes
+            .iter()
+            .max_by(|(hash_a, count_a), (hash_b, count_b)| {
+                count_a.cmp(count_b).then(hash_b.cmp(hash_a))
+            })?;
+        let consensus = FinalizedConsensus {
+            height,
+            hash,
+            diverged: hashes.len() > 1,
+        };
+
+        // Nodes leaving can briefly drag the majority back, but finalized blocks stay finalized:
+        match &self.consensus {
+            Some(current) if current.height > height || *current == consensus => None,
+            _ => {
+                self.consensus = Some(consensus);
+                self.consensus.as_ref()
+            }
+        }
+    }
+
+    /// The finalized block that a majority of nodes last agreed on, if any.
+    pub fn consensus(&self) -> Option<&FinalizedConsensus> {
+        self.consensus.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn block(height: BlockNumber, hash: u64) -> Block {
+        Block {
+            height,
+            hash: BlockHash::from_low_u64_be(hash),
+        }
+    }
+
+    #[test]
+    fn staggered_finalization_reaches_consensus_once_a_majority_agree() {
+        let mut consensus = FinalityConsensus::default();
+        let mut finalized = [0; 5];
+        let mut report = |consensus: &mut FinalityConsensus, node: usize, height: BlockNumber| {
+            finalized[node] = height;
+            consensus.record(block(height, height));
+            consensus.update(finalized.into_iter()).copied()
+        };
+        let agreed = |height| {
+            Some(FinalizedConsensus {
+                height,
+                hash: BlockHash::from_low_u64_be(height),
+                diverged: false,
+            })
+        };
+
+        // One node is a majority of the nodes reporting finalization so far:
+        assert_eq!(report(&mut consensus, 0, 10), agreed(10));
+
+        // Nodes that are behind don't take the consensus backwards:
+        assert_eq!(report(&mut consensus, 1, 8), None);
+        assert_eq!(report(&mut consensus, 2, 9), None);
+        assert_eq!(report(&mut consensus, 3, 10), None);
+        assert_eq!(report(&mut consensus, 4, 10), None);
+
+        // Height 11 is only agreed once three of the five nodes have reached it:
+        assert_eq!(report(&mut consensus, 1, 11), None);
+        assert_eq!(report(&mut consensus, 2, 12), None);
+        assert_eq!(report(&mut consensus, 3, 11), agreed(11));
+        assert_eq!(consensus.consensus().copied(), agreed(11));
+
+        // ..and 12 once a third node has reached it (or gone past it):
+        assert_eq!(report(&mut consensus, 0, 12), None);
+        assert_eq!(report(&mut consensus, 4, 13), agreed(12));
+    }
+
+    #[test]
+    fn conflicting_hashes_are_flagged_and_the_majority_reported() {
+        let mut consensus = FinalityConsensus::default();
+        consensus.record(block(10, 1));
+        consensus.record(block(10, 2));
+        consensus.record(block(10, 1));
+
+        assert_eq!(
+            consensus.update([10, 10, 10].into_iter()).copied(),
+            Some(FinalizedConsensus {
+                height: 10,
+                hash: BlockHash::from_low_u64_be(1),
+                diverged: true,
+            })
+        );
+    }
+
+    #[test]
+    fn tracking_many_heights_stays_bounded() {
+        let mut consensus = FinalityConsensus::default();
+        consensus.set_max_heights(8);
+
+        // A fast chain, with a few nodes finalizing every height (one of them on a fork):
+        for height in 1..=100_000 {
+            consensus.record(block(height, height));
+            consensus.record(block(height, height));
+            consensus.record(block(height, height + 1));
+            assert!(consensus.reports.len() <= 8);
+        }
+        assert_eq!(consensus.reports.keys().next(), Some(&99_993));
+        assert!(consensus.reports.values().all(|hashes| hashes.len() == 2));
+        assert_eq!(
+            consensus
+                .update([100_000, 100_000, 100_000].into_iter())
+                .copied(),
+            Some(FinalizedConsensus {
+                height: 100_000,
+                hash: BlockHash::from_low_u64_be(100_000),
+                diverged: true,
+            })
+        );
+    }
+}
@@ -14,12 +14,19 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+mod authority_sets;
+mod block_propagation;
+mod block_times;
 mod chain;
 mod chain_stats;
 mod counter;
+mod finality_consensus;
 mod node;
+mod reorgs;
 
+#[allow(clippy::module_inception)]
 mod state;
 
-pub use node::Node;
+pub use chain::ChainTags;
+pub use node::{Node, PeerCountHandling};
 pub use state::*;
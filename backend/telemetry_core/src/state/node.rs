@@ -15,12 +15,69 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use crate::find_location;
-use common::node_message::SystemInterval;
+use common::node_message::{parse_startup_time, SystemInterval, MAX_CUSTOM_METRICS};
 use common::node_types::{
     Block, BlockDetails, NodeDetails, NodeHardware, NodeHwBench, NodeIO, NodeLocation, NodeStats,
-    Timestamp,
+    NodeSyncState, Timestamp,
 };
-use common::time;
+use common::{time, MeanList};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// How should we handle nodes that report implausible peer counts (that is,
+/// negative counts, or no peers while they appear to be synced)?
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerCountHandling {
+    /// Clamp negative peer counts to zero.
+    Clamp,
+    /// Clamp negative peer counts to zero, and flag the node's stats
+    /// so that feeds know not to trust the count.
+    Flag,
+}
+
+impl std::str::FromStr for PeerCountHandling {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clamp" => Ok(PeerCountHandling::Clamp),
+            "flag" => Ok(PeerCountHandling::Flag),
+            _ => Err(anyhow::anyhow!(
+                "'{s}' is not a valid option; expected 'clamp' or 'flag'"
+            )),
+        }
+    }
+}
+
+/// Which of a node's interval-derived details have changed and need sending to feeds.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct IntervalUpdates {
+    pub hardware: bool,
+    pub stats: bool,
+    pub peer_counts: bool,
+    pub io: bool,
+    pub custom_metrics: bool,
+    /// Every interval message moves on when we last heard from the node.
+    pub last_seen: bool,
+}
+
+impl IntervalUpdates {
+    fn any(&self) -> bool {
+        self.hardware
+            || self.stats
+            || self.peer_counts
+            || self.io
+            || self.custom_metrics
+            || self.last_seen
+    }
+    fn merge(&mut self, other: IntervalUpdates) {
+        self.hardware |= other.hardware;
+        self.stats |= other.stats;
+        self.peer_counts |= other.peer_counts;
+        self.io |= other.io;
+        self.custom_metrics |= other.custom_metrics;
+        self.last_seen |= other.last_seen;
+    }
+}
 
 /// Minimum time between block below broadcasting updates to the browser gets throttled, in ms.
 const THROTTLE_THRESHOLD: u64 = 100;
@@ -50,27 +107,81 @@ pub struct Node {
     startup_time: Option<Timestamp>,
     /// Hardware benchmark results for the node
     hwbench: Option<NodeHwBench>,
+    /// Unix timestamp for when we last received a message from the node
+    last_message: Timestamp,
+    /// ID of the shard that the node is connected through
+    shard_id: Option<Box<str>>,
+    /// Metrics that the node reported which we don't otherwise understand
+    custom_metrics: HashMap<String, f64>,
+    /// Peer counts over time
+    peer_counts: MeanList<f32>,
+    /// Unix timestamp for when we last sent interval-derived updates to feeds
+    last_interval_updates: Timestamp,
+    /// Interval-derived updates which haven't been sent to feeds yet
+    pending_interval_updates: IntervalUpdates,
+    /// Was the node let in despite its chain not being on the allow list?
+    bypassed_allowlist: bool,
+    /// How far ahead of the shard's clock the node's clock is in ms, if it's too far out
+    clock_skew: Option<i64>,
 }
 
 impl Node {
-    pub fn new(mut details: NodeDetails) -> Self {
+    pub fn new(details: NodeDetails) -> Self {
+        Node::with_stat_history_len(details, None)
+    }
+
+    /// Like [`Node::new`], but keeping at most `stat_history_len` values of each of the node's
+    /// stat series rather than the default number.
+    pub fn with_stat_history_len(
+        mut details: NodeDetails,
+        stat_history_len: Option<usize>,
+    ) -> Self {
         let startup_time = details
             .startup_time
             .take()
-            .and_then(|time| time.parse().ok());
+            .and_then(|time| parse_startup_time(&time));
+        let (io, hardware, peer_counts) = match stat_history_len {
+            Some(len) => (NodeIO::new(len), NodeHardware::new(len), MeanList::new(len)),
+            None => Default::default(),
+        };
 
         Node {
             details,
             stats: NodeStats::default(),
-            io: NodeIO::default(),
+            io,
             best: BlockDetails::default(),
             finalized: Block::zero(),
             throttle: 0,
-            hardware: NodeHardware::default(),
+            hardware,
             location: None,
             stale: false,
             startup_time,
             hwbench: None,
+            last_message: time::now(),
+            shard_id: None,
+            custom_metrics: HashMap::new(),
+            peer_counts,
+            last_interval_updates: 0,
+            pending_interval_updates: IntervalUpdates::default(),
+            bypassed_allowlist: false,
+            clock_skew: None,
+        }
+    }
+
+    /// A node that had disconnected has reconnected with the given details. It
+    /// keeps the stats and history that it had built up, but is otherwise new.
+    pub fn reconnected(self, details: NodeDetails) -> Self {
+        Node {
+            stats: self.stats,
+            io: self.io,
+            best: self.best,
+            finalized: self.finalized,
+            hardware: self.hardware,
+            location: self.location,
+            hwbench: self.hwbench,
+            custom_metrics: self.custom_metrics,
+            peer_counts: self.peer_counts,
+            ..Node::new(details)
         }
     }
 
@@ -138,7 +249,8 @@ impl Node {
         timestamp: u64,
         propagation_time: Option<u64>,
     ) -> Option<&BlockDetails> {
-        self.best.block_time = timestamp - self.best.block_timestamp;
+        // Nodes' clocks can go backwards, so this can't assume that time only moves forwards:
+        self.best.block_time = timestamp.saturating_sub(self.best.block_timestamp);
         self.best.block_timestamp = timestamp;
         self.best.propagation_time = propagation_time;
 
@@ -162,19 +274,55 @@ impl Node {
         if let Some(download) = interval.bandwidth_download {
             changed |= self.hardware.download.push(download);
         }
+        if let Some(cpu) = interval.cpu {
+            changed |= self.hardware.cpu.push(cpu);
+        }
+        if let Some(memory) = interval.memory {
+            changed |= self.hardware.memory.push(memory);
+        }
+        if let Some(disk_usage) = interval.disk_usage {
+            changed |= self.hardware.disk_usage.push(disk_usage);
+        }
+        if let Some(disk_read) = interval.disk_read_per_sec {
+            changed |= self.hardware.disk_read.push(disk_read);
+        }
+        if let Some(disk_write) = interval.disk_write_per_sec {
+            changed |= self.hardware.disk_write.push(disk_write);
+        }
         self.hardware.chart_stamps.push(time::now() as f64);
 
         changed
     }
 
-    pub fn update_stats(&mut self, interval: &SystemInterval) -> Option<&NodeStats> {
+    /// Update the node stats given some interval message, and whether the node is syncing as
+    /// of it. If `synced` is true, the node is expected to have found some peers.
+    pub fn update_stats(
+        &mut self,
+        interval: &SystemInterval,
+        synced: bool,
+        sync_state: NodeSyncState,
+        peer_count_handling: PeerCountHandling,
+    ) -> Option<&NodeStats> {
         let mut changed = false;
 
+        if self.stats.sync_state != Some(sync_state) {
+            self.stats.sync_state = Some(sync_state);
+            changed = true;
+        }
+
         if let Some(peers) = interval.peers {
+            let implausible_peers = peer_count_handling == PeerCountHandling::Flag
+                && (peers < 0 || (peers == 0 && synced));
+            let peers = peers.max(0) as u64;
+
             if peers != self.stats.peers {
                 self.stats.peers = peers;
                 changed = true;
             }
+            if implausible_peers != self.stats.implausible_peers {
+                self.stats.implausible_peers = implausible_peers;
+                changed = true;
+            }
         }
         if let Some(txcount) = interval.txcount {
             if txcount != self.stats.txcount {
@@ -190,6 +338,47 @@ impl Node {
         }
     }
 
+    /// Update the custom metrics reported by the node, returning them if any have changed.
+    /// Once a node has [`MAX_CUSTOM_METRICS`] of them, any new ones are ignored.
+    pub fn update_custom_metrics(
+        &mut self,
+        interval: &SystemInterval,
+    ) -> Option<&HashMap<String, f64>> {
+        let mut changed = false;
+        for (key, &value) in &interval.custom_metrics {
+            let at_capacity = self.custom_metrics.len() >= MAX_CUSTOM_METRICS;
+            match self.custom_metrics.get_mut(key) {
+                Some(current) if *current != value => {
+                    *current = value;
+                    changed = true;
+                }
+                Some(_) => {}
+                None if !at_capacity => {
+                    self.custom_metrics.insert(key.clone(), value);
+                    changed = true;
+                }
+                None => {}
+            }
+        }
+        changed.then_some(&self.custom_metrics)
+    }
+
+    pub fn custom_metrics(&self) -> &HashMap<String, f64> {
+        &self.custom_metrics
+    }
+
+    /// Record the peer count reported by the node, returning the series of peer counts
+    /// over time if it has changed. Negative counts are recorded as zero peers.
+    pub fn update_peer_counts(&mut self, interval: &SystemInterval) -> Option<&[f32]> {
+        let peers = interval.peers?;
+        let changed = self.peer_counts.push(peers.max(0) as f32);
+        changed.then(|| self.peer_counts.slice())
+    }
+
+    pub fn peer_counts(&self) -> &[f32] {
+        self.peer_counts.slice()
+    }
+
     pub fn update_io(&mut self, interval: &SystemInterval) -> Option<&NodeIO> {
         let mut changed = false;
 
@@ -204,6 +393,27 @@ impl Node {
         }
     }
 
+    /// Record that the given interval-derived details have changed, returning every change
+    /// that's due to be sent to feeds. Changes are held back until at least `min_interval`
+    /// has passed since they were last sent, so that feeds see at most one update per interval
+    /// containing the latest values.
+    pub fn coalesce_interval_updates(
+        &mut self,
+        changed: IntervalUpdates,
+        now: Timestamp,
+        min_interval: Duration,
+    ) -> Option<IntervalUpdates> {
+        self.pending_interval_updates.merge(changed);
+        if !self.pending_interval_updates.any()
+            || now.saturating_sub(self.last_interval_updates) < min_interval.as_millis() as u64
+        {
+            return None;
+        }
+
+        self.last_interval_updates = now;
+        Some(std::mem::take(&mut self.pending_interval_updates))
+    }
+
     pub fn update_finalized(&mut self, block: Block) -> Option<&Block> {
         if block.height > self.finalized.height {
             self.finalized = block;
@@ -213,6 +423,24 @@ impl Node {
         }
     }
 
+    /// Recalculate how far our best block is ahead of our finalized block, returning the
+    /// updated stats if this has changed. The lag is unknown until a finalized block has
+    /// been reported, and is clamped to zero if finalization briefly overtakes the best block.
+    pub fn update_finality_lag(&mut self) -> Option<&NodeStats> {
+        let finality_lag = if self.finalized.height == 0 {
+            None
+        } else {
+            Some(self.best.block.height.saturating_sub(self.finalized.height))
+        };
+
+        if finality_lag != self.stats.finality_lag {
+            self.stats.finality_lag = finality_lag;
+            Some(&self.stats)
+        } else {
+            None
+        }
+    }
+
     pub fn update_stale(&mut self, threshold: u64) -> bool {
         if self.best.block_timestamp < threshold {
             self.stale = true;
@@ -225,6 +453,33 @@ impl Node {
         self.stale
     }
 
+    pub fn last_message(&self) -> Timestamp {
+        self.last_message
+    }
+
+    pub fn update_last_message(&mut self, now: Timestamp) {
+        self.last_message = now;
+    }
+
+    pub fn shard_id(&self) -> Option<&str> {
+        self.shard_id.as_deref()
+    }
+
+    pub fn set_shard_id(&mut self, shard_id: Box<str>) {
+        self.shard_id = Some(shard_id);
+    }
+
+    pub fn is_authority(&self) -> bool {
+        self.details.authority
+    }
+
+    /// Set whether the node is an authority, returning true if this has changed.
+    pub fn set_authority(&mut self, authority: bool) -> bool {
+        let changed = self.details.authority != authority;
+        self.details.authority = authority;
+        changed
+    }
+
     pub fn set_validator_address(&mut self, addr: Box<str>) -> bool {
         if self.details.validator.as_ref() == Some(&addr) {
             false
@@ -237,4 +492,162 @@ impl Node {
     pub fn startup_time(&self) -> Option<Timestamp> {
         self.startup_time
     }
+
+    pub fn bypassed_allowlist(&self) -> bool {
+        self.bypassed_allowlist
+    }
+
+    pub fn set_bypassed_allowlist(&mut self, bypassed: bool) {
+        self.bypassed_allowlist = bypassed;
+    }
+
+    pub fn clock_skew(&self) -> Option<i64> {
+        self.clock_skew
+    }
+
+    /// Set how skewed the node's clock is, returning true if this has changed.
+    pub fn set_clock_skew(&mut self, skew_ms: Option<i64>) -> bool {
+        let changed = self.clock_skew != skew_ms;
+        self.clock_skew = skew_ms;
+        changed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn node() -> Node {
+        Node::new(NodeDetails {
+            chain: "Chain".into(),
+            name: "Node".into(),
+            implementation: "Bar".into(),
+            target_arch: None,
+            target_os: None,
+            target_env: None,
+            commit: None,
+            version: "0.1".into(),
+            validator: None,
+            authority: false,
+            role: Default::default(),
+            network_id: Default::default(),
+            startup_time: None,
+            sysinfo: None,
+            ip: None,
+        })
+    }
+
+    fn interval(cpu: Option<f32>, memory: Option<f32>) -> SystemInterval {
+        SystemInterval {
+            peers: None,
+            txcount: None,
+            bandwidth_upload: None,
+            bandwidth_download: None,
+            finalized_height: None,
+            finalized_hash: None,
+            block: None,
+            used_state_cache_size: None,
+            cpu,
+            memory,
+            disk_usage: None,
+            disk_read_per_sec: None,
+            disk_write_per_sec: None,
+            is_major_syncing: None,
+            custom_metrics: Default::default(),
+        }
+    }
+
+    #[test]
+    fn resource_usage_is_added_to_hardware_series() {
+        let mut node = node();
+
+        assert!(node.update_hardware(&interval(Some(12.5), Some(1024.0))));
+        assert!(node.update_hardware(&interval(Some(25.0), None)));
+        // Nothing reported, so nothing changes:
+        assert!(!node.update_hardware(&interval(None, None)));
+
+        assert_eq!(node.hardware().cpu.slice(), &[12.5, 25.0]);
+        assert_eq!(node.hardware().memory.slice(), &[1024.0]);
+        assert!(node.hardware().disk_usage.slice().is_empty());
+
+        // The new series are sent to feeds after the existing ones:
+        let json = serde_json::to_value(node.hardware()).unwrap();
+        assert_eq!(json[3], serde_json::json!([12.5, 25.0]));
+        assert_eq!(json[4], serde_json::json!([1024.0]));
+        assert_eq!(json[5], serde_json::json!([]));
+    }
+
+    #[test]
+    fn disk_io_is_added_to_hardware_series() {
+        let mut node = node();
+
+        let disk_io = |read: Option<f64>, write: Option<f64>| SystemInterval {
+            disk_read_per_sec: read,
+            disk_write_per_sec: write,
+            ..interval(None, None)
+        };
+        assert!(node.update_hardware(&disk_io(Some(1024.0), Some(2048.0))));
+        assert!(node.update_hardware(&disk_io(Some(4096.0), None)));
+        // Nodes that don't report disk I/O leave the series alone:
+        assert!(!node.update_hardware(&disk_io(None, None)));
+
+        assert_eq!(node.hardware().disk_read.slice(), &[1024.0, 4096.0]);
+        assert_eq!(node.hardware().disk_write.slice(), &[2048.0]);
+
+        // These are sent to feeds after every other hardware series:
+        let json = serde_json::to_value(node.hardware()).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), 8);
+        assert_eq!(json[6], serde_json::json!([1024.0, 4096.0]));
+        assert_eq!(json[7], serde_json::json!([2048.0]));
+    }
+
+    #[test]
+    fn interval_updates_are_coalesced() {
+        let mut node = node();
+        let stats = IntervalUpdates {
+            stats: true,
+            ..Default::default()
+        };
+        let io = IntervalUpdates {
+            io: true,
+            ..Default::default()
+        };
+        let min_interval = Duration::from_secs(1);
+
+        // The first change is sent straight away:
+        assert_eq!(
+            node.coalesce_interval_updates(stats, 10_000, min_interval),
+            Some(stats)
+        );
+
+        // Changes within the interval are held back, and nothing is sent if nothing changed:
+        assert_eq!(
+            node.coalesce_interval_updates(io, 10_400, min_interval),
+            None
+        );
+        assert_eq!(
+            node.coalesce_interval_updates(IntervalUpdates::default(), 10_800, min_interval),
+            None
+        );
+
+        // Once the interval has passed, everything that changed in the meantime is sent:
+        assert_eq!(
+            node.coalesce_interval_updates(stats, 11_000, min_interval),
+            Some(IntervalUpdates {
+                stats: true,
+                io: true,
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            node.coalesce_interval_updates(IntervalUpdates::default(), 12_000, min_interval),
+            None
+        );
+
+        // With no minimum interval, changes are always sent immediately:
+        assert_eq!(
+            node.coalesce_interval_updates(io, 12_001, Duration::ZERO),
+            Some(io)
+        );
+    }
 }
@@ -0,0 +1,207 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::{BTreeMap, HashMap};
+
+use common::node_types::{Block, BlockHash, BlockNumber};
+
+use super::chain::ChainNodeId;
+
+/// How many of the most recent best block heights we keep track of the reported hashes for
+/// by default. Reorgs deeper than this go unnoticed.
+pub const DEFAULT_TRACKED_HEIGHTS: usize = 20;
+
+/// The hashes that nodes have reported as the best block at a single height.
+#[derive(Default)]
+struct HeightReports {
+    /// The hash that each node last reported at this height.
+    hashes: HashMap<ChainNodeId, BlockHash>,
+    /// The hash that most nodes reported, which the network agrees on.
+    agreed: Option<BlockHash>,
+}
+
+impl HeightReports {
+    fn nodes_reporting(&self, hash: BlockHash) -> usize {
+        self.hashes.values().filter(|&&h| h == hash).count()
+    }
+}
+
+/// Spots reorgs on a chain, by keeping track of which best block hash most nodes have
+/// reported at each recent height and noticing when a different hash overtakes it.
+pub struct Reorgs {
+    reports: BTreeMap<BlockNumber, HeightReports>,
+    /// The most heights that we keep track of at once.
+    max_heights: usize,
+    /// How many reorgs we've seen.
+    count: u64,
+}
+
+impl Default for Reorgs {
+    fn default() -> Self {
+        Reorgs {
+            reports: BTreeMap::new(),
+            max_heights: DEFAULT_TRACKED_HEIGHTS,
+            count: 0,
+        }
+    }
+}
+
+impl Reorgs {
+    /// Keep track of the hashes reported at most this many of the most recent heights (and
+    /// at least one), forgetting about older ones straight away if need be.
+    pub fn set_max_heights(&mut self, max_heights: usize) {
+        self.max_heights = max_heights.max(1);
+        while self.reports.len() > self.max_heights {
+            self.reports.pop_first();
+        }
+    }
+
+    /// Make a note that a node has reported a new best block. If more nodes now agree on
+    /// a different hash at that height than on the one before, that's a reorg, and its depth
+    /// (how many of the blocks that we know about were replaced) is returned.
+    ///
+    /// Reports that arrive late, or out of order, only count towards the hash that they're
+    /// for, and so aren't reorgs unless that hash comes to be reported by most nodes.
+    pub fn record(&mut self, nid: ChainNodeId, block: Block) -> Option<u64> {
+        // Nodes catching up report heights that we've long since stopped tracking:
+        let is_full = self.reports.len() >= self.max_heights;
+        if is_full
+            && self
+                .reports
+                .keys()
+                .next()
+                .is_some_and(|&h| block.height < h)
+        {
+            return None;
+        }
+
+        let tip = self.tip();
+        let reports = self.reports.entry(block.height).or_default();
+        reports.hashes.insert(nid, block.hash);
+
+        let reorged = match reports.agreed {
+            None => {
+                reports.agreed = Some(block.hash);
+                false
+            }
+            Some(agreed) if agreed == block.hash => false,
+            Some(agreed) => {
+                let overtaken =
+                    reports.nodes_reporting(block.hash) > reports.nodes_reporting(agreed);
+                if overtaken {
+                    reports.agreed = Some(block.hash);
+                }
+                overtaken
+            }
+        };
+        while self.reports.len() > self.max_heights {
+            self.reports.pop_first();
+        }
+        if !reorged {
+            return None;
+        }
+
+        // The blocks above this one were on the branch that's been abandoned. Forget about
+        // them, so that nodes moving over to the new branch aren't counted as more reorgs:
+        self.reports.split_off(&(block.height + 1));
+        self.count += 1;
+        Some(tip.map_or(1, |tip| tip.saturating_sub(block.height) + 1))
+    }
+
+    /// The highest height that we've been told about a best block at.
+    fn tip(&self) -> Option<BlockNumber> {
+        self.reports.keys().next_back().copied()
+    }
+
+    /// How many reorgs we've seen.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn node(id: usize) -> ChainNodeId {
+        ChainNodeId::new(id)
+    }
+
+    fn block(height: BlockNumber, hash: u64) -> Block {
+        Block {
+            height,
+            hash: BlockHash::from_low_u64_be(hash),
+        }
+    }
+
+    #[test]
+    fn a_new_hash_overtaking_the_agreed_one_is_a_reorg() {
+        let mut reorgs = Reorgs::default();
+
+        // Three nodes agree on blocks 10 and 11 (hashes 1 and 2):
+        for id in 0..3 {
+            assert_eq!(reorgs.record(node(id), block(10, 1)), None);
+            assert_eq!(reorgs.record(node(id), block(11, 2)), None);
+        }
+
+        // A single node on a fork isn't enough to reorg, however often it says so:
+        assert_eq!(reorgs.record(node(0), block(10, 3)), None);
+        assert_eq!(reorgs.record(node(0), block(10, 3)), None);
+        assert_eq!(reorgs.count(), 0);
+
+        // But once more nodes are on the fork than not, blocks 10 and 11 are replaced:
+        assert_eq!(reorgs.record(node(1), block(10, 3)), Some(2));
+        assert_eq!(reorgs.count(), 1);
+
+        // Nodes following on from the new block 10 aren't more reorgs:
+        for id in 0..3 {
+            assert_eq!(reorgs.record(node(id), block(10, 3)), None);
+            assert_eq!(reorgs.record(node(id), block(11, 4)), None);
+        }
+        assert_eq!(reorgs.count(), 1);
+    }
+
+    #[test]
+    fn out_of_order_reports_are_not_reorgs() {
+        let mut reorgs = Reorgs::default();
+
+        for height in 1..=5 {
+            assert_eq!(reorgs.record(node(0), block(height, height)), None);
+        }
+        // A lagging node reporting the same blocks, in a different order, changes nothing:
+        for height in [3, 1, 5, 2, 4] {
+            assert_eq!(reorgs.record(node(1), block(height, height)), None);
+        }
+        assert_eq!(reorgs.count(), 0);
+    }
+
+    #[test]
+    fn only_recent_heights_are_tracked() {
+        let mut reorgs = Reorgs::default();
+        reorgs.set_max_heights(4);
+
+        for height in 1..=100 {
+            reorgs.record(node(0), block(height, height));
+        }
+        assert_eq!(reorgs.reports.len(), 4);
+
+        // Heights that we've stopped tracking can't be reorged:
+        for id in 1..4 {
+            assert_eq!(reorgs.record(node(id), block(50, 1000)), None);
+        }
+        assert_eq!(reorgs.count(), 0);
+    }
+}
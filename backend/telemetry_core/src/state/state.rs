@@ -14,16 +14,20 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use super::node::Node;
-use crate::feed_message::{ChainStats, FeedMessageSerializer};
+use super::node::{Node, PeerCountHandling};
+use crate::feed_message::{
+    AuthoritySet, BlockPropagationStats, ChainFeedSerializer, ChainStats, FinalizedConsensus,
+};
 use crate::find_location;
 use common::node_message::Payload;
-use common::node_types::{Block, BlockHash, NodeDetails, Timestamp};
-use common::{id_type, DenseMap};
+use common::node_types::{Block, BlockHash, BlockNumber, NetworkId, NodeDetails, Timestamp};
+use common::{id_type, time, DenseMap};
 use std::collections::{HashMap, HashSet};
 use std::iter::IntoIterator;
+use std::str::FromStr;
+use std::time::Duration;
 
-use super::chain::{self, Chain, ChainNodeId};
+use super::chain::{self, Chain, ChainNodeId, ChainTags, Label};
 
 id_type! {
     /// A globally unique Chain ID.
@@ -41,6 +45,23 @@ impl NodeId {
     }
 }
 
+/// How many disconnected nodes do we remember, so that they can pick up where
+/// they left off if they reconnect?
+const MAX_DEPARTED_NODES: usize = 1000;
+
+/// A node is identified across reconnects by the genesis hash of its chain
+/// and the network (peer) ID that it reports.
+type NodeIdentity = (BlockHash, NetworkId);
+
+/// A node that has disconnected, which we remember in case it reconnects.
+struct DepartedNode {
+    /// The ID it had on its chain.
+    id: ChainNodeId,
+    node: Node,
+    /// Nodes that departed longer ago have smaller numbers here.
+    departure: u64,
+}
+
 /// Our state contains node and chain information
 pub struct State {
     chains: DenseMap<ChainId, Chain>,
@@ -51,17 +72,61 @@ pub struct State {
     /// Chain labels that we do not want to allow connecting.
     denylist: HashSet<String>,
 
+    /// If not empty, only chains with these genesis hashes are allowed to
+    /// connect, and they are given the corresponding label.
+    allowlist: HashMap<BlockHash, Label>,
+
+    /// Tags to attach to chains with these genesis hashes when they're created.
+    chain_tags: HashMap<BlockHash, ChainTags>,
+
+    /// Only authority nodes are allowed to connect to chains with these genesis hashes.
+    authority_only_chains: HashSet<BlockHash>,
+
     /// How many nodes from third party chains are allowed to connect
     /// before we prevent connections from them.
     max_third_party_nodes: usize,
+
+    /// How many chains can we keep track of at once? Nodes on new chains
+    /// are turned away once we're at this limit.
+    max_chains: usize,
+
+    /// Nodes that have recently disconnected.
+    departed_nodes: HashMap<NodeIdentity, DepartedNode>,
+
+    /// How many departures there have been.
+    departures: u64,
+
+    /// The connected nodes with each identity, by name. Nodes should have unique
+    /// network IDs, but we can't rely on that.
+    connected_identities: HashMap<NodeIdentity, HashMap<Box<str>, Vec<ChainNodeId>>>,
+
+    /// How long do chains stick around for once their last node has gone? If zero,
+    /// they are removed straight away.
+    empty_chain_ttl: Duration,
+
+    /// When did each of the chains with no nodes left become empty?
+    empty_chains: HashMap<ChainId, Timestamp>,
+    /// Best blocks more than this many blocks ahead of their chain's best block are ignored.
+    max_block_height_jump: Option<u64>,
+    /// How many values of each stat series new nodes keep, if not the default.
+    stat_history_len: Option<usize>,
+    /// How many recent block heights chains keep track of, if not the defaults.
+    block_window: Option<usize>,
 }
 
 /// Adding a node to a chain leads to this result.
 pub enum AddNodeResult<'a> {
     /// The chain is on the "deny list", so we can't add the node
     ChainOnDenyList,
+    /// An allow list is in use and the chain isn't on it, so we can't add the node
+    ChainNotOnAllowList,
+    /// Only authority nodes are tracked on the chain, and the node isn't one
+    NotAnAuthority,
     /// The chain is over quota (too many nodes connected), so can't add the node
     ChainOverQuota,
+    /// The chain is new to us, but we're already tracking as many chains as we're
+    /// allowed to, so can't add the node
+    TooManyChains,
     /// The node was added to the chain
     NodeAddedToChain(NodeAddedToChain<'a>),
 }
@@ -80,13 +145,16 @@ pub struct NodeAddedToChain<'a> {
     /// The ID assigned to this node.
     pub id: NodeId,
     /// The old label of the chain.
+    #[allow(dead_code)]
     pub old_chain_label: Box<str>,
     /// The new label of the chain.
     pub new_chain_label: &'a str,
     /// The node that was added.
     pub node: &'a Node,
-    /// Number of nodes in the chain. If 1, the chain was just added.
+    /// Number of nodes in the chain.
     pub chain_node_count: usize,
+    /// Was the chain added, because this is the first node on it that we've seen?
+    pub chain_added: bool,
     /// Has the chain label been updated?
     pub has_chain_label_changed: bool,
 }
@@ -95,26 +163,229 @@ pub struct NodeAddedToChain<'a> {
 pub struct RemovedNode {
     /// How many nodes remain on the chain (0 if the chain was removed)
     pub chain_node_count: usize,
+    /// Was the chain removed, because no nodes remain on it?
+    pub chain_removed: bool,
     /// Has the chain label been updated?
     pub has_chain_label_changed: bool,
     /// The old label of the chain.
+    #[allow(dead_code)]
     pub old_chain_label: Box<str>,
     /// Genesis hash of the chain to be updated.
     pub chain_genesis_hash: BlockHash,
     /// The new label of the chain.
     pub new_chain_label: Box<str>,
+    /// Was the removed node an authority?
+    pub was_authority: bool,
+    /// If the removed node was the chain leader, the node that has taken its place.
+    pub new_chain_leader: Option<(ChainNodeId, BlockNumber)>,
+}
+
+/// A chain that is allowed to connect, and the label that it will be given.
+/// This is parsed from strings of the form `<genesis_hash>=<label>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AllowedChain {
+    pub genesis_hash: BlockHash,
+    pub label: Label,
+}
+
+impl FromStr for AllowedChain {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (genesis_hash, label) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expecting format `<genesis_hash>=<label>`"))?;
+        let label = label.trim();
+        if label.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Label for chain {genesis_hash} cannot be empty"
+            ));
+        }
+        Ok(AllowedChain {
+            genesis_hash: genesis_hash.trim().parse()?,
+            label: label.into(),
+        })
+    }
+}
+
+/// A tag to attach to a chain. This is parsed from strings of the form
+/// `<genesis_hash>=<key>:<value>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainTag {
+    pub genesis_hash: BlockHash,
+    pub key: Box<str>,
+    pub value: Box<str>,
+}
+
+impl FromStr for ChainTag {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (genesis_hash, tag) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expecting format `<genesis_hash>=<key>:<value>`"))?;
+        let (key, value) = tag
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Expecting format `<genesis_hash>=<key>:<value>`"))?;
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Tag key for chain {genesis_hash} cannot be empty"
+            ));
+        }
+        Ok(ChainTag {
+            genesis_hash: genesis_hash.trim().parse()?,
+            key: key.into(),
+            value: value.trim().into(),
+        })
+    }
 }
 
 impl State {
-    pub fn new<T: IntoIterator<Item = String>>(denylist: T, max_third_party_nodes: usize) -> State {
-        State {
+    pub fn new<T, A>(
+        denylist: T,
+        allowlist: A,
+        max_third_party_nodes: usize,
+        max_chains: usize,
+    ) -> State
+    where
+        T: IntoIterator<Item = String>,
+        A: IntoIterator<Item = AllowedChain>,
+    {
+        let mut state = State {
             chains: DenseMap::new(),
             chains_by_genesis_hash: HashMap::new(),
-            denylist: denylist.into_iter().collect(),
+            denylist: HashSet::new(),
+            allowlist: HashMap::new(),
+            chain_tags: HashMap::new(),
+            authority_only_chains: HashSet::new(),
             max_third_party_nodes,
+            max_chains,
+            departed_nodes: HashMap::new(),
+            departures: 0,
+            connected_identities: HashMap::new(),
+            empty_chain_ttl: Duration::ZERO,
+            empty_chains: HashMap::new(),
+            max_block_height_jump: None,
+            stat_history_len: None,
+            block_window: None,
+        };
+        state.set_chain_lists(denylist, allowlist);
+        state
+    }
+
+    /// Replace the chain deny and allow lists. Nodes that connect from now on are checked
+    /// against the new lists; the IDs of any connected nodes that the new lists would not
+    /// have let in are returned, so that they can be removed. Existing chains keep their
+    /// current label until they are next created.
+    pub fn set_chain_lists<T, A>(&mut self, denylist: T, allowlist: A) -> Vec<NodeId>
+    where
+        T: IntoIterator<Item = String>,
+        A: IntoIterator<Item = AllowedChain>,
+    {
+        self.denylist = denylist.into_iter().collect();
+        self.allowlist = allowlist
+            .into_iter()
+            .map(|c| (c.genesis_hash, c.label))
+            .collect();
+
+        let mut disallowed_node_ids = Vec::new();
+        for (chain_id, chain) in self.chains.iter() {
+            let chain_allowed =
+                self.allowlist.is_empty() || self.allowlist.contains_key(&chain.genesis_hash());
+            let node_ids = chain.node_ids().filter(|&id| {
+                chain.get_node(id).is_some_and(|node| {
+                    self.denylist.contains(&*node.details().chain)
+                        || (!chain_allowed && !node.bypassed_allowlist())
+                })
+            });
+            disallowed_node_ids.extend(node_ids.map(|id| NodeId(chain_id, id)));
+        }
+        disallowed_node_ids
+    }
+
+    /// Attach these tags to chains with the given genesis hashes when they're created. If a
+    /// chain is given the same key more than once, the last value wins. Chains that already
+    /// exist keep their current tags until they are next created.
+    pub fn set_chain_tags<T>(&mut self, tags: T)
+    where
+        T: IntoIterator<Item = ChainTag>,
+    {
+        self.chain_tags.clear();
+        for tag in tags {
+            self.chain_tags
+                .entry(tag.genesis_hash)
+                .or_default()
+                .insert(tag.key, tag.value);
+        }
+    }
+
+    /// Only allow authority nodes to connect to the chains with these genesis hashes; other
+    /// nodes on them are turned away. Nodes that are already connected are unaffected.
+    pub fn set_authority_only_chains<T>(&mut self, genesis_hashes: T)
+    where
+        T: IntoIterator<Item = BlockHash>,
+    {
+        self.authority_only_chains = genesis_hashes.into_iter().collect();
+    }
+
+    /// Keep chains around for this long after their last node has gone, in case nodes
+    /// reconnect to them, rather than removing them straight away. Chains that are already
+    /// empty are unaffected until they're next checked via [`State::remove_empty_chains`].
+    pub fn set_empty_chain_ttl(&mut self, ttl: Duration) {
+        self.empty_chain_ttl = ttl;
+    }
+
+    /// Ignore best blocks that nodes report which are more than this many blocks ahead of
+    /// their chain's best block, rather than letting one node drag the whole chain's best
+    /// block ahead. `None` (the default) accepts any plausible height.
+    pub fn set_max_block_height_jump(&mut self, max_jump: Option<u64>) {
+        self.max_block_height_jump = max_jump;
+        for (_, chain) in self.chains.iter_mut() {
+            chain.set_max_block_height_jump(max_jump);
+        }
+    }
+
+    /// Keep at most this many values of each stat series (such as bandwidth or peer counts)
+    /// for nodes that are added from now on. Older values are averaged together to make room
+    /// for new ones. `None` keeps the default number.
+    pub fn set_stat_history_len(&mut self, len: Option<usize>) {
+        self.stat_history_len = len;
+    }
+
+    /// Keep track of at most this many of the most recent block heights on each chain when
+    /// working out how quickly blocks propagate and which finalized block nodes agree on.
+    /// `None` keeps the defaults.
+    pub fn set_block_window(&mut self, window: Option<usize>) {
+        self.block_window = window;
+        for (_, chain) in self.chains.iter_mut() {
+            chain.set_block_window(window);
         }
     }
 
+    /// Remove any chains that have had no nodes for at least the configured TTL as of
+    /// `now`, returning their genesis hashes.
+    pub fn remove_empty_chains(&mut self, now: Timestamp) -> Vec<BlockHash> {
+        let ttl = self.empty_chain_ttl.as_millis() as Timestamp;
+        let expired: Vec<_> = self
+            .empty_chains
+            .iter()
+            .filter(|(_, &empty_since)| now.saturating_sub(empty_since) >= ttl)
+            .map(|(&chain_id, _)| chain_id)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|chain_id| self.remove_chain(chain_id))
+            .collect()
+    }
+
+    /// Remove a chain and clean up indexes to it, returning its genesis hash.
+    fn remove_chain(&mut self, chain_id: ChainId) -> Option<BlockHash> {
+        self.empty_chains.remove(&chain_id);
+        let chain = self.chains.remove(chain_id)?;
+        let genesis_hash = chain.genesis_hash();
+        self.chains_by_genesis_hash.remove(&genesis_hash);
+        Some(genesis_hash)
+    }
+
     pub fn iter_chains(&self) -> impl Iterator<Item = StateChain<'_>> {
         self.chains
             .iter()
@@ -132,28 +403,79 @@ impl State {
             .map(|chain| StateChain { chain })
     }
 
+    /// Find the chain with the label given. Chains are grouped by genesis hash, so more than
+    /// one can have the same label, in which case the one with the most nodes is returned.
+    pub fn get_chain_by_label(&self, label: &str) -> Option<StateChain<'_>> {
+        self.iter_chains()
+            .filter(|chain| chain.label() == label)
+            .max_by_key(|chain| chain.node_count())
+    }
+
+    /// Add a node, which isn't allowed to bypass the allow list.
+    #[cfg(test)]
     pub fn add_node(
         &mut self,
         genesis_hash: BlockHash,
         node_details: NodeDetails,
+    ) -> AddNodeResult<'_> {
+        self.add_node_with_allowlist_bypass(genesis_hash, node_details, false)
+    }
+
+    /// Add a node, which may be allowed to bypass the allow list (for instance because it has
+    /// proven that it's one of our own authority nodes). The deny list takes precedence over
+    /// the allow list, which takes precedence over this; a node on a denied chain is never
+    /// added, and a bypassing node on an allowed chain is added like any other.
+    pub fn add_node_with_allowlist_bypass(
+        &mut self,
+        genesis_hash: BlockHash,
+        node_details: NodeDetails,
+        bypass_allowlist: bool,
     ) -> AddNodeResult<'_> {
         if self.denylist.contains(&*node_details.chain) {
             return AddNodeResult::ChainOnDenyList;
         }
 
+        // If an allow list is in use, the chain must be on it. An allowed chain
+        // is always given the label we've configured for it.
+        let mut bypassed_allowlist = false;
+        let fixed_label = match self.allowlist.get(&genesis_hash) {
+            Some(label) => Some(label.clone()),
+            None if self.allowlist.is_empty() => None,
+            None if bypass_allowlist => {
+                bypassed_allowlist = true;
+                None
+            }
+            None => return AddNodeResult::ChainNotOnAllowList,
+        };
+
+        if !node_details.authority && self.authority_only_chains.contains(&genesis_hash) {
+            return AddNodeResult::NotAnAuthority;
+        }
+
         // Get the chain ID, creating a new empty chain if one doesn't exist.
         // If we create a chain here, we are expecting that it will allow at
         // least this node to be added, because we don't currently try and clean it up
         // if the add fails.
+        let mut chain_added = false;
         let chain_id = match self.chains_by_genesis_hash.get(&genesis_hash) {
             Some(id) => *id,
+            None if self.chains.len() >= self.max_chains => {
+                return AddNodeResult::TooManyChains;
+            }
             None => {
                 let max_nodes = match chain::is_first_party_network(&genesis_hash) {
                     true => usize::MAX,
                     false => self.max_third_party_nodes,
                 };
-                let chain_id = self.chains.add(Chain::new(genesis_hash, max_nodes));
+                let mut chain = Chain::new(genesis_hash, max_nodes, fixed_label);
+                if let Some(tags) = self.chain_tags.get(&genesis_hash) {
+                    chain.set_tags(tags.clone());
+                }
+                chain.set_max_block_height_jump(self.max_block_height_jump);
+                chain.set_block_window(self.block_window);
+                let chain_id = self.chains.add(chain);
                 self.chains_by_genesis_hash.insert(genesis_hash, chain_id);
+                chain_added = true;
                 chain_id
             }
         };
@@ -162,14 +484,56 @@ impl State {
         let chain = self.chains.get_mut(chain_id).expect(
             "should be known to exist after the above (unless chains_by_genesis_hash out of sync)",
         );
+        if chain.is_overquota() {
+            return AddNodeResult::ChainOverQuota;
+        }
 
-        let node = Node::new(node_details);
+        // A node that has reconnected picks up where it left off, and gets its old
+        // ID back if it's free. If another connected node has the same identity, we
+        // can't tell which of them this is, so it's treated as a new node.
+        let identity = (!node_details.network_id.is_empty())
+            .then_some((genesis_hash, node_details.network_id));
+        let departed = identity.and_then(|identity| {
+            if self.connected_identities.contains_key(&identity) {
+                log::warn!(
+                    "Node with network ID {} is already connected to chain {:?}; treating it as a new node",
+                    identity.1,
+                    genesis_hash
+                );
+                return None;
+            }
+            self.departed_nodes.remove(&identity)
+        });
+        let (mut node, preferred_id) = match departed {
+            Some(departed) => (departed.node.reconnected(node_details), Some(departed.id)),
+            None => (
+                Node::with_stat_history_len(node_details, self.stat_history_len),
+                None,
+            ),
+        };
+        node.set_bypassed_allowlist(bypassed_allowlist);
         let old_chain_label = chain.label().into();
 
-        match chain.add_node(node) {
+        match chain.add_node(node, preferred_id) {
             chain::AddNodeResult::Overquota => AddNodeResult::ChainOverQuota,
             chain::AddNodeResult::Added { id, chain_renamed } => {
                 let chain = &*chain;
+                if let Some(identity) = identity {
+                    let name = chain
+                        .get_node(id)
+                        .expect("node added above")
+                        .details()
+                        .name
+                        .clone();
+                    self.connected_identities
+                        .entry(identity)
+                        .or_default()
+                        .entry(name)
+                        .or_default()
+                        .push(id);
+                }
+                // A node has (re)joined the chain, so it's no longer due to be removed:
+                self.empty_chains.remove(&chain_id);
 
                 AddNodeResult::NodeAddedToChain(NodeAddedToChain {
                     id: NodeId(chain_id, id),
@@ -177,48 +541,129 @@ impl State {
                     old_chain_label,
                     new_chain_label: chain.label(),
                     chain_node_count: chain.node_count(),
+                    chain_added,
                     has_chain_label_changed: chain_renamed,
                 })
             }
         }
     }
 
+    /// Find a connected node that a node about to be added is a new connection from. This
+    /// happens when a node reconnects before its old connection has been noticed to be
+    /// gone. Distinct nodes can share a network ID by mistake, so the name has to match
+    /// too before we'll assume that they're the same node.
+    pub fn find_superseded_node(
+        &self,
+        genesis_hash: BlockHash,
+        node_details: &NodeDetails,
+    ) -> Option<NodeId> {
+        if node_details.network_id.is_empty() {
+            return None;
+        }
+        let chain_id = *self.chains_by_genesis_hash.get(&genesis_hash)?;
+        let chain = self.chains.get(chain_id)?;
+        self.connected_identities
+            .get(&(genesis_hash, node_details.network_id))?
+            .get(&node_details.name)?
+            .iter()
+            .copied()
+            .find(|&id| chain.get_node(id).is_some())
+            .map(|id| NodeId(chain_id, id))
+    }
+
     /// Remove a node
     pub fn remove_node(&mut self, NodeId(chain_id, chain_node_id): NodeId) -> Option<RemovedNode> {
         let chain = self.chains.get_mut(chain_id)?;
         let old_chain_label = chain.label().into();
 
         // Actually remove the node
-        let remove_result = chain.remove_node(chain_node_id);
+        let mut remove_result = chain.remove_node(chain_node_id);
 
         // Get updated chain details.
         let new_chain_label: Box<str> = chain.label().into();
         let chain_node_count = chain.node_count();
         let chain_genesis_hash = chain.genesis_hash();
 
-        // Is the chain empty? Remove if so and clean up indexes to it
+        // Is the chain empty? Remove it, or make a note to remove it later if nobody rejoins it:
+        let mut chain_removed = false;
         if chain_node_count == 0 {
-            let genesis_hash = chain.genesis_hash();
-            self.chains_by_genesis_hash.remove(&genesis_hash);
-            self.chains.remove(chain_id);
+            if self.empty_chain_ttl.is_zero() {
+                chain_removed = self.remove_chain(chain_id).is_some();
+            } else {
+                self.empty_chains.entry(chain_id).or_insert_with(time::now);
+            }
+        }
+
+        if let Some(node) = remove_result.node.take() {
+            self.node_departed(chain_genesis_hash, chain_node_id, node);
         }
 
         Some(RemovedNode {
             old_chain_label,
             new_chain_label,
             chain_node_count,
+            chain_removed,
             chain_genesis_hash,
             has_chain_label_changed: remove_result.chain_renamed,
+            was_authority: remove_result.was_authority,
+            new_chain_leader: remove_result.new_leader,
         })
     }
 
+    /// Remember a node that has been removed, in case it reconnects.
+    fn node_departed(&mut self, genesis_hash: BlockHash, id: ChainNodeId, node: Node) {
+        let network_id = node.details().network_id;
+        if network_id.is_empty() {
+            return;
+        }
+        let identity = (genesis_hash, network_id);
+        if let Some(names) = self.connected_identities.get_mut(&identity) {
+            let name = &node.details().name;
+            if let Some(ids) = names.get_mut(name) {
+                ids.retain(|&connected_id| connected_id != id);
+                if ids.is_empty() {
+                    names.remove(name);
+                }
+            }
+            if names.is_empty() {
+                self.connected_identities.remove(&identity);
+            }
+        }
+
+        self.departures += 1;
+        self.departed_nodes.insert(
+            identity,
+            DepartedNode {
+                id,
+                node,
+                departure: self.departures,
+            },
+        );
+
+        // Forget the node that departed longest ago if we're remembering too many:
+        if self.departed_nodes.len() > MAX_DEPARTED_NODES {
+            let oldest = self
+                .departed_nodes
+                .iter()
+                .min_by_key(|(_, departed)| departed.departure)
+                .map(|(&identity, _)| identity);
+            if let Some(oldest) = oldest {
+                self.departed_nodes.remove(&oldest);
+            }
+        }
+    }
+
     /// Attempt to update the best block seen, given a node and block.
+    #[allow(clippy::too_many_arguments)]
     pub fn update_node(
         &mut self,
         NodeId(chain_id, chain_node_id): NodeId,
         payload: Payload,
-        feed: &mut FeedMessageSerializer,
+        sent_at: Option<Timestamp>,
+        feed: &mut ChainFeedSerializer,
         expose_node_details: bool,
+        peer_count_handling: PeerCountHandling,
+        node_update_min_interval: Duration,
     ) {
         let chain = match self.chains.get_mut(chain_id) {
             Some(chain) => chain,
@@ -228,7 +673,55 @@ impl State {
             }
         };
 
-        chain.update_node(chain_node_id, payload, feed, expose_node_details)
+        chain.update_node(
+            chain_node_id,
+            payload,
+            sent_at,
+            feed,
+            expose_node_details,
+            peer_count_handling,
+            node_update_min_interval,
+        )
+    }
+
+    /// Record which shard a node is connected through. Return `false` if the node was not found.
+    pub fn update_node_shard_id(
+        &mut self,
+        NodeId(chain_id, chain_node_id): NodeId,
+        shard_id: Box<str>,
+    ) -> bool {
+        if let Some(chain) = self.chains.get_mut(chain_id) {
+            chain.update_node_shard_id(chain_node_id, shard_id)
+        } else {
+            false
+        }
+    }
+
+    /// Record how skewed a node's clock is. Return `false` if the node was not found, or
+    /// this hasn't changed.
+    pub fn update_node_clock_skew(
+        &mut self,
+        NodeId(chain_id, chain_node_id): NodeId,
+        skew_ms: Option<i64>,
+    ) -> bool {
+        if let Some(chain) = self.chains.get_mut(chain_id) {
+            chain.update_node_clock_skew(chain_node_id, skew_ms)
+        } else {
+            false
+        }
+    }
+
+    /// Find the nodes that we haven't received a message from since the threshold given. This is
+    /// just a scan over the last message timestamps of each node, so it's quick even on large chains.
+    pub fn silent_node_ids(&self, threshold: Timestamp) -> Vec<NodeId> {
+        self.chains
+            .iter()
+            .flat_map(|(chain_id, chain)| {
+                chain
+                    .silent_node_ids(threshold)
+                    .map(move |chain_node_id| NodeId(chain_id, chain_node_id))
+            })
+            .collect()
     }
 
     /// Update the location for a node. Return `false` if the node was not found.
@@ -260,6 +753,9 @@ impl<'a> StateChain<'a> {
     pub fn genesis_hash(&self) -> BlockHash {
         self.chain.genesis_hash()
     }
+    pub fn tags(&self) -> &'a ChainTags {
+        self.chain.tags()
+    }
     pub fn node_count(&self) -> usize {
         self.chain.node_count()
     }
@@ -269,24 +765,45 @@ impl<'a> StateChain<'a> {
     pub fn timestamp(&self) -> Timestamp {
         self.chain.timestamp().unwrap_or(0)
     }
+    pub fn clock_now(&self) -> Timestamp {
+        self.chain.clock_now()
+    }
     pub fn average_block_time(&self) -> Option<u64> {
         self.chain.average_block_time()
     }
     pub fn finalized_block(&self) -> &'a Block {
         self.chain.finalized_block()
     }
+    pub fn get_node(&self, id: ChainNodeId) -> Option<&'a Node> {
+        self.chain.get_node(id)
+    }
     pub fn nodes_slice(&self) -> &[Option<Node>] {
         self.chain.nodes_slice()
     }
     pub fn stats(&self) -> &ChainStats {
         self.chain.stats()
     }
+    pub fn block_propagation_stats(&self) -> Option<&BlockPropagationStats> {
+        self.chain.block_propagation_stats()
+    }
+    pub fn finalized_consensus(&self) -> Option<&FinalizedConsensus> {
+        self.chain.finalized_consensus()
+    }
+    pub fn reorg_count(&self) -> u64 {
+        self.chain.reorg_count()
+    }
+    pub fn authority_set(&self) -> Option<&AuthoritySet> {
+        self.chain.authority_set()
+    }
+    pub fn leader(&self) -> Option<(ChainNodeId, BlockNumber)> {
+        self.chain.leader()
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use common::node_types::NetworkId;
+    use crate::feed_message::FeedFormat;
 
     fn node(name: &str, chain: &str) -> NodeDetails {
         NodeDetails {
@@ -296,8 +813,11 @@ mod test {
             target_arch: Some("x86_64".into()),
             target_os: Some("linux".into()),
             target_env: Some("env".into()),
+            commit: None,
             version: "0.1".into(),
             validator: None,
+            authority: false,
+            role: Default::default(),
             network_id: NetworkId::new(),
             startup_time: None,
             sysinfo: None,
@@ -307,7 +827,7 @@ mod test {
 
     #[test]
     fn adding_a_node_returns_expected_response() {
-        let mut state = State::new(None, 1000);
+        let mut state = State::new(None, None, 1000, usize::MAX);
 
         let chain1_genesis = BlockHash::from_low_u64_be(1);
 
@@ -315,34 +835,40 @@ mod test {
 
         let add_node_result = match add_result {
             AddNodeResult::ChainOnDenyList => panic!("Chain not on deny list"),
+            AddNodeResult::ChainNotOnAllowList => panic!("No allow list in use"),
+            AddNodeResult::NotAnAuthority => panic!("No authority-only chains"),
             AddNodeResult::ChainOverQuota => panic!("Chain not Overquota"),
+            AddNodeResult::TooManyChains => panic!("No limit on chains"),
             AddNodeResult::NodeAddedToChain(details) => details,
         };
 
         assert_eq!(add_node_result.id, NodeId(0.into(), 0.into()));
         assert_eq!(&*add_node_result.old_chain_label, "");
-        assert_eq!(&*add_node_result.new_chain_label, "Chain One");
+        assert_eq!(add_node_result.new_chain_label, "Chain One");
         assert_eq!(add_node_result.chain_node_count, 1);
-        assert_eq!(add_node_result.has_chain_label_changed, true);
+        assert!(add_node_result.has_chain_label_changed);
 
         let add_result = state.add_node(chain1_genesis, node("A", "Chain One"));
 
         let add_node_result = match add_result {
             AddNodeResult::ChainOnDenyList => panic!("Chain not on deny list"),
+            AddNodeResult::ChainNotOnAllowList => panic!("No allow list in use"),
+            AddNodeResult::NotAnAuthority => panic!("No authority-only chains"),
             AddNodeResult::ChainOverQuota => panic!("Chain not Overquota"),
+            AddNodeResult::TooManyChains => panic!("No limit on chains"),
             AddNodeResult::NodeAddedToChain(details) => details,
         };
 
         assert_eq!(add_node_result.id, NodeId(0.into(), 1.into()));
         assert_eq!(&*add_node_result.old_chain_label, "Chain One");
-        assert_eq!(&*add_node_result.new_chain_label, "Chain One");
+        assert_eq!(add_node_result.new_chain_label, "Chain One");
         assert_eq!(add_node_result.chain_node_count, 2);
-        assert_eq!(add_node_result.has_chain_label_changed, false);
+        assert!(!add_node_result.has_chain_label_changed);
     }
 
     #[test]
     fn adding_and_removing_nodes_updates_chain_label_mapping() {
-        let mut state = State::new(None, 1000);
+        let mut state = State::new(None, None, 1000, usize::MAX);
 
         let chain1_genesis = BlockHash::from_low_u64_be(1);
         let node_id0 = state
@@ -402,7 +928,7 @@ mod test {
 
     #[test]
     fn chain_removed_when_last_node_is() {
-        let mut state = State::new(None, 1000);
+        let mut state = State::new(None, None, 1000, usize::MAX);
 
         let chain1_genesis = BlockHash::from_low_u64_be(1);
         let node_id = state
@@ -417,4 +943,768 @@ mod test {
         assert!(state.get_chain_by_genesis_hash(&chain1_genesis).is_none());
         assert_eq!(state.iter_chains().count(), 0);
     }
+
+    #[test]
+    fn empty_chain_kept_until_ttl_passes() {
+        let mut state = State::new(None, None, 1000, usize::MAX);
+        state.set_empty_chain_ttl(Duration::from_secs(30));
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let node_id = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+        let removed = state.remove_node(node_id).unwrap();
+        assert!(!removed.chain_removed);
+        assert_eq!(removed.chain_node_count, 0);
+
+        // The chain hangs around until the TTL has passed:
+        let now = time::now();
+        assert!(state.remove_empty_chains(now).is_empty());
+        assert!(state.get_chain_by_genesis_hash(&chain1_genesis).is_some());
+
+        // A node rejoining the chain in the meantime means it won't be removed:
+        let node_id = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+        assert!(state.remove_empty_chains(now + 60_000).is_empty());
+        assert_eq!(state.iter_chains().count(), 1);
+
+        // Once it's empty again, it's removed (once) when the TTL passes:
+        state.remove_node(node_id).unwrap();
+        let now = time::now();
+        assert!(state.remove_empty_chains(now + 29_000).is_empty());
+        assert_eq!(
+            state.remove_empty_chains(now + 30_000),
+            vec![chain1_genesis]
+        );
+        assert!(state.remove_empty_chains(now + 60_000).is_empty());
+        assert!(state.get_chain_by_genesis_hash(&chain1_genesis).is_none());
+        assert_eq!(state.iter_chains().count(), 0);
+    }
+
+    #[test]
+    fn nodes_on_new_chains_rejected_once_at_max_chains() {
+        let mut state = State::new(None, None, 1000, 2);
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let chain2_genesis = BlockHash::from_low_u64_be(2);
+        let chain3_genesis = BlockHash::from_low_u64_be(3);
+        state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+        let node_id = state
+            .add_node(chain2_genesis, node("B", "Chain Two"))
+            .unwrap_id();
+
+        // No room for a third chain:
+        assert!(matches!(
+            state.add_node(chain3_genesis, node("C", "Chain Three")),
+            AddNodeResult::TooManyChains
+        ));
+        assert!(state.get_chain_by_genesis_hash(&chain3_genesis).is_none());
+
+        // Existing chains still accept nodes:
+        state
+            .add_node(chain1_genesis, node("D", "Chain One"))
+            .unwrap_id();
+
+        // Once a chain goes away, there's room for another:
+        state.remove_node(node_id);
+        state
+            .add_node(chain3_genesis, node("C", "Chain Three"))
+            .unwrap_id();
+    }
+
+    #[test]
+    fn allowlist_drops_other_chains_and_fixes_label() {
+        let allowed_genesis = BlockHash::from_low_u64_be(1);
+        let other_genesis = BlockHash::from_low_u64_be(2);
+        let mut state = State::new(
+            None,
+            vec![AllowedChain {
+                genesis_hash: allowed_genesis,
+                label: "Official Chain".into(),
+            }],
+            1000,
+            usize::MAX,
+        );
+
+        assert!(matches!(
+            state.add_node(other_genesis, node("A", "Chain Two")),
+            AddNodeResult::ChainNotOnAllowList
+        ));
+        assert!(state.get_chain_by_genesis_hash(&other_genesis).is_none());
+
+        let node_id = state
+            .add_node(allowed_genesis, node("B", "Something Else"))
+            .unwrap_id();
+        state
+            .add_node(allowed_genesis, node("C", "Another Thing"))
+            .unwrap_id();
+
+        // Whatever the nodes report, the configured label is used:
+        assert_eq!(
+            state
+                .get_chain_by_node_id(node_id)
+                .expect("Chain should exist")
+                .label(),
+            "Official Chain"
+        );
+    }
+
+    #[test]
+    fn bypassing_nodes_are_let_in_unless_denied() {
+        let allowed_genesis = BlockHash::from_low_u64_be(1);
+        let new_genesis = BlockHash::from_low_u64_be(2);
+        let allowlist = vec![AllowedChain {
+            genesis_hash: allowed_genesis,
+            label: "Official Chain".into(),
+        }];
+        let mut state = State::new(vec!["Bad Chain".to_string()], allowlist.clone(), 1000, 10);
+
+        // Nodes allowed to bypass the allow list can start a new chain, but other nodes
+        // still can't join it:
+        let a = state
+            .add_node_with_allowlist_bypass(new_genesis, node("A", "New Chain"), true)
+            .unwrap_id();
+        assert!(matches!(
+            state.add_node(new_genesis, node("B", "New Chain")),
+            AddNodeResult::ChainNotOnAllowList
+        ));
+
+        // On an allowed chain, bypassing nodes are treated like any other:
+        let c = state
+            .add_node_with_allowlist_bypass(allowed_genesis, node("C", "Whatever"), true)
+            .unwrap_id();
+        assert_eq!(
+            state.get_chain_by_node_id(c).unwrap().label(),
+            "Official Chain"
+        );
+
+        // The deny list still applies:
+        assert!(matches!(
+            state.add_node_with_allowlist_bypass(new_genesis, node("D", "Bad Chain"), true),
+            AddNodeResult::ChainOnDenyList
+        ));
+
+        // Bypassing nodes aren't removed when the allow list is reloaded, but are
+        // when their chain is denied:
+        assert!(state
+            .set_chain_lists(vec!["Bad Chain".to_string()], allowlist.clone())
+            .is_empty());
+        assert_eq!(
+            state.set_chain_lists(vec!["New Chain".to_string()], allowlist),
+            vec![a]
+        );
+    }
+
+    #[test]
+    fn changing_chain_lists_returns_nodes_no_longer_allowed() {
+        let genesis1 = BlockHash::from_low_u64_be(1);
+        let genesis2 = BlockHash::from_low_u64_be(2);
+        let mut state = State::new(None, None, 1000, usize::MAX);
+
+        let a = state.add_node(genesis1, node("A", "Chain One")).unwrap_id();
+        let b = state.add_node(genesis1, node("B", "Bad Chain")).unwrap_id();
+        state.add_node(genesis2, node("C", "Chain Two")).unwrap_id();
+
+        // Only nodes reporting a denied chain name are returned:
+        let removed = state.set_chain_lists(vec!["Bad Chain".to_string()], None);
+        assert_eq!(removed, vec![b]);
+        assert!(matches!(
+            state.add_node(genesis2, node("D", "Bad Chain")),
+            AddNodeResult::ChainOnDenyList
+        ));
+
+        // Every node on a chain missing from a new allowlist is returned:
+        let removed = state.set_chain_lists(
+            None,
+            vec![AllowedChain {
+                genesis_hash: genesis2,
+                label: "Chain Two".into(),
+            }],
+        );
+        assert_eq!(removed, vec![a, b]);
+        state.add_node(genesis2, node("E", "Bad Chain")).unwrap_id();
+    }
+
+    #[test]
+    fn allowed_chain_parses_from_str() {
+        let allowed: AllowedChain =
+            "0x91b171bb158e2d3848fa23a9f1c25182fb8e20313b2c1eb49219da7a70ce90c3=Polkadot"
+                .parse()
+                .unwrap();
+        assert_eq!(&*allowed.label, "Polkadot");
+        assert!(chain::is_first_party_network(&allowed.genesis_hash));
+
+        assert!("0x1234".parse::<AllowedChain>().is_err());
+        assert!(
+            "0x91b171bb158e2d3848fa23a9f1c25182fb8e20313b2c1eb49219da7a70ce90c3="
+                .parse::<AllowedChain>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn authority_only_chains_turn_away_other_nodes() {
+        let authority_only_genesis = BlockHash::from_low_u64_be(1);
+        let other_genesis = BlockHash::from_low_u64_be(2);
+        let mut state = State::new(None, None, 1000, usize::MAX);
+        state.set_authority_only_chains([authority_only_genesis]);
+
+        assert!(matches!(
+            state.add_node(authority_only_genesis, node("A", "Chain One")),
+            AddNodeResult::NotAnAuthority
+        ));
+        assert!(state
+            .get_chain_by_genesis_hash(&authority_only_genesis)
+            .is_none());
+
+        let authority = NodeDetails {
+            authority: true,
+            ..node("B", "Chain One")
+        };
+        let b = state
+            .add_node(authority_only_genesis, authority)
+            .unwrap_id();
+        assert_eq!(state.get_chain_by_node_id(b).unwrap().node_count(), 1);
+
+        // Other chains take any node:
+        state
+            .add_node(other_genesis, node("C", "Chain Two"))
+            .unwrap_id();
+    }
+
+    #[test]
+    fn chain_tag_parses_from_str() {
+        let tag: ChainTag =
+            "0x91b171bb158e2d3848fa23a9f1c25182fb8e20313b2c1eb49219da7a70ce90c3=operator: Parity"
+                .parse()
+                .unwrap();
+        assert!(chain::is_first_party_network(&tag.genesis_hash));
+        assert_eq!((&*tag.key, &*tag.value), ("operator", "Parity"));
+
+        // Values can contain colons, but keys can't be empty:
+        let genesis = format!("{:#x}", BlockHash::from_low_u64_be(1));
+        let tag: ChainTag = format!("{genesis}=url:https://example.com")
+            .parse()
+            .unwrap();
+        assert_eq!((&*tag.key, &*tag.value), ("url", "https://example.com"));
+        assert!(format!("{genesis}=network").parse::<ChainTag>().is_err());
+        assert!(format!("{genesis}=:testnet").parse::<ChainTag>().is_err());
+        assert!("0x1234=network:testnet".parse::<ChainTag>().is_err());
+        assert!("network:testnet".parse::<ChainTag>().is_err());
+    }
+
+    #[test]
+    fn chains_are_tagged_when_created() {
+        let genesis = BlockHash::from_low_u64_be(1);
+        let mut state = State::new(None, None, 1000, usize::MAX);
+        state.set_chain_tags(
+            ["network:testnet", "operator:Alice", "network:mainnet"]
+                .map(|t| format!("{genesis:#x}={t}").parse().unwrap()),
+        );
+
+        let a = state.add_node(genesis, node("A", "Chain One")).unwrap_id();
+        let b = state
+            .add_node(BlockHash::from_low_u64_be(2), node("B", "Chain Two"))
+            .unwrap_id();
+        let tags: Vec<_> = state
+            .get_chain_by_node_id(a)
+            .unwrap()
+            .tags()
+            .iter()
+            .map(|(k, v)| (&**k, &**v))
+            .collect();
+        assert_eq!(tags, [("network", "mainnet"), ("operator", "Alice")]);
+        assert!(state.get_chain_by_node_id(b).unwrap().tags().is_empty());
+    }
+
+    #[test]
+    fn finality_lag_computed_from_best_and_finalized_blocks() {
+        use common::node_message::Finalized;
+
+        let mut state = State::new(None, None, 1000, usize::MAX);
+        let node_id = state
+            .add_node(BlockHash::from_low_u64_be(1), node("A", "Chain One"))
+            .unwrap_id();
+
+        let finality_lag = |state: &State| {
+            let chain = state.get_chain_by_node_id(node_id).unwrap();
+            let node = chain.nodes_slice()[0].as_ref().unwrap();
+            node.stats().finality_lag
+        };
+        let update = |state: &mut State, payload| {
+            let mut feed = ChainFeedSerializer::new([FeedFormat::FULL], []);
+            state.update_node(
+                node_id,
+                payload,
+                None,
+                &mut feed,
+                false,
+                PeerCountHandling::Flag,
+                Duration::ZERO,
+            );
+        };
+        let finalized = |height: u64| {
+            Payload::NotifyFinalized(Finalized {
+                hash: BlockHash::from_low_u64_be(height),
+                height: height.to_string().into(),
+            })
+        };
+
+        // No finalization info yet, so no lag:
+        update(
+            &mut state,
+            Payload::BlockImport(Block {
+                hash: BlockHash::from_low_u64_be(10),
+                height: 10,
+            }),
+        );
+        assert_eq!(finality_lag(&state), None);
+
+        // Lag is the difference between best and finalized heights:
+        update(&mut state, finalized(4));
+        assert_eq!(finality_lag(&state), Some(6));
+
+        // Finalized can transiently be ahead of best; clamp to zero:
+        update(&mut state, finalized(12));
+        assert_eq!(finality_lag(&state), Some(0));
+    }
+
+    #[test]
+    fn implausible_best_blocks_are_ignored() {
+        let mut state = State::new(None, None, 1000, usize::MAX);
+        state.set_max_block_height_jump(Some(1000));
+        let a = state
+            .add_node(BlockHash::from_low_u64_be(1), node("A", "Chain One"))
+            .unwrap_id();
+        let b = state
+            .add_node(BlockHash::from_low_u64_be(1), node("B", "Chain One"))
+            .unwrap_id();
+
+        let import_block = |state: &mut State, node_id: NodeId, block: Block| {
+            let mut feed = ChainFeedSerializer::new([FeedFormat::FULL], []);
+            state.update_node(
+                node_id,
+                Payload::BlockImport(block),
+                None,
+                &mut feed,
+                false,
+                PeerCountHandling::Flag,
+                Duration::ZERO,
+            );
+        };
+        let block = |height: u64| Block {
+            hash: BlockHash::from_low_u64_be(height),
+            height,
+        };
+        let chain_best = |state: &State| state.get_chain_by_node_id(a).unwrap().best_block().height;
+
+        // Heights that can't be displayed are ignored, even on a new chain:
+        import_block(&mut state, b, block(u64::MAX));
+        assert_eq!(chain_best(&state), 0);
+
+        import_block(&mut state, a, block(100));
+        assert_eq!(chain_best(&state), 100);
+
+        // A block with no hash is nonsense:
+        import_block(
+            &mut state,
+            b,
+            Block {
+                hash: BlockHash::zero(),
+                height: 101,
+            },
+        );
+        assert_eq!(chain_best(&state), 100);
+
+        // Jumping too far ahead of the chain is ignored, but getting there gradually isn't:
+        import_block(&mut state, b, block(1101));
+        assert_eq!(chain_best(&state), 100);
+        import_block(&mut state, b, block(1100));
+        assert_eq!(chain_best(&state), 1100);
+        import_block(&mut state, a, block(2100));
+        assert_eq!(chain_best(&state), 2100);
+
+        // Without a limit, any plausible height is accepted:
+        state.set_max_block_height_jump(None);
+        import_block(&mut state, b, block(1_000_000));
+        assert_eq!(chain_best(&state), 1_000_000);
+    }
+
+    #[test]
+    fn chain_leader_follows_the_highest_best_block() {
+        use crate::feed_message::ChainLeader;
+        use crate::feed_message::FeedMessage;
+
+        let mut state = State::new(None, None, 1000, usize::MAX);
+        let a = state
+            .add_node(BlockHash::from_low_u64_be(1), node("A", "Chain One"))
+            .unwrap_id();
+        let b = state
+            .add_node(BlockHash::from_low_u64_be(1), node("B", "Chain One"))
+            .unwrap_id();
+
+        // Import a block, handing back any leader changes that feeds are told about:
+        let import_block = |state: &mut State, node_id: NodeId, height: u64| {
+            let mut feed = ChainFeedSerializer::new([FeedFormat::FULL], []);
+            state.update_node(
+                node_id,
+                Payload::BlockImport(Block {
+                    hash: BlockHash::from_low_u64_be(height),
+                    height,
+                }),
+                None,
+                &mut feed,
+                false,
+                PeerCountHandling::Flag,
+                Duration::ZERO,
+            );
+            let bytes = feed
+                .into_finalized()
+                .0
+                .get(FeedFormat::FULL)
+                .cloned()
+                .unwrap_or_default();
+            let values: Vec<serde_json::Value> = serde_json::from_slice(&bytes).unwrap_or_default();
+            values
+                .chunks(2)
+                .filter(|msg| msg[0] == ChainLeader::ACTION)
+                .map(|msg| serde_json::from_value::<(usize, u64)>(msg[1].clone()).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let id = |node_id: NodeId| usize::from(node_id.get_chain_node_id());
+        let leader = |state: &State| {
+            let (node_id, height) = state.get_chain_by_node_id(a).unwrap().leader()?;
+            Some((usize::from(node_id), height))
+        };
+
+        assert_eq!(import_block(&mut state, a, 10), vec![(id(a), 10)]);
+        // Catching up with the leader isn't enough to take over; A got there first:
+        assert_eq!(import_block(&mut state, b, 10), vec![]);
+        // Getting ahead is:
+        assert_eq!(import_block(&mut state, b, 11), vec![(id(b), 11)]);
+        // The leader moving further ahead doesn't change who's leading:
+        assert_eq!(import_block(&mut state, b, 12), vec![]);
+        assert_eq!(import_block(&mut state, a, 12), vec![]);
+        assert_eq!(leader(&state), Some((id(b), 12)));
+
+        // When the leader goes, the node that's furthest ahead takes over:
+        let removed = state.remove_node(b).unwrap();
+        assert_eq!(
+            removed
+                .new_chain_leader
+                .map(|(nid, height)| (usize::from(nid), height)),
+            Some((id(a), 12))
+        );
+        assert_eq!(leader(&state), Some((id(a), 12)));
+
+        // Removing a node that isn't leading changes nothing:
+        let c = state
+            .add_node(BlockHash::from_low_u64_be(1), node("C", "Chain One"))
+            .unwrap_id();
+        assert_eq!(import_block(&mut state, c, 5), vec![]);
+        assert!(state.remove_node(c).unwrap().new_chain_leader.is_none());
+        assert_eq!(leader(&state), Some((id(a), 12)));
+    }
+
+    #[test]
+    fn implausible_peer_counts_are_clamped_and_flagged() {
+        use common::node_message::SystemInterval;
+
+        let interval = |peers: i64| {
+            Payload::SystemInterval(SystemInterval {
+                peers: Some(peers),
+                txcount: None,
+                bandwidth_upload: None,
+                bandwidth_download: None,
+                finalized_height: None,
+                finalized_hash: None,
+                block: None,
+                used_state_cache_size: None,
+                cpu: None,
+                memory: None,
+                disk_usage: None,
+                disk_read_per_sec: None,
+                disk_write_per_sec: None,
+                is_major_syncing: None,
+                custom_metrics: Default::default(),
+            })
+        };
+        let block = |height: u64| {
+            Payload::BlockImport(Block {
+                hash: BlockHash::from_low_u64_be(height),
+                height,
+            })
+        };
+        let stats = |state: &State, node_id: NodeId| {
+            let chain = state.get_chain_by_node_id(node_id).unwrap();
+            *chain.get_node(node_id.get_chain_node_id()).unwrap().stats()
+        };
+
+        let mut state = State::new(None, None, 1000, usize::MAX);
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let node_a = state
+            .add_node(genesis_hash, node("A", "Chain One"))
+            .unwrap_id();
+        let node_b = state
+            .add_node(genesis_hash, node("B", "Chain One"))
+            .unwrap_id();
+
+        let update = |state: &mut State, node_id, payload, handling| {
+            let mut feed = ChainFeedSerializer::new([FeedFormat::FULL], []);
+            state.update_node(
+                node_id,
+                payload,
+                None,
+                &mut feed,
+                false,
+                handling,
+                Duration::ZERO,
+            );
+        };
+
+        // Node A is still syncing, so having no peers is plausible:
+        update(&mut state, node_b, block(100), PeerCountHandling::Flag);
+        update(&mut state, node_a, block(10), PeerCountHandling::Flag);
+        update(&mut state, node_a, interval(0), PeerCountHandling::Flag);
+        assert_eq!(stats(&state, node_a).peers, 0);
+        assert!(!stats(&state, node_a).implausible_peers);
+
+        // Negative peer counts are clamped and flagged:
+        update(&mut state, node_a, interval(-3), PeerCountHandling::Flag);
+        assert_eq!(stats(&state, node_a).peers, 0);
+        assert!(stats(&state, node_a).implausible_peers);
+
+        // A sensible count clears the flag:
+        update(&mut state, node_a, interval(5), PeerCountHandling::Flag);
+        assert_eq!(stats(&state, node_a).peers, 5);
+        assert!(!stats(&state, node_a).implausible_peers);
+
+        // Once synced, having no peers is flagged:
+        update(&mut state, node_a, block(100), PeerCountHandling::Flag);
+        update(&mut state, node_a, interval(0), PeerCountHandling::Flag);
+        assert!(stats(&state, node_a).implausible_peers);
+
+        // If we're only clamping, negative counts aren't flagged:
+        update(&mut state, node_a, interval(-3), PeerCountHandling::Clamp);
+        assert_eq!(stats(&state, node_a).peers, 0);
+        assert!(!stats(&state, node_a).implausible_peers);
+    }
+
+    #[test]
+    fn nodes_far_behind_the_chain_are_syncing() {
+        use common::node_message::SystemInterval;
+        use common::node_types::NodeSyncState;
+
+        let interval = |is_major_syncing: Option<bool>| {
+            Payload::SystemInterval(SystemInterval {
+                peers: Some(5),
+                txcount: None,
+                bandwidth_upload: None,
+                bandwidth_download: None,
+                finalized_height: None,
+                finalized_hash: None,
+                block: None,
+                used_state_cache_size: None,
+                cpu: None,
+                memory: None,
+                disk_usage: None,
+                disk_read_per_sec: None,
+                disk_write_per_sec: None,
+                is_major_syncing,
+                custom_metrics: Default::default(),
+            })
+        };
+        let block = |height: u64| {
+            Payload::BlockImport(Block {
+                hash: BlockHash::from_low_u64_be(height),
+                height,
+            })
+        };
+        let sync_state = |state: &State, node_id: NodeId| {
+            let chain = state.get_chain_by_node_id(node_id).unwrap();
+            chain
+                .get_node(node_id.get_chain_node_id())
+                .unwrap()
+                .stats()
+                .sync_state
+        };
+        let update = |state: &mut State, node_id, payload| {
+            let mut feed = ChainFeedSerializer::new([FeedFormat::FULL], []);
+            state.update_node(
+                node_id,
+                payload,
+                None,
+                &mut feed,
+                false,
+                PeerCountHandling::Flag,
+                Duration::ZERO,
+            );
+        };
+
+        let mut state = State::new(None, None, 1000, usize::MAX);
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let tip = state
+            .add_node(genesis_hash, node("A", "Chain One"))
+            .unwrap_id();
+        let behind = state
+            .add_node(genesis_hash, node("B", "Chain One"))
+            .unwrap_id();
+
+        // We don't know until a node tells us how it's doing:
+        assert_eq!(sync_state(&state, tip), None);
+
+        update(&mut state, tip, block(1000));
+        update(&mut state, behind, block(10));
+        update(&mut state, tip, interval(None));
+        update(&mut state, behind, interval(None));
+        assert_eq!(sync_state(&state, tip), Some(NodeSyncState::Synced));
+        assert_eq!(sync_state(&state, behind), Some(NodeSyncState::Syncing));
+
+        // Catching up to within a couple of blocks of the tip counts as synced:
+        update(&mut state, behind, block(998));
+        update(&mut state, behind, interval(None));
+        assert_eq!(sync_state(&state, behind), Some(NodeSyncState::Synced));
+
+        // What the node reports wins over what we'd work out ourselves:
+        update(&mut state, tip, interval(Some(true)));
+        assert_eq!(sync_state(&state, tip), Some(NodeSyncState::Syncing));
+    }
+
+    #[test]
+    fn reconnecting_nodes_pick_up_where_they_left_off() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let peer = |name: &str, network_id: &str| NodeDetails {
+            network_id: network_id.parse().unwrap(),
+            ..node(name, "Chain One")
+        };
+        let import_block = |state: &mut State, node_id: NodeId, height: u64| {
+            let mut feed = ChainFeedSerializer::new([FeedFormat::FULL], []);
+            let payload = Payload::BlockImport(Block {
+                hash: BlockHash::from_low_u64_be(height),
+                height,
+            });
+            state.update_node(
+                node_id,
+                payload,
+                None,
+                &mut feed,
+                false,
+                PeerCountHandling::Flag,
+                Duration::ZERO,
+            );
+        };
+        let best_height = |state: &State, node_id: NodeId| {
+            let chain = state.get_chain_by_node_id(node_id).unwrap();
+            chain
+                .get_node(node_id.get_chain_node_id())
+                .unwrap()
+                .best()
+                .height
+        };
+
+        let mut state = State::new(None, None, 1000, usize::MAX);
+        let a = state
+            .add_node(genesis_hash, peer("A", "peer-a"))
+            .unwrap_id();
+        let b = state
+            .add_node(genesis_hash, peer("B", "peer-b"))
+            .unwrap_id();
+        import_block(&mut state, a, 10);
+
+        // A leaves, and another node takes its ID in the meantime:
+        state.remove_node(a);
+        let c = state
+            .add_node(genesis_hash, peer("C", "peer-c"))
+            .unwrap_id();
+        assert_eq!(c, a);
+
+        // When A comes back it keeps what it had, but its old ID is taken:
+        let a2 = state
+            .add_node(genesis_hash, peer("A", "peer-a"))
+            .unwrap_id();
+        assert_ne!(a2, c);
+        assert_eq!(best_height(&state, a2), 10);
+
+        // B leaves and comes back, and gets its old ID back, even after
+        // the chain has gone away in the meantime:
+        state.remove_node(a2);
+        state.remove_node(b);
+        state.remove_node(c);
+        assert_eq!(state.iter_chains().count(), 0);
+        let a3 = state
+            .add_node(genesis_hash, peer("A", "peer-a"))
+            .unwrap_id();
+        let b2 = state
+            .add_node(genesis_hash, peer("B", "peer-b"))
+            .unwrap_id();
+        assert_eq!(b2.get_chain_node_id(), b.get_chain_node_id());
+        assert_eq!(a3.get_chain_node_id(), a2.get_chain_node_id());
+        assert_eq!(best_height(&state, a3), 10);
+
+        // A second node claiming to be A is kept apart from it:
+        let a4 = state
+            .add_node(genesis_hash, peer("A", "peer-a"))
+            .unwrap_id();
+        assert_ne!(a4, a3);
+        assert_eq!(best_height(&state, a4), 0);
+
+        // Nodes that don't tell us their network ID are always new:
+        let d = state
+            .add_node(genesis_hash, node("D", "Chain One"))
+            .unwrap_id();
+        import_block(&mut state, d, 5);
+        state.remove_node(d);
+        let d2 = state
+            .add_node(genesis_hash, node("D", "Chain One"))
+            .unwrap_id();
+        assert_eq!(best_height(&state, d2), 0);
+    }
+
+    #[test]
+    fn new_connections_from_connected_nodes_are_found() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let peer = |name: &str, network_id: &str| NodeDetails {
+            network_id: network_id.parse().unwrap(),
+            ..node(name, "Chain One")
+        };
+
+        let mut state = State::new(None, None, 1000, usize::MAX);
+        assert_eq!(
+            state.find_superseded_node(genesis_hash, &peer("A", "peer-a")),
+            None
+        );
+        let a = state
+            .add_node(genesis_hash, peer("A", "peer-a"))
+            .unwrap_id();
+        state.add_node(genesis_hash, node("B", "Chain One"));
+
+        assert_eq!(
+            state.find_superseded_node(genesis_hash, &peer("A", "peer-a")),
+            Some(a)
+        );
+        // A different node that's been given the same network ID isn't the same node:
+        assert_eq!(
+            state.find_superseded_node(genesis_hash, &peer("Z", "peer-a")),
+            None
+        );
+        // Nor is a node on another chain, or one that doesn't tell us its network ID:
+        assert_eq!(
+            state.find_superseded_node(BlockHash::from_low_u64_be(2), &peer("A", "peer-a")),
+            None
+        );
+        assert_eq!(
+            state.find_superseded_node(genesis_hash, &node("B", "Chain One")),
+            None
+        );
+
+        // Once the old connection is gone, there's nothing to supersede:
+        state.remove_node(a);
+        assert_eq!(
+            state.find_superseded_node(genesis_hash, &peer("A", "peer-a")),
+            None
+        );
+    }
 }
@@ -36,13 +36,17 @@ cargo test e2e -- --ignored
 ```
 */
 
+use bincode::Options;
+use common::internal_messages;
 use common::node_types::BlockHash;
-use common::ws_client::SentMessage;
+use common::ws_client::{self, SentMessage};
 use serde_json::json;
 use std::{str::FromStr, time::Duration};
+use telemetry_core::CoreBuilder;
 use test_utils::{
     assert_contains_matches,
     feed_message_de::{FeedMessage, NodeDetails},
+    server::channels::{FeedReceiver, FeedSender},
     workspace::{start_server, start_server_debug, CoreOpts, ServerOpts, ShardOpts},
 };
 
@@ -56,6 +60,22 @@ fn ghash(id: u64) -> BlockHash {
     BlockHash::from_low_u64_be(id)
 }
 
+/// Feeds are sent the feed protocol version on connecting, followed by details of the build.
+/// The build time depends on when the binary being tested was built, so isn't checked exactly.
+fn assert_sent_version(feed_messages: &[FeedMessage]) {
+    let info = telemetry_core::BuildInfo::get();
+    assert!(
+        matches!(
+            feed_messages,
+            [
+                FeedMessage::Version(32),
+                FeedMessage::BuildInfo { version, commit, built_at },
+            ] if version == info.version && commit.as_deref() == info.commit && *built_at > 0
+        ),
+        "expecting version, got {feed_messages:?}"
+    );
+}
+
 /// The simplest test we can run; the main benefit of this test (since we check similar)
 /// below) is just to give a feel for _how_ we can test basic feed related things.
 #[tokio::test]
@@ -65,14 +85,52 @@ async fn e2e_feed_sent_version_on_connect() {
     // Connect a feed:
     let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
 
-    // Expect a version response of 31:
+    // Expect a version response of 32, followed by details of the build:
     let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_sent_version(&feed_messages);
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// A shard speaking a different internal protocol version is told which version
+/// the core speaks, and is then disconnected.
+#[tokio::test]
+async fn e2e_shard_with_incompatible_protocol_version_is_rejected() {
+    let server = start_server_debug().await;
+
+    // Connect to the core as if we were a shard speaking some future protocol version:
+    let (mut shard_tx, mut shard_rx) = server.get_core().connect_shard_raw().await.unwrap();
+    let shard_protocol_version = internal_messages::PROTOCOL_VERSION + 1;
+    let handshake = internal_messages::Handshake {
+        protocol_version: shard_protocol_version,
+    };
+    shard_tx
+        .send_binary(bincode::options().serialize(&handshake).unwrap())
+        .await
+        .unwrap();
+    shard_tx.flush().await.unwrap();
+
+    // The core should reject our version, telling us which version it speaks:
+    let mut bytes = Vec::new();
+    shard_rx.receive_data(&mut bytes).await.unwrap();
+    let response: internal_messages::HandshakeResponse =
+        bincode::options().deserialize(&bytes).unwrap();
     assert_eq!(
-        feed_messages,
-        vec![FeedMessage::Version(32)],
-        "expecting version"
+        response.into_result(shard_protocol_version),
+        Err(internal_messages::ProtocolVersionMismatch {
+            shard_protocol_version,
+            core_protocol_version: internal_messages::PROTOCOL_VERSION,
+        })
     );
 
+    // ..and then close the connection:
+    let mut bytes = Vec::new();
+    let res = tokio::time::timeout(Duration::from_secs(5), shard_rx.receive_data(&mut bytes))
+        .await
+        .expect("connection should be closed promptly");
+    assert!(res.is_err(), "connection should be closed");
+
     // Tidy up:
     server.shutdown().await;
 }
@@ -102,6 +160,179 @@ async fn e2e_feed_ping_responded_to_with_pong() {
     server.shutdown().await;
 }
 
+/// The core can be run in-process via `CoreBuilder`, which lets us ask it how many
+/// feeds and nodes are connected, and shut it down.
+#[tokio::test]
+async fn e2e_core_can_be_embedded_with_builder() {
+    let core = CoreBuilder::new()
+        .listen("127.0.0.1:0".parse().unwrap())
+        .worker_threads(2)
+        .denylist(["Denied Chain"])
+        .spawn()
+        .await
+        .unwrap();
+
+    // Connect a couple of feeds, which should be sent the version like normal:
+    let uri: http::Uri = format!("http://{}/feed", core.local_addr())
+        .parse()
+        .unwrap();
+    let mut feeds = Vec::new();
+    for _ in 0..2 {
+        let (feed_tx, feed_rx) = ws_client::connect(&uri).await.unwrap().into_channels();
+        let (feed_tx, mut feed_rx) = (FeedSender::from(feed_tx), FeedReceiver::from(feed_rx));
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        assert_sent_version(&feed_messages);
+        feeds.push((feed_tx, feed_rx));
+    }
+
+    assert_eq!(core.num_connected_feeds().await.unwrap(), 2);
+    assert_eq!(core.num_connected_nodes().await.unwrap(), 0);
+
+    // Once shut down, nothing can connect:
+    core.shutdown().await.unwrap();
+    assert!(ws_client::connect(&uri).await.is_err());
+}
+
+/// Feeds are told about commands that we can't make sense of, and are disconnected
+/// if they send a command that's larger than we allow.
+#[tokio::test]
+async fn e2e_feeds_sending_bad_commands_are_told_or_disconnected() {
+    let core = CoreBuilder::new()
+        .listen("127.0.0.1:0".parse().unwrap())
+        .max_feed_command_bytes(1000)
+        .spawn()
+        .await
+        .unwrap();
+
+    let uri: http::Uri = format!("http://{}/feed", core.local_addr())
+        .parse()
+        .unwrap();
+    let (feed_tx, feed_rx) = ws_client::connect(&uri).await.unwrap().into_channels();
+    let (feed_tx, mut feed_rx) = (FeedSender::from(feed_tx), FeedReceiver::from(feed_rx));
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    // Malformed commands are answered with an error, and the feed stays connected:
+    for cmd in ["wibble", "subscribe", "node-shard:abc"] {
+        feed_tx
+            .unbounded_send(SentMessage::Text(cmd.to_owned()))
+            .unwrap();
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        assert!(
+            matches!(&*feed_messages, [FeedMessage::CommandError { .. }]),
+            "expected an error for '{cmd}', got {feed_messages:?}"
+        );
+    }
+    feed_tx.send_command("ping", "hello").unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(feed_messages, FeedMessage::Pong { msg } if msg == "hello");
+
+    // Commands that are too large aren't answered; the feed is disconnected instead:
+    feed_tx.send_command("ping", &"a".repeat(2000)).unwrap();
+    assert!(feed_rx.recv_feed_messages().await.is_err());
+
+    core.shutdown().await.unwrap();
+}
+
+/// Embedders can have the core's metrics registered with their own prometheus registry,
+/// in which case we don't serve them ourselves.
+#[tokio::test]
+async fn e2e_metrics_can_be_registered_with_a_shared_registry() {
+    let registry = prometheus::Registry::new();
+    let core = CoreBuilder::new()
+        .listen("127.0.0.1:0".parse().unwrap())
+        .num_aggregators(2)
+        .prometheus_registry(registry.clone())
+        .spawn()
+        .await
+        .unwrap();
+
+    let connected_nodes = registry
+        .gather()
+        .into_iter()
+        .find(|family| family.get_name() == "telemetry_core_connected_nodes")
+        .expect("metrics should be registered");
+    let aggregators: Vec<_> = connected_nodes
+        .get_metric()
+        .iter()
+        .map(|m| m.get_label()[0].get_value().to_owned())
+        .collect();
+    assert_eq!(aggregators, vec!["0", "1"]);
+
+    let res = reqwest::get(format!("http://{}/metrics", core.local_addr()))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 404);
+
+    // Once the core is gone, so are its metrics:
+    core.shutdown().await.unwrap();
+    assert!(registry.gather().is_empty());
+}
+
+/// The version of the core, and the commit it was built from, can be fetched over HTTP.
+#[tokio::test]
+async fn e2e_version_endpoint_reports_build_info() {
+    let core = CoreBuilder::new()
+        .listen("127.0.0.1:0".parse().unwrap())
+        .spawn()
+        .await
+        .unwrap();
+
+    let res = reqwest::get(format!("http://{}/version", core.local_addr()))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    let json: serde_json::Value = res.json().await.unwrap();
+    let info = telemetry_core::BuildInfo::get();
+    assert_eq!(
+        json,
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "commit": info.commit,
+            "built_at": info.built_at,
+        })
+    );
+    assert!(info.built_at > 0);
+
+    core.shutdown().await.unwrap();
+}
+
+/// The admin HTTP endpoints are only served to requests bearing the admin token.
+#[tokio::test]
+async fn e2e_admin_endpoints_require_token() {
+    let core = CoreBuilder::new()
+        .listen("127.0.0.1:0".parse().unwrap())
+        .admin_token("s3cret")
+        .spawn()
+        .await
+        .unwrap();
+    let client = reqwest::Client::new();
+    let url = |path: &str| format!("http://{}{path}", core.local_addr());
+
+    let res = client.get(url("/admin/chains")).send().await.unwrap();
+    assert_eq!(res.status(), 401);
+    let res = client
+        .get(url("/admin/chains"))
+        .bearer_auth("wrong")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 401);
+
+    for path in ["/admin/chains", "/admin/top_chains?n=5", "/admin/shards"] {
+        let res = client
+            .get(url(path))
+            .bearer_auth("s3cret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200, "{path}");
+        let json: serde_json::Value = res.json().await.unwrap();
+        assert_eq!(json, json!([]), "{path}");
+    }
+
+    core.shutdown().await.unwrap();
+}
+
 /// As a prelude to `lots_of_mute_messages_dont_cause_a_deadlock`, we can check that
 /// a lot of nodes can simultaneously subscribe and are all sent the expected response.
 #[tokio::test]
@@ -123,13 +354,9 @@ async fn e2e_multiple_feeds_sent_version_on_connect() {
         .await
         .expect("we shouldn't hit a timeout waiting for responses");
 
-    // Expect a version response of 31 to all of them:
+    // Expect a version response of 32 to all of them:
     for feed_messages in responses {
-        assert_eq!(
-            feed_messages.expect("should have messages"),
-            vec![FeedMessage::Version(32)],
-            "expecting version"
-        );
+        assert_sent_version(&feed_messages.expect("should have messages"));
     }
 
     // Tidy up:
@@ -144,7 +371,16 @@ async fn e2e_multiple_feeds_sent_version_on_connect() {
 /// resulting in a deadlock. This test gives confidence that we don't run into such a deadlock.
 #[tokio::test]
 async fn e2e_lots_of_mute_messages_dont_cause_a_deadlock() {
-    let mut server = start_server_debug().await;
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts::default(),
+        // All of our nodes connect from the same address:
+        ShardOpts {
+            max_conns_per_ip: Some(usize::MAX),
+            ..Default::default()
+        },
+    )
+    .await;
     let shard_id = server.add_shard().await.unwrap();
 
     // Connect 1000 nodes to the shard:
@@ -246,6 +482,7 @@ async fn e2e_feed_add_and_remove_node() {
         name: "Local Testnet".to_owned(),
         genesis_hash: ghash(1),
         node_count: 1,
+        tags: Default::default(),
     }));
 
     // Disconnect the node:
@@ -260,6 +497,153 @@ async fn e2e_feed_add_and_remove_node() {
     server.shutdown().await;
 }
 
+/// When a node disconnects, feeds subscribed to its chain are told that it was removed
+/// because it disconnected.
+#[tokio::test]
+async fn e2e_feeds_are_told_why_nodes_were_removed() {
+    use FeedMessage::*;
+
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    // Connect two nodes on the same chain, each on its own connection:
+    let mut node_txs = vec![];
+    for name in ["Alice", "Bob"] {
+        let (mut node_tx, node_rx) = server
+            .get_shard(shard_id)
+            .unwrap()
+            .connect_node()
+            .await
+            .expect("can connect to shard");
+        node_tx
+            .send_json_text(json!(
+                {
+                    "id":1,
+                    "ts":"2021-07-12T10:37:47.714666+01:00",
+                    "payload": {
+                        "authority":true,
+                        "chain":"Local Testnet",
+                        "config":"",
+                        "genesis_hash": ghash(1),
+                        "implementation":"Substrate Node",
+                        "msg":"system.connected",
+                        "name":name,
+                        "network_id":format!("12D3KooW{name}"),
+                        "startup_time":"1625565542717",
+                        "version":"2.0.0-07a1af348-aarch64-macos"
+                    },
+                }
+            ))
+            .unwrap();
+        node_txs.push((node_tx, node_rx));
+    }
+
+    // Subscribe a feed once it knows about both nodes:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    loop {
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        if feed_messages
+            .iter()
+            .any(|m| matches!(m, AddedChain { node_count: 2, .. }))
+        {
+            break;
+        }
+    }
+    feed_tx
+        .send_command("subscribe", &format!("{:#x}", ghash(1)))
+        .unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    // Disconnect the first node:
+    node_txs[0].0.close().await.unwrap();
+
+    let mut removals = vec![];
+    while !removals.contains(&RemovedNode { node_id: 0 }) {
+        for m in feed_rx.recv_feed_messages().await.unwrap() {
+            if matches!(m, RemovedNode { .. } | RemovedNodeReason { .. }) {
+                removals.push(m);
+            }
+        }
+    }
+    assert_eq!(
+        removals,
+        [
+            RemovedNodeReason {
+                node_id: 0,
+                reason: "disconnected".to_owned()
+            },
+            RemovedNode { node_id: 0 }
+        ]
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Nodes can ask to send compressed messages when they connect, and these should be
+/// decompressed and handled just like uncompressed ones.
+#[tokio::test]
+async fn e2e_node_can_send_compressed_messages() {
+    use std::io::Write;
+
+    // Connect server and add shard
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    // Connect a node to the shard, asking to send gzip compressed messages:
+    let (node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node_with_protocol("telemetry-gzip")
+        .await
+        .expect("can connect to shard");
+
+    // Send a compressed "system connected" message:
+    let msg = json!(
+        {
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }
+    );
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder
+        .write_all(&serde_json::to_vec(&msg).unwrap())
+        .unwrap();
+    node_tx
+        .unbounded_send(SentMessage::Binary(encoder.finish().unwrap()))
+        .unwrap();
+
+    // Wait a little for this message to propagate to the core
+    // (so that our feed connects after the core knows and not before).
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // Connect a feed; the node should have been registered.
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&FeedMessage::AddedChain {
+        name: "Local Testnet".to_owned(),
+        genesis_hash: ghash(1),
+        node_count: 1,
+        tags: Default::default(),
+    }));
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
 /// If a node is added, a connecting feed should be told about the new chain.
 /// However, sending a duplicate "system.connected" message from the same node
 /// should not count as a new node but rather the second message should be ignored.
@@ -310,6 +694,7 @@ async fn e2e_feed_ignore_duplicate_nodes() {
         name: "Local Testnet".to_owned(),
         genesis_hash: ghash(1),
         node_count: 1,
+        tags: Default::default(),
     }));
 
     // Disconnect the node:
@@ -367,20 +752,17 @@ async fn e2e_feeds_told_about_chain_rename_and_stay_subscribed() {
     // it knows what we're on about when we subscribe, below.
     tokio::time::sleep(Duration::from_millis(500)).await;
 
-    // Connect a feed and subscribe to the above chain:
+    // Connect a feed and subscribe to the above chain by its name:
     let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
     feed_tx
-        .send_command(
-            "subscribe",
-            "0x0000000000000000000000000000000000000000000000000000000000000001",
-        )
+        .send_command("subscribe", "label:Initial chain name")
         .unwrap();
 
     // Feed is told about the chain, and the node on this chain:
     let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
     assert_contains_matches!(
         feed_messages,
-        FeedMessage::AddedChain { name, genesis_hash, node_count: 1 } if name == "Initial chain name" && genesis_hash == ghash(1),
+        FeedMessage::AddedChain { name, genesis_hash, node_count: 1, .. } if name == "Initial chain name" && genesis_hash == ghash(1),
         FeedMessage::SubscribedTo { genesis_hash } if genesis_hash == ghash(1),
         FeedMessage::AddedNode { node: NodeDetails { name: node_name, .. }, ..} if node_name == "Node 1",
     );
@@ -394,20 +776,23 @@ async fn e2e_feeds_told_about_chain_rename_and_stay_subscribed() {
     assert_contains_matches!(
         feed_messages,
         FeedMessage::AddedNode { node: NodeDetails { name: node_name, .. }, ..} if node_name == "Node 2",
-        FeedMessage::AddedChain { name, genesis_hash, node_count: 2 } if name == "Initial chain name" && genesis_hash == ghash(1),
+        FeedMessage::AddedChain { name, genesis_hash, node_count: 2, .. } if name == "Initial chain name" && genesis_hash == ghash(1),
     );
 
     // Subscribe a third node. The chain renames, so we're told about the new node but also
-    // about the chain rename.
+    // about the chain rename. The chain isn't removed, since it's the same chain as before.
     node_tx
         .send_json_text(node_init_msg(3, "New chain name", "Node 3"))
         .unwrap();
     let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(!feed_messages
+        .iter()
+        .any(|m| matches!(m, FeedMessage::RemovedChain { .. })));
     assert_contains_matches!(
         feed_messages,
         FeedMessage::AddedNode { node: NodeDetails { name: node_name, .. }, ..} if node_name == "Node 3",
-        FeedMessage::RemovedChain { genesis_hash } if genesis_hash == ghash(1),
-        FeedMessage::AddedChain { name, genesis_hash, node_count: 3 } if name == "New chain name" && genesis_hash == ghash(1),
+        FeedMessage::ChainLabelChanged { name, genesis_hash } if name == "New chain name" && genesis_hash == ghash(1),
+        FeedMessage::AddedChain { name, genesis_hash, node_count: 3, .. } if name == "New chain name" && genesis_hash == ghash(1),
     );
 
     // Just to be sure, subscribing a fourth node on this chain will still lead to updates
@@ -419,7 +804,20 @@ async fn e2e_feeds_told_about_chain_rename_and_stay_subscribed() {
     assert_contains_matches!(
         feed_messages,
         FeedMessage::AddedNode { node: NodeDetails { name: node_name, .. }, ..} if node_name == "Node 4",
-        FeedMessage::AddedChain { name, genesis_hash, node_count: 4 } if name == "New chain name" && genesis_hash == ghash(1),
+        FeedMessage::AddedChain { name, genesis_hash, node_count: 4, .. } if name == "New chain name" && genesis_hash == ghash(1),
+    );
+
+    // No chain has the old label any more, so subscribing by it fails:
+    feed_tx
+        .send_command("subscribe", "label:Initial chain name")
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(
+        matches!(
+            &*feed_messages,
+            [FeedMessage::SubscribeError { code, genesis_hash: None }] if code == "unknown-label"
+        ),
+        "expected a subscribe error, got {feed_messages:?}"
     );
 }
 
@@ -475,12 +873,14 @@ async fn e2e_feed_add_and_remove_shard() {
     assert!(feed_messages.contains(&FeedMessage::AddedChain {
         name: "Local Testnet 1".to_owned(),
         genesis_hash: ghash(1),
-        node_count: 1
+        node_count: 1,
+        tags: Default::default(),
     }));
     assert!(feed_messages.contains(&FeedMessage::AddedChain {
         name: "Local Testnet 2".to_owned(),
         genesis_hash: ghash(2),
-        node_count: 1
+        node_count: 1,
+        tags: Default::default(),
     }));
 
     // Disconnect the first shard:
@@ -546,9 +946,16 @@ async fn e2e_feed_can_subscribe_and_unsubscribe_from_chain() {
     let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
 
     let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
-    assert_contains_matches!(feed_messages, AddedChain { name, genesis_hash, node_count: 1 } if name == "Local Testnet 1" && genesis_hash == ghash(1));
+    assert_contains_matches!(feed_messages, AddedChain { name, genesis_hash, node_count: 1, .. } if name == "Local Testnet 1" && genesis_hash == ghash(1));
 
-    // Subscribe it to a chain
+    // Subscribe it to a chain, asking for finality messages about it too, which are only
+    // sent to feeds that ask for them:
+    feed_tx
+        .send_command(
+            "send-finality",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
     feed_tx
         .send_command(
             "subscribe",
@@ -585,6 +992,12 @@ async fn e2e_feed_can_subscribe_and_unsubscribe_from_chain() {
         .expect_err("Timeout should elapse since no messages sent");
 
     // We can change our subscription:
+    feed_tx
+        .send_command(
+            "send-finality",
+            "0x0000000000000000000000000000000000000000000000000000000000000002",
+        )
+        .unwrap();
     feed_tx
         .send_command(
             "subscribe",
@@ -617,78 +1030,121 @@ async fn e2e_feed_can_subscribe_and_unsubscribe_from_chain() {
     server.shutdown().await;
 }
 
-/// If a node sends more than some rolling average amount of data, it'll be booted.
+/// Feeds can ask for a list of the chains without subscribing to any of them.
 #[tokio::test]
-async fn e2e_node_banned_if_it_sends_too_much_data() {
-    async fn try_send_data(max_bytes: usize, send_msgs: usize, bytes_per_msg: usize) -> bool {
-        let mut server = start_server(
-            ServerOpts::default(),
-            CoreOpts::default(),
-            ShardOpts {
-                // Remember, this is (currently) averaged over the last 10 seconds,
-                // so we need to send 10x this amount of data for an imemdiate ban:
-                max_node_data_per_second: Some(max_bytes),
-                ..Default::default()
-            },
-        )
-        .await;
-
-        // Give us a shard to talk to:
-        let shard_id = server.add_shard().await.unwrap();
-        let (node_tx, _node_rx) = server
-            .get_shard(shard_id)
-            .unwrap()
-            .connect_node()
-            .await
-            .unwrap();
-
-        // Send the data requested to the shard:
-        for _ in 0..send_msgs {
-            node_tx
-                .unbounded_send(SentMessage::Binary(vec![1; bytes_per_msg]))
-                .unwrap();
-        }
-
-        // Wait a little for the shard to react and cut off the connection (or not):
-        tokio::time::sleep(Duration::from_millis(250)).await;
+async fn e2e_feed_can_list_chains_without_subscribing() {
+    use FeedMessage::*;
 
-        // Has the connection been closed?
-        node_tx.is_closed()
-    }
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
 
+    // Connect two nodes to chain 1 and one node to chain 2:
+    for (id, chain) in [(1, 1), (2, 1), (3, 2)] {
+        node_tx
+            .send_json_text(json!(
+                {
+                    "id":id,
+                    "ts":"2021-07-12T10:37:47.714666+01:00",
+                    "payload": {
+                        "authority":true,
+                        "chain":format!("Local Testnet {}", chain),
+                        "config":"",
+                        "genesis_hash": ghash(chain),
+                        "implementation":"Substrate Node",
+                        "msg":"system.connected",
+                        "name":format!("Alice {}", id),
+                        "network_id":format!("12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp{}", id),
+                        "startup_time":"1625565542717",
+                        "version":"2.0.0-07a1af348-aarch64-macos"
+                    },
+                }
+            ))
+            .unwrap();
+    }
+
+    // Wait until the feed knows about all of the nodes:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let mut node_counts = std::collections::HashMap::new();
+    while node_counts.values().sum::<usize>() < 3 {
+        for m in feed_rx.recv_feed_messages().await.unwrap() {
+            if let AddedChain {
+                genesis_hash,
+                node_count,
+                ..
+            } = m
+            {
+                node_counts.insert(genesis_hash, node_count);
+            }
+        }
+    }
+
+    feed_tx.send_command("list-chains", "").unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    let mut chains = match <[FeedMessage; 1]>::try_from(feed_messages) {
+        Ok([ChainsList { chains }]) => chains,
+        msgs => panic!("expected a list of chains, got {msgs:?}"),
+    };
+    chains.sort_by_key(|(_, genesis_hash, _, _)| *genesis_hash);
     assert_eq!(
-        try_send_data(1000, 10, 1000).await,
-        false,
-        "shouldn't be closed; we didn't exceed 10x threshold"
-    );
-    assert_eq!(
-        try_send_data(999, 10, 1000).await,
-        true,
-        "should be closed; we sent just over 10x the block threshold"
+        chains,
+        vec![
+            (
+                "Local Testnet 1".to_owned(),
+                ghash(1),
+                2,
+                Default::default()
+            ),
+            (
+                "Local Testnet 2".to_owned(),
+                ghash(2),
+                1,
+                Default::default()
+            ),
+        ]
     );
+
+    // The feed hasn't been subscribed to anything, so hears nothing more about the nodes:
+    node_tx
+        .send_json_text(json!(
+            {"id":1, "payload":{ "best":BlockHash::from_low_u64_be(1),"height":1,"msg":"block.import" },"ts":"2021-07-12T10:37:48.330433+01:00" }
+        ))
+        .unwrap();
+    let feed_messages = feed_rx
+        .recv_feed_messages_timeout(Duration::from_secs(2))
+        .await
+        .unwrap();
+    assert!(!feed_messages
+        .iter()
+        .any(|m| matches!(m, ImportedBlock { .. } | SubscribedTo { .. })));
+
+    // Tidy up:
+    server.shutdown().await;
 }
 
-/// Feeds will be disconnected if they can't receive messages quickly enough.
+/// Chains can be tagged with metadata, which feeds are told about alongside each chain.
 #[tokio::test]
-async fn e2e_slow_feeds_are_disconnected() {
+async fn e2e_tagged_chains_carry_their_tags_in_the_feed() {
+    use std::collections::{BTreeMap, HashMap};
+    use FeedMessage::*;
+
     let mut server = start_server(
         ServerOpts::default(),
-        // Timeout faster so the test can be quicker:
         CoreOpts {
-            feed_timeout: Some(1),
-            ..Default::default()
-        },
-        // Allow us to send more messages in more easily:
-        ShardOpts {
-            max_nodes_per_connection: Some(100_000),
-            // Prevent the shard being being banned when it sends a load of data at once:
-            max_node_data_per_second: Some(100_000_000),
+            chain_tag: vec![
+                format!("{:#x}=network:testnet", ghash(1)),
+                format!("{:#x}=operator:Parity", ghash(1)),
+            ],
             ..Default::default()
         },
+        ShardOpts::default(),
     )
     .await;
-
-    // Give us a shard to talk to:
     let shard_id = server.add_shard().await.unwrap();
     let (mut node_tx, _node_rx) = server
         .get_shard(shard_id)
@@ -696,93 +1152,77 @@ async fn e2e_slow_feeds_are_disconnected() {
         .connect_node()
         .await
         .unwrap();
-
-    // Add a load of nodes from this shard so there's plenty of data to give to a feed.
-    // We want to exhaust any buffers between core and feed (eg BufWriters). If the number
-    // is too low, data will happily be sent into a buffer and the connection won't need to
-    // be closed.
-    for n in 1..100_000 {
+    for chain in [1, 2] {
         node_tx
             .send_json_text(json!({
-                "id":n,
+                "id":chain,
                 "ts":"2021-07-12T10:37:47.714666+01:00",
                 "payload": {
                     "authority":true,
-                    "chain":"Polkadot",
+                    "chain":format!("Local Testnet {}", chain),
                     "config":"",
-                    "genesis_hash": polkadot_genesis_hash(), // First party node connections aren't limited.
+                    "genesis_hash": ghash(chain),
                     "implementation":"Substrate Node",
                     "msg":"system.connected",
-                    "name": format!("Alice {}", n),
-                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "name":format!("Alice {}", chain),
+                    "network_id":format!("12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp{}", chain),
                     "startup_time":"1625565542717",
                     "version":"2.0.0-07a1af348-aarch64-macos"
-                }
+                },
             }))
             .unwrap();
     }
 
-    // Connect a raw feed so that we can control how fast we consume data from the websocket
-    let (mut raw_feed_tx, mut raw_feed_rx) = server.get_core().connect_feed_raw().await.unwrap();
-
-    // Subscribe the feed:
-    raw_feed_tx
-        .send_text("subscribe:0x0000000000000000000000000000000000000000000000000000000000000001")
-        .await
-        .unwrap();
-
-    // Wait a little.. the feed hasn't been receiving messages so it should
-    // be booted after ~a second.
-    tokio::time::sleep(Duration::from_secs(5)).await;
-
-    // Drain anything out and expect to hit a "closed" error, rather than get stuck
-    // waiting to receive more data (or see some other error).
-    loop {
-        let mut v = Vec::new();
-        let data =
-            tokio::time::timeout(Duration::from_secs(2), raw_feed_rx.receive_data(&mut v)).await;
-
-        match data {
-            Ok(Ok(_)) => {
-                continue; // Drain data
-            }
-            Ok(Err(soketto::connection::Error::Closed)) => {
-                break; // End loop; success!
-            }
-            Ok(Err(_e)) => {
-                // Occasionally we might hit an error here before the channel is marked as closed. The error probably
-                // means that the socket has been killed, but we haven't managed to set the state to closed in time
-                // and so we still hit this. We may be able to tighten this up and avoid this permanently, at which point
-                // this can become a test failure.
-                break;
-            }
-            Err(_) => {
-                panic!("recv should be closed but seems to be happy waiting for more data");
+    // Feeds are told about the tags of each chain as it's added:
+    let expected_tags: BTreeMap<String, String> = [
+        ("network".to_owned(), "testnet".to_owned()),
+        ("operator".to_owned(), "Parity".to_owned()),
+    ]
+    .into();
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let mut chain_tags = HashMap::new();
+    while chain_tags.len() < 2 {
+        for m in feed_rx.recv_feed_messages().await.unwrap() {
+            if let AddedChain {
+                genesis_hash, tags, ..
+            } = m
+            {
+                chain_tags.insert(genesis_hash, tags);
             }
         }
     }
+    assert_eq!(chain_tags[&ghash(1)], expected_tags);
+    assert!(chain_tags[&ghash(2)].is_empty());
+
+    // And when they ask for a list of the chains:
+    feed_tx.send_command("list-chains", "").unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    let mut chains = match <[FeedMessage; 1]>::try_from(feed_messages) {
+        Ok([ChainsList { chains }]) => chains,
+        msgs => panic!("expected a list of chains, got {msgs:?}"),
+    };
+    chains.sort_by_key(|(_, genesis_hash, _, _)| *genesis_hash);
+    let tags: Vec<_> = chains.into_iter().map(|(_, _, _, tags)| tags).collect();
+    assert_eq!(tags, vec![expected_tags, BTreeMap::new()]);
 
-    // Tidy up:
     server.shutdown().await;
 }
 
-/// If something connects to the `/submit` endpoint, there is a limit to the number
-/// of different messags IDs it can send telemetry about, to prevent a malicious actor from
-/// spamming a load of message IDs and exhausting our memory.
+/// Once the core is tracking as many chains as it's allowed to, nodes on new chains
+/// are rejected, while existing chains continue to accept nodes.
 #[tokio::test]
-async fn e2e_max_nodes_per_connection_is_enforced() {
+async fn e2e_nodes_on_new_chains_rejected_once_at_max_chains() {
+    use FeedMessage::*;
+
     let mut server = start_server(
         ServerOpts::default(),
-        CoreOpts::default(),
-        // Limit max nodes per connection to 2; any other msgs should be ignored.
-        ShardOpts {
-            max_nodes_per_connection: Some(2),
+        CoreOpts {
+            max_chains: Some(2),
             ..Default::default()
         },
+        ShardOpts::default(),
     )
     .await;
-
-    // Connect to a shard
     let shard_id = server.add_shard().await.unwrap();
     let (mut node_tx, _node_rx) = server
         .get_shard(shard_id)
@@ -791,135 +1231,3562 @@ async fn e2e_max_nodes_per_connection_is_enforced() {
         .await
         .unwrap();
 
-    // Connect a feed.
-    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
-
-    // We'll send these messages from the node:
-    let json_msg = |n| {
+    let system_connected = |id: u64, chain: u64| {
         json!({
-            "id":n,
+            "id":id,
             "ts":"2021-07-12T10:37:47.714666+01:00",
             "payload": {
                 "authority":true,
-                "chain":"Test Chain",
+                "chain":format!("Local Testnet {}", chain),
                 "config":"",
-                "genesis_hash": ghash(1),
-                "implementation":"Polkadot",
+                "genesis_hash": ghash(chain),
+                "implementation":"Substrate Node",
                 "msg":"system.connected",
-                "name": format!("Alice {}", n),
-                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "name":format!("Alice {}", id),
+                "network_id":format!("12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp{}", id),
                 "startup_time":"1625565542717",
                 "version":"2.0.0-07a1af348-aarch64-macos"
-            }
+            },
         })
     };
 
-    // First message ID should lead to feed messages:
-    node_tx.send_json_text(json_msg(1)).unwrap();
-    assert_ne!(
-        feed_rx
-            .recv_feed_messages_timeout(Duration::from_secs(1))
-            .await
-            .unwrap()
-            .len(),
-        0
-    );
+    // Fill up the chains we're allowed, and then try adding a node to a third chain
+    // followed by one more node on an existing chain:
+    for (id, chain) in [(1, 1), (2, 2), (3, 3), (4, 1)] {
+        node_tx.send_json_text(system_connected(id, chain)).unwrap();
+    }
 
-    // Second message ID should lead to feed messages as well:
-    node_tx.send_json_text(json_msg(2)).unwrap();
-    assert_ne!(
-        feed_rx
-            .recv_feed_messages_timeout(Duration::from_secs(1))
-            .await
-            .unwrap()
-            .len(),
-        0
-    );
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let mut node_counts = std::collections::HashMap::new();
+    while node_counts.values().sum::<usize>() < 3 {
+        for m in feed_rx.recv_feed_messages().await.unwrap() {
+            if let AddedChain {
+                genesis_hash,
+                node_count,
+                ..
+            } = m
+            {
+                node_counts.insert(genesis_hash, node_count);
+            }
+        }
+    }
 
-    // Third message ID should be ignored:
-    node_tx.send_json_text(json_msg(3)).unwrap();
+    // Give the rejected node a moment to show up if it was going to:
+    let feed_messages = feed_rx
+        .recv_feed_messages_timeout(Duration::from_secs(2))
+        .await
+        .unwrap();
+    assert!(!feed_messages
+        .iter()
+        .any(|m| matches!(m, AddedChain { genesis_hash, .. } if *genesis_hash == ghash(3))));
     assert_eq!(
-        feed_rx
-            .recv_feed_messages_timeout(Duration::from_secs(1))
-            .await
-            .unwrap()
-            .len(),
-        0
+        node_counts,
+        [(ghash(1), 2), (ghash(2), 1)].into_iter().collect()
     );
 
-    // Forth message ID should be ignored as well:
-    node_tx.send_json_text(json_msg(4)).unwrap();
-    assert_eq!(
-        feed_rx
-            .recv_feed_messages_timeout(Duration::from_secs(1))
-            .await
-            .unwrap()
-            .len(),
-        0
-    );
+    // Tidy up:
+    server.shutdown().await;
+}
 
-    // (now that the chain "Test Chain" is known about, subscribe to it for update messages.
-    // This wasn't needed to receive messages re the above since everybody hears about node
-    // count changes)
-    feed_tx
-        .send_command(
-            "subscribe",
-            "0x0000000000000000000000000000000000000000000000000000000000000001",
-        )
+/// Feeds can subscribe to a chain by giving its genesis hash with an explicit `genesis:`
+/// prefix, and are sent a snapshot of that chain just as they are without one.
+#[tokio::test]
+async fn e2e_feed_can_subscribe_by_explicit_genesis_hash() {
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
         .unwrap();
-    feed_rx.recv_feed_messages().await.unwrap();
 
-    // Update about non-ignored IDs should still lead to feed output:
+    // Two chains with the same name, which only their genesis hashes tell apart:
+    for chain in [1, 2] {
+        node_tx
+            .send_json_text(json!({
+                "id":chain,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": ghash(chain),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":format!("Node {chain}"),
+                    "network_id":format!("12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp{chain}"),
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }))
+            .unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(500)).await;
 
-    node_tx.send_json_text(json!(
-        {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:38:48.330433+01:00" }
-    )).unwrap();
-    assert_ne!(
-        feed_rx
-            .recv_feed_messages_timeout(Duration::from_secs(1))
-            .await
-            .unwrap()
-            .len(),
-        0
-    );
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_tx
+        .send_command("subscribe", &format!("genesis:{:#x}", ghash(2)))
+        .unwrap();
 
-    node_tx.send_json_text(json!(
-        {"id":2, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:38:48.330433+01:00" }
-    )).unwrap();
-    assert_ne!(
-        feed_rx
-            .recv_feed_messages_timeout(Duration::from_secs(1))
-            .await
-            .unwrap()
-            .len(),
-        0
+    let mut feed_messages = Vec::new();
+    while !feed_messages
+        .iter()
+        .any(|m| matches!(m, FeedMessage::AddedNode { .. }))
+    {
+        feed_messages.extend(feed_rx.recv_feed_messages().await.unwrap());
+    }
+    assert!(!feed_messages.iter().any(|m| matches!(
+        m,
+        FeedMessage::AddedNode { node: NodeDetails { name, .. }, .. } if name == "Node 1"
+    )));
+    assert_contains_matches!(
+        feed_messages,
+        FeedMessage::SubscribedTo { genesis_hash } if genesis_hash == ghash(2),
+        FeedMessage::AddedNode { node: NodeDetails { name, .. }, .. } if name == "Node 2",
     );
 
-    // Updates about ignored IDs are still ignored:
+    server.shutdown().await;
+}
 
+/// A node that's turned away because its chain is full is told why before its connection is
+/// closed, so that it can back off rather than reconnecting straight away.
+#[tokio::test]
+async fn e2e_nodes_rejected_for_quota_are_told_why() {
+    use futures::StreamExt;
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            max_third_party_nodes: Some(1),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let shard = server.get_shard(shard_id).unwrap();
+    let (mut node_tx, mut node_rx) = shard.connect_node().await.unwrap();
+    let (mut overquota_node_tx, mut overquota_node_rx) = shard.connect_node().await.unwrap();
+
+    let system_connected = |name: &str| {
+        json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":name,
+                "network_id":format!("12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp{name}"),
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        })
+    };
+
+    // The first node fills the chain, so the second is over quota:
+    node_tx.send_json_text(system_connected("Alice")).unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    overquota_node_tx
+        .send_json_text(system_connected("Bob"))
+        .unwrap();
+
+    let msg = tokio::time::timeout(Duration::from_secs(5), overquota_node_rx.next())
+        .await
+        .expect("the overquota node should be told why it was rejected");
+    match msg {
+        Some(Ok(ws_client::RecvMessage::Text(text))) => assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+            json!({ "code": 4001, "reason": "quota-exceeded" })
+        ),
+        other => panic!("unexpected message from shard: {other:?}"),
+    }
+    let closed = tokio::time::timeout(Duration::from_secs(5), overquota_node_rx.next())
+        .await
+        .expect("the overquota node should be disconnected");
+    assert!(!matches!(closed, Some(Ok(_))), "got {closed:?}");
+
+    // The node that got in first is left alone:
+    let res = tokio::time::timeout(Duration::from_millis(500), node_rx.next()).await;
+    assert!(res.is_err(), "the first node shouldn't hear anything");
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// On chains where only authority nodes are tracked, other nodes are told that their chain
+/// isn't allowed and disconnected, while authority nodes are added as usual.
+#[tokio::test]
+async fn e2e_non_authority_nodes_are_rejected_on_authority_only_chains() {
+    use futures::StreamExt;
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            authority_only_chain: vec![format!("{:#x}", ghash(1))],
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let shard = server.get_shard(shard_id).unwrap();
+    let (mut authority_tx, mut authority_rx) = shard.connect_node().await.unwrap();
+    let (mut full_node_tx, mut full_node_rx) = shard.connect_node().await.unwrap();
+
+    let system_connected = |name: &str, authority: bool| {
+        json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":authority,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":name,
+                "network_id":format!("12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp{name}"),
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        })
+    };
+
+    authority_tx
+        .send_json_text(system_connected("Alice", true))
+        .unwrap();
+    full_node_tx
+        .send_json_text(system_connected("Bob", false))
+        .unwrap();
+
+    let msg = tokio::time::timeout(Duration::from_secs(5), full_node_rx.next())
+        .await
+        .expect("the non-authority node should be told why it was rejected");
+    match msg {
+        Some(Ok(ws_client::RecvMessage::Text(text))) => assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+            json!({ "code": 4002, "reason": "chain-denied" })
+        ),
+        other => panic!("unexpected message from shard: {other:?}"),
+    }
+    let closed = tokio::time::timeout(Duration::from_secs(5), full_node_rx.next())
+        .await
+        .expect("the non-authority node should be disconnected");
+    assert!(!matches!(closed, Some(Ok(_))), "got {closed:?}");
+
+    // The authority node is left alone, and is the only node on the chain:
+    let res = tokio::time::timeout(Duration::from_millis(500), authority_rx.next()).await;
+    assert!(res.is_err(), "the authority node shouldn't hear anything");
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_tx
+        .send_command("subscribe", &format!("{:#x}", ghash(1)))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    let names: Vec<_> = feed_messages
+        .iter()
+        .filter_map(|m| match m {
+            FeedMessage::AddedNode { node, .. } => Some(&*node.name),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(names, ["Alice"]);
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Shards only accept nodes that speak a version of the node protocol in the range that
+/// they're given, and parse their messages according to that version.
+#[tokio::test]
+async fn e2e_nodes_speaking_unsupported_node_protocols_are_told_why() {
+    use futures::StreamExt;
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts::default(),
+        ShardOpts {
+            min_node_protocol: Some(2),
+            max_node_protocol: Some(2),
+            ..Default::default()
+        },
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let shard = server.get_shard(shard_id).unwrap();
+
+    let system_connected = |name: &str, protocol_version: Option<u32>| {
+        let mut msg = json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":name,
+                "network_id":format!("12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp{name}"),
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        });
+        if let Some(version) = protocol_version {
+            msg["payload"]["protocol_version"] = version.into();
+        }
+        msg
+    };
+
+    // Nodes speaking older or newer versions are turned away:
+    for (name, protocol_version) in [("Old", None), ("New", Some(3))] {
+        let (mut node_tx, mut node_rx) = shard.connect_node().await.unwrap();
+        node_tx
+            .send_json_text(system_connected(name, protocol_version))
+            .unwrap();
+        let msg = tokio::time::timeout(Duration::from_secs(5), node_rx.next())
+            .await
+            .expect("the node should be told why it was rejected");
+        match msg {
+            Some(Ok(ws_client::RecvMessage::Text(text))) => assert_eq!(
+                serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+                json!({ "code": 4006, "reason": "unsupported-protocol" })
+            ),
+            other => panic!("unexpected message from shard: {other:?}"),
+        }
+        let closed = tokio::time::timeout(Duration::from_secs(5), node_rx.next())
+            .await
+            .expect("the node should be disconnected");
+        assert!(!matches!(closed, Some(Ok(_))), "got {closed:?}");
+    }
+
+    // A node speaking version 2 is accepted, and sends block numbers as numbers:
+    let (mut node_tx, _node_rx) = shard.connect_node().await.unwrap();
+    node_tx
+        .send_json_text(system_connected("Alice", Some(2)))
+        .unwrap();
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    loop {
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        if feed_messages
+            .iter()
+            .any(|m| matches!(m, FeedMessage::AddedChain { node_count: 1, .. }))
+        {
+            break;
+        }
+    }
+    let chain = format!("{:#x}", ghash(1));
+    feed_tx
+        .send_command("send-finality", chain.as_str())
+        .unwrap();
+    feed_tx.send_command("subscribe", chain.as_str()).unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    node_tx.send_json_text(json!(
+        {"id":1, "payload":{ "best":BlockHash::from_low_u64_be(5),"height":5,"msg":"notify.finalized" },"ts":"2021-07-12T10:37:49.330433+01:00" }
+    )).unwrap();
+    loop {
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        if feed_messages.iter().any(|m| {
+            matches!(
+                m,
+                FeedMessage::FinalizedBlock {
+                    node_id: 0,
+                    block_number: 5,
+                    ..
+                }
+            )
+        }) {
+            break;
+        }
+    }
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// A node that connects again while its old connection is still open replaces it, rather than
+/// showing up twice. The old connection is closed, and the node is told why.
+#[tokio::test]
+async fn e2e_node_reconnecting_supersedes_its_old_connection() {
+    use futures::StreamExt;
+
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let shard = server.get_shard(shard_id).unwrap();
+    let (mut old_node_tx, mut old_node_rx) = shard.connect_node().await.unwrap();
+    let (mut new_node_tx, mut new_node_rx) = shard.connect_node().await.unwrap();
+    let (mut other_node_tx, _other_node_rx) = shard.connect_node().await.unwrap();
+
+    let system_connected = |name: &str, network_id: &str| {
+        json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":name,
+                "network_id":network_id,
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        })
+    };
+    let peer_id = "12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp";
+
+    old_node_tx
+        .send_json_text(system_connected("Alice", peer_id))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    new_node_tx
+        .send_json_text(system_connected("Alice", peer_id))
+        .unwrap();
+    // A different node that's been given the same network ID is kept apart:
+    other_node_tx
+        .send_json_text(system_connected("Bob", peer_id))
+        .unwrap();
+
+    let msg = tokio::time::timeout(Duration::from_secs(5), old_node_rx.next())
+        .await
+        .expect("the old connection should be told why it's being closed");
+    match msg {
+        Some(Ok(ws_client::RecvMessage::Text(text))) => assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+            json!({ "code": 4005, "reason": "superseded" })
+        ),
+        other => panic!("unexpected message from shard: {other:?}"),
+    }
+    let closed = tokio::time::timeout(Duration::from_secs(5), old_node_rx.next())
+        .await
+        .expect("the old connection should be closed");
+    assert!(!matches!(closed, Some(Ok(_))), "got {closed:?}");
+
+    // The new connection is left alone:
+    let res = tokio::time::timeout(Duration::from_millis(500), new_node_rx.next()).await;
+    assert!(res.is_err(), "the new connection shouldn't hear anything");
+
+    // Only one copy of Alice is left, alongside Bob:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_tx
+        .send_command("subscribe", &format!("{:#x}", ghash(1)))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&FeedMessage::AddedChain {
+        name: "Local Testnet".to_owned(),
+        genesis_hash: ghash(1),
+        node_count: 2,
+        tags: Default::default(),
+    }));
+    let mut names: Vec<_> = feed_messages
+        .iter()
+        .filter_map(|msg| match msg {
+            FeedMessage::AddedNode { node, .. } => Some(node.name.clone()),
+            _ => None,
+        })
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["Alice".to_owned(), "Bob".to_owned()]);
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// When the core is given a shard secret, only shards that present the same secret can
+/// connect to it.
+#[tokio::test]
+async fn e2e_shards_must_present_the_shard_secret() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            shard_secret: Some("s3cret".to_owned()),
+            ..Default::default()
+        },
+        ShardOpts {
+            shard_secret: Some("s3cret".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // Anything that doesn't present the right secret is refused:
+    let uri: http::Uri = format!("http://{}/shard_submit", server.get_core().host())
+        .parse()
+        .unwrap();
+    for headers in [vec![], vec![("Authorization", "Bearer wrong")]] {
+        let res = ws_client::connect_with_headers(&uri, &headers).await;
+        assert!(
+            matches!(
+                res,
+                Err(ws_client::ConnectError::ConnectionFailedRejected { status_code: 401 })
+            ),
+            "expected to be refused with headers {headers:?}"
+        );
+    }
+    assert!(
+        ws_client::connect_with_headers(&uri, &[("Authorization", "Bearer s3cret")])
+            .await
+            .is_ok()
+    );
+
+    // Shards started with the right secret connect, and pass on the nodes connected to them:
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&FeedMessage::AddedChain {
+        name: "Local Testnet".to_owned(),
+        genesis_hash: ghash(1),
+        node_count: 1,
+        tags: Default::default(),
+    }));
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds can ask for everything we know about a node on the chain they're subscribed to,
+/// and are told why if we can't answer.
+#[tokio::test]
+async fn e2e_feed_can_fetch_the_detail_of_a_node() {
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    // Feeds have to be subscribed to the node's chain to ask about it:
+    feed_tx.send_command("node-detail", "0").unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(
+        matches!(&*feed_messages, [FeedMessage::CommandError { .. }]),
+        "expected an error, got {feed_messages:?}"
+    );
+
+    feed_tx
+        .send_command("subscribe", &format!("{:#x}", ghash(1)))
+        .unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    feed_tx.send_command("node-detail", "0").unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    match &*feed_messages {
+        [FeedMessage::NodeDetail {
+            node_id: 0,
+            node,
+            startup_time,
+            stale: false,
+            ..
+        }] => {
+            assert_eq!(node.name, "Alice");
+            assert_eq!(*startup_time, Some(1625565542717));
+        }
+        _ => panic!("expected the node's detail, got {feed_messages:?}"),
+    }
+
+    // Nodes that we don't know about are answered with an error:
+    feed_tx.send_command("node-detail", "1").unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(
+        matches!(&*feed_messages, [FeedMessage::CommandError { .. }]),
+        "expected an error, got {feed_messages:?}"
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds that subscribe with `lean=1` aren't sent the network IDs of nodes, while other
+/// feeds are.
+#[tokio::test]
+async fn e2e_lean_feeds_are_not_sent_network_ids() {
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut network_ids = Vec::new();
+    for options in ["", ":lean=1"] {
+        let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+        feed_rx.recv_feed_messages().await.unwrap();
+        feed_tx
+            .send_command("subscribe", &format!("{:#x}{options}", ghash(1)))
+            .unwrap();
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        let network_id = feed_messages.iter().find_map(|m| match m {
+            FeedMessage::AddedNode { node, .. } => Some(node.network_id.clone()),
+            _ => None,
+        });
+        network_ids.push(network_id.expect("the node should be sent to the feed"));
+    }
+    assert_eq!(
+        network_ids,
+        [
+            Some("12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp".to_owned()),
+            None
+        ]
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feed connections beyond '--max-feeds' are refused, and once a feed disconnects, another
+/// can take its place.
+#[tokio::test]
+async fn e2e_feeds_beyond_max_feeds_are_refused() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            max_feeds: Some(2),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+
+    let (mut feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let (_feed_tx2, _feed_rx2) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    // The websocket handshake is refused with a reason:
+    let host = server.get_core().host().to_owned();
+    let refusal = async {
+        let mut stream = tokio::net::TcpStream::connect(&host).await.unwrap();
+        let request = format!(
+            "GET /feed HTTP/1.1\r\nHost: {host}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        // The connection may be kept alive, so read until we have the whole reason:
+        let mut response = Vec::new();
+        while !response.ends_with(b"Too many feeds are connected") {
+            let mut buf = [0u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed early");
+            response.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8(response).unwrap()
+    };
+    let response = tokio::time::timeout(Duration::from_secs(5), refusal)
+        .await
+        .unwrap();
+    assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+    assert!(server.get_core().connect_feed().await.is_err());
+
+    // Once a feed disconnects, there's room for another:
+    feed_tx.close().await.unwrap();
+    drop(feed_rx);
+    let mut reconnected = None;
+    for _ in 0..50 {
+        if let Ok(feed) = server.get_core().connect_feed().await {
+            reconnected = Some(feed);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let (_feed_tx, mut feed_rx) = reconnected.expect("a feed should be let in once one leaves");
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Shards compare the time that nodes say they sent each message with their own clocks, and
+/// nodes whose clocks are too far out are flagged to feeds.
+#[tokio::test]
+async fn e2e_nodes_with_skewed_clocks_are_flagged() {
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    // The first node's clock is years behind; the second doesn't say what time it is:
+    for (id, ts) in [(1, Some("2021-07-12T10:37:47.714666+01:00")), (2, None)] {
+        let mut msg = json!({
+            "id": id,
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name": format!("Node {id}"),
+                "network_id": format!("Node {id}"),
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        });
+        if let Some(ts) = ts {
+            msg["ts"] = json!(ts);
+        }
+        node_tx.send_json_text(msg).unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+    feed_tx
+        .send_command("subscribe", &format!("{:#x}", ghash(1)))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+
+    let skews: Vec<_> = feed_messages
+        .iter()
+        .filter_map(|m| match m {
+            FeedMessage::NodeClockSkew { node_id, skew_ms } => Some((*node_id, *skew_ms)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(skews.len(), 1, "only the first node should be flagged");
+    let (node_id, skew_ms) = skews[0];
+    assert_eq!(node_id, 0);
+    assert!(
+        skew_ms.unwrap() < -365 * 24 * 60 * 60 * 1000,
+        "the node's clock should be over a year behind, not {skew_ms:?}ms"
+    );
+
+    // The skew is also a part of the node's details:
+    feed_tx.send_command("node-detail", "0").unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    let clock_skew = feed_messages.iter().find_map(|m| match m {
+        FeedMessage::NodeDetail { clock_skew, .. } => Some(*clock_skew),
+        _ => None,
+    });
+    assert_eq!(clock_skew, Some(skew_ms));
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds can also be consumed as newline delimited JSON over plain TCP, with each line holding
+/// one message. Consumers can choose a chain to subscribe to by sending a query when they connect.
+#[tokio::test]
+async fn e2e_feed_can_be_consumed_as_ndjson_over_tcp() {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{tcp::OwnedReadHalf, TcpStream};
+
+    let ndjson_port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            feed_ndjson_port: Some(ndjson_port),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // Each line is a single message, and we read lines until we find the one we want:
+    async fn recv_until(
+        lines: &mut tokio::io::Lines<BufReader<OwnedReadHalf>>,
+        is_wanted: impl Fn(&FeedMessage) -> bool,
+    ) -> Vec<FeedMessage> {
+        let mut feed_messages = Vec::new();
+        loop {
+            let line = tokio::time::timeout(Duration::from_secs(5), lines.next_line())
+                .await
+                .expect("should be sent the message we're waiting for")
+                .unwrap()
+                .expect("connection shouldn't be closed");
+            let mut msgs = FeedMessage::from_bytes(line.as_bytes()).unwrap();
+            assert_eq!(msgs.len(), 1, "expected one message per line: {line}");
+            let msg = msgs.remove(0);
+            let found = is_wanted(&msg);
+            feed_messages.push(msg);
+            if found {
+                return feed_messages;
+            }
+        }
+    }
+    let connect = || async {
+        let stream = TcpStream::connect(("127.0.0.1", ndjson_port))
+            .await
+            .unwrap();
+        let (read_half, write_half) = stream.into_split();
+        (write_half, BufReader::new(read_half).lines())
+    };
+
+    // Consumers that don't send anything are told about the chains, like any other feed:
+    let (_chains_tx, mut chains_rx) = connect().await;
+    let feed_messages = recv_until(&mut chains_rx, |msg| {
+        matches!(msg, FeedMessage::AddedChain { genesis_hash, .. } if *genesis_hash == ghash(1))
+    })
+    .await;
+    assert_sent_version(&feed_messages[..2]);
+
+    // Consumers can subscribe to a chain:
+    let (mut chain_tx, mut chain_rx) = connect().await;
+    chain_tx
+        .write_all(format!("chain={:#x}\n", ghash(1)).as_bytes())
+        .await
+        .unwrap();
+    let feed_messages = recv_until(
+        &mut chain_rx,
+        |msg| matches!(msg, FeedMessage::AddedNode { node, .. } if node.name == "Alice"),
+    )
+    .await;
+    assert!(feed_messages.contains(&FeedMessage::SubscribedTo {
+        genesis_hash: ghash(1)
+    }));
+
+    // Queries that we can't make sense of are answered with an error:
+    let (mut bad_tx, mut bad_rx) = connect().await;
+    bad_tx.write_all(b"wibble=1\n").await.unwrap();
+    recv_until(&mut bad_rx, |msg| {
+        matches!(msg, FeedMessage::CommandError { .. })
+    })
+    .await;
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds are told when we last heard from each node, by our clock, both when they subscribe
+/// and as the node's interval messages arrive.
+#[tokio::test]
+async fn e2e_node_last_seen_advances_with_interval_messages() {
+    use FeedMessage::*;
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts::default(),
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    let connected_at = common::time::now();
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": ghash(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Alice",
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }
+        ))
+        .unwrap();
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(feed_messages, AddedChain { node_count: 1, .. });
+    feed_tx
+        .send_command("subscribe", &format!("{:#x}", ghash(1)))
+        .unwrap();
+
+    // The snapshot says when we last heard from the node (the timestamp that it sent is ignored):
+    let mut last_seen = None;
+    while last_seen.is_none() {
+        for m in feed_rx.recv_feed_messages().await.unwrap() {
+            if let AddedNode { last_seen: t, .. } = m {
+                last_seen = Some(t);
+            }
+        }
+    }
+    let mut last_seen = last_seen.unwrap();
+    assert!(last_seen >= connected_at, "{last_seen} < {connected_at}");
+
+    // And each interval message moves it on:
+    for peers in 1..=3 {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        node_tx
+            .send_json_text(json!(
+                {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":peers},"ts":"2021-07-12T10:37:48.330433+01:00" }
+            ))
+            .unwrap();
+
+        let mut next_seen = None;
+        while next_seen.is_none() {
+            for m in feed_rx.recv_feed_messages().await.unwrap() {
+                if let NodeLastSeen {
+                    node_id: 0,
+                    last_seen: t,
+                } = m
+                {
+                    next_seen = Some(t);
+                }
+            }
+        }
+        let next_seen = next_seen.unwrap();
+        assert!(next_seen > last_seen, "{next_seen} <= {last_seen}");
+        last_seen = next_seen;
+    }
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// When asked to, the core limits how often the details that a node reports periodically are
+/// sent to feeds, collapsing the updates in between into the latest values.
+#[tokio::test]
+async fn e2e_node_updates_are_sent_at_most_once_per_min_interval() {
+    use FeedMessage::*;
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            node_update_min_interval: Some(2000),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": ghash(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Alice",
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }
+        ))
+        .unwrap();
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(feed_messages, AddedChain { node_count: 1, .. });
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    // Report a handful of different peer counts in quick succession:
+    let interval = |peers: u64| {
+        json!(
+            {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":peers},"ts":"2021-07-12T10:37:48.330433+01:00" }
+        )
+    };
+    for peers in 1..=5 {
+        node_tx.send_json_text(interval(peers)).unwrap();
+    }
+
+    // Only the first is sent straight away; the rest are held back..
+    let mut peers_sent = vec![];
+    while peers_sent.is_empty() {
+        for m in feed_rx.recv_feed_messages().await.unwrap() {
+            if let NodeStatsUpdate { stats, .. } = m {
+                peers_sent.push(stats.peers);
+            }
+        }
+    }
+    assert_eq!(peers_sent, vec![1]);
+
+    // ..until the interval has passed, when the latest values go out with the next update:
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    node_tx.send_json_text(interval(5)).unwrap();
+    while peers_sent.len() < 2 {
+        for m in feed_rx.recv_feed_messages().await.unwrap() {
+            if let NodeStatsUpdate { stats, .. } = m {
+                peers_sent.push(stats.peers);
+            }
+        }
+    }
+    assert_eq!(peers_sent, vec![1, 5]);
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// If a node sends more than some rolling average amount of data, it'll be booted.
+#[tokio::test]
+#[allow(clippy::bool_assert_comparison)]
+async fn e2e_node_banned_if_it_sends_too_much_data() {
+    async fn try_send_data(max_bytes: usize, send_msgs: usize, bytes_per_msg: usize) -> bool {
+        let mut server = start_server(
+            ServerOpts::default(),
+            CoreOpts::default(),
+            ShardOpts {
+                // Remember, this is (currently) averaged over the last 10 seconds,
+                // so we need to send 10x this amount of data for an imemdiate ban:
+                max_node_data_per_second: Some(max_bytes),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        // Give us a shard to talk to:
+        let shard_id = server.add_shard().await.unwrap();
+        let (node_tx, _node_rx) = server
+            .get_shard(shard_id)
+            .unwrap()
+            .connect_node()
+            .await
+            .unwrap();
+
+        // Send the data requested to the shard:
+        for _ in 0..send_msgs {
+            node_tx
+                .unbounded_send(SentMessage::Binary(vec![1; bytes_per_msg]))
+                .unwrap();
+        }
+
+        // Wait a little for the shard to react and cut off the connection (or not):
+        tokio::time::sleep(Duration::from_millis(250)).await;
+
+        // Has the connection been closed?
+        node_tx.is_closed()
+    }
+
+    assert_eq!(
+        try_send_data(1000, 10, 1000).await,
+        false,
+        "shouldn't be closed; we didn't exceed 10x threshold"
+    );
+    assert_eq!(
+        try_send_data(999, 10, 1000).await,
+        true,
+        "should be closed; we sent just over 10x the block threshold"
+    );
+}
+
+/// Nodes that send a message larger than the shard allows are disconnected.
+#[tokio::test]
+async fn e2e_node_disconnected_if_it_sends_too_large_a_message() {
+    async fn send_message(max_node_msg_bytes: usize, msg_bytes: usize) -> bool {
+        let mut server = start_server(
+            ServerOpts::default(),
+            CoreOpts::default(),
+            ShardOpts {
+                max_node_msg_bytes: Some(max_node_msg_bytes),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let shard_id = server.add_shard().await.unwrap();
+        let (mut node_tx, mut node_rx) = server
+            .get_shard(shard_id)
+            .unwrap()
+            .connect_node_raw()
+            .await
+            .unwrap();
+
+        node_tx.send_binary(vec![1; msg_bytes]).await.unwrap();
+        node_tx.flush().await.unwrap();
+
+        // Wait a little for the shard to close the connection (or not):
+        let mut bytes = Vec::new();
+        let res =
+            tokio::time::timeout(Duration::from_millis(500), node_rx.receive(&mut bytes)).await;
+        let is_closed = match res {
+            // Timed out; the connection is still open:
+            Err(_) => false,
+            Ok(Ok(soketto::Incoming::Closed(reason))) => {
+                assert_eq!(reason.code, 1000, "should be closed cleanly");
+                true
+            }
+            Ok(Ok(incoming)) => panic!("unexpected message from shard: {incoming:?}"),
+            Ok(Err(soketto::connection::Error::Closed)) => true,
+            Ok(Err(e)) => panic!("unexpected error from shard: {e}"),
+        };
+
+        server.shutdown().await;
+        is_closed
+    }
+
+    assert!(
+        !send_message(1000, 1000).await,
+        "shouldn't be closed; the message is within the limit"
+    );
+    assert!(
+        send_message(1000, 1001).await,
+        "should be closed; the message exceeds the limit"
+    );
+}
+
+/// Authority nodes that connect with the right token, in the URL or in a header, are let in even
+/// if their chain isn't on the allow list, while other nodes on that chain are still turned away.
+#[tokio::test]
+async fn e2e_authority_nodes_with_token_bypass_allowlist() {
+    use FeedMessage::*;
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            allow_chain: vec![format!("{:#x}=Allowed Chain", ghash(1))],
+            authority_node_token: Some("s3cret".to_owned()),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let shard = server.get_shard(shard_id).unwrap();
+    let (mut token_node_tx, _token_node_rx) =
+        shard.connect_node_with_token("s3cret").await.unwrap();
+    let (mut wrong_token_node_tx, _wrong_token_node_rx) =
+        shard.connect_node_with_token("wrong").await.unwrap();
+    let (mut header_token_node_tx, _header_token_node_rx) = shard
+        .connect_node_with_headers(&[("Authorization", "Bearer s3cret")])
+        .await
+        .unwrap();
+    let (mut node_tx, _node_rx) = shard.connect_node().await.unwrap();
+
+    let system_connected = |id: u64, chain: u64, authority: bool| {
+        json!({
+            "id":id,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":authority,
+                "chain":format!("Local Testnet {}", chain),
+                "config":"",
+                "genesis_hash": ghash(chain),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":format!("Alice {}", id),
+                "network_id":format!("12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp{}", id),
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        })
+    };
+
+    // Only the authority node with the right token can join a chain that isn't allowed:
+    token_node_tx
+        .send_json_text(system_connected(1, 2, true))
+        .unwrap();
+    token_node_tx
+        .send_json_text(system_connected(2, 2, false))
+        .unwrap();
+    wrong_token_node_tx
+        .send_json_text(system_connected(1, 2, true))
+        .unwrap();
+    header_token_node_tx
+        .send_json_text(system_connected(3, 2, true))
+        .unwrap();
+    node_tx
+        .send_json_text(system_connected(1, 2, true))
+        .unwrap();
+    // Any node can join an allowed chain:
+    node_tx
+        .send_json_text(system_connected(2, 1, false))
+        .unwrap();
+
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let mut node_counts = std::collections::HashMap::new();
+    while node_counts.len() < 2 {
+        for m in feed_rx.recv_feed_messages().await.unwrap() {
+            if let AddedChain {
+                name,
+                genesis_hash,
+                node_count,
+                ..
+            } = m
+            {
+                node_counts.insert(genesis_hash, (name, node_count));
+            }
+        }
+    }
+
+    // Give any nodes that shouldn't have been let in a moment to show up:
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    for m in feed_rx
+        .recv_feed_messages_once_timeout(Duration::from_millis(500))
+        .await
+        .unwrap_or_default()
+    {
+        if let AddedChain {
+            name,
+            genesis_hash,
+            node_count,
+            ..
+        } = m
+        {
+            node_counts.insert(genesis_hash, (name, node_count));
+        }
+    }
+    assert_eq!(node_counts.len(), 2);
+    assert_eq!(node_counts[&ghash(1)], ("Allowed Chain".to_owned(), 1));
+    assert_eq!(node_counts[&ghash(2)], ("Local Testnet 2".to_owned(), 2));
+
+    server.shutdown().await;
+}
+
+/// Malformed messages from a node are ignored and counted, while the valid messages around them
+/// are handled as usual, until the node sends too many malformed messages in a row.
+#[tokio::test]
+async fn e2e_malformed_node_messages_are_ignored_until_too_many_in_a_row() {
+    use FeedMessage::*;
+
+    use futures::StreamExt;
+
+    let admin_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts::default(),
+        ShardOpts {
+            admin_listen: Some(admin_addr),
+            max_consecutive_malformed_messages: Some(3),
+            ..Default::default()
+        },
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, mut node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    let garbage = |s: &str| SentMessage::Text(s.to_owned());
+    node_tx.unbounded_send(garbage("{not json")).unwrap();
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": ghash(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Alice",
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }
+        ))
+        .unwrap();
+    node_tx
+        .unbounded_send(garbage(
+            r#"{"id":1,"payload":{"msg":"system.interval","peers":"lots"}}"#,
+        ))
+        .unwrap();
+    node_tx
+        .send_json_text(json!(
+            {"id":1, "payload":{ "msg":"block.import", "best":"0x0000000000000000000000000000000000000000000000000000000000000003", "height":3 },"ts":"2021-07-12T10:37:48.330433+01:00" }
+        ))
+        .unwrap();
+
+    // The valid messages are handled despite the malformed ones around them:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(feed_messages, AddedChain { node_count: 1, .. });
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    let mut best_height = 0;
+    while best_height != 3 {
+        for m in feed_rx.recv_feed_messages().await.unwrap() {
+            if let BestBlock { block_number, .. } = m {
+                best_height = block_number;
+            }
+        }
+    }
+
+    // The malformed messages were counted:
+    let metrics = reqwest::get(format!("http://{admin_addr}/metrics"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("telemetry_shard_malformed_node_messages 2\n"));
+    assert!(metrics.contains("telemetry_shard_nodes_disconnected_for_malformed_messages 0\n"));
+
+    // Too many in a row and the node is disconnected:
+    for _ in 0..3 {
+        node_tx.unbounded_send(garbage("wibble")).unwrap();
+    }
+    let res = tokio::time::timeout(Duration::from_secs(5), node_rx.next()).await;
+    assert!(
+        matches!(res, Ok(None) | Ok(Some(Err(_)))),
+        "node should have been disconnected"
+    );
+    let metrics = reqwest::get(format!("http://{admin_addr}/metrics"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("telemetry_shard_malformed_node_messages 5\n"));
+    assert!(metrics.contains("telemetry_shard_nodes_disconnected_for_malformed_messages 1\n"));
+
+    server.shutdown().await;
+}
+
+/// With '--dedupe-node-messages', a message that's identical to the one before it is dropped by
+/// the shard and counted, while distinct messages are passed on to the core.
+#[tokio::test]
+async fn e2e_duplicate_node_messages_are_dropped() {
+    let admin_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            admin_token: Some("s3cret".to_owned()),
+            ..Default::default()
+        },
+        ShardOpts {
+            admin_listen: Some(admin_addr),
+            dedupe_node_messages: true,
+            ..Default::default()
+        },
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let core_host = server.get_core().host().to_owned();
+    let core_url = |path: &str| format!("http://{core_host}{path}");
+
+    // Keep every node message that reaches the core:
+    let res = client
+        .post(core_url("/admin/sampling?every=1&capacity=100"))
+        .bearer_auth("s3cret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+    let interval = |peers: u64| {
+        json!({
+            "id":1,
+            "payload":{
+                "bandwidth_download":576,
+                "bandwidth_upload":576,
+                "msg":"system.interval",
+                "peers":peers
+            },
+            "ts":"2021-07-12T10:37:48.330433+01:00"
+        })
+    };
+    for _ in 0..5 {
+        node_tx.send_json_text(interval(1)).unwrap();
+    }
+    node_tx.send_json_text(interval(2)).unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // Only the distinct messages made it to the core:
+    let samples: serde_json::Value = client
+        .get(core_url("/admin/samples"))
+        .bearer_auth("s3cret")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let peers: Vec<_> = samples
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| s["payload"]["SystemInterval"]["peers"].clone())
+        .collect();
+    assert_eq!(peers, [json!(1), json!(2)]);
+
+    // And the duplicates were counted:
+    let metrics = reqwest::get(format!("http://{admin_addr}/metrics"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("telemetry_shard_duplicate_node_messages 4\n"));
+
+    server.shutdown().await;
+}
+
+/// Shards refuse connections from an address that already has too many open, and
+/// allow them again once some of those connections close.
+#[tokio::test]
+async fn e2e_max_conns_per_ip_is_enforced() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts::default(),
+        ShardOpts {
+            max_conns_per_ip: Some(3),
+            ..Default::default()
+        },
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let shard = server.get_shard(shard_id).unwrap();
+
+    // All of our connections come from localhost, so only the first 3 are accepted:
+    let mut nodes = shard
+        .connect_multiple_nodes(3)
+        .await
+        .expect("nodes can connect");
+    assert!(shard.connect_node().await.is_err());
+    assert!(shard.connect_node().await.is_err());
+
+    // Refused connections aren't counted, and a closed connection frees up a slot:
+    let (mut node_tx, _node_rx) = nodes.pop().unwrap();
+    node_tx.close().await.unwrap();
+    let mut reconnected = false;
+    for _ in 0..20 {
+        if shard.connect_node().await.is_ok() {
+            reconnected = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(
+        reconnected,
+        "should be able to connect once a connection closes"
+    );
+
+    server.shutdown().await;
+}
+
+/// Shards only take the address of a node from proxy headers if the connection comes
+/// from a trusted proxy, so untrusted connections can't dodge per-IP limits with them.
+#[tokio::test]
+async fn e2e_proxy_headers_are_only_trusted_from_trusted_proxies() {
+    async fn both_connections_accepted(trusted_proxy: &str) -> bool {
+        let mut server = start_server(
+            ServerOpts::default(),
+            CoreOpts::default(),
+            ShardOpts {
+                max_conns_per_ip: Some(1),
+                trust_proxy_header: true,
+                trusted_proxies: vec![trusted_proxy.to_owned()],
+                ..Default::default()
+            },
+        )
+        .await;
+        let shard_id = server.add_shard().await.unwrap();
+        let shard = server.get_shard(shard_id).unwrap();
+
+        // Two connections, each claiming to be forwarded on behalf of a different address:
+        let _first = shard
+            .connect_node_with_headers(&[("X-Forwarded-For", "1.2.3.4")])
+            .await
+            .expect("first connection is under the limit");
+        let second = shard
+            .connect_node_with_headers(&[("X-Forwarded-For", "5.6.7.8")])
+            .await;
+
+        server.shutdown().await;
+        second.is_ok()
+    }
+
+    assert!(
+        both_connections_accepted("127.0.0.0/8").await,
+        "connections are from a trusted proxy, so come from different addresses"
+    );
+    assert!(
+        !both_connections_accepted("10.0.0.0/8").await,
+        "connections aren't from a trusted proxy, so come from the same address"
+    );
+}
+
+/// Shards can serve health checks on a separate address to node submissions.
+#[tokio::test]
+async fn e2e_shard_can_serve_health_checks_on_separate_address() {
+    // Find a free port to ask the shard to serve health checks on:
+    let admin_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts::default(),
+        ShardOpts {
+            admin_listen: Some(admin_addr),
+            ..Default::default()
+        },
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let shard_host = server.get_shard(shard_id).unwrap().host().to_owned();
+
+    let res = reqwest::get(format!("http://{admin_addr}/health"))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.text().await.unwrap(), "OK");
+
+    // Health checks are no longer served alongside node submissions, which still work:
+    let res = reqwest::get(format!("http://{shard_host}/health"))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 404);
+    server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("nodes can still connect");
+
+    server.shutdown().await;
+}
+
+/// Shards can serve health checks and metrics on a Unix domain socket instead.
+#[cfg(unix)]
+#[tokio::test]
+async fn e2e_shard_can_serve_metrics_on_a_unix_socket() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let admin_uds =
+        std::env::temp_dir().join(format!("telemetry-shard-{}.sock", std::process::id()));
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts::default(),
+        ShardOpts {
+            admin_uds: Some(admin_uds.clone()),
+            ..Default::default()
+        },
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let shard_host = server.get_shard(shard_id).unwrap().host().to_owned();
+
+    // The socket may not be ready quite as soon as the shard is:
+    let mut stream = None;
+    for _ in 0..50 {
+        match tokio::net::UnixStream::connect(&admin_uds).await {
+            Ok(s) => {
+                stream = Some(s);
+                break;
+            }
+            Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    }
+    let mut stream = stream.expect("should be able to connect to the admin socket");
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut res = String::new();
+    stream.read_to_string(&mut res).await.unwrap();
+    assert!(res.starts_with("HTTP/1.1 200"), "{res}");
+    assert!(
+        res.contains("telemetry_shard_malformed_node_messages 0"),
+        "{res}"
+    );
+
+    // Metrics are no longer served alongside node submissions:
+    let res = reqwest::get(format!("http://{shard_host}/metrics"))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 404);
+
+    server.shutdown().await;
+    let _ = std::fs::remove_file(&admin_uds);
+}
+
+/// Draining shards refuse new nodes but keep the ones they have, until asked to
+/// disconnect them so that they reconnect elsewhere.
+#[tokio::test]
+async fn e2e_draining_shard_refuses_new_nodes() {
+    use futures::StreamExt;
+
+    let admin_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts::default(),
+        ShardOpts {
+            admin_listen: Some(admin_addr),
+            ..Default::default()
+        },
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let shard = server.get_shard(shard_id).unwrap();
+    let client = reqwest::Client::new();
+    let drain = |method: reqwest::Method, query: &str| {
+        client
+            .request(method, format!("http://{admin_addr}/drain{query}"))
+            .send()
+    };
+
+    let (mut node_tx, mut node_rx) = shard.connect_node().await.unwrap();
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+
+    let res = drain(reqwest::Method::POST, "").await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.text().await.unwrap(), r#"{"draining":true}"#);
+
+    // New nodes are refused, but the existing one is still connected and tracked:
+    assert!(shard.connect_node().await.is_err());
+    tokio::time::timeout(Duration::from_secs(1), node_rx.next())
+        .await
+        .expect_err("the existing node should still be connected");
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(feed_messages, FeedMessage::AddedChain { node_count: 1, .. });
+
+    // Nodes can connect again once we stop draining:
+    let res = drain(reqwest::Method::DELETE, "").await.unwrap();
+    assert_eq!(res.text().await.unwrap(), r#"{"draining":false}"#);
+    let (_node_tx2, mut node_rx2) = shard.connect_node().await.unwrap();
+
+    // Connected nodes are told that we're draining and disconnected after the delay if
+    // we're asked to:
+    let res = drain(reqwest::Method::POST, "?reconnect_after=1")
+        .await
+        .unwrap();
+    assert_eq!(res.text().await.unwrap(), r#"{"draining":true}"#);
+    for node_rx in [&mut node_rx, &mut node_rx2] {
+        let res = tokio::time::timeout(Duration::from_secs(5), node_rx.next()).await;
+        assert!(
+            matches!(
+                &res,
+                Ok(Some(Ok(ws_client::RecvMessage::Text(text))))
+                    if text == r#"{"code":4003,"reason":"draining"}"#
+            ),
+            "node should have been told that the shard is draining, got {res:?}"
+        );
+        let res = tokio::time::timeout(Duration::from_secs(5), node_rx.next()).await;
+        assert!(
+            matches!(res, Ok(None) | Ok(Some(Err(_)))),
+            "node should have been disconnected"
+        );
+    }
+
+    // Bad delays are rejected, and draining can't be toggled alongside node submissions:
+    let res = drain(reqwest::Method::POST, "?reconnect_after=soon")
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 400);
+    let res = reqwest::get(format!("http://{}/drain", shard.host()))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 404);
+
+    server.shutdown().await;
+}
+
+/// Shards and the core answer liveness probes whenever they're running, but shards only
+/// report that they're ready while they're connected to the core and not draining.
+#[tokio::test]
+async fn e2e_readiness_reflects_the_connection_to_the_core() {
+    let get = |url: String| async move {
+        let res = reqwest::get(url).await.unwrap();
+        (res.status().as_u16(), res.text().await.unwrap())
+    };
+    let free_addr = || {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    };
+
+    // A shard that the core refuses is live, but never ready:
+    let admin_addr = free_addr();
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            shard_secret: Some("s3cret".to_owned()),
+            ..Default::default()
+        },
+        ShardOpts {
+            admin_listen: Some(admin_addr),
+            shard_secret: Some("wrong".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await;
+    server.add_shard().await.unwrap();
+    let core_host = server.get_core().host().to_owned();
+    assert_eq!(get(format!("http://{core_host}/healthz")).await.0, 200);
+    assert_eq!(get(format!("http://{core_host}/readyz")).await.0, 200);
+    assert_eq!(get(format!("http://{admin_addr}/healthz")).await.0, 200);
+    assert_eq!(
+        get(format!("http://{admin_addr}/readyz")).await,
+        (503, "Not connected to the telemetry core".to_owned())
+    );
+    server.shutdown().await;
+
+    // A shard that connects becomes ready, until it starts draining:
+    let admin_addr = free_addr();
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts::default(),
+        ShardOpts {
+            admin_listen: Some(admin_addr),
+            ..Default::default()
+        },
+    )
+    .await;
+    server.add_shard().await.unwrap();
+    let mut ready = (0, String::new());
+    for _ in 0..50 {
+        ready = get(format!("http://{admin_addr}/readyz")).await;
+        if ready.0 == 200 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(ready, (200, "OK".to_owned()));
+
+    let client = reqwest::Client::new();
+    let drain = |method: reqwest::Method| {
+        client
+            .request(method, format!("http://{admin_addr}/drain"))
+            .send()
+    };
+    drain(reqwest::Method::POST).await.unwrap();
+    assert_eq!(
+        get(format!("http://{admin_addr}/readyz")).await,
+        (503, "Draining".to_owned())
+    );
+    drain(reqwest::Method::DELETE).await.unwrap();
+    assert_eq!(get(format!("http://{admin_addr}/readyz")).await.0, 200);
+
+    server.shutdown().await;
+}
+
+/// Feeds will be disconnected if they can't receive messages quickly enough.
+#[tokio::test]
+async fn e2e_slow_feeds_are_disconnected() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        // Timeout faster so the test can be quicker:
+        CoreOpts {
+            feed_timeout: Some(1),
+            ..Default::default()
+        },
+        // Allow us to send more messages in more easily:
+        ShardOpts {
+            max_nodes_per_connection: Some(100_000),
+            // Prevent the shard being being banned when it sends a load of data at once:
+            max_node_data_per_second: Some(100_000_000),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // Give us a shard to talk to:
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    // Add a load of nodes from this shard so there's plenty of data to give to a feed.
+    // We want to exhaust any buffers between core and feed (eg BufWriters). If the number
+    // is too low, data will happily be sent into a buffer and the connection won't need to
+    // be closed.
+    for n in 1..100_000 {
+        node_tx
+            .send_json_text(json!({
+                "id":n,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Polkadot",
+                    "config":"",
+                    "genesis_hash": polkadot_genesis_hash(), // First party node connections aren't limited.
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name": format!("Alice {}", n),
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                }
+            }))
+            .unwrap();
+    }
+
+    // Connect a raw feed so that we can control how fast we consume data from the websocket
+    let (mut raw_feed_tx, mut raw_feed_rx) = server.get_core().connect_feed_raw().await.unwrap();
+
+    // Subscribe the feed:
+    raw_feed_tx
+        .send_text("subscribe:0x0000000000000000000000000000000000000000000000000000000000000001")
+        .await
+        .unwrap();
+
+    // Wait a little.. the feed hasn't been receiving messages so it should
+    // be booted after ~a second.
+    tokio::time::sleep(Duration::from_secs(5)).await;
+
+    // Drain anything out and expect to hit a "closed" error, rather than get stuck
+    // waiting to receive more data (or see some other error).
+    loop {
+        let mut v = Vec::new();
+        let data =
+            tokio::time::timeout(Duration::from_secs(2), raw_feed_rx.receive_data(&mut v)).await;
+
+        match data {
+            Ok(Ok(_)) => {
+                continue; // Drain data
+            }
+            Ok(Err(soketto::connection::Error::Closed)) => {
+                break; // End loop; success!
+            }
+            Ok(Err(_e)) => {
+                // Occasionally we might hit an error here before the channel is marked as closed. The error probably
+                // means that the socket has been killed, but we haven't managed to set the state to closed in time
+                // and so we still hit this. We may be able to tighten this up and avoid this permanently, at which point
+                // this can become a test failure.
+                break;
+            }
+            Err(_) => {
+                panic!("recv should be closed but seems to be happy waiting for more data");
+            }
+        }
+    }
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds whose writes stall for longer than the send timeout are disconnected, even if
+/// the feed timeout for a whole batch of messages is much longer.
+#[tokio::test]
+async fn e2e_feeds_whose_writes_stall_are_disconnected() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            feed_timeout: Some(600),
+            feed_send_timeout: Some(1),
+            ..Default::default()
+        },
+        ShardOpts {
+            max_nodes_per_connection: Some(100_000),
+            max_node_data_per_second: Some(100_000_000),
+            ..Default::default()
+        },
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    // Add enough nodes that a snapshot of the chain won't fit in the socket buffers:
+    let num_nodes = 50_000;
+    for n in 1..=num_nodes {
+        node_tx
+            .send_json_text(json!({
+                "id":n,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Polkadot",
+                    "config":"",
+                    "genesis_hash": polkadot_genesis_hash(),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name": format!("Alice {}", n),
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                }
+            }))
+            .unwrap();
+    }
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    loop {
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        let all_added = feed_messages.iter().any(
+            |m| matches!(m, FeedMessage::AddedChain { node_count, .. } if *node_count == num_nodes),
+        );
+        if all_added {
+            break;
+        }
+    }
+
+    // Subscribe a feed that never reads anything, so that writes to it stall once the
+    // socket buffers fill up:
+    let (mut raw_feed_tx, mut raw_feed_rx) = server.get_core().connect_feed_raw().await.unwrap();
+    raw_feed_tx
+        .send_text(format!("subscribe:{:?}", polkadot_genesis_hash()))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_secs(5)).await;
+
+    // Well before the feed timeout, the feed has been disconnected:
+    loop {
+        let mut v = Vec::new();
+        let data =
+            tokio::time::timeout(Duration::from_secs(2), raw_feed_rx.receive_data(&mut v)).await;
+        match data {
+            Ok(Ok(_)) => continue,
+            Ok(Err(_)) => break,
+            Err(_) => panic!("the feed should have been disconnected"),
+        }
+    }
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds can subscribe to just the authority nodes on a chain, and won't hear
+/// anything about other nodes on that chain.
+#[tokio::test]
+async fn e2e_feed_subscribed_to_authorities_only_sees_authority_nodes() {
+    use FeedMessage::*;
+
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    // Node 1 is an authority, node 2 is not:
+    for (id, authority) in [(1, true), (2, false)] {
+        node_tx
+            .send_json_text(json!({
+                "id":id,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":authority,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": ghash(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":format!("Alice {id}"),
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }))
+            .unwrap();
+    }
+
+    // Connect a feed and wait until it knows about both nodes:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    loop {
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        if feed_messages
+            .iter()
+            .any(|m| matches!(m, AddedChain { node_count: 2, .. }))
+        {
+            break;
+        }
+    }
+
+    feed_tx
+        .send_command(
+            "subscribe-authorities",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+
+    // Only the authority node is included when we subscribe:
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    let added_names: Vec<_> = feed_messages
+        .iter()
+        .filter_map(|m| match m {
+            AddedNode { node, .. } => Some(node.name.clone()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(added_names, vec!["Alice 1".to_string()]);
+
+    // Updates about the non-authority node aren't sent to the feed:
+    node_tx.send_json_text(json!(
+        {"id":2, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:37:48.330433+01:00" }
+    )).unwrap();
+    tokio::time::timeout(Duration::from_secs(1), feed_rx.recv_feed_messages())
+        .await
+        .expect_err("Timeout should elapse since no messages sent");
+
+    // Updates about the authority node are:
+    node_tx.send_json_text(json!(
+        {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:37:48.330433+01:00" }
+    )).unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_ne!(feed_messages.len(), 0);
+
+    // If the authority node leaves the authority set, it's removed from the feed's view:
+    node_tx.send_json_text(json!(
+        {"id":1, "payload":{ "authority_id":"","msg":"afg.authority_set"},"ts":"2021-07-12T10:37:49.330433+01:00" }
+    )).unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        RemovedNodeReason { node_id: 0, reason } if reason == "no-longer-authority",
+        RemovedNode { node_id: 0 }
+    );
+
+    // If the other node joins the authority set, it's added to the feed's view:
+    node_tx.send_json_text(json!(
+        {"id":2, "payload":{ "authority_id":"5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY","msg":"afg.authority_set"},"ts":"2021-07-12T10:37:49.330433+01:00" }
+    )).unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        AddedNode { node_id: 1, node: NodeDetails { name, .. }, .. } if name == "Alice 2"
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// The role that a node connects with is passed on to feeds, falling back to
+/// what the authority flag tells us if the node doesn't give one we recognise.
+#[tokio::test]
+async fn e2e_node_roles_are_sent_to_feeds() {
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    for (id, authority, role) in [
+        (1, false, json!(null)),
+        (2, true, json!(null)),
+        (3, false, json!("light")),
+        (4, true, json!("collator")),
+        (5, false, json!("unheard-of")),
+    ] {
+        node_tx
+            .send_json_text(json!({
+                "id":id,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":authority,
+                    "role":role,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": ghash(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":format!("Alice {id}"),
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }))
+            .unwrap();
+    }
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    loop {
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        if feed_messages
+            .iter()
+            .any(|m| matches!(m, FeedMessage::AddedChain { node_count: 5, .. }))
+        {
+            break;
+        }
+    }
+
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    let roles: Vec<_> = feed_messages
+        .iter()
+        .filter_map(|m| match m {
+            FeedMessage::AddedNode { node, .. } => Some((node.name.as_str(), node.role.as_str())),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        roles,
+        vec![
+            ("Alice 1", "full"),
+            ("Alice 2", "authority"),
+            ("Alice 3", "light"),
+            ("Alice 4", "collator"),
+            ("Alice 5", "full"),
+        ]
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// GRANDPA messages that validators report are passed on to feeds subscribed to their chain,
+/// and any that we can't make sense of are ignored.
+#[tokio::test]
+async fn e2e_afg_messages_are_sent_to_feeds() {
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "validator":"5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    loop {
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        if feed_messages
+            .iter()
+            .any(|m| matches!(m, FeedMessage::AddedChain { node_count: 1, .. }))
+        {
+            break;
+        }
+    }
+    // Finality messages are only sent to feeds that ask for them:
+    feed_tx
+        .send_command(
+            "send-finality",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    let block_hash = ghash(12);
+    for payload in [
+        // These are malformed, and so ignored:
+        json!({ "msg":"afg.finalized", "finalized_number":"12" }),
+        json!({ "msg":"afg.received_prevote", "target_hash":"nope", "target_number":"12" }),
+        json!({ "msg":"afg.received_precommit", "target_hash":block_hash, "target_number":"twelve" }),
+        // These are passed on:
+        json!({ "msg":"afg.finalized", "finalized_hash":block_hash, "finalized_number":"12" }),
+        json!({ "msg":"afg.received_prevote", "target_hash":block_hash, "target_number":"13", "voter":"Bob" }),
+        json!({ "msg":"afg.received_precommit", "target_hash":block_hash, "target_number":"14" }),
+        json!({ "msg":"afg.authority_set", "authority_id":"Alice", "authorities":"[\"Alice\"]", "authority_set_id":"3" }),
+    ] {
+        node_tx
+            .send_json_text(
+                json!({ "id":1, "ts":"2021-07-12T10:37:48.330433+01:00", "payload":payload }),
+            )
+            .unwrap();
+    }
+
+    let mut afg_messages = Vec::new();
+    while afg_messages.len() < 4 {
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        afg_messages.extend(feed_messages.into_iter().filter(|m| {
+            matches!(
+                m,
+                FeedMessage::AfgFinalized { .. }
+                    | FeedMessage::AfgReceivedPrevote { .. }
+                    | FeedMessage::AfgReceivedPrecommit { .. }
+                    | FeedMessage::AfgAuthoritySet { .. }
+            )
+        }));
+    }
+
+    let address = "5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY".to_owned();
+    assert_eq!(
+        afg_messages,
+        vec![
+            FeedMessage::AfgFinalized {
+                address: address.clone(),
+                block_number: 12,
+                block_hash,
+            },
+            FeedMessage::AfgReceivedPrevote {
+                address: address.clone(),
+                block_number: 13,
+                block_hash,
+                voter: Some("Bob".to_owned()),
+            },
+            FeedMessage::AfgReceivedPrecommit {
+                address,
+                block_number: 14,
+                block_hash,
+                voter: None,
+            },
+            FeedMessage::AfgAuthoritySet {
+                a1: "Alice".to_owned(),
+                a2: r#"["Alice"]"#.to_owned(),
+                a3: "3".to_owned(),
+                block_number: 0,
+                block_hash: BlockHash::zero(),
+            },
+        ]
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds subscribing with strict ordering are sent a snapshot of every node in order of node
+/// ID, even if there are enough nodes that serializing them is split across threads, and the
+/// nodes are busy sending updates at the same time.
+#[tokio::test]
+async fn e2e_feed_strict_ordering_preserved_under_concurrent_updates() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts::default(),
+        ShardOpts {
+            max_nodes_per_connection: Some(1000),
+            ..Default::default()
+        },
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    // Add enough nodes that they'll be sent to the feed in several chunks:
+    let num_nodes = 500;
+    for id in 1..=num_nodes {
+        node_tx
+            .send_json_text(json!({
+                "id":id,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Test Chain",
+                    "config":"",
+                    "genesis_hash": ghash(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":format!("Alice {id}"),
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }))
+            .unwrap();
+    }
+
+    // Wait until the feed knows about all of the nodes:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    loop {
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        let all_added = feed_messages.iter().any(
+            |m| matches!(m, FeedMessage::AddedChain { node_count, .. } if *node_count == num_nodes),
+        );
+        if all_added {
+            break;
+        }
+    }
+
+    // Keep the nodes busy sending updates while we subscribe:
+    let updates = tokio::spawn(async move {
+        for _ in 0..5 {
+            for id in 1..=num_nodes {
+                node_tx.send_json_text(json!(
+                    {"id":id, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:37:48.330433+01:00" }
+                )).unwrap();
+            }
+            tokio::task::yield_now().await;
+        }
+        node_tx
+    });
+
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001:strict",
+        )
+        .unwrap();
+
+    // Every node should be added in order of its ID:
+    let mut added_node_ids = Vec::new();
+    while added_node_ids.len() < num_nodes {
+        let feed_messages = feed_rx
+            .recv_feed_messages_once_timeout(Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert!(!feed_messages.is_empty(), "timed out waiting for nodes");
+        for msg in feed_messages {
+            if let FeedMessage::AddedNode { node_id, .. } = msg {
+                added_node_ids.push(node_id);
+            }
+        }
+    }
+    let expected_node_ids: Vec<_> = (0..num_nodes).collect();
+    assert_eq!(added_node_ids, expected_node_ids);
+
+    // Tidy up:
+    let _node_tx = updates.await.unwrap();
+    server.shutdown().await;
+}
+
+/// If something connects to the `/submit` endpoint, there is a limit to the number
+/// of different messags IDs it can send telemetry about, to prevent a malicious actor from
+/// spamming a load of message IDs and exhausting our memory.
+#[tokio::test]
+async fn e2e_max_nodes_per_connection_is_enforced() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts::default(),
+        // Limit max nodes per connection to 2; any other msgs should be ignored.
+        ShardOpts {
+            max_nodes_per_connection: Some(2),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // Connect to a shard
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    // Connect a feed.
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+
+    // We'll send these messages from the node:
+    let json_msg = |n| {
+        json!({
+            "id":n,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Test Chain",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Polkadot",
+                "msg":"system.connected",
+                "name": format!("Alice {}", n),
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            }
+        })
+    };
+
+    // First message ID should lead to feed messages:
+    node_tx.send_json_text(json_msg(1)).unwrap();
+    assert_ne!(
+        feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(1))
+            .await
+            .unwrap()
+            .len(),
+        0
+    );
+
+    // Second message ID should lead to feed messages as well:
+    node_tx.send_json_text(json_msg(2)).unwrap();
+    assert_ne!(
+        feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(1))
+            .await
+            .unwrap()
+            .len(),
+        0
+    );
+
+    // Third message ID should be ignored:
+    node_tx.send_json_text(json_msg(3)).unwrap();
+    assert_eq!(
+        feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(1))
+            .await
+            .unwrap()
+            .len(),
+        0
+    );
+
+    // Forth message ID should be ignored as well:
+    node_tx.send_json_text(json_msg(4)).unwrap();
+    assert_eq!(
+        feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(1))
+            .await
+            .unwrap()
+            .len(),
+        0
+    );
+
+    // (now that the chain "Test Chain" is known about, subscribe to it for update messages.
+    // This wasn't needed to receive messages re the above since everybody hears about node
+    // count changes)
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    // Update about non-ignored IDs should still lead to feed output:
+
+    node_tx.send_json_text(json!(
+        {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:38:48.330433+01:00" }
+    )).unwrap();
+    assert_ne!(
+        feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(1))
+            .await
+            .unwrap()
+            .len(),
+        0
+    );
+
+    node_tx.send_json_text(json!(
+        {"id":2, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:38:48.330433+01:00" }
+    )).unwrap();
+    assert_ne!(
+        feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(1))
+            .await
+            .unwrap()
+            .len(),
+        0
+    );
+
+    // Updates about ignored IDs are still ignored:
+
+    node_tx.send_json_text(json!(
+        {"id":3, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:38:48.330433+01:00" }
+    )).unwrap();
+    assert_eq!(
+        feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(1))
+            .await
+            .unwrap()
+            .len(),
+        0
+    );
+
+    node_tx.send_json_text(json!(
+        {"id":4, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:38:48.330433+01:00" }
+    )).unwrap();
+    assert_eq!(
+        feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(1))
+            .await
+            .unwrap()
+            .len(),
+        0
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Numeric values in `system.interval` messages that we don't otherwise understand are
+/// passed on to feeds as custom metrics.
+#[tokio::test]
+async fn e2e_custom_metrics_are_passed_on_to_feeds() {
+    use FeedMessage::*;
+
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+
+    // Connect a feed and wait until it knows about the node's chain before subscribing to it:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    loop {
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        if feed_messages.iter().any(|m| matches!(m, AddedChain { .. })) {
+            break;
+        }
+    }
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(feed_messages, AddedNode { node_id: 0, .. });
+
+    node_tx.send_json_text(json!(
+        {"id":1, "payload":{ "msg":"system.interval","peers":1,"my_gauge":42.5,"my_label":"ignored" },"ts":"2021-07-12T10:37:48.330433+01:00" }
+    )).unwrap();
+
+    let expected_metrics = std::collections::HashMap::from([("my_gauge".to_owned(), 42.5)]);
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        NodeCustomMetrics { node_id: 0, metrics } if metrics == expected_metrics
+    );
+
+    // Feeds subscribing later are sent the latest metrics along with the node:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        AddedNode { node_id: 0, .. },
+        NodeCustomMetrics { node_id: 0, metrics } if metrics == expected_metrics
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds are sent the peer counts that a node reports over time, including when it reports
+/// having no peers, and feeds subscribing later are sent the series along with the node.
+#[tokio::test]
+async fn e2e_peer_counts_are_sent_to_feeds_as_a_series() {
+    use FeedMessage::*;
+
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+
+    // Connect a feed and wait until it knows about the node's chain before subscribing to it:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    loop {
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        if feed_messages.iter().any(|m| matches!(m, AddedChain { .. })) {
+            break;
+        }
+    }
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(feed_messages, AddedNode { node_id: 0, .. });
+
+    // Each interval adds to the series, even when the count doesn't change:
+    for (peers, expected) in [
+        (0, vec![0.0]),
+        (0, vec![0.0, 0.0]),
+        (3, vec![0.0, 0.0, 3.0]),
+    ] {
+        node_tx.send_json_text(json!(
+            {"id":1, "payload":{ "msg":"system.interval","peers":peers },"ts":"2021-07-12T10:37:48.330433+01:00" }
+        )).unwrap();
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        assert_contains_matches!(
+            feed_messages,
+            NodePeerCount { node_id: 0, peer_counts } if peer_counts == expected
+        );
+    }
+
+    // Feeds subscribing later are sent the series along with the node:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        AddedNode { node_id: 0, .. },
+        NodePeerCount { node_id: 0, peer_counts } if peer_counts == vec![0.0, 0.0, 3.0]
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// If geolocation is disabled, feeds aren't told where nodes are.
+#[tokio::test]
+async fn e2e_nodes_are_not_located_if_geolocation_is_disabled() {
+    use FeedMessage::*;
+
+    async fn feed_messages_about_new_node(disable_geolocation: bool) -> Vec<FeedMessage> {
+        let mut server = start_server(
+            ServerOpts::default(),
+            CoreOpts {
+                disable_geolocation,
+                ..Default::default()
+            },
+            ShardOpts::default(),
+        )
+        .await;
+        let shard_id = server.add_shard().await.unwrap();
+        let mut nodes = server
+            .get_shard(shard_id)
+            .unwrap()
+            .connect_multiple_nodes(2)
+            .await
+            .unwrap();
+        let node_connected = |name: &str| {
+            json!({
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": ghash(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":name,
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            })
+        };
+        nodes[0].0.send_json_text(node_connected("Alice")).unwrap();
+
+        // Wait until the feed knows about the chain before subscribing to it:
+        let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+        loop {
+            let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+            if feed_messages.iter().any(|m| matches!(m, AddedChain { .. })) {
+                break;
+            }
+        }
+        feed_tx
+            .send_command(
+                "subscribe",
+                "0x0000000000000000000000000000000000000000000000000000000000000001",
+            )
+            .unwrap();
+        feed_rx.recv_feed_messages().await.unwrap();
+
+        // Now that we're subscribed, add another node and see what we're told about it:
+        nodes[1].0.send_json_text(node_connected("Bob")).unwrap();
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+
+        server.shutdown().await;
+        feed_messages
+    }
+
+    // Local nodes are located in Berlin by default:
+    let feed_messages = feed_messages_about_new_node(false).await;
+    assert_contains_matches!(
+        feed_messages,
+        AddedNode { node_id: 1, .. },
+        LocatedNode { node_id: 1, city, .. } if city == "Berlin"
+    );
+
+    let feed_messages = feed_messages_about_new_node(true).await;
+    assert_contains_matches!(
+        &feed_messages,
+        AddedNode {
+            node_id: 1,
+            location: None,
+            ..
+        }
+    );
+    assert!(!feed_messages
+        .iter()
+        .any(|m| matches!(m, LocatedNode { .. })));
+}
+
+/// A node that disconnects and reconnects (as identified by its chain and network ID)
+/// gets its old ID back, and keeps what we knew about it.
+#[tokio::test]
+async fn e2e_reconnecting_nodes_keep_their_id_and_history() {
+    use FeedMessage::*;
+
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let system_connected = json!({
+        "id":1,
+        "ts":"2021-07-12T10:37:47.714666+01:00",
+        "payload": {
+            "authority":true,
+            "chain":"Local Testnet",
+            "config":"",
+            "genesis_hash": ghash(1),
+            "implementation":"Substrate Node",
+            "msg":"system.connected",
+            "name":"Alice",
+            "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+            "startup_time":"1625565542717",
+            "version":"2.0.0-07a1af348-aarch64-macos"
+        },
+    });
+
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx.send_json_text(system_connected.clone()).unwrap();
+
+    // Watch the chain, so that we know when things have happened:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    loop {
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        if feed_messages.iter().any(|m| matches!(m, AddedChain { .. })) {
+            break;
+        }
+    }
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    // The node imports a block and then goes away, taking the chain with it:
+    node_tx.send_json_text(json!(
+        {"id":1, "payload":{ "best":BlockHash::from_low_u64_be(5),"height":5,"msg":"block.import" },"ts":"2021-07-12T10:37:48.330433+01:00" }
+    )).unwrap();
+    loop {
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        if feed_messages
+            .iter()
+            .any(|m| matches!(m, ImportedBlock { node_id: 0, .. }))
+        {
+            break;
+        }
+    }
+    node_tx.close().await.unwrap();
+    loop {
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        if feed_messages
+            .iter()
+            .any(|m| matches!(m, RemovedChain { .. }))
+        {
+            break;
+        }
+    }
+
+    // When it comes back, the chain is added again, and the node is
+    // given its old ID and best block:
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx.send_json_text(system_connected).unwrap();
+    let mut feed_messages = Vec::new();
+    while !feed_messages.iter().any(|m| matches!(m, AddedChain { .. })) {
+        feed_messages.extend(feed_rx.recv_feed_messages().await.unwrap());
+    }
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    let mut feed_messages = Vec::new();
+    while !feed_messages.iter().any(|m| matches!(m, AddedNode { .. })) {
+        feed_messages.extend(feed_rx.recv_feed_messages().await.unwrap());
+    }
+    assert_contains_matches!(
+        feed_messages,
+        AddedNode { node_id: 0, block_details, .. } if block_details.block.height == 5
+    );
+
+    server.shutdown().await;
+}
+
+/// If asked to, the core periodically sends a heartbeat to every feed, even if
+/// nothing else is happening.
+#[tokio::test]
+async fn e2e_idle_feeds_are_sent_heartbeats() {
+    let server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            feed_heartbeat_interval: Some(1),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+
+    // No nodes are connected, so heartbeats are all that the feed is sent:
+    let mut timestamps = Vec::new();
+    while timestamps.len() < 2 {
+        let feed_messages = feed_rx
+            .recv_feed_messages_once_timeout(Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(
+            !feed_messages.is_empty(),
+            "timed out waiting for a heartbeat"
+        );
+        timestamps.extend(feed_messages.into_iter().filter_map(|m| match m {
+            FeedMessage::Heartbeat { timestamp } => Some(timestamp),
+            _ => None,
+        }));
+    }
+    assert!(timestamps[0] <= timestamps[1]);
+
+    server.shutdown().await;
+}
+
+/// Heartbeats are off by default.
+#[tokio::test]
+async fn e2e_feeds_are_not_sent_heartbeats_by_default() {
+    let server = start_server_debug().await;
+
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx
+        .recv_feed_messages_timeout(Duration::from_secs(3))
+        .await
+        .unwrap();
+    assert!(!feed_messages
+        .iter()
+        .any(|m| matches!(m, FeedMessage::Heartbeat { .. })));
+
+    server.shutdown().await;
+}
+
+/// Feeds can ask for an older version of the feed protocol when connecting, and aren't
+/// sent messages that were added in later versions.
+#[tokio::test]
+async fn e2e_feeds_asking_for_v1_protocol_are_not_sent_newer_messages() {
+    use FeedMessage::*;
+
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+
+    // Connect a feed speaking the latest protocol and one speaking v1, and wait until they
+    // know about the node's chain before subscribing to it:
+    let core = server.get_core();
+    let mut feeds = vec![
+        core.connect_feed().await.unwrap(),
+        core.connect_feed_with_protocol("telemetry-feed-v1")
+            .await
+            .unwrap(),
+    ];
+    for (feed_tx, feed_rx) in &mut feeds {
+        loop {
+            let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+            if feed_messages.iter().any(|m| matches!(m, AddedChain { .. })) {
+                break;
+            }
+        }
+        feed_tx
+            .send_command(
+                "subscribe",
+                "0x0000000000000000000000000000000000000000000000000000000000000001",
+            )
+            .unwrap();
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        assert_contains_matches!(feed_messages, AddedNode { node_id: 0, .. });
+    }
+
+    // Custom metrics are only a part of v2 of the protocol:
     node_tx.send_json_text(json!(
-        {"id":3, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:38:48.330433+01:00" }
+        {"id":1, "payload":{ "msg":"system.interval","peers":1,"my_gauge":42.5 },"ts":"2021-07-12T10:37:48.330433+01:00" }
     )).unwrap();
-    assert_eq!(
-        feed_rx
-            .recv_feed_messages_timeout(Duration::from_secs(1))
-            .await
-            .unwrap()
-            .len(),
-        0
+
+    let (_, latest_rx) = &mut feeds[0];
+    let feed_messages = latest_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        NodeStatsUpdate { node_id: 0, .. },
+        NodeCustomMetrics { node_id: 0, .. }
     );
 
-    node_tx.send_json_text(json!(
-        {"id":4, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:38:48.330433+01:00" }
+    let (_, v1_rx) = &mut feeds[1];
+    let feed_messages = v1_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(&feed_messages, NodeStatsUpdate { node_id: 0, .. });
+    assert!(!feed_messages
+        .iter()
+        .any(|m| matches!(m, NodeCustomMetrics { .. })));
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds can ask for recent events on a chain to be replayed after the snapshot of it. Only
+/// events about nodes that have since gone are replayed; the snapshot already describes the
+/// nodes that are still connected.
+#[tokio::test]
+async fn e2e_feeds_can_ask_for_recent_events_to_be_replayed() {
+    use FeedMessage::*;
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            event_history_size: Some(10),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let mut nodes = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_multiple_nodes(2)
+        .await
+        .unwrap();
+    let (mut bob_tx, _bob_rx) = nodes.pop().unwrap();
+    let (mut alice_tx, _alice_rx) = nodes.pop().unwrap();
+
+    let system_connected = |name: &str| {
+        json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":name,
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        })
+    };
+    alice_tx.send_json_text(system_connected("Alice")).unwrap();
+
+    // Watch the chain with one feed, so that we know when things have happened:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    loop {
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        if feed_messages.iter().any(|m| matches!(m, AddedChain { .. })) {
+            break;
+        }
+    }
+    // Finality messages are only sent to feeds that ask for them:
+    feed_tx
+        .send_command(
+            "send-finality",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(feed_messages, AddedNode { node_id: 0, .. });
+
+    bob_tx.send_json_text(system_connected("Bob")).unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(feed_messages, AddedNode { node_id: 1, .. });
+
+    // Alice imports and finalizes some blocks, and then goes away:
+    for height in [1, 2] {
+        alice_tx.send_json_text(json!(
+            {"id":1, "payload":{ "best":BlockHash::from_low_u64_be(height),"height":height,"msg":"block.import" },"ts":"2021-07-12T10:37:48.330433+01:00" }
+        )).unwrap();
+    }
+    alice_tx.send_json_text(json!(
+        {"id":1, "payload":{ "best":BlockHash::from_low_u64_be(1),"height":"1","msg":"notify.finalized" },"ts":"2021-07-12T10:37:49.330433+01:00" }
     )).unwrap();
+    loop {
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        if feed_messages.iter().any(|m| {
+            matches!(
+                m,
+                FinalizedBlock {
+                    node_id: 0,
+                    block_number: 1,
+                    ..
+                }
+            )
+        }) {
+            break;
+        }
+    }
+    alice_tx.close().await.unwrap();
+    loop {
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        if feed_messages.contains(&RemovedNode { node_id: 0 }) {
+            break;
+        }
+    }
+
+    // A feed subscribing now and asking for a replay is told what Alice got up to:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+    feed_tx
+        .send_command(
+            "send-finality",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001:replay=10",
+        )
+        .unwrap();
+    let mut feed_messages = Vec::new();
+    while !feed_messages.contains(&RemovedNode { node_id: 0 }) {
+        feed_messages.extend(feed_rx.recv_feed_messages().await.unwrap());
+    }
+
+    assert_contains_matches!(
+        &feed_messages,
+        AddedNode { node_id: 1, .. },
+        AddedNode { node_id: 0, .. },
+        ImportedBlock { node_id: 0, block_details } if block_details.block.height == 1,
+        ImportedBlock { node_id: 0, block_details } if block_details.block.height == 2,
+        FinalizedBlock { node_id: 0, block_number: 1, .. },
+        RemovedNode { node_id: 0 }
+    );
+    // Bob is only described by the snapshot:
+    let bob_messages = feed_messages
+        .iter()
+        .filter(|m| matches!(m, AddedNode { node_id: 1, .. }))
+        .count();
+    assert_eq!(bob_messages, 1);
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Node messages can be sampled while the core is running and then fetched, and the level
+/// that the core logs at can be changed, using the admin endpoints.
+#[tokio::test]
+async fn e2e_admin_can_sample_node_messages_and_change_the_log_level() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            admin_token: Some("s3cret".to_owned()),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let host = server.get_core().host().to_owned();
+    let url = |path: &str| format!("http://{host}{path}");
+
+    let res = client.post(url("/admin/sampling")).send().await.unwrap();
+    assert_eq!(res.status(), 401);
+    let res = client
+        .post(url("/admin/sampling?every=1&capacity=10"))
+        .bearer_auth("s3cret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+
+    // Connect a node and have it send a couple of messages:
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "payload":{
+                "bandwidth_download":576,
+                "bandwidth_upload":576,
+                "msg":"system.interval",
+                "peers":1
+            },
+            "ts":"2021-07-12T10:37:48.330433+01:00"
+        }))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // The interval message was sampled. Connecting adds a node rather than updating one,
+    // so that message isn't:
+    let samples: serde_json::Value = client
+        .get(url("/admin/samples"))
+        .bearer_auth("s3cret")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let samples = samples.as_array().unwrap();
+    assert_eq!(samples.len(), 1, "{samples:?}");
+    assert_eq!(samples[0]["payload"]["SystemInterval"]["peers"], json!(1));
+
+    // Nothing more is sampled once sampling is disabled, but what was is kept:
+    let status: serde_json::Value = client
+        .delete(url("/admin/sampling"))
+        .bearer_auth("s3cret")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(status["enabled"], json!(false));
+    assert_eq!(status["captured"], json!(1));
+
+    // The log level can be changed, and levels that don't make sense are refused:
+    let res = client
+        .put(url("/admin/log_level"))
+        .bearer_auth("s3cret")
+        .body("telemetry_core::aggregator=debug")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    let level = client
+        .get(url("/admin/log_level"))
+        .bearer_auth("s3cret")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(
+        level.starts_with("telemetry_core::aggregator=debug,"),
+        "{level}"
+    );
+    let res = client
+        .put(url("/admin/log_level"))
+        .bearer_auth("s3cret")
+        .body("telemetry_core=loud")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 400);
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// The admin snapshot endpoint streams every chain, followed by each of its nodes, as lines
+/// of JSON.
+#[tokio::test]
+async fn e2e_admin_snapshot_includes_every_chain_and_node() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            admin_token: Some("s3cret".to_owned()),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let host = server.get_core().host().to_owned();
+    let url = |path: &str| format!("http://{host}{path}");
+
+    let res = client.get(url("/admin/snapshot")).send().await.unwrap();
+    assert_eq!(res.status(), 401);
+
+    // Three nodes on the first chain, and one on each of two others:
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+    for (id, chain) in [(1, 1), (2, 1), (3, 1), (4, 2), (5, 3)] {
+        node_tx
+            .send_json_text(json!({
+                "id":id,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":format!("Local Testnet {chain}"),
+                    "config":"",
+                    "genesis_hash": ghash(chain),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":format!("Node {id}"),
+                    "network_id":format!("12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp{id}"),
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }))
+            .unwrap();
+    }
+    node_tx
+        .send_json_text(json!({
+            "id":4,
+            "payload":{
+                "bandwidth_download":576,
+                "bandwidth_upload":576,
+                "msg":"system.interval",
+                "peers":7
+            },
+            "ts":"2021-07-12T10:37:48.330433+01:00"
+        }))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let res = client
+        .get(url("/admin/snapshot"))
+        .bearer_auth("s3cret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
     assert_eq!(
-        feed_rx
-            .recv_feed_messages_timeout(Duration::from_secs(1))
-            .await
-            .unwrap()
-            .len(),
-        0
+        res.headers()[reqwest::header::CONTENT_TYPE],
+        "application/x-ndjson"
     );
+    let body = res.text().await.unwrap();
+    let lines: Vec<serde_json::Value> = body
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+
+    // Each chain is followed by its nodes:
+    let mut chains = std::collections::BTreeMap::new();
+    let mut current_chain = None;
+    for line in &lines {
+        if let Some(chain) = line.get("chain") {
+            let genesis_hash = chain["genesis_hash"].as_str().unwrap().to_owned();
+            chains.insert(
+                genesis_hash.clone(),
+                (chain["node_count"].as_u64().unwrap(), Vec::new()),
+            );
+            current_chain = Some(genesis_hash);
+        } else {
+            let node = &line["node"];
+            let genesis_hash = current_chain.as_ref().expect("nodes follow their chain");
+            assert_eq!(node["genesis_hash"].as_str().unwrap(), genesis_hash);
+            chains.get_mut(genesis_hash).unwrap().1.push(node.clone());
+        }
+    }
+
+    let node_names = |genesis_hash: BlockHash| {
+        let (node_count, nodes) = &chains[&format!("{genesis_hash:#x}")];
+        let mut names: Vec<_> = nodes
+            .iter()
+            .map(|n| n["details"]["name"].as_str().unwrap().to_owned())
+            .collect();
+        names.sort();
+        assert_eq!(*node_count as usize, names.len());
+        names
+    };
+    assert_eq!(chains.len(), 3);
+    assert_eq!(node_names(ghash(1)), ["Node 1", "Node 2", "Node 3"]);
+    assert_eq!(node_names(ghash(2)), ["Node 4"]);
+    assert_eq!(node_names(ghash(3)), ["Node 5"]);
+
+    // Nodes come with their current stats:
+    let (_, nodes) = &chains[&format!("{:#x}", ghash(2))];
+    assert_eq!(nodes[0]["stats"][0], json!(7));
 
     // Tidy up:
     server.shutdown().await;
@@ -34,6 +34,12 @@ In general, if you run into issues, it may be better to run this on a linux
 box; MacOS seems to hit limits quicker in general.
 */
 
+#![allow(
+    clippy::needless_update,
+    clippy::redundant_static_lifetimes,
+    clippy::useless_conversion
+)]
+
 use common::node_types::BlockHash;
 use common::ws_client::SentMessage;
 use futures::{future, StreamExt};
@@ -55,6 +61,48 @@ use test_utils::workspace::{start_server, CoreOpts, ServerOpts, ShardOpts};
 /// TELEMETRY_BIN=~/old_telemetry_binary SOAK_TEST_ARGS='--feeds 100 --nodes 100 --shards 4' cargo test --release -- soak_test --ignored --nocapture
 /// ```
 ///
+/// To see how batching up messages to feeds affects the number of frames sent out to them, compare
+/// the "msgs out" reported when running with different flush intervals (in milliseconds):
+/// ```sh
+/// SOAK_TEST_ARGS='--feeds 10 --nodes 100 --shards 4 --core-feed-flush-interval 0' cargo test --release -- soak_test --ignored --nocapture
+/// SOAK_TEST_ARGS='--feeds 10 --nodes 100 --shards 4 --core-feed-flush-interval 250' cargo test --release -- soak_test --ignored --nocapture
+/// ```
+///
+/// Similarly, the capacities of the channels between nodes, shards, the core and feeds can be swept
+/// to see how they affect throughput under load:
+/// ```sh
+/// SOAK_TEST_ARGS='--feeds 10 --nodes 100 --shards 4 --shard-aggregator-channel-capacity 100 --shard-core-channel-capacity 100' cargo test --release -- soak_test --ignored --nocapture
+/// SOAK_TEST_ARGS='--feeds 10 --nodes 100 --shards 4 --core-feed-channel-capacity 10000' cargo test --release -- soak_test --ignored --nocapture
+/// ```
+///
+/// To see how much limiting the rate of node stats updates sent to feeds reduces the "bytes out",
+/// compare runs with and without a minimum interval (in milliseconds) between them:
+/// ```sh
+/// SOAK_TEST_ARGS='--feeds 10 --nodes 100 --shards 4' cargo test --release -- soak_test --ignored --nocapture
+/// SOAK_TEST_ARGS='--feeds 10 --nodes 100 --shards 4 --core-node-update-min-interval 15000' cargo test --release -- soak_test --ignored --nocapture
+/// ```
+///
+/// To see how much asking for lean node messages reduces the "bytes out", compare runs where
+/// feeds subscribe with and without `lean=1`:
+/// ```sh
+/// SOAK_TEST_ARGS='--feeds 10 --nodes 100 --shards 4' cargo test --release -- soak_test --ignored --nocapture
+/// SOAK_TEST_ARGS='--feeds 10 --nodes 100 --shards 4 --lean-feeds' cargo test --release -- soak_test --ignored --nocapture
+/// ```
+///
+/// Feeds that ask for the same events and lean-ness share the bytes sent out to them. To see
+/// what serving several formats at once costs, compare the core's CPU usage (which is reported
+/// on Linux) when feeds all ask for the same thing and when they cycle through a mix of formats:
+/// ```sh
+/// SOAK_TEST_ARGS='--feeds 100 --nodes 100 --shards 4' cargo test --release -- soak_test --ignored --nocapture
+/// SOAK_TEST_ARGS='--feeds 100 --nodes 100 --shards 4 --mixed-feeds' cargo test --release -- soak_test --ignored --nocapture
+/// ```
+///
+/// The RUST_LOG environment variable is passed on to the shard and core processes that are
+/// started, so their log levels can be set per module while debugging:
+/// ```sh
+/// RUST_LOG='telemetry_core::aggregator=debug,info' SOAK_TEST_ARGS='--feeds 10 --nodes 100 --shards 4' cargo test --release -- soak_test --ignored --nocapture
+/// ```
+///
 /// Or, you can run it against existing processes on the network with something like this:
 /// ```sh
 /// TELEMETRY_SUBMIT_HOSTS='127.0.0.1:8001' TELEMETRY_FEED_HOST='127.0.0.1:8000' SOAK_TEST_ARGS='--feeds 100 --nodes 100 --shards 4' cargo test --release -- soak_test --ignored --nocapture
@@ -74,6 +122,85 @@ pub fn soak_test() {
         .block_on(run_soak_test(opts));
 }
 
+/// Open a storm of connections to a shard, far faster than it's allowed to handle them, and
+/// count how many are upgraded to websockets, how many are told to try again later, and how
+/// many are left hanging (or are reset) with no answer. Each connection waits a moment before
+/// sending its upgrade request, as a slow client would, so accepted connections pile up:
+/// ```sh
+/// SOAK_TEST_ARGS='--shards 1 --feeds 0 --nodes 0 --storm-connections 5000 --shard-max-accepts-per-sec 500' cargo test --release -- connection_storm_soak_test --ignored --nocapture
+/// SOAK_TEST_ARGS='--shards 1 --feeds 0 --nodes 0 --storm-connections 5000 --shard-max-accepts-per-sec 500 --shard-max-pending-connections 100 --shard-listen-backlog 128' cargo test --release -- connection_storm_soak_test --ignored --nocapture
+/// ```
+#[ignore]
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+pub async fn connection_storm_soak_test() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let opts = get_soak_test_opts();
+    let mut server = start_server(
+        ServerOpts {
+            release_mode: true,
+            log_output: opts.log_output,
+        },
+        CoreOpts::default(),
+        ShardOpts {
+            max_conns_per_ip: Some(usize::MAX),
+            max_accepts_per_sec: opts.shard_max_accepts_per_sec,
+            listen_backlog: opts.shard_listen_backlog,
+            max_pending_connections: opts.shard_max_pending_connections,
+            ..Default::default()
+        },
+    )
+    .await;
+    let shard_id = server.add_shard().await.expect("shard can't be added");
+    let shard_host = server.get_shard(shard_id).unwrap().host().to_owned();
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    enum Outcome {
+        Upgraded,
+        TryLater,
+        Hung,
+        Reset,
+    }
+
+    let connections = (0..opts.storm_connections).map(|_| {
+        let shard_host = shard_host.clone();
+        async move {
+            let start = std::time::Instant::now();
+            let attempt = async {
+                let mut stream = tokio::net::TcpStream::connect(&shard_host).await?;
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                let req = format!(
+                    "GET /submit HTTP/1.1\r\nHost: {shard_host}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+                );
+                stream.write_all(req.as_bytes()).await?;
+                let mut res = [0; 12];
+                stream.read_exact(&mut res).await?;
+                Ok::<_, std::io::Error>(res)
+            };
+            let outcome = match tokio::time::timeout(Duration::from_secs(10), attempt).await {
+                Ok(Ok(res)) if res.ends_with(b"101") => Outcome::Upgraded,
+                Ok(Ok(res)) if res.ends_with(b"503") => Outcome::TryLater,
+                Ok(_) => Outcome::Reset,
+                Err(_) => Outcome::Hung,
+            };
+            (outcome, start.elapsed())
+        }
+    });
+    let results = future::join_all(connections).await;
+
+    let mut counts = std::collections::HashMap::<Outcome, (usize, Duration)>::new();
+    for (outcome, took) in results {
+        let entry = counts.entry(outcome).or_default();
+        entry.0 += 1;
+        entry.1 = entry.1.max(took);
+    }
+    for (outcome, (count, slowest)) in counts {
+        println!("{outcome:?}: {count} connection(s), slowest answered after {slowest:?}");
+    }
+
+    server.shutdown().await;
+}
+
 /// A general soak test runner.
 /// This test sends realistic messages from connected nodes
 /// so that we can see how things react under more normal
@@ -88,10 +215,17 @@ async fn run_soak_test(opts: SoakTestOpts) {
         CoreOpts {
             worker_threads: opts.core_worker_threads,
             num_aggregators: opts.core_num_aggregators,
+            feed_flush_interval: opts.core_feed_flush_interval,
+            feed_channel_capacity: opts.core_feed_channel_capacity,
+            node_update_min_interval: opts.core_node_update_min_interval,
             ..Default::default()
         },
         ShardOpts {
             worker_threads: opts.shard_worker_threads,
+            // All of our nodes connect from the same address:
+            max_conns_per_ip: Some(usize::MAX),
+            aggregator_channel_capacity: opts.shard_aggregator_channel_capacity,
+            core_channel_capacity: opts.shard_core_channel_capacity,
             ..Default::default()
         },
     )
@@ -161,6 +295,9 @@ async fn run_soak_test(opts: SoakTestOpts) {
         }
     }
 
+    // Give the nodes a moment to connect, so that the chain that feeds subscribe to exists:
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
     // Connect feeds to the core:
     let mut feeds = server
         .get_core()
@@ -169,10 +306,19 @@ async fn run_soak_test(opts: SoakTestOpts) {
         .expect("feed connections failed");
 
     // Every feed subscribes to the first chain we have started up. We ignore the rest.
-    for (feed_tx, _) in &mut feeds {
-        feed_tx
-            .send_command("subscribe", &first_genesis_hash_string)
-            .unwrap();
+    let subscription = match opts.lean_feeds {
+        true => format!("{first_genesis_hash_string}:lean=1"),
+        false => first_genesis_hash_string.clone(),
+    };
+    for (idx, (feed_tx, _)) in feeds.iter_mut().enumerate() {
+        let subscription = match opts.mixed_feeds {
+            true => format!(
+                "{first_genesis_hash_string}{}",
+                MIXED_FEED_OPTIONS[idx % MIXED_FEED_OPTIONS.len()]
+            ),
+            false => subscription.clone(),
+        };
+        feed_tx.send_command("subscribe", &subscription).unwrap();
     }
 
     // Also start receiving messages, counting the bytes received so far.
@@ -193,33 +339,44 @@ async fn run_soak_test(opts: SoakTestOpts) {
     }
 
     // Periodically report on bytes out
+    let core_pid = server.get_core().pid();
     tokio::task::spawn(async move {
         let one_mb = 1024.0 * 1024.0;
         let mut last_bytes_in = 0;
         let mut last_bytes_out = 0;
         let mut last_msgs_out = 0;
+        let mut last_core_cpu_ticks = core_pid.and_then(cpu_ticks);
         let mut n = 1;
         loop {
             tokio::time::sleep(Duration::from_secs(1)).await;
             let bytes_in_val = bytes_in.load(Ordering::Relaxed);
             let bytes_out_val = bytes_out.load(Ordering::Relaxed);
             let msgs_out_val = msgs_out.load(Ordering::Relaxed);
+            let core_cpu_ticks = core_pid.and_then(cpu_ticks);
+
+            // Ticks are 1/100th of a second, so ticks per second are a percentage of one CPU:
+            let core_cpu = match (last_core_cpu_ticks, core_cpu_ticks) {
+                (Some(last), Some(now)) => format!("{}%", now - last),
+                _ => "unknown".to_owned(),
+            };
 
             println!(
-                "#{}: MB in/out per measurement: {:.4} / {:.4}, total bytes in/out: {} / {}, msgs out: {}, total msgs out: {})",
+                "#{}: MB in/out per measurement: {:.4} / {:.4}, total bytes in/out: {} / {}, msgs out: {}, total msgs out: {}, core CPU: {})",
                 n,
                 (bytes_in_val - last_bytes_in) as f64 / one_mb,
                 (bytes_out_val - last_bytes_out) as f64 / one_mb,
                 bytes_in_val,
                 bytes_out_val,
                 (msgs_out_val - last_msgs_out),
-                msgs_out_val
+                msgs_out_val,
+                core_cpu
             );
 
             n += 1;
             last_bytes_in = bytes_in_val;
             last_bytes_out = bytes_out_val;
             last_msgs_out = msgs_out_val;
+            last_core_cpu_ticks = core_cpu_ticks;
         }
     });
 
@@ -227,6 +384,26 @@ async fn run_soak_test(opts: SoakTestOpts) {
     future::pending().await
 }
 
+/// With `--mixed-feeds`, feeds take turns to subscribe with each of these options.
+const MIXED_FEED_OPTIONS: [&str; 4] = [
+    "",
+    ":lean=1",
+    ":events=block,finalized",
+    ":lean=1:events=node,stats",
+];
+
+/// How much CPU time, in ticks of 1/100th of a second, has the process with the given ID used
+/// so far? This is only known on Linux, where it's read from "/proc".
+fn cpu_ticks(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The process name is in parens and may contain spaces, so skip past it. The user and
+    // system CPU times are then the 12th and 13th fields.
+    let mut fields = stat.rsplit_once(')')?.1.split_whitespace().skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some(utime + stime)
+}
+
 /// Return an iterator of `total` unique chain names.
 fn chain_names(total: usize) -> impl Iterator<Item = String> {
     static CHAIN_STARTS: [&'static str; 5] = ["Polkadot", "Kusama", "Khala", "Wibble", "Moonbase"];
@@ -290,15 +467,48 @@ struct SoakTestOpts {
     /// Number of worker threads the core will use
     #[structopt(long)]
     core_worker_threads: Option<usize>,
+    /// How long, in milliseconds, the core waits to batch up messages before sending them to feeds
+    #[structopt(long)]
+    core_feed_flush_interval: Option<u64>,
+    /// How many messages can be waiting to be sent to a feed before the core disconnects it
+    #[structopt(long)]
+    core_feed_channel_capacity: Option<usize>,
+    /// How long, in milliseconds, the core waits between sending feeds each node's stats updates
+    #[structopt(long)]
+    core_node_update_min_interval: Option<u64>,
     /// Number of worker threads each shard will use
     #[structopt(long)]
     shard_worker_threads: Option<usize>,
+    /// How many messages from nodes can be queued up for each shard's aggregator
+    #[structopt(long)]
+    shard_aggregator_channel_capacity: Option<usize>,
+    /// How many messages can be queued up to be sent from each shard to the core
+    #[structopt(long)]
+    shard_core_channel_capacity: Option<usize>,
+    /// Should feeds ask for lean node messages when they subscribe?
+    #[structopt(long)]
+    lean_feeds: bool,
+    /// Should feeds subscribe asking for a mix of events and lean-ness? This overrides '--lean-feeds'.
+    #[structopt(long)]
+    mixed_feeds: bool,
     /// Should we log output from the core/shards to stdout?
     #[structopt(long)]
     log_output: bool,
     /// How many worker threads should the soak test runner use?
     #[structopt(long, default_value = "4")]
     test_worker_threads: usize,
+    /// How many connections 'connection_storm_soak_test' opens at once
+    #[structopt(long, default_value = "1000")]
+    storm_connections: usize,
+    /// How many node connections each shard accepts per second
+    #[structopt(long)]
+    shard_max_accepts_per_sec: Option<u32>,
+    /// How many node connections the OS can queue up for each shard to accept
+    #[structopt(long)]
+    shard_listen_backlog: Option<u32>,
+    /// How many accepted node connections each shard can have waiting to be upgraded at once
+    #[structopt(long)]
+    shard_max_pending_connections: Option<usize>,
 }
 
 /// Get soak test args from an envvar and parse them via structopt.
@@ -14,17 +14,20 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::connection::{create_ws_connection_to_core, Message};
+use crate::connection::{create_ws_connection_to_core, Message, OnProtocolMismatch};
+use crate::metrics::Metrics;
+use crate::rejection::Rejection;
 use common::{
-    internal_messages::{self, ShardNodeId},
+    internal_messages::{self, RemovalReason, ShardNodeId},
     node_message,
-    node_types::BlockHash,
+    node_types::{BlockHash, Timestamp},
     AssignId,
 };
 use futures::{Sink, SinkExt};
 use std::collections::{HashMap, HashSet};
-use std::sync::atomic::AtomicU64;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 /// A unique Id is assigned per websocket connection (or more accurately,
 /// per thing-that-subscribes-to-the-aggregator). That connection might send
@@ -37,6 +40,7 @@ type ConnId = u64;
 /// external messages are via subscriptions that take
 /// [`FromWebsocket`] instances.
 #[derive(Clone, Debug)]
+#[allow(clippy::large_enum_variant)]
 enum ToAggregator {
     /// Sent when the telemetry core is disconnected.
     DisconnectedFromTelemetryCore,
@@ -46,6 +50,12 @@ enum ToAggregator {
     FromWebsocket(ConnId, FromWebsocket),
     /// Send when a message comes in from the telemetry core.
     FromTelemetryCore(internal_messages::FromTelemetryCore),
+    /// Start or stop draining. When we start, the nodes connected to us are optionally
+    /// disconnected after `reconnect_after`, so that they reconnect to another shard.
+    SetDraining {
+        draining: bool,
+        reconnect_after: Option<Duration>,
+    },
 }
 
 /// An incoming socket connection can provide these messages.
@@ -58,8 +68,9 @@ pub enum FromWebsocket {
         /// When a message is sent back up this channel, we terminate
         /// the websocket connection and force the node to reconnect
         /// so that it sends its system info again incase the telemetry
-        /// core has restarted.
-        close_connection: flume::Sender<()>,
+        /// core has restarted. If a rejection is given, the node is told
+        /// why it was disconnected.
+        close_connection: flume::Sender<Option<Rejection>>,
     },
     /// Tell the aggregator about a new node.
     Add {
@@ -67,18 +78,30 @@ pub enum FromWebsocket {
         ip: std::net::IpAddr,
         node: common::node_types::NodeDetails,
         genesis_hash: BlockHash,
+        /// The token that the node connected with, if it gave one.
+        token: Option<Box<str>>,
     },
     /// Update/pass through details about a node.
     Update {
         message_id: node_message::NodeMessageId,
         payload: node_message::Payload,
+        /// When the message was received, if it's been picked to have its latency measured.
+        received_at: Option<std::time::Instant>,
+        /// When the node says that it sent the message, if it said.
+        sent_at: Option<Timestamp>,
+    },
+    /// A node's clock has started (`Some(skew_ms)`) or stopped (`None`) disagreeing
+    /// with ours by more than we allow.
+    ClockSkew {
+        message_id: node_message::NodeMessageId,
+        skew_ms: Option<i64>,
     },
-    /// remove a node with the given message ID
+    /// remove a node with the given message ID, which has stopped sending messages
     Remove {
         message_id: node_message::NodeMessageId,
     },
-    /// Make a note when the node disconnects.
-    Disconnected,
+    /// Make a note when the node disconnects, and why.
+    Disconnected { reason: RemovalReason },
 }
 
 pub type FromAggregator = internal_messages::FromShardAggregator;
@@ -98,27 +121,63 @@ struct AggregatorInternal {
     /// stored here so that anybody holding an `Aggregator` handle can
     /// make use of it.
     tx_to_aggregator: flume::Sender<ToAggregator>,
+    /// Are we draining? New node connections are refused while we are, and this is
+    /// checked for each of them, so it's kept here rather than in the aggregator loop.
+    draining: AtomicBool,
+    /// Are we connected to the telemetry core? We aren't ready to take nodes until we are.
+    connected_to_core: Arc<AtomicBool>,
 }
 
 impl Aggregator {
-    /// Spawn a new Aggregator. This connects to the telemetry backend
-    pub async fn spawn(telemetry_uri: http::Uri) -> anyhow::Result<Aggregator> {
-        let (tx_to_aggregator, rx_from_external) = flume::bounded(10);
+    /// Spawn a new Aggregator. This connects to the telemetry backend. Node connections
+    /// wait for room to send messages when more than `channel_capacity` are queued up for
+    /// the aggregator, and the aggregator does the same when more than `core_channel_capacity`
+    /// are queued up to be sent to the telemetry backend.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn spawn(
+        telemetry_uri: http::Uri,
+        on_protocol_mismatch: OnProtocolMismatch,
+        shard_id: Option<Box<str>>,
+        channel_capacity: usize,
+        core_channel_capacity: usize,
+        tcp_keepalive: Option<Duration>,
+        shard_secret: Option<Box<str>>,
+        metrics: Metrics,
+    ) -> anyhow::Result<Aggregator> {
+        let (tx_to_aggregator, rx_from_external) = flume::bounded(channel_capacity);
 
         // Establish a resilient connection to the core (this retries as needed):
-        let (tx_to_telemetry_core, rx_from_telemetry_core) =
-            create_ws_connection_to_core(telemetry_uri).await;
+        let (tx_to_telemetry_core, rx_from_telemetry_core) = create_ws_connection_to_core(
+            telemetry_uri,
+            on_protocol_mismatch,
+            core_channel_capacity,
+            tcp_keepalive,
+            shard_secret,
+        )
+        .await;
 
         // Forward messages from the telemetry core into the aggregator:
         let tx_to_aggregator2 = tx_to_aggregator.clone();
+        let connected_to_core = Arc::new(AtomicBool::new(false));
+        let connected_to_core2 = Arc::clone(&connected_to_core);
         tokio::spawn(async move {
             while let Ok(msg) = rx_from_telemetry_core.recv_async().await {
                 let msg_to_aggregator = match msg {
-                    Message::Connected => ToAggregator::ConnectedToTelemetryCore,
-                    Message::Disconnected => ToAggregator::DisconnectedFromTelemetryCore,
+                    Message::Connected => {
+                        connected_to_core2.store(true, Ordering::Relaxed);
+                        ToAggregator::ConnectedToTelemetryCore
+                    }
+                    Message::Disconnected => {
+                        connected_to_core2.store(false, Ordering::Relaxed);
+                        ToAggregator::DisconnectedFromTelemetryCore
+                    }
                     Message::Data(data) => ToAggregator::FromTelemetryCore(data),
                 };
-                if let Err(_) = tx_to_aggregator2.send_async(msg_to_aggregator).await {
+                if tx_to_aggregator2
+                    .send_async(msg_to_aggregator)
+                    .await
+                    .is_err()
+                {
                     // This will close the ws channels, which themselves log messages.
                     break;
                 }
@@ -129,12 +188,16 @@ impl Aggregator {
         tokio::spawn(Aggregator::handle_messages(
             rx_from_external,
             tx_to_telemetry_core,
+            shard_id,
+            metrics,
         ));
 
         // Return a handle to our aggregator so that we can send in messages to it:
         Ok(Aggregator(Arc::new(AggregatorInternal {
             conn_id: AtomicU64::new(1),
             tx_to_aggregator,
+            draining: AtomicBool::new(false),
+            connected_to_core,
         })))
     }
 
@@ -144,6 +207,8 @@ impl Aggregator {
     async fn handle_messages(
         rx_from_external: flume::Receiver<ToAggregator>,
         tx_to_telemetry_core: flume::Sender<FromAggregator>,
+        shard_id: Option<Box<str>>,
+        metrics: Metrics,
     ) {
         use internal_messages::{FromShardAggregator, FromTelemetryCore};
 
@@ -151,9 +216,10 @@ impl Aggregator {
         // or not, and ignore incoming messages while we aren't.
         let mut connected_to_telemetry_core = false;
 
-        // A list of close channels for the currently connected substrate nodes. Send an empty
-        // tuple to these to ask the connections to be closed.
-        let mut close_connections: HashMap<ConnId, flume::Sender<()>> = HashMap::new();
+        // A list of close channels for the currently connected substrate nodes. Send to these
+        // to ask the connections to be closed, along with the reason to give to the node, if any.
+        let mut close_connections: HashMap<ConnId, flume::Sender<Option<Rejection>>> =
+            HashMap::new();
 
         // Maintain mappings from the connection ID and node message ID to the "local ID" which we
         // broadcast to the telemetry core.
@@ -162,6 +228,11 @@ impl Aggregator {
         // Any messages coming from nodes that have been muted are ignored:
         let mut muted: HashSet<ShardNodeId> = HashSet::new();
 
+        // Are we draining, and if so, is there a task waiting to disconnect the nodes
+        // that were connected when we started?
+        let mut draining = false;
+        let mut disconnect_drained_nodes: Option<tokio::task::JoinHandle<()>> = None;
+
         // Now, loop and receive messages to handle.
         while let Ok(msg) = rx_from_external.recv_async().await {
             match msg {
@@ -171,7 +242,7 @@ impl Aggregator {
 
                     for (_, closer) in closers {
                         // if this fails, it probably means the connection has died already anyway.
-                        let _ = closer.send_async(()).await;
+                        let _ = closer.send_async(None).await;
                     }
 
                     // We've told everything to disconnect. Now, reset our state:
@@ -181,6 +252,12 @@ impl Aggregator {
 
                     connected_to_telemetry_core = true;
                     log::info!("Connected to telemetry core");
+
+                    if draining {
+                        let _ = tx_to_telemetry_core
+                            .send_async(FromShardAggregator::Draining { draining })
+                            .await;
+                    }
                 }
                 ToAggregator::DisconnectedFromTelemetryCore => {
                     connected_to_telemetry_core = false;
@@ -202,6 +279,7 @@ impl Aggregator {
                         ip,
                         node,
                         genesis_hash,
+                        token,
                     },
                 ) => {
                     // Don't bother doing anything else if we're disconnected, since we'll force the
@@ -220,6 +298,8 @@ impl Aggregator {
                             node,
                             genesis_hash,
                             local_id,
+                            shard_id: shard_id.clone(),
+                            token,
                         })
                         .await;
                 }
@@ -228,6 +308,8 @@ impl Aggregator {
                     FromWebsocket::Update {
                         message_id,
                         payload,
+                        received_at,
+                        sent_at,
                     },
                 ) => {
                     // Ignore incoming messages if we're not connected to the backend:
@@ -248,7 +330,37 @@ impl Aggregator {
 
                     // Send the message to the telemetry core with this local ID:
                     let _ = tx_to_telemetry_core
-                        .send_async(FromShardAggregator::UpdateNode { local_id, payload })
+                        .send_async(FromShardAggregator::UpdateNode {
+                            local_id,
+                            payload,
+                            sent_at,
+                        })
+                        .await;
+                    if let Some(received_at) = received_at {
+                        metrics.record_forward_latency(received_at);
+                    }
+                }
+                ToAggregator::FromWebsocket(
+                    conn_id,
+                    FromWebsocket::ClockSkew {
+                        message_id,
+                        skew_ms,
+                    },
+                ) => {
+                    // Ignore this if we're not connected to the backend, or the node is unknown or muted:
+                    if !connected_to_telemetry_core {
+                        continue;
+                    }
+                    let local_id = match to_local_id.get_id(&(conn_id, message_id)) {
+                        Some(id) => id,
+                        None => continue,
+                    };
+                    if muted.contains(&local_id) {
+                        continue;
+                    }
+
+                    let _ = tx_to_telemetry_core
+                        .send_async(FromShardAggregator::ClockSkew { local_id, skew_ms })
                         .await;
                 }
                 ToAggregator::FromWebsocket(conn_id, FromWebsocket::Remove { message_id }) => {
@@ -266,11 +378,17 @@ impl Aggregator {
                     // all nodes associated with this shard anyway, so the remove message would be redundant.
                     if connected_to_telemetry_core {
                         let _ = tx_to_telemetry_core
-                            .send_async(FromShardAggregator::RemoveNode { local_id })
+                            .send_async(FromShardAggregator::RemoveNode {
+                                local_id,
+                                reason: RemovalReason::TimedOut,
+                            })
                             .await;
                     }
                 }
-                ToAggregator::FromWebsocket(disconnected_conn_id, FromWebsocket::Disconnected) => {
+                ToAggregator::FromWebsocket(
+                    disconnected_conn_id,
+                    FromWebsocket::Disconnected { reason },
+                ) => {
                     // Find all of the local IDs corresponding to the disconnected connection ID and
                     // remove them, telling Telemetry Core about them too. This could be more efficient,
                     // but the mapping isn't currently cached and it's not a super frequent op.
@@ -290,30 +408,101 @@ impl Aggregator {
                         // all nodes associated with this shard anyway, so the remove message would be redundant.
                         if connected_to_telemetry_core {
                             let _ = tx_to_telemetry_core
-                                .send_async(FromShardAggregator::RemoveNode { local_id })
+                                .send_async(FromShardAggregator::RemoveNode { local_id, reason })
                                 .await;
                         }
                     }
                 }
-                ToAggregator::FromTelemetryCore(FromTelemetryCore::Mute {
-                    local_id,
-                    reason: _,
-                }) => {
+                ToAggregator::FromTelemetryCore(FromTelemetryCore::Mute { local_id, reason }) => {
                     // Mute the local ID we've been told to:
                     muted.insert(local_id);
+
+                    // If every node on the connection is now muted, there's no point keeping it
+                    // open, so close it and tell the node why. Connections with other nodes on
+                    // them are left alone, since those nodes are still being listened to.
+                    let conn_id = match to_local_id.get_details(local_id) {
+                        Some(&(conn_id, _)) => conn_id,
+                        None => continue,
+                    };
+                    let all_muted = to_local_id
+                        .iter()
+                        .filter(|(_, &(id, _))| id == conn_id)
+                        .all(|(local_id, _)| muted.contains(&local_id));
+                    if all_muted {
+                        if let Some(closer) = close_connections.get(&conn_id) {
+                            // If this is full, the connection is being closed already:
+                            let _ = closer.try_send(Some(reason.into()));
+                        }
+                    }
+                }
+                ToAggregator::SetDraining {
+                    draining: now_draining,
+                    reconnect_after,
+                } => {
+                    // Any pending disconnect is superseded, either because we've stopped
+                    // draining or because we've been given a new delay:
+                    if let Some(handle) = disconnect_drained_nodes.take() {
+                        handle.abort();
+                    }
+                    if let (true, Some(reconnect_after)) = (now_draining, reconnect_after) {
+                        let closers: Vec<_> = close_connections.values().cloned().collect();
+                        disconnect_drained_nodes = Some(tokio::spawn(async move {
+                            tokio::time::sleep(reconnect_after).await;
+                            log::info!(
+                                "Disconnecting {} drained connections so that they reconnect elsewhere",
+                                closers.len()
+                            );
+                            for closer in closers {
+                                let _ = closer.send_async(Some(Rejection::Draining)).await;
+                            }
+                        }));
+                    }
+
+                    if draining != now_draining {
+                        draining = now_draining;
+                        log::info!("{} draining", if draining { "Started" } else { "Stopped" });
+                        if connected_to_telemetry_core {
+                            let _ = tx_to_telemetry_core
+                                .send_async(FromShardAggregator::Draining { draining })
+                                .await;
+                        }
+                    }
                 }
             }
         }
     }
 
+    /// Is the shard draining? If so, new node connections should be refused.
+    pub fn is_draining(&self) -> bool {
+        self.0.draining.load(Ordering::Relaxed)
+    }
+
+    /// Are we connected to the telemetry core? Messages from nodes are ignored until we are.
+    pub fn is_connected_to_core(&self) -> bool {
+        self.0.connected_to_core.load(Ordering::Relaxed)
+    }
+
+    /// Start or stop draining, which is done ahead of taking the shard down so that nodes can
+    /// move to other shards first. While draining, new node connections should be refused
+    /// (see [`Aggregator::is_draining`]), and if `reconnect_after` is given, the nodes that are
+    /// already connected are disconnected after that long so that they reconnect elsewhere.
+    pub async fn set_draining(&self, draining: bool, reconnect_after: Option<Duration>) {
+        self.0.draining.store(draining, Ordering::Relaxed);
+        let _ = self
+            .0
+            .tx_to_aggregator
+            .send_async(ToAggregator::SetDraining {
+                draining,
+                reconnect_after,
+            })
+            .await;
+    }
+
     /// Return a sink that a node can send messages into to be handled by the aggregator.
     pub fn subscribe_node(&self) -> impl Sink<FromWebsocket, Error = anyhow::Error> + Unpin {
         // Assign a unique aggregator-local ID to each connection that subscribes, and pass
         // that along with every message to the aggregator loop:
-        let conn_id: ConnId = self
-            .0
-            .conn_id
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let conn_id: ConnId = self.0.conn_id.fetch_add(1, Ordering::Relaxed);
         let tx_to_aggregator = self.0.tx_to_aggregator.clone();
 
         // Calling `send` on this Sink requires Unpin. There may be a nicer way than this,
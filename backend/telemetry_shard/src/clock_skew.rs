@@ -0,0 +1,145 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use common::node_message::NodeMessageId;
+use common::node_types::Timestamp;
+
+/// Keep track of which of the nodes on a connection have clocks that disagree with ours by
+/// more than we allow. A node is flagged once its clock is out by more than the maximum skew,
+/// and isn't unflagged until it's back within half of that, so that a node hovering around
+/// the limit doesn't flip back and forth.
+#[derive(Debug)]
+pub struct ClockSkews {
+    max_skew_ms: u64,
+    /// Nodes that have been flagged at some point, and whether they're flagged now.
+    flagged: HashMap<NodeMessageId, bool>,
+}
+
+/// A node's clock has started or stopped disagreeing with ours.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSkewChange {
+    /// The node's clock is this many ms ahead of ours (or behind, if negative). `first` is
+    /// true if this is the first time that the node has been flagged.
+    Skewed { skew_ms: i64, first: bool },
+    /// The node's clock agrees with ours again.
+    Corrected,
+}
+
+impl ClockSkewChange {
+    /// The skew to tell the core about; `None` once a node's clock agrees with ours again.
+    pub fn skew_ms(&self) -> Option<i64> {
+        match self {
+            ClockSkewChange::Skewed { skew_ms, .. } => Some(*skew_ms),
+            ClockSkewChange::Corrected => None,
+        }
+    }
+}
+
+impl ClockSkews {
+    /// Flag nodes whose clocks are out by more than `max_skew`.
+    pub fn new(max_skew: Duration) -> ClockSkews {
+        ClockSkews {
+            max_skew_ms: max_skew.as_millis().try_into().unwrap_or(u64::MAX),
+            flagged: HashMap::new(),
+        }
+    }
+
+    /// Compare when a node says that it sent a message with when we received it (both in
+    /// ms since the unix epoch), returning the change if this flags or unflags the node.
+    pub fn check(
+        &mut self,
+        message_id: NodeMessageId,
+        sent_at: Timestamp,
+        received_at: Timestamp,
+    ) -> Option<ClockSkewChange> {
+        let skew_ms = sent_at as i64 - received_at as i64;
+        let skew = skew_ms.unsigned_abs();
+        match self.flagged.get_mut(&message_id) {
+            Some(flagged) if *flagged => {
+                if skew > self.max_skew_ms / 2 {
+                    return None;
+                }
+                *flagged = false;
+                Some(ClockSkewChange::Corrected)
+            }
+            flagged => {
+                if skew <= self.max_skew_ms {
+                    return None;
+                }
+                let first = flagged.is_none();
+                self.flagged.insert(message_id, true);
+                Some(ClockSkewChange::Skewed { skew_ms, first })
+            }
+        }
+    }
+
+    /// Forget about a node that's gone away.
+    pub fn remove(&mut self, message_id: NodeMessageId) {
+        self.flagged.remove(&message_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn nodes_are_flagged_when_their_clocks_drift_too_far() {
+        let mut skews = ClockSkews::new(Duration::from_secs(10));
+        let now = 1_000_000;
+
+        // Within the limit either way:
+        assert_eq!(skews.check(1, now + 10_000, now), None);
+        assert_eq!(skews.check(1, now - 10_000, now), None);
+
+        // Too far behind, and then still too far behind:
+        assert_eq!(
+            skews.check(1, now - 60_000, now),
+            Some(ClockSkewChange::Skewed {
+                skew_ms: -60_000,
+                first: true
+            })
+        );
+        assert_eq!(skews.check(1, now - 20_000, now), None);
+
+        // Only unflagged once it's well within the limit:
+        assert_eq!(skews.check(1, now + 8_000, now), None);
+        assert_eq!(
+            skews.check(1, now + 4_000, now),
+            Some(ClockSkewChange::Corrected)
+        );
+
+        // Flagged again, but not for the first time:
+        assert_eq!(
+            skews.check(1, now + 11_000, now),
+            Some(ClockSkewChange::Skewed {
+                skew_ms: 11_000,
+                first: false
+            })
+        );
+
+        // Other nodes are tracked separately, and forgotten when removed:
+        assert!(matches!(
+            skews.check(2, now + 11_000, now),
+            Some(ClockSkewChange::Skewed { first: true, .. })
+        ));
+        skews.remove(2);
+        assert_eq!(skews.check(2, now, now), None);
+    }
+}
@@ -15,8 +15,11 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use bincode::Options;
+use common::internal_messages::{self, Handshake, HandshakeResponse};
 use common::ws_client;
 use futures::StreamExt;
+use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Clone, Debug)]
 pub enum Message<Out> {
@@ -25,39 +28,104 @@ pub enum Message<Out> {
     Data(Out),
 }
 
+/// What should we do if the telemetry core speaks a different version of the
+/// internal protocol to us?
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnProtocolMismatch {
+    /// Log the error and keep trying to reconnect.
+    Retry,
+    /// Log the error and exit the process.
+    Exit,
+}
+
+impl FromStr for OnProtocolMismatch {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "retry" => Ok(OnProtocolMismatch::Retry),
+            "exit" => Ok(OnProtocolMismatch::Exit),
+            _ => Err(anyhow::anyhow!(
+                "'{s}' is not a valid option; expected 'retry' or 'exit'"
+            )),
+        }
+    }
+}
+
 /// Connect to the telemetry core, retrying the connection if we're disconnected.
 /// - Sends `Message::Connected` and `Message::Disconnected` when the connection goes up/down.
 /// - Returns a channel that allows you to send messages to the connection.
+/// - Before anything else, agrees on an internal protocol version with the core (see
+///   [`internal_messages::Handshake`]), and handles any disagreement according to `on_protocol_mismatch`.
 /// - Messages are all encoded/decoded to/from bincode, and so need to support being (de)serialized from
 ///   a non self-describing encoding.
+/// - The channel to send messages to the connection holds at most `channel_capacity` messages. When it's
+///   full, senders wait for room. While we're not connected, pending messages are thrown away.
+/// - If a `shard_secret` is given, it's presented to the core in an "Authorization: Bearer <secret>"
+///   header when connecting. Cores that expect a different secret refuse the connection.
 ///
 /// Note: have a look at [`common::internal_messages`] to see the different message types exchanged
 /// between aggregator and core.
 pub async fn create_ws_connection_to_core<In, Out>(
     telemetry_uri: http::Uri,
+    on_protocol_mismatch: OnProtocolMismatch,
+    channel_capacity: usize,
+    tcp_keepalive: Option<Duration>,
+    shard_secret: Option<Box<str>>,
 ) -> (flume::Sender<In>, flume::Receiver<Message<Out>>)
 where
     In: serde::Serialize + Send + 'static,
     Out: serde::de::DeserializeOwned + Send + 'static,
 {
-    let (tx_in, rx_in) = flume::bounded::<In>(10);
+    let (tx_in, rx_in) = flume::bounded::<In>(channel_capacity);
     let (tx_out, rx_out) = flume::bounded(10);
 
     let mut is_connected = false;
 
     tokio::spawn(async move {
+        let authorization = shard_secret.map(|secret| format!("Bearer {secret}"));
+        let headers: Vec<_> = authorization
+            .iter()
+            .map(|auth| ("Authorization", auth.as_str()))
+            .collect();
+
         loop {
             // Throw away any pending messages from the incoming channel so that it
             // doesn't get filled up and begin blocking while we're looping and waiting
             // for a reconnection.
-            while let Ok(_) = rx_in.try_recv() {}
+            while rx_in.try_recv().is_ok() {}
 
             // Try to connect. If connection established, we serialize and forward messages
             // to/from the core. If the external channels break, we end for good. If the internal
             // channels break, we loop around and try connecting again.
-            match ws_client::connect(&telemetry_uri).await {
+            match ws_client::connect_with_headers_and_tcp_keepalive(
+                &telemetry_uri,
+                &headers,
+                tcp_keepalive,
+            )
+            .await
+            {
                 Ok(connection) => {
                     let (tx_to_core, mut rx_from_core) = connection.into_channels();
+
+                    // Make sure that we speak the same protocol version as the core before sending anything else:
+                    match negotiate_protocol(&tx_to_core, &mut rx_from_core).await {
+                        Ok(()) => {}
+                        Err(NegotiateError::Mismatch(e)) => {
+                            if on_protocol_mismatch == OnProtocolMismatch::Exit {
+                                log::error!("{e}; exiting");
+                                std::process::exit(1);
+                            }
+                            log::error!("{e} (will reconnect)");
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                            continue;
+                        }
+                        Err(NegotiateError::Other(e)) => {
+                            log::error!("Error negotiating protocol version with core (will reconnect): {e}");
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                            continue;
+                        }
+                    }
+
                     is_connected = true;
                     let tx_out = tx_out.clone();
 
@@ -115,6 +183,11 @@ where
                         };
                     }
                 }
+                Err(ws_client::ConnectError::ConnectionFailedRejected { status_code: 401 }) => {
+                    log::error!(
+                        "Core refused our connection; is '--shard-secret' set to the secret that it expects? (will reconnect)"
+                    );
+                }
                 Err(connect_err) => {
                     // Issue connecting? Wait and try again on the next loop iteration.
                     log::error!(
@@ -133,9 +206,49 @@ where
             }
 
             // Wait a little before we try to connect again.
-            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            tokio::time::sleep(Duration::from_secs(1)).await;
         }
     });
 
     (tx_in, rx_out)
 }
+
+#[derive(thiserror::Error, Debug)]
+enum NegotiateError {
+    #[error(transparent)]
+    Mismatch(#[from] internal_messages::ProtocolVersionMismatch),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+/// Tell the core which version of the internal protocol we speak, and wait to hear
+/// whether it speaks the same version.
+async fn negotiate_protocol(
+    tx_to_core: &ws_client::Sender,
+    rx_from_core: &mut ws_client::Receiver,
+) -> Result<(), NegotiateError> {
+    let handshake = Handshake {
+        protocol_version: internal_messages::PROTOCOL_VERSION,
+    };
+    let bytes = bincode::options()
+        .serialize(&handshake)
+        .expect("handshake must be serializable");
+    tx_to_core
+        .unbounded_send(ws_client::SentMessage::Binary(bytes))
+        .map_err(|e| anyhow::anyhow!("Failed to send handshake: {e}"))?;
+
+    let bytes = match rx_from_core.next().await {
+        Some(Ok(ws_client::RecvMessage::Binary(bytes))) => bytes,
+        Some(Ok(ws_client::RecvMessage::Text(s))) => s.into_bytes(),
+        Some(Err(e)) => {
+            return Err(anyhow::anyhow!("Failed to receive handshake response: {e}").into())
+        }
+        None => return Err(anyhow::anyhow!("Connection closed before handshake response").into()),
+    };
+    let response: HandshakeResponse = bincode::options()
+        .deserialize(&bytes)
+        .map_err(|e| anyhow::anyhow!("Failed to deserialize handshake response: {e}"))?;
+
+    response.into_result(handshake.protocol_version)?;
+    Ok(())
+}
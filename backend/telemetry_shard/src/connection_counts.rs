@@ -0,0 +1,111 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Keep track of how many connections are open from each address, so that
+/// we can refuse new ones from addresses that already have too many.
+#[derive(Debug, Clone)]
+pub struct ConnectionCounts(Arc<ConnectionCountsInner>);
+
+#[derive(Debug)]
+struct ConnectionCountsInner {
+    max_per_addr: usize,
+    counts: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl ConnectionCounts {
+    /// Create a new set of connection counts. At most `max_per_addr`
+    /// connections will be allowed from a given address at once.
+    pub fn new(max_per_addr: usize) -> ConnectionCounts {
+        ConnectionCounts(Arc::new(ConnectionCountsInner {
+            max_per_addr,
+            counts: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Try to count a new connection from the given address. If the address
+    /// is already at the limit, this returns None and the connection should be
+    /// refused. Else, the connection is counted until the returned guard is dropped.
+    pub fn try_connect(&self, addr: IpAddr) -> Option<ConnectionGuard> {
+        let mut counts = self.0.counts.lock().unwrap();
+        let count = counts.entry(addr).or_default();
+        if *count >= self.0.max_per_addr {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionGuard {
+            counts: self.clone(),
+            addr,
+        })
+    }
+}
+
+/// A connection that's being counted. The count for the address is
+/// decremented when this is dropped, however the connection ends.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    counts: ConnectionCounts,
+    addr: IpAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut counts = self.counts.0.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.addr) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.addr);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn connections_past_the_limit_are_refused() {
+        let counts = ConnectionCounts::new(2);
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        let other_addr: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let first = counts.try_connect(addr).expect("under the limit");
+        let _second = counts.try_connect(addr).expect("under the limit");
+        assert!(counts.try_connect(addr).is_none());
+
+        // Other addresses have their own limit:
+        let _other = counts.try_connect(other_addr).expect("under the limit");
+
+        // Once a connection ends, there's room for another:
+        drop(first);
+        let _third = counts.try_connect(addr).expect("under the limit again");
+        assert!(counts.try_connect(addr).is_none());
+    }
+
+    #[test]
+    fn addresses_are_forgotten_when_their_connections_end() {
+        let counts = ConnectionCounts::new(2);
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let guards: Vec<_> = (0..2).map(|_| counts.try_connect(addr).unwrap()).collect();
+        drop(guards);
+        assert!(counts.0.counts.lock().unwrap().is_empty());
+    }
+}
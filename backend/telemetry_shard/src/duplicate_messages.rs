@@ -0,0 +1,78 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+/// Spot messages on a connection that are identical to the message before them. Messages are
+/// compared by a hash of their bytes, so only the last one needs remembering. A message is
+/// only a duplicate if the copy before it was passed on within the window, so a node that
+/// repeats itself forever still has one copy passed on every so often.
+#[derive(Debug)]
+pub struct DuplicateMessages {
+    window: Duration,
+    /// A hash of the last message that was passed on, and when it was.
+    last: Option<(u64, Instant)>,
+}
+
+impl DuplicateMessages {
+    /// Treat messages as duplicates if they repeat one passed on less than `window` ago.
+    pub fn new(window: Duration) -> DuplicateMessages {
+        DuplicateMessages { window, last: None }
+    }
+
+    /// Is this message, received at the time given, a duplicate of the one before it? If
+    /// not, it's remembered as the last message to be passed on.
+    pub fn is_duplicate(&mut self, bytes: &[u8], now: Instant) -> bool {
+        let mut hasher = DefaultHasher::new();
+        bytes.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        if let Some((last_hash, passed_on)) = self.last {
+            if last_hash == hash && now.saturating_duration_since(passed_on) < self.window {
+                return true;
+            }
+        }
+        self.last = Some((hash, now));
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_repeats_within_the_window_are_duplicates() {
+        let mut duplicates = DuplicateMessages::new(Duration::from_secs(1));
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        assert!(!duplicates.is_duplicate(b"a", at(0)));
+        assert!(duplicates.is_duplicate(b"a", at(100)));
+        assert!(duplicates.is_duplicate(b"a", at(900)));
+
+        // Different messages are passed on, and only the last one is compared with:
+        assert!(!duplicates.is_duplicate(b"b", at(950)));
+        assert!(!duplicates.is_duplicate(b"a", at(960)));
+        assert!(duplicates.is_duplicate(b"a", at(970)));
+
+        // Once the window has passed, the next copy is passed on:
+        assert!(!duplicates.is_duplicate(b"a", at(1960)));
+        assert!(duplicates.is_duplicate(b"a", at(2000)));
+    }
+}
@@ -0,0 +1,45 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::str::FromStr;
+
+/// What should we do if a node that's already connected sends another `system.connected`
+/// message, with a different genesis hash to the first? A node can't change chains, so this
+/// is a misconfigured node, or several nodes sending messages with the same ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnGenesisChange {
+    /// Ignore the new message, leaving the node on the chain it first said it was on.
+    Ignore,
+    /// Treat the node as a new one: remove it from the chain it was on, and add it to the
+    /// chain with the new genesis hash.
+    Readd,
+    /// Close the connection, telling the node why.
+    Reject,
+}
+
+impl FromStr for OnGenesisChange {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(OnGenesisChange::Ignore),
+            "readd" => Ok(OnGenesisChange::Readd),
+            "reject" => Ok(OnGenesisChange::Reject),
+            _ => Err(anyhow::anyhow!(
+                "'{s}' is not a valid option; expected 'ignore', 'readd' or 'reject'"
+            )),
+        }
+    }
+}
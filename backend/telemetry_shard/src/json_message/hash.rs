@@ -58,9 +58,10 @@ impl<'de> Visitor<'de> for HashVisitor {
     where
         E: de::Error,
     {
-        value
-            .parse()
-            .map_err(|_| de::Error::invalid_value(Unexpected::Str(value), &self))
+        value.parse().map_err(|e| match e {
+            HashParseError::InvalidLength(_) => de::Error::custom(e),
+            _ => de::Error::invalid_value(Unexpected::Str(value), &self),
+        })
     }
 
     fn visit_bytes<E>(self, value: &[u8]) -> Result<Self::Value, E>
@@ -75,8 +76,10 @@ impl<'de> Visitor<'de> for HashVisitor {
             return Ok(Hash(hash));
         }
 
-        Hash::from_ascii(value)
-            .map_err(|_| de::Error::invalid_value(Unexpected::Bytes(value), &self))
+        Hash::from_ascii(value).map_err(|e| match e {
+            HashParseError::InvalidLength(_) => de::Error::custom(e),
+            _ => de::Error::invalid_value(Unexpected::Bytes(value), &self),
+        })
     }
 
     fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
@@ -106,9 +109,14 @@ impl Hash {
             return Err(HashParseError::InvalidPrefix);
         }
 
+        let hex = &value[2..];
+        if hex.len() != HASH_BYTES * 2 {
+            return Err(HashParseError::InvalidLength(hex.len()));
+        }
+
         let mut hash = [0; HASH_BYTES];
 
-        hex::decode_to_slice(&value[2..], &mut hash).map_err(HashParseError::HexError)?;
+        hex::decode_to_slice(hex, &mut hash).map_err(HashParseError::HexError)?;
 
         Ok(Hash(hash))
     }
@@ -165,6 +173,8 @@ pub enum HashParseError {
     HexError(hex::FromHexError),
     #[error("Invalid hex prefix: expected '0x'")]
     InvalidPrefix,
+    #[error("Invalid hash length: expected 64 hex digits after '0x', got {0}")]
+    InvalidLength(usize),
 }
 
 #[cfg(test)]
@@ -190,6 +200,19 @@ mod tests {
         assert_eq!(hash, DUMMY);
     }
 
+    #[test]
+    fn deserialize_json_hash_str_wrong_length() {
+        let json = r#""0xdeadBEEF""#;
+
+        let err = serde_json::from_str::<Hash>(json).unwrap_err();
+
+        assert!(
+            err.to_string()
+                .contains("expected 64 hex digits after '0x', got 8"),
+            "unexpected error: {err}"
+        );
+    }
+
     #[test]
     fn deserialize_json_array() {
         let json = r#"[222,173,190,239,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]"#;
@@ -19,5 +19,38 @@
 mod hash;
 mod node_message;
 
+#[allow(unused_imports)]
 pub use hash::Hash;
 pub use node_message::*;
+
+use common::node_message::{MalformedNodeMessage, NodeMessageId};
+use common::node_types::Timestamp;
+
+/// Parse a JSON message from a node into our internal representation of it, along with
+/// when the node says that it sent the message (in ms since the unix epoch), if it did, and
+/// the version of the node protocol that it's in.
+///
+/// `system.connected` messages say which version they're in. Any other message is expected
+/// to be in the version that `node_protocol` gives for the ID of the node that it's about.
+pub fn parse(
+    bytes: &[u8],
+    node_protocol: impl FnOnce(NodeMessageId) -> NodeProtocol,
+) -> Result<
+    (
+        common::node_message::NodeMessage,
+        Option<Timestamp>,
+        NodeProtocol,
+    ),
+    MalformedNodeMessage,
+> {
+    let node_message: NodeMessage =
+        serde_json::from_slice(bytes).map_err(|e| MalformedNodeMessage::new(bytes, e))?;
+    let protocol = node_message
+        .protocol_version()
+        .unwrap_or_else(|| node_protocol(node_message.id()));
+    node_message
+        .check_protocol(protocol)
+        .map_err(|e| MalformedNodeMessage::new(bytes, e))?;
+    let sent_at = node_message.sent_at();
+    Ok((node_message.into(), sent_at, protocol))
+}
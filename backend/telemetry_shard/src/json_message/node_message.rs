@@ -22,7 +22,25 @@
 use super::hash::Hash;
 use common::node_message as internal;
 use common::node_types;
+use serde::de::{Deserializer, IgnoredAny, MapAccess, Visitor};
 use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// A version of the format that nodes send telemetry in. Nodes say which version they speak
+/// via `protocol_version` in their `system.connected` message, and every other message about
+/// the node is expected to be in that version. The versions differ in that:
+///
+/// - Version 1 (which nodes that don't say speak) sends the block numbers in `notify.finalized`
+///   and `afg.*` messages as strings.
+/// - Version 2 sends them as numbers, like every other block number.
+pub type NodeProtocol = u32;
+
+/// The version of the node protocol spoken by nodes that don't say.
+pub const DEFAULT_NODE_PROTOCOL: NodeProtocol = 1;
+
+/// The latest version of the node protocol that we understand.
+pub const LATEST_NODE_PROTOCOL: NodeProtocol = 2;
 
 /// This struct represents a telemetry message sent from a node as
 /// a JSON payload. Since JSON is self describing, we can use attributes
@@ -40,22 +58,85 @@ use serde::Deserialize;
 #[serde(untagged)]
 pub enum NodeMessage {
     V1 {
+        #[serde(default)]
+        ts: SentAt,
         #[serde(flatten)]
         payload: Payload,
     },
     V2 {
         id: NodeMessageId,
+        #[serde(default)]
+        ts: SentAt,
         payload: Payload,
     },
 }
 
+impl NodeMessage {
+    /// When the node says that it sent this message, in ms since the unix epoch.
+    pub fn sent_at(&self) -> Option<node_types::Timestamp> {
+        match self {
+            NodeMessage::V1 { ts, .. } | NodeMessage::V2 { ts, .. } => ts.0,
+        }
+    }
+
+    /// The ID of the node that the message is about.
+    pub fn id(&self) -> NodeMessageId {
+        match self {
+            NodeMessage::V1 { .. } => 0,
+            NodeMessage::V2 { id, .. } => *id,
+        }
+    }
+
+    fn payload(&self) -> &Payload {
+        match self {
+            NodeMessage::V1 { payload, .. } | NodeMessage::V2 { payload, .. } => payload,
+        }
+    }
+
+    /// The version of the node protocol that a `system.connected` message says the node speaks.
+    /// Other messages don't say.
+    pub fn protocol_version(&self) -> Option<NodeProtocol> {
+        match self.payload() {
+            Payload::SystemConnected(connected) => Some(connected.protocol_version),
+            _ => None,
+        }
+    }
+
+    /// Check that the message is in the given version of the node protocol, returning why
+    /// not if it isn't.
+    pub fn check_protocol(&self, protocol: NodeProtocol) -> Result<(), serde_json::Error> {
+        let block_numbers: &[&VersionedBlockNumber] = match self.payload() {
+            Payload::NotifyFinalized(finalized) => &[&finalized.height],
+            Payload::AfgFinalized(finalized) => &[&finalized.finalized_number],
+            Payload::AfgReceivedPrevote(received) | Payload::AfgReceivedPrecommit(received) => {
+                &[&received.target_number]
+            }
+            _ => &[],
+        };
+        let expected = match protocol {
+            0 | 1 => "strings",
+            _ => "numbers",
+        };
+        let is_expected = |block_number: &&VersionedBlockNumber| match block_number {
+            VersionedBlockNumber::String(_) => expected == "strings",
+            VersionedBlockNumber::Number(_) => expected == "numbers",
+        };
+        match block_numbers.iter().all(is_expected) {
+            true => Ok(()),
+            false => Err(serde::de::Error::custom(format!(
+                "block numbers are sent as {expected} in version {protocol} of the node protocol"
+            ))),
+        }
+    }
+}
+
 impl From<NodeMessage> for internal::NodeMessage {
     fn from(msg: NodeMessage) -> Self {
         match msg {
-            NodeMessage::V1 { payload } => internal::NodeMessage::V1 {
+            NodeMessage::V1 { payload, .. } => internal::NodeMessage::V1 {
                 payload: payload.into(),
             },
-            NodeMessage::V2 { id, payload } => internal::NodeMessage::V2 {
+            NodeMessage::V2 { id, payload, .. } => internal::NodeMessage::V2 {
                 id,
                 payload: payload.into(),
             },
@@ -63,6 +144,33 @@ impl From<NodeMessage> for internal::NodeMessage {
     }
 }
 
+/// When a node says that it sent a message, in ms since the unix epoch. Nodes send this as an
+/// RFC 3339 string, according to their own clocks; anything else is treated as if it wasn't given.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SentAt(pub Option<node_types::Timestamp>);
+
+impl<'de> Deserialize<'de> for SentAt {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use time::format_description::well_known::Rfc3339;
+
+        /// Deserializes any value, but only keeps hold of strings.
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum MaybeString<'a> {
+            String(#[serde(borrow)] Cow<'a, str>),
+            Other(IgnoredAny),
+        }
+
+        let sent_at = match MaybeString::deserialize(deserializer)? {
+            MaybeString::String(ts) => time::OffsetDateTime::parse(&ts, &Rfc3339)
+                .ok()
+                .and_then(|ts| (ts.unix_timestamp_nanos() / 1_000_000).try_into().ok()),
+            MaybeString::Other(_) => None,
+        };
+        Ok(SentAt(sent_at))
+    }
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(tag = "msg")]
 pub enum Payload {
@@ -76,6 +184,12 @@ pub enum Payload {
     NotifyFinalized(Finalized),
     #[serde(rename = "afg.authority_set")]
     AfgAuthoritySet(AfgAuthoritySet),
+    #[serde(rename = "afg.finalized")]
+    AfgFinalized(AfgFinalized),
+    #[serde(rename = "afg.received_prevote")]
+    AfgReceivedPrevote(AfgReceived),
+    #[serde(rename = "afg.received_precommit")]
+    AfgReceivedPrecommit(AfgReceived),
     #[serde(rename = "sysinfo.hwbench")]
     HwBench(NodeHwBench),
 }
@@ -89,6 +203,9 @@ impl From<Payload> for internal::Payload {
             Payload::NotifyFinalized(m) => internal::Payload::NotifyFinalized(m.into()),
             Payload::AfgAuthoritySet(m) => internal::Payload::AfgAuthoritySet(m.into()),
             Payload::HwBench(m) => internal::Payload::HwBench(m.into()),
+            Payload::AfgFinalized(m) => internal::Payload::AfgFinalized(m.into()),
+            Payload::AfgReceivedPrevote(m) => internal::Payload::AfgReceivedPrevote(m.into()),
+            Payload::AfgReceivedPrecommit(m) => internal::Payload::AfgReceivedPrecommit(m.into()),
         }
     }
 }
@@ -96,10 +213,17 @@ impl From<Payload> for internal::Payload {
 #[derive(Deserialize, Debug)]
 pub struct SystemConnected {
     pub genesis_hash: Hash,
+    /// The version of the node protocol that the node speaks.
+    #[serde(default = "default_node_protocol")]
+    pub protocol_version: NodeProtocol,
     #[serde(flatten)]
     pub node: NodeDetails,
 }
 
+fn default_node_protocol() -> NodeProtocol {
+    DEFAULT_NODE_PROTOCOL
+}
+
 impl From<SystemConnected> for internal::SystemConnected {
     fn from(msg: SystemConnected) -> Self {
         internal::SystemConnected {
@@ -111,7 +235,7 @@ impl From<SystemConnected> for internal::SystemConnected {
 
 #[derive(Deserialize, Debug)]
 pub struct SystemInterval {
-    pub peers: Option<u64>,
+    pub peers: Option<i64>,
     pub txcount: Option<u64>,
     pub bandwidth_upload: Option<f64>,
     pub bandwidth_download: Option<f64>,
@@ -120,6 +244,17 @@ pub struct SystemInterval {
     #[serde(flatten)]
     pub block: Option<Block>,
     pub used_state_cache_size: Option<f32>,
+    pub cpu: Option<f32>,
+    pub memory: Option<f32>,
+    pub disk_usage: Option<f64>,
+    pub disk_read_per_sec: Option<f64>,
+    pub disk_write_per_sec: Option<f64>,
+    #[serde(alias = "is_syncing")]
+    pub is_major_syncing: Option<bool>,
+    /// Everything else; this must come after the other flattened fields so that
+    /// they get first pick of the remaining keys.
+    #[serde(flatten)]
+    pub custom_metrics: CustomMetrics,
 }
 
 impl From<SystemInterval> for internal::SystemInterval {
@@ -133,6 +268,75 @@ impl From<SystemInterval> for internal::SystemInterval {
             finalized_hash: msg.finalized_hash.map(|h| h.into()),
             block: msg.block.map(|b| b.into()),
             used_state_cache_size: msg.used_state_cache_size,
+            cpu: msg.cpu,
+            memory: msg.memory,
+            disk_usage: msg.disk_usage,
+            disk_read_per_sec: msg.disk_read_per_sec,
+            disk_write_per_sec: msg.disk_write_per_sec,
+            is_major_syncing: msg.is_major_syncing,
+            custom_metrics: msg.custom_metrics.0,
+        }
+    }
+}
+
+/// Any keys in a message that we don't otherwise understand, and which have numeric
+/// values. Keys with other values are ignored, as are any numeric ones past the first
+/// [`internal::MAX_CUSTOM_METRICS`].
+#[derive(Debug, Default)]
+pub struct CustomMetrics(pub HashMap<String, f64>);
+
+impl<'de> Deserialize<'de> for CustomMetrics {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct CustomMetricsVisitor;
+
+        impl<'de> Visitor<'de> for CustomMetricsVisitor {
+            type Value = CustomMetrics;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a map")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut metrics = HashMap::new();
+                while let Some(key) = map.next_key::<String>()? {
+                    match map.next_value::<MaybeNumber>()? {
+                        MaybeNumber::Number(n) if metrics.len() < internal::MAX_CUSTOM_METRICS => {
+                            metrics.insert(key, n);
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(CustomMetrics(metrics))
+            }
+        }
+
+        /// Deserializes any value, but only keeps hold of numbers.
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum MaybeNumber {
+            Number(f64),
+            Other(IgnoredAny),
+        }
+
+        deserializer.deserialize_map(CustomMetricsVisitor)
+    }
+}
+
+/// A block number in one of the messages whose format depends on the version of the node
+/// protocol; see [`NodeProtocol`]. Either form is accepted here, and checked against the
+/// version that the node speaks by [`NodeMessage::check_protocol()`].
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum VersionedBlockNumber {
+    String(Box<str>),
+    Number(BlockNumber),
+}
+
+impl From<VersionedBlockNumber> for Box<str> {
+    fn from(block_number: VersionedBlockNumber) -> Self {
+        match block_number {
+            VersionedBlockNumber::String(s) => s,
+            VersionedBlockNumber::Number(n) => n.to_string().into(),
         }
     }
 }
@@ -141,14 +345,14 @@ impl From<SystemInterval> for internal::SystemInterval {
 pub struct Finalized {
     #[serde(rename = "best")]
     pub hash: Hash,
-    pub height: Box<str>,
+    pub height: VersionedBlockNumber,
 }
 
 impl From<Finalized> for internal::Finalized {
     fn from(msg: Finalized) -> Self {
         internal::Finalized {
             hash: msg.hash.into(),
-            height: msg.height,
+            height: msg.height.into(),
         }
     }
 }
@@ -156,12 +360,51 @@ impl From<Finalized> for internal::Finalized {
 #[derive(Deserialize, Debug)]
 pub struct AfgAuthoritySet {
     pub authority_id: Box<str>,
+    // Older nodes only report their own authority ID:
+    #[serde(default)]
+    pub authorities: Box<str>,
+    #[serde(default)]
+    pub authority_set_id: Box<str>,
 }
 
 impl From<AfgAuthoritySet> for internal::AfgAuthoritySet {
     fn from(msg: AfgAuthoritySet) -> Self {
         internal::AfgAuthoritySet {
             authority_id: msg.authority_id,
+            authorities: msg.authorities,
+            authority_set_id: msg.authority_set_id,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AfgFinalized {
+    pub finalized_hash: Hash,
+    pub finalized_number: VersionedBlockNumber,
+}
+
+impl From<AfgFinalized> for internal::AfgFinalized {
+    fn from(msg: AfgFinalized) -> Self {
+        internal::AfgFinalized {
+            finalized_hash: msg.finalized_hash.into(),
+            finalized_number: msg.finalized_number.into(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AfgReceived {
+    pub target_hash: Hash,
+    pub target_number: VersionedBlockNumber,
+    pub voter: Option<Box<str>>,
+}
+
+impl From<AfgReceived> for internal::AfgReceived {
+    fn from(msg: AfgReceived) -> Self {
+        internal::AfgReceived {
+            target_hash: msg.target_hash.into(),
+            target_number: msg.target_number.into(),
+            voter: msg.voter,
         }
     }
 }
@@ -242,6 +485,11 @@ pub struct NodeDetails {
     pub implementation: Box<str>,
     pub version: Box<str>,
     pub validator: Option<Box<str>>,
+    #[serde(default)]
+    pub authority: bool,
+    /// What the node says its role is, if anything ("full", "light", "collator", ...).
+    #[serde(default)]
+    pub role: Option<Box<str>>,
     pub network_id: node_types::NetworkId,
     pub startup_time: Option<Box<str>>,
     pub target_os: Option<Box<str>>,
@@ -252,89 +500,34 @@ pub struct NodeDetails {
 }
 
 impl From<NodeDetails> for node_types::NodeDetails {
-    fn from(mut details: NodeDetails) -> Self {
-        // Migrate old-style `version` to the split metrics.
-        // TODO: Remove this once everyone updates their nodes.
-        if details.target_os.is_none()
-            && details.target_arch.is_none()
-            && details.target_env.is_none()
-        {
-            if let Some((version, target_arch, target_os, target_env)) =
-                split_old_style_version(&details.version)
-            {
-                details.target_arch = Some(target_arch.into());
-                details.target_os = Some(target_os.into());
-                details.target_env = Some(target_env.into());
-                details.version = version.into();
-            }
-        }
-
-        node_types::NodeDetails {
+    fn from(details: NodeDetails) -> Self {
+        let mut details = node_types::NodeDetails {
             chain: details.chain,
             name: details.name,
             implementation: details.implementation,
             version: details.version,
             validator: details.validator,
+            authority: details.authority,
+            role: node_types::NodeRole::from_connected(details.authority, details.role.as_deref()),
             network_id: details.network_id,
             startup_time: details.startup_time,
             target_os: details.target_os,
             target_arch: details.target_arch,
             target_env: details.target_env,
+            commit: None,
             sysinfo: details.sysinfo.map(|sysinfo| sysinfo.into()),
             ip: details.ip,
-        }
+        };
+        // Fill in the commit, and migrate old-style `version` to the split target.
+        // TODO: Remove the target migration once everyone updates their nodes.
+        details.parse_version();
+        details
     }
 }
 
 type NodeMessageId = u64;
 type BlockNumber = u64;
 
-fn is_version_or_hash(name: &str) -> bool {
-    name.bytes().all(|byte| {
-        byte.is_ascii_digit()
-            || byte == b'.'
-            || byte == b'a'
-            || byte == b'b'
-            || byte == b'c'
-            || byte == b'd'
-            || byte == b'e'
-            || byte == b'f'
-    })
-}
-
-/// Split an old style version string into its version + target_arch + target_os + target_arch parts.
-fn split_old_style_version(version_and_target: &str) -> Option<(&str, &str, &str, &str)> {
-    // Old style versions are composed of the following parts:
-    //    $version-$commit_hash-$arch-$os-$env
-    // where $commit_hash and $env are optional.
-    //
-    // For example these are all valid:
-    //   0.9.17-75dd6c7d0-x86_64-linux-gnu
-    //   0.9.17-75dd6c7d0-x86_64-linux
-    //   0.9.17-x86_64-linux-gnu
-    //   0.9.17-x86_64-linux
-    //   2.0.0-alpha.5-da487d19d-x86_64-linux
-
-    let mut iter = version_and_target.rsplit('-').take(3).skip(2);
-
-    // This will one of these: $arch, $commit_hash, $version
-    let item = iter.next()?;
-
-    let target_offset = if is_version_or_hash(item) {
-        item.as_ptr() as usize + item.len() + 1
-    } else {
-        item.as_ptr() as usize
-    } - version_and_target.as_ptr() as usize;
-
-    let version = version_and_target.get(0..target_offset - 1)?;
-    let mut target = version_and_target.get(target_offset..)?.split('-');
-    let target_arch = target.next()?;
-    let target_os = target.next()?;
-    let target_env = target.next().unwrap_or("");
-
-    Some((version, target_arch, target_os, target_env))
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -377,6 +570,35 @@ mod tests {
         );
     }
 
+    #[test]
+    fn sent_at_is_parsed_from_ts() {
+        let v1 = r#"{
+            "msg":"notify.finalized",
+            "ts":"2021-01-13T12:38:25.410794650+01:00",
+            "best":"0x031c3521ca2f9c673812d692fc330b9a18e18a2781e3f9976992f861fd3ea0cb",
+            "height":"50"
+        }"#;
+        let msg = serde_json::from_str::<NodeMessage>(v1).unwrap();
+        assert_eq!(msg.sent_at(), Some(1610537905410));
+
+        let v2 = r#"{
+            "id":1,
+            "ts":"2021-01-13T11:22:20.053Z",
+            "payload":{ "msg":"system.interval", "peers":1 }
+        }"#;
+        let msg = serde_json::from_str::<NodeMessage>(v2).unwrap();
+        assert_eq!(msg.sent_at(), Some(1610536940053));
+    }
+
+    #[test]
+    fn missing_or_unparseable_ts_is_ignored() {
+        for ts in [r#""#, r#""ts":"yesterday","#, r#""ts":1234,"#] {
+            let json = format!(r#"{{ "id":1, {ts} "payload":{{ "msg":"system.interval" }} }}"#);
+            let msg = serde_json::from_str::<NodeMessage>(&json).unwrap();
+            assert_eq!(msg.sent_at(), None, "{json}");
+        }
+    }
+
     #[test]
     fn message_v2_tx_pool_import() {
         // We should happily ignore any fields we don't care about.
@@ -405,44 +627,402 @@ mod tests {
     }
 
     #[test]
-    fn split_old_style_version_works() {
-        let (version, target_arch, target_os, target_env) =
-            split_old_style_version("0.9.17-75dd6c7d0-x86_64-linux-gnu").unwrap();
-        assert_eq!(version, "0.9.17-75dd6c7d0");
-        assert_eq!(target_arch, "x86_64");
-        assert_eq!(target_os, "linux");
-        assert_eq!(target_env, "gnu");
-
-        let (version, target_arch, target_os, target_env) =
-            split_old_style_version("0.9.17-75dd6c7d0-x86_64-linux").unwrap();
-        assert_eq!(version, "0.9.17-75dd6c7d0");
-        assert_eq!(target_arch, "x86_64");
-        assert_eq!(target_os, "linux");
-        assert_eq!(target_env, "");
-
-        let (version, target_arch, target_os, target_env) =
-            split_old_style_version("0.9.17-x86_64-linux-gnu").unwrap();
-        assert_eq!(version, "0.9.17");
-        assert_eq!(target_arch, "x86_64");
-        assert_eq!(target_os, "linux");
-        assert_eq!(target_env, "gnu");
-
-        let (version, target_arch, target_os, target_env) =
-            split_old_style_version("0.9.17-x86_64-linux").unwrap();
-        assert_eq!(version, "0.9.17");
-        assert_eq!(target_arch, "x86_64");
-        assert_eq!(target_os, "linux");
-        assert_eq!(target_env, "");
-
-        let (version, target_arch, target_os, target_env) =
-            split_old_style_version("2.0.0-alpha.5-da487d19d-x86_64-linux").unwrap();
-        assert_eq!(version, "2.0.0-alpha.5-da487d19d");
-        assert_eq!(target_arch, "x86_64");
-        assert_eq!(target_os, "linux");
-        assert_eq!(target_env, "");
-
-        assert_eq!(split_old_style_version(""), None);
-        assert_eq!(split_old_style_version("a"), None);
-        assert_eq!(split_old_style_version("a-b"), None);
+    fn message_v2_negative_peers_are_passed_on() {
+        // Implausible peer counts are handled in the core, so don't reject them here.
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "msg":"system.interval",
+                "peers":-1
+            }
+        }"#;
+        assert!(
+            matches!(
+                serde_json::from_str::<NodeMessage>(json).unwrap(),
+                NodeMessage::V2 {
+                    payload: Payload::SystemInterval(SystemInterval {
+                        peers: Some(-1),
+                        ..
+                    }),
+                    ..
+                },
+            ),
+            "message did not match the expected output",
+        );
+    }
+
+    #[test]
+    fn message_v2_unknown_numeric_values_are_custom_metrics() {
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "msg":"system.interval",
+                "peers":4,
+                "best":"0xcc41708573f2acaded9dd75e07dac2d4163d136ca35b3061c558d7a35a09dd8d",
+                "height":1234,
+                "my_gauge":1.5,
+                "my_counter":12,
+                "my_label":"not a number"
+            }
+        }"#;
+        let interval = match serde_json::from_str::<NodeMessage>(json).unwrap() {
+            NodeMessage::V2 {
+                payload: Payload::SystemInterval(interval),
+                ..
+            } => interval,
+            msg => panic!("message did not match the expected output: {msg:?}"),
+        };
+        assert_eq!(interval.peers, Some(4));
+        assert_eq!(interval.block.map(|b| b.height), Some(1234));
+
+        let mut metrics: Vec<_> = interval.custom_metrics.0.into_iter().collect();
+        metrics.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            metrics,
+            vec![
+                ("my_counter".to_owned(), 12.0),
+                ("my_gauge".to_owned(), 1.5)
+            ]
+        );
+    }
+
+    #[test]
+    fn message_v2_resource_usage() {
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "msg":"system.interval",
+                "peers":4,
+                "cpu":12.5,
+                "memory":524288,
+                "disk_usage":1073741824
+            }
+        }"#;
+        let interval = match serde_json::from_str::<NodeMessage>(json).unwrap() {
+            NodeMessage::V2 {
+                payload: Payload::SystemInterval(interval),
+                ..
+            } => interval,
+            msg => panic!("message did not match the expected output: {msg:?}"),
+        };
+        assert_eq!(interval.cpu, Some(12.5));
+        assert_eq!(interval.memory, Some(524288.0));
+        assert_eq!(interval.disk_usage, Some(1073741824.0));
+        // These are understood, so aren't passed on as custom metrics too:
+        assert!(interval.custom_metrics.0.is_empty());
+        // Nodes that don't report disk I/O are fine:
+        assert_eq!(interval.disk_read_per_sec, None);
+        assert_eq!(interval.disk_write_per_sec, None);
+    }
+
+    #[test]
+    fn message_v2_disk_io() {
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "msg":"system.interval",
+                "peers":4,
+                "disk_read_per_sec":1048576,
+                "disk_write_per_sec":524288.5
+            }
+        }"#;
+        let interval = match serde_json::from_str::<NodeMessage>(json).unwrap() {
+            NodeMessage::V2 {
+                payload: Payload::SystemInterval(interval),
+                ..
+            } => interval,
+            msg => panic!("message did not match the expected output: {msg:?}"),
+        };
+        assert_eq!(interval.disk_read_per_sec, Some(1048576.0));
+        assert_eq!(interval.disk_write_per_sec, Some(524288.5));
+        assert!(interval.custom_metrics.0.is_empty());
+    }
+
+    #[test]
+    fn message_v2_major_syncing() {
+        let is_major_syncing = |extra: &str| {
+            let json = format!(r#"{{ "id":1, "payload":{{ "msg":"system.interval"{extra} }} }}"#);
+            match serde_json::from_str::<NodeMessage>(&json).unwrap() {
+                NodeMessage::V2 {
+                    payload: Payload::SystemInterval(interval),
+                    ..
+                } => interval.is_major_syncing,
+                msg => panic!("message did not match the expected output: {msg:?}"),
+            }
+        };
+
+        assert_eq!(is_major_syncing(""), None);
+        assert_eq!(is_major_syncing(r#","is_major_syncing":true"#), Some(true));
+        assert_eq!(is_major_syncing(r#","is_syncing":false"#), Some(false));
+    }
+
+    #[test]
+    fn message_v2_system_connected_role() {
+        let role = |extra: &str| {
+            let json = format!(
+                r#"{{
+                    "id":1,
+                    "payload":{{
+                        "msg":"system.connected",
+                        "genesis_hash":"0x0000000000000000000000000000000000000000000000000000000000000000",
+                        "chain":"Local Testnet",
+                        "name":"Alice",
+                        "implementation":"Substrate Node",
+                        "version":"2.0.0-07a1af348-aarch64-macos",
+                        "validator":null,
+                        "network_id":"12D3KooW"{extra}
+                    }}
+                }}"#
+            );
+            match serde_json::from_str::<NodeMessage>(&json).unwrap() {
+                NodeMessage::V2 {
+                    payload: Payload::SystemConnected(connected),
+                    ..
+                } => node_types::NodeDetails::from(connected.node).role,
+                msg => panic!("message did not match the expected output: {msg:?}"),
+            }
+        };
+
+        assert_eq!(role(""), node_types::NodeRole::Full);
+        assert_eq!(
+            role(r#","authority":true"#),
+            node_types::NodeRole::Authority
+        );
+        assert_eq!(role(r#","role":"light""#), node_types::NodeRole::Light);
+        assert_eq!(
+            role(r#","authority":true,"role":"collator""#),
+            node_types::NodeRole::Collator
+        );
+        assert_eq!(
+            role(r#","role":"something-new""#),
+            node_types::NodeRole::Full
+        );
+    }
+
+    #[test]
+    fn message_v2_system_connected_genesis_hash_encodings() {
+        let genesis_hash = |genesis_hash: &str| {
+            let json = format!(
+                r#"{{
+                    "id":1,
+                    "payload":{{
+                        "msg":"system.connected",
+                        "genesis_hash":{genesis_hash},
+                        "chain":"Local Testnet",
+                        "name":"Alice",
+                        "implementation":"Substrate Node",
+                        "version":"2.0.0-07a1af348-aarch64-macos",
+                        "validator":null,
+                        "network_id":"12D3KooW"
+                    }}
+                }}"#
+            );
+            match serde_json::from_str::<NodeMessage>(&json)? {
+                NodeMessage::V2 {
+                    payload: Payload::SystemConnected(connected),
+                    ..
+                } => {
+                    Ok::<_, serde_json::Error>(node_types::BlockHash::from(connected.genesis_hash))
+                }
+                msg => panic!("message did not match the expected output: {msg:?}"),
+            }
+        };
+
+        let mut expected = [0; 32];
+        expected[0] = 0xde;
+        expected[31] = 0x01;
+        let expected = node_types::BlockHash::from(expected);
+
+        assert_eq!(
+            genesis_hash(&format!(r#""{expected:#x}""#)).unwrap(),
+            expected
+        );
+        let bytes: Vec<String> = expected.0.iter().map(|b| b.to_string()).collect();
+        assert_eq!(
+            genesis_hash(&format!("[{}]", bytes.join(","))).unwrap(),
+            expected
+        );
+
+        assert!(genesis_hash(r#""0xdead""#).is_err());
+        assert!(genesis_hash(&format!("[{}]", bytes[..31].join(","))).is_err());
+    }
+
+    fn afg_payload(payload: &str) -> Result<internal::Payload, serde_json::Error> {
+        versioned_payload(payload, DEFAULT_NODE_PROTOCOL)
+    }
+
+    fn versioned_payload(
+        payload: &str,
+        protocol: NodeProtocol,
+    ) -> Result<internal::Payload, serde_json::Error> {
+        let json = format!(r#"{{ "id":1, "payload":{payload} }}"#);
+        let msg = serde_json::from_str::<NodeMessage>(&json)?;
+        msg.check_protocol(protocol)?;
+        match msg {
+            NodeMessage::V2 { payload, .. } => Ok(payload.into()),
+            msg => panic!("message did not match the expected output: {msg:?}"),
+        }
+    }
+
+    #[test]
+    fn message_v2_afg_messages() {
+        let hash = "0xcc41708573f2acaded9dd75e07dac2d4163d136ca35b3061c558d7a35a09dd8d";
+
+        let finalized = afg_payload(&format!(
+            r#"{{ "msg":"afg.finalized", "finalized_hash":"{hash}", "finalized_number":"12" }}"#
+        ));
+        assert!(
+            matches!(
+                finalized,
+                Ok(internal::Payload::AfgFinalized(internal::AfgFinalized { ref finalized_number, .. }))
+                    if &**finalized_number == "12"
+            ),
+            "unexpected afg.finalized: {finalized:?}",
+        );
+
+        let prevote = afg_payload(&format!(
+            r#"{{ "msg":"afg.received_prevote", "target_hash":"{hash}", "target_number":"13", "voter":"Alice" }}"#
+        ));
+        assert!(
+            matches!(
+                prevote,
+                Ok(internal::Payload::AfgReceivedPrevote(internal::AfgReceived { ref target_number, voter: Some(ref voter), .. }))
+                    if &**target_number == "13" && &**voter == "Alice"
+            ),
+            "unexpected afg.received_prevote: {prevote:?}",
+        );
+
+        let precommit = afg_payload(&format!(
+            r#"{{ "msg":"afg.received_precommit", "target_hash":"{hash}", "target_number":"14" }}"#
+        ));
+        assert!(
+            matches!(
+                precommit,
+                Ok(internal::Payload::AfgReceivedPrecommit(internal::AfgReceived { ref target_number, voter: None, .. }))
+                    if &**target_number == "14"
+            ),
+            "unexpected afg.received_precommit: {precommit:?}",
+        );
+
+        let authority_set = afg_payload(
+            r#"{ "msg":"afg.authority_set", "authority_id":"Alice", "authorities":"[\"Alice\"]", "authority_set_id":"3" }"#,
+        );
+        assert!(
+            matches!(
+                authority_set,
+                Ok(internal::Payload::AfgAuthoritySet(internal::AfgAuthoritySet { ref authorities, ref authority_set_id, .. }))
+                    if &**authorities == r#"["Alice"]"# && &**authority_set_id == "3"
+            ),
+            "unexpected afg.authority_set: {authority_set:?}",
+        );
+
+        // Older nodes only report their own authority ID:
+        let authority_set = afg_payload(r#"{ "msg":"afg.authority_set", "authority_id":"Alice" }"#);
+        assert!(
+            matches!(
+                authority_set,
+                Ok(internal::Payload::AfgAuthoritySet(internal::AfgAuthoritySet { ref authority_id, ref authorities, .. }))
+                    if &**authority_id == "Alice" && authorities.is_empty()
+            ),
+            "unexpected afg.authority_set: {authority_set:?}",
+        );
+    }
+
+    #[test]
+    fn malformed_afg_messages_are_rejected() {
+        // These are reported as malformed, and so ignored by the shard, rather than
+        // being passed on with made up values:
+        let malformed = [
+            r#"{ "msg":"afg.finalized", "finalized_number":"12" }"#,
+            r#"{ "msg":"afg.finalized", "finalized_hash":"0x1234", "finalized_number":"12" }"#,
+            r#"{ "msg":"afg.received_prevote", "target_number":"13", "voter":"Alice" }"#,
+            r#"{ "msg":"afg.received_precommit", "target_hash":"nope", "target_number":"14" }"#,
+            r#"{ "msg":"afg.received_precommit", "target_hash":"0xcc41708573f2acaded9dd75e07dac2d4163d136ca35b3061c558d7a35a09dd8d", "target_number":14 }"#,
+            r#"{ "msg":"afg.authority_set", "authorities":"[]" }"#,
+        ];
+        for payload in malformed {
+            assert!(
+                afg_payload(payload).is_err(),
+                "{payload} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn block_numbers_are_parsed_according_to_the_node_protocol() {
+        let hash = "0xcc41708573f2acaded9dd75e07dac2d4163d136ca35b3061c558d7a35a09dd8d";
+        let finalized = |height: &str| {
+            format!(r#"{{ "msg":"notify.finalized", "best":"{hash}", "height":{height} }}"#)
+        };
+        let height = |payload: Result<internal::Payload, _>| match payload {
+            Ok(internal::Payload::NotifyFinalized(finalized)) => finalized.height,
+            other => panic!("unexpected notify.finalized: {other:?}"),
+        };
+
+        // Block numbers are strings in version 1 and numbers in version 2, and come out
+        // the same either way:
+        assert_eq!(&*height(versioned_payload(&finalized(r#""50""#), 1)), "50");
+        assert_eq!(&*height(versioned_payload(&finalized("50"), 2)), "50");
+
+        // But each version only accepts its own format:
+        assert!(versioned_payload(&finalized("50"), 1).is_err());
+        assert!(versioned_payload(&finalized(r#""50""#), 2).is_err());
+        let prevote = format!(
+            r#"{{ "msg":"afg.received_prevote", "target_hash":"{hash}", "target_number":"13" }}"#
+        );
+        assert!(versioned_payload(&prevote, 1).is_ok());
+        assert!(versioned_payload(&prevote, 2).is_err());
+    }
+
+    #[test]
+    fn system_connected_says_which_node_protocol_is_spoken() {
+        let protocol_version = |extra: &str| {
+            let json = format!(
+                r#"{{ "id":1, "payload":{{
+                    "msg":"system.connected",
+                    "genesis_hash":"0xcc41708573f2acaded9dd75e07dac2d4163d136ca35b3061c558d7a35a09dd8d",
+                    "chain":"Local Testnet",
+                    "name":"Alice",
+                    "implementation":"Substrate Node",
+                    "version":"2.0.0",
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp"{extra}
+                }} }}"#
+            );
+            serde_json::from_str::<NodeMessage>(&json)
+                .unwrap()
+                .protocol_version()
+        };
+
+        assert_eq!(protocol_version(""), Some(DEFAULT_NODE_PROTOCOL));
+        assert_eq!(protocol_version(r#","protocol_version":2"#), Some(2));
+
+        let interval = r#"{ "id":1, "payload":{ "msg":"system.interval" } }"#;
+        let interval = serde_json::from_str::<NodeMessage>(interval).unwrap();
+        assert_eq!(interval.protocol_version(), None);
+    }
+
+    #[test]
+    fn custom_metrics_are_capped() {
+        let mut payload = serde_json::json!({ "msg": "system.interval" });
+        for n in 0..internal::MAX_CUSTOM_METRICS * 2 {
+            payload[format!("metric_{n}")] = n.into();
+        }
+        let json = serde_json::json!({ "id": 1, "payload": payload }).to_string();
+
+        let interval = match serde_json::from_str::<NodeMessage>(&json).unwrap() {
+            NodeMessage::V2 {
+                payload: Payload::SystemInterval(interval),
+                ..
+            } => interval,
+            msg => panic!("message did not match the expected output: {msg:?}"),
+        };
+        assert_eq!(
+            interval.custom_metrics.0.len(),
+            internal::MAX_CUSTOM_METRICS
+        );
     }
 }
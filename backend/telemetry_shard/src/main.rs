@@ -17,27 +17,48 @@
 #[warn(missing_docs)]
 mod aggregator;
 mod blocked_addrs;
+mod clock_skew;
 mod connection;
+mod connection_counts;
+mod duplicate_messages;
+mod genesis_change;
 mod json_message;
+mod metrics;
+mod payload_encoding;
 mod real_ip;
+mod rejection;
 
 use std::{
     collections::HashMap,
     net::IpAddr,
+    ops::RangeInclusive,
     time::{Duration, Instant},
 };
 
 use aggregator::{Aggregator, FromWebsocket};
 use blocked_addrs::BlockedAddrs;
+use clock_skew::{ClockSkewChange, ClockSkews};
 use common::byte_size::ByteSize;
+use common::config_file;
 use common::http_utils;
+use common::internal_messages::RemovalReason;
 use common::node_message;
 use common::node_message::NodeMessageId;
+use common::node_types::BlockHash;
 use common::rolling_total::RollingTotalBuilder;
-use futures::{SinkExt, StreamExt};
+use common::runtime;
+use connection::OnProtocolMismatch;
+use connection_counts::ConnectionCounts;
+use duplicate_messages::DuplicateMessages;
+use futures::SinkExt;
+use genesis_change::OnGenesisChange;
 use http::Uri;
-use hyper::{Method, Response};
-use simple_logger::SimpleLogger;
+use hyper::{Body, Method, Request, Response};
+use json_message::{NodeProtocol, DEFAULT_NODE_PROTOCOL, LATEST_NODE_PROTOCOL};
+use metrics::Metrics;
+use payload_encoding::PayloadEncoding;
+use real_ip::{IpCidr, TrustedProxies};
+use rejection::Rejection;
 use structopt::StructOpt;
 
 #[cfg(not(target_env = "msvc"))]
@@ -47,6 +68,10 @@ use jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
+/// With '--dedupe-node-messages', a message that's identical to the one before it on the same
+/// connection is dropped if that one was passed on less than this long ago.
+const DUPLICATE_MESSAGE_WINDOW: Duration = Duration::from_secs(1);
+
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
 const NAME: &str = "Substrate Telemetry Backend Shard";
@@ -56,13 +81,42 @@ const ABOUT: &str = "This is the Telemetry Backend Shard that forwards the \
 #[derive(StructOpt, Debug)]
 #[structopt(name = NAME, version = VERSION, author = AUTHORS, about = ABOUT)]
 struct Opts {
+    /// A TOML file to load options from. Its keys are the long names of any of these options,
+    /// for example 'max-feeds = 100' or 'allow-chain = ["..."]', and options given on the
+    /// command line override those in the file.
+    #[structopt(long)]
+    config: Option<std::path::PathBuf>,
     /// This is the socket address that this shard is listening to. This is restricted to
     /// localhost (127.0.0.1) by default and should be fine for most use cases. If
     /// you are using Telemetry in a container, you likely want to set this to '0.0.0.0:8000'
     #[structopt(short = "l", long = "listen", default_value = "127.0.0.1:8001")]
     socket: std::net::SocketAddr,
+    /// If provided, serve "/health" and "/metrics" on this socket address rather than alongside
+    /// "/submit" on the '--listen' address. This allows node submissions to be exposed publicly
+    /// while health checks and metrics are kept on a private interface.
+    ///
+    /// Liveness and readiness probes are served wherever "/health" is. "/healthz" responds as
+    /// long as the shard is running, and "/readyz" responds with a "503 Service Unavailable"
+    /// while the shard isn't connected to the core or is draining.
+    ///
+    /// "/drain" is also served here (and only here). "POST /drain" puts the shard into draining
+    /// mode ahead of maintenance: new nodes are refused with a "503 Service Unavailable" so that
+    /// they connect to another shard, and if "?reconnect_after=<seconds>" is given, the nodes
+    /// that are already connected are disconnected after that long so that they reconnect
+    /// elsewhere. "DELETE /drain" stops draining, and "GET /drain" reports whether we are.
+    #[structopt(long)]
+    admin_listen: Option<std::net::SocketAddr>,
+    /// Like '--admin-listen', but serve "/health", "/metrics", "/drain" and the probes on a Unix
+    /// domain socket at this path instead (on Linux and macOS only), for instance so that they
+    /// can be reached from a sidecar container that the socket is mounted into. A stale socket
+    /// left at the path is replaced, but the shard won't start if anything else is there. The
+    /// socket is removed when the shard shuts down.
+    #[structopt(long, parse(from_os_str))]
+    admin_uds: Option<std::path::PathBuf>,
     /// The desired log level; one of 'error', 'warn', 'info', 'debug' or 'trace', where
-    /// 'error' only logs errors and 'trace' logs everything.
+    /// 'error' only logs errors and 'trace' logs everything. Levels for individual modules
+    /// can be set via the RUST_LOG environment variable, for example
+    /// 'RUST_LOG=telemetry_shard::aggregator=debug,info', which overrides this.
     #[structopt(long = "log", default_value = "info")]
     log_level: log::LevelFilter,
     /// Url to the Backend Core endpoint accepting shard connections
@@ -79,6 +133,21 @@ struct Opts {
     /// RAM by suggesting that it accounts for billions of nodes.
     #[structopt(long, default_value = "20")]
     max_nodes_per_connection: usize,
+    /// How many connections to the /submit endpoint can be open from a single IP address at
+    /// once? Further connections from that address are refused until some of them close.
+    #[structopt(long, default_value = "50")]
+    max_conns_per_ip: usize,
+    /// Work out the IP address of each node from the 'Forwarded', 'X-Forwarded-For' or
+    /// 'X-Real-IP' header added by a proxy in front of this shard, rather than using the
+    /// address of the connection. This address is used to locate the node and to apply
+    /// per-IP limits. Headers are only trusted on connections from '--trusted-proxies'.
+    #[structopt(long)]
+    trust_proxy_header: bool,
+    /// The address (such as '10.1.2.3') or CIDR range (such as '10.0.0.0/8') of a proxy
+    /// whose headers are trusted when '--trust-proxy-header' is given. Can be provided
+    /// multiple times.
+    #[structopt(long = "trusted-proxies", number_of_values = 1)]
+    trusted_proxies: Vec<IpCidr>,
     /// What is the maximum number of bytes per second, on average, that a connection from a
     /// node is allowed to send to a shard before it gets booted. This is averaged over a
     /// rolling window of 10 seconds, and so spikes beyond this limit are allowed as long as
@@ -96,23 +165,122 @@ struct Opts {
     /// on the machine. If no value is given, use an internal default that we have deemed sane.
     #[structopt(long)]
     worker_threads: Option<usize>,
+    /// Try to pin each worker thread to its own CPU. This is best-effort; a warning is
+    /// logged if it can't be done on this platform.
+    #[structopt(long)]
+    worker_cpu_affinity: bool,
     /// Roughly how long to wait in seconds for new telemetry data to arrive from a node. If
     /// telemetry for a node does not arrive in this time frame, we remove the corresponding node
     /// state, and if no messages are received on the connection at all in this time, it will be
     /// dropped.
     #[structopt(long, default_value = "60")]
     stale_node_timeout: u64,
+    /// What to do if the Backend Core speaks a different version of the internal protocol
+    /// to this shard; one of 'retry' (keep trying to reconnect, for instance while the core
+    /// is being upgraded) or 'exit' (stop the shard).
+    #[structopt(long, default_value = "retry")]
+    on_protocol_mismatch: OnProtocolMismatch,
+    /// An ID to identify this shard by. The Backend Core attributes each node connected through
+    /// this shard to it, which can help when diagnosing how load is spread across shards.
+    #[structopt(long)]
+    shard_id: Option<String>,
+    /// Nodes can ask to send compressed messages when they connect (via the 'telemetry-gzip'
+    /// or 'telemetry-brotli' websocket subprotocols). Compressed messages that decompress to
+    /// more than this size are ignored.
+    #[structopt(long, default_value = "512k")]
+    max_decompressed_message_size: ByteSize,
+    /// The largest message that a node can send to us. Nodes that send larger messages are
+    /// disconnected, without us buffering the message.
+    #[structopt(long, default_value = "1MiB")]
+    max_node_msg_bytes: ByteSize,
+    /// Messages from nodes that can't be parsed are ignored. Nodes that send this many of them in
+    /// a row are disconnected. "0" never disconnects nodes for this.
+    #[structopt(long, default_value = "20")]
+    max_consecutive_malformed_messages: usize,
+    /// Drop messages from nodes that are identical to the message before them on the same
+    /// connection, if that was received within the last second. Some nodes resend the same
+    /// message many times a second, which tells us nothing new. Dropped messages are counted.
+    #[structopt(long)]
+    dedupe_node_messages: bool,
+    /// The oldest version of the node protocol (the format that nodes send telemetry in) to
+    /// accept. Nodes say which version they speak when they connect, and are disconnected if
+    /// it's outside of '--min-node-protocol' to '--max-node-protocol'.
+    #[structopt(long, default_value = "1")]
+    min_node_protocol: NodeProtocol,
+    /// The newest version of the node protocol to accept. If no value is given, this is the
+    /// latest version that this shard understands.
+    #[structopt(long)]
+    max_node_protocol: Option<NodeProtocol>,
+    /// What to do if a node sends a second 'system.connected' message with a different genesis
+    /// hash to its first; one of 'ignore' (keep the node on the chain it first said it was on),
+    /// 'readd' (remove the node from that chain and add it to the new one) or 'reject' (close
+    /// the connection, telling the node why).
+    #[structopt(long, default_value = "ignore")]
+    on_genesis_change: OnGenesisChange,
+    /// Nodes say when they sent each message, according to their own clocks. Nodes whose clocks
+    /// are more than this many seconds ahead of or behind ours are flagged as having skewed
+    /// clocks, and a warning is logged. "0" turns this off.
+    #[structopt(long, default_value = "30")]
+    max_clock_skew: u64,
+    /// Accept at most this many new node connections per second. Connections beyond this rate
+    /// are left waiting to be accepted rather than refused, so that a flood of nodes reconnecting
+    /// at once is spread out over time. If no value is given, there is no limit.
+    #[structopt(long)]
+    max_accepts_per_sec: Option<u32>,
+    /// How many node connections the OS can queue up for us to accept. If no value is given,
+    /// the system default is used.
+    #[structopt(long)]
+    listen_backlog: Option<u32>,
+    /// How many accepted node connections can be waiting to be upgraded to websockets at once.
+    /// Connections beyond this are answered straight away with a "503 Service Unavailable"
+    /// asking them to try again later, rather than being left hanging. If no value is given,
+    /// there is no limit.
+    #[structopt(long)]
+    max_pending_connections: Option<usize>,
+    /// How many messages from node connections can be queued up for the shard's aggregator.
+    /// When this is full, node connections wait for room, and stop reading from their sockets
+    /// until there is some. Larger values absorb bursts better, at the cost of memory.
+    #[structopt(long, default_value = "10")]
+    aggregator_channel_capacity: usize,
+    /// How many messages from a single node connection can be waiting to be passed on to the
+    /// shard's aggregator. Messages back up like this when the core can't keep up. A node that
+    /// sends more than this before its earlier messages are passed on is disconnected, and told
+    /// that it was rate limited, rather than having its messages buffered without limit. If no
+    /// value is given, there is no limit.
+    #[structopt(long)]
+    max_node_backlog: Option<usize>,
+    /// How many messages can be queued up to be sent to the Backend Core. When this is full,
+    /// the shard's aggregator waits for room (and so, in turn, do node connections). While the
+    /// shard is disconnected from the core, queued messages are thrown away.
+    #[structopt(long, default_value = "10")]
+    core_channel_capacity: usize,
+    /// Enable TCP keepalive on node connections and on our connection to the core, probing
+    /// them once they've been idle for this many seconds so that dead ones are noticed.
+    #[structopt(long)]
+    tcp_keepalive: Option<u64>,
+    /// Time how long every Nth message from nodes takes to be passed on to the core, and
+    /// expose percentiles of this on "/metrics". "0" turns this off.
+    #[structopt(long, default_value = "100")]
+    latency_sample_every: u64,
+    /// Percentiles of the time taken to pass messages on are reported over windows of this
+    /// many seconds.
+    #[structopt(long, default_value = "60")]
+    latency_window: u64,
+    /// Present this secret to the core when connecting to it. Cores started with a
+    /// '--shard-secret' only accept shards that present the same secret.
+    #[structopt(long)]
+    shard_secret: Option<String>,
 }
 
 fn main() {
-    let opts = Opts::from_args();
+    let opts: Opts = config_file::from_args();
 
-    SimpleLogger::new()
-        .with_level(opts.log_level)
-        .init()
-        .expect("Must be able to start a logger");
+    common::logging::init(opts.log_level);
 
     log::info!("Starting Telemetry Shard version: {}", VERSION);
+    if let Some(path) = &opts.config {
+        log::info!("Loaded options from {}", path.display());
+    }
 
     let worker_threads = match opts.worker_threads {
         Some(0) => num_cpus::get(),
@@ -122,89 +290,387 @@ fn main() {
         None => usize::min(num_cpus::get(), 4),
     };
 
-    tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .worker_threads(worker_threads)
-        .thread_name("telemetry_shard_worker")
-        .build()
-        .unwrap()
-        .block_on(async {
-            if let Err(e) = start_server(opts).await {
-                log::error!("Error starting server: {}", e);
-            }
-        });
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime::configure_worker_threads(
+        &mut runtime,
+        "tel-shard-worker",
+        worker_threads,
+        opts.worker_cpu_affinity,
+    )
+    .enable_all()
+    .worker_threads(worker_threads)
+    .build()
+    .unwrap()
+    .block_on(async {
+        if let Err(e) = start_server(opts).await {
+            log::error!("Error starting server: {}", e);
+        }
+    });
 }
 
 /// Declare our routes and start the server.
 async fn start_server(opts: Opts) -> anyhow::Result<()> {
+    let tcp_keepalive = opts.tcp_keepalive.map(Duration::from_secs);
     let block_list = BlockedAddrs::new(Duration::from_secs(opts.node_block_seconds));
-    let aggregator = Aggregator::spawn(opts.core_url).await?;
+    let connection_counts = ConnectionCounts::new(opts.max_conns_per_ip);
+    let trusted_proxies = match (opts.trust_proxy_header, opts.trusted_proxies) {
+        (false, _) => TrustedProxies::default(),
+        (true, cidrs) if cidrs.is_empty() => {
+            anyhow::bail!("'--trust-proxy-header' requires at least one '--trusted-proxies'")
+        }
+        (true, cidrs) => TrustedProxies::new(cidrs),
+    };
+    let metrics = Metrics::new(
+        opts.latency_sample_every,
+        Duration::from_secs(opts.latency_window),
+    );
+    let aggregator = Aggregator::spawn(
+        opts.core_url,
+        opts.on_protocol_mismatch,
+        opts.shard_id.map(Into::into),
+        opts.aggregator_channel_capacity,
+        opts.core_channel_capacity,
+        tcp_keepalive,
+        opts.shard_secret.map(Into::into),
+        metrics.clone(),
+    )
+    .await?;
     let socket_addr = opts.socket;
     let max_nodes_per_connection = opts.max_nodes_per_connection;
     let bytes_per_second = opts.max_node_data_per_second;
     let stale_node_timeout = Duration::from_secs(opts.stale_node_timeout);
+    let max_decompressed_message_size = opts.max_decompressed_message_size;
+    let max_consecutive_malformed_messages = opts.max_consecutive_malformed_messages;
+    let max_node_backlog = opts.max_node_backlog;
+    if max_node_backlog == Some(0) {
+        anyhow::bail!("'--max-node-backlog' must be at least 1");
+    }
+    let max_node_protocol = opts.max_node_protocol.unwrap_or(LATEST_NODE_PROTOCOL);
+    if max_node_protocol > LATEST_NODE_PROTOCOL {
+        anyhow::bail!(
+            "'--max-node-protocol' can be at most {LATEST_NODE_PROTOCOL}, the latest version that this shard understands"
+        );
+    }
+    if opts.min_node_protocol > max_node_protocol {
+        anyhow::bail!("'--min-node-protocol' can't be more than '--max-node-protocol'");
+    }
+    let node_protocols = opts.min_node_protocol..=max_node_protocol;
+    let on_genesis_change = opts.on_genesis_change;
+    let dedupe_window = opts
+        .dedupe_node_messages
+        .then_some(DUPLICATE_MESSAGE_WINDOW);
+    let max_clock_skew = match opts.max_clock_skew {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let node_ws_opts = http_utils::WsUpgradeOpts {
+        protocols: payload_encoding::SUBPROTOCOLS,
+        max_message_size: Some(opts.max_node_msg_bytes.num_bytes()),
+    };
 
-    let server = http_utils::start_server(socket_addr, move |addr, req| {
-        let aggregator = aggregator.clone();
-        let block_list = block_list.clone();
-        async move {
-            match (req.method(), req.uri().path().trim_end_matches('/')) {
-                // Check that the server is up and running:
-                (&Method::GET, "/health") => Ok(Response::new("OK".into())),
-                // Nodes send messages here:
-                (&Method::GET, "/submit") => {
-                    let (real_addr, real_addr_source) = real_ip::real_ip(addr, req.headers());
-
-                    if let Some(reason) = block_list.blocked_reason(&real_addr) {
-                        return Ok(Response::builder().status(403).body(reason.into()).unwrap());
+    // Health checks and metrics are served on the admin address if we're given one, and
+    // alongside node submissions if not:
+    let (admin_socket_addr, admin_uds) = match (opts.admin_listen, opts.admin_uds) {
+        (Some(_), Some(_)) => {
+            anyhow::bail!("Only one of '--admin-listen' and '--admin-uds' can be given")
+        }
+        admin => admin,
+    };
+    let serve_admin_routes = admin_socket_addr.is_none() && admin_uds.is_none();
+    let admin_metrics = metrics.clone();
+    let admin_aggregator = aggregator.clone();
+
+    let server_opts = http_utils::ServerOpts {
+        max_accepts_per_sec: opts.max_accepts_per_sec,
+        tcp_keepalive,
+        listen_backlog: opts.listen_backlog,
+        max_pending_connections: opts.max_pending_connections,
+    };
+
+    let (_, server) = http_utils::bind_server_with_opts(
+        socket_addr,
+        server_opts,
+        move |addr, req| {
+            let aggregator = aggregator.clone();
+            let block_list = block_list.clone();
+            let connection_counts = connection_counts.clone();
+            let trusted_proxies = trusted_proxies.clone();
+            let metrics = metrics.clone();
+            let node_protocols = node_protocols.clone();
+            async move {
+                match (req.method(), req.uri().path().trim_end_matches('/')) {
+                    (_, "/health" | "/healthz" | "/readyz" | "/metrics") if serve_admin_routes => {
+                        Ok(admin_response(&req, &metrics, &aggregator))
                     }
+                    // Nodes send messages here:
+                    (&Method::GET, "/submit") => {
+                        if aggregator.is_draining() {
+                            return Ok(Response::builder()
+                                .status(503)
+                                .body(Rejection::Draining.to_json().into())
+                                .unwrap());
+                        }
 
-                    Ok(http_utils::upgrade_to_websocket(
-                        req,
-                        move |ws_send, ws_recv| async move {
-                            log::info!(
-                                "Opening /submit connection from {:?} (address source: {})",
+                        let (real_addr, real_addr_source) =
+                            real_ip::real_ip(addr, req.headers(), &trusted_proxies);
+                        let node_token = node_token(&req);
+
+                        if let Some(reason) = block_list.blocked_reason(&real_addr) {
+                            log::debug!(
+                                "Refusing /submit connection from {:?}: {}",
                                 real_addr,
-                                real_addr_source
+                                reason
                             );
-                            let tx_to_aggregator = aggregator.subscribe_node();
-                            let (mut tx_to_aggregator, mut ws_send) =
-                                handle_node_websocket_connection(
+                            return Ok(Response::builder()
+                                .status(403)
+                                .body(Rejection::RateLimited.to_json().into())
+                                .unwrap());
+                        }
+
+                        // This is counted against the address until the connection
+                        // is dropped, however that happens:
+                        let connection_guard = match connection_counts.try_connect(real_addr) {
+                            Some(guard) => guard,
+                            None => {
+                                log::info!(
+                                    "Refusing /submit connection from {:?}: too many connections from this address",
+                                    real_addr
+                                );
+                                return Ok(Response::builder()
+                                    .status(429)
+                                    .body(Rejection::RateLimited.to_json().into())
+                                    .unwrap());
+                            }
+                        };
+
+                        Ok(http_utils::upgrade_to_websocket_with_opts(
+                            req,
+                            node_ws_opts,
+                            move |ws_send, ws_recv, protocol| async move {
+                                log::info!(
+                                    "Opening /submit connection from {:?} (address source: {})",
                                     real_addr,
-                                    ws_send,
-                                    ws_recv,
-                                    tx_to_aggregator,
-                                    max_nodes_per_connection,
-                                    bytes_per_second,
-                                    block_list,
-                                    stale_node_timeout,
-                                )
-                                .await;
-                            log::info!(
-                                "Closing /submit connection from {:?} (address source: {})",
-                                real_addr,
-                                real_addr_source
-                            );
-                            // Tell the aggregator that this connection has closed, so it can tidy up.
-                            let _ = tx_to_aggregator.send(FromWebsocket::Disconnected).await;
-                            let _ = ws_send.close().await;
-                        },
-                    ))
+                                    real_addr_source
+                                );
+                                let payload_encoding = PayloadEncoding::from_subprotocol(protocol);
+                                let tx_to_aggregator = aggregator.subscribe_node();
+                                let (mut tx_to_aggregator, mut ws_send, rejection) =
+                                    handle_node_websocket_connection(
+                                        real_addr,
+                                        ws_send,
+                                        ws_recv,
+                                        tx_to_aggregator,
+                                        max_nodes_per_connection,
+                                        bytes_per_second,
+                                        block_list,
+                                        stale_node_timeout,
+                                        payload_encoding,
+                                        max_decompressed_message_size,
+                                        max_consecutive_malformed_messages,
+                                        max_node_backlog,
+                                        dedupe_window,
+                                        max_clock_skew,
+                                        node_protocols,
+                                        on_genesis_change,
+                                        metrics,
+                                        node_token,
+                                    )
+                                    .await;
+                                log::info!(
+                                    "Closing /submit connection from {:?} (address source: {})",
+                                    real_addr,
+                                    real_addr_source
+                                );
+                                // Tell the node why it's being disconnected, if we know, so that it
+                                // can decide whether and where to reconnect. This is done first,
+                                // since the aggregator may be backed up and slow to hear about it:
+                                if let Some(rejection) = rejection {
+                                    let _ = ws_send.send_text(rejection.to_json()).await;
+                                    let _ = ws_send.flush().await;
+                                }
+                                let _ = ws_send.close().await;
+                                // Tell the aggregator that this connection has closed, so it can tidy up.
+                                let reason = rejection
+                                    .map_or(RemovalReason::Disconnected, Rejection::removal_reason);
+                                let _ = tx_to_aggregator
+                                    .send(FromWebsocket::Disconnected { reason })
+                                    .await;
+                                drop(connection_guard);
+                            },
+                        ))
+                    }
+                    // 404 for anything else:
+                    _ => Ok(not_found()),
                 }
-                // 404 for anything else:
-                _ => Ok(Response::builder()
-                    .status(404)
-                    .body("Not found".into())
-                    .unwrap()),
             }
+        },
+        futures::future::pending(),
+    )?;
+
+    let admin_handler = move |req: Request<Body>| {
+        let metrics = admin_metrics.clone();
+        let aggregator = admin_aggregator.clone();
+        async move {
+            if req.uri().path().trim_end_matches('/') == "/drain" {
+                return Ok(drain_response(&req, &aggregator).await);
+            }
+            Ok(admin_response(&req, &metrics, &aggregator))
+        }
+    };
+
+    let servers = async move {
+        match (admin_socket_addr, admin_uds) {
+            (Some(admin_socket_addr), _) => {
+                let (_, admin_server) = http_utils::bind_server(
+                    admin_socket_addr,
+                    move |_addr, req| admin_handler(req),
+                    futures::future::pending(),
+                )?;
+                futures::future::try_join(server, admin_server).await?;
+            }
+            #[cfg(unix)]
+            (None, Some(admin_uds)) => {
+                let admin_server = http_utils::bind_unix_server(
+                    &admin_uds,
+                    admin_handler,
+                    futures::future::pending(),
+                )?;
+                futures::future::try_join(server, admin_server).await?;
+            }
+            #[cfg(not(unix))]
+            (None, Some(_)) => anyhow::bail!("'--admin-uds' is only supported on Unix"),
+            (None, None) => server.await?,
+        }
+        Ok(())
+    };
+
+    // Stopping the servers rather than just exiting gives them the chance to tidy up
+    // (removing the '--admin-uds' socket, for instance):
+    tokio::select! {
+        res = servers => res,
+        _ = shutdown_signal() => {
+            log::info!("Shutting down");
+            Ok(())
+        }
+    }
+}
+
+/// Wait until we're asked to shut down, via Ctrl-C or (on Unix) SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => {},
+                _ = terminate.recv() => {},
+            },
+            Err(e) => {
+                log::warn!("Cannot listen for SIGTERM: {e}");
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Respond to requests that don't need to be exposed alongside node submissions, and so
+/// are served on the '--admin-listen' address (or '--admin-uds' socket) if one is given.
+fn admin_response(
+    req: &Request<Body>,
+    metrics: &Metrics,
+    aggregator: &Aggregator,
+) -> Response<Body> {
+    match (req.method(), req.uri().path().trim_end_matches('/')) {
+        // Check that the server is up and running:
+        (&Method::GET, "/health" | "/healthz") => Response::new("OK".into()),
+        // Check that we're ready to take nodes; we aren't while we're not connected to the
+        // core (including while starting up), or while we're draining:
+        (&Method::GET, "/readyz") => {
+            let not_ready = if !aggregator.is_connected_to_core() {
+                Some("Not connected to the telemetry core")
+            } else if aggregator.is_draining() {
+                Some("Draining")
+            } else {
+                None
+            };
+            match not_ready {
+                Some(reason) => Response::builder().status(503).body(reason.into()).unwrap(),
+                None => Response::new("OK".into()),
+            }
+        }
+        // Return metrics in a prometheus-friendly text based format:
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(metrics.to_prometheus().into())
+            .unwrap(),
+        _ => not_found(),
+    }
+}
+
+/// Start or stop draining, or report whether we're draining. This is only served on the
+/// '--admin-listen' address (or '--admin-uds' socket), so that it's never exposed alongside
+/// node submissions.
+async fn drain_response(req: &Request<Body>, aggregator: &Aggregator) -> Response<Body> {
+    match *req.method() {
+        Method::GET => {}
+        Method::POST => {
+            let reconnect_after = match reconnect_after(req.uri()) {
+                Ok(reconnect_after) => reconnect_after,
+                Err(e) => {
+                    return Response::builder()
+                        .status(400)
+                        .body(e.to_string().into())
+                        .unwrap()
+                }
+            };
+            aggregator.set_draining(true, reconnect_after).await;
+        }
+        Method::DELETE => aggregator.set_draining(false, None).await,
+        _ => {
+            return Response::builder()
+                .status(405)
+                .body("Method not allowed".into())
+                .unwrap()
         }
+    }
+
+    let body = serde_json::json!({ "draining": aggregator.is_draining() });
+    Response::builder()
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(body.to_string().into())
+        .unwrap()
+}
+
+/// Parse the optional "reconnect_after=<seconds>" query parameter given to "POST /drain".
+fn reconnect_after(uri: &Uri) -> anyhow::Result<Option<Duration>> {
+    let secs = uri.query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|param| param.strip_prefix("reconnect_after="))
     });
+    match secs {
+        Some(secs) => match secs.parse() {
+            Ok(secs) => Ok(Some(Duration::from_secs(secs))),
+            Err(_) => {
+                anyhow::bail!("Invalid reconnect_after '{secs}'; expected a number of seconds")
+            }
+        },
+        None => Ok(None),
+    }
+}
 
-    server.await?;
-    Ok(())
+fn not_found() -> Response<Body> {
+    Response::builder()
+        .status(404)
+        .body("Not found".into())
+        .unwrap()
 }
 
 /// This takes care of handling messages from an established socket connection.
+#[allow(clippy::too_many_arguments)]
 async fn handle_node_websocket_connection<S>(
     real_addr: IpAddr,
     ws_send: http_utils::WsSender,
@@ -214,7 +680,17 @@ async fn handle_node_websocket_connection<S>(
     bytes_per_second: ByteSize,
     block_list: BlockedAddrs,
     stale_node_timeout: Duration,
-) -> (S, http_utils::WsSender)
+    payload_encoding: PayloadEncoding,
+    max_decompressed_message_size: ByteSize,
+    max_consecutive_malformed_messages: usize,
+    max_node_backlog: Option<usize>,
+    dedupe_window: Option<Duration>,
+    max_clock_skew: Option<Duration>,
+    node_protocols: RangeInclusive<NodeProtocol>,
+    on_genesis_change: OnGenesisChange,
+    metrics: Metrics,
+    node_token: Option<Box<str>>,
+) -> (S, http_utils::WsSender, Option<Rejection>)
 where
     S: futures::Sink<FromWebsocket, Error = anyhow::Error> + Unpin + Send + 'static,
 {
@@ -222,6 +698,12 @@ where
     // `max_nodes_per_connection` before ignoring others.
     let mut allowed_message_ids = HashMap::<NodeMessageId, Instant>::new();
 
+    // Keep track of the version of the node protocol that each of these nodes speaks:
+    let mut node_protocol_versions = HashMap::<NodeMessageId, NodeProtocol>::new();
+
+    // Keep track of the chain that each of these nodes said it was on:
+    let mut node_genesis_hashes = HashMap::<NodeMessageId, BlockHash>::new();
+
     // Limit the number of bytes based on a rolling total and the incoming bytes per second
     // that has been configured via the CLI opts.
     let bytes_per_second = bytes_per_second.num_bytes();
@@ -230,6 +712,19 @@ where
         .window_size_multiple(10)
         .start();
 
+    // Count the messages that we couldn't parse, in total and since the last one we could.
+    let mut malformed_messages = 0;
+    let mut consecutive_malformed_messages = 0;
+
+    // Spot messages that repeat the one before them, if we're dropping those:
+    let mut duplicate_messages = dedupe_window.map(DuplicateMessages::new);
+
+    // Keep track of which nodes have clocks that are too far out, if we're checking:
+    let mut clock_skews = max_clock_skew.map(ClockSkews::new);
+
+    // Pick which messages to time on their way to the core:
+    let mut latency_sampler = metrics.latency_sampler();
+
     // This could be a oneshot channel, but it's useful to be able to clone
     // messages, and we can't clone oneshot channel senders.
     let (close_connection_tx, close_connection_rx) = flume::bounded(1);
@@ -240,35 +735,57 @@ where
     };
     if let Err(e) = tx_to_aggregator.send(init_msg).await {
         log::error!("Shutting down websocket connection from {real_addr:?}: Error sending message to aggregator: {e}");
-        return (tx_to_aggregator, ws_send);
+        return (tx_to_aggregator, ws_send, None);
     }
 
     // Receiving data isn't cancel safe, so let it happen in a separate task.
-    // If this loop ends, the outer will receive a `None` message and end too.
+    // If this loop ends, the outer will find the channel closed and end too.
     // If the outer loop ends, it fires a msg on `close_connection_rx` to ensure this ends too.
-    let (ws_tx_atomic, mut ws_rx_atomic) = futures::channel::mpsc::unbounded();
-    tokio::task::spawn(async move {
+    // The task hands back the reason that the connection was closed for, if it was given one.
+    // At most `max_node_backlog` messages (if given) can be waiting to be handled; if any more
+    // arrive, `backlog_full_tx` is fired and the connection is closed.
+    let (ws_tx_atomic, ws_rx_atomic) = match max_node_backlog {
+        Some(max_node_backlog) => flume::bounded(max_node_backlog),
+        None => flume::unbounded(),
+    };
+    let (backlog_full_tx, backlog_full_rx) = tokio::sync::oneshot::channel();
+    let backlog_metrics = metrics.clone();
+    let mut recv_task = tokio::task::spawn(async move {
         loop {
             let mut bytes = Vec::new();
             tokio::select! {
                 // The close channel has fired, so end the loop. `ws_recv.receive_data` is
                 // *not* cancel safe, but since we're closing the connection we don't care.
-                _ = close_connection_rx.recv_async() => {
+                rejection = close_connection_rx.recv_async() => {
                     log::info!("connection to {real_addr:?} being closed");
-                    break
+                    break rejection.ok().flatten()
                 },
                 // Receive data and relay it on to our main select loop below.
                 msg_info = ws_recv.receive_data(&mut bytes) => {
                     if let Err(soketto::connection::Error::Closed) = msg_info {
-                        break;
+                        break None;
                     }
                     if let Err(e) = msg_info {
-                        log::error!("Shutting down websocket connection from {real_addr:?}: Failed to receive data: {e}");
-                        break;
+                        if is_message_too_large(&e) {
+                            log::warn!("Shutting down websocket connection from {real_addr:?}: Message exceeds '--max-node-msg-bytes': {e}");
+                        } else {
+                            log::error!("Shutting down websocket connection from {real_addr:?}: Failed to receive data: {e}");
+                        }
+                        break None;
                     }
-                    if ws_tx_atomic.unbounded_send(bytes).is_err() {
+                    match ws_tx_atomic.try_send(bytes) {
+                        Ok(()) => {}
+                        // The node is sending messages faster than we can pass them on, which
+                        // happens if the core can't keep up. Rather than buffer its messages
+                        // without limit, we disconnect it:
+                        Err(flume::TrySendError::Full(_)) => {
+                            backlog_metrics.record_node_disconnected_for_backlog();
+                            log::warn!("Shutting down websocket connection from {real_addr:?}: More than {} messages are waiting to be passed on", max_node_backlog.unwrap_or_default());
+                            let _ = backlog_full_tx.send(());
+                            break Some(Rejection::RateLimited);
+                        }
                         // The other end closed; end this loop.
-                        break;
+                        Err(flume::TrySendError::Disconnected(_)) => break None,
                     }
                 }
             }
@@ -279,115 +796,423 @@ where
     let mut stale_interval = tokio::time::interval(stale_node_timeout / 2);
 
     // Our main select loop atomically receives and handles telemetry messages from the node,
-    // and periodically checks for stale connections to keep our node state tidy.
-    loop {
-        tokio::select! {
-            // We periodically check for stale message IDs and remove nodes associated with
-            // them, to prevent a buildup. We boot the whole connection if no interpretable
-            // messages have been sent at all in the time period.
-            _ = stale_interval.tick() => {
-                let stale_ids: Vec<NodeMessageId> = allowed_message_ids.iter()
-                    .filter(|(_, last_seen)| last_seen.elapsed() > stale_node_timeout)
-                    .map(|(&id, _)| id)
-                    .collect();
-
-                for &message_id in &stale_ids {
-                    log::info!("Removing stale node with message ID {message_id} from {real_addr:?}");
-                    allowed_message_ids.remove(&message_id);
-                    let _ = tx_to_aggregator.send(FromWebsocket::Remove { message_id } ).await;
-                }
-
-                if !stale_ids.is_empty() && allowed_message_ids.is_empty() {
-                    // End the entire connection if no recent messages came in for any ID.
-                    log::info!("Closing stale connection from {real_addr:?}");
-                    break;
-                }
-            },
-            // Handle messages received by the connected node.
-            msg = ws_rx_atomic.next() => {
-                // No more messages? break.
-                let bytes = match msg {
-                    Some(bytes) => bytes,
-                    None => { break; }
-                };
+    // and periodically checks for stale connections to keep our node state tidy. If we close
+    // the connection for a reason that the node should know about, it's handed back.
+    let main_loop = async {
+        let mut rejection = None;
+        loop {
+            tokio::select! {
+                // We periodically check for stale message IDs and remove nodes associated with
+                // them, to prevent a buildup. We boot the whole connection if no interpretable
+                // messages have been sent at all in the time period.
+                _ = stale_interval.tick() => {
+                    let stale_ids: Vec<NodeMessageId> = allowed_message_ids.iter()
+                        .filter(|(_, last_seen)| last_seen.elapsed() > stale_node_timeout)
+                        .map(|(&id, _)| id)
+                        .collect();
 
-                // Keep track of total bytes and bail if average over last 10 secs exceeds preference.
-                rolling_total_bytes.push(bytes.len());
-                let this_bytes_per_second = rolling_total_bytes.total() / 10;
-                if this_bytes_per_second > bytes_per_second {
-                    block_list.block_addr(real_addr, "Too much traffic");
-                    log::error!("Shutting down websocket connection: Too much traffic ({this_bytes_per_second}bps averaged over last 10s)");
-                    break;
-                }
+                    for &message_id in &stale_ids {
+                        log::info!("Removing stale node with message ID {message_id} from {real_addr:?}");
+                        allowed_message_ids.remove(&message_id);
+                        node_protocol_versions.remove(&message_id);
+                        node_genesis_hashes.remove(&message_id);
+                        if let Some(clock_skews) = &mut clock_skews {
+                            clock_skews.remove(message_id);
+                        }
+                        let _ = tx_to_aggregator.send(FromWebsocket::Remove { message_id } ).await;
+                    }
 
-                // Deserialize from JSON, warning in debug mode if deserialization fails:
-                let node_message: json_message::NodeMessage = match serde_json::from_slice(&bytes) {
-                    Ok(node_message) => node_message,
-                    #[cfg(debug)]
-                    Err(e) => {
-                        let bytes: &[u8] = bytes.get(..512).unwrap_or_else(|| &bytes);
-                        let msg_start = std::str::from_utf8(bytes).unwrap_or_else(|_| "INVALID UTF8");
-                        log::warn!("Failed to parse node message ({msg_start}): {e}");
-                        continue;
-                    },
-                    #[cfg(not(debug))]
-                    Err(_) => {
-                        continue;
+                    if !stale_ids.is_empty() && allowed_message_ids.is_empty() {
+                        // End the entire connection if no recent messages came in for any ID.
+                        log::info!("Closing stale connection from {real_addr:?}");
+                        break;
                     }
-                };
+                },
+                // Handle messages received by the connected node.
+                msg = ws_rx_atomic.recv_async() => {
+                    // No more messages? break.
+                    let bytes = match msg {
+                        Ok(bytes) => bytes,
+                        Err(_) => {
+                            rejection = (&mut recv_task).await.unwrap_or(None);
+                            break;
+                        }
+                    };
 
-                // Pull relevant details from the message:
-                let node_message: node_message::NodeMessage = node_message.into();
-                let message_id = node_message.id();
-                let payload = node_message.into_payload();
-
-                // Until the aggregator receives an `Add` message, which we can create once
-                // we see one of these SystemConnected ones, it will ignore messages with
-                // the corresponding message_id.
-                if let node_message::Payload::SystemConnected(info) = payload {
-                    // Too many nodes seen on this connection? Ignore this one.
-                    if allowed_message_ids.len() >= max_nodes_per_connection {
-                        log::info!("Ignoring new node with ID {message_id} from {real_addr:?} (we've hit the max of {max_nodes_per_connection} nodes per connection)");
-                        continue;
+                    // Sampled messages are timed from here, as soon as they've been read in full:
+                    let received_at = latency_sampler.sample();
+
+                    // Keep track of total bytes and bail if average over last 10 secs exceeds preference.
+                    rolling_total_bytes.push(bytes.len());
+                    let this_bytes_per_second = rolling_total_bytes.total() / 10;
+                    if this_bytes_per_second > bytes_per_second {
+                        block_list.block_addr(real_addr, "Too much traffic");
+                        log::error!("Shutting down websocket connection: Too much traffic ({this_bytes_per_second}bps averaged over last 10s)");
+                        rejection = Some(Rejection::RateLimited);
+                        break;
                     }
 
-                    // Note of the message ID, allowing telemetry for it.
-                    let prev_join_time = allowed_message_ids.insert(message_id, Instant::now());
-                    if prev_join_time.is_some() {
-                        log::info!("Ignoring duplicate new node with ID {message_id} from {real_addr:?}");
-                        continue;
+                    // Decompress the message if the node asked to send compressed messages:
+                    let bytes = match payload_encoding.decode(&bytes, max_decompressed_message_size.num_bytes()) {
+                        Ok(bytes) => bytes,
+                        Err(e) => {
+                            log::warn!("Ignoring message from {real_addr:?}: {e}");
+                            continue;
+                        }
+                    };
+
+                    // Drop messages that are the same as the one before them, before doing any more
+                    // work on them:
+                    if let Some(duplicate_messages) = &mut duplicate_messages {
+                        if duplicate_messages.is_duplicate(&bytes, Instant::now()) {
+                            metrics.record_duplicate_node_message();
+                            continue;
+                        }
                     }
 
-                    // Tell the aggregator loop about the new node.
-                    log::info!("Adding node with message ID {message_id} from {real_addr:?}");
-                    let _ = tx_to_aggregator.send(FromWebsocket::Add {
-                        message_id,
-                        ip: real_addr,
-                        node: info.node,
-                        genesis_hash: info.genesis_hash,
-                    }).await;
-                }
-                // Anything that's not an "Add" is an Update. The aggregator will ignore
-                // updates against a message_id that hasn't first been Added, above.
-                else {
-                    if let Some(last_seen) = allowed_message_ids.get_mut(&message_id) {
-                        *last_seen = Instant::now();
-                        if let Err(e) = tx_to_aggregator.send(FromWebsocket::Update { message_id, payload } ).await {
-                            log::error!("Failed to send node message to aggregator: {e}");
+                    // Deserialize from JSON, ignoring messages that we can't make sense of unless
+                    // the node sends too many of them in a row:
+                    let node_protocol = |message_id| {
+                        node_protocol_versions.get(&message_id).copied().unwrap_or(DEFAULT_NODE_PROTOCOL)
+                    };
+                    let (node_message, sent_at, protocol) = match json_message::parse(&bytes, node_protocol) {
+                        Ok(parsed) => {
+                            consecutive_malformed_messages = 0;
+                            parsed
+                        },
+                        Err(e) => {
+                            metrics.record_malformed_node_message();
+                            malformed_messages += 1;
+                            consecutive_malformed_messages += 1;
+                            log::debug!("Ignoring message from {real_addr:?} ({malformed_messages} malformed so far): {e}");
+
+                            if max_consecutive_malformed_messages > 0 && consecutive_malformed_messages >= max_consecutive_malformed_messages {
+                                metrics.record_node_disconnected_for_malformed_messages();
+                                log::warn!("Shutting down websocket connection from {real_addr:?}: {consecutive_malformed_messages} malformed messages in a row");
+                                break;
+                            }
+                            continue;
+                        }
+                    };
+
+                    // Pull relevant details from the message:
+                    let message_id = node_message.id();
+                    let payload = node_message.into_payload();
+
+                    // Until the aggregator receives an `Add` message, which we can create once
+                    // we see one of these SystemConnected ones, it will ignore messages with
+                    // the corresponding message_id.
+                    if let node_message::Payload::SystemConnected(info) = payload {
+                        // Nodes that speak a version of the protocol that we don't accept are told so:
+                        if !node_protocols.contains(&protocol) {
+                            log::warn!("Shutting down websocket connection from {real_addr:?}: Node with ID {message_id} speaks version {protocol} of the node protocol, which isn't between '--min-node-protocol' and '--max-node-protocol'");
+                            rejection = Some(Rejection::UnsupportedProtocol);
+                            break;
+                        }
+
+                        // A node can't move to another chain, so one saying that it has is dealt
+                        // with according to '--on-genesis-change':
+                        let genesis_changed = node_genesis_hashes
+                            .get(&message_id)
+                            .is_some_and(|&genesis_hash| genesis_hash != info.genesis_hash);
+                        if genesis_changed {
+                            match on_genesis_change {
+                                OnGenesisChange::Ignore => {
+                                    log::info!("Ignoring new genesis hash from node with ID {message_id} from {real_addr:?}");
+                                    continue;
+                                }
+                                OnGenesisChange::Reject => {
+                                    log::warn!("Shutting down websocket connection from {real_addr:?}: Node with ID {message_id} changed its genesis hash");
+                                    rejection = Some(Rejection::GenesisChanged);
+                                    break;
+                                }
+                                OnGenesisChange::Readd => {
+                                    log::info!("Node with ID {message_id} from {real_addr:?} changed its genesis hash; adding it again");
+                                    allowed_message_ids.remove(&message_id);
+                                    let _ = tx_to_aggregator.send(FromWebsocket::Remove { message_id }).await;
+                                }
+                            }
+                        }
+
+                        // Too many nodes seen on this connection? Ignore this one.
+                        if allowed_message_ids.len() >= max_nodes_per_connection {
+                            log::info!("Ignoring new node with ID {message_id} from {real_addr:?} (we've hit the max of {max_nodes_per_connection} nodes per connection)");
+                            continue;
+                        }
+
+                        // Note of the message ID, allowing telemetry for it.
+                        let prev_join_time = allowed_message_ids.insert(message_id, Instant::now());
+                        if prev_join_time.is_some() {
+                            log::info!("Ignoring duplicate new node with ID {message_id} from {real_addr:?}");
+                            continue;
+                        }
+                        node_protocol_versions.insert(message_id, protocol);
+                        node_genesis_hashes.insert(message_id, info.genesis_hash);
+
+                        // Tell the aggregator loop about the new node.
+                        log::info!("Adding node with message ID {message_id} from {real_addr:?}");
+                        let _ = tx_to_aggregator.send(FromWebsocket::Add {
+                            message_id,
+                            ip: real_addr,
+                            node: info.node,
+                            genesis_hash: info.genesis_hash,
+                            token: node_token.clone(),
+                        }).await;
+                    }
+                    // Anything that's not an "Add" is an Update. The aggregator will ignore
+                    // updates against a message_id that hasn't first been Added, above.
+                    else {
+                        if let Some(last_seen) = allowed_message_ids.get_mut(&message_id) {
+                            *last_seen = Instant::now();
+                            if let Err(e) = tx_to_aggregator.send(FromWebsocket::Update { message_id, payload, received_at, sent_at } ).await {
+                                log::error!("Failed to send node message to aggregator: {e}");
+                                continue;
+                            }
+                        } else {
+                            log::info!("Ignoring message with ID {message_id} from {real_addr:?} (we've hit the max of {max_nodes_per_connection} nodes per connection)");
                             continue;
                         }
-                    } else {
-                        log::info!("Ignoring message with ID {message_id} from {real_addr:?} (we've hit the max of {max_nodes_per_connection} nodes per connection)");
-                        continue;
+                    }
+
+                    // Compare the node's clock with ours, and let the aggregator know if the node
+                    // has started or stopped being flagged for the difference:
+                    let skew_change = match (&mut clock_skews, sent_at) {
+                        (Some(clock_skews), Some(sent_at)) => {
+                            clock_skews.check(message_id, sent_at, common::time::now())
+                        },
+                        _ => None,
+                    };
+                    if let Some(change) = skew_change {
+                        if let ClockSkewChange::Skewed { skew_ms, first: true } = change {
+                            log::warn!("Node with message ID {message_id} from {real_addr:?} has a clock that's {skew_ms}ms out from ours");
+                        }
+                        let _ = tx_to_aggregator.send(FromWebsocket::ClockSkew { message_id, skew_ms: change.skew_ms() }).await;
                     }
                 }
             }
         }
-    }
+        rejection
+    };
+
+    // Nodes whose messages back up are disconnected straight away, even if we're stuck waiting
+    // for room to pass one of their earlier messages on:
+    let rejection = tokio::select! {
+        rejection = main_loop => rejection,
+        Ok(()) = backlog_full_rx => Some(Rejection::RateLimited),
+    };
 
     // Make sure to kill off the receive-messages task if the main select loop ends:
-    let _ = close_connection_tx.send(());
+    let _ = close_connection_tx.send(None);
 
     // Return what we need to close the connection gracefully:
-    (tx_to_aggregator, ws_send)
+    (tx_to_aggregator, ws_send, rejection)
+}
+
+/// Nodes can identify themselves with a token, which is passed on to the core to decide what
+/// to do with it. The token is taken from an "Authorization: Bearer <token>" header if there
+/// is one, and otherwise from the URL that the node connects to, as in "/submit?token=<token>",
+/// since nodes can't always be configured to send headers. URLs are more likely than headers
+/// to end up in proxy and access logs, so the header should be preferred where possible.
+fn node_token<B>(req: &hyper::Request<B>) -> Option<Box<str>> {
+    let header_token = req
+        .headers()
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|auth| auth.to_str().ok()?.strip_prefix("Bearer "));
+    let query_token = || {
+        req.uri()
+            .query()?
+            .split('&')
+            .find_map(|param| param.strip_prefix("token="))
+    };
+    header_token.or_else(query_token).map(Into::into)
+}
+
+/// Did receiving a message fail because it was larger than we allow? Depending on whether
+/// the message was sent in one frame or several, this is spotted in different places.
+fn is_message_too_large(e: &soketto::connection::Error) -> bool {
+    matches!(
+        e,
+        soketto::connection::Error::MessageTooLarge { .. }
+            | soketto::connection::Error::Codec(soketto::base::Error::PayloadTooLarge { .. })
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::ws_client::{self, RecvMessage, SentMessage};
+    use futures::StreamExt;
+
+    /// Serve node connections on a local address, passing their messages on to the channel
+    /// given, and telling nodes why their connection was closed as the shard would.
+    fn serve_nodes(
+        tx_to_aggregator: flume::Sender<FromWebsocket>,
+        max_node_backlog: Option<usize>,
+        on_genesis_change: OnGenesisChange,
+    ) -> std::net::SocketAddr {
+        let (addr, server) = http_utils::bind_server(
+            "127.0.0.1:0".parse().unwrap(),
+            move |_addr, req| {
+                let tx_to_aggregator = tx_to_aggregator.clone();
+                async move {
+                    Ok(http_utils::upgrade_to_websocket(
+                        req,
+                        move |ws_send, ws_recv| async move {
+                            let (_, mut ws_send, rejection) = handle_node_websocket_connection(
+                                "127.0.0.1".parse().unwrap(),
+                                ws_send,
+                                ws_recv,
+                                tx_to_aggregator
+                                    .into_sink()
+                                    .sink_map_err(anyhow::Error::from),
+                                20,
+                                ByteSize::new(usize::MAX),
+                                BlockedAddrs::new(Duration::from_secs(60)),
+                                Duration::from_secs(60),
+                                PayloadEncoding::Identity,
+                                ByteSize::new(1024 * 1024),
+                                0,
+                                max_node_backlog,
+                                None,
+                                None,
+                                DEFAULT_NODE_PROTOCOL..=LATEST_NODE_PROTOCOL,
+                                on_genesis_change,
+                                Metrics::new(0, Duration::from_secs(60)),
+                                None,
+                            )
+                            .await;
+                            if let Some(rejection) = rejection {
+                                let _ = ws_send.send_text(rejection.to_json()).await;
+                                let _ = ws_send.flush().await;
+                            }
+                            let _ = ws_send.close().await;
+                        },
+                    ))
+                }
+            },
+            futures::future::pending(),
+        )
+        .unwrap();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn nodes_are_disconnected_once_their_backlog_fills() {
+        // Nothing takes messages from the aggregator's end of this, as if the core had stalled,
+        // so the connection gets stuck trying to pass on the node's first message:
+        let (tx_to_aggregator, _rx_from_node) = flume::bounded::<FromWebsocket>(1);
+        let addr = serve_nodes(tx_to_aggregator, Some(5), OnGenesisChange::Ignore);
+
+        let uri = format!("http://{addr}/submit").parse().unwrap();
+        let (node_tx, mut node_rx) = ws_client::connect(&uri).await.unwrap().into_channels();
+        for n in 0..20 {
+            let msg = serde_json::json!({
+                "id": 1,
+                "payload": { "msg": "block.import", "best": format!("0x{n:064x}"), "height": n },
+            });
+            node_tx
+                .unbounded_send(SentMessage::Text(msg.to_string()))
+                .unwrap();
+        }
+
+        // Once more than 5 messages are waiting, the node is told why and disconnected:
+        let msg = tokio::time::timeout(Duration::from_secs(5), node_rx.next())
+            .await
+            .expect("node should be disconnected promptly");
+        assert!(matches!(
+            msg,
+            Some(Ok(RecvMessage::Text(text))) if text == Rejection::RateLimited.to_json()
+        ));
+        let next = tokio::time::timeout(Duration::from_secs(5), node_rx.next())
+            .await
+            .expect("connection should be closed");
+        assert!(!matches!(next, Some(Ok(_))));
+    }
+
+    fn system_connected(genesis_hash: u64) -> SentMessage {
+        let msg = serde_json::json!({
+            "id": 1,
+            "payload": {
+                "authority": true,
+                "chain": "Test Chain",
+                "config": "",
+                "genesis_hash": BlockHash::from_low_u64_be(genesis_hash),
+                "implementation": "Substrate Node",
+                "msg": "system.connected",
+                "name": "Alice",
+                "network_id": "12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time": "1625565542717",
+                "version": "2.0.0-07a1af348-aarch64-macos"
+            },
+        });
+        SentMessage::Text(msg.to_string())
+    }
+
+    #[tokio::test]
+    async fn nodes_changing_genesis_hash_are_handled_as_configured() {
+        for on_genesis_change in [
+            OnGenesisChange::Ignore,
+            OnGenesisChange::Readd,
+            OnGenesisChange::Reject,
+        ] {
+            let (tx_to_aggregator, rx_from_node) = flume::unbounded();
+            let addr = serve_nodes(tx_to_aggregator, None, on_genesis_change);
+
+            // The node says it's on one chain, and then on another:
+            let uri = format!("http://{addr}/submit").parse().unwrap();
+            let (node_tx, mut node_rx) = ws_client::connect(&uri).await.unwrap().into_channels();
+            node_tx.unbounded_send(system_connected(1)).unwrap();
+            node_tx.unbounded_send(system_connected(2)).unwrap();
+            let ping =
+                serde_json::json!({ "id": 1, "payload": { "msg": "system.interval", "peers": 1 } });
+            node_tx
+                .unbounded_send(SentMessage::Text(ping.to_string()))
+                .unwrap();
+
+            // Nodes are only told anything if their connection is closed:
+            if on_genesis_change == OnGenesisChange::Reject {
+                let msg = tokio::time::timeout(Duration::from_secs(5), node_rx.next())
+                    .await
+                    .expect("node should be told why it was disconnected");
+                assert!(matches!(
+                    msg,
+                    Some(Ok(RecvMessage::Text(text))) if text == Rejection::GenesisChanged.to_json()
+                ));
+            }
+
+            // Otherwise, wait for the connection to have passed on the last message:
+            let mut events = Vec::new();
+            loop {
+                let msg = match on_genesis_change {
+                    OnGenesisChange::Reject => match rx_from_node.try_recv() {
+                        Ok(msg) => msg,
+                        Err(_) => break,
+                    },
+                    _ => tokio::time::timeout(Duration::from_secs(5), rx_from_node.recv_async())
+                        .await
+                        .expect("the node's messages should be passed on")
+                        .unwrap(),
+                };
+                match msg {
+                    FromWebsocket::Add { genesis_hash, .. } => {
+                        events.push(format!("add {}", genesis_hash.to_low_u64_be()))
+                    }
+                    FromWebsocket::Remove { .. } => events.push("remove".to_owned()),
+                    FromWebsocket::Update { .. } => {
+                        events.push("update".to_owned());
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+
+            let expected: &[&str] = match on_genesis_change {
+                OnGenesisChange::Ignore => &["add 1", "update"],
+                OnGenesisChange::Readd => &["add 1", "remove", "add 2", "update"],
+                OnGenesisChange::Reject => &["add 1"],
+            };
+            assert_eq!(events, expected, "with {on_genesis_change:?}");
+        }
+    }
 }
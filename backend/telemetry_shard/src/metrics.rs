@@ -0,0 +1,142 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use common::latency::{LatencyHistogram, LatencySampler};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Counters shared by every node connection to the shard.
+#[derive(Debug, Clone)]
+pub struct Metrics(Arc<MetricsInner>);
+
+#[derive(Debug)]
+struct MetricsInner {
+    malformed_node_messages: AtomicU64,
+    nodes_disconnected_for_malformed_messages: AtomicU64,
+    nodes_disconnected_for_backlog: AtomicU64,
+    duplicate_node_messages: AtomicU64,
+    latency_sample_every: u64,
+    forward_latency: Mutex<LatencyHistogram>,
+}
+
+impl Metrics {
+    /// Time every `latency_sample_every`th node message (or none if this is 0) on its way
+    /// through the shard, reporting percentiles over windows of `latency_window`.
+    pub fn new(latency_sample_every: u64, latency_window: Duration) -> Self {
+        Metrics(Arc::new(MetricsInner {
+            malformed_node_messages: AtomicU64::new(0),
+            nodes_disconnected_for_malformed_messages: AtomicU64::new(0),
+            nodes_disconnected_for_backlog: AtomicU64::new(0),
+            duplicate_node_messages: AtomicU64::new(0),
+            latency_sample_every,
+            forward_latency: Mutex::new(LatencyHistogram::new(latency_window)),
+        }))
+    }
+
+    /// Each node connection picks the messages to time with one of these.
+    pub fn latency_sampler(&self) -> LatencySampler {
+        LatencySampler::new(self.0.latency_sample_every)
+    }
+
+    /// A sampled node message, received at the time given, has been handed to the
+    /// connection to the core.
+    pub fn record_forward_latency(&self, received_at: Instant) {
+        self.0
+            .forward_latency
+            .lock()
+            .unwrap()
+            .record_since(received_at);
+    }
+
+    /// A node sent a message that we couldn't parse.
+    pub fn record_malformed_node_message(&self) {
+        self.0
+            .malformed_node_messages
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A node was disconnected for sending too many malformed messages in a row.
+    pub fn record_node_disconnected_for_malformed_messages(&self) {
+        self.0
+            .nodes_disconnected_for_malformed_messages
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A node was disconnected because too many of its messages were waiting to be passed on.
+    pub fn record_node_disconnected_for_backlog(&self) {
+        self.0
+            .nodes_disconnected_for_backlog
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A node sent a message identical to the one before it, which was dropped.
+    pub fn record_duplicate_node_message(&self) {
+        self.0
+            .duplicate_node_messages
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Write out the metrics in the text format that prometheus expects. See
+    /// `return_prometheus_metrics` in the telemetry core for more on this format.
+    pub fn to_prometheus(&self) -> String {
+        let mut s = String::new();
+        let _ = writeln!(
+            &mut s,
+            "telemetry_shard_malformed_node_messages {}",
+            self.0.malformed_node_messages.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_shard_nodes_disconnected_for_malformed_messages {}",
+            self.0
+                .nodes_disconnected_for_malformed_messages
+                .load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_shard_nodes_disconnected_for_backlog {}",
+            self.0
+                .nodes_disconnected_for_backlog
+                .load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_shard_duplicate_node_messages {}",
+            self.0.duplicate_node_messages.load(Ordering::Relaxed)
+        );
+
+        let latency = self.0.forward_latency.lock().unwrap().percentiles();
+        let _ = writeln!(
+            &mut s,
+            "telemetry_shard_forward_latency_samples {}",
+            latency.samples
+        );
+        for (name, value) in [
+            ("p50", latency.p50_us),
+            ("p90", latency.p90_us),
+            ("p99", latency.p99_us),
+            ("max", latency.max_us),
+        ] {
+            let _ = writeln!(
+                &mut s,
+                "telemetry_shard_forward_latency_{name}_microseconds {value}"
+            );
+        }
+        s
+    }
+}
@@ -0,0 +1,160 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::borrow::Cow;
+use std::io::Read;
+
+/// The websocket subprotocol that a node can ask for to send gzip compressed messages.
+pub const GZIP_SUBPROTOCOL: &str = "telemetry-gzip";
+/// The websocket subprotocol that a node can ask for to send brotli compressed messages.
+pub const BROTLI_SUBPROTOCOL: &str = "telemetry-brotli";
+
+/// The subprotocols that nodes can ask for when connecting, in order of preference.
+pub const SUBPROTOCOLS: &[&str] = &[BROTLI_SUBPROTOCOL, GZIP_SUBPROTOCOL];
+
+/// How are the messages sent from a node encoded? This is agreed on when the
+/// node connects, by way of the websocket subprotocol that it asks for. Nodes
+/// that don't ask for a subprotocol send uncompressed JSON.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PayloadEncoding {
+    #[default]
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum DecodeError {
+    #[error("Decompressed message exceeds the maximum size of {max_size} bytes")]
+    TooLarge { max_size: usize },
+    #[error("Failed to decompress message: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl PayloadEncoding {
+    /// Which encoding does the subprotocol we agreed on with a node imply?
+    pub fn from_subprotocol(subprotocol: Option<&str>) -> PayloadEncoding {
+        match subprotocol {
+            Some(GZIP_SUBPROTOCOL) => PayloadEncoding::Gzip,
+            Some(BROTLI_SUBPROTOCOL) => PayloadEncoding::Brotli,
+            _ => PayloadEncoding::Identity,
+        }
+    }
+
+    /// Decode a message sent by a node. To guard against small messages which decompress into
+    /// huge ones, we give up if the decompressed message would exceed the maximum size given.
+    pub fn decode<'a>(
+        &self,
+        bytes: &'a [u8],
+        max_size: usize,
+    ) -> Result<Cow<'a, [u8]>, DecodeError> {
+        match self {
+            PayloadEncoding::Identity => Ok(Cow::Borrowed(bytes)),
+            PayloadEncoding::Gzip => {
+                read_to_max(flate2::read::GzDecoder::new(bytes), max_size).map(Cow::Owned)
+            }
+            PayloadEncoding::Brotli => read_to_max(
+                brotli_decompressor::Decompressor::new(bytes, 4096),
+                max_size,
+            )
+            .map(Cow::Owned),
+        }
+    }
+}
+
+/// Read everything from a reader, failing if there are more than `max_size` bytes to read.
+fn read_to_max(reader: impl Read, max_size: usize) -> Result<Vec<u8>, DecodeError> {
+    let mut decoded = Vec::new();
+    reader.take(max_size as u64 + 1).read_to_end(&mut decoded)?;
+
+    if decoded.len() > max_size {
+        return Err(DecodeError::TooLarge { max_size });
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    const MESSAGE: &[u8] = br#"{"id":1,"payload":{"msg":"system.interval","peers":4}}"#;
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn brotli(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+        encoder.write_all(bytes).unwrap();
+        encoder.into_inner()
+    }
+
+    #[test]
+    fn subprotocols_map_to_encodings() {
+        assert_eq!(
+            PayloadEncoding::from_subprotocol(None),
+            PayloadEncoding::Identity
+        );
+        assert_eq!(
+            PayloadEncoding::from_subprotocol(Some(GZIP_SUBPROTOCOL)),
+            PayloadEncoding::Gzip
+        );
+        assert_eq!(
+            PayloadEncoding::from_subprotocol(Some(BROTLI_SUBPROTOCOL)),
+            PayloadEncoding::Brotli
+        );
+    }
+
+    #[test]
+    fn compressed_messages_are_decoded() {
+        let identity = PayloadEncoding::Identity.decode(MESSAGE, 1024).unwrap();
+        assert_eq!(&*identity, MESSAGE);
+
+        let gzipped = gzip(MESSAGE);
+        let decoded = PayloadEncoding::Gzip.decode(&gzipped, 1024).unwrap();
+        assert_eq!(&*decoded, MESSAGE);
+
+        let brotlied = brotli(MESSAGE);
+        let decoded = PayloadEncoding::Brotli.decode(&brotlied, 1024).unwrap();
+        assert_eq!(&*decoded, MESSAGE);
+    }
+
+    #[test]
+    fn messages_decompressing_beyond_max_size_are_rejected() {
+        // A megabyte of zeroes compresses down to almost nothing:
+        let bomb = vec![0u8; 1024 * 1024];
+
+        let gzipped = gzip(&bomb);
+        assert!(gzipped.len() < 4096);
+        assert!(matches!(
+            PayloadEncoding::Gzip.decode(&gzipped, 4096),
+            Err(DecodeError::TooLarge { max_size: 4096 })
+        ));
+
+        let brotlied = brotli(&bomb);
+        assert!(matches!(
+            PayloadEncoding::Brotli.decode(&brotlied, 4096),
+            Err(DecodeError::TooLarge { max_size: 4096 })
+        ));
+
+        // Exactly the max size is fine though:
+        let decoded = PayloadEncoding::Gzip.decode(&gzipped, bomb.len()).unwrap();
+        assert_eq!(decoded.len(), bomb.len());
+    }
+}
@@ -15,10 +15,14 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
 
 /**
 Extract the "real" IP address of the connection by looking at headers
 set by proxies (this is inspired by Actix Web's implementation of the feature).
+These headers can be set by anybody, and so they are only looked at if the
+connection comes from one of the `trusted_proxies`.
 
 First, check for the standardised "Forwarded" header. This looks something like:
 
@@ -35,13 +39,91 @@ If still no luck, look for the X-Real-IP header, which we expect to contain a si
 
 If that _still_ doesn't work, fall back to the socket address of the connection.
 */
-pub fn real_ip(addr: SocketAddr, headers: &hyper::HeaderMap) -> (IpAddr, Source) {
+pub fn real_ip(
+    addr: SocketAddr,
+    headers: &hyper::HeaderMap,
+    trusted_proxies: &TrustedProxies,
+) -> (IpAddr, Source) {
+    if !trusted_proxies.contains(addr.ip()) {
+        return (addr.ip(), Source::SocketAddr);
+    }
     let forwarded = headers.get("forwarded").and_then(header_as_str);
     let forwarded_for = headers.get("x-forwarded-for").and_then(header_as_str);
     let real_ip = headers.get("x-real-ip").and_then(header_as_str);
     pick_best_ip_from_options(forwarded, forwarded_for, real_ip, addr)
 }
 
+/// The proxies that we trust to tell us the real IP address of the connections they
+/// forward to us. By default, no proxies are trusted.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies(Arc<[IpCidr]>);
+
+impl TrustedProxies {
+    pub fn new(cidrs: Vec<IpCidr>) -> TrustedProxies {
+        TrustedProxies(cidrs.into())
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+/// A range of IP addresses, written in CIDR notation (such as "10.0.0.0/8" or
+/// "fd00::/8"). A single address (such as "10.1.2.3") is also accepted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpCidr {
+    fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 addresses may arrive mapped into IPv6 addresses, so compare them as IPv4:
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(net), u32::from(ip), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Do the first `prefix_len` bits of the two numbers match?
+fn prefix_matches<T>(a: T, b: T, prefix_len: u8) -> bool
+where
+    T: std::ops::BitXor<Output = T> + std::ops::Shr<u32, Output = T> + PartialEq + From<u8>,
+{
+    let num_bits = 8 * std::mem::size_of::<T>() as u32;
+    let prefix_len = u32::from(prefix_len);
+    prefix_len == 0 || (a ^ b) >> (num_bits - prefix_len) == T::from(0)
+}
+
+impl FromStr for IpCidr {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| anyhow::anyhow!("'{s}' is not a valid CIDR range: {e}"))?;
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(n) => n
+                .parse()
+                .ok()
+                .filter(|&n| n <= max_prefix_len)
+                .ok_or_else(|| anyhow::anyhow!("'{s}' does not have a valid prefix length"))?,
+            None => max_prefix_len,
+        };
+        Ok(IpCidr { addr, prefix_len })
+    }
+}
+
 /// The source of the address returned
 pub enum Source {
     ForwardedHeader,
@@ -90,9 +172,9 @@ fn pick_best_ip_from_options(
         })
         .or_else(|| {
             // fall back to X-Real-IP
-            real_ip.as_ref().and_then(|val| {
+            real_ip.as_ref().map(|val| {
                 let addr = val.trim();
-                Some((addr, Source::XRealIpHeader))
+                (addr, Source::XRealIpHeader)
             })
         })
         .and_then(|(ip, source)| {
@@ -129,9 +211,7 @@ fn get_first_addr_from_forwarded_header(value: &str) -> Option<&str> {
     let first_values = value.split(',').next()?;
 
     for pair in first_values.split(';') {
-        let mut parts = pair.trim().splitn(2, '=');
-        let key = parts.next()?;
-        let value = parts.next()?;
+        let (key, value) = pair.trim().split_once('=')?;
 
         if key.to_lowercase() == "for" {
             // trim double quotes if they surround the value:
@@ -176,4 +256,46 @@ mod test {
             );
         }
     }
+
+    #[test]
+    fn cidrs_contain_addresses_in_range() {
+        let cidr: IpCidr = "10.1.0.0/16".parse().unwrap();
+        assert!(cidr.contains("10.1.2.3".parse().unwrap()));
+        assert!(cidr.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains("10.2.0.0".parse().unwrap()));
+        assert!(!cidr.contains("::1".parse().unwrap()));
+
+        let cidr: IpCidr = "fd00::/8".parse().unwrap();
+        assert!(cidr.contains("fd12:3456::1".parse().unwrap()));
+        assert!(!cidr.contains("fe00::1".parse().unwrap()));
+
+        let cidr: IpCidr = "127.0.0.1".parse().unwrap();
+        assert!(cidr.contains("127.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains("127.0.0.2".parse().unwrap()));
+
+        let cidr: IpCidr = "0.0.0.0/0".parse().unwrap();
+        assert!(cidr.contains("1.2.3.4".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+        assert!("10.0.0/8".parse::<IpCidr>().is_err());
+    }
+
+    #[test]
+    fn headers_are_only_trusted_from_trusted_proxies() {
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("x-forwarded-for", "1.2.3.4, 10.0.0.1".parse().unwrap());
+        let proxy_addr: SocketAddr = "10.0.0.2:1234".parse().unwrap();
+
+        // No proxies are trusted by default:
+        let (ip, _) = real_ip(proxy_addr, &headers, &TrustedProxies::default());
+        assert_eq!(ip, proxy_addr.ip());
+
+        let untrusted = TrustedProxies::new(vec!["192.168.0.0/16".parse().unwrap()]);
+        let (ip, _) = real_ip(proxy_addr, &headers, &untrusted);
+        assert_eq!(ip, proxy_addr.ip());
+
+        let trusted = TrustedProxies::new(vec!["10.0.0.0/8".parse().unwrap()]);
+        let (ip, _) = real_ip(proxy_addr, &headers, &trusted);
+        assert_eq!(ip, "1.2.3.4".parse::<IpAddr>().unwrap());
+    }
 }
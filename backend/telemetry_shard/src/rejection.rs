@@ -0,0 +1,116 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use common::internal_messages::{MuteReason, RemovalReason};
+
+/// Why a node's connection was refused or closed by the shard. Nodes are told this, so
+/// that those which understand it can back off or reconnect to another shard as
+/// appropriate. The codes and reasons are part of the node protocol; see the README.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// The chain that the node is on already has as many nodes as it's allowed.
+    QuotaExceeded,
+    /// The chain that the node is on isn't allowed, or is one chain too many.
+    ChainDenied,
+    /// The shard is draining; the node should connect to another shard.
+    Draining,
+    /// The node sent too much data, opened too many connections, or sent messages faster
+    /// than we could pass them on.
+    RateLimited,
+    /// The node connected again, and its new connection is used instead of this one.
+    Superseded,
+    /// The node speaks a version of the node protocol that the shard doesn't accept.
+    UnsupportedProtocol,
+    /// A node on the connection said that it was on a different chain to the one it said
+    /// it was on before.
+    GenesisChanged,
+}
+
+impl Rejection {
+    /// A code for the rejection, in the range that WebSocket reserves for applications.
+    pub fn code(self) -> u16 {
+        match self {
+            Rejection::QuotaExceeded => 4001,
+            Rejection::ChainDenied => 4002,
+            Rejection::Draining => 4003,
+            Rejection::RateLimited => 4004,
+            Rejection::Superseded => 4005,
+            Rejection::UnsupportedProtocol => 4006,
+            Rejection::GenesisChanged => 4007,
+        }
+    }
+
+    /// A short, stable string describing the rejection.
+    pub fn reason(self) -> &'static str {
+        match self {
+            Rejection::QuotaExceeded => "quota-exceeded",
+            Rejection::ChainDenied => "chain-denied",
+            Rejection::Draining => "draining",
+            Rejection::RateLimited => "rate-limited",
+            Rejection::Superseded => "superseded",
+            Rejection::UnsupportedProtocol => "unsupported-protocol",
+            Rejection::GenesisChanged => "genesis-changed",
+        }
+    }
+
+    /// Why the core is told that the nodes on a connection closed for this reason were removed.
+    /// Connections are only closed for quotas, chains and superseding once the core has muted
+    /// all of their nodes, so it has removed them already.
+    pub fn removal_reason(self) -> RemovalReason {
+        match self {
+            Rejection::RateLimited => RemovalReason::RateLimited,
+            Rejection::Draining => RemovalReason::Draining,
+            Rejection::QuotaExceeded
+            | Rejection::ChainDenied
+            | Rejection::Superseded
+            | Rejection::UnsupportedProtocol
+            | Rejection::GenesisChanged => RemovalReason::Disconnected,
+        }
+    }
+
+    /// The message sent to a node to tell it about the rejection, as the last message on
+    /// its connection or as the body of the HTTP response refusing it.
+    pub fn to_json(self) -> String {
+        serde_json::json!({ "code": self.code(), "reason": self.reason() }).to_string()
+    }
+}
+
+impl From<MuteReason> for Rejection {
+    fn from(reason: MuteReason) -> Self {
+        match reason {
+            MuteReason::Overquota => Rejection::QuotaExceeded,
+            MuteReason::ChainNotAllowed | MuteReason::TooManyChains => Rejection::ChainDenied,
+            MuteReason::Superseded => Rejection::Superseded,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mute_reasons_map_to_rejections() {
+        assert_eq!(
+            Rejection::from(MuteReason::Overquota).to_json(),
+            r#"{"code":4001,"reason":"quota-exceeded"}"#
+        );
+        assert_eq!(
+            Rejection::from(MuteReason::TooManyChains),
+            Rejection::ChainDenied
+        );
+    }
+}
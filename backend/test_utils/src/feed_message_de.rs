@@ -20,8 +20,10 @@ use common::node_types::{
     Timestamp,
 };
 use serde_json::value::RawValue;
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Debug, PartialEq)]
+#[allow(clippy::large_enum_variant)]
 pub enum FeedMessage {
     Version(usize),
     BestBlock {
@@ -43,10 +45,15 @@ pub enum FeedMessage {
         location: Option<NodeLocation>,
         startup_time: Option<Timestamp>,
         hwbench: Option<NodeHwBench>,
+        last_seen: Timestamp,
     },
     RemovedNode {
         node_id: usize,
     },
+    RemovedNodeReason {
+        node_id: usize,
+        reason: String,
+    },
     LocatedNode {
         node_id: usize,
         lat: f32,
@@ -77,10 +84,15 @@ pub enum FeedMessage {
         name: String,
         genesis_hash: BlockHash,
         node_count: usize,
+        tags: BTreeMap<String, String>,
     },
     RemovedChain {
         genesis_hash: BlockHash,
     },
+    ChainLabelChanged {
+        genesis_hash: BlockHash,
+        name: String,
+    },
     SubscribedTo {
         genesis_hash: BlockHash,
     },
@@ -122,6 +134,107 @@ pub enum FeedMessage {
         node_id: usize,
         // details: NodeIO, // can't losslessly deserialize
     },
+    NodeShard {
+        node_id: usize,
+        shard_id: String,
+    },
+    NodeCustomMetrics {
+        node_id: usize,
+        metrics: HashMap<String, f64>,
+    },
+    NodePeerCount {
+        node_id: usize,
+        peer_counts: Vec<f32>,
+    },
+    Heartbeat {
+        timestamp: Timestamp,
+    },
+    ChainFinalized {
+        block_number: BlockNumber,
+        block_hash: BlockHash,
+        /// Have nodes reported different hashes as finalized at this height?
+        diverged: bool,
+    },
+    ChainsList {
+        /// The name, genesis hash, node count and tags of each chain.
+        chains: Vec<(String, BlockHash, usize, BTreeMap<String, String>)>,
+    },
+    Shards {
+        /// The connection ID, shard ID, node count, last-seen time and whether
+        /// each shard is draining.
+        shards: Vec<(u64, Option<String>, usize, Timestamp, bool)>,
+    },
+    BuildInfo {
+        version: String,
+        commit: Option<String>,
+        built_at: Timestamp,
+    },
+    CommandError {
+        error: String,
+    },
+    ChainLeader {
+        node_id: usize,
+        block_number: BlockNumber,
+    },
+    Reorg {
+        block_number: BlockNumber,
+        block_hash: BlockHash,
+        depth: u64,
+    },
+    ForChain {
+        genesis_hash: BlockHash,
+    },
+    AuthoritySetChanged {
+        authority_set_id: u64,
+        authorities: String,
+    },
+    NodeCount {
+        genesis_hash: BlockHash,
+        added: usize,
+        removed: usize,
+        total: usize,
+    },
+    NodeDetail {
+        node_id: usize,
+        node: NodeDetails,
+        stats: NodeStats,
+        // io: NodeIO, // can't losslessly deserialize
+        // hardware: NodeHardware, // can't losslessly deserialize
+        block_details: BlockDetails,
+        location: Option<NodeLocation>,
+        startup_time: Option<Timestamp>,
+        hwbench: Option<NodeHwBench>,
+        last_seen: Timestamp,
+        custom_metrics: HashMap<String, f64>,
+        peer_counts: Vec<f32>,
+        stale: bool,
+        clock_skew: Option<i64>,
+    },
+    BlockPropagation {
+        min: u64,
+        median: u64,
+        p95: u64,
+        max: u64,
+    },
+    SubscribeError {
+        /// Why the subscription failed, as in "unknown-chain" or "unknown-label".
+        code: String,
+        /// The chain that was asked for, unless it wasn't a single chain.
+        genesis_hash: Option<BlockHash>,
+    },
+    NodeClockSkew {
+        node_id: usize,
+        skew_ms: Option<i64>,
+    },
+    NodeLastSeen {
+        node_id: usize,
+        last_seen: Timestamp,
+    },
+    TopChains {
+        /// The name, genesis hash, node count and average block time of each chain, most
+        /// active first.
+        chains: Vec<(String, BlockHash, usize, Option<u64>)>,
+    },
     /// A "special" case when we don't know how to decode an action:
     UnknownValue {
         action: u8,
@@ -138,6 +251,7 @@ pub struct NodeDetails {
     pub network_id: Option<String>,
     pub ip: Option<String>,
     pub sysinfo: Option<NodeSysInfo>,
+    pub role: String,
 }
 
 impl FeedMessage {
@@ -189,13 +303,24 @@ impl FeedMessage {
             3 => {
                 let (
                     node_id,
-                    (name, implementation, version, validator, network_id, ip, sysinfo, hwbench),
+                    (
+                        name,
+                        implementation,
+                        version,
+                        validator,
+                        network_id,
+                        ip,
+                        sysinfo,
+                        hwbench,
+                        role,
+                    ),
                     stats,
                     io,
                     hardware,
                     block_details,
                     location,
                     startup_time,
+                    last_seen,
                 ) = serde_json::from_str(raw_val.get())?;
 
                 // Give these two types but don't use the results:
@@ -211,12 +336,14 @@ impl FeedMessage {
                         network_id,
                         ip,
                         sysinfo,
+                        role,
                     },
                     stats,
                     block_details,
                     location,
                     startup_time,
                     hwbench,
+                    last_seen,
                 }
             }
             // RemoveNode
@@ -268,11 +395,12 @@ impl FeedMessage {
             }
             // AddedChain
             11 => {
-                let (name, genesis_hash, node_count) = serde_json::from_str(raw_val.get())?;
+                let (name, genesis_hash, node_count, tags) = serde_json::from_str(raw_val.get())?;
                 FeedMessage::AddedChain {
                     name,
                     genesis_hash,
                     node_count,
+                    tags,
                 }
             }
             // RemovedChain
@@ -348,6 +476,197 @@ impl FeedMessage {
                 let (node_id, _node_io): (_, &RawValue) = serde_json::from_str(raw_val.get())?;
                 FeedMessage::NodeIOUpdate { node_id }
             }
+            // NodeShard
+            23 => {
+                let (node_id, shard_id) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeShard { node_id, shard_id }
+            }
+            // NodeCustomMetrics
+            24 => {
+                let (node_id, metrics) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeCustomMetrics { node_id, metrics }
+            }
+            // BlockPropagation
+            25 => {
+                let (min, median, p95, max) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::BlockPropagation {
+                    min,
+                    median,
+                    p95,
+                    max,
+                }
+            }
+            // NodePeerCount
+            26 => {
+                let (node_id, peer_counts) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodePeerCount {
+                    node_id,
+                    peer_counts,
+                }
+            }
+            // Heartbeat
+            27 => {
+                let timestamp = serde_json::from_str(raw_val.get())?;
+                FeedMessage::Heartbeat { timestamp }
+            }
+            // ChainsList
+            28 => {
+                let chains = serde_json::from_str(raw_val.get())?;
+                FeedMessage::ChainsList { chains }
+            }
+            // ChainFinalized
+            29 => {
+                let (block_number, block_hash, diverged) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::ChainFinalized {
+                    block_number,
+                    block_hash,
+                    diverged,
+                }
+            }
+            // Shards
+            30 => {
+                let shards = serde_json::from_str(raw_val.get())?;
+                FeedMessage::Shards { shards }
+            }
+            // BuildInfo
+            31 => {
+                let (version, commit, built_at) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::BuildInfo {
+                    version,
+                    commit,
+                    built_at,
+                }
+            }
+            // CommandError
+            32 => {
+                let error = serde_json::from_str(raw_val.get())?;
+                FeedMessage::CommandError { error }
+            }
+            // ChainLeader
+            33 => {
+                let (node_id, block_number) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::ChainLeader {
+                    node_id,
+                    block_number,
+                }
+            }
+            // NodeDetail
+            34 => {
+                let (
+                    node_id,
+                    (
+                        name,
+                        implementation,
+                        version,
+                        validator,
+                        network_id,
+                        ip,
+                        sysinfo,
+                        hwbench,
+                        role,
+                    ),
+                    stats,
+                    io,
+                    hardware,
+                    block_details,
+                    location,
+                    startup_time,
+                    last_seen,
+                    custom_metrics,
+                    peer_counts,
+                    stale,
+                    clock_skew,
+                ) = serde_json::from_str(raw_val.get())?;
+
+                // Give these two types but don't use the results:
+                let (_, _): (&RawValue, &RawValue) = (io, hardware);
+
+                FeedMessage::NodeDetail {
+                    node_id,
+                    node: NodeDetails {
+                        name,
+                        implementation,
+                        version,
+                        validator,
+                        network_id,
+                        ip,
+                        sysinfo,
+                        role,
+                    },
+                    stats,
+                    block_details,
+                    location,
+                    startup_time,
+                    hwbench,
+                    last_seen,
+                    custom_metrics,
+                    peer_counts,
+                    stale,
+                    clock_skew,
+                }
+            }
+            // SubscribeError
+            35 => {
+                let (code, genesis_hash) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::SubscribeError { code, genesis_hash }
+            }
+            // NodeClockSkew
+            36 => {
+                let (node_id, skew_ms) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeClockSkew { node_id, skew_ms }
+            }
+            // TopChains
+            37 => {
+                let chains = serde_json::from_str(raw_val.get())?;
+                FeedMessage::TopChains { chains }
+            }
+            // NodeLastSeen
+            38 => {
+                let (node_id, last_seen) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeLastSeen { node_id, last_seen }
+            }
+            // RemovedNodeReason
+            39 => {
+                let (node_id, reason) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::RemovedNodeReason { node_id, reason }
+            }
+            // ChainLabelChanged
+            40 => {
+                let (genesis_hash, name) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::ChainLabelChanged { genesis_hash, name }
+            }
+            // Reorg
+            41 => {
+                let (block_number, block_hash, depth) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::Reorg {
+                    block_number,
+                    block_hash,
+                    depth,
+                }
+            }
+            // ForChain
+            42 => {
+                let genesis_hash = serde_json::from_str(raw_val.get())?;
+                FeedMessage::ForChain { genesis_hash }
+            }
+            // NodeCount
+            43 => {
+                let (genesis_hash, added, removed, total) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeCount {
+                    genesis_hash,
+                    added,
+                    removed,
+                    total,
+                }
+            }
+            // AuthoritySetChanged
+            44 => {
+                let (authority_set_id, authorities) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::AuthoritySetChanged {
+                    authority_set_id,
+                    authorities,
+                }
+            }
             // A catchall for messages we don't know/care about yet:
             _ => {
                 let value = raw_val.to_string();
@@ -379,8 +698,8 @@ mod test {
 
     #[test]
     fn decode_remove_then_add_node_msg() {
-        // "remove chain '', then add chain 'Local Testnet' with 1 node":
-        let msg = r#"[12,"0x0000000000000000000000000000000000000000000000000000000000000000",11,["Local Testnet","0x0000000000000000000000000000000000000000000000000000000000000000",1]]"#;
+        // "remove chain '', then add chain 'Local Testnet' with 1 node and a tag":
+        let msg = r#"[12,"0x0000000000000000000000000000000000000000000000000000000000000000",11,["Local Testnet","0x0000000000000000000000000000000000000000000000000000000000000000",1,{"network":"testnet"}]]"#;
 
         assert_eq!(
             FeedMessage::from_bytes(msg.as_bytes()).unwrap(),
@@ -391,7 +710,8 @@ mod test {
                 FeedMessage::AddedChain {
                     name: "Local Testnet".to_owned(),
                     genesis_hash: BlockHash::zero(),
-                    node_count: 1
+                    node_count: 1,
+                    tags: [("network".to_owned(), "testnet".to_owned())].into(),
                 },
             ]
         );
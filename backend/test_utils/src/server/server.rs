@@ -191,7 +191,7 @@ impl Server {
 
                 let pid = shards.add_with(|id| Process {
                     id,
-                    host: format!("{}", host),
+                    host: host.to_string(),
                     handle: None,
                     _channel_type: PhantomData,
                 });
@@ -214,6 +214,7 @@ impl Server {
                     .arg("info")
                     .arg("--core")
                     .arg(core_shard_submit_uri)
+                    .env(common::logging::LOG_FILTER_ENV_VAR, utils::log_filter_env())
                     .kill_on_drop(true)
                     .stdout(std::process::Stdio::piped())
                     .stdin(std::process::Stdio::piped());
@@ -222,16 +223,21 @@ impl Server {
                 let mut child_stdout = shard_process.stdout.take().expect("shard stdout");
                 let shard_port = utils::get_port(&mut child_stdout)
                     .await
-                    .map_err(|e| Error::ErrorObtainingPort(e))?;
+                    .map_err(Error::ErrorObtainingPort)?;
 
                 // Attempt to wait until we've received word that the shard is connected to the
                 // core before continuing. If we don't wait for this, the connection may happen
                 // after we've attempted to connect node sockets, and they would be booted and
-                // made to reconnect, which we don't want to deal with in general.
-                let _ = utils::wait_for_line_containing(
-                    &mut child_stdout,
-                    |s| s.contains("Connected to telemetry core"),
+                // made to reconnect, which we don't want to deal with in general. A shard that
+                // can't connect keeps logging, so limit the total wait and not just the wait
+                // between lines.
+                let _ = tokio::time::timeout(
                     std::time::Duration::from_secs(5),
+                    utils::wait_for_line_containing(
+                        &mut child_stdout,
+                        |s| s.contains("Connected to telemetry core"),
+                        std::time::Duration::from_secs(5),
+                    ),
                 )
                 .await;
 
@@ -323,6 +329,7 @@ impl Server {
             .arg("127.0.0.1:0") // 0 to have a port picked by the kernel
             .arg("--log")
             .arg("info")
+            .env(common::logging::LOG_FILTER_ENV_VAR, utils::log_filter_env())
             .kill_on_drop(true)
             .stdout(std::process::Stdio::piped())
             .stdin(std::process::Stdio::piped())
@@ -332,7 +339,7 @@ impl Server {
         let mut child_stdout = child.stdout.take().expect("core stdout");
         let core_port = utils::get_port(&mut child_stdout)
             .await
-            .map_err(|e| Error::ErrorObtainingPort(e))?;
+            .map_err(Error::ErrorObtainingPort)?;
 
         // Since we're piping stdout from the child process, we need somewhere for it to go
         // else the process will get stuck when it tries to produce output:
@@ -383,6 +390,11 @@ impl<Channel> Process<Channel> {
         &self.host
     }
 
+    /// Get the OS process ID, if we started the process ourselves and it's still running.
+    pub fn pid(&self) -> Option<u32> {
+        self.handle.as_ref().and_then(|handle| handle.id())
+    }
+
     /// Kill the process and wait for this to complete
     /// Not public: Klling done via Server.
     async fn kill(self) -> Result<(), Error> {
@@ -442,6 +454,41 @@ impl ShardProcess {
         Process::connect_to_uri(&uri).await
     }
 
+    /// Establish a connection to the process, asking for the given websocket subprotocol
+    pub async fn connect_node_with_protocol(
+        &self,
+        protocol: &str,
+    ) -> Result<(channels::ShardSender, channels::ShardReceiver), Error> {
+        let uri = format!("http://{}/submit", self.host).parse()?;
+        ws_client::connect_with_protocols(&uri, &[protocol])
+            .await
+            .map(|c| c.into_channels())
+            .map(|(s, r)| (s.into(), r.into()))
+            .map_err(|e| e.into())
+    }
+
+    /// Establish a connection to the process, identifying the node with the given token
+    pub async fn connect_node_with_token(
+        &self,
+        token: &str,
+    ) -> Result<(channels::ShardSender, channels::ShardReceiver), Error> {
+        let uri = format!("http://{}/submit?token={}", self.host, token).parse()?;
+        Process::connect_to_uri(&uri).await
+    }
+
+    /// Establish a connection to the process, sending the given (name, value) headers
+    pub async fn connect_node_with_headers(
+        &self,
+        headers: &[(&str, &str)],
+    ) -> Result<(channels::ShardSender, channels::ShardReceiver), Error> {
+        let uri = format!("http://{}/submit", self.host).parse()?;
+        ws_client::connect_with_headers(&uri, headers)
+            .await
+            .map(|c| c.into_channels())
+            .map(|(s, r)| (s.into(), r.into()))
+            .map_err(|e| e.into())
+    }
+
     /// Establish multiple connections to the process
     pub async fn connect_multiple_nodes(
         &self,
@@ -469,6 +516,27 @@ impl CoreProcess {
         Process::connect_to_uri(&uri).await
     }
 
+    /// Establish a connection to the process, asking for the given websocket subprotocol
+    pub async fn connect_feed_with_protocol(
+        &self,
+        protocol: &str,
+    ) -> Result<(channels::FeedSender, channels::FeedReceiver), Error> {
+        let uri = format!("http://{}/feed", self.host).parse()?;
+        ws_client::connect_with_protocols(&uri, &[protocol])
+            .await
+            .map(|c| c.into_channels())
+            .map(|(s, r)| (s.into(), r.into()))
+            .map_err(|e| e.into())
+    }
+
+    /// Establish a raw connection to the process, pretending to be a shard
+    pub async fn connect_shard_raw(
+        &self,
+    ) -> Result<(ws_client::RawSender, ws_client::RawReceiver), Error> {
+        let uri = format!("http://{}/shard_submit", self.host).parse()?;
+        connect_to_uri_raw(&uri).await
+    }
+
     /// Establish multiple connections to the process
     pub async fn connect_multiple_feeds(
         &self,
@@ -502,10 +570,10 @@ impl Command {
     }
 }
 
-impl Into<TokioCommand> for Command {
-    fn into(self) -> TokioCommand {
-        let mut cmd = TokioCommand::new(self.command);
-        cmd.args(self.args);
+impl From<Command> for TokioCommand {
+    fn from(val: Command) -> Self {
+        let mut cmd = TokioCommand::new(val.command);
+        cmd.args(val.args);
         cmd
     }
 }
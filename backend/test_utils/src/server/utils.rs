@@ -45,6 +45,17 @@ pub async fn get_port<R: AsyncRead + Unpin>(reader: R) -> Result<u16, anyhow::Er
         })
 }
 
+/// The `RUST_LOG` value to hand to spawned shard/core processes. Any filter that we were given
+/// is passed on so that module levels can be tweaked when running tests with `--nocapture`, but
+/// we always need to see the line that [`get_port`] looks for.
+pub fn log_filter_env() -> String {
+    let port_log = "common::http_utils=info";
+    match std::env::var(common::logging::LOG_FILTER_ENV_VAR) {
+        Ok(filter) if !filter.trim().is_empty() => format!("{filter},{port_log}"),
+        _ => port_log.to_owned(),
+    }
+}
+
 /// Wait for a line of output containing the text given. Also provide a timeout,
 /// such that if we don't see a new line of output within the timeout we bail out
 /// and return an error.
@@ -18,54 +18,63 @@ use super::commands;
 use crate::server::{self, Command, Server};
 
 /// Options for the server
+#[derive(Default)]
 pub struct ServerOpts {
     pub release_mode: bool,
     pub log_output: bool,
 }
 
-impl Default for ServerOpts {
-    fn default() -> Self {
-        Self {
-            release_mode: false,
-            log_output: false,
-        }
-    }
-}
-
 /// Additional options to pass to the core command.
+#[derive(Default)]
 pub struct CoreOpts {
     pub feed_timeout: Option<u64>,
+    pub feed_send_timeout: Option<u64>,
     pub worker_threads: Option<usize>,
+    pub worker_cpu_affinity: bool,
     pub num_aggregators: Option<usize>,
-}
-
-impl Default for CoreOpts {
-    fn default() -> Self {
-        Self {
-            feed_timeout: None,
-            worker_threads: None,
-            num_aggregators: None,
-        }
-    }
+    pub feed_flush_interval: Option<u64>,
+    pub event_history_size: Option<usize>,
+    pub feed_channel_capacity: Option<usize>,
+    pub disable_geolocation: bool,
+    pub feed_heartbeat_interval: Option<u64>,
+    pub max_chains: Option<usize>,
+    pub max_feeds: Option<usize>,
+    pub max_third_party_nodes: Option<usize>,
+    pub node_update_min_interval: Option<u64>,
+    pub allow_chain: Vec<String>,
+    pub chain_tag: Vec<String>,
+    pub authority_only_chain: Vec<String>,
+    pub authority_node_token: Option<String>,
+    pub feed_ndjson_port: Option<u16>,
+    pub shard_secret: Option<String>,
+    pub admin_token: Option<String>,
 }
 
 /// Additional options to pass to the shard command.
+#[derive(Default)]
 pub struct ShardOpts {
     pub max_nodes_per_connection: Option<usize>,
+    pub max_conns_per_ip: Option<usize>,
+    pub max_accepts_per_sec: Option<u32>,
+    pub listen_backlog: Option<u32>,
+    pub max_pending_connections: Option<usize>,
     pub max_node_data_per_second: Option<usize>,
     pub node_block_seconds: Option<u64>,
     pub worker_threads: Option<usize>,
-}
-
-impl Default for ShardOpts {
-    fn default() -> Self {
-        Self {
-            max_nodes_per_connection: None,
-            max_node_data_per_second: None,
-            node_block_seconds: None,
-            worker_threads: None,
-        }
-    }
+    pub worker_cpu_affinity: bool,
+    pub max_node_msg_bytes: Option<usize>,
+    pub max_consecutive_malformed_messages: Option<usize>,
+    pub max_node_backlog: Option<usize>,
+    pub dedupe_node_messages: bool,
+    pub min_node_protocol: Option<u32>,
+    pub max_node_protocol: Option<u32>,
+    pub admin_listen: Option<std::net::SocketAddr>,
+    pub admin_uds: Option<std::path::PathBuf>,
+    pub aggregator_channel_capacity: Option<usize>,
+    pub core_channel_capacity: Option<usize>,
+    pub trust_proxy_header: bool,
+    pub trusted_proxies: Vec<String>,
+    pub shard_secret: Option<String>,
 }
 
 /// Start a telemetry server. We'll use `cargo run` by default, but you can also provide
@@ -105,7 +114,7 @@ pub async fn start_server(
         let feed_host = feed_host.trim().into();
         let submit_hosts: Vec<_> = std::env::var("TELEMETRY_SUBMIT_HOSTS")
             .map(|var| var.split(",").map(|var| var.trim().into()).collect())
-            .unwrap_or(Vec::new());
+            .unwrap_or_default();
         return Server::start(server::StartOpts::ConnectToExisting {
             feed_host,
             submit_hosts,
@@ -117,7 +126,7 @@ pub async fn start_server(
 
     // Build the shard command
     let mut shard_command = std::env::var("TELEMETRY_SHARD_BIN")
-        .map(|val| Command::new(val))
+        .map(Command::new)
         .unwrap_or_else(|_| {
             commands::cargo_run_telemetry_shard(server_opts.release_mode)
                 .expect("must be in rust workspace to run shard command")
@@ -134,6 +143,70 @@ pub async fn start_server(
             .arg("--max-node-data-per-second")
             .arg(val.to_string());
     }
+    if let Some(val) = shard_opts.max_conns_per_ip {
+        shard_command = shard_command.arg("--max-conns-per-ip").arg(val.to_string());
+    }
+    if let Some(val) = shard_opts.max_accepts_per_sec {
+        shard_command = shard_command
+            .arg("--max-accepts-per-sec")
+            .arg(val.to_string());
+    }
+    if let Some(val) = shard_opts.listen_backlog {
+        shard_command = shard_command.arg("--listen-backlog").arg(val.to_string());
+    }
+    if let Some(val) = shard_opts.max_pending_connections {
+        shard_command = shard_command
+            .arg("--max-pending-connections")
+            .arg(val.to_string());
+    }
+    if let Some(val) = shard_opts.max_node_msg_bytes {
+        shard_command = shard_command
+            .arg("--max-node-msg-bytes")
+            .arg(val.to_string());
+    }
+    if let Some(val) = shard_opts.max_consecutive_malformed_messages {
+        shard_command = shard_command
+            .arg("--max-consecutive-malformed-messages")
+            .arg(val.to_string());
+    }
+    if let Some(val) = shard_opts.max_node_backlog {
+        shard_command = shard_command.arg("--max-node-backlog").arg(val.to_string());
+    }
+    if shard_opts.dedupe_node_messages {
+        shard_command = shard_command.arg("--dedupe-node-messages");
+    }
+    if let Some(val) = shard_opts.min_node_protocol {
+        shard_command = shard_command
+            .arg("--min-node-protocol")
+            .arg(val.to_string());
+    }
+    if let Some(val) = shard_opts.max_node_protocol {
+        shard_command = shard_command
+            .arg("--max-node-protocol")
+            .arg(val.to_string());
+    }
+    if let Some(val) = shard_opts.admin_listen {
+        shard_command = shard_command.arg("--admin-listen").arg(val.to_string());
+    }
+    if let Some(val) = shard_opts.admin_uds {
+        shard_command = shard_command.arg("--admin-uds").arg(val);
+    }
+    if shard_opts.trust_proxy_header {
+        shard_command = shard_command.arg("--trust-proxy-header");
+    }
+    for val in shard_opts.trusted_proxies {
+        shard_command = shard_command.arg("--trusted-proxies").arg(val);
+    }
+    if let Some(val) = shard_opts.aggregator_channel_capacity {
+        shard_command = shard_command
+            .arg("--aggregator-channel-capacity")
+            .arg(val.to_string());
+    }
+    if let Some(val) = shard_opts.core_channel_capacity {
+        shard_command = shard_command
+            .arg("--core-channel-capacity")
+            .arg(val.to_string());
+    }
     if let Some(val) = shard_opts.node_block_seconds {
         shard_command = shard_command
             .arg("--node-block-seconds")
@@ -142,10 +215,16 @@ pub async fn start_server(
     if let Some(val) = shard_opts.worker_threads {
         shard_command = shard_command.arg("--worker-threads").arg(val.to_string());
     }
+    if shard_opts.worker_cpu_affinity {
+        shard_command = shard_command.arg("--worker-cpu-affinity");
+    }
+    if let Some(val) = shard_opts.shard_secret {
+        shard_command = shard_command.arg("--shard-secret").arg(val);
+    }
 
     // Build the core command
     let mut core_command = std::env::var("TELEMETRY_CORE_BIN")
-        .map(|val| Command::new(val))
+        .map(Command::new)
         .unwrap_or_else(|_| {
             commands::cargo_run_telemetry_core(server_opts.release_mode)
                 .expect("must be in rust workspace to run core command")
@@ -155,12 +234,78 @@ pub async fn start_server(
     if let Some(val) = core_opts.feed_timeout {
         core_command = core_command.arg("--feed-timeout").arg(val.to_string());
     }
+    if let Some(val) = core_opts.feed_send_timeout {
+        core_command = core_command.arg("--feed-send-timeout").arg(val.to_string());
+    }
     if let Some(val) = core_opts.worker_threads {
         core_command = core_command.arg("--worker-threads").arg(val.to_string());
     }
+    if core_opts.worker_cpu_affinity {
+        core_command = core_command.arg("--worker-cpu-affinity");
+    }
     if let Some(val) = core_opts.num_aggregators {
         core_command = core_command.arg("--num-aggregators").arg(val.to_string());
     }
+    if let Some(val) = core_opts.feed_flush_interval {
+        core_command = core_command
+            .arg("--feed-flush-interval")
+            .arg(val.to_string());
+    }
+    if let Some(val) = core_opts.event_history_size {
+        core_command = core_command
+            .arg("--event-history-size")
+            .arg(val.to_string());
+    }
+    if let Some(val) = core_opts.max_chains {
+        core_command = core_command.arg("--max-chains").arg(val.to_string());
+    }
+    if let Some(val) = core_opts.max_feeds {
+        core_command = core_command.arg("--max-feeds").arg(val.to_string());
+    }
+    if let Some(val) = core_opts.max_third_party_nodes {
+        core_command = core_command
+            .arg("--max-third-party-nodes")
+            .arg(val.to_string());
+    }
+    if let Some(val) = core_opts.node_update_min_interval {
+        core_command = core_command
+            .arg("--node-update-min-interval")
+            .arg(val.to_string());
+    }
+    if let Some(val) = core_opts.feed_heartbeat_interval {
+        core_command = core_command
+            .arg("--feed-heartbeat-interval")
+            .arg(val.to_string());
+    }
+    if core_opts.disable_geolocation {
+        core_command = core_command.arg("--disable-geolocation");
+    }
+    for chain in core_opts.allow_chain {
+        core_command = core_command.arg("--allow-chain").arg(chain);
+    }
+    for tag in core_opts.chain_tag {
+        core_command = core_command.arg("--chain-tag").arg(tag);
+    }
+    for chain in core_opts.authority_only_chain {
+        core_command = core_command.arg("--authority-only-chain").arg(chain);
+    }
+    if let Some(val) = core_opts.authority_node_token {
+        core_command = core_command.arg("--authority-node-token").arg(val);
+    }
+    if let Some(val) = core_opts.feed_channel_capacity {
+        core_command = core_command
+            .arg("--feed-channel-capacity")
+            .arg(val.to_string());
+    }
+    if let Some(val) = core_opts.feed_ndjson_port {
+        core_command = core_command.arg("--feed-ndjson-port").arg(val.to_string());
+    }
+    if let Some(val) = core_opts.shard_secret {
+        core_command = core_command.arg("--shard-secret").arg(val);
+    }
+    if let Some(val) = core_opts.admin_token {
+        core_command = core_command.arg("--admin-token").arg(val);
+    }
 
     // Start the server
     Server::start(server::StartOpts::ShardAndCore {
@@ -146,6 +146,8 @@ export class Connection {
       setHashData({ chain });
     }
 
+    // Finalized blocks are only sent if we ask for them:
+    this.socket.send(`send-finality:${chain}`);
     this.socket.send(`subscribe:${chain}`);
   }
 
@@ -316,6 +318,7 @@ export class Connection {
           const chain = chains.get(genesisHash);
 
           if (chain) {
+            chain.label = label;
             chain.nodeCount = nodeCount;
           } else {
             chains.set(genesisHash, { label, genesisHash, nodeCount });
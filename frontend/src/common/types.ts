@@ -34,6 +34,8 @@ export type PropagationTime = Opaque<Milliseconds, 'PropagationTime'>;
 export type NodeCount = Opaque<number, 'NodeCount'>;
 export type PeerCount = Opaque<number, 'PeerCount'>;
 export type TransactionCount = Opaque<number, 'TransactionCount'>;
+export type FinalityLag = Opaque<number, 'FinalityLag'>;
+export type ImplausiblePeers = Opaque<boolean, 'ImplausiblePeers'>;
 export type Latitude = Opaque<number, 'Latitude'>;
 export type Longitude = Opaque<number, 'Longitude'>;
 export type City = Opaque<string, 'City'>;
@@ -58,7 +60,12 @@ export type NodeDetails = [
   Maybe<NetworkId>,
   Maybe<string>
 ];
-export type NodeStats = [PeerCount, TransactionCount];
+export type NodeStats = [
+  PeerCount,
+  TransactionCount,
+  Maybe<FinalityLag>?,
+  ImplausiblePeers?
+];
 export type NodeIO = [Array<Bytes>];
 export type NodeHardware = [
   Array<BytesPerSecond>,